@@ -0,0 +1,509 @@
+use crate::analysis::Histogram;
+use crate::{AutoBalance, Curve, Equalization};
+use image::{GrayImage, Luma, RgbaImage, imageops};
+
+/// Percentile of each channel the white patch method makes white
+const WHITE_PATCH: f32 = 99.0;
+/// Strongest channel change of the temperature and tint sliders at 100
+const SLIDER_RANGE: f32 = 0.3;
+/// Gamma of the darkest areas at full shadow strength
+const SHADOW_LIFT: f32 = 0.3;
+/// Gamma of the brightest areas at full highlight strength
+const HIGHLIGHT_HOLD: f32 = 2.5;
+
+/// Color adjustments applied to every pixel
+///
+/// Steps run in field order: channel multipliers, exposure, gamma,
+/// saturation and finally the tone curve. Alpha is left unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adjustments {
+    /// Red, green and blue multipliers
+    pub channels: [f32; 3],
+    /// Exposure in stops, each stop doubles the brightness
+    pub exposure: f32,
+    /// Gamma correction, values above 1 brighten the midtones
+    pub gamma: f32,
+    /// Saturation multiplier, 0 is grayscale
+    pub saturation: f32,
+    /// Tone curve applied to each color channel
+    pub curve: Option<Curve>,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Adjustments {
+            channels: [1.0; 3],
+            exposure: 0.0,
+            gamma: 1.0,
+            saturation: 1.0,
+            curve: None,
+        }
+    }
+}
+
+/// Apply the adjustments to every pixel of the image
+pub fn adjust(img: &mut RgbaImage, adjustments: &Adjustments) {
+    let lut = adjustments.curve.as_ref().map(Curve::lut);
+    let gain = 2f32.powf(adjustments.exposure);
+
+    for pixel in img.pixels_mut() {
+        let mut rgb = [0.0; 3];
+        for (i, value) in rgb.iter_mut().enumerate() {
+            let linear = pixel[i] as f32 / 255.0 * adjustments.channels[i] * gain;
+            *value = linear.clamp(0.0, 1.0).powf(1.0 / adjustments.gamma);
+        }
+
+        // Move each channel away from or towards the Rec. 709 luma
+        let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+        for value in rgb.iter_mut() {
+            *value = luma + (*value - luma) * adjustments.saturation;
+        }
+
+        for (i, value) in rgb.into_iter().enumerate() {
+            let value = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+            pixel[i] = match &lut {
+                Some(lut) => lut[value as usize],
+                None => value,
+            };
+        }
+    }
+}
+
+/// Stretch each channel so its `low` and `high` percentiles become black and white
+///
+/// Gray histograms stretch all channels alike. Channels with a single value
+/// are left unchanged.
+pub fn autolevel(img: &mut RgbaImage, histogram: &Histogram, low: f32, high: f32) {
+    let luts: Vec<[u8; 256]> = (0..histogram.channels.len())
+        .map(|channel| {
+            let (black, white) = histogram.percentiles(channel, low, high);
+            let mut lut = [0u8; 256];
+            for (value, level) in lut.iter_mut().enumerate() {
+                *level = match white > black {
+                    true => {
+                        let range = (white - black) as f32;
+                        let stretched = (value as f32 - black as f32) * 255.0 / range;
+                        stretched.round().clamp(0.0, 255.0) as u8
+                    }
+                    false => value as u8,
+                };
+            }
+            lut
+        })
+        .collect();
+
+    for pixel in img.pixels_mut() {
+        for i in 0..3 {
+            pixel[i] = luts[i.min(luts.len() - 1)][pixel[i] as usize];
+        }
+    }
+}
+
+/// Channel multipliers that take out the color cast the method estimates
+///
+/// Gray world scales the channel means to their average, keeping the
+/// brightness. White patch scales the brightest percent of each channel to
+/// white. Transparent pixels are left out.
+pub fn balance_gains(img: &RgbaImage, method: AutoBalance) -> [f32; 3] {
+    let mut counts = [[0u64; 256]; 3];
+    for pixel in img.pixels().filter(|p| p[3] > 0) {
+        for (channel, counts) in counts.iter_mut().enumerate() {
+            counts[pixel[channel] as usize] += 1;
+        }
+    }
+    match method {
+        AutoBalance::GrayWorld => {
+            let means = counts.map(|counts| {
+                let total: u64 = counts.iter().sum();
+                let sum: u64 = (0..256).map(|value| value as u64 * counts[value]).sum();
+                sum as f32 / total.max(1) as f32
+            });
+            gray_point_gains(means)
+        }
+        AutoBalance::WhitePatch => {
+            let histogram = Histogram {
+                channels: vec![
+                    ("red", counts[0]),
+                    ("green", counts[1]),
+                    ("blue", counts[2]),
+                ],
+            };
+            [0, 1, 2].map(|channel| {
+                let (_, white) = histogram.percentiles(channel, 0.0, WHITE_PATCH);
+                match white {
+                    0 => 1.0,
+                    white => 255.0 / white as f32,
+                }
+            })
+        }
+    }
+}
+
+/// Channel multipliers that turn `color` gray of the same average brightness
+pub fn gray_point_gains(color: [f32; 3]) -> [f32; 3] {
+    let average = color.iter().sum::<f32>() / 3.0;
+    color.map(|value| match value > 0.0 {
+        true => average / value,
+        false => 1.0,
+    })
+}
+
+/// Channel multipliers of the temperature and tint sliders, both -100.0 ~ 100.0
+///
+/// Warming raises red and lowers blue, a magenta tint lowers green.
+pub fn temperature_gains(temperature: f32, tint: f32) -> [f32; 3] {
+    let warm = temperature / 100.0 * SLIDER_RANGE;
+    let magenta = tint / 100.0 * SLIDER_RANGE;
+    [1.0 + warm, 1.0 - magenta, 1.0 - warm]
+}
+
+/// Brighten dark areas and darken bright ones, `shadows` and `highlights` 0.0 ~ 1.0
+///
+/// The brightness blurred by `radius` masks the change, so whole areas are
+/// lifted or held back while the contrast within them stays. The gamma of a
+/// pixel follows how dark or bright its area is, black and white stay put.
+/// Colors are scaled with their luma to keep their hue.
+pub fn shadows_highlights(img: &mut RgbaImage, shadows: f32, highlights: f32, radius: f32) {
+    let luma = |pixel: &image::Rgba<u8>| {
+        (0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32) / 255.0
+    };
+    let lumas = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        Luma([(luma(img.get_pixel(x, y)) * 255.0).round() as u8])
+    });
+    let mask = match radius > 0.0 {
+        true => imageops::fast_blur(&lumas, radius),
+        false => lumas,
+    };
+
+    for (pixel, area) in img.pixels_mut().zip(mask.pixels()) {
+        let level = luma(pixel);
+        if level <= 0.0 {
+            continue;
+        }
+        let area = area[0] as f32 / 255.0;
+        let dark = (1.0 - area).powi(2) * shadows;
+        let bright = area.powi(2) * highlights;
+        let gamma = 1.0 + dark * (SHADOW_LIFT - 1.0) + bright * (HIGHLIGHT_HOLD - 1.0);
+        let gain = level.powf(gamma) / level;
+        for i in 0..3 {
+            pixel[i] = (pixel[i] as f32 * gain).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Spread out the brightness levels of the image
+///
+/// Global equalization maps levels through the cumulative histogram of the
+/// whole image. clahe does so for each of `columns` x `rows` tiles, with no
+/// level counted more than `clip_limit` times the average, and blends the
+/// mappings of the four nearest tiles. Only the luma changes, the same amount
+/// is added to each channel.
+pub fn equalize(
+    img: &mut RgbaImage,
+    method: Equalization,
+    (columns, rows): (u32, u32),
+    clip_limit: f32,
+) {
+    let (w, h) = img.dimensions();
+    let luma = GrayImage::from_fn(w, h, |x, y| {
+        let pixel = img.get_pixel(x, y);
+        let luma = 0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32;
+        Luma([luma.round() as u8])
+    });
+
+    let mapped = match method {
+        Equalization::Global => {
+            let mut counts = [0u64; 256];
+            for pixel in luma.pixels() {
+                counts[pixel[0] as usize] += 1;
+            }
+            let lut = global_lut(&counts);
+            GrayImage::from_fn(w, h, |x, y| Luma([lut[luma.get_pixel(x, y)[0] as usize]]))
+        }
+        Equalization::Clahe => clahe(&luma, columns, rows, clip_limit),
+    };
+
+    for ((pixel, before), after) in img.pixels_mut().zip(luma.pixels()).zip(mapped.pixels()) {
+        let change = after[0] as i16 - before[0] as i16;
+        for i in 0..3 {
+            pixel[i] = (pixel[i] as i16 + change).clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Levels through the cumulative histogram, the darkest level present becomes black
+fn global_lut(counts: &[u64; 256]) -> [u8; 256] {
+    let total: u64 = counts.iter().sum();
+    let darkest = counts.iter().copied().find(|&count| count > 0).unwrap_or(0);
+    let mut lut = [0u8; 256];
+    let mut cumulative = 0;
+    for (value, level) in lut.iter_mut().enumerate() {
+        cumulative += counts[value];
+        *level = match total > darkest {
+            true => {
+                let share = cumulative.saturating_sub(darkest) as f32 / (total - darkest) as f32;
+                (share * 255.0).round() as u8
+            }
+            false => value as u8,
+        };
+    }
+    lut
+}
+
+/// Equalize `columns` x `rows` tiles with clipped histograms and blend between them
+fn clahe(luma: &GrayImage, columns: u32, rows: u32, clip_limit: f32) -> GrayImage {
+    let (w, h) = luma.dimensions();
+    let edge = |i: u32, count: u32, len: u32| (i as u64 * len as u64 / count as u64) as u32;
+    let luts: Vec<[u8; 256]> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            let (x0, x1) = (edge(column, columns, w), edge(column + 1, columns, w));
+            let (y0, y1) = (edge(row, rows, h), edge(row + 1, rows, h));
+            let mut counts = [0.0f32; 256];
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    counts[luma.get_pixel(x, y)[0] as usize] += 1.0;
+                }
+            }
+            // Spread what is over the limit evenly over all levels
+            let total = ((x1 - x0) * (y1 - y0)) as f32;
+            let limit = (clip_limit * total / 256.0).max(1.0);
+            let excess: f32 = counts.iter().map(|&count| (count - limit).max(0.0)).sum();
+            let mut lut = [0u8; 256];
+            let mut cumulative = 0.0;
+            for (count, level) in counts.iter().zip(lut.iter_mut()) {
+                cumulative += count.min(limit) + excess / 256.0;
+                *level = (cumulative / total * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+            lut
+        })
+        .collect();
+
+    // Position between the tile centers, 0.0 at the first center
+    let between = |pos: u32, len: u32, count: u32| {
+        let at =
+            ((pos as f32 + 0.5) * count as f32 / len as f32 - 0.5).clamp(0.0, (count - 1) as f32);
+        let first = (at.floor() as u32).min(count - 1);
+        (first, (first + 1).min(count - 1), at - first as f32)
+    };
+    GrayImage::from_fn(w, h, |x, y| {
+        let (c0, c1, fx) = between(x, w, columns);
+        let (r0, r1, fy) = between(y, h, rows);
+        let value = luma.get_pixel(x, y)[0] as usize;
+        let level = |column: u32, row: u32| luts[(row * columns + column) as usize][value] as f32;
+        let top = level(c0, r0) * (1.0 - fx) + level(c1, r0) * fx;
+        let bottom = level(c0, r1) * (1.0 - fx) + level(c1, r1) * fx;
+        Luma([(top * (1.0 - fy) + bottom * fy).round() as u8])
+    })
+}
+
+impl Curve {
+    /// Lookup table of the curve, linear between points and flat outside them
+    pub fn lut(&self) -> [u8; 256] {
+        let points = &self.0;
+        let mut lut = [0u8; 256];
+        for (x, value) in lut.iter_mut().enumerate() {
+            let x = x as f32;
+            let next = points.iter().position(|&(px, _)| px as f32 >= x);
+            *value = match next {
+                None => points[points.len() - 1].1,
+                Some(0) => points[0].1,
+                Some(i) => {
+                    let (x0, y0) = (points[i - 1].0 as f32, points[i - 1].1 as f32);
+                    let (x1, y1) = (points[i].0 as f32, points[i].1 as f32);
+                    (y0 + (y1 - y0) * (x - x0) / (x1 - x0)).round() as u8
+                }
+            };
+        }
+        lut
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::str::FromStr;
+
+    fn adjusted(pixel: Rgba<u8>, adjustments: &Adjustments) -> Rgba<u8> {
+        let mut img = RgbaImage::from_pixel(1, 1, pixel);
+        adjust(&mut img, adjustments);
+        *img.get_pixel(0, 0)
+    }
+
+    #[test]
+    fn test_identity() {
+        let pixel = Rgba([12, 128, 250, 100]);
+        assert_eq!(adjusted(pixel, &Adjustments::default()), pixel);
+    }
+
+    #[test]
+    fn test_channels_and_exposure() {
+        let pixel = Rgba([100, 100, 100, 255]);
+        let channels = Adjustments {
+            channels: [2.0, 0.5, 1.0],
+            ..Adjustments::default()
+        };
+        assert_eq!(adjusted(pixel, &channels), Rgba([200, 50, 100, 255]));
+
+        let exposure = Adjustments {
+            exposure: 1.0,
+            ..Adjustments::default()
+        };
+        assert_eq!(adjusted(pixel, &exposure), Rgba([200, 200, 200, 255]));
+        assert_eq!(
+            adjusted(Rgba([200, 200, 200, 255]), &exposure),
+            Rgba([255, 255, 255, 255])
+        );
+    }
+
+    #[test]
+    fn test_gamma_and_saturation() {
+        let gamma = Adjustments {
+            gamma: 2.0,
+            ..Adjustments::default()
+        };
+        // sqrt(0.25) = 0.5
+        assert_eq!(
+            adjusted(Rgba([64, 64, 64, 255]), &gamma),
+            Rgba([128, 128, 128, 255])
+        );
+
+        let grayscale = Adjustments {
+            saturation: 0.0,
+            ..Adjustments::default()
+        };
+        let gray = adjusted(Rgba([255, 0, 0, 255]), &grayscale);
+        assert_eq!(gray, Rgba([54, 54, 54, 255]));
+    }
+
+    #[test]
+    fn test_autolevel() {
+        // A flat image using values 100 to 150 only
+        let mut img = RgbaImage::from_fn(51, 1, |x, _| Rgba([100 + x as u8, 120, 100, 255]));
+        let histogram = Histogram::new(&image::DynamicImage::ImageRgba8(img.clone()));
+        autolevel(&mut img, &histogram, 0.0, 100.0);
+
+        assert_eq!(img.get_pixel(0, 0).0, [0, 120, 100, 255]);
+        assert_eq!(img.get_pixel(25, 0).0[0], 128);
+        assert_eq!(img.get_pixel(50, 0).0, [255, 120, 100, 255]);
+    }
+
+    #[test]
+    fn test_white_balance() {
+        // A bluish cast on a dark and a light gray
+        let mut img = RgbaImage::from_fn(2, 1, |x, _| match x {
+            0 => Rgba([60, 70, 80, 255]),
+            _ => Rgba([180, 210, 240, 255]),
+        });
+        let gains = balance_gains(&img, AutoBalance::GrayWorld);
+        adjust(
+            &mut img,
+            &Adjustments {
+                channels: gains,
+                ..Adjustments::default()
+            },
+        );
+        let [r, g, b, _] = img.get_pixel(1, 0).0;
+        assert!(r.abs_diff(g) <= 1 && g.abs_diff(b) <= 1);
+
+        let img = RgbaImage::from_fn(10, 10, |x, _| Rgba([25 * x as u8, 20 * x as u8, 200, 255]));
+        let [r, g, b] = balance_gains(&img, AutoBalance::WhitePatch);
+        assert!((r - 255.0 / 225.0).abs() < 1e-3);
+        assert!((g - 255.0 / 180.0).abs() < 1e-3);
+        assert!((b - 255.0 / 200.0).abs() < 1e-3);
+
+        assert_eq!(
+            gray_point_gains([50.0, 100.0, 150.0]),
+            [2.0, 1.0, 100.0 / 150.0]
+        );
+        assert_eq!(
+            gray_point_gains([0.0, 30.0, 30.0]),
+            [1.0, 2.0 / 3.0, 2.0 / 3.0]
+        );
+        assert_eq!(temperature_gains(0.0, 0.0), [1.0; 3]);
+        let [r, g, b] = temperature_gains(50.0, -100.0);
+        assert!(r > 1.0 && b < 1.0 && g > 1.0);
+    }
+
+    #[test]
+    fn test_shadows_highlights() {
+        // A dark half next to a bright half, both with some texture
+        let backlit = RgbaImage::from_fn(40, 10, |x, y| {
+            let base = if x < 20 { 30 } else { 220 };
+            let texture = ((x + y) % 2 * 10) as u8;
+            Rgba([base + texture, base + texture, base, 255])
+        });
+
+        let mut img = backlit.clone();
+        shadows_highlights(&mut img, 0.0, 0.0, 5.0);
+        assert_eq!(img, backlit);
+
+        let mut img = backlit.clone();
+        shadows_highlights(&mut img, 1.0, 0.0, 5.0);
+        let (before, after) = (backlit.get_pixel(5, 5), img.get_pixel(5, 5));
+        assert!(after[0] > before[0] + 40);
+        // The texture stays, and so does the hue
+        assert!(img.get_pixel(5, 5)[0] != img.get_pixel(6, 5)[0]);
+        let tinted = img.get_pixel(6, 5);
+        assert!(tinted[2] < tinted[0]);
+        assert!(img.get_pixel(35, 5)[0].abs_diff(backlit.get_pixel(35, 5)[0]) <= 3);
+
+        let mut img = backlit.clone();
+        shadows_highlights(&mut img, 0.0, 1.0, 5.0);
+        assert!(img.get_pixel(35, 5)[0] + 30 < backlit.get_pixel(35, 5)[0]);
+        assert!(img.get_pixel(5, 5)[0].abs_diff(backlit.get_pixel(5, 5)[0]) <= 3);
+        assert_eq!(img.get_pixel(0, 0)[3], 255);
+    }
+
+    #[test]
+    fn test_equalize() {
+        // A flat image using levels 100 to 119
+        let flat = RgbaImage::from_fn(20, 20, |x, _| {
+            Rgba([100 + x as u8, 100 + x as u8, 100 + x as u8, 255])
+        });
+        let mut img = flat.clone();
+        equalize(&mut img, Equalization::Global, (8, 8), 2.0);
+        assert_eq!(img.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert_eq!(img.get_pixel(19, 0).0, [255, 255, 255, 255]);
+        assert!(img.get_pixel(10, 0)[0].abs_diff(134) <= 2);
+
+        // Colors move together, keeping their differences
+        let mut img = RgbaImage::from_fn(3, 1, |x, _| {
+            let base = 40 + 50 * x as u8;
+            Rgba([base, base + 10, base + 20, 255])
+        });
+        equalize(&mut img, Equalization::Global, (8, 8), 2.0);
+        let [r, g, b, _] = img.get_pixel(1, 0).0;
+        assert_eq!((g - r, b - g), (10, 10));
+
+        // Two halves of different brightness both get their detail stretched
+        let halves = RgbaImage::from_fn(64, 32, |x, y| {
+            let value = if x < 32 { 40 } else { 200 } + ((x + y) % 4 * 4) as u8;
+            Rgba([value, value, value, 255])
+        });
+        let mut img = halves.clone();
+        equalize(&mut img, Equalization::Clahe, (2, 1), 8.0);
+        let spread = |img: &RgbaImage, x: u32| img.get_pixel(x + 3, 0)[0] - img.get_pixel(x, 0)[0];
+        assert!(spread(&img, 8) > 2 * spread(&halves, 8));
+        assert!(spread(&img, 48) > 2 * spread(&halves, 48));
+        // A lower clip limit adds less contrast
+        let mut clipped = halves.clone();
+        equalize(&mut clipped, Equalization::Clahe, (2, 1), 2.0);
+        assert!(spread(&clipped, 8) < spread(&img, 8));
+    }
+
+    #[test]
+    fn test_curve() {
+        let curve = Curve::from_str("curve(0:0,128:150,255:255)").unwrap();
+        let lut = curve.lut();
+        assert_eq!(lut[0], 0);
+        assert_eq!(lut[64], 75);
+        assert_eq!(lut[128], 150);
+        assert_eq!(lut[255], 255);
+
+        // Flat outside the first and last point
+        let lut = Curve::from_str("50:0,200:255").unwrap().lut();
+        assert_eq!((lut[0], lut[50], lut[200], lut[255]), (0, 0, 255, 255));
+    }
+}
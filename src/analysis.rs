@@ -0,0 +1,685 @@
+use crate::colormap::heat;
+use crate::process::rgba8;
+use crate::{ImgtoolsError, Point, QualityMetric, Region, ReportFormat};
+use image::{DynamicImage, GrayImage, RgbImage, Rgba, RgbaImage};
+use serde_json::json;
+
+/// Luma at or above which a pixel counts as overexposed
+const CLIPPED_WHITE: u8 = 250;
+/// Luma at or below which a pixel counts as underexposed
+const CLIPPED_BLACK: u8 = 5;
+
+/// Pixel counts of each color channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    /// Channel names and their 256 value counts, gray images have one channel
+    pub channels: Vec<(&'static str, [u64; 256])>,
+}
+
+impl Histogram {
+    /// Count the channel values of an image, alpha is ignored
+    pub fn new(img: &DynamicImage) -> Self {
+        if !img.color().has_color() {
+            let mut counts = [0; 256];
+            for pixel in img.to_luma8().pixels() {
+                counts[pixel[0] as usize] += 1;
+            }
+            return Histogram {
+                channels: vec![("gray", counts)],
+            };
+        }
+
+        let mut counts = [[0; 256]; 3];
+        for pixel in img.to_rgb8().pixels() {
+            for (channel, &value) in counts.iter_mut().zip(pixel.0.iter()) {
+                channel[value as usize] += 1;
+            }
+        }
+        let [red, green, blue] = counts;
+        Histogram {
+            channels: vec![("red", red), ("green", green), ("blue", blue)],
+        }
+    }
+
+    /// Values of a channel at the `low` and `high` percentiles
+    ///
+    /// Percentiles range from 0 to 100, where 0 and 100 give the darkest and
+    /// brightest value present.
+    pub fn percentiles(&self, channel: usize, low: f32, high: f32) -> (u8, u8) {
+        let counts = &self.channels[channel].1;
+        let total = counts.iter().sum::<u64>() as f64;
+        let below = total * low as f64 / 100.0;
+        let above = total * (100.0 - high as f64) / 100.0;
+
+        let mut seen = 0;
+        let low = counts
+            .iter()
+            .position(|&count| {
+                seen += count;
+                seen as f64 > below
+            })
+            .unwrap_or(0);
+        let mut seen = 0;
+        let high = counts
+            .iter()
+            .rposition(|&count| {
+                seen += count;
+                seen as f64 > above
+            })
+            .unwrap_or(255);
+        (low as u8, high as u8)
+    }
+
+    /// Format the histogram as an ASCII chart or as JSON
+    ///
+    /// The chart groups values into 16 bins per channel.
+    pub fn report(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => self
+                .channels
+                .iter()
+                .map(|(name, counts)| {
+                    let bins: Vec<u64> = counts.chunks(16).map(|bin| bin.iter().sum()).collect();
+                    let max = bins.iter().copied().max().unwrap_or(0).max(1);
+                    let rows = bins.iter().enumerate().map(|(i, &count)| {
+                        let bar = "#".repeat((count * 40).div_ceil(max) as usize);
+                        format!("{:>3}-{:<3} |{:<40}| {}", i * 16, i * 16 + 15, bar, count)
+                    });
+                    std::iter::once(name.to_string())
+                        .chain(rows)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            ReportFormat::Json => {
+                let channels: Vec<_> = self
+                    .channels
+                    .iter()
+                    .map(|(name, counts)| json!({ "name": name, "counts": counts.as_slice() }))
+                    .collect();
+                json!({ "histogram": channels }).to_string()
+            }
+        }
+    }
+
+    /// Draw the channels as overlapping bar charts on a black background
+    ///
+    /// Each of the 256 values gets a column scaled to the image width, bar
+    /// heights are relative to the largest count of all channels.
+    pub fn render(&self, width: u32, height: u32) -> RgbaImage {
+        let max = self
+            .channels
+            .iter()
+            .flat_map(|(_, counts)| counts.iter().copied())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut chart = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+
+        for (name, counts) in &self.channels {
+            let color = match *name {
+                "red" => [255, 0, 0],
+                "green" => [0, 255, 0],
+                "blue" => [0, 0, 255],
+                _ => [220, 220, 220],
+            };
+            for x in 0..width {
+                let value = (x as u64 * 256 / width.max(1) as u64) as usize;
+                let bar = (counts[value] * height as u64).div_ceil(max) as u32;
+                // Add the channel color so overlapping bars mix
+                for y in height - bar.min(height)..height {
+                    let pixel = chart.get_pixel_mut(x, y);
+                    for (c, &add) in pixel.0.iter_mut().zip(color.iter()) {
+                        *c = c.saturating_add(add);
+                    }
+                }
+            }
+        }
+        chart
+    }
+}
+
+/// Range, mean and spread of the values of one channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelStats {
+    pub min: u8,
+    pub max: u8,
+    pub mean: f64,
+    /// Standard deviation from the mean
+    pub stddev: f64,
+}
+
+/// Channel statistics of an image or an area of it, and sampled pixels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    /// Area the statistics are taken over, the whole image if none was given
+    pub region: Region,
+    /// Channel names and their statistics, gray images have one color channel
+    pub channels: Vec<(&'static str, ChannelStats)>,
+    /// Sampled pixels and their values of each channel
+    pub pixels: Vec<(Point, Vec<u8>)>,
+}
+
+impl Statistics {
+    /// Measure the channels over `region` and read the values at `pixels`
+    ///
+    /// Regions reaching past the image are cut at its edges, alpha is only
+    /// included for images that have it.
+    pub fn new(
+        img: &DynamicImage,
+        region: Option<Region>,
+        pixels: &[Point],
+    ) -> Result<Self, ImgtoolsError> {
+        let (width, height) = (img.width(), img.height());
+        let region = match region {
+            Some(r) if r.x >= width || r.y >= height => {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Region {},{},{},{} is outside the {}x{} image",
+                    r.x, r.y, r.width, r.height, width, height
+                )));
+            }
+            Some(r) => Region {
+                width: r.width.min(width - r.x),
+                height: r.height.min(height - r.y),
+                ..r
+            },
+            None => Region {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+        };
+        if let Some(point) = pixels.iter().find(|p| p.x >= width || p.y >= height) {
+            return Err(ImgtoolsError::InvalidArgument(format!(
+                "Pixel {},{} is outside the {}x{} image",
+                point.x, point.y, width, height
+            )));
+        }
+
+        let color = img.color();
+        let names: &[&'static str] = match (color.has_color(), color.has_alpha()) {
+            (true, true) => &["red", "green", "blue", "alpha"],
+            (true, false) => &["red", "green", "blue"],
+            (false, true) => &["gray", "alpha"],
+            (false, false) => &["gray"],
+        };
+        let (rgba, luma) = (rgba8(img), img.to_luma_alpha8());
+        // Values of a pixel in the order of the names
+        let values = |x: u32, y: u32| -> Vec<u8> {
+            let (p, l) = (rgba.get_pixel(x, y), luma.get_pixel(x, y));
+            match color.has_color() {
+                true => p.0[..names.len()].to_vec(),
+                false => l.0[..names.len()].to_vec(),
+            }
+        };
+
+        let mut sums = vec![(u8::MAX, u8::MIN, 0.0, 0.0); names.len()];
+        for y in region.y..region.y + region.height {
+            for x in region.x..region.x + region.width {
+                for (sum, v) in sums.iter_mut().zip(values(x, y)) {
+                    let value = v as f64;
+                    *sum = (
+                        sum.0.min(v),
+                        sum.1.max(v),
+                        sum.2 + value,
+                        sum.3 + value * value,
+                    );
+                }
+            }
+        }
+        let n = (region.width as f64 * region.height as f64).max(1.0);
+        let channels = names
+            .iter()
+            .zip(sums)
+            .map(|(&name, (min, max, sum, squares))| {
+                let mean = sum / n;
+                let stats = ChannelStats {
+                    min,
+                    max,
+                    mean,
+                    stddev: (squares / n - mean * mean).max(0.0).sqrt(),
+                };
+                (name, stats)
+            })
+            .collect();
+        Ok(Statistics {
+            region,
+            channels,
+            pixels: pixels.iter().map(|&p| (p, values(p.x, p.y))).collect(),
+        })
+    }
+
+    /// Format the statistics and pixel values as text lines or JSON
+    pub fn report(&self, format: ReportFormat) -> String {
+        let r = &self.region;
+        match format {
+            ReportFormat::Text => {
+                let mut lines = vec![format!("Region: {},{},{},{}", r.x, r.y, r.width, r.height)];
+                for (name, c) in &self.channels {
+                    lines.push(format!(
+                        "{}: min {}, max {}, mean {:.2}, stddev {:.2}",
+                        name, c.min, c.max, c.mean, c.stddev
+                    ));
+                }
+                for (point, values) in &self.pixels {
+                    let values: Vec<String> = values.iter().map(u8::to_string).collect();
+                    lines.push(format!(
+                        "Pixel {},{}: {}",
+                        point.x,
+                        point.y,
+                        values.join(", ")
+                    ));
+                }
+                lines.join("\n")
+            }
+            ReportFormat::Json => {
+                let channels: serde_json::Map<_, _> = self
+                    .channels
+                    .iter()
+                    .map(|(name, c)| {
+                        let stats = json!({
+                            "min": c.min,
+                            "max": c.max,
+                            "mean": c.mean,
+                            "stddev": c.stddev,
+                        });
+                        (name.to_string(), stats)
+                    })
+                    .collect();
+                let pixels: Vec<_> = self
+                    .pixels
+                    .iter()
+                    .map(|(point, values)| {
+                        let mut pixel = serde_json::Map::new();
+                        pixel.insert("x".into(), point.x.into());
+                        pixel.insert("y".into(), point.y.into());
+                        for ((name, _), &v) in self.channels.iter().zip(values) {
+                            pixel.insert(name.to_string(), v.into());
+                        }
+                        pixel
+                    })
+                    .collect();
+                json!({
+                    "region": { "x": r.x, "y": r.y, "width": r.width, "height": r.height },
+                    "channels": channels,
+                    "pixels": pixels,
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+/// No-reference quality measures of an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assessment {
+    /// Variance of the Laplacian of the brightness, low for blurry images
+    pub blur: f64,
+    /// Percentage of pixels that are nearly white
+    pub overexposed: f64,
+    /// Percentage of pixels that are nearly black
+    pub underexposed: f64,
+    /// Estimated standard deviation of the noise, in levels of 0 ~ 255
+    pub noise: f64,
+}
+
+impl Assessment {
+    /// Measure the brightness of the image
+    ///
+    /// Blur is the variance of the 3x3 Laplacian as OpenCV computes it, so the
+    /// usual thresholds apply. Noise is estimated with Immerkær's method from
+    /// a filter that cancels out edges and smooth gradients.
+    pub fn new(img: &DynamicImage) -> Self {
+        let luma = img.to_luma8();
+        let (w, h) = luma.dimensions();
+        let total = (w as f64 * h as f64).max(1.0);
+        let (mut white, mut black) = (0u64, 0u64);
+        for p in luma.pixels() {
+            white += (p[0] >= CLIPPED_WHITE) as u64;
+            black += (p[0] <= CLIPPED_BLACK) as u64;
+        }
+
+        let (mut blur, mut noise) = (0.0, 0.0);
+        if w >= 3 && h >= 3 {
+            let at = |x: u32, y: u32| luma.get_pixel(x, y)[0] as f64;
+            let (mut sum, mut squares, mut residual) = (0.0, 0.0, 0.0);
+            for y in 1..h - 1 {
+                for x in 1..w - 1 {
+                    let center = at(x, y);
+                    let sides = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1);
+                    let corners =
+                        at(x - 1, y - 1) + at(x + 1, y - 1) + at(x - 1, y + 1) + at(x + 1, y + 1);
+                    let laplacian = sides - 4.0 * center;
+                    sum += laplacian;
+                    squares += laplacian * laplacian;
+                    residual += (corners - 2.0 * sides + 4.0 * center).abs();
+                }
+            }
+            let inner = (w - 2) as f64 * (h - 2) as f64;
+            let mean = sum / inner;
+            blur = squares / inner - mean * mean;
+            noise = residual * (std::f64::consts::PI / 2.0).sqrt() / (6.0 * inner);
+        }
+        Assessment {
+            blur,
+            overexposed: white as f64 * 100.0 / total,
+            underexposed: black as f64 * 100.0 / total,
+            noise,
+        }
+    }
+
+    pub fn value(&self, metric: QualityMetric) -> f64 {
+        match metric {
+            QualityMetric::Blur => self.blur,
+            QualityMetric::Overexposed => self.overexposed,
+            QualityMetric::Underexposed => self.underexposed,
+            QualityMetric::Noise => self.noise,
+        }
+    }
+
+    /// Format the measures as text lines or JSON
+    pub fn report(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => format!(
+                "Blur (Laplacian variance): {:.2}\nOverexposed: {:.2}%\nUnderexposed: {:.2}%\nNoise: {:.2}",
+                self.blur, self.overexposed, self.underexposed, self.noise
+            ),
+            ReportFormat::Json => json!({
+                "blur": self.blur,
+                "overexposed": self.overexposed,
+                "underexposed": self.underexposed,
+                "noise": self.noise,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Differences between two images of the same size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    /// Mean absolute error of the RGBA channels, 0 to 255
+    pub mae: f64,
+    /// Peak signal to noise ratio in dB, infinite for identical images
+    pub psnr: f64,
+    /// Mean structural similarity of the luminance, 1.0 for identical images
+    pub ssim: f64,
+}
+
+impl Comparison {
+    /// Compare two images channel by channel
+    pub fn new(a: &DynamicImage, b: &DynamicImage) -> Result<Self, ImgtoolsError> {
+        check_sizes(a, b)?;
+        let (a8, b8) = (rgba8(a), rgba8(b));
+        let (mut abs, mut squared) = (0.0, 0.0);
+        for (x, y) in a8.as_raw().iter().zip(b8.as_raw()) {
+            let d = *x as f64 - *y as f64;
+            abs += d.abs();
+            squared += d * d;
+        }
+        let n = a8.as_raw().len().max(1) as f64;
+        let mse = squared / n;
+
+        Ok(Comparison {
+            mae: abs / n,
+            psnr: 10.0 * (255.0 * 255.0 / mse).log10(),
+            ssim: ssim(&a.to_luma8(), &b.to_luma8()),
+        })
+    }
+
+    /// Format the metrics as text or JSON, an infinite PSNR is null in JSON
+    pub fn report(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => format!(
+                "MAE: {:.4}\nPSNR: {:.2} dB\nSSIM: {:.4}",
+                self.mae, self.psnr, self.ssim
+            ),
+            ReportFormat::Json => json!({
+                "mae": self.mae,
+                "psnr": self.psnr.is_finite().then_some(self.psnr),
+                "ssim": self.ssim,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Draw the largest channel difference of each pixel as a heat color
+pub fn diff_heatmap(a: &DynamicImage, b: &DynamicImage) -> Result<RgbImage, ImgtoolsError> {
+    check_sizes(a, b)?;
+    let (a8, b8) = (rgba8(a), rgba8(b));
+    Ok(RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (p, q) = (a8.get_pixel(x, y), b8.get_pixel(x, y));
+        let diff = (0..4).map(|c| p[c].abs_diff(q[c])).max().unwrap_or(0);
+        heat(diff as f32 / 255.0)
+    }))
+}
+
+fn check_sizes(a: &DynamicImage, b: &DynamicImage) -> Result<(), ImgtoolsError> {
+    match (a.width(), a.height()) == (b.width(), b.height()) {
+        true => Ok(()),
+        false => Err(ImgtoolsError::InvalidArgument(format!(
+            "Image sizes differ: {}x{} and {}x{}",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        ))),
+    }
+}
+
+/// Mean SSIM over 8x8 windows moved by 4 pixels
+///
+/// Images smaller than a window are compared as a whole.
+fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (w, h) = a.dimensions();
+    let (win_w, win_h) = (w.min(8), h.min(8));
+    if win_w == 0 || win_h == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y0 in (0..=h - win_h).step_by(4) {
+        for x0 in (0..=w - win_w).step_by(4) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in y0..y0 + win_h {
+                for x in x0..x0 + win_w {
+                    let (p, q) = (a.get_pixel(x, y)[0] as f64, b.get_pixel(x, y)[0] as f64);
+                    sa += p;
+                    sb += q;
+                    saa += p * p;
+                    sbb += q * q;
+                    sab += p * q;
+                }
+            }
+            let n = (win_w * win_h) as f64;
+            let (ma, mb) = (sa / n, sb / n);
+            let (va, vb) = (saa / n - ma * ma, sbb / n - mb * mb);
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2))
+                / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, RgbImage};
+
+    #[test]
+    fn test_histogram_counts() {
+        let mut img = RgbImage::from_pixel(4, 1, image::Rgb([10, 20, 30]));
+        img.put_pixel(0, 0, image::Rgb([10, 255, 0]));
+        let histogram = Histogram::new(&DynamicImage::ImageRgb8(img));
+
+        let names: Vec<_> = histogram.channels.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["red", "green", "blue"]);
+        assert_eq!(histogram.channels[0].1[10], 4);
+        assert_eq!(histogram.channels[1].1[20], 3);
+        assert_eq!(histogram.channels[1].1[255], 1);
+        assert_eq!(histogram.channels[2].1[0], 1);
+
+        let gray = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([7])));
+        let histogram = Histogram::new(&gray);
+        assert_eq!(histogram.channels.len(), 1);
+        assert_eq!(histogram.channels[0].1[7], 4);
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        // Values 0..100, one pixel each
+        let img = GrayImage::from_fn(100, 1, |x, _| Luma([x as u8]));
+        let histogram = Histogram::new(&DynamicImage::ImageLuma8(img));
+        assert_eq!(histogram.percentiles(0, 0.0, 100.0), (0, 99));
+        assert_eq!(histogram.percentiles(0, 1.0, 99.0), (1, 98));
+        assert_eq!(histogram.percentiles(0, 10.0, 80.0), (10, 79));
+    }
+
+    #[test]
+    fn test_histogram_report() {
+        let gray = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([20])));
+        let histogram = Histogram::new(&gray);
+
+        let text = histogram.report(ReportFormat::Text);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 17);
+        assert_eq!(lines[0], "gray");
+        assert_eq!(lines[1], format!("  0-15  |{:40}| 0", ""));
+        assert_eq!(lines[2], format!(" 16-31  |{}| 4", "#".repeat(40)));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&histogram.report(ReportFormat::Json)).unwrap();
+        assert_eq!(json["histogram"][0]["name"], "gray");
+        assert_eq!(json["histogram"][0]["counts"][20], 4);
+    }
+
+    #[test]
+    fn test_comparison() {
+        let gray = |v| DynamicImage::ImageLuma8(GrayImage::from_pixel(16, 16, Luma([v])));
+        let same = Comparison::new(&gray(100), &gray(100)).unwrap();
+        assert_eq!(same.mae, 0.0);
+        assert!(same.psnr.is_infinite());
+        assert!((same.ssim - 1.0).abs() < 1e-9);
+        let json: serde_json::Value =
+            serde_json::from_str(&same.report(ReportFormat::Json)).unwrap();
+        assert!(json["psnr"].is_null());
+
+        // Red, green and blue differ by 10, alpha is equal
+        let off = Comparison::new(&gray(100), &gray(110)).unwrap();
+        assert!((off.mae - 7.5).abs() < 1e-9, "{}", off.mae);
+        assert!((off.psnr - 20.0 * (255.0f64 / 75.0f64.sqrt()).log10()).abs() < 1e-9);
+
+        // Noise lowers the structural similarity
+        let noisy = GrayImage::from_fn(16, 16, |x, y| Luma([100 + ((x * 7 + y * 13) % 40) as u8]));
+        let noisy = Comparison::new(&gray(100), &DynamicImage::ImageLuma8(noisy)).unwrap();
+        assert!(noisy.ssim < 0.9, "{}", noisy.ssim);
+
+        assert!(Comparison::new(&gray(0), &DynamicImage::new_luma8(2, 2)).is_err());
+    }
+
+    #[test]
+    fn test_diff_heatmap() {
+        let a = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 1, image::Rgb([0, 0, 0])));
+        let mut b = a.to_rgb8();
+        b.put_pixel(1, 0, image::Rgb([0, 255, 0]));
+        let heatmap = diff_heatmap(&a, &DynamicImage::ImageRgb8(b)).unwrap();
+        assert_eq!(heatmap.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(heatmap.get_pixel(1, 0).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_histogram_render() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([0, 0, 255])));
+        let chart = Histogram::new(&img).render(256, 100);
+        assert_eq!(chart.dimensions(), (256, 100));
+        // Red and green peak at 0, blue at 255
+        assert_eq!(chart.get_pixel(0, 0).0, [255, 255, 0, 255]);
+        assert_eq!(chart.get_pixel(255, 0).0, [0, 0, 255, 255]);
+        assert_eq!(chart.get_pixel(128, 99).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_statistics() {
+        // Black left half, white right half
+        let img = GrayImage::from_fn(4, 2, |x, _| Luma([if x < 2 { 0 } else { 255 }]));
+        let img = DynamicImage::ImageLuma8(img);
+        let stats = Statistics::new(&img, None, &[Point { x: 3, y: 1 }]).unwrap();
+        assert_eq!(stats.channels.len(), 1);
+        let (name, gray) = stats.channels[0];
+        assert_eq!((name, gray.min, gray.max), ("gray", 0, 255));
+        assert_eq!((gray.mean, gray.stddev), (127.5, 127.5));
+        assert_eq!(stats.pixels, [(Point { x: 3, y: 1 }, vec![255])]);
+        assert_eq!(
+            stats.report(ReportFormat::Text),
+            "Region: 0,0,4,2\ngray: min 0, max 255, mean 127.50, stddev 127.50\nPixel 3,1: 255"
+        );
+
+        // Regions are cut at the edges
+        let region = Region {
+            x: 2,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let stats = Statistics::new(&img, Some(region), &[]).unwrap();
+        assert_eq!((stats.region.width, stats.region.height), (2, 2));
+        assert_eq!(
+            (stats.channels[0].1.mean, stats.channels[0].1.stddev),
+            (255.0, 0.0)
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&stats.report(ReportFormat::Json)).unwrap();
+        assert_eq!(json["channels"]["gray"]["min"], 255);
+        assert_eq!(json["region"]["width"], 2);
+
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([1, 2, 3, 4])));
+        let stats = Statistics::new(&rgba, None, &[Point { x: 0, y: 0 }]).unwrap();
+        let names: Vec<_> = stats.channels.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["red", "green", "blue", "alpha"]);
+        assert_eq!(stats.pixels[0].1, [1, 2, 3, 4]);
+        assert!(Statistics::new(&rgba, None, &[Point { x: 1, y: 0 }]).is_err());
+        assert!(Statistics::new(&rgba, Some(region), &[]).is_err());
+    }
+
+    #[test]
+    fn test_assessment() {
+        let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(20, 20, Luma([128])));
+        let assessment = Assessment::new(&flat);
+        assert_eq!(
+            (assessment.blur, assessment.noise, assessment.overexposed),
+            (0.0, 0.0, 0.0)
+        );
+
+        // Noise of a known strength is estimated closely
+        let mut rng = crate::filters::Rng::new(7);
+        let noisy = GrayImage::from_fn(200, 200, |_, _| {
+            Luma([(128.0 + 10.0 * rng.gaussian()).round() as u8])
+        });
+        let noise = Assessment::new(&DynamicImage::ImageLuma8(noisy)).noise;
+        assert!((noise - 10.0).abs() < 1.0, "{}", noise);
+
+        // Sharp stripes are far from blurry, blurring them lowers the variance
+        let stripes = GrayImage::from_fn(40, 40, |x, y| match (x / 2 + y) % 2 {
+            0 => Luma([255]),
+            _ => Luma([0]),
+        });
+        let sharp = Assessment::new(&DynamicImage::ImageLuma8(stripes.clone()));
+        let soft = Assessment::new(&DynamicImage::ImageLuma8(image::imageops::blur(
+            &stripes, 2.0,
+        )));
+        assert!(sharp.blur > 1000.0 && soft.blur < sharp.blur / 10.0);
+        assert_eq!((sharp.overexposed, sharp.underexposed), (50.0, 50.0));
+        assert_eq!(sharp.value(QualityMetric::Overexposed), sharp.overexposed);
+        let json: serde_json::Value =
+            serde_json::from_str(&sharp.report(ReportFormat::Json)).unwrap();
+        assert_eq!(json["underexposed"], 50.0);
+    }
+}
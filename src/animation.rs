@@ -0,0 +1,442 @@
+use crate::geometry::pad;
+use crate::process::decode;
+use crate::{Command, Format, ImgtoolsError, apply_command};
+use gif::Repeat;
+use image::codecs::gif::{GifDecoder, GifEncoder};
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::error::{EncodingError, ImageFormatHint};
+use image::{
+    AnimationDecoder, Delay, DynamicImage, ExtendedColorType, Frame, ImageError, ImageFormat, Rgba,
+};
+use std::io::{Cursor, Write};
+
+/// The frames of an animated image together with its loop count
+pub struct Animation {
+    /// Fully composed frames with their delays
+    pub frames: Vec<Frame>,
+    /// How often the animation plays
+    pub repeat: Repeat,
+}
+
+impl Animation {
+    /// Decode every frame of a GIF
+    pub fn decode_gif(data: &[u8]) -> Result<Self, ImgtoolsError> {
+        let decoder = GifDecoder::new(Cursor::new(data)).map_err(ImgtoolsError::Decode)?;
+        let frames = decoder
+            .into_frames()
+            .collect_frames()
+            .map_err(ImgtoolsError::Decode)?;
+
+        Ok(Animation {
+            frames,
+            repeat: gif_repeat(data),
+        })
+    }
+
+    /// Decode every frame of an image, still images give a single frame
+    pub fn decode(data: &[u8], format: Option<ImageFormat>) -> Result<Self, ImgtoolsError> {
+        let format = format.or_else(|| image::guess_format(data).ok());
+        if let Some(animation) = format
+            .map(|format| Self::decode_animated(data, format))
+            .transpose()?
+            .flatten()
+        {
+            return Ok(animation);
+        }
+
+        let buffer = decode(data, format)?.into_rgba8();
+        Ok(Animation {
+            frames: vec![Frame::new(buffer)],
+            repeat: Repeat::Finite(0),
+        })
+    }
+
+    /// Decode every frame of a GIF, or of a WebP or PNG that is animated
+    ///
+    /// Other formats and still WebP and PNG images give `None`.
+    pub fn decode_animated(
+        data: &[u8],
+        format: ImageFormat,
+    ) -> Result<Option<Self>, ImgtoolsError> {
+        let (frames, repeat) = match format {
+            ImageFormat::Gif => return Self::decode_gif(data).map(Some),
+            ImageFormat::WebP => {
+                let decoder = WebPDecoder::new(Cursor::new(data)).map_err(ImgtoolsError::Decode)?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                (decoder.into_frames(), webp_repeat(data))
+            }
+            ImageFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(data)).map_err(ImgtoolsError::Decode)?;
+                if !decoder.is_apng().map_err(ImgtoolsError::Decode)? {
+                    return Ok(None);
+                }
+                let decoder = decoder.apng().map_err(ImgtoolsError::Decode)?;
+                (decoder.into_frames(), apng_repeat(data))
+            }
+            _ => return Ok(None),
+        };
+
+        let frames = frames.collect_frames().map_err(ImgtoolsError::Decode)?;
+        Ok(Some(Animation { frames, repeat }))
+    }
+
+    /// Build an animation that shows each image for `delay`
+    ///
+    /// Smaller images are centered on a transparent canvas the size of the
+    /// largest one.
+    pub fn from_images(images: &[DynamicImage], delay: Delay, repeat: Repeat) -> Self {
+        let width = images.iter().map(|img| img.width()).max().unwrap_or(1);
+        let height = images.iter().map(|img| img.height()).max().unwrap_or(1);
+        let frames = images
+            .iter()
+            .map(|img| {
+                let x = (width - img.width()) / 2;
+                let y = (height - img.height()) / 2;
+                let canvas = pad(img, width, height, (x as i64, y as i64), Rgba([0; 4]));
+                Frame::from_parts(canvas.into_rgba8(), 0, 0, delay)
+            })
+            .collect();
+
+        Animation { frames, repeat }
+    }
+
+    /// Apply a processing command to every frame, keeping the frame delays
+    pub fn apply(self, command: &Command) -> Result<Self, ImgtoolsError> {
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let delay = frame.delay();
+                let img = DynamicImage::ImageRgba8(frame.into_buffer());
+                let img = apply_command(img, command)?;
+                Ok(Frame::from_parts(img.into_rgba8(), 0, 0, delay))
+            })
+            .collect::<Result<Vec<_>, ImgtoolsError>>()?;
+
+        Ok(Animation {
+            frames,
+            repeat: self.repeat,
+        })
+    }
+
+    /// Encode the frames as an animated GIF, WebP or PNG
+    pub fn encode<W: Write>(&self, format: Format, output: W) -> Result<(), ImgtoolsError> {
+        match format {
+            Format::Gif => self.encode_gif(output),
+            Format::WebP => self.encode_webp(output),
+            Format::Png => self.encode_apng(output),
+            format => Err(ImgtoolsError::InvalidArgument(format!(
+                "Animations can't be saved as {}, only as gif, webp or png",
+                format
+            ))),
+        }
+    }
+
+    /// Encode the frames as an animated GIF
+    pub fn encode_gif<W: Write>(&self, output: W) -> Result<(), ImgtoolsError> {
+        let mut encoder = GifEncoder::new(output);
+        // A missing loop extension means the animation plays once
+        let repeat = match self.repeat {
+            Repeat::Finite(0) => None,
+            Repeat::Finite(n) => Some(image::codecs::gif::Repeat::Finite(n)),
+            Repeat::Infinite => Some(image::codecs::gif::Repeat::Infinite),
+        };
+        if let Some(repeat) = repeat {
+            encoder.set_repeat(repeat).map_err(ImgtoolsError::Encode)?;
+        }
+        encoder
+            .encode_frames(self.frames.iter().cloned())
+            .map_err(ImgtoolsError::Encode)
+    }
+
+    /// Encode the frames as an animated PNG
+    pub fn encode_apng<W: Write>(&self, output: W) -> Result<(), ImgtoolsError> {
+        let (width, height) = self.size()?;
+        let png_error = |e: png::EncodingError| {
+            ImgtoolsError::Encode(ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Exact(ImageFormat::Png),
+                e,
+            )))
+        };
+
+        let mut encoder = png::Encoder::new(output, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(self.frames.len() as u32, plays(self.repeat))
+            .map_err(png_error)?;
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        for frame in &self.frames {
+            let delay = delay_ms(frame).min(u16::MAX as u32) as u16;
+            writer.set_frame_delay(delay, 1000).map_err(png_error)?;
+            writer
+                .write_image_data(frame.buffer().as_raw())
+                .map_err(png_error)?;
+        }
+        writer.finish().map_err(png_error)
+    }
+
+    /// Encode the frames as an animated lossless WebP
+    ///
+    /// The WebP encoder only writes still images, so each frame is encoded on
+    /// its own and its bitstream is wrapped in an ANMF chunk.
+    pub fn encode_webp<W: Write>(&self, mut output: W) -> Result<(), ImgtoolsError> {
+        let (width, height) = self.size()?;
+        let has_alpha = self
+            .frames
+            .iter()
+            .any(|frame| frame.buffer().pixels().any(|p| p[3] < 255));
+
+        // VP8X: animation and alpha flags, then the canvas size minus one
+        let mut chunks = Vec::new();
+        let mut vp8x = vec![if has_alpha { 0x12 } else { 0x02 }, 0, 0, 0];
+        vp8x.extend_from_slice(&u24(width - 1));
+        vp8x.extend_from_slice(&u24(height - 1));
+        push_chunk(&mut chunks, b"VP8X", &vp8x);
+
+        // ANIM: transparent background and loop count
+        let loops = plays(self.repeat).min(u16::MAX as u32) as u16;
+        let mut anim = vec![0; 4];
+        anim.extend_from_slice(&loops.to_le_bytes());
+        push_chunk(&mut chunks, b"ANIM", &anim);
+
+        for frame in &self.frames {
+            let mut still = Vec::new();
+            WebPEncoder::new_lossless(&mut still)
+                .encode(frame.buffer(), width, height, ExtendedColorType::Rgba8)
+                .map_err(ImgtoolsError::Encode)?;
+            let bitstream = riff_chunk(&still, b"VP8L").ok_or_else(|| {
+                ImgtoolsError::InvalidArgument("The WebP encoder wrote no lossless frame".into())
+            })?;
+
+            // Frame at the origin covering the canvas, replacing the previous one
+            let mut anmf = [u24(0), u24(0), u24(width - 1), u24(height - 1)].concat();
+            anmf.extend_from_slice(&u24(delay_ms(frame).min(0xFF_FFFF)));
+            anmf.push(0x02);
+            push_chunk(&mut anmf, b"VP8L", bitstream);
+            push_chunk(&mut chunks, b"ANMF", &anmf);
+        }
+
+        let mut riff = b"RIFF".to_vec();
+        riff.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+        riff.extend_from_slice(b"WEBP");
+        riff.extend_from_slice(&chunks);
+        output
+            .write_all(&riff)
+            .map_err(|e| ImgtoolsError::Encode(e.into()))
+    }
+
+    /// Size shared by every frame
+    fn size(&self) -> Result<(u32, u32), ImgtoolsError> {
+        let first = self.frames.first().ok_or_else(|| {
+            ImgtoolsError::InvalidArgument("An animation needs at least one frame".into())
+        })?;
+        let size = first.buffer().dimensions();
+        match self.frames.iter().all(|f| f.buffer().dimensions() == size) {
+            true => Ok(size),
+            false => Err(ImgtoolsError::InvalidArgument(
+                "All frames of an animation must have the same size".into(),
+            )),
+        }
+    }
+}
+
+/// Number of times an animation plays, 0 for forever
+fn plays(repeat: Repeat) -> u32 {
+    match repeat {
+        Repeat::Infinite => 0,
+        Repeat::Finite(n) => n as u32 + 1,
+    }
+}
+
+/// Frame delay in whole milliseconds
+fn delay_ms(frame: &Frame) -> u32 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    (numer as f64 / denom.max(1) as f64).round() as u32
+}
+
+/// Little-endian 24-bit value as used by WebP chunks
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+/// Append a RIFF chunk, padded to an even length
+fn push_chunk(data: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    data.extend_from_slice(fourcc);
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        data.push(0);
+    }
+}
+
+/// Payload of the first chunk with the given name in a RIFF file
+fn riff_chunk<'a>(data: &'a [u8], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let size = u32::from_le_bytes(data[at + 4..at + 8].try_into().ok()?) as usize;
+        let payload = data.get(at + 8..at + 8 + size)?;
+        if &data[at..at + 4] == fourcc {
+            return Some(payload);
+        }
+        at += 8 + size + size % 2;
+    }
+    None
+}
+
+/// Loop count from a number of plays, 0 for forever
+fn repeat(plays: u32) -> Repeat {
+    match plays {
+        0 => Repeat::Infinite,
+        plays => Repeat::Finite((plays - 1).min(u16::MAX as u32) as u16),
+    }
+}
+
+/// Read the loop count of an animated WebP from its ANIM chunk
+fn webp_repeat(data: &[u8]) -> Repeat {
+    match riff_chunk(data, b"ANIM").and_then(|anim| anim.get(4..6)) {
+        Some(loops) => repeat(u16::from_le_bytes([loops[0], loops[1]]) as u32),
+        None => Repeat::Finite(0),
+    }
+}
+
+/// Read the number of plays of an APNG from its acTL chunk
+fn apng_repeat(data: &[u8]) -> Repeat {
+    // Chunks follow the 8 byte signature: length, name, data and CRC
+    let mut at = 8;
+    while let Some(header) = data.get(at..at + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if &header[4..] == b"acTL" {
+            return match data.get(at + 12..at + 16) {
+                Some(plays) => repeat(u32::from_be_bytes([plays[0], plays[1], plays[2], plays[3]])),
+                None => Repeat::Finite(0),
+            };
+        }
+        at += 12 + length;
+    }
+    Repeat::Finite(0)
+}
+
+/// Read the loop count of a GIF, the image decoder doesn't expose it
+fn gif_repeat(data: &[u8]) -> Repeat {
+    let mut options = gif::DecodeOptions::new();
+    options.skip_frame_decoding(true);
+    let Ok(mut decoder) = options.read_info(Cursor::new(data)) else {
+        return Repeat::Finite(0);
+    };
+    // The loop extension may follow the first frames
+    while let Ok(Some(_)) = decoder.read_next_frame() {}
+    decoder.repeat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Filter;
+    use image::{Delay, RgbaImage};
+
+    fn animation(repeat: Repeat) -> Vec<u8> {
+        let frames = (0..3u8).map(|i| {
+            let buffer = RgbaImage::from_pixel(8, 6, image::Rgba([i * 80, 0, 0, 255]));
+            Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(100, 1))
+        });
+
+        let mut data = Vec::new();
+        let mut encoder = GifEncoder::new(&mut data);
+        let repeat = match repeat {
+            Repeat::Finite(n) => image::codecs::gif::Repeat::Finite(n),
+            Repeat::Infinite => image::codecs::gif::Repeat::Infinite,
+        };
+        encoder.set_repeat(repeat).unwrap();
+        encoder.encode_frames(frames).unwrap();
+        drop(encoder);
+        data
+    }
+
+    #[test]
+    fn test_resize_keeps_frames_delays_and_loop_count() {
+        let data = animation(Repeat::Finite(3));
+        let animation = Animation::decode_gif(&data).unwrap();
+        assert_eq!(animation.frames.len(), 3);
+        assert_eq!(animation.repeat, Repeat::Finite(3));
+
+        let command = Command::Resize {
+            width: Some(4),
+            height: Some(3),
+            exact: true,
+            filter: Filter::Nearest,
+            scale: None,
+            fast: false,
+        };
+        let animation = animation.apply(&command).unwrap();
+        let mut encoded = Vec::new();
+        animation.encode_gif(&mut encoded).unwrap();
+
+        let decoded = Animation::decode_gif(&encoded).unwrap();
+        assert_eq!(decoded.frames.len(), 3);
+        assert_eq!(decoded.repeat, Repeat::Finite(3));
+        for frame in &decoded.frames {
+            assert_eq!(frame.buffer().dimensions(), (4, 3));
+            assert_eq!(frame.delay(), Delay::from_numer_denom_ms(100, 1));
+        }
+    }
+
+    #[test]
+    fn test_encode_images_as_webp_and_apng() {
+        let images = [
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]))),
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 255, 255]))),
+        ];
+        let delay = Delay::from_numer_denom_ms(250, 1);
+        let animation = Animation::from_images(&images, delay, Repeat::Finite(1));
+
+        let mut webp = Vec::new();
+        animation.encode(Format::WebP, &mut webp).unwrap();
+        let decoded = Animation::decode(&webp, None).unwrap();
+        assert_eq!(decoded.repeat, Repeat::Finite(1));
+        let frames = decoded.frames;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].delay(), delay);
+        // The small frame is centered on a transparent canvas
+        let second = frames[1].buffer();
+        assert_eq!(second.get_pixel(0, 0)[3], 0);
+        assert_eq!(second.get_pixel(1, 1), &image::Rgba([0, 0, 255, 255]));
+
+        let mut apng = Vec::new();
+        animation.encode(Format::Png, &mut apng).unwrap();
+        let decoded = Animation::decode(&apng, Some(ImageFormat::Png)).unwrap();
+        assert_eq!(decoded.repeat, Repeat::Finite(1));
+        let frames = decoded.frames;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].delay(), delay);
+        assert_eq!(
+            frames[0].buffer().get_pixel(3, 3),
+            &image::Rgba([255, 0, 0, 255])
+        );
+
+        assert!(animation.encode(Format::Jpeg, &mut Vec::new()).is_err());
+
+        // Still images are a single frame
+        let mut still = Vec::new();
+        images[1]
+            .write_to(&mut Cursor::new(&mut still), ImageFormat::Png)
+            .unwrap();
+        assert!(
+            Animation::decode_animated(&still, ImageFormat::Png)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(Animation::decode(&still, None).unwrap().frames.len(), 1);
+    }
+
+    #[test]
+    fn test_infinite_loop_is_preserved() {
+        let data = animation(Repeat::Infinite);
+        let animation = Animation::decode_gif(&data).unwrap();
+        assert_eq!(animation.repeat, Repeat::Infinite);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::Shape;
+use crate::fonts::Fonts;
+use crate::process::with_color_type;
+use ab_glyph::PxScale;
+use image::{ColorType, DynamicImage, Rgba, RgbaImage, imageops};
+use imageproc::drawing::{
+    draw_antialiased_polygon_mut, draw_filled_circle_mut, draw_filled_rect_mut,
+    draw_line_segment_mut,
+};
+use imageproc::point::Point;
+use imageproc::rect::Rect;
+
+/// Draw the shapes over the image in order
+///
+/// Each shape is drawn on a layer of its own and laid over the image, so
+/// translucent colors show what is under them. Grayscale images become RGB
+/// to show the colors.
+pub fn annotate(img: &DynamicImage, shapes: &[Shape], fonts: &Fonts) -> DynamicImage {
+    let mut canvas = img.to_rgba8();
+    let (w, h) = canvas.dimensions();
+    for shape in shapes {
+        if let Shape::Text {
+            at,
+            ref text,
+            color,
+            size,
+        } = *shape
+        {
+            fonts.draw_text_mut(&mut canvas, color.into(), at, PxScale::from(size), text);
+            continue;
+        }
+        let mut layer = RgbaImage::new(w, h);
+        draw_shape(&mut layer, shape);
+        imageops::overlay(&mut canvas, &layer, 0, 0);
+    }
+    let color = match img.color() {
+        ColorType::L8 | ColorType::L16 => ColorType::Rgb8,
+        ColorType::La8 | ColorType::La16 => ColorType::Rgba8,
+        color => color,
+    };
+    with_color_type(canvas, color)
+}
+
+/// Draw a shape other than text on an empty layer
+fn draw_shape(layer: &mut RgbaImage, shape: &Shape) {
+    let clear = Rgba([0, 0, 0, 0]);
+    match *shape {
+        Shape::Line {
+            from,
+            to,
+            color,
+            width,
+        } => draw_thick_line(layer, point(from), point(to), color.into(), width),
+        Shape::Arrow {
+            from,
+            to,
+            color,
+            width,
+        } => {
+            let (from, to) = (point(from), point(to));
+            let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+            let length = dx.hypot(dy);
+            if length == 0.0 {
+                return;
+            }
+            let (ux, uy) = (dx / length, dy / length);
+            // The head grows with the line width, but never past the start of the arrow
+            let head = (width.max(1) as f32 * 4.0).max(12.0).min(length);
+            let base = (to.0 - ux * head, to.1 - uy * head);
+            let half = head * 0.5;
+            // The shaft reaches a little into the head so no gap shows between them
+            let shaft_end = (base.0 + ux, base.1 + uy);
+            draw_thick_line(layer, from, shaft_end, color.into(), width);
+            let head = [
+                to,
+                (base.0 - uy * half, base.1 + ux * half),
+                (base.0 + uy * half, base.1 - ux * half),
+            ];
+            fill_polygon(layer, &head, color.into());
+        }
+        Shape::Rect {
+            at: (x, y),
+            size: (rect_w, rect_h),
+            color,
+            width,
+        } => {
+            if rect_w == 0 || rect_h == 0 {
+                return;
+            }
+            draw_filled_rect_mut(layer, Rect::at(x, y).of_size(rect_w, rect_h), color.into());
+            // The outline is what is left after clearing the inside
+            if width > 0 && rect_w > 2 * width && rect_h > 2 * width {
+                let inner = Rect::at(x + width as i32, y + width as i32)
+                    .of_size(rect_w - 2 * width, rect_h - 2 * width);
+                draw_filled_rect_mut(layer, inner, clear);
+            }
+        }
+        Shape::Circle {
+            center,
+            radius,
+            color,
+            width,
+        } => {
+            draw_filled_circle_mut(layer, center, radius as i32, color.into());
+            if width > 0 && radius > width {
+                draw_filled_circle_mut(layer, center, (radius - width) as i32, clear);
+            }
+        }
+        Shape::Text { .. } => {}
+    }
+}
+
+fn point((x, y): (i32, i32)) -> (f32, f32) {
+    (x as f32, y as f32)
+}
+
+/// Draw a line `width` pixels wide as a rectangle along it
+fn draw_thick_line(
+    layer: &mut RgbaImage,
+    from: (f32, f32),
+    to: (f32, f32),
+    color: Rgba<u8>,
+    width: u32,
+) {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length = dx.hypot(dy);
+    if width <= 1 || length == 0.0 {
+        draw_line_segment_mut(layer, from, to, color);
+        return;
+    }
+    let half = width as f32 / 2.0;
+    let (nx, ny) = (-dy / length * half, dx / length * half);
+    let corners = [
+        (from.0 + nx, from.1 + ny),
+        (to.0 + nx, to.1 + ny),
+        (to.0 - nx, to.1 - ny),
+        (from.0 - nx, from.1 - ny),
+    ];
+    fill_polygon(layer, &corners, color);
+}
+
+/// Fill a polygon with anti-aliased edges
+fn fill_polygon(layer: &mut RgbaImage, corners: &[(f32, f32)], color: Rgba<u8>) {
+    let mut points: Vec<Point<i32>> = corners
+        .iter()
+        .map(|&(x, y)| Point::new(x.round() as i32, y.round() as i32))
+        .collect();
+    points.dedup();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    if points.len() < 3 {
+        if let [a, b] = points[..] {
+            draw_line_segment_mut(layer, point((a.x, a.y)), point((b.x, b.y)), color);
+        }
+        return;
+    }
+    // The layer only ever holds this color, edges just get partial coverage
+    draw_antialiased_polygon_mut(layer, &points, color, |color, under, weight| {
+        let alpha = (color[3] as f32 * weight).round() as u8;
+        Rgba([color[0], color[1], color[2], alpha.max(under[3])])
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Color;
+
+    fn blank() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(100, 100, image::Rgb([255; 3])))
+    }
+
+    #[test]
+    fn test_annotate_shapes() {
+        let shapes: Vec<Shape> = [
+            "rect(10,10,30,20)",
+            "circle(70,70,10,blue,0)",
+            "line(0,99,99,99,black,1)",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let img = annotate(&blank(), &shapes, &Fonts::load(None).unwrap());
+        assert_eq!(img.color(), ColorType::Rgb8);
+        let img = img.to_rgb8();
+        // The outline is 3 pixels wide inside the rectangle
+        assert_eq!(img.get_pixel(10, 10).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(12, 20).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(13, 20).0, [255, 255, 255]);
+        assert_eq!(img.get_pixel(39, 29).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(40, 30).0, [255, 255, 255]);
+        // Width 0 fills the circle
+        assert_eq!(img.get_pixel(70, 70).0, [0, 0, 255]);
+        assert_eq!(img.get_pixel(50, 99).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_annotate_arrow_and_text() {
+        let fonts = Fonts::load(None).unwrap();
+        let arrow = Shape::Arrow {
+            from: (10, 50),
+            to: (90, 50),
+            color: Color::Rgba(0, 0, 0, 128),
+            width: 4,
+        };
+        let img = annotate(&blank(), &[arrow], &fonts).to_rgb8();
+        // Translucent black over white
+        assert_eq!(img.get_pixel(30, 50).0, [127, 127, 127]);
+        assert_eq!(img.get_pixel(30, 56).0, [255, 255, 255]);
+        // The head is wider than the shaft
+        assert!(img.get_pixel(78, 55)[0] < 200);
+
+        let text = "text(5,5,\"Hi, there\",blue,30)".parse().unwrap();
+        let gray =
+            DynamicImage::ImageLuma8(image::GrayImage::from_pixel(100, 50, image::Luma([255])));
+        let img = annotate(&gray, &[text], &fonts);
+        assert_eq!(img.color(), ColorType::Rgb8);
+        assert!(img.to_rgb8().pixels().any(|p| p[2] > 200 && p[0] < 50));
+    }
+}
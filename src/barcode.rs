@@ -0,0 +1,879 @@
+use crate::error::ImgtoolsError;
+use clap::ValueEnum;
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+
+/// Kind of code the qrcode command draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Symbology {
+    Qr,
+    Code128,
+    Ean13,
+}
+
+/// Share of a QR code that can be damaged and still read: about 7%, 15%, 25% and 30%
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EcLevel {
+    L,
+    M,
+    Q,
+    H,
+}
+
+/// Dark modules of a code, row by row, a barcode has a single row
+pub type Modules = Vec<Vec<bool>>;
+
+/// Encode text as a code of the symbology
+pub fn encode_code(
+    text: &str,
+    symbology: Symbology,
+    ec: EcLevel,
+) -> Result<Modules, ImgtoolsError> {
+    match symbology {
+        Symbology::Qr => qr(text, ec),
+        Symbology::Code128 => code128(text).map(|row| vec![row]),
+        Symbology::Ean13 => ean13(text).map(|row| vec![row]),
+    }
+}
+
+/// Look of a rendered code
+#[derive(Debug, Clone, Copy)]
+pub struct CodeStyle {
+    /// Width of a module in pixels
+    pub module: u32,
+    /// Blank modules around the code
+    pub quiet_zone: u32,
+    /// Height of the bars of a barcode in pixels
+    pub bar_height: u32,
+    pub color: Rgba<u8>,
+    pub background: Rgba<u8>,
+}
+
+/// Draw the modules of a code, a single row is stretched into bars
+pub fn render_code(modules: &Modules, style: &CodeStyle) -> RgbaImage {
+    let module = style.module.max(1);
+    let columns = modules.first().map_or(0, Vec::len) as u32;
+    let row_height = match modules.len() {
+        1 => style.bar_height.max(1),
+        _ => module,
+    };
+    let margin = style.quiet_zone * module;
+    let width = columns * module + 2 * margin;
+    let height = modules.len() as u32 * row_height + 2 * margin;
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (Some(x), Some(y)) = (x.checked_sub(margin), y.checked_sub(margin)) else {
+            return style.background;
+        };
+        let dark = modules
+            .get((y / row_height) as usize)
+            .and_then(|row| row.get((x / module) as usize))
+            .copied()
+            .unwrap_or(false);
+        match dark {
+            true => style.color,
+            false => style.background,
+        }
+    })
+}
+
+/// Put a logo in the middle of a rendered QR code, on a patch of the background
+///
+/// The logo covers about a fifth of the code's width, little enough for
+/// error correction level H, and usually Q, to read past it.
+pub fn overlay_logo(code: &mut RgbaImage, logo: &DynamicImage, style: &CodeStyle) {
+    let margin = style.quiet_zone * style.module;
+    let side = code.width().saturating_sub(2 * margin);
+    let limit = (side / 5).max(1);
+    let logo = logo
+        .resize(limit, limit, imageops::FilterType::Lanczos3)
+        .to_rgba8();
+    let pad = style.module;
+    let patch = RgbaImage::from_pixel(
+        logo.width() + 2 * pad,
+        logo.height() + 2 * pad,
+        style.background,
+    );
+    let x = (code.width() as i64 - patch.width() as i64) / 2;
+    let y = (code.height() as i64 - patch.height() as i64) / 2;
+    imageops::overlay(code, &patch, x, y);
+    imageops::overlay(code, &logo, x + pad as i64, y + pad as i64);
+}
+
+// QR codes follow ISO/IEC 18004, with the module placement of its annexes
+
+/// Error correction codewords in each block, by level and version
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 4] = [
+    [
+        0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28,
+        30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28,
+        28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    ],
+    [
+        0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30,
+        30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24,
+        30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+];
+
+/// Error correction blocks the codewords are split into, by level and version
+const ECC_BLOCKS: [[u8; 41]; 4] = [
+    [
+        0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13,
+        14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25,
+    ],
+    [
+        0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21,
+        23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+    ],
+    [
+        0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29,
+        34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68,
+    ],
+    [
+        0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32,
+        35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81,
+    ],
+];
+
+/// Characters of the alphanumeric mode, in the order of their values
+const ALPHANUMERIC: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+impl EcLevel {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The level's two bits in the format information
+    fn format_bits(self) -> u32 {
+        match self {
+            EcLevel::L => 1,
+            EcLevel::M => 0,
+            EcLevel::Q => 3,
+            EcLevel::H => 2,
+        }
+    }
+}
+
+/// Encoding of the data, the most compact one the whole text fits
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Numeric,
+    Alphanumeric,
+    Byte,
+}
+
+impl Mode {
+    fn of(text: &str) -> Mode {
+        if text.bytes().all(|b| b.is_ascii_digit()) {
+            Mode::Numeric
+        } else if text.bytes().all(|b| ALPHANUMERIC.contains(&b)) {
+            Mode::Alphanumeric
+        } else {
+            Mode::Byte
+        }
+    }
+
+    fn indicator(self) -> u32 {
+        match self {
+            Mode::Numeric => 1,
+            Mode::Alphanumeric => 2,
+            Mode::Byte => 4,
+        }
+    }
+
+    /// Bits of the character count, which grow with the version
+    fn count_bits(self, version: usize) -> usize {
+        let size = match version {
+            1..=9 => 0,
+            10..=26 => 1,
+            _ => 2,
+        };
+        match self {
+            Mode::Numeric => [10, 12, 14][size],
+            Mode::Alphanumeric => [9, 11, 13][size],
+            Mode::Byte => [8, 16, 16][size],
+        }
+    }
+}
+
+/// Bits appended most significant first
+#[derive(Default)]
+struct BitBuffer(Vec<bool>);
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, bits: usize) {
+        self.0
+            .extend((0..bits).rev().map(|i| (value >> i) & 1 == 1));
+    }
+}
+
+/// Bits of the data itself, without the mode and count
+fn data_bits(text: &str, mode: Mode) -> BitBuffer {
+    let mut bits = BitBuffer::default();
+    let bytes = text.as_bytes();
+    match mode {
+        Mode::Numeric => {
+            for group in bytes.chunks(3) {
+                let value = group.iter().fold(0, |n, b| n * 10 + (b - b'0') as u32);
+                bits.push(value, group.len() * 3 + 1);
+            }
+        }
+        Mode::Alphanumeric => {
+            let value = |b: &u8| ALPHANUMERIC.iter().position(|c| c == b).unwrap_or(0) as u32;
+            for pair in bytes.chunks(2) {
+                match pair {
+                    [a, b] => bits.push(value(a) * 45 + value(b), 11),
+                    [a] => bits.push(value(a), 6),
+                    _ => unreachable!(),
+                }
+            }
+        }
+        Mode::Byte => bytes.iter().for_each(|&b| bits.push(b as u32, 8)),
+    }
+    bits
+}
+
+/// Modules of a version left for data and error correction, in bits
+fn raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+/// Data codewords of a version at a level
+fn data_codewords(version: usize, ec: EcLevel) -> usize {
+    raw_data_modules(version) / 8
+        - ECC_CODEWORDS_PER_BLOCK[ec.index()][version] as usize
+            * ECC_BLOCKS[ec.index()][version] as usize
+}
+
+/// Data codewords for the text in the smallest version it fits
+fn qr_data(text: &str, ec: EcLevel) -> Result<(usize, Vec<u8>), ImgtoolsError> {
+    let mode = Mode::of(text);
+    let data = data_bits(text, mode);
+    let count = match mode {
+        Mode::Byte => text.len(),
+        _ => text.chars().count(),
+    };
+    let version = (1..=40)
+        .find(|&v| {
+            count < 1 << mode.count_bits(v)
+                && 4 + mode.count_bits(v) + data.0.len() <= data_codewords(v, ec) * 8
+        })
+        .ok_or_else(|| {
+            ImgtoolsError::InvalidArgument(format!(
+                "{} bytes of text are too many for a QR code at level {ec:?}",
+                text.len()
+            ))
+        })?;
+
+    let mut bits = BitBuffer::default();
+    bits.push(mode.indicator(), 4);
+    bits.push(count as u32, mode.count_bits(version));
+    bits.0.extend(data.0);
+    let capacity = data_codewords(version, ec) * 8;
+    // Terminator, then zeros up to a whole byte
+    bits.push(0, (capacity - bits.0.len()).min(4));
+    bits.push(0, (8 - bits.0.len() % 8) % 8);
+    let mut codewords: Vec<u8> = bits
+        .0
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |n, &bit| n << 1 | bit as u8))
+        .collect();
+    // Alternating pad bytes fill the rest
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity / 8 {
+            break;
+        }
+        codewords.push(pad);
+    }
+    Ok((version, codewords))
+}
+
+/// Product in GF(256) with the QR polynomial x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+/// Reed-Solomon generator polynomial of a degree, without its leading term
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0; degree];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+/// Error correction codewords of a block
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; divisor.len()];
+    for &b in data {
+        let factor = b ^ result.remove(0);
+        result.push(0);
+        for (r, &d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(d, factor);
+        }
+    }
+    result
+}
+
+/// Split the data into blocks, add their error correction and interleave them all
+fn add_error_correction(data: &[u8], version: usize, ec: EcLevel) -> Vec<u8> {
+    let blocks = ECC_BLOCKS[ec.index()][version] as usize;
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[ec.index()][version] as usize;
+    let raw = raw_data_modules(version) / 8;
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks;
+    let divisor = rs_divisor(ecc_len);
+
+    let mut rest = data;
+    let split: Vec<(&[u8], Vec<u8>)> = (0..blocks)
+        .map(|i| {
+            let len = short_len - ecc_len + usize::from(i >= short_blocks);
+            let (block, tail) = rest.split_at(len);
+            rest = tail;
+            (block, rs_remainder(block, &divisor))
+        })
+        .collect();
+
+    let mut result = Vec::with_capacity(raw);
+    for i in 0..=short_len - ecc_len {
+        result.extend(split.iter().filter_map(|(block, _)| block.get(i)));
+    }
+    for i in 0..ecc_len {
+        result.extend(split.iter().map(|(_, ecc)| ecc[i]));
+    }
+    result
+}
+
+/// A QR code being laid out, modules indexed `[y][x]`
+struct QrGrid {
+    size: usize,
+    dark: Vec<Vec<bool>>,
+    function: Vec<Vec<bool>>,
+}
+
+impl QrGrid {
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        QrGrid {
+            size,
+            dark: vec![vec![false; size]; size],
+            function: vec![vec![false; size]; size],
+        }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.dark[y][x] = dark;
+        self.function[y][x] = true;
+    }
+
+    /// Finders, separators, timing and alignment patterns, with room reserved for the format
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i32..=4 {
+                for dx in -4i32..=4 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &cx) in positions.iter().enumerate() {
+            for (j, &cy) in positions.iter().enumerate() {
+                // The corners with finders get no alignment pattern
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2i32..=2 {
+                    for dx in -2i32..=2 {
+                        let (x, y) = ((cx as i32 + dx) as usize, (cy as i32 + dy) as usize);
+                        self.set_function(x, y, dx.abs().max(dy.abs()) != 1);
+                    }
+                }
+            }
+        }
+        self.draw_format(EcLevel::M, 0);
+        self.draw_version(version);
+    }
+
+    /// Two copies of the level and mask, with their BCH error correction
+    fn draw_format(&mut self, ec: EcLevel, mask: u32) {
+        let bits = format_bits(ec, mask);
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        // Always dark
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Two copies of the version from version 7 on
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+        let bits = version_bits(version);
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 == 1;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Fill the codewords in, two columns at a time zigzagging up and down from the right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let total = codewords.len() * 8;
+        let mut i = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            // The vertical timing pattern is skipped
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.function[y][x] && i < total {
+                        self.dark[y][x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    /// Flip the data modules where the mask's pattern says so, masking twice undoes it
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if flip && !self.function[y][x] {
+                    self.dark[y][x] = !self.dark[y][x];
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to read, the lowest scoring mask is used
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let lines = (0..size).flat_map(|i| {
+            let row: Vec<bool> = (0..size).map(|j| self.dark[i][j]).collect();
+            let column: Vec<bool> = (0..size).map(|j| self.dark[j][i]).collect();
+            [row, column]
+        });
+        for line in lines {
+            // Runs of five or more modules of one color
+            for run in line.chunk_by(|a, b| a == b).map(<[bool]>::len) {
+                if run >= 5 {
+                    penalty += run - 2;
+                }
+            }
+            // Patterns that look like a finder
+            let finder = [true, false, true, true, true, false, true];
+            for start in 0..size.saturating_sub(10) {
+                let window = &line[start..start + 11];
+                let before = window[..4].iter().all(|&d| !d) && window[4..] == finder;
+                let after = window[..7] == finder && window[7..].iter().all(|&d| !d);
+                if before || after {
+                    penalty += 40;
+                }
+            }
+        }
+        // Blocks of 2x2 modules of one color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.dark[y][x];
+                if self.dark[y][x + 1] == color
+                    && self.dark[y + 1][x] == color
+                    && self.dark[y + 1][x + 1] == color
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        // Every 5% the dark share is away from half
+        let dark = self.dark.iter().flatten().filter(|&&d| d).count();
+        let total = size * size;
+        let k = (dark * 20)
+            .abs_diff(total * 10)
+            .div_ceil(total)
+            .saturating_sub(1);
+        penalty + k * 10
+    }
+}
+
+/// Centers of the alignment patterns along each axis
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let size = version * 4 + 17;
+    let step = match version {
+        32 => 26,
+        _ => (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2,
+    };
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// The 15 format bits of a level and mask
+fn format_bits(ec: EcLevel, mask: u32) -> u32 {
+    let data = ec.format_bits() << 3 | mask;
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    (data << 10 | rem) ^ 0x5412
+}
+
+/// The 18 version bits of version 7 and up
+fn version_bits(version: usize) -> u32 {
+    let mut rem = version as u32;
+    for _ in 0..12 {
+        rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+    }
+    (version as u32) << 12 | rem
+}
+
+/// Encode text as a QR code, in the smallest version that holds it
+fn qr(text: &str, ec: EcLevel) -> Result<Modules, ImgtoolsError> {
+    let (version, data) = qr_data(text, ec)?;
+    let codewords = add_error_correction(&data, version, ec);
+    let mut grid = QrGrid::new(version);
+    grid.draw_function_patterns(version);
+    grid.draw_codewords(&codewords);
+
+    let mut best = (usize::MAX, 0);
+    for mask in 0..8 {
+        grid.apply_mask(mask);
+        grid.draw_format(ec, mask);
+        best = best.min((grid.penalty(), mask));
+        grid.apply_mask(mask);
+    }
+    grid.apply_mask(best.1);
+    grid.draw_format(ec, best.1);
+    Ok(grid.dark)
+}
+
+/// Bar and space widths of the Code 128 symbols, in modules
+const CODE128: [&str; 107] = [
+    "212222", "222122", "222221", "121223", "121322", "131222", "122213", "122312", "132212",
+    "221213", "221312", "231212", "112232", "122132", "122231", "113222", "123122", "123221",
+    "223211", "221132", "221231", "213212", "223112", "312131", "311222", "321122", "321221",
+    "312212", "322112", "322211", "212123", "212321", "232121", "111323", "131123", "131321",
+    "112313", "132113", "132311", "211313", "231113", "231311", "112133", "112331", "132131",
+    "113123", "113321", "133121", "313121", "211331", "231131", "213113", "213311", "213131",
+    "311123", "311321", "331121", "312113", "312311", "332111", "314111", "221411", "431111",
+    "111224", "111422", "121124", "121421", "141122", "141221", "112214", "112412", "122114",
+    "122411", "142112", "142211", "241211", "221114", "413111", "241112", "134111", "111242",
+    "121142", "121241", "114212", "124112", "124211", "411212", "421112", "421211", "212141",
+    "214121", "412121", "111143", "111341", "131141", "114113", "114311", "411113", "411311",
+    "113141", "114131", "311141", "411131", "211412", "211214", "211232", "2331112",
+];
+
+const CODE128_START_B: usize = 104;
+const CODE128_START_C: usize = 105;
+const CODE128_STOP: usize = 106;
+
+/// Encode printable ASCII as Code 128
+///
+/// An even number of digits uses code set C, two digits a symbol, anything
+/// else code set B.
+fn code128(text: &str) -> Result<Vec<bool>, ImgtoolsError> {
+    if text.is_empty() {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Code 128 needs some text".into(),
+        ));
+    }
+    let digits = text.len().is_multiple_of(2) && text.bytes().all(|b| b.is_ascii_digit());
+    let mut symbols = match digits {
+        true => {
+            let pairs = text.as_bytes().chunks(2);
+            let mut symbols = vec![CODE128_START_C];
+            symbols.extend(pairs.map(|p| ((p[0] - b'0') * 10 + p[1] - b'0') as usize));
+            symbols
+        }
+        false => {
+            let mut symbols = vec![CODE128_START_B];
+            for c in text.chars() {
+                match c {
+                    ' '..='~' => symbols.push(c as usize - 32),
+                    _ => {
+                        return Err(ImgtoolsError::InvalidArgument(format!(
+                            "Code 128 holds printable ASCII only, not {c:?}"
+                        )));
+                    }
+                }
+            }
+            symbols
+        }
+    };
+    let checksum = symbols
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| i.max(1) * s)
+        .sum::<usize>()
+        % 103;
+    symbols.push(checksum);
+    symbols.push(CODE128_STOP);
+
+    let mut modules = Vec::new();
+    for symbol in symbols {
+        for (i, width) in CODE128[symbol].bytes().enumerate() {
+            modules.extend(std::iter::repeat_n(i % 2 == 0, (width - b'0') as usize));
+        }
+    }
+    Ok(modules)
+}
+
+/// Left-hand digits with odd parity, the right-hand ones are their inverse
+const EAN_L: [u8; 10] = [
+    0b0001101, 0b0011001, 0b0010011, 0b0111101, 0b0100011, 0b0110001, 0b0101111, 0b0111011,
+    0b0110111, 0b0001011,
+];
+
+/// Which left-hand digits use even parity, set by the first digit
+const EAN_PARITY: [u8; 10] = [
+    0b000000, 0b001011, 0b001101, 0b001110, 0b010011, 0b011001, 0b011100, 0b010101, 0b010110,
+    0b011010,
+];
+
+/// Check digit of the first twelve digits of an EAN-13
+fn ean_check_digit(digits: &[u8]) -> u8 {
+    let sum: u32 = digits
+        .iter()
+        .enumerate()
+        .map(|(i, &d)| d as u32 * if i % 2 == 0 { 1 } else { 3 })
+        .sum();
+    ((10 - sum % 10) % 10) as u8
+}
+
+/// Encode 12 digits, or 13 with the check digit, as an EAN-13
+fn ean13(text: &str) -> Result<Vec<bool>, ImgtoolsError> {
+    let invalid = |message: String| ImgtoolsError::InvalidArgument(message);
+    if !text.bytes().all(|b| b.is_ascii_digit()) || !(12..=13).contains(&text.len()) {
+        return Err(invalid(format!(
+            "EAN-13 takes 12 or 13 digits, not {text:?}"
+        )));
+    }
+    let mut digits: Vec<u8> = text.bytes().map(|b| b - b'0').collect();
+    let check = ean_check_digit(&digits[..12]);
+    match digits.get(12) {
+        Some(&given) if given != check => {
+            return Err(invalid(format!(
+                "The check digit of {text} should be {check}"
+            )));
+        }
+        Some(_) => {}
+        None => digits.push(check),
+    }
+
+    let mut modules = Vec::with_capacity(95);
+    let mut push = |pattern: u8, bits: usize| {
+        modules.extend((0..bits).rev().map(|i| (pattern >> i) & 1 == 1));
+    };
+    push(0b101, 3);
+    let parity = EAN_PARITY[digits[0] as usize];
+    for (i, &d) in digits[1..7].iter().enumerate() {
+        let l = EAN_L[d as usize];
+        match (parity >> (5 - i)) & 1 {
+            // Even parity is the right-hand pattern read backwards
+            1 => push((!l & 0x7F).reverse_bits() >> 1, 7),
+            _ => push(l, 7),
+        }
+    }
+    push(0b01010, 5);
+    for &d in &digits[7..] {
+        push(!EAN_L[d as usize] & 0x7F, 7);
+    }
+    push(0b101, 3);
+    Ok(modules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(modules: &[bool]) -> String {
+        modules.iter().map(|&d| if d { '1' } else { '0' }).collect()
+    }
+
+    #[test]
+    fn test_qr_data() {
+        // The worked example of "HELLO WORLD" at 1-M
+        let (version, data) = qr_data("HELLO WORLD", EcLevel::M).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(
+            data,
+            [
+                32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17
+            ]
+        );
+        let ecc = rs_remainder(&data, &rs_divisor(10));
+        assert_eq!(ecc, [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+
+        assert_eq!(Mode::of("01234"), Mode::Numeric);
+        assert_eq!(Mode::of("https://example.com"), Mode::Byte);
+        let (version, _) = qr_data(&"a".repeat(100), EcLevel::H).unwrap();
+        assert_eq!(version, 10);
+        assert!(qr_data(&"a".repeat(3000), EcLevel::L).is_err());
+    }
+
+    #[test]
+    fn test_qr_tables() {
+        for ec in [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H] {
+            // Each block keeps at least one data codeword
+            for (version, &blocks) in ECC_BLOCKS[ec.index()].iter().enumerate().skip(1) {
+                assert!(data_codewords(version, ec) >= blocks as usize);
+            }
+        }
+        assert_eq!(data_codewords(1, EcLevel::L), 19);
+        assert_eq!(data_codewords(40, EcLevel::H), 1276);
+        assert_eq!(format_bits(EcLevel::M, 0), 0b101010000010010);
+        assert_eq!(format_bits(EcLevel::H, 7), 0b000100000111011);
+        assert_eq!(version_bits(7), 0b000111110010010100);
+        assert_eq!(alignment_positions(7), [6, 22, 38]);
+        assert_eq!(alignment_positions(32), [6, 34, 60, 86, 112, 138]);
+    }
+
+    #[test]
+    fn test_qr() {
+        let code = qr("https://example.com", EcLevel::M).unwrap();
+        assert_eq!(code.len(), 25);
+        // Finders in three corners with their separators
+        assert!(code[0][0] && code[0][6] && code[6][0] && code[3][3]);
+        assert!(!code[1][1] && !code[7][7]);
+        assert!(code[24][0] && code[0][24]);
+        // The alignment pattern of version 2
+        assert!(code[18][18] && !code[17][18] && code[16][18]);
+        assert!(code[25 - 8][8]);
+        assert_eq!(qr(&"9".repeat(500), EcLevel::Q).unwrap().len(), 17 + 4 * 13);
+    }
+
+    #[test]
+    fn test_code128() {
+        assert!(
+            CODE128[..106]
+                .iter()
+                .all(|s| s.bytes().map(|b| b - b'0').sum::<u8>() == 11)
+        );
+        let modules = code128("Hi").unwrap();
+        // Start, two characters, checksum and the longer stop
+        assert_eq!(modules.len(), 11 * 4 + 13);
+        assert_eq!(bits(&modules[..11]), "11010010000");
+        assert_eq!(bits(&modules[modules.len() - 13..]), "1100011101011");
+        // Digits are packed in pairs
+        assert_eq!(code128("123456").unwrap().len(), 11 * 5 + 13);
+        assert!(code128("héllo").is_err());
+        assert!(code128("").is_err());
+    }
+
+    #[test]
+    fn test_ean13() {
+        assert_eq!(ean_check_digit(&[4, 0, 0, 6, 3, 8, 1, 3, 3, 3, 9, 3]), 1);
+        let modules = ean13("400638133393").unwrap();
+        assert_eq!(modules, ean13("4006381333931").unwrap());
+        assert_eq!(modules.len(), 95);
+        // 4 sets the parity LGLLGG, so the first digit 0 is odd and the second even
+        assert_eq!(bits(&modules[..17]), "10100011010100111");
+        assert_eq!(bits(&modules[92..]), "101");
+        assert!(ean13("4006381333932").is_err());
+        assert!(ean13("12345").is_err());
+    }
+
+    #[test]
+    fn test_render_code() {
+        let style = CodeStyle {
+            module: 2,
+            quiet_zone: 4,
+            bar_height: 30,
+            color: Rgba([0, 0, 0, 255]),
+            background: Rgba([255, 255, 255, 255]),
+        };
+        let code = encode_code("hello", Symbology::Qr, EcLevel::M).unwrap();
+        let mut img = render_code(&code, &style);
+        assert_eq!(img.dimensions(), (29 * 2, 29 * 2));
+        assert_eq!(img.get_pixel(7, 7).0, [255, 255, 255, 255]);
+        assert_eq!(img.get_pixel(8, 8).0, [0, 0, 0, 255]);
+
+        let logo = DynamicImage::ImageRgba8(RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 255])));
+        overlay_logo(&mut img, &logo, &style);
+        assert_eq!(img.get_pixel(29, 29).0, [255, 0, 0, 255]);
+
+        let bars = encode_code("4006381333931", Symbology::Ean13, EcLevel::M).unwrap();
+        let img = render_code(&bars, &style);
+        assert_eq!(img.dimensions(), ((95 + 8) * 2, 30 + 16));
+        assert_eq!(img.get_pixel(8, 8).0, [0, 0, 0, 255]);
+        assert_eq!(img.get_pixel(10, 37).0, [255, 255, 255, 255]);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{ImgtoolsError, ReportFormat};
+use image::DynamicImage;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/// Timings of an operation run repeatedly on one image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Benchmark {
+    /// Wall time of every timed run, in order
+    pub runs: Vec<Duration>,
+    /// Untimed runs before them
+    pub warmup: usize,
+    /// Pixels of the input, in millions
+    pub megapixels: f64,
+    /// Peak resident memory of the whole process, where the platform reports it
+    pub peak_memory: Option<u64>,
+    /// Size of the encoded result of the last run, when the runs encode
+    pub encoded_bytes: Option<u64>,
+}
+
+impl Benchmark {
+    /// Run the operation `warmup` times, then time it `runs` times
+    ///
+    /// Every run gets its own copy of the image, copied before the clock
+    /// starts. The operation returns the encoded size if it encodes.
+    pub fn measure(
+        img: &DynamicImage,
+        runs: usize,
+        warmup: usize,
+        mut run: impl FnMut(DynamicImage) -> Result<Option<u64>, ImgtoolsError>,
+    ) -> Result<Self, ImgtoolsError> {
+        for _ in 0..warmup {
+            run(img.clone())?;
+        }
+        let mut times = Vec::with_capacity(runs);
+        let mut encoded_bytes = None;
+        for _ in 0..runs {
+            let input = img.clone();
+            let start = Instant::now();
+            encoded_bytes = run(input)?;
+            times.push(start.elapsed());
+        }
+        Ok(Benchmark {
+            runs: times,
+            warmup,
+            megapixels: img.width() as f64 * img.height() as f64 / 1e6,
+            peak_memory: peak_memory(),
+            encoded_bytes,
+        })
+    }
+
+    /// Middle run time, the mean of the two middle ones for an even count
+    pub fn median(&self) -> Duration {
+        let mut sorted = self.runs.clone();
+        sorted.sort_unstable();
+        match sorted.len() {
+            0 => Duration::ZERO,
+            n if n % 2 == 1 => sorted[n / 2],
+            n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2,
+        }
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.runs.len() {
+            0 => Duration::ZERO,
+            n => self.runs.iter().sum::<Duration>() / n as u32,
+        }
+    }
+
+    /// Megapixels of the input handled per second, at the median time
+    pub fn throughput(&self) -> f64 {
+        match self.median().as_secs_f64() {
+            0.0 => f64::INFINITY,
+            seconds => self.megapixels / seconds,
+        }
+    }
+
+    /// Format the timings as text lines or JSON, times in milliseconds
+    pub fn report(&self, format: ReportFormat) -> String {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let min = self.runs.iter().min().copied().unwrap_or_default();
+        let max = self.runs.iter().max().copied().unwrap_or_default();
+        match format {
+            ReportFormat::Text => {
+                let mut lines = vec![
+                    format!("Runs: {} after {} warmup", self.runs.len(), self.warmup),
+                    format!("Median: {:.2} ms", ms(self.median())),
+                    format!("Mean: {:.2} ms", ms(self.mean())),
+                    format!("Min: {:.2} ms", ms(min)),
+                    format!("Max: {:.2} ms", ms(max)),
+                    format!("Throughput: {:.2} MP/s", self.throughput()),
+                ];
+                if let Some(bytes) = self.peak_memory {
+                    lines.push(format!("Peak memory: {:.1} MB", bytes as f64 / 1e6));
+                }
+                if let Some(bytes) = self.encoded_bytes {
+                    lines.push(format!("Encoded size: {} bytes", bytes));
+                }
+                lines.join("\n")
+            }
+            ReportFormat::Json => json!({
+                "runs": self.runs.len(),
+                "warmup": self.warmup,
+                "median_ms": ms(self.median()),
+                "mean_ms": ms(self.mean()),
+                "min_ms": ms(min),
+                "max_ms": ms(max),
+                "megapixels": self.megapixels,
+                "megapixels_per_second": self.throughput(),
+                "peak_memory": self.peak_memory,
+                "encoded_bytes": self.encoded_bytes,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Largest resident memory of the process so far, in bytes
+#[cfg(unix)]
+fn peak_memory() -> Option<u64> {
+    // SAFETY: getrusage only fills in the struct it is given
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    // macOS counts bytes, other systems kilobytes
+    let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Some(usage.ru_maxrss as u64 * unit)
+}
+
+#[cfg(not(unix))]
+fn peak_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_benchmark() {
+        let img = DynamicImage::new_rgb8(2000, 500);
+        let mut calls = 0;
+        let bench = Benchmark::measure(&img, 3, 2, |img| {
+            calls += 1;
+            Ok(Some(img.width() as u64))
+        })
+        .unwrap();
+        assert_eq!(calls, 5);
+        assert_eq!((bench.runs.len(), bench.warmup), (3, 2));
+        assert_eq!(bench.megapixels, 1.0);
+        assert_eq!(bench.encoded_bytes, Some(2000));
+        #[cfg(unix)]
+        assert!(bench.peak_memory.unwrap() > 1_000_000);
+
+        let bench = Benchmark {
+            runs: [40, 10, 30, 20].map(Duration::from_millis).to_vec(),
+            ..bench
+        };
+        assert_eq!(bench.median(), Duration::from_millis(25));
+        assert_eq!(bench.mean(), Duration::from_millis(25));
+        assert_eq!(bench.throughput(), 40.0);
+        let report: serde_json::Value =
+            serde_json::from_str(&bench.report(ReportFormat::Json)).unwrap();
+        assert_eq!(report["max_ms"], 40.0);
+        assert_eq!(report["runs"], 4);
+        assert!(
+            bench
+                .report(ReportFormat::Text)
+                .contains("Throughput: 40.00 MP/s")
+        );
+    }
+}
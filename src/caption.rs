@@ -0,0 +1,188 @@
+use crate::draw::draw_rounded_rect_mut;
+use crate::fonts::Fonts;
+use crate::{Alignment, Fill};
+use ab_glyph::PxScale;
+use image::{Rgba, RgbaImage, imageops};
+
+/// How a caption is laid out and drawn
+pub struct Caption<'a> {
+    pub fonts: &'a Fonts,
+    pub scale: PxScale,
+    /// Text color, or a gradient spanning all lines
+    pub color: Fill,
+    /// How lines of different widths line up
+    pub align: Alignment,
+    /// Distance between baselines as a multiple of the font's line height
+    pub line_spacing: f32,
+    /// Width in pixels that lines are wrapped at
+    pub max_width: Option<u32>,
+    /// Box behind the text, transparent if not given
+    pub background: Option<Rgba<u8>>,
+    /// Space between the text and the edge of the box
+    pub padding: u32,
+    /// Corner radius of the box
+    pub radius: u32,
+}
+
+/// Render the caption text in its box, ready to be placed on an image
+///
+/// Lines break at newlines, written as `\n` too, and wrap at the maximum
+/// width between words. Words wider than that, and text without spaces as
+/// in Chinese, break between characters.
+pub fn render_caption(text: &str, caption: &Caption) -> RgbaImage {
+    let text = text.replace("\\n", "\n");
+    let lines: Vec<String> = text
+        .lines()
+        .flat_map(|line| match caption.max_width {
+            Some(width) => wrap_line(line, caption, width),
+            None => vec![line.to_string()],
+        })
+        .collect();
+    let widths: Vec<u32> = lines
+        .iter()
+        .map(|line| caption.fonts.text_size(caption.scale, line).0)
+        .collect();
+
+    let height = caption.fonts.height(caption.scale).ceil();
+    let advance = (height * caption.line_spacing).max(0.0);
+    let text_w = widths.iter().copied().max().unwrap_or(0);
+    let text_h = match lines.len() {
+        0 => 0,
+        n => (advance * (n - 1) as f32 + height).ceil() as u32,
+    };
+    let (w, h) = (text_w + 2 * caption.padding, text_h + 2 * caption.padding);
+
+    let mut canvas = RgbaImage::new(w.max(1), h.max(1));
+    if let Some(background) = caption.background {
+        draw_rounded_rect_mut(&mut canvas, (0, 0), (w, h), caption.radius, background);
+    }
+    // A gradient is painted over text drawn in white on a layer of its own
+    let (color, mut layer) = match caption.color {
+        Fill::Solid(color) => (color.into(), None),
+        Fill::Gradient(_) => (Rgba([255; 4]), Some(RgbaImage::new(w.max(1), h.max(1)))),
+    };
+    for (i, (line, line_w)) in lines.iter().zip(widths).enumerate() {
+        let offset = match caption.align {
+            Alignment::Start => 0,
+            Alignment::Center => (text_w - line_w) / 2,
+            Alignment::End => text_w - line_w,
+        };
+        let x = (caption.padding + offset) as i32;
+        let y = caption.padding as i32 + (advance * i as f32).round() as i32;
+        let target = layer.as_mut().unwrap_or(&mut canvas);
+        caption
+            .fonts
+            .draw_text_mut(target, color, (x, y), caption.scale, line);
+    }
+    if let (Fill::Gradient(gradient), Some(mut layer)) = (&caption.color, layer) {
+        let p = caption.padding as i32;
+        gradient.paint(&mut layer, (p, p, text_w, text_h));
+        imageops::overlay(&mut canvas, &layer, 0, 0);
+    }
+    canvas
+}
+
+/// Split one line into lines no wider than `width`, a line keeps at least one character
+fn wrap_line(line: &str, caption: &Caption, width: u32) -> Vec<String> {
+    let fits = |s: &str| caption.fonts.text_size(caption.scale, s).0 <= width;
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in line.split(' ') {
+        let candidate = match current.is_empty() {
+            true => word.to_string(),
+            false => format!("{} {}", current, word),
+        };
+        if fits(&candidate) {
+            current = candidate;
+            continue;
+        }
+        if !current.is_empty() {
+            lines.push(std::mem::take(&mut current));
+        }
+        // Break a word that doesn't fit on a line of its own
+        for c in word.chars() {
+            let candidate = format!("{}{}", current, c);
+            if !current.is_empty() && !fits(&candidate) {
+                lines.push(std::mem::take(&mut current));
+                current.push(c);
+            } else {
+                current = candidate;
+            }
+        }
+    }
+    lines.push(current);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Color;
+
+    fn caption(fonts: &Fonts, max_width: Option<u32>) -> Caption<'_> {
+        Caption {
+            fonts,
+            scale: PxScale::from(20.0),
+            color: Fill::Solid(Color::White),
+            align: Alignment::Center,
+            line_spacing: 1.0,
+            max_width,
+            background: Some(Rgba([0, 0, 0, 255])),
+            padding: 4,
+            radius: 0,
+        }
+    }
+
+    #[test]
+    fn test_wrap_line() {
+        let fonts = Fonts::load(None).unwrap();
+        let caption = caption(&fonts, None);
+        let width = fonts.text_size(caption.scale, "hello world").0;
+        assert_eq!(wrap_line("hello world", &caption, width), ["hello world"]);
+        assert_eq!(
+            wrap_line("hello world", &caption, width - 1),
+            ["hello", "world"]
+        );
+        // Text without spaces breaks between characters
+        let lines = wrap_line("图像处理工具", &caption, 45);
+        assert!(lines.len() > 1);
+        assert_eq!(lines.concat(), "图像处理工具");
+        // A single character wider than the limit still gets a line
+        assert_eq!(wrap_line("W", &caption, 1), ["W"]);
+    }
+
+    #[test]
+    fn test_render_caption() {
+        let fonts = Fonts::load(None).unwrap();
+        let one = render_caption("Title", &caption(&fonts, None));
+        let two = render_caption("Title\\nSubtitle", &caption(&fonts, None));
+        assert!(two.height() > one.height());
+        assert!(two.width() > one.width());
+        // The box fills the corners, the text stays inside the padding
+        assert_eq!(two.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert!(two.pixels().any(|p| p.0 == [255, 255, 255, 255]));
+
+        // A gradient runs across all lines, red at the top and blue at the bottom
+        let gradient = Caption {
+            color: "gradient(linear,180,red,blue)".parse().unwrap(),
+            ..caption(&fonts, None)
+        };
+        let two = render_caption("Title\\nSubtitle", &gradient);
+        let rows = |range: std::ops::Range<u32>| {
+            range
+                .flat_map(|y| (0..two.width()).map(move |x| (x, y)))
+                .map(|(x, y)| two.get_pixel(x, y).0)
+                .filter(|p| p[0] > 0 || p[2] > 0)
+                .fold([0u32; 2], |[r, b], p| [r + p[0] as u32, b + p[2] as u32])
+        };
+        let ([top_r, top_b], [bottom_r, bottom_b]) = (
+            rows(0..two.height() / 2),
+            rows(two.height() / 2..two.height()),
+        );
+        assert!(top_r > top_b && bottom_b > bottom_r);
+
+        let wrapped = render_caption("one two three four", &caption(&fonts, Some(60)));
+        assert!(wrapped.width() <= 60 + 2 * 4);
+        assert!(wrapped.height() > one.height());
+    }
+}
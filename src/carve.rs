@@ -0,0 +1,225 @@
+use crate::process::{rgba8, with_color_type};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, GrayImage, Rgba, RgbaImage};
+
+/// Energy of a fully protected pixel, a seam only crosses one when all do
+const PROTECTED: f64 = 1e6;
+
+/// Shrink the image to `width` x `height` by removing seams
+///
+/// A seam is a path of pixels from one edge to the other, one per row or
+/// column, moving at most one pixel sideways each step. The seam through
+/// the flattest pixels is removed each time, so edges and details stay while
+/// flat areas narrow. Columns are removed before rows. White areas of the
+/// protection mask, stretched to the image size, are only cut into when
+/// nothing else is left.
+pub fn liquid_resize(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    protect: Option<&GrayImage>,
+) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    let protect = protect.map(|mask| match mask.dimensions() == (w, h) {
+        true => mask.clone(),
+        false => imageops::resize(mask, w, h, FilterType::Triangle),
+    });
+    let mut carver = Carver::new(&rgba8(img), protect.as_ref());
+    carver.carve(width);
+    carver.transpose();
+    carver.carve(height);
+    carver.transpose();
+    with_color_type(carver.into_image(), img.color())
+}
+
+/// A pixel with what the energy is computed from
+#[derive(Clone, Copy)]
+struct Cell {
+    pixel: Rgba<u8>,
+    luma: f32,
+    /// 0.0 ~ 1.0 from the protection mask
+    protect: f32,
+}
+
+/// The remaining pixels, row by row, `width` to a row
+struct Carver {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+impl Carver {
+    fn new(img: &RgbaImage, protect: Option<&GrayImage>) -> Self {
+        let cells = img
+            .enumerate_pixels()
+            .map(|(x, y, &pixel)| {
+                let [r, g, b, a] = pixel.0.map(f32::from);
+                // Transparent pixels are flat, whatever color they hide
+                let luma = (0.299 * r + 0.587 * g + 0.114 * b) * a / 255.0;
+                let protect = protect.map_or(0.0, |mask| mask.get_pixel(x, y)[0] as f32 / 255.0);
+                Cell {
+                    pixel,
+                    luma,
+                    protect,
+                }
+            })
+            .collect();
+        Carver {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            cells,
+        }
+    }
+
+    /// Remove vertical seams until the rows are `width` long
+    fn carve(&mut self, width: u32) {
+        while self.width > (width as usize).max(1) {
+            let seam = self.find_seam();
+            self.remove(&seam);
+        }
+    }
+
+    /// Gradient magnitude of every cell, plus the protection
+    fn energy(&self) -> Vec<f64> {
+        let (w, h) = (self.width, self.height);
+        let luma = |x: usize, y: usize| self.cells[y * w + x].luma as f64;
+        let mut energy = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let dx = luma((x + 1).min(w - 1), y) - luma(x.saturating_sub(1), y);
+                let dy = luma(x, (y + 1).min(h - 1)) - luma(x, y.saturating_sub(1));
+                let protect = self.cells[y * w + x].protect as f64 * PROTECTED;
+                energy.push(dx.abs() + dy.abs() + protect);
+            }
+        }
+        energy
+    }
+
+    /// The x of the lowest energy seam in each row
+    fn find_seam(&self) -> Vec<usize> {
+        let (w, h) = (self.width, self.height);
+        // Lowest energy of a seam from the top row down to each cell
+        let mut cost = self.energy();
+        for y in 1..h {
+            for x in 0..w {
+                let above = &cost[(y - 1) * w..y * w];
+                let best = above[x.saturating_sub(1)..=(x + 1).min(w - 1)]
+                    .iter()
+                    .fold(f64::INFINITY, |a, &b| a.min(b));
+                cost[y * w + x] += best;
+            }
+        }
+        // Follow the cheapest cells back up, keeping straight on ties
+        let mut seam = vec![0; h];
+        let last = &cost[(h - 1) * w..];
+        seam[h - 1] = (0..w).min_by(|&a, &b| last[a].total_cmp(&last[b])).unwrap();
+        for y in (1..h).rev() {
+            let x = seam[y];
+            let row = &cost[(y - 1) * w..y * w];
+            seam[y - 1] = [x, x.saturating_sub(1), (x + 1).min(w - 1)]
+                .into_iter()
+                .min_by(|&a, &b| row[a].total_cmp(&row[b]))
+                .unwrap();
+        }
+        seam
+    }
+
+    fn remove(&mut self, seam: &[usize]) {
+        let w = self.width;
+        let mut index = 0;
+        self.cells.retain(|_| {
+            let (x, y) = (index % w, index / w);
+            index += 1;
+            x != seam[y]
+        });
+        self.width -= 1;
+    }
+
+    /// Swap rows and columns, so removing columns removes rows
+    fn transpose(&mut self) {
+        let (w, h) = (self.width, self.height);
+        self.cells = (0..w * h)
+            .map(|i| self.cells[(i % h) * w + i / h])
+            .collect();
+        (self.width, self.height) = (h, w);
+    }
+
+    fn into_image(self) -> RgbaImage {
+        let (w, h) = (self.width as u32, self.height as u32);
+        RgbaImage::from_fn(w, h, |x, y| self.cells[(y * w + x) as usize].pixel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb, RgbImage};
+
+    /// A flat gray image with a black square on the left and a white one on the right
+    fn scene() -> DynamicImage {
+        let mut img = RgbImage::from_pixel(40, 20, Rgb([128; 3]));
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            if (5..10).contains(&y) {
+                if (4..9).contains(&x) {
+                    *pixel = Rgb([0; 3]);
+                } else if (30..35).contains(&x) {
+                    *pixel = Rgb([255; 3]);
+                }
+            }
+        }
+        DynamicImage::ImageRgb8(img)
+    }
+
+    fn count(img: &RgbImage, value: u8) -> usize {
+        img.pixels().filter(|p| p[0] == value).count()
+    }
+
+    #[test]
+    fn test_liquid_resize() {
+        let img = liquid_resize(&scene(), 20, 15, None);
+        assert_eq!(img.color(), image::ColorType::Rgb8);
+        assert_eq!(img.dimensions(), (20, 15));
+        // Only the flat gray went, both squares are whole
+        let img = img.to_rgb8();
+        assert_eq!(count(&img, 0), 25);
+        assert_eq!(count(&img, 255), 25);
+        let first = img.pixels().position(|p| p[0] == 0).unwrap() as u32;
+        let (x, y) = (first % 20, first / 20);
+        for (dx, dy) in [(0, 0), (4, 0), (0, 4), (4, 4)] {
+            assert_eq!(img.get_pixel(x + dx, y + dy).0, [0; 3]);
+        }
+        // Nothing to remove
+        assert_eq!(
+            liquid_resize(&scene(), 40, 20, None).to_rgb8(),
+            scene().to_rgb8()
+        );
+    }
+
+    #[test]
+    fn test_liquid_resize_protect() {
+        // A plain gradient, every seam costs about the same
+        let img =
+            DynamicImage::ImageRgb8(RgbImage::from_fn(30, 10, |x, _| Rgb([x as u8 * 8, 0, 0])));
+        let mut mask = GrayImage::new(30, 10);
+        for x in 10..20 {
+            for y in 0..10 {
+                mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+        let out = liquid_resize(&img, 15, 10, Some(&mask)).to_rgb8();
+        // The protected columns are all kept, next to each other
+        let row: Vec<u8> = (0..15).map(|x| out.get_pixel(x, 3)[0]).collect();
+        let start = row.iter().position(|&v| v == 80).unwrap();
+        assert_eq!(
+            row[start..start + 10],
+            (10..20).map(|x| x * 8).collect::<Vec<u8>>()
+        );
+
+        // A mask of another size is stretched, even a fully protected image shrinks
+        let full = GrayImage::from_pixel(3, 2, Luma([255]));
+        assert_eq!(
+            liquid_resize(&img, 12, 4, Some(&full)).dimensions(),
+            (12, 4)
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use crate::process::rgba8;
+use crate::{ChannelMap, ImgtoolsError};
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageBuffer, LumaA, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
+
+/// Names of `count` channels: l, la, rgb or rgba
+pub fn channel_names(count: usize) -> &'static [char] {
+    match count {
+        1 => &['l'],
+        2 => &['l', 'a'],
+        3 => &['r', 'g', 'b'],
+        _ => &['r', 'g', 'b', 'a'],
+    }
+}
+
+/// Split the image into one grayscale plane per channel
+///
+/// Gray images give l and a planes, color images r, g, b and a planes. Alpha
+/// is only split off when the image has an alpha channel.
+pub fn split(img: &DynamicImage) -> Vec<(char, GrayImage)> {
+    let planes = |raw: Vec<u8>, count: usize| {
+        let names = channel_names(count);
+        names
+            .iter()
+            .enumerate()
+            .map(|(c, &name)| {
+                let plane = raw.iter().skip(c).step_by(count).copied().collect();
+                let plane = GrayImage::from_raw(img.width(), img.height(), plane)
+                    .expect("plane has one byte per pixel");
+                (name, plane)
+            })
+            .collect()
+    };
+
+    match (img.color().has_color(), img.color().has_alpha()) {
+        (false, false) => planes(img.to_luma8().into_raw(), 1),
+        (false, true) => planes(img.to_luma_alpha8().into_raw(), 2),
+        (true, false) => planes(img.to_rgb8().into_raw(), 3),
+        (true, true) => planes(img.to_rgba8().into_raw(), 4),
+    }
+}
+
+/// Combine grayscale planes into one image
+///
+/// The planes are named like the output of [`split`] for their count, the map
+/// picks a plane by name for each output channel. One to four channels give
+/// a gray, gray alpha, RGB or RGBA image.
+pub fn merge(planes: &[GrayImage], map: &ChannelMap) -> Result<DynamicImage, ImgtoolsError> {
+    let names = channel_names(planes.len());
+    let first = planes
+        .first()
+        .ok_or_else(|| ImgtoolsError::InvalidArgument("No planes to merge".into()))?;
+    if let Some(plane) = planes.iter().find(|p| p.dimensions() != first.dimensions()) {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Plane size {}x{} differs from {}x{}",
+            plane.width(),
+            plane.height(),
+            first.width(),
+            first.height()
+        )));
+    }
+
+    let sources = map
+        .0
+        .iter()
+        .map(|name| {
+            names
+                .iter()
+                .position(|n| n == name)
+                .map(|i| &planes[i])
+                .ok_or_else(|| {
+                    ImgtoolsError::InvalidArgument(format!(
+                        "Channel '{}' is not one of {}",
+                        name,
+                        names.iter().collect::<String>()
+                    ))
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (width, height) = first.dimensions();
+    Ok(match sources.len() {
+        1 => DynamicImage::ImageLuma8(sources[0].clone()),
+        2 => DynamicImage::ImageLumaA8(combine::<LumaA<u8>>(&sources, width, height)),
+        3 => DynamicImage::ImageRgb8(combine::<Rgb<u8>>(&sources, width, height)),
+        _ => DynamicImage::ImageRgba8(combine::<Rgba<u8>>(&sources, width, height)),
+    })
+}
+
+/// Flatten the image onto a solid background, removing the alpha channel
+///
+/// Gray images on a gray background stay gray, everything else becomes RGB.
+pub fn flatten(img: &DynamicImage, background: Rgba<u8>) -> DynamicImage {
+    let rgba = rgba8(img);
+    let flat = RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        let alpha = pixel[3] as f32 / 255.0;
+        Rgb([0, 1, 2].map(|c| {
+            let value = pixel[c] as f32 * alpha + background[c] as f32 * (1.0 - alpha);
+            value.round() as u8
+        }))
+    });
+
+    let gray_background = background[0] == background[1] && background[1] == background[2];
+    match !img.color().has_color() && gray_background {
+        true => DynamicImage::ImageRgb8(flat).to_luma8().into(),
+        false => DynamicImage::ImageRgb8(flat),
+    }
+}
+
+/// Alpha channel as a grayscale image, opaque images give white
+pub fn extract_alpha(img: &DynamicImage) -> GrayImage {
+    let rgba = rgba8(img);
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        image::Luma([rgba.get_pixel(x, y)[3]])
+    })
+}
+
+/// Replace the alpha channel with a grayscale mask, white is opaque
+///
+/// A mask of a different size is stretched to the image.
+pub fn apply_mask(img: &DynamicImage, mask: &GrayImage) -> RgbaImage {
+    let mask = match mask.dimensions() == (img.width(), img.height()) {
+        true => mask.clone(),
+        false => image::imageops::resize(mask, img.width(), img.height(), FilterType::Triangle),
+    };
+    let mut rgba = img.to_rgba8();
+    for (pixel, alpha) in rgba.pixels_mut().zip(mask.pixels()) {
+        pixel[3] = alpha[0];
+    }
+    rgba
+}
+
+/// Multiply the color channels by alpha
+pub fn premultiply(img: &mut RgbaImage) {
+    for pixel in img.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for c in 0..3 {
+            pixel[c] = ((pixel[c] as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Make pixels close to the key color transparent
+///
+/// Distances are RGB distances relative to the largest possible one. Pixels
+/// within `tolerance` become transparent, the next `feather` fade in to their
+/// original alpha.
+pub fn chromakey(img: &mut RgbaImage, key: Rgba<u8>, tolerance: f32, feather: f32) {
+    let max = (3.0f32 * 255.0 * 255.0).sqrt();
+    for pixel in img.pixels_mut() {
+        let distance = (0..3)
+            .map(|c| (pixel[c] as f32 - key[c] as f32).powi(2))
+            .sum::<f32>()
+            .sqrt()
+            / max;
+        let keep = match feather > 0.0 {
+            true => ((distance - tolerance) / feather).clamp(0.0, 1.0),
+            false => (distance > tolerance) as u8 as f32,
+        };
+        pixel[3] = (pixel[3] as f32 * keep).round() as u8;
+    }
+}
+
+fn combine<P: Pixel<Subpixel = u8>>(
+    sources: &[&GrayImage],
+    width: u32,
+    height: u32,
+) -> ImageBuffer<P, Vec<u8>> {
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let values: Vec<u8> = sources.iter().map(|s| s.get_pixel(x, y)[0]).collect();
+        *P::from_slice(&values)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, RgbaImage};
+
+    fn planes(img: &DynamicImage) -> Vec<GrayImage> {
+        split(img).into_iter().map(|(_, plane)| plane).collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 1, Rgba([1, 2, 3, 4])));
+        let planes = split(&img);
+        let names: String = planes.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, "rgba");
+        assert_eq!(planes[2].1.get_pixel(1, 0), &Luma([3]));
+
+        let gray = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([9])));
+        assert_eq!(split(&gray).len(), 1);
+        assert_eq!(split(&gray)[0].0, 'l');
+    }
+
+    #[test]
+    fn test_alpha() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| match x {
+            0 => Rgba([200, 100, 0, 255]),
+            _ => Rgba([200, 100, 0, 51]),
+        }));
+
+        // Half covered pixels mix with the background
+        let flat = flatten(&img, Rgba([0, 0, 255, 255]));
+        assert_eq!(flat.color(), image::ColorType::Rgb8);
+        assert_eq!(flat.to_rgb8().get_pixel(0, 0), &Rgb([200, 100, 0]));
+        assert_eq!(flat.to_rgb8().get_pixel(1, 0), &Rgb([40, 20, 204]));
+
+        let alpha = extract_alpha(&img);
+        assert_eq!(alpha.as_raw(), &[255, 51]);
+
+        let mask = GrayImage::from_pixel(1, 1, Luma([128]));
+        let masked = apply_mask(&img, &mask);
+        assert!(masked.pixels().all(|p| p[3] == 128 && p[0] == 200));
+
+        let mut rgba = img.to_rgba8();
+        premultiply(&mut rgba);
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([200, 100, 0, 255]));
+        assert_eq!(rgba.get_pixel(1, 0), &Rgba([40, 20, 0, 51]));
+
+        // Gray stays gray on a gray background
+        let gray = DynamicImage::ImageLumaA8(image::GrayAlphaImage::new(1, 1));
+        assert_eq!(
+            flatten(&gray, Rgba([255; 4])).to_luma8().get_pixel(0, 0),
+            &Luma([255])
+        );
+    }
+
+    #[test]
+    fn test_chromakey() {
+        let green = Rgba([0, 255, 0, 255]);
+        let mut img = RgbaImage::from_fn(4, 1, |x, _| match x {
+            0 => green,
+            1 => Rgba([20, 240, 10, 255]),
+            2 => Rgba([40, 220, 30, 255]),
+            _ => Rgba([200, 50, 50, 255]),
+        });
+        chromakey(&mut img, green, 0.1, 0.1);
+        let alpha: Vec<u8> = img.pixels().map(|p| p[3]).collect();
+        assert_eq!(alpha[0], 0);
+        assert_eq!(alpha[1], 0);
+        assert!(alpha[2] > 0 && alpha[2] < 255, "{}", alpha[2]);
+        assert_eq!(alpha[3], 255);
+        assert_eq!(img.get_pixel(3, 0)[0], 200);
+
+        // Without feathering the edge is hard
+        let mut img = RgbaImage::from_pixel(1, 1, Rgba([40, 220, 30, 255]));
+        chromakey(&mut img, green, 0.1, 0.0);
+        assert_eq!(img.get_pixel(0, 0)[3], 255);
+    }
+
+    #[test]
+    fn test_merge() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 1, Rgba([1, 2, 3, 4])));
+        let planes = planes(&img);
+
+        let same = merge(&planes, &"rgba".parse().unwrap()).unwrap();
+        assert_eq!(same, img);
+        let swapped = merge(&planes[..3], &"bgr".parse().unwrap()).unwrap();
+        assert_eq!(swapped.to_rgb8().get_pixel(0, 0), &Rgb([3, 2, 1]));
+        let alpha = merge(&planes, &"a".parse().unwrap()).unwrap();
+        assert_eq!(alpha.to_luma8().get_pixel(0, 0), &Luma([4]));
+
+        assert!(merge(&planes[..3], &"rgba".parse().unwrap()).is_err());
+        let small = GrayImage::new(1, 1);
+        assert!(merge(&[planes[0].clone(), small], &"la".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::TintMode;
+use image::{Rgb, Rgba, RgbaImage};
+
+/// Recolor the image with a tint, mixed with the original by `strength`
+///
+/// Sepia uses the classic sepia matrix, warm and cool shift the balance
+/// between red and blue, and duotone maps the luminance onto a gradient from
+/// the dark to the light color. Alpha is left unchanged.
+pub fn tint(img: &mut RgbaImage, mode: TintMode, strength: f32) {
+    for pixel in img.pixels_mut() {
+        let rgb = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+        let tinted = match mode {
+            TintMode::Sepia => [
+                0.393 * rgb[0] + 0.769 * rgb[1] + 0.189 * rgb[2],
+                0.349 * rgb[0] + 0.686 * rgb[1] + 0.168 * rgb[2],
+                0.272 * rgb[0] + 0.534 * rgb[1] + 0.131 * rgb[2],
+            ],
+            TintMode::Warm => [rgb[0] * 1.1 + 10.0, rgb[1] * 1.02, rgb[2] * 0.85],
+            TintMode::Cool => [rgb[0] * 0.85, rgb[1] * 1.02, rgb[2] * 1.1 + 10.0],
+            TintMode::Duotone(dark, light) => {
+                let (dark, light) = (Rgba::<u8>::from(dark), Rgba::<u8>::from(light));
+                let luma = (0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]) / 255.0;
+                [0, 1, 2].map(|i| dark[i] as f32 + (light[i] as f32 - dark[i] as f32) * luma)
+            }
+        };
+
+        for (i, value) in tinted.into_iter().enumerate() {
+            let mixed = rgb[i] + (value - rgb[i]) * strength;
+            pixel[i] = mixed.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Heat color for `t` from 0.0 to 1.0, running from black over red and yellow to white
+pub(crate) fn heat(t: f32) -> Rgb<u8> {
+    let t = t.clamp(0.0, 1.0) * 3.0;
+    Rgb([t, t - 1.0, t - 2.0].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Color;
+
+    fn tinted(pixel: Rgba<u8>, mode: TintMode, strength: f32) -> Rgba<u8> {
+        let mut img = RgbaImage::from_pixel(1, 1, pixel);
+        tint(&mut img, mode, strength);
+        *img.get_pixel(0, 0)
+    }
+
+    #[test]
+    fn test_sepia() {
+        let gray = Rgba([100, 100, 100, 200]);
+        assert_eq!(
+            tinted(gray, TintMode::Sepia, 1.0),
+            Rgba([135, 120, 94, 200])
+        );
+        assert_eq!(tinted(gray, TintMode::Sepia, 0.0), gray);
+        assert_eq!(
+            tinted(gray, TintMode::Sepia, 0.5),
+            Rgba([118, 110, 97, 200])
+        );
+    }
+
+    #[test]
+    fn test_warm_and_cool() {
+        let gray = Rgba([100, 100, 100, 255]);
+        let warm = tinted(gray, TintMode::Warm, 1.0);
+        let cool = tinted(gray, TintMode::Cool, 1.0);
+        assert!(warm[0] > warm[2]);
+        assert!(cool[2] > cool[0]);
+    }
+
+    #[test]
+    fn test_heat() {
+        assert_eq!(heat(0.0), Rgb([0, 0, 0]));
+        assert_eq!(heat(0.5), Rgb([255, 128, 0]));
+        assert_eq!(heat(1.0), Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn test_duotone() {
+        let mode = TintMode::Duotone(Color::Blue, Color::Rgba(255, 255, 0, 255));
+        assert_eq!(
+            tinted(Rgba([0, 0, 0, 255]), mode, 1.0),
+            Rgba([0, 0, 255, 255])
+        );
+        assert_eq!(
+            tinted(Rgba([255, 255, 255, 255]), mode, 1.0),
+            Rgba([255, 255, 0, 255])
+        );
+        assert_eq!(
+            tinted(Rgba([128, 128, 128, 255]), mode, 1.0),
+            Rgba([128, 128, 127, 255])
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::process::with_color_type;
+use crate::{BlendMode, Position};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Top-left offsets of an overlay of `size` placed on a canvas of `canvas`
+///
+/// Offsets may be negative when the overlay is larger than the canvas, the
+/// part outside the canvas is clipped when blending. Flat lay yields one
+/// offset per tile.
+pub fn placements(
+    position: Position,
+    (width, height): (u32, u32),
+    (w, h): (u32, u32),
+    margin: u32,
+) -> Vec<(i64, i64)> {
+    let (width, height, w, h, margin) = (
+        width as i64,
+        height as i64,
+        w as i64,
+        h as i64,
+        margin as i64,
+    );
+    let center_x = (width - w) / 2;
+    let center_y = (height - h) / 2;
+    let right = width - w - margin;
+    let bottom = height - h - margin;
+
+    let offset = match position {
+        Position::Center => (center_x, center_y),
+        Position::TopLeft => (margin, margin),
+        Position::TopCenter => (center_x, margin),
+        Position::TopRight => (right, margin),
+        Position::MiddleLeft => (margin, center_y),
+        Position::MiddleRight => (right, center_y),
+        Position::BottomLeft => (margin, bottom),
+        Position::BottomCenter => (center_x, bottom),
+        Position::BottomRight => (right, bottom),
+        Position::Custom(x, y) => (x as i64, y as i64),
+        // Tile the canvas
+        Position::FlatLay(gap_x, gap_y) => {
+            let tiling = Tiling {
+                gap: (gap_x, gap_y),
+                ..Tiling::default()
+            };
+            return tiling
+                .plan((width as u32, height as u32), (w as u32, h as u32))
+                .concat();
+        }
+    };
+    vec![offset]
+}
+
+/// Layout of an overlay repeated over the whole canvas
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Tiling {
+    /// Horizontal and vertical gap between tiles in pixels
+    pub gap: (u32, u32),
+    /// Shift of every other row as a share of the horizontal step, 0.5 gives a brick pattern
+    pub row_offset: f32,
+    /// Share of the canvas covered by tiles, overrides the gap
+    pub coverage: Option<f32>,
+}
+
+impl Tiling {
+    /// Top-left offsets of tiles of `size` on a canvas of `canvas`, row by row
+    ///
+    /// Tiles start at the top-left corner and rows are filled up to the
+    /// canvas edges, shifted rows begin left of the canvas so no gap is left
+    /// at the edge.
+    pub fn plan(&self, (width, height): (u32, u32), (w, h): (u32, u32)) -> Vec<Vec<(i64, i64)>> {
+        let (w, h) = (w.max(1) as f64, h.max(1) as f64);
+        let (step_x, step_y) = match self.coverage {
+            // Scale the tile pitch so tiles cover the requested share of the area
+            Some(coverage) => {
+                let pitch = (1.0 / coverage.clamp(f32::EPSILON, 1.0) as f64).sqrt();
+                (w * pitch, h * pitch)
+            }
+            None => (w + self.gap.0 as f64, h + self.gap.1 as f64),
+        };
+        let (step_x, step_y) = (
+            (step_x.round() as i64).max(1),
+            (step_y.round() as i64).max(1),
+        );
+        let offset = (self.row_offset as f64 * step_x as f64).round() as i64;
+
+        (0..)
+            .map(|row| row * step_y)
+            .take_while(|&y| y < height as i64)
+            .enumerate()
+            .map(|(row, y)| {
+                let shift = match row % 2 {
+                    1 => offset.rem_euclid(step_x),
+                    _ => 0,
+                };
+                let start = if shift > 0 { shift - step_x } else { 0 };
+                (start..width as i64)
+                    .step_by(step_x as usize)
+                    .map(|x| (x, y))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Blend `top` onto `base` with its top-left corner at `(x, y)`
+///
+/// The blend mode combines the colors where both images are opaque, the
+/// result is then composited with source-over alpha. `opacity` scales the
+/// alpha of the overlay.
+pub fn blend(base: &mut RgbaImage, top: &RgbaImage, x: i64, y: i64, opacity: f32, mode: BlendMode) {
+    let (width, height) = (base.width() as i64, base.height() as i64);
+    for (tx, ty, src) in top.enumerate_pixels() {
+        let (bx, by) = (x + tx as i64, y + ty as i64);
+        if bx < 0 || by < 0 || bx >= width || by >= height {
+            continue;
+        }
+        let dst = base.get_pixel_mut(bx as u32, by as u32);
+        *dst = blend_pixel(*dst, *src, opacity, mode);
+    }
+}
+
+/// Overlay `top` onto a copy of `img` at the given position
+///
+/// The result keeps the color type of `img` for 8-bit gray and RGB images and
+/// is RGBA otherwise.
+pub fn composite(
+    img: &DynamicImage,
+    top: &RgbaImage,
+    position: Position,
+    margin: u32,
+    opacity: f32,
+    mode: BlendMode,
+) -> DynamicImage {
+    let mut base = img.to_rgba8();
+    for (x, y) in placements(position, base.dimensions(), top.dimensions(), margin) {
+        blend(&mut base, top, x, y, opacity, mode);
+    }
+
+    with_color_type(base, img.color())
+}
+
+/// Composite one source pixel over one destination pixel
+fn blend_pixel(dst: Rgba<u8>, src: Rgba<u8>, opacity: f32, mode: BlendMode) -> Rgba<u8> {
+    let unit = |v: u8| v as f32 / 255.0;
+    let src_a = unit(src[3]) * opacity.clamp(0.0, 1.0);
+    let dst_a = unit(dst[3]);
+    let out_a = src_a + dst_a * (1.0 - src_a);
+    if out_a <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let mut out = [0u8; 4];
+    for i in 0..3 {
+        let (cs, cb) = (unit(src[i]), unit(dst[i]));
+        // Blended color only applies where the destination is opaque
+        let mixed = (1.0 - dst_a) * cs + dst_a * mode.apply(cb, cs);
+        let color = (src_a * mixed + dst_a * cb * (1.0 - src_a)) / out_a;
+        out[i] = (color * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    out[3] = (out_a * 255.0).round() as u8;
+    Rgba(out)
+}
+
+impl BlendMode {
+    /// Blend a backdrop channel `cb` with a source channel `cs`, both in 0..=1
+    pub fn apply(self, cb: f32, cs: f32) -> f32 {
+        match self {
+            BlendMode::Normal => cs,
+            BlendMode::Multiply => cb * cs,
+            BlendMode::Screen => cb + cs - cb * cs,
+            BlendMode::Overlay if cb <= 0.5 => 2.0 * cb * cs,
+            BlendMode::Overlay => 1.0 - 2.0 * (1.0 - cb) * (1.0 - cs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ColorType;
+
+    #[test]
+    fn test_placements() {
+        let canvas = (100, 80);
+        let size = (20, 10);
+        assert_eq!(
+            placements(Position::Center, canvas, size, 5),
+            vec![(40, 35)]
+        );
+        assert_eq!(placements(Position::TopLeft, canvas, size, 5), vec![(5, 5)]);
+        assert_eq!(
+            placements(Position::BottomRight, canvas, size, 5),
+            vec![(75, 65)]
+        );
+        assert_eq!(
+            placements(Position::Custom(3, 4), canvas, size, 5),
+            vec![(3, 4)]
+        );
+        assert_eq!(
+            placements(Position::FlatLay(30, 30), canvas, size, 0),
+            vec![(0, 0), (50, 0), (0, 40), (50, 40)]
+        );
+        // Larger overlays are centered with negative offsets
+        assert_eq!(
+            placements(Position::Center, (10, 10), (20, 30), 0),
+            vec![(-5, -10)]
+        );
+    }
+
+    #[test]
+    fn test_tiling_plan() {
+        // Gaps are added to the tile size
+        let tiling = Tiling {
+            gap: (10, 5),
+            ..Tiling::default()
+        };
+        assert_eq!(
+            tiling.plan((50, 30), (15, 10)),
+            vec![vec![(0, 0), (25, 0)], vec![(0, 15), (25, 15)]]
+        );
+
+        // Brick pattern, shifted rows start left of the canvas
+        let tiling = Tiling {
+            gap: (10, 5),
+            row_offset: 0.5,
+            ..Tiling::default()
+        };
+        assert_eq!(
+            tiling.plan((50, 30), (15, 10)),
+            vec![vec![(0, 0), (25, 0)], vec![(-12, 15), (13, 15), (38, 15)]]
+        );
+
+        // A quarter coverage doubles the pitch in both directions
+        let tiling = Tiling {
+            coverage: Some(0.25),
+            ..Tiling::default()
+        };
+        assert_eq!(
+            tiling.plan((100, 100), (20, 10)),
+            vec![
+                vec![(0, 0), (40, 0), (80, 0)],
+                vec![(0, 20), (40, 20), (80, 20)],
+                vec![(0, 40), (40, 40), (80, 40)],
+                vec![(0, 60), (40, 60), (80, 60)],
+                vec![(0, 80), (40, 80), (80, 80)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let dst = Rgba([128, 64, 255, 255]);
+        let src = Rgba([255, 128, 0, 255]);
+        assert_eq!(blend_pixel(dst, src, 1.0, BlendMode::Normal), src);
+        assert_eq!(
+            blend_pixel(dst, src, 1.0, BlendMode::Multiply),
+            Rgba([128, 32, 0, 255])
+        );
+        assert_eq!(
+            blend_pixel(dst, src, 1.0, BlendMode::Screen),
+            Rgba([255, 160, 255, 255])
+        );
+        assert_eq!(
+            blend_pixel(dst, src, 1.0, BlendMode::Overlay),
+            Rgba([255, 64, 255, 255])
+        );
+    }
+
+    #[test]
+    fn test_blend_alpha() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        // Half opacity mixes evenly
+        assert_eq!(
+            blend_pixel(blue, red, 0.5, BlendMode::Normal),
+            Rgba([128, 0, 128, 255])
+        );
+        // Transparent destinations take the source color
+        assert_eq!(
+            blend_pixel(Rgba([0, 0, 0, 0]), red, 0.5, BlendMode::Multiply),
+            Rgba([255, 0, 0, 128])
+        );
+        assert_eq!(
+            blend_pixel(blue, Rgba([255, 0, 0, 0]), 1.0, BlendMode::Normal),
+            blue
+        );
+    }
+
+    #[test]
+    fn test_composite_keeps_color_type() {
+        let img = DynamicImage::new_rgb8(10, 10);
+        let top = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        let out = composite(&img, &top, Position::TopLeft, 0, 1.0, BlendMode::Normal);
+        assert_eq!(out.color(), ColorType::Rgb8);
+        assert_eq!(out.to_rgb8().get_pixel(3, 3).0, [255, 255, 255]);
+        assert_eq!(out.to_rgb8().get_pixel(4, 4).0, [0, 0, 0]);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{Command, Format, ImgtoolsError, Pipeline, ProcessOptions, Profile};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Named presets read from the config file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    pub presets: HashMap<String, Preset>,
+}
+
+/// Operations and options stored under a name, e.g. `[preset.web]`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Preset {
+    /// Command or pipeline to run, `pipeline = "resize ... | convert -f webp"`
+    pub command: Option<Command>,
+    pub input_format: Option<Format>,
+    pub keep_metadata: bool,
+    pub auto_orient: bool,
+    pub assume_profile: Option<Profile>,
+    pub convert_to: Option<Profile>,
+    pub output_template: Option<String>,
+}
+
+/// Value of a config key
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Bool(bool),
+}
+
+impl Config {
+    /// Default config file, `imgtools/config.toml` in `$XDG_CONFIG_HOME` or `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("imgtools").join("config.toml"))
+    }
+
+    /// Read and parse a config file
+    pub fn load(path: &Path) -> Result<Self, ImgtoolsError> {
+        let text = fs::read_to_string(path).map_err(|source| ImgtoolsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        text.parse().map_err(|message| ImgtoolsError::Config {
+            path: path.to_path_buf(),
+            message,
+        })
+    }
+
+    /// Look up a preset by name
+    pub fn preset(&self, name: &str) -> Result<&Preset, ImgtoolsError> {
+        self.presets.get(name).ok_or_else(|| {
+            let mut names: Vec<_> = self.presets.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            ImgtoolsError::InvalidArgument(match names.is_empty() {
+                true => format!("Unknown preset {}, the config file defines none", name),
+                false => format!("Unknown preset {}, available: {}", name, names.join(", ")),
+            })
+        })
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = String;
+
+    /// Parse the subset of TOML that presets use: `[preset.name]` tables
+    /// with string and boolean values
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+        let mut current = None;
+        for (number, line) in s.lines().enumerate() {
+            let at = |message: String| format!("line {}: {}", number + 1, message);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(table) = line.strip_prefix('[') {
+                let table = table
+                    .strip_suffix(']')
+                    .ok_or_else(|| at("Unclosed table header".into()))?
+                    .trim();
+                let name = table
+                    .strip_prefix("preset.")
+                    .map(|name| name.trim().trim_matches('"'))
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| {
+                        at(format!("Unknown table [{}], expected [preset.name]", table))
+                    })?;
+                config.presets.entry(name.to_string()).or_default();
+                current = Some(name.to_string());
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| at(format!("Expected key = value, found {}", line)))?;
+            let name = current
+                .as_ref()
+                .ok_or_else(|| at("Keys must be inside a [preset.name] table".into()))?;
+            let key = key.trim();
+            if !Preset::KEYS.contains(&key) {
+                return Err(at(format!("Unknown preset key {}", key)));
+            }
+            let preset = config.presets.get_mut(name).expect("table was added");
+            let value = parse_value(value.trim()).map_err(at)?;
+            preset.set(key, value).map_err(at)?;
+        }
+        Ok(config)
+    }
+}
+
+impl Preset {
+    /// Keys a preset table may set
+    const KEYS: [&str; 7] = [
+        "pipeline",
+        "input-format",
+        "keep-metadata",
+        "auto-orient",
+        "assume-profile",
+        "convert-to",
+        "output-template",
+    ];
+
+    /// Merge the preset with the command line
+    ///
+    /// Command line values win over the preset, flags are on when either sets
+    /// them. A command given as well runs after the preset's operations.
+    pub fn apply(
+        &self,
+        command: Option<Command>,
+        options: ProcessOptions,
+    ) -> Result<(Command, ProcessOptions), ImgtoolsError> {
+        let command = match (self.command.clone(), command) {
+            (Some(preset), Some(command)) => {
+                let steps = [preset.steps(), command.steps()].concat();
+                Command::Pipeline {
+                    steps: Pipeline(steps.into_iter().cloned().collect()),
+                }
+            }
+            (Some(command), None) | (None, Some(command)) => command,
+            (None, None) => {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "The preset has no pipeline, give a command as well".into(),
+                ));
+            }
+        };
+        let options = ProcessOptions {
+            input_format: options.input_format.or(self.input_format),
+            keep_metadata: options.keep_metadata || self.keep_metadata,
+            auto_orient: options.auto_orient || self.auto_orient,
+            assume_profile: options.assume_profile.or(self.assume_profile.clone()),
+            convert_to: options.convert_to.or(self.convert_to.clone()),
+            output_template: options.output_template.or(self.output_template.clone()),
+            ..options
+        };
+        Ok((command, options))
+    }
+
+    /// Set a key read from the config file
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "pipeline" => {
+                let Pipeline(mut steps) = value.string(key)?.parse()?;
+                // A single step stays a plain command, e.g. montage
+                self.command = Some(match steps.len() {
+                    1 => steps.remove(0),
+                    _ => Command::Pipeline {
+                        steps: Pipeline(steps),
+                    },
+                });
+            }
+            "input-format" => self.input_format = Some(value.string(key)?.parse()?),
+            "keep-metadata" => self.keep_metadata = value.bool(key)?,
+            "auto-orient" => self.auto_orient = value.bool(key)?,
+            "assume-profile" => self.assume_profile = Some(value.string(key)?.parse()?),
+            "convert-to" => self.convert_to = Some(value.string(key)?.parse()?),
+            "output-template" => self.output_template = Some(value.string(key)?),
+            _ => unreachable!("keys are checked against Preset::KEYS"),
+        }
+        Ok(())
+    }
+}
+
+impl Value {
+    fn string(self, key: &str) -> Result<String, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(format!("{} must be a string", key)),
+        }
+    }
+
+    fn bool(self, key: &str) -> Result<bool, String> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            _ => Err(format!("{} must be true or false", key)),
+        }
+    }
+}
+
+/// Drop a trailing `#` comment that is not inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some(literal) = s.strip_prefix('\'') {
+        return literal
+            .strip_suffix('\'')
+            .map(|literal| Value::String(literal.to_string()))
+            .ok_or_else(|| "Unclosed string".to_string());
+    }
+    if let Some(basic) = s.strip_prefix('"') {
+        let basic = basic
+            .strip_suffix('"')
+            .ok_or_else(|| "Unclosed string".to_string())?;
+        let mut value = String::with_capacity(basic.len());
+        let mut chars = basic.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            value.push(match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('t') => '\t',
+                other => return Err(format!("Unsupported escape \\{}", other.unwrap_or(' '))),
+            });
+        }
+        return Ok(Value::String(value));
+    }
+    match s {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ => Err(format!("Unsupported value {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_presets() {
+        let config: Config = r#"
+            # Presets for the website
+            [preset.web]
+            pipeline = "resize -w 1600 -h 1600 -f lanczos3 | convert -f webp" # shrink
+            auto-orient = true
+            convert-to = "srgb"
+            output-template = '{stem}_{width}.{format}'
+
+            [preset."sheet"]
+            pipeline = "montage -c 4"
+        "#
+        .parse()
+        .unwrap();
+
+        let web = config.preset("web").unwrap();
+        assert!(web.auto_orient && !web.keep_metadata);
+        assert_eq!(web.convert_to, Some(Profile::Srgb));
+        assert_eq!(
+            web.output_template.as_deref(),
+            Some("{stem}_{width}.{format}")
+        );
+        let Some(Command::Pipeline { steps }) = &web.command else {
+            panic!("expected a pipeline, got {:?}", web.command);
+        };
+        assert_eq!(steps.0.len(), 2);
+        assert!(matches!(
+            config.preset("sheet").unwrap().command,
+            Some(Command::Montage { columns: 4, .. })
+        ));
+        assert!(config.preset("print").is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for (text, error) in [
+            ("pipeline = \"invert\"", "line 1: Keys must be inside"),
+            ("[presets.web]", "line 1: Unknown table"),
+            ("[preset.web]\nquality = 80", "line 2: Unknown preset key"),
+            (
+                "[preset.web]\nauto-orient = \"yes\"",
+                "line 2: auto-orient must be",
+            ),
+            (
+                "[preset.web]\npipeline = \"invert",
+                "line 2: Unclosed string",
+            ),
+        ] {
+            let message = text.parse::<Config>().unwrap_err();
+            assert!(message.starts_with(error), "{}: {}", text, message);
+        }
+    }
+
+    #[test]
+    fn test_apply_preset() {
+        let preset = Preset {
+            command: Some(Command::Grayscale),
+            auto_orient: true,
+            convert_to: Some(Profile::Srgb),
+            ..Preset::default()
+        };
+        let options = ProcessOptions {
+            convert_to: Some(Profile::DisplayP3),
+            ..ProcessOptions::default()
+        };
+
+        let (command, options) = preset.apply(Some(Command::Invert), options).unwrap();
+        assert_eq!(command.steps(), [&Command::Grayscale, &Command::Invert]);
+        assert!(options.auto_orient);
+        assert_eq!(options.convert_to, Some(Profile::DisplayP3));
+
+        let (command, _) = preset.apply(None, ProcessOptions::default()).unwrap();
+        assert_eq!(command, Command::Grayscale);
+        assert!(
+            Preset::default()
+                .apply(None, ProcessOptions::default())
+                .is_err()
+        );
+    }
+}
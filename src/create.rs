@@ -0,0 +1,159 @@
+use crate::filters::Rng;
+use crate::{Fill, Pattern};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Octaves of noise added together, each with half the feature size of the one before
+const OCTAVES: u32 = 4;
+
+/// Create a `width` x `height` image filled with the pattern
+///
+/// The result is RGB when the pattern has no transparency. The seed picks
+/// the noise, the same seed gives the same image.
+pub fn create(width: u32, height: u32, pattern: &Pattern, seed: u64) -> DynamicImage {
+    let canvas = match pattern {
+        Pattern::Fill(Fill::Solid(color)) => RgbaImage::from_pixel(width, height, (*color).into()),
+        Pattern::Fill(Fill::Gradient(gradient)) => gradient.render(width, height),
+        Pattern::Checkerboard { size, colors } => {
+            let size = (*size).max(1);
+            let colors = colors.map(Rgba::from);
+            RgbaImage::from_fn(width, height, |x, y| {
+                colors[((x / size + y / size) % 2) as usize]
+            })
+        }
+        Pattern::Noise { scale, colors } => {
+            let perlin = Perlin::new(seed);
+            let [low, high] = colors.map(Rgba::from);
+            RgbaImage::from_fn(width, height, |x, y| {
+                let t = perlin.fractal(x as f32 / scale, y as f32 / scale);
+                mix(low, high, t)
+            })
+        }
+    };
+    match canvas.pixels().all(|p| p[3] == 255) {
+        true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+        false => DynamicImage::ImageRgba8(canvas),
+    }
+}
+
+fn mix(low: Rgba<u8>, high: Rgba<u8>, t: f32) -> Rgba<u8> {
+    Rgba(std::array::from_fn(|c| {
+        (low[c] as f32 + (high[c] as f32 - low[c] as f32) * t).round() as u8
+    }))
+}
+
+/// Gradient noise, smooth random hills repeating every 256 cells
+struct Perlin {
+    permutation: [u8; 512],
+}
+
+impl Perlin {
+    fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+        // Fisher-Yates shuffle
+        for i in (1..256).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+        Perlin {
+            permutation: std::array::from_fn(|i| table[i % 256]),
+        }
+    }
+
+    /// Noise at a point, about -1.0 ~ 1.0
+    fn noise(&self, x: f32, y: f32) -> f32 {
+        let (cell_x, cell_y) = (x.floor(), y.floor());
+        let (fx, fy) = (x - cell_x, y - cell_y);
+        let (cx, cy) = (
+            cell_x.rem_euclid(256.0) as usize,
+            cell_y.rem_euclid(256.0) as usize,
+        );
+        let p = &self.permutation;
+        let corner = |dx: usize, dy: usize| p[p[cx + dx] as usize + cy + dy];
+        // Dot product of the corner's gradient with the distance to the point
+        let gradient = |hash: u8, x: f32, y: f32| match hash & 3 {
+            0 => x + y,
+            1 => -x + y,
+            2 => x - y,
+            _ => -x - y,
+        };
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let (u, v) = (fade(fx), fade(fy));
+        let top = lerp(
+            gradient(corner(0, 0), fx, fy),
+            gradient(corner(1, 0), fx - 1.0, fy),
+            u,
+        );
+        let bottom = lerp(
+            gradient(corner(0, 1), fx, fy - 1.0),
+            gradient(corner(1, 1), fx - 1.0, fy - 1.0),
+            u,
+        );
+        lerp(top, bottom, v)
+    }
+
+    /// Octaves of noise added together, 0.0 ~ 1.0
+    fn fractal(&self, x: f32, y: f32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..OCTAVES {
+            sum += self.noise(x * frequency, y * frequency) * amplitude;
+            total += amplitude;
+            amplitude /= 2.0;
+            frequency *= 2.0;
+        }
+        (sum / total * 0.5 + 0.5).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Color;
+
+    #[test]
+    fn test_create_fill() {
+        let img = create(4, 3, &Pattern::Fill(Fill::Solid(Color::Red)), 0);
+        assert_eq!((img.width(), img.height()), (4, 3));
+        assert!(!img.color().has_alpha());
+        assert_eq!(img.to_rgba8().get_pixel(3, 2).0, [255, 0, 0, 255]);
+
+        let gradient = "gradient(linear,90,black,white)".parse().unwrap();
+        let img = create(10, 1, &Pattern::Fill(gradient), 0).to_rgba8();
+        assert!(img.get_pixel(0, 0)[0] < img.get_pixel(9, 0)[0]);
+
+        let clear = create(2, 2, &Pattern::Fill("transparent".parse().unwrap()), 0);
+        assert!(clear.color().has_alpha());
+    }
+
+    #[test]
+    fn test_create_checkerboard() {
+        let pattern = Pattern::Checkerboard {
+            size: 2,
+            colors: [Color::White, Color::Black],
+        };
+        let img = create(4, 4, &pattern, 0).to_rgba8();
+        assert_eq!(img.get_pixel(1, 1).0, [255, 255, 255, 255]);
+        assert_eq!(img.get_pixel(2, 1).0, [0, 0, 0, 255]);
+        assert_eq!(img.get_pixel(3, 3).0, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_create_noise() {
+        let pattern = Pattern::Noise {
+            scale: 16.0,
+            colors: [Color::Black, Color::White],
+        };
+        let img = create(64, 64, &pattern, 7).to_rgba8();
+        // Smooth: neighbours are close, but the image isn't flat
+        let (min, max) = img
+            .pixels()
+            .fold((255, 0), |(lo, hi), p| (p[0].min(lo), p[0].max(hi)));
+        assert!(max - min > 64);
+        assert!(img.get_pixel(10, 10)[0].abs_diff(img.get_pixel(11, 10)[0]) < 32);
+        // The seed repeats the noise
+        assert_eq!(create(64, 64, &pattern, 7).to_rgba8(), img);
+        assert_ne!(create(64, 64, &pattern, 8).to_rgba8(), img);
+    }
+}
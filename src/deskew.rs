@@ -0,0 +1,260 @@
+use crate::geometry::sample_bilinear;
+use crate::process::{rgba8, with_color_type};
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, GrayImage, Rgba, RgbaImage};
+use imageproc::contrast::otsu_level;
+use imageproc::edges::canny;
+
+/// Longest side the angle is searched at, larger pages are scaled down first
+const MAX_SIDE: u32 = 1000;
+/// Fewest ink pixels that are worth measuring
+const MIN_INK: usize = 50;
+/// Shortest horizon as a share of the image width
+const MIN_HORIZON: f32 = 0.25;
+/// Angle between the lines tried for the horizon in degrees
+const HORIZON_STEP: f32 = 0.1;
+
+/// Angle in degrees the lines of the page are turned clockwise, within `max_angle`
+///
+/// The dark pixels, or the light ones on a dark page, are projected onto
+/// rows at each angle. Lines of text put them into few rows with empty
+/// rows between, so the angle that piles them up highest is the skew. Pages
+/// with too little ink give `None`.
+pub fn skew_angle(img: &DynamicImage, max_angle: f32) -> Option<f32> {
+    let gray = small_gray(img);
+    let level = otsu_level(&gray);
+    let dark = gray.pixels().filter(|p| p[0] <= level).count();
+    // Ink is the rarer of dark and light
+    let ink_is_dark = dark * 2 <= gray.len();
+    let (cx, cy) = (gray.width() as f32 / 2.0, gray.height() as f32 / 2.0);
+    let ink: Vec<(f32, f32)> = gray
+        .enumerate_pixels()
+        .filter(|(_, _, p)| (p[0] <= level) == ink_is_dark)
+        .map(|(x, y, _)| (x as f32 - cx, y as f32 - cy))
+        .collect();
+    if ink.len() < MIN_INK || ink.len() == gray.len() {
+        return None;
+    }
+
+    // A turned page grows in height by at most its width
+    let rows = (gray.width() + gray.height()) as usize + 2;
+    let piling = |degrees: f32| {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut profile = vec![0i64; rows];
+        for &(x, y) in &ink {
+            let row = y * cos - x * sin + rows as f32 / 2.0;
+            profile[row as usize] += 1;
+        }
+        profile.iter().map(|count| count * count).sum::<i64>()
+    };
+    let best = |from: f32, to: f32, step: f32| {
+        let steps = ((to - from) / step).round() as i32;
+        (0..=steps)
+            .map(|i| from + i as f32 * step)
+            .map(|degrees| (degrees, piling(degrees)))
+            .fold((0.0, i64::MIN), |best, next| match next.1 > best.1 {
+                true => next,
+                false => best,
+            })
+    };
+    // Coarse steps first, then finer ones around the best of them
+    let (coarse, _) = best(-max_angle, max_angle, 0.5);
+    let (fine, score) = best(
+        (coarse - 0.5).max(-max_angle),
+        (coarse + 0.5).min(max_angle),
+        0.05,
+    );
+    // Stay put unless turning makes a difference
+    match score > piling(0.0) {
+        true => Some(fine),
+        false => Some(0.0),
+    }
+}
+
+/// Angle in degrees the horizon is turned clockwise, within `max_angle`
+///
+/// Every edge pixel votes for the lines through it at each angle, a Hough
+/// transform, and the line with the most votes gives the tilt, e.g. the
+/// horizon or the edge of a building. Photos without a line across at least
+/// a quarter of their width give `None`.
+pub fn horizon_angle(img: &DynamicImage, max_angle: f32) -> Option<f32> {
+    let gray = small_gray(img);
+    let edges = canny(&gray, 20.0, 50.0);
+    let (cx, cy) = (gray.width() as f32 / 2.0, gray.height() as f32 / 2.0);
+    let points: Vec<(f32, f32)> = edges
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p[0] > 0)
+        .map(|(x, y, _)| (x as f32 - cx, y as f32 - cy))
+        .collect();
+
+    let rows = (gray.width() + gray.height()) as usize + 2;
+    let steps = (max_angle / HORIZON_STEP).round() as i32;
+    // Level angles first, so ties keep the smaller turn
+    let mut angles: Vec<i32> = (-steps..=steps).collect();
+    angles.sort_by_key(|i| i.abs());
+    let mut best = (0.0, 0);
+    for i in angles {
+        let degrees = i as f32 * HORIZON_STEP;
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut votes = vec![0u32; rows];
+        for &(x, y) in &points {
+            votes[(y * cos - x * sin + rows as f32 / 2.0) as usize] += 1;
+        }
+        // Edges can be two pixels thick where a step falls between pixels
+        let most = votes
+            .windows(2)
+            .map(|pair| pair[0] + pair[1])
+            .max()
+            .unwrap_or(0);
+        if most > best.1 {
+            best = (degrees, most);
+        }
+    }
+    (best.1 as f32 >= gray.width() as f32 * MIN_HORIZON).then_some(best.0)
+}
+
+/// Turn the image `skew` degrees counterclockwise and crop off the corners turned into view
+///
+/// What is left is the largest centered rectangle of the image's aspect ratio
+/// inside the turned image.
+pub fn level(img: &DynamicImage, skew: f32) -> DynamicImage {
+    let turned = straighten(img, skew, Rgba([0, 0, 0, 255]));
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let (sin, cos) = skew.abs().to_radians().sin_cos();
+    let scale = (w / (w * cos + h * sin)).min(h / (w * sin + h * cos));
+    // A pixel less on each side, pixels on the edge blend with the corners
+    let inside = |side: f32| ((side * scale).floor() as u32).saturating_sub(2).max(1);
+    let (crop_w, crop_h) = (inside(w), inside(h));
+    turned.crop_imm(
+        (img.width() - crop_w) / 2,
+        (img.height() - crop_h) / 2,
+        crop_w,
+        crop_h,
+    )
+}
+
+/// The image in grayscale, scaled down to at most `MAX_SIDE`
+fn small_gray(img: &DynamicImage) -> GrayImage {
+    match img.width().max(img.height()) > MAX_SIDE {
+        true => img.resize(MAX_SIDE, MAX_SIDE, FilterType::Triangle),
+        false => img.clone(),
+    }
+    .into_luma8()
+}
+
+/// Turn the image `skew` degrees counterclockwise about its center, keeping its size
+///
+/// Corners turned into view are filled with the background. The color type
+/// is kept, gaining alpha when the background is transparent.
+pub fn straighten(img: &DynamicImage, skew: f32, background: Rgba<u8>) -> DynamicImage {
+    let rgba = rgba8(img);
+    let (sin, cos) = (-skew).to_radians().sin_cos();
+    let (cx, cy) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+    // Turn each output pixel center back to where it comes from
+    let canvas = RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        let sx = cx + dx * cos + dy * sin;
+        let sy = cy - dx * sin + dy * cos;
+        sample_bilinear(&rgba, sx - 0.5, sy - 0.5, background)
+    });
+    let color = match img.color() {
+        color if background[3] == 255 || color.has_alpha() => color,
+        ColorType::L8 => ColorType::La8,
+        ColorType::L16 => ColorType::La16,
+        ColorType::Rgb16 => ColorType::Rgba16,
+        ColorType::Rgb32F => ColorType::Rgba32F,
+        _ => ColorType::Rgba8,
+    };
+    with_color_type(canvas, color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb, RgbImage};
+
+    /// A page of dashed black lines of text, turned `degrees` clockwise
+    fn page(degrees: f32) -> DynamicImage {
+        let mut img = GrayImage::from_pixel(300, 200, Luma([255]));
+        for y in (30..170).step_by(20) {
+            for x in 30..270 {
+                if x % 12 < 9 {
+                    for dy in 0..5 {
+                        img.put_pixel(x, y + dy, Luma([0]));
+                    }
+                }
+            }
+        }
+        straighten(&img.into(), -degrees, Rgba([255, 255, 255, 255]))
+    }
+
+    #[test]
+    fn test_skew_angle() {
+        for degrees in [0.0, 3.0, -2.2, 7.5] {
+            let found = skew_angle(&page(degrees), 10.0).unwrap();
+            assert!((found - degrees).abs() <= 0.15, "{} for {}", found, degrees);
+        }
+        // The search stays inside the bound
+        assert!(skew_angle(&page(7.5), 5.0).unwrap().abs() <= 5.0);
+        // White text on black
+        let mut inverted = page(-4.0);
+        inverted.invert();
+        assert!((skew_angle(&inverted, 10.0).unwrap() + 4.0).abs() <= 0.15);
+
+        let blank = DynamicImage::ImageLuma8(GrayImage::from_pixel(50, 50, Luma([255])));
+        assert_eq!(skew_angle(&blank, 10.0), None);
+    }
+
+    /// Bright sky over dark ground, the horizon turned `degrees` clockwise
+    fn landscape(degrees: f32) -> DynamicImage {
+        let tan = degrees.to_radians().tan();
+        let img = RgbImage::from_fn(300, 200, |x, y| {
+            match y as f32 > 100.0 + (x as f32 - 150.0) * tan {
+                true => Rgb([40, 70, 30]),
+                false => Rgb([170, 200, 240]),
+            }
+        });
+        img.into()
+    }
+
+    #[test]
+    fn test_horizon_angle() {
+        for degrees in [0.0, 3.0, -1.5] {
+            let found = horizon_angle(&landscape(degrees), 5.0).unwrap();
+            assert!((found - degrees).abs() <= 0.15, "{} for {}", found, degrees);
+        }
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 200, Rgb([90; 3])));
+        assert_eq!(horizon_angle(&flat, 5.0), None);
+    }
+
+    #[test]
+    fn test_level() {
+        let img = level(&landscape(3.0), 3.0);
+        assert_eq!(img.color(), ColorType::Rgb8);
+        assert_eq!((img.width(), img.height()), (276, 183));
+        // The horizon runs level through the middle, no black corners are left
+        let img = img.to_rgb8();
+        for x in [5, 270] {
+            assert_eq!(img.get_pixel(x, 86).0, [170, 200, 240]);
+            assert_eq!(img.get_pixel(x, 96).0, [40, 70, 30]);
+        }
+        for (x, y) in [(0, 0), (275, 0), (0, 182), (275, 182)] {
+            assert!(img.get_pixel(x, y)[2] > 20);
+        }
+    }
+
+    #[test]
+    fn test_straighten() {
+        let img = page(5.0);
+        assert_eq!(img.color(), ColorType::L8);
+        assert_eq!((img.width(), img.height()), (300, 200));
+        // Clockwise moves the right end of a line down
+        let gray = img.to_luma8();
+        assert!(gray.get_pixel(260, 32 + 20 * 3)[0] > 128);
+        assert!(gray.get_pixel(260, 32 + 20 * 3 + 10)[0] < 128);
+
+        let clear = straighten(&img, 10.0, Rgba([0, 0, 0, 0]));
+        assert_eq!(clear.color(), ColorType::La8);
+        assert_eq!(clear.to_rgba8().get_pixel(0, 0)[3], 0);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::BlendMode;
+use crate::composite::blend;
+use crate::geometry::pad;
+use crate::process::with_color_type;
+use image::{DynamicImage, GrayImage, Luma, Rgba, RgbaImage};
+use imageproc::drawing::{Canvas, draw_filled_circle_mut, draw_filled_rect_mut};
+use imageproc::rect::Rect;
+
+/// Fill a `width` x `height` rectangle at `(x, y)` with corners rounded by `radius`
+///
+/// The radius is limited to half of the shorter side.
+pub fn draw_rounded_rect_mut<C: Canvas>(
+    canvas: &mut C,
+    (x, y): (i32, i32),
+    (width, height): (u32, u32),
+    radius: u32,
+    color: C::Pixel,
+) {
+    if width == 0 || height == 0 {
+        return;
+    }
+    let r = radius.min(width / 2).min(height / 2);
+
+    // A cross of two rectangles, the corners are filled by circles
+    if width > 2 * r {
+        let rect = Rect::at(x + r as i32, y).of_size(width - 2 * r, height);
+        draw_filled_rect_mut(canvas, rect, color);
+    }
+    if height > 2 * r {
+        let rect = Rect::at(x, y + r as i32).of_size(width, height - 2 * r);
+        draw_filled_rect_mut(canvas, rect, color);
+    }
+    if r > 0 {
+        let (r, right, bottom) = (
+            r as i32,
+            x + width as i32 - 1 - r as i32,
+            y + height as i32 - 1 - r as i32,
+        );
+        for center in [
+            (x + r, y + r),
+            (right, y + r),
+            (x + r, bottom),
+            (right, bottom),
+        ] {
+            draw_filled_circle_mut(canvas, center, r, color);
+        }
+    }
+}
+
+/// Draw a border of `width` pixels around the image
+///
+/// An inset border covers the edge of the image, an outset border grows the
+/// canvas by `width` on each side. With a radius the outer corners are
+/// rounded and become transparent.
+pub fn border(
+    img: &DynamicImage,
+    width: u32,
+    radius: u32,
+    color: Rgba<u8>,
+    inset: bool,
+) -> DynamicImage {
+    if width == 0 && radius == 0 {
+        return img.clone();
+    }
+
+    let mut canvas = match inset {
+        true => img.to_rgba8(),
+        false => {
+            let (w, h) = (img.width() + 2 * width, img.height() + 2 * width);
+            let offset = (width as i64, width as i64);
+            pad(img, w, h, offset, Rgba([0, 0, 0, 0])).into_rgba8()
+        }
+    };
+    let (w, h) = canvas.dimensions();
+
+    // Frame: the outer rounded rectangle minus the inner one
+    let mut frame = RgbaImage::new(w, h);
+    draw_rounded_rect_mut(&mut frame, (0, 0), (w, h), radius, color);
+    if w > 2 * width && h > 2 * width {
+        let inner = (w - 2 * width, h - 2 * width);
+        let inner_radius = radius.saturating_sub(width);
+        let (x, y) = (width as i32, width as i32);
+        draw_rounded_rect_mut(&mut frame, (x, y), inner, inner_radius, Rgba([0, 0, 0, 0]));
+    }
+    blend(&mut canvas, &frame, 0, 0, 1.0, BlendMode::Normal);
+
+    if radius == 0 && color[3] == 255 {
+        return with_color_type(canvas, img.color());
+    }
+
+    // Clear everything outside the rounded corners
+    let mut mask = GrayImage::new(w, h);
+    draw_rounded_rect_mut(&mut mask, (0, 0), (w, h), radius, Luma([255]));
+    for (pixel, inside) in canvas.pixels_mut().zip(mask.pixels()) {
+        if inside[0] == 0 {
+            *pixel = Rgba([0, 0, 0, 0]);
+        }
+    }
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Make the corners of the image transparent, or everything outside the inscribed ellipse
+///
+/// Edges are anti-aliased from the distance of each pixel center to the
+/// outline. The radius is limited to half of the shorter side.
+pub fn round(img: &DynamicImage, radius: f32, ellipse: bool) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    let (w, h) = (rgba.width() as f32, rgba.height() as f32);
+    let r = radius.clamp(0.0, w.min(h) / 2.0);
+    let (a, b) = (w / 2.0, h / 2.0);
+
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let distance = match ellipse {
+            // First order distance to the ellipse outline
+            true => {
+                let (dx, dy) = (px - a, py - b);
+                let f = dx * dx / (a * a) + dy * dy / (b * b) - 1.0;
+                let gradient = 2.0 * (dx * dx / a.powi(4) + dy * dy / b.powi(4)).sqrt();
+                match gradient > 0.0 {
+                    true => f / gradient,
+                    false => f32::NEG_INFINITY,
+                }
+            }
+            // Distance to the corner arc, straight edges are inside
+            false => {
+                let qx = (r - px).max(px - (w - r)).max(0.0);
+                let qy = (r - py).max(py - (h - r)).max(0.0);
+                match qx > 0.0 && qy > 0.0 {
+                    true => (qx * qx + qy * qy).sqrt() - r,
+                    false => f32::NEG_INFINITY,
+                }
+            }
+        };
+        let coverage = (0.5 - distance).clamp(0.0, 1.0);
+        pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ColorType, Rgb, RgbImage};
+
+    fn gray() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 8, Rgb([128, 128, 128])))
+    }
+
+    #[test]
+    fn test_rounded_rect() {
+        let mut mask = GrayImage::new(10, 10);
+        draw_rounded_rect_mut(&mut mask, (0, 0), (10, 10), 4, Luma([255]));
+        assert_eq!(mask.get_pixel(0, 0)[0], 0);
+        assert_eq!(mask.get_pixel(5, 0)[0], 255);
+        assert_eq!(mask.get_pixel(0, 5)[0], 255);
+        assert_eq!(mask.get_pixel(5, 5)[0], 255);
+        assert_eq!(mask.get_pixel(9, 9)[0], 0);
+    }
+
+    #[test]
+    fn test_round_corners() {
+        let rounded = round(&gray(), 4.0, false).into_rgba8();
+        assert_eq!(rounded.get_pixel(0, 0)[3], 0);
+        assert_eq!(rounded.get_pixel(9, 7)[3], 0);
+        assert_eq!(rounded.get_pixel(5, 0)[3], 255);
+        assert_eq!(rounded.get_pixel(5, 4)[3], 255);
+        // The arc edge is partially transparent
+        let edge = rounded.get_pixel(1, 1)[3];
+        assert!(edge > 0 && edge < 255, "{}", edge);
+
+        // No radius leaves the image opaque
+        let square = round(&gray(), 0.0, false).into_rgba8();
+        assert!(square.pixels().all(|p| p[3] == 255));
+    }
+
+    #[test]
+    fn test_round_ellipse() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(20, 10, Rgb([1, 2, 3])));
+        let ellipse = round(&img, 0.0, true).into_rgba8();
+        assert_eq!(ellipse.get_pixel(0, 0)[3], 0);
+        assert_eq!(ellipse.get_pixel(10, 5)[3], 255);
+        assert_eq!(ellipse.get_pixel(10, 0)[3], 255);
+        assert_eq!(ellipse.get_pixel(1, 5)[3], 255);
+        assert_eq!(ellipse.get_pixel(19, 0)[3], 0);
+    }
+
+    #[test]
+    fn test_border_outset() {
+        let red = Rgba([255, 0, 0, 255]);
+        let framed = border(&gray(), 2, 0, red, false);
+        assert_eq!((framed.width(), framed.height()), (14, 12));
+        assert_eq!(framed.color(), ColorType::Rgb8);
+        let rgb = framed.to_rgb8();
+        assert_eq!(rgb.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(rgb.get_pixel(1, 6), &Rgb([255, 0, 0]));
+        assert_eq!(rgb.get_pixel(2, 2), &Rgb([128, 128, 128]));
+        assert_eq!(rgb.get_pixel(13, 11), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn test_border_inset_rounded() {
+        let red = Rgba([255, 0, 0, 255]);
+        let framed = border(&gray(), 1, 3, red, true);
+        assert_eq!((framed.width(), framed.height()), (10, 8));
+        let rgba = framed.to_rgba8();
+        // Corners are cut off, the edges are covered by the border
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(rgba.get_pixel(5, 0), &red);
+        assert_eq!(rgba.get_pixel(5, 4), &Rgba([128, 128, 128, 255]));
+    }
+}
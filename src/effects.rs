@@ -0,0 +1,222 @@
+use crate::draw::draw_rounded_rect_mut;
+use crate::{RedactMethod, Region};
+use image::{GenericImageView, GrayImage, Luma, Rgba, RgbaImage, imageops};
+
+/// Blend the image towards `color` with a radial falloff from the center
+///
+/// Distances are measured on an ellipse fitted to the image, so the falloff
+/// starts at `radius` (0.0 center, 1.0 corners) and reaches `strength` in the
+/// corners. The alpha of `color` scales the strength.
+pub fn vignette(img: &mut RgbaImage, strength: f32, radius: f32, color: Rgba<u8>) {
+    let (cx, cy) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+    let strength = strength * color[3] as f32 / 255.0;
+
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let dx = (x as f32 + 0.5 - cx) / cx;
+        let dy = (y as f32 + 0.5 - cy) / cy;
+        let distance = ((dx * dx + dy * dy) / 2.0).sqrt();
+
+        // Smoothstep between the radius and the corners
+        let t = ((distance - radius) / (1.0 - radius)).clamp(0.0, 1.0);
+        let amount = t * t * (3.0 - 2.0 * t) * strength;
+        for i in 0..3 {
+            let value = pixel[i] as f32 + (color[i] as f32 - pixel[i] as f32) * amount;
+            pixel[i] = value.round() as u8;
+        }
+    }
+}
+
+/// Reduce each color channel to `levels` evenly spaced values
+pub fn posterize(img: &mut RgbaImage, levels: u8) {
+    let steps = levels.max(2) as f32 - 1.0;
+    for pixel in img.pixels_mut() {
+        for i in 0..3 {
+            let level = (pixel[i] as f32 / 255.0 * steps).round();
+            pixel[i] = (level * 255.0 / steps).round() as u8;
+        }
+    }
+}
+
+/// Invert the color channels at or above `threshold`
+pub fn solarize(img: &mut RgbaImage, threshold: u8) {
+    for pixel in img.pixels_mut() {
+        for i in 0..3 {
+            if pixel[i] >= threshold {
+                pixel[i] = 255 - pixel[i];
+            }
+        }
+    }
+}
+
+/// Turn gray values at or above `value` white and the rest black
+pub fn threshold(img: &mut GrayImage, value: u8) {
+    for pixel in img.pixels_mut() {
+        pixel[0] = if pixel[0] >= value { 255 } else { 0 };
+    }
+}
+
+/// Pixelate or blur an area of the image, the part of it outside the image is ignored
+///
+/// Only pixels inside the area are used, so nothing around it shows through
+/// the blur. A radius rounds the corners of the area.
+pub fn redact(
+    img: &mut RgbaImage,
+    region: Region,
+    method: RedactMethod,
+    strength: f32,
+    radius: u32,
+) {
+    let x = region.x.min(img.width());
+    let y = region.y.min(img.height());
+    let w = region.width.min(img.width() - x);
+    let h = region.height.min(img.height() - y);
+    if w == 0 || h == 0 {
+        return;
+    }
+    let area = imageops::crop_imm(img, x, y, w, h).to_image();
+    let obscured = match method {
+        RedactMethod::Pixelate => pixelate(&area, strength.round().max(1.0) as u32),
+        RedactMethod::Blur => imageops::blur(&area, strength),
+    };
+    let mut mask = GrayImage::new(w, h);
+    draw_rounded_rect_mut(&mut mask, (0, 0), (w, h), radius, Luma([255]));
+    for (px, py, pixel) in obscured.enumerate_pixels() {
+        if mask.get_pixel(px, py)[0] != 0 {
+            img.put_pixel(x + px, y + py, *pixel);
+        }
+    }
+}
+
+/// Fill blocks of `size` pixels with their average color, from the top left corner
+fn pixelate(img: &RgbaImage, size: u32) -> RgbaImage {
+    let mut result = img.clone();
+    for by in (0..img.height()).step_by(size as usize) {
+        for bx in (0..img.width()).step_by(size as usize) {
+            let (w, h) = (size.min(img.width() - bx), size.min(img.height() - by));
+            let block = imageops::crop_imm(img, bx, by, w, h);
+            // Colors are weighted by alpha so transparent pixels don't darken the block
+            let mut sum = [0u64; 4];
+            for (_, _, pixel) in block.pixels() {
+                let alpha = pixel[3] as u64;
+                for c in 0..3 {
+                    sum[c] += pixel[c] as u64 * alpha;
+                }
+                sum[3] += alpha;
+            }
+            let count = (w * h) as u64;
+            let color = match sum[3] {
+                0 => Rgba([0, 0, 0, 0]),
+                alpha => Rgba([
+                    ((sum[0] + alpha / 2) / alpha) as u8,
+                    ((sum[1] + alpha / 2) / alpha) as u8,
+                    ((sum[2] + alpha / 2) / alpha) as u8,
+                    ((alpha + count / 2) / count) as u8,
+                ]),
+            };
+            for py in by..by + h {
+                for px in bx..bx + w {
+                    result.put_pixel(px, py, color);
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posterize() {
+        let mut img = RgbaImage::from_fn(4, 1, |x, _| Rgba([x as u8 * 60, 100, 200, 7]));
+        posterize(&mut img, 2);
+        assert_eq!(img.get_pixel(0, 0).0, [0, 0, 255, 7]);
+        assert_eq!(img.get_pixel(3, 0).0, [255, 0, 255, 7]);
+
+        let mut img = RgbaImage::from_pixel(1, 1, Rgba([100, 40, 220, 255]));
+        posterize(&mut img, 3);
+        assert_eq!(img.get_pixel(0, 0).0, [128, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_solarize_and_threshold() {
+        let mut img = RgbaImage::from_pixel(1, 1, Rgba([100, 128, 200, 255]));
+        solarize(&mut img, 128);
+        assert_eq!(img.get_pixel(0, 0).0, [100, 127, 55, 255]);
+
+        let mut gray = GrayImage::from_fn(3, 1, |x, _| image::Luma([[10, 128, 250][x as usize]]));
+        threshold(&mut gray, 128);
+        assert_eq!(gray.as_raw(), &[0, 255, 255]);
+    }
+
+    #[test]
+    fn test_vignette() {
+        let white = Rgba([255, 255, 255, 255]);
+        let mut img = RgbaImage::from_pixel(100, 60, white);
+        vignette(&mut img, 1.0, 0.5, Rgba([0, 0, 0, 255]));
+
+        // The center keeps its color, corners are darkened the most
+        assert_eq!(img.get_pixel(50, 30), &white);
+        let corner = img.get_pixel(0, 0)[0];
+        let edge = img.get_pixel(0, 30)[0];
+        assert!(corner < 10, "{}", corner);
+        assert!(corner < edge && edge < 255, "{} {}", corner, edge);
+        assert_eq!(img.get_pixel(0, 0)[3], 255);
+    }
+
+    #[test]
+    fn test_vignette_strength_and_color() {
+        let gray = Rgba([100, 100, 100, 255]);
+        let mut img = RgbaImage::from_pixel(10, 10, gray);
+        vignette(&mut img, 0.0, 0.5, Rgba([0, 0, 0, 255]));
+        assert!(img.pixels().all(|p| *p == gray));
+
+        // A white vignette lightens towards the corners
+        vignette(&mut img, 1.0, 0.0, Rgba([255, 255, 255, 255]));
+        assert!(img.get_pixel(0, 0)[0] > 200);
+    }
+
+    #[test]
+    fn test_redact() {
+        // Stripes that pixelation averages out
+        let stripes = RgbaImage::from_fn(40, 40, |x, _| match x % 2 {
+            0 => Rgba([0, 0, 0, 255]),
+            _ => Rgba([200, 100, 50, 255]),
+        });
+        let region = Region {
+            x: 4,
+            y: 4,
+            width: 16,
+            height: 16,
+        };
+        let mut img = stripes.clone();
+        redact(&mut img, region, RedactMethod::Pixelate, 8.0, 0);
+        assert_eq!(img.get_pixel(4, 4).0, [100, 50, 25, 255]);
+        assert_eq!(img.get_pixel(19, 19).0, [100, 50, 25, 255]);
+        assert_eq!(img.get_pixel(3, 4), stripes.get_pixel(3, 4));
+        assert_eq!(img.get_pixel(20, 10), stripes.get_pixel(20, 10));
+
+        let mut img = stripes.clone();
+        redact(&mut img, region, RedactMethod::Blur, 4.0, 0);
+        let pixel = img.get_pixel(12, 12);
+        assert!(pixel[0].abs_diff(100) < 10 && pixel[1].abs_diff(50) < 10);
+
+        // Rounded corners keep the pixels outside them
+        let mut img = stripes.clone();
+        redact(&mut img, region, RedactMethod::Pixelate, 4.0, 6);
+        assert_eq!(img.get_pixel(4, 4), stripes.get_pixel(4, 4));
+        assert_eq!(img.get_pixel(12, 4).0, [100, 50, 25, 255]);
+
+        // Areas reaching past the edge are clipped
+        let mut img = stripes.clone();
+        let edge = Region {
+            x: 30,
+            y: 30,
+            width: 100,
+            height: 100,
+        };
+        redact(&mut img, edge, RedactMethod::Pixelate, 10.0, 0);
+        assert_eq!(img.get_pixel(39, 39).0, [100, 50, 25, 255]);
+    }
+}
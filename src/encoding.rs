@@ -0,0 +1,438 @@
+use crate::{ImgtoolsError, Metadata};
+use clap::ValueEnum;
+use flate2::write::ZlibEncoder;
+use flate2::{Compress, Compression, FlushCompress, Status};
+use image::error::{EncodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, ImageFormat};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// How the encoders lay out the data, for images that render while loading
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// Write JPEG as a sequence of scans that refine the image
+    pub progressive: bool,
+    /// Interlacing of PNG rows
+    pub interlace: Option<Interlace>,
+}
+
+/// PNG interlacing method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Interlace {
+    /// Seven passes over an 8x8 grid, a coarse image appears after 1/64 of the data
+    Adam7,
+}
+
+/// Passes of Adam7 as the first column and row and the steps between them
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Images with less data are compressed on one thread
+pub(crate) const PARALLEL_PNG_BYTES: usize = 1 << 20;
+
+/// Smallest stripe compressed on a thread of its own, each one starts
+/// without the previous data to refer back to
+const STRIPE_BYTES: usize = 1 << 17;
+
+/// Largest IDAT chunk written
+const IDAT_BYTES: usize = 1 << 20;
+
+/// Map an error of the png crate
+pub(crate) fn png_error(e: png::EncodingError) -> ImgtoolsError {
+    ImgtoolsError::Encode(ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        e,
+    )))
+}
+
+/// Encode an 8 or 16-bit image as PNG with the encoding options
+pub(crate) fn encode_png<W: Write>(
+    img: &DynamicImage,
+    metadata: &Metadata,
+    options: &EncodeOptions,
+    output: W,
+) -> Result<(), ImgtoolsError> {
+    let (color, depth) = match img {
+        DynamicImage::ImageLuma8(_) => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        DynamicImage::ImageLumaA8(_) => (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight),
+        DynamicImage::ImageRgb8(_) => (png::ColorType::Rgb, png::BitDepth::Eight),
+        DynamicImage::ImageRgba8(_) => (png::ColorType::Rgba, png::BitDepth::Eight),
+        DynamicImage::ImageLuma16(_) => (png::ColorType::Grayscale, png::BitDepth::Sixteen),
+        DynamicImage::ImageLumaA16(_) => (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen),
+        DynamicImage::ImageRgb16(_) => (png::ColorType::Rgb, png::BitDepth::Sixteen),
+        _ => (png::ColorType::Rgba, png::BitDepth::Sixteen),
+    };
+    // PNG stores 16-bit samples big-endian
+    let data = match depth {
+        png::BitDepth::Sixteen => Cow::Owned(
+            img.as_bytes()
+                .chunks_exact(2)
+                .flat_map(|sample| u16::from_ne_bytes([sample[0], sample[1]]).to_be_bytes())
+                .collect(),
+        ),
+        _ => Cow::Borrowed(img.as_bytes()),
+    };
+
+    // Without interlacing the image is only encoded here to use every
+    // thread, at the fast level image's PngEncoder defaults to
+    let compression = match options.interlace {
+        Some(_) => png::DeflateCompression::Level(6),
+        None => png::DeflateCompression::Level(1),
+    };
+    let mut info = png::Info::with_size(img.width(), img.height());
+    info.color_type = color;
+    info.bit_depth = depth;
+    info.interlaced = options.interlace.is_some();
+    info.icc_profile = metadata.icc.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = metadata.exif.as_deref().map(Cow::Borrowed);
+    write_png(info, &data, png::Filter::Adaptive, compression, output)
+}
+
+/// Write packed rows as PNG, interlaced if the info asks for it
+///
+/// The png crate only writes the interlace flag, so interlaced data is
+/// split into the Adam7 passes, filtered and compressed here. So is a large
+/// image when the thread pool has more than one thread, in stripes.
+pub(crate) fn write_png<W: Write>(
+    info: png::Info,
+    data: &[u8],
+    filter: png::Filter,
+    compression: png::DeflateCompression,
+    output: W,
+) -> Result<(), ImgtoolsError> {
+    let interlaced = info.interlaced;
+    let bits = info.bits_per_pixel();
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut encoder = png::Encoder::with_info(output, info).map_err(png_error)?;
+    encoder.set_deflate_compression(compression);
+    encoder.set_filter(filter);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    let level = match compression {
+        png::DeflateCompression::NoCompression => 0,
+        png::DeflateCompression::Level(level) => level.into(),
+        _ => 1,
+    };
+    if !interlaced && rayon::current_num_threads() > 1 && data.len() >= PARALLEL_PNG_BYTES {
+        let stride = (width * bits).div_ceil(8);
+        let compressed = compress_stripes(data, stride, bits.div_ceil(8), filter, level)?;
+        for chunk in compressed.chunks(IDAT_BYTES) {
+            writer
+                .write_chunk(png::chunk::IDAT, chunk)
+                .map_err(png_error)?;
+        }
+        return writer.finish().map_err(png_error);
+    }
+    if !interlaced {
+        writer.write_image_data(data).map_err(png_error)?;
+        return writer.finish().map_err(png_error);
+    }
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::new(level));
+    for pass in adam7_passes(data, width, height, bits) {
+        let mut previous = vec![0; pass.first().map_or(0, Vec::len)];
+        for row in pass {
+            zlib.write_all(&filter_row(&row, &previous, bits.div_ceil(8), filter))
+                .map_err(|e| ImgtoolsError::Encode(e.into()))?;
+            previous = row;
+        }
+    }
+    let compressed = zlib.finish().map_err(|e| ImgtoolsError::Encode(e.into()))?;
+    writer
+        .write_chunk(png::chunk::IDAT, &compressed)
+        .map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+/// Filter and compress rows as one zlib stream, stripes of them in parallel
+///
+/// Each stripe is deflated on its own and ends in a sync flush, which
+/// leaves it on a byte boundary so the stripes join into one stream. Their
+/// Adler-32 checksums are combined for the trailer.
+fn compress_stripes(
+    data: &[u8],
+    stride: usize,
+    bpp: usize,
+    filter: png::Filter,
+    level: u32,
+) -> Result<Vec<u8>, ImgtoolsError> {
+    let height = data.len() / stride;
+    let threads = rayon::current_num_threads();
+    let rows = height
+        .div_ceil(threads * 2)
+        .max(STRIPE_BYTES.div_ceil(stride));
+    let stripes: Vec<_> = (0..height)
+        .step_by(rows)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|start| {
+            let end = (start + rows).min(height);
+            let mut filtered = Vec::with_capacity((end - start) * (stride + 1));
+            let zeros = vec![0; stride];
+            for y in start..end {
+                let previous = match y {
+                    0 => &zeros[..],
+                    _ => &data[(y - 1) * stride..y * stride],
+                };
+                let row = &data[y * stride..(y + 1) * stride];
+                filtered.extend(filter_row(row, previous, bpp, filter));
+            }
+            let compressed = deflate(&filtered, level, end == height)?;
+            Ok((compressed, adler32(&filtered), filtered.len()))
+        })
+        .collect::<Result<_, io::Error>>()
+        .map_err(|e| ImgtoolsError::Encode(e.into()))?;
+
+    // The header for the level as zlib writes it, then the stripes
+    let flags = match level {
+        0 | 1 => 0x01,
+        2..=5 => 0x5e,
+        6 => 0x9c,
+        _ => 0xda,
+    };
+    let mut stream = vec![0x78, flags];
+    let mut checksum = 1;
+    for (compressed, adler, len) in stripes {
+        stream.extend(compressed);
+        checksum = adler32_combine(checksum, adler, len);
+    }
+    stream.extend(checksum.to_be_bytes());
+    Ok(stream)
+}
+
+/// Raw deflate data, ending in a sync flush or, for the last, the final block
+fn deflate(data: &[u8], level: u32, last: bool) -> io::Result<Vec<u8>> {
+    let mut compress = Compress::new(Compression::new(level), false);
+    let flush = match last {
+        true => FlushCompress::Finish,
+        false => FlushCompress::Sync,
+    };
+    let mut output = Vec::with_capacity(data.len() / 2 + 1024);
+    loop {
+        let consumed = compress.total_in() as usize;
+        let status = compress
+            .compress_vec(&data[consumed..], &mut output, flush)
+            .map_err(io::Error::other)?;
+        // A flush is done once it leaves room in the output
+        let flushed = compress.total_in() as usize == data.len()
+            && match last {
+                true => status == Status::StreamEnd,
+                false => output.len() < output.capacity(),
+            };
+        if flushed {
+            return Ok(output);
+        }
+        output.reserve(output.capacity().max(1024));
+    }
+}
+
+/// Largest prime below 2^16, the modulus of Adler-32
+const ADLER_BASE: u32 = 65521;
+
+/// Adler-32 checksum as zlib streams end with
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // The sums can't overflow in blocks of 5552 bytes
+    for block in data.chunks(5552) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_BASE;
+        b %= ADLER_BASE;
+    }
+    (b << 16) | a
+}
+
+/// Checksum of two pieces of data from their own, as zlib's adler32_combine
+fn adler32_combine(first: u32, second: u32, second_len: usize) -> u32 {
+    let base = ADLER_BASE as u64;
+    let rem = second_len as u64 % base;
+    let (a1, b1) = ((first & 0xffff) as u64, (first >> 16) as u64);
+    let (a2, b2) = ((second & 0xffff) as u64, (second >> 16) as u64);
+    let a = (a1 + a2 + base - 1) % base;
+    let b = (rem * a1 % base + b1 + b2 + base - rem) % base;
+    ((b << 16) | a) as u32
+}
+
+/// Rows of every Adam7 pass, empty passes have no rows
+fn adam7_passes(data: &[u8], width: usize, height: usize, bits: usize) -> Vec<Vec<Vec<u8>>> {
+    let stride = (width * bits).div_ceil(8);
+    ADAM7
+        .iter()
+        .map(|&(x0, y0, dx, dy)| {
+            let columns = (width + dx - 1 - x0) / dx;
+            if columns == 0 {
+                return Vec::new();
+            }
+            (y0..height)
+                .step_by(dy)
+                .map(|y| {
+                    let source = &data[y * stride..(y + 1) * stride];
+                    let mut row = vec![0; (columns * bits).div_ceil(8)];
+                    for (i, x) in (x0..width).step_by(dx).enumerate() {
+                        match bits {
+                            // Samples of less than a byte are moved bit by bit
+                            1 | 2 | 4 => {
+                                let value = source[x * bits / 8] >> (8 - bits - x * bits % 8);
+                                let mask = (1 << bits) - 1;
+                                row[i * bits / 8] |= (value & mask) << (8 - bits - i * bits % 8);
+                            }
+                            _ => {
+                                let size = bits / 8;
+                                row[i * size..(i + 1) * size]
+                                    .copy_from_slice(&source[x * size..(x + 1) * size]);
+                            }
+                        }
+                    }
+                    row
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Filter one row against the one above, prefixed with the filter type
+///
+/// The adaptive filter takes the type with the smallest sum of absolute
+/// differences, as libpng does.
+fn filter_row(row: &[u8], previous: &[u8], bpp: usize, filter: png::Filter) -> Vec<u8> {
+    let apply = |kind: u8| -> Vec<u8> {
+        let mut filtered = Vec::with_capacity(row.len() + 1);
+        filtered.push(kind);
+        for (i, &value) in row.iter().enumerate() {
+            let left = if i >= bpp { row[i - bpp] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+            let prediction = match kind {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                _ => paeth(left, up, up_left),
+            };
+            filtered.push(value.wrapping_sub(prediction));
+        }
+        filtered
+    };
+    match filter {
+        png::Filter::NoFilter => apply(0),
+        png::Filter::Sub => apply(1),
+        png::Filter::Up => apply(2),
+        png::Filter::Avg => apply(3),
+        png::Filter::Paeth => apply(4),
+        _ => (0..5)
+            .map(apply)
+            .min_by_key(|filtered| {
+                filtered[1..]
+                    .iter()
+                    .map(|&value| (value as i8).unsigned_abs() as u64)
+                    .sum::<u64>()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// Paeth predictor, whichever neighbour is closest to left + up - up left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (a, b, c) = (
+        (estimate - left as i16).abs(),
+        (estimate - up as i16).abs(),
+        (estimate - up_left as i16).abs(),
+    );
+    if a <= b && a <= c {
+        left
+    } else if b <= c {
+        up
+    } else {
+        up_left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, ImageBuffer, Rgba};
+
+    #[test]
+    fn test_interlaced_png() {
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(13, 11, |x, y| {
+            Rgba([(x * 19) as u8, (y * 23) as u8, (x * y) as u8, 200])
+        }));
+        let options = EncodeOptions {
+            interlace: Some(Interlace::Adam7),
+            ..Default::default()
+        };
+        for img in [img.clone(), DynamicImage::ImageRgba16(img.to_rgba16())] {
+            let mut data = Vec::new();
+            encode_png(&img, &Metadata::default(), &options, &mut data).unwrap();
+            // Byte 28 of the header is the interlace method
+            assert_eq!(data[28], 1);
+            let decoded = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+            assert_eq!(decoded.dimensions(), (13, 11));
+            assert_eq!(decoded.as_bytes(), img.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_png_stripes() {
+        // Adler-32 of "Wikipedia", combined from two pieces like the stripes
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        let combined = adler32_combine(adler32(b"Wiki"), adler32(b"pedia"), 5);
+        assert_eq!(combined, 0x11e60398);
+
+        let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(700, 500, |x, y| {
+            Rgba([(x * 3) as u8, (y * 5) as u8, ((x ^ y) % 256) as u8, 255])
+        }));
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        pool.install(|| encode_png(&img, &Metadata::default(), &Default::default(), &mut data))
+            .unwrap();
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+        assert_eq!(decoded.as_bytes(), img.as_bytes());
+    }
+
+    #[test]
+    fn test_interlaced_palette() {
+        // Two colors packed 1 bit per pixel, the passes split bytes apart
+        let (width, height) = (10, 9);
+        let pixels: Vec<u8> = (0..width * height).map(|i| (i % 3 == 0) as u8).collect();
+        let stride = (width as usize).div_ceil(8);
+        let mut packed = vec![0u8; stride * height as usize];
+        for (i, &pixel) in pixels.iter().enumerate() {
+            let (x, y) = (i % width as usize, i / width as usize);
+            packed[y * stride + x / 8] |= pixel << (7 - x % 8);
+        }
+        let mut info = png::Info::with_size(width, height);
+        info.color_type = png::ColorType::Indexed;
+        info.bit_depth = png::BitDepth::One;
+        info.palette = Some(Cow::Borrowed(&[0, 0, 0, 255, 255, 255]));
+        info.interlaced = true;
+        let mut data = Vec::new();
+        write_png(
+            info,
+            &packed,
+            png::Filter::Paeth,
+            png::DeflateCompression::Level(9),
+            &mut data,
+        )
+        .unwrap();
+
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Png)
+            .unwrap()
+            .to_luma8();
+        let expected: Vec<u8> = pixels.iter().map(|&pixel| pixel * 255).collect();
+        assert_eq!(decoded.into_raw(), expected);
+    }
+}
@@ -0,0 +1,129 @@
+use image::ImageError;
+use serde_json::{Value, json};
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Errors produced while processing images
+#[derive(Debug, Error)]
+pub enum ImgtoolsError {
+    /// A file or directory could not be read
+    #[error("Failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    /// A file or directory could not be written
+    #[error("Failed to write {}: {source}", path.display())]
+    Write { path: PathBuf, source: io::Error },
+    /// The output exists and replacing it was not forced
+    #[error("{} already exists, use --force to overwrite it", path.display())]
+    OutputExists { path: PathBuf },
+    /// The output would replace the input without --in-place
+    #[error("Refusing to overwrite the input {}, use --in-place to edit it", path.display())]
+    InputOverwrite { path: PathBuf },
+    /// The image data could not be decoded
+    #[error("Failed to decode image: {0}")]
+    Decode(#[source] ImageError),
+    /// The image could not be encoded or saved
+    #[error("Failed to encode image: {0}")]
+    Encode(#[source] ImageError),
+    /// The image metadata could not be parsed
+    #[error("Failed to read metadata: {0}")]
+    Metadata(#[source] exif::Error),
+    /// The font file could not be parsed
+    #[error("Unable to parse font file: {0}")]
+    Font(String),
+    /// An ICC color profile could not be parsed or applied
+    #[error("Unable to apply color profile: {0}")]
+    Profile(String),
+    /// A config or recipe file could not be parsed
+    #[error("Invalid {}: {message}", path.display())]
+    Config { path: PathBuf, message: String },
+    /// An input could not be downloaded
+    #[cfg(feature = "fetch")]
+    #[error("Failed to fetch {url}: {message}")]
+    Fetch { url: String, message: String },
+    /// The server could not listen on its address
+    #[cfg(feature = "serve")]
+    #[error("Unable to listen on {address}: {source}")]
+    Listen { address: String, source: io::Error },
+    /// An argument is outside of its valid range or does not fit the image
+    #[error("{0}")]
+    InvalidArgument(String),
+    /// Some images of a batch failed, each failure has already been reported
+    #[error("{failed} of {total} images failed")]
+    Batch { failed: usize, total: usize },
+    /// The image failed a quality check of the assess command
+    #[error("Quality check failed: {0}")]
+    QualityCheck(String),
+}
+
+impl ImgtoolsError {
+    /// Exit code of the command line tool for this error
+    ///
+    /// 2 for invalid arguments, 3 when an image can't be decoded, 4 when one
+    /// can't be encoded, 5 when some images of a batch failed, 6 when an image
+    /// fails a quality check and 1 for everything else, such as files that
+    /// can't be read or written.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ImgtoolsError::InvalidArgument(_)
+            | ImgtoolsError::Config { .. }
+            | ImgtoolsError::Font(_)
+            | ImgtoolsError::OutputExists { .. }
+            | ImgtoolsError::InputOverwrite { .. } => 2,
+            ImgtoolsError::Decode(_) | ImgtoolsError::Metadata(_) => 3,
+            ImgtoolsError::Encode(_) => 4,
+            ImgtoolsError::Batch { .. } => 5,
+            ImgtoolsError::QualityCheck(_) => 6,
+            _ => 1,
+        }
+    }
+}
+
+/// Report of the failed images of a batch, to retry only those
+///
+/// Lists every failed input with its error message and exit code.
+pub fn error_report(total: usize, failures: &[(&Path, &ImgtoolsError)]) -> Value {
+    let failures: Vec<_> = failures
+        .iter()
+        .map(|(input, error)| {
+            json!({
+                "input": input.display().to_string(),
+                "error": error.to_string(),
+                "exit_code": error.exit_code(),
+            })
+        })
+        .collect();
+    json!({
+        "total": total,
+        "failed": failures.len(),
+        "failures": failures,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_report() {
+        let decode = ImgtoolsError::Decode(ImageError::IoError(io::ErrorKind::InvalidData.into()));
+        let exists = ImgtoolsError::OutputExists {
+            path: PathBuf::from("out/b.png"),
+        };
+        let report = error_report(
+            3,
+            &[(Path::new("a.png"), &decode), (Path::new("b.png"), &exists)],
+        );
+        assert_eq!(report["total"], 3);
+        assert_eq!(report["failed"], 2);
+        assert_eq!(report["failures"][0]["input"], "a.png");
+        assert_eq!(report["failures"][0]["exit_code"], 3);
+        assert_eq!(report["failures"][1]["exit_code"], 2);
+        assert!(
+            report["failures"][1]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("out/b.png already exists")
+        );
+    }
+}
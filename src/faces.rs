@@ -0,0 +1,583 @@
+use crate::xml::{self, Element};
+use crate::{ImgtoolsError, Region};
+use image::{DynamicImage, GrayImage, imageops};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Environment variable naming the cascade file, when it isn't in a usual place
+const MODEL_VAR: &str = "IMGTOOLS_FACE_MODEL";
+
+/// The frontal face cascade that ships with OpenCV
+const MODEL_NAME: &str = "haarcascade_frontalface_default.xml";
+
+/// Where OpenCV's data packages install their cascades
+const MODEL_DIRS: [&str; 6] = [
+    "/usr/share/opencv4/haarcascades",
+    "/usr/share/opencv/haarcascades",
+    "/usr/local/share/opencv4/haarcascades",
+    "/usr/local/share/opencv/haarcascades",
+    "/opt/homebrew/share/opencv4/haarcascades",
+    "/opt/local/share/opencv4/haarcascades",
+];
+
+/// Longer side images are scaled down to before searching, faces smaller than
+/// the cascade's window at this size are missed
+const MAX_SIDE: u32 = 1200;
+
+/// Each level of the image pyramid is this much smaller than the one before
+const SCALE_STEP: f32 = 1.1;
+
+/// Overlapping detections needed to report a face, fewer are taken as noise
+const MIN_NEIGHBORS: usize = 3;
+
+/// Find faces in the image with the Haar cascade of OpenCV
+///
+/// The cascade file is read from `IMGTOOLS_FACE_MODEL`, or found where
+/// OpenCV installs haarcascade_frontalface_default.xml. Each face is the
+/// square around the eyes, nose and mouth.
+pub fn detect_faces(img: &DynamicImage) -> Result<Vec<Region>, ImgtoolsError> {
+    static CASCADE: OnceLock<Result<Cascade, String>> = OnceLock::new();
+    let cascade = CASCADE
+        .get_or_init(|| {
+            let path = model_path().ok_or_else(|| {
+                format!(
+                    "No face model found, install OpenCV's {} or set {} to a Haar cascade file",
+                    MODEL_NAME, MODEL_VAR
+                )
+            })?;
+            let xml = fs::read_to_string(&path)
+                .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+            Cascade::parse(&xml).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+        })
+        .as_ref()
+        .map_err(|e| ImgtoolsError::InvalidArgument(e.clone()))?;
+    Ok(cascade.detect(&img.to_luma8()))
+}
+
+fn model_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(MODEL_VAR) {
+        return Some(PathBuf::from(path));
+    }
+    MODEL_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(MODEL_NAME))
+        .find(|path| path.is_file())
+}
+
+/// A boosted cascade of Haar-like features, in OpenCV's cascade file format
+#[derive(Debug)]
+struct Cascade {
+    width: u32,
+    height: u32,
+    stages: Vec<Stage>,
+    features: Vec<Feature>,
+}
+
+#[derive(Debug)]
+struct Stage {
+    threshold: f64,
+    classifiers: Vec<Classifier>,
+}
+
+/// A small decision tree, usually a single split
+#[derive(Debug)]
+struct Classifier {
+    nodes: Vec<Node>,
+    leaves: Vec<f64>,
+}
+
+/// Children of a node are nodes when positive, leaves when zero or negative
+#[derive(Debug)]
+struct Node {
+    left: i32,
+    right: i32,
+    feature: usize,
+    threshold: f64,
+}
+
+/// Weighted rectangles of the window, from the top left corner
+#[derive(Debug)]
+struct Feature {
+    rects: Vec<(u32, u32, u32, u32, f64)>,
+}
+
+impl Cascade {
+    fn parse(text: &str) -> Result<Self, String> {
+        let root = xml::parse(text)?;
+        let cascade = root
+            .find("cascade")
+            .ok_or("no <cascade>, only the format written by opencv_traincascade is read")?;
+        if let Some(kind) = cascade.child("featureType")
+            && kind.text.trim() != "HAAR"
+        {
+            return Err(format!("{} features aren't supported", kind.text.trim()));
+        }
+        let size = |name: &str| -> Result<u32, String> {
+            cascade
+                .child(name)
+                .and_then(|n| n.text.trim().parse().ok())
+                .filter(|&n: &u32| n >= 3)
+                .ok_or_else(|| format!("missing or invalid <{}>", name))
+        };
+        let (width, height) = (size("width")?, size("height")?);
+
+        let stages = cascade
+            .child("stages")
+            .ok_or("no <stages>")?
+            .children
+            .iter()
+            .map(|stage| {
+                let threshold = stage
+                    .child("stageThreshold")
+                    .ok_or("a stage without <stageThreshold>")?
+                    .number()?;
+                let classifiers = stage
+                    .child("weakClassifiers")
+                    .ok_or("a stage without <weakClassifiers>")?
+                    .children
+                    .iter()
+                    .map(Classifier::parse)
+                    .collect::<Result<_, String>>()?;
+                Ok(Stage {
+                    threshold,
+                    classifiers,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let features = cascade
+            .child("features")
+            .ok_or("no <features>")?
+            .children
+            .iter()
+            .map(|feature| {
+                if feature
+                    .child("tilted")
+                    .is_some_and(|t| t.text.trim() == "1")
+                {
+                    return Err("tilted features aren't supported".to_string());
+                }
+                let rects = feature
+                    .child("rects")
+                    .ok_or("a feature without <rects>")?
+                    .children
+                    .iter()
+                    .map(|rect| match rect.numbers()?.as_slice() {
+                        &[x, y, w, h, weight] if x >= 0.0 && y >= 0.0 => {
+                            Ok((x as u32, y as u32, w as u32, h as u32, weight))
+                        }
+                        _ => Err(format!("invalid rectangle: {}", rect.text.trim())),
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                let fits = rects
+                    .iter()
+                    .all(|&(x, y, w, h, _)| x + w <= width && y + h <= height);
+                match fits {
+                    true => Ok(Feature { rects }),
+                    false => Err("a feature reaches outside the window".to_string()),
+                }
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let valid = stages
+            .iter()
+            .flat_map(|stage| &stage.classifiers)
+            .all(|classifier| classifier.is_valid(features.len()));
+        match valid && !stages.is_empty() {
+            true => Ok(Cascade {
+                width,
+                height,
+                stages,
+                features,
+            }),
+            false => Err("a classifier refers to a missing node, leaf or feature".into()),
+        }
+    }
+
+    /// Scan windows over a pyramid of the image and group the hits into faces
+    fn detect(&self, img: &GrayImage) -> Vec<Region> {
+        let longest = img.width().max(img.height());
+        let (img, shrink) = match longest > MAX_SIDE {
+            true => {
+                let shrink = longest as f32 / MAX_SIDE as f32;
+                let (w, h) = (
+                    (img.width() as f32 / shrink).round().max(1.0) as u32,
+                    (img.height() as f32 / shrink).round().max(1.0) as u32,
+                );
+                (
+                    imageops::resize(img, w, h, imageops::FilterType::Triangle),
+                    shrink,
+                )
+            }
+            false => (img.clone(), 1.0),
+        };
+
+        let mut hits = Vec::new();
+        let mut factor = 1.0f32;
+        loop {
+            let (w, h) = (
+                (img.width() as f32 / factor).round() as u32,
+                (img.height() as f32 / factor).round() as u32,
+            );
+            if w < self.width || h < self.height {
+                break;
+            }
+            let level = match factor {
+                1.0 => img.clone(),
+                _ => imageops::resize(&img, w, h, imageops::FilterType::Triangle),
+            };
+            let integral = Integral::new(&level);
+            // Coarser steps on the larger levels, as OpenCV does
+            let step = if factor > 2.0 { 1 } else { 2 };
+            for y in (0..=h - self.height).step_by(step) {
+                for x in (0..=w - self.width).step_by(step) {
+                    if self.matches(&integral, x, y) {
+                        let scale = factor * shrink;
+                        hits.push((
+                            x as f32 * scale,
+                            y as f32 * scale,
+                            self.width as f32 * scale,
+                            self.height as f32 * scale,
+                        ));
+                    }
+                }
+            }
+            factor *= SCALE_STEP;
+        }
+        group(&hits, MIN_NEIGHBORS)
+    }
+
+    /// Whether the window at `(x, y)` passes every stage
+    fn matches(&self, integral: &Integral, x: u32, y: u32) -> bool {
+        // Feature values are compared relative to the window's contrast
+        let (nw, nh) = (self.width - 2, self.height - 2);
+        let area = (nw * nh) as f64;
+        let sum = integral.sum(x + 1, y + 1, nw, nh) as f64;
+        let squares = integral.squares(x + 1, y + 1, nw, nh) as f64;
+        let norm = match area * squares - sum * sum {
+            v if v > 0.0 => v.sqrt(),
+            _ => 1.0,
+        };
+        self.stages.iter().all(|stage| {
+            let total: f64 = stage
+                .classifiers
+                .iter()
+                .map(|classifier| {
+                    let mut index = 0;
+                    loop {
+                        let node = &classifier.nodes[index as usize];
+                        let value = self.features[node.feature]
+                            .rects
+                            .iter()
+                            .map(|&(rx, ry, rw, rh, weight)| {
+                                integral.sum(x + rx, y + ry, rw, rh) as f64 * weight
+                            })
+                            .sum::<f64>();
+                        index = match value < node.threshold * norm {
+                            true => node.left,
+                            false => node.right,
+                        };
+                        if index <= 0 {
+                            break classifier.leaves[(-index) as usize];
+                        }
+                    }
+                })
+                .sum();
+            total >= stage.threshold
+        })
+    }
+}
+
+impl Classifier {
+    fn parse(node: &Element) -> Result<Self, String> {
+        let internal = node
+            .child("internalNodes")
+            .ok_or("a classifier without <internalNodes>")?
+            .numbers()?;
+        let leaves = node
+            .child("leafValues")
+            .ok_or("a classifier without <leafValues>")?
+            .numbers()?;
+        if internal.is_empty() || internal.len() % 4 != 0 {
+            return Err("<internalNodes> needs four numbers a node".into());
+        }
+        let nodes = internal
+            .chunks(4)
+            .map(|n| Node {
+                left: n[0] as i32,
+                right: n[1] as i32,
+                feature: n[2].max(0.0) as usize,
+                threshold: n[3],
+            })
+            .collect();
+        Ok(Classifier { nodes, leaves })
+    }
+
+    fn is_valid(&self, features: usize) -> bool {
+        let child = |index: i32| match index {
+            i if i > 0 => (i as usize) < self.nodes.len(),
+            i => ((-i) as usize) < self.leaves.len(),
+        };
+        // Children point further down the list, so walking a tree always ends
+        self.nodes.iter().enumerate().all(|(i, node)| {
+            node.feature < features
+                && child(node.left)
+                && child(node.right)
+                && (node.left <= 0 || node.left as usize > i)
+                && (node.right <= 0 || node.right as usize > i)
+        })
+    }
+}
+
+/// Summed-area tables of the pixels and their squares
+struct Integral {
+    width: usize,
+    sums: Vec<u64>,
+    squares: Vec<u64>,
+}
+
+impl Integral {
+    fn new(img: &GrayImage) -> Self {
+        let width = img.width() as usize + 1;
+        let size = width * (img.height() as usize + 1);
+        let (mut sums, mut squares) = (vec![0; size], vec![0; size]);
+        for (y, row) in img.rows().enumerate() {
+            let (mut sum, mut square) = (0u64, 0u64);
+            for (x, pixel) in row.enumerate() {
+                let v = pixel[0] as u64;
+                sum += v;
+                square += v * v;
+                let i = (y + 1) * width + x + 1;
+                sums[i] = sums[i - width] + sum;
+                squares[i] = squares[i - width] + square;
+            }
+        }
+        Integral {
+            width,
+            sums,
+            squares,
+        }
+    }
+
+    fn area(table: &[u64], width: usize, x: u32, y: u32, w: u32, h: u32) -> u64 {
+        let (x, y, w, h) = (x as usize, y as usize, w as usize, h as usize);
+        let at = |x: usize, y: usize| table[y * width + x];
+        at(x + w, y + h) + at(x, y) - at(x + w, y) - at(x, y + h)
+    }
+
+    fn sum(&self, x: u32, y: u32, w: u32, h: u32) -> u64 {
+        Integral::area(&self.sums, self.width, x, y, w, h)
+    }
+
+    fn squares(&self, x: u32, y: u32, w: u32, h: u32) -> u64 {
+        Integral::area(&self.squares, self.width, x, y, w, h)
+    }
+}
+
+/// Merge overlapping hits into one region each, dropping groups of `min_neighbors` or fewer
+///
+/// Hits whose sides are all within a fifth of their size of each other
+/// belong together, as in OpenCV's groupRectangles. Groups inside a
+/// stronger group are dropped too.
+fn group(hits: &[(f32, f32, f32, f32)], min_neighbors: usize) -> Vec<Region> {
+    const EPS: f32 = 0.2;
+    let similar = |a: &(f32, f32, f32, f32), b: &(f32, f32, f32, f32)| {
+        let delta = EPS * (a.2.min(b.2) + a.3.min(b.3)) * 0.5;
+        (a.0 - b.0).abs() <= delta
+            && (a.1 - b.1).abs() <= delta
+            && (a.0 + a.2 - b.0 - b.2).abs() <= delta
+            && (a.1 + a.3 - b.1 - b.3).abs() <= delta
+    };
+
+    // Union-find over the hits
+    let mut parent: Vec<usize> = (0..hits.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hits.len() {
+        for j in i + 1..hits.len() {
+            if similar(&hits[i], &hits[j]) {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a] = b;
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, [f32; 4], usize)> = Vec::new();
+    for (i, hit) in hits.iter().enumerate() {
+        let r = root(&mut parent, i);
+        let group = match groups.iter_mut().find(|g| g.0 == r) {
+            Some(group) => group,
+            None => {
+                groups.push((r, [0.0; 4], 0));
+                groups.last_mut().unwrap()
+            }
+        };
+        for (sum, v) in group.1.iter_mut().zip([hit.0, hit.1, hit.2, hit.3]) {
+            *sum += v;
+        }
+        group.2 += 1;
+    }
+    let averaged: Vec<([f32; 4], usize)> = groups
+        .into_iter()
+        .filter(|g| g.2 > min_neighbors)
+        .map(|(_, sums, n)| (sums.map(|s| s / n as f32), n))
+        .collect();
+
+    averaged
+        .iter()
+        .filter(|(r1, n1)| {
+            !averaged.iter().any(|(r2, n2)| {
+                let (dx, dy) = (r2[2] * EPS, r2[3] * EPS);
+                r1 != r2
+                    && *n2 > (*n1).max(3)
+                    && r1[0] >= r2[0] - dx
+                    && r1[1] >= r2[1] - dy
+                    && r1[0] + r1[2] <= r2[0] + r2[2] + dx
+                    && r1[1] + r1[3] <= r2[1] + r2[3] + dy
+            })
+        })
+        .map(|(r, _)| Region {
+            x: r[0].round().max(0.0) as u32,
+            y: r[1].round().max(0.0) as u32,
+            width: r[2].round().max(1.0) as u32,
+            height: r[3].round().max(1.0) as u32,
+        })
+        .collect()
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    /// The first element of the name in the whole tree
+    fn find(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|c| {
+            if c.name == name {
+                Some(c)
+            } else {
+                c.find(name)
+            }
+        })
+    }
+
+    fn numbers(&self) -> Result<Vec<f64>, String> {
+        self.text
+            .split_whitespace()
+            .map(|n| n.parse().map_err(|_| format!("not a number: {}", n)))
+            .collect()
+    }
+
+    fn number(&self) -> Result<f64, String> {
+        match self.numbers()?.as_slice() {
+            &[n] => Ok(n),
+            _ => Err(format!("expected one number in <{}>", self.name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    /// A one-feature cascade that finds a dark square in a light frame twice its size
+    const SQUARE: &str = r#"<?xml version="1.0"?>
+<opencv_storage>
+<cascade type_id="opencv-cascade-classifier"><stageType>BOOST</stageType>
+  <featureType>HAAR</featureType>
+  <height>8</height>
+  <width>8</width>
+  <stageNum>1</stageNum>
+  <stages>
+    <_>
+      <maxWeakCount>1</maxWeakCount>
+      <stageThreshold>0.</stageThreshold>
+      <weakClassifiers>
+        <_>
+          <internalNodes>
+            0 -1 0 5.0000000000000000e-01</internalNodes>
+          <leafValues>
+            -1. 1.</leafValues></_></weakClassifiers></_></stages>
+  <features>
+    <_>
+      <rects>
+        <_>
+          0 0 8 8 1.</_>
+        <_>
+          2 2 4 4 -4.</_></rects></_></features></cascade>
+</opencv_storage>
+"#;
+
+    #[test]
+    fn test_parse_cascade() {
+        let cascade = Cascade::parse(SQUARE).unwrap();
+        assert_eq!((cascade.width, cascade.height), (8, 8));
+        assert_eq!(cascade.stages.len(), 1);
+        assert_eq!(cascade.stages[0].classifiers[0].leaves, [-1.0, 1.0]);
+        assert_eq!(cascade.features[0].rects[1], (2, 2, 4, 4, -4.0));
+
+        assert!(Cascade::parse("<opencv_storage></opencv_storage>").is_err());
+        let outside = SQUARE.replace("2 2 4 4 -4.", "6 6 4 4 -4.");
+        assert!(Cascade::parse(&outside).is_err());
+        let missing = SQUARE.replace("0 -1 0 5.", "0 -1 3 5.");
+        assert!(Cascade::parse(&missing).is_err());
+        assert!(Cascade::parse(&SQUARE.replace("</cascade>", "")).is_err());
+    }
+
+    #[test]
+    fn test_detect() {
+        let cascade = Cascade::parse(SQUARE).unwrap();
+        let mut img = GrayImage::from_pixel(120, 100, Luma([230]));
+        for y in 40..60 {
+            for x in 60..80 {
+                img.put_pixel(x, y, Luma([20]));
+            }
+        }
+        let found = cascade.detect(&img);
+        assert_eq!(found.len(), 1);
+        let face = found[0];
+        let center = (face.x + face.width / 2, face.y + face.height / 2);
+        assert!(center.0.abs_diff(70) <= 3 && center.1.abs_diff(50) <= 3);
+        // Windows from about the square's size up to twice it match, the group averages them
+        assert!((20..=40).contains(&face.width) && face.width == face.height);
+
+        // Nothing in a flat image
+        assert!(
+            cascade
+                .detect(&GrayImage::from_pixel(60, 60, Luma([128])))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_group() {
+        let hits = [
+            (10.0, 10.0, 20.0, 20.0),
+            (11.0, 10.0, 20.0, 20.0),
+            (10.0, 12.0, 21.0, 21.0),
+            (9.0, 9.0, 20.0, 20.0),
+            // A lone hit elsewhere
+            (80.0, 80.0, 20.0, 20.0),
+        ];
+        let groups = group(&hits, 3);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0],
+            Region {
+                x: 10,
+                y: 10,
+                width: 20,
+                height: 20
+            }
+        );
+        assert!(group(&hits, 4).is_empty());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::ImgtoolsError;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Program HTTPS downloads go through
+const CURL: &str = "curl";
+
+/// Longest a connection, or a wait for more of the answer, may take
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest image that is downloaded
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// Most header lines read before an answer is refused
+const MAX_HEADERS: usize = 100;
+
+/// Download an image into memory
+///
+/// Redirects are followed, and the answer must be a successful one with an
+/// image content type no larger than 64 MiB. Plain `http://` is spoken
+/// directly, `https://` through the curl program, as there is no TLS library
+/// in this build.
+pub(crate) fn fetch(url: &str) -> Result<Vec<u8>, ImgtoolsError> {
+    let fail = |message: String| ImgtoolsError::Fetch {
+        url: url.to_string(),
+        message,
+    };
+    let mut location = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        match get(&location).map_err(fail)? {
+            Answer::Body(data) => return Ok(data),
+            Answer::Redirect(next) => location = resolve(&location, &next),
+        }
+    }
+    Err(fail(format!("More than {} redirects", MAX_REDIRECTS)))
+}
+
+/// What a single request got back
+enum Answer {
+    Body(Vec<u8>),
+    Redirect(String),
+}
+
+/// Send one GET request and read the answer
+fn get(url: &str) -> Result<Answer, String> {
+    let parts = split_url(url)?;
+    if parts.secure {
+        return get_with_curl(url);
+    }
+    let Url {
+        host, port, path, ..
+    } = parts;
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("Unable to resolve {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+
+    // HTTP/1.0 keeps the body in one piece, without chunked encoding
+    let host = match (port, host.contains(':')) {
+        (80, _) => host.to_string(),
+        (port, true) => format!("[{}]:{}", host, port),
+        (port, false) => format!("{}:{}", host, port),
+    };
+    write!(
+        stream,
+        "GET {}{} HTTP/1.0\r\nHost: {}\r\nAccept: image/*\r\nUser-Agent: {}\r\n\r\n",
+        if path.starts_with('/') { "" } else { "/" },
+        path,
+        host,
+        user_agent()
+    )
+    .and_then(|_| stream.flush())
+    .map_err(|e| e.to_string())?;
+    read_answer(BufReader::new(stream))
+}
+
+/// Send one GET request over HTTPS through curl, which brings the TLS
+///
+/// Curl only makes the request and passes the answer with its headers on,
+/// redirects and limits are handled as for plain HTTP.
+fn get_with_curl(url: &str) -> Result<Answer, String> {
+    let timeout = TIMEOUT.as_secs().to_string();
+    let mut curl = Command::new(CURL)
+        .args(["--silent", "--show-error", "--include", "--noproxy", "*"])
+        .args(["--proto", "=https", "--connect-timeout", &timeout])
+        .args(["--speed-limit", "1", "--speed-time", &timeout])
+        .args(["--header", "Accept: image/*", "--user-agent", &user_agent()])
+        .args(["--url", url])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => "HTTPS downloads need curl, which was not found".into(),
+            _ => format!("Unable to run curl: {}", e),
+        })?;
+    let Some(stdout) = curl.stdout.take() else {
+        unreachable!("The output of curl is piped")
+    };
+    // Dropping the output when the answer is read stops curl if it is still sending
+    let answer = read_answer(BufReader::new(stdout));
+    let mut message = String::new();
+    if let Some(mut stderr) = curl.stderr.take() {
+        let _ = stderr.read_to_string(&mut message);
+    }
+    let status = curl.wait().map_err(|e| e.to_string())?;
+    let failure = message.lines().next().unwrap_or_default().trim();
+    match answer {
+        // Curl's own failure explains more than the answer it cut short, exit
+        // code 23 only means the rest of the answer wasn't wanted
+        Err(_) if !status.success() && status.code() != Some(23) && !failure.is_empty() => {
+            Err(failure.to_string())
+        }
+        answer => answer,
+    }
+}
+
+fn user_agent() -> String {
+    format!("imgtools/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Read the status line, headers and body of an answer
+fn read_answer(mut reader: impl BufRead) -> Result<Answer, String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or("Malformed answer from the server")?;
+
+    let (mut content_type, mut length, mut location) = (None, None, None);
+    for _ in 0..=MAX_HEADERS {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(|e| e.to_string())? == 0
+            || header.trim().is_empty()
+        {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-type" => content_type = Some(value),
+            "content-length" => length = value.parse::<u64>().ok(),
+            "location" => location = Some(value),
+            _ => {}
+        }
+    }
+
+    match status {
+        200 => {}
+        301 | 302 | 303 | 307 | 308 => {
+            return location
+                .map(Answer::Redirect)
+                .ok_or_else(|| format!("Redirect {} without a location", status));
+        }
+        status => return Err(format!("The server answered with status {}", status)),
+    }
+    match content_type.as_deref() {
+        Some(content_type) if is_image_type(content_type) => {}
+        Some(content_type) => return Err(format!("{} is not an image", content_type)),
+        None => return Err("The server didn't say what it sent".into()),
+    }
+    if length.is_some_and(|length| length > MAX_SIZE) {
+        return Err(too_large());
+    }
+
+    let mut data = Vec::new();
+    reader
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| e.to_string())?;
+    if data.len() as u64 > MAX_SIZE {
+        return Err(too_large());
+    }
+    if length.is_some_and(|length| length != data.len() as u64) {
+        return Err("The download ended early".into());
+    }
+    Ok(Answer::Body(data))
+}
+
+fn too_large() -> String {
+    format!("The image is larger than {} MiB", MAX_SIZE / 1024 / 1024)
+}
+
+/// Images, and generic binary data that servers often send for them
+fn is_image_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("image/") || mime == "application/octet-stream"
+}
+
+/// Parts of a URL a request needs
+#[derive(Debug, PartialEq)]
+struct Url<'a> {
+    /// Whether the scheme is https
+    secure: bool,
+    host: &'a str,
+    port: u16,
+    /// Path with its query
+    path: &'a str,
+}
+
+/// Split a URL into its scheme, host, port and the path with its query
+fn split_url(url: &str) -> Result<Url<'_>, String> {
+    let (secure, rest) = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (false, rest),
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (true, rest),
+        _ => return Err("Only http:// and https:// URLs can be fetched".into()),
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    if authority.contains('@') {
+        return Err("Credentials in URLs are not supported".into());
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse()
+                .map_err(|_| format!("Invalid port in {}", url))?,
+        ),
+        _ => (authority, if secure { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        return Err(format!("No host in {}", url));
+    }
+    Ok(Url {
+        secure,
+        host: host.trim_start_matches('[').trim_end_matches(']'),
+        port,
+        path,
+    })
+}
+
+/// Resolve the location of a redirect against the URL it came from
+fn resolve(base: &str, location: &str) -> String {
+    if location.contains("://") {
+        return location.to_string();
+    }
+    let scheme_end = base.find("://").map_or(0, |i| i + 3);
+    let host_end = base[scheme_end..]
+        .find(['/', '?', '#'])
+        .map_or(base.len(), |i| scheme_end + i);
+    match location {
+        location if location.starts_with("//") => {
+            format!("{}{}", &base[..scheme_end - 2], location)
+        }
+        location if location.starts_with('/') => format!("{}{}", &base[..host_end], location),
+        location => {
+            let path = base[host_end..]
+                .split(['?', '#'])
+                .next()
+                .unwrap_or_default();
+            let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+            let dir = if dir.is_empty() { "/" } else { dir };
+            format!("{}{}{}", &base[..host_end], dir, location)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_split_url() {
+        let parts = |url| {
+            let Url {
+                secure,
+                host,
+                port,
+                path,
+            } = split_url(url).unwrap();
+            (secure, host, port, path)
+        };
+        assert_eq!(
+            parts("http://example.com/a/b.png?x=1#top"),
+            (false, "example.com", 80, "/a/b.png?x=1")
+        );
+        assert_eq!(
+            parts("HTTP://localhost:8080"),
+            (false, "localhost", 8080, "")
+        );
+        assert_eq!(parts("http://[::1]:81/a.png"), (false, "::1", 81, "/a.png"));
+        assert_eq!(
+            parts("https://example.com/a.png"),
+            (true, "example.com", 443, "/a.png")
+        );
+        for (url, message) in [
+            ("ftp://example.com/a.png", "Only http:// and https://"),
+            ("http://user:pw@example.com/", "Credentials"),
+            ("http://example.com:http/", "Invalid port"),
+            ("http:///a.png", "No host"),
+        ] {
+            let error = split_url(url).unwrap_err();
+            assert!(error.starts_with(message), "{}: {}", url, error);
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let base = "http://example.com/images/a.png?size=2";
+        for (location, resolved) in [
+            ("http://other.org/b.png", "http://other.org/b.png"),
+            ("//cdn.example.com/b.png", "http://cdn.example.com/b.png"),
+            ("/b.png", "http://example.com/b.png"),
+            ("b.png", "http://example.com/images/b.png"),
+        ] {
+            assert_eq!(resolve(base, location), resolved);
+        }
+        assert_eq!(
+            resolve("http://example.com", "b.png"),
+            "http://example.com/b.png"
+        );
+    }
+
+    /// Answer one connection for every canned answer, in order
+    fn server(answers: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for answer in answers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                while reader.read_line(&mut request).unwrap() > 2 {
+                    request.clear();
+                }
+                stream.write_all(answer.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn test_fetch() {
+        let url = server(vec![
+            "HTTP/1.0 302 Found\r\nLocation: /b.png\r\n\r\n".into(),
+            "HTTP/1.0 200 OK\r\nContent-Type: image/png\r\nContent-Length: 4\r\n\r\nPNG!".into(),
+        ]);
+        assert_eq!(fetch(&format!("{}/a.png", url)).unwrap(), b"PNG!");
+
+        for (answer, message) in [
+            ("HTTP/1.0 404 Not Found\r\n\r\n", "status 404"),
+            (
+                "HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n<html>",
+                "text/html is not an image",
+            ),
+            (
+                "HTTP/1.0 200 OK\r\nContent-Type: image/png\r\nContent-Length: 999999999\r\n\r\n",
+                "larger than",
+            ),
+            (
+                "HTTP/1.0 200 OK\r\nContent-Type: image/png\r\nContent-Length: 10\r\n\r\nPNG",
+                "ended early",
+            ),
+        ] {
+            let url = server(vec![answer.into()]);
+            let error = fetch(&url).unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+
+        // HTTPS goes through curl, which reports what went wrong
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let error = fetch(&format!("https://{}/a.png", closed)).unwrap_err();
+        assert!(error.to_string().contains("curl"), "{}", error);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::{DenoiseMethod, EdgeOperator, Kernel, NoiseType};
+use image::{GrayImage, Luma, RgbaImage};
+use imageproc::edges::canny;
+use imageproc::filter::filter3x3;
+use imageproc::gradients::{prewitt_gradients, sobel_gradients};
+use rayon::prelude::*;
+
+/// Emboss kernel, light falls in from the top left
+const EMBOSS: [f32; 9] = [-1.0, -1.0, 0.0, -1.0, 0.0, 1.0, 0.0, 1.0, 1.0];
+
+/// Sigma from which a Gaussian blur of an 8-bit image is done with boxes,
+/// the true kernel grows with sigma and gets slow well before this
+pub(crate) const BOX_BLUR_SIGMA: f32 = 8.0;
+
+/// Detect edges in a grayscale image
+///
+/// Sobel and Prewitt give the gradient magnitude, magnitudes below `low` are
+/// dropped as noise and everything above 255 is clipped. Canny gives thin
+/// white edges and uses both `low` and `high` as hysteresis thresholds.
+pub fn edges(img: &GrayImage, operator: EdgeOperator, low: f32, high: f32) -> GrayImage {
+    let gradients = match operator {
+        EdgeOperator::Canny => return canny(img, low, high),
+        EdgeOperator::Sobel => sobel_gradients(img),
+        EdgeOperator::Prewitt => prewitt_gradients(img),
+    };
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let magnitude = gradients.get_pixel(x, y)[0] as f32;
+        match magnitude < low {
+            true => Luma([0]),
+            false => Luma([magnitude.min(255.0) as u8]),
+        }
+    })
+}
+
+/// Relief effect on mid gray, `strength` scales the height of the edges
+pub fn emboss(img: &GrayImage, strength: f32) -> GrayImage {
+    let kernel = EMBOSS.map(|k| k * strength);
+    let relief: image::ImageBuffer<Luma<f32>, Vec<f32>> = filter3x3(img, &kernel);
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let value = relief.get_pixel(x, y)[0] + 128.0;
+        Luma([value.round().clamp(0.0, 255.0) as u8])
+    })
+}
+
+/// Convolve the color channels with a kernel, alpha is kept
+///
+/// Each weighted sum is divided by `divisor` and shifted by `offset`, pixels
+/// outside the image repeat the nearest edge pixel.
+pub fn convolve(img: &RgbaImage, kernel: &Kernel, divisor: f32, offset: f32) -> RgbaImage {
+    let (w, h) = (img.width() as i64, img.height() as i64);
+    let size = kernel.size() as i64;
+    let half = size / 2;
+
+    RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+        let mut sum = [0.0f32; 3];
+        for (i, &weight) in kernel.0.iter().enumerate() {
+            let kx = (x as i64 + i as i64 % size - half).clamp(0, w - 1);
+            let ky = (y as i64 + i as i64 / size - half).clamp(0, h - 1);
+            let pixel = img.get_pixel(kx as u32, ky as u32);
+            for c in 0..3 {
+                sum[c] += pixel[c] as f32 * weight;
+            }
+        }
+        let mut pixel = *img.get_pixel(x, y);
+        for c in 0..3 {
+            pixel[c] = (sum[c] / divisor + offset).round().clamp(0.0, 255.0) as u8;
+        }
+        pixel
+    })
+}
+
+/// Add random noise to the color channels, alpha is kept
+///
+/// Gaussian noise has a standard deviation of `amount` times 255, salt and
+/// pepper noise turns a share of `amount` pixels black or white.
+pub fn noise(img: &mut RgbaImage, noise_type: NoiseType, amount: f32, seed: u64) {
+    let mut rng = Rng::new(seed);
+    for pixel in img.as_mut().chunks_exact_mut(4) {
+        match noise_type {
+            NoiseType::Gaussian => {
+                for value in &mut pixel[..3] {
+                    let noisy = *value as f32 + rng.gaussian() * amount * 255.0;
+                    *value = noisy.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+            NoiseType::SaltPepper => {
+                if rng.next_f32() < amount {
+                    let value = if rng.next_f32() < 0.5 { 0 } else { 255 };
+                    pixel[..3].fill(value);
+                }
+            }
+        }
+    }
+}
+
+/// Smooth the color channels in a `radius` neighbourhood, alpha is kept
+///
+/// The bilateral filter weights neighbours by distance and by color
+/// difference, `sigma` is the color difference with a weight of about 0.6.
+/// Pixels outside the image repeat the nearest edge pixel.
+pub fn denoise(img: &RgbaImage, method: DenoiseMethod, radius: u32, sigma: f32) -> RgbaImage {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let r = radius as i64;
+    let raw = img.as_raw();
+    let index = |x: i64, y: i64| {
+        let (x, y) = (x.clamp(0, w as i64 - 1), y.clamp(0, h as i64 - 1));
+        (y as usize * w + x as usize) * 4
+    };
+    let sigma_spatial = (radius as f32 / 2.0).max(0.5);
+    let mut out = raw.clone();
+    let mut window: Vec<[u8; 3]> = Vec::with_capacity(((2 * r + 1) * (2 * r + 1)) as usize);
+
+    for y in 0..h as i64 {
+        for x in 0..w as i64 {
+            window.clear();
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let i = index(x + dx, y + dy);
+                    window.push([raw[i], raw[i + 1], raw[i + 2]]);
+                }
+            }
+            let center = index(x, y);
+            let filtered = match method {
+                DenoiseMethod::Median => [0, 1, 2].map(|c| {
+                    let mut values: Vec<u8> = window.iter().map(|p| p[c]).collect();
+                    let mid = values.len() / 2;
+                    *values.select_nth_unstable(mid).1
+                }),
+                DenoiseMethod::Bilateral => {
+                    let mut sum = [0.0f32; 3];
+                    let mut total = 0.0;
+                    for (i, p) in window.iter().enumerate() {
+                        let dx = (i as i64 % (2 * r + 1) - r) as f32;
+                        let dy = (i as i64 / (2 * r + 1) - r) as f32;
+                        let diff: f32 = (0..3)
+                            .map(|c| (p[c] as f32 - raw[center + c] as f32).powi(2))
+                            .sum();
+                        let weight = (-(dx * dx + dy * dy) / (2.0 * sigma_spatial.powi(2))
+                            - diff / (2.0 * sigma * sigma))
+                            .exp();
+                        for c in 0..3 {
+                            sum[c] += p[c] as f32 * weight;
+                        }
+                        total += weight;
+                    }
+                    sum.map(|s| (s / total).round().clamp(0.0, 255.0) as u8)
+                }
+            };
+            out[center..center + 3].copy_from_slice(&filtered);
+        }
+    }
+    RgbaImage::from_raw(img.width(), img.height(), out).unwrap_or_else(|| img.clone())
+}
+
+/// Gaussian blur approximated by three box blurs, its cost independent of sigma
+///
+/// The box widths follow Kovesi's "Fast almost-Gaussian filtering", the
+/// result stays within a few levels of a true Gaussian. Rows are blurred
+/// in parallel, columns as the rows of the transposed image.
+pub fn box_blur(img: &RgbaImage, sigma: f32) -> RgbaImage {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let radii = box_radii(sigma);
+    let mut data = img.as_raw().clone();
+    for radius in radii {
+        box_rows(&mut data, width, radius);
+    }
+    let mut data = transpose(&data, width, height);
+    for radius in radii {
+        box_rows(&mut data, height, radius);
+    }
+    let data = transpose(&data, height, width);
+    RgbaImage::from_raw(img.width(), img.height(), data).unwrap_or_else(|| img.clone())
+}
+
+/// Radii of the three boxes whose repeated blur comes closest to the sigma
+fn box_radii(sigma: f32) -> [usize; 3] {
+    let variance = 12.0 * sigma * sigma;
+    let ideal = (variance / 3.0 + 1.0).sqrt();
+    let lower = match ideal.floor() as usize {
+        w if w % 2 == 0 => w.saturating_sub(1).max(1),
+        w => w,
+    };
+    let l = lower as f32;
+    // How many boxes take the lower width, the others are two pixels wider
+    let narrow = ((variance - 3.0 * l * l - 12.0 * l - 9.0) / (-4.0 * l - 4.0)).round();
+    std::array::from_fn(|i| match (i as f32) < narrow {
+        true => lower / 2,
+        false => lower / 2 + 1,
+    })
+}
+
+/// Blur each row of RGBA pixels with a box `2 * radius + 1` wide, the edge
+/// pixels repeated beyond the ends
+fn box_rows(data: &mut [u8], width: usize, radius: usize) {
+    let span = 2 * radius as u32 + 1;
+    let r = radius as isize;
+    data.par_chunks_mut(width * 4).for_each(|row| {
+        let source = row.to_vec();
+        let at =
+            |x: isize, c: usize| source[x.clamp(0, width as isize - 1) as usize * 4 + c] as u32;
+        for c in 0..4 {
+            let mut sum: u32 = (-r..=r).map(|x| at(x, c)).sum();
+            for x in 0..width as isize {
+                row[x as usize * 4 + c] = ((sum + span / 2) / span) as u8;
+                sum = sum + at(x + r + 1, c) - at(x - r, c);
+            }
+        }
+    });
+}
+
+/// Swap the rows and columns of RGBA pixels
+fn transpose(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut out = vec![0u8; data.len()];
+    out.par_chunks_mut(height * 4)
+        .enumerate()
+        .for_each(|(x, column)| {
+            for (y, pixel) in column.chunks_exact_mut(4).enumerate() {
+                let i = (y * width + x) * 4;
+                pixel.copy_from_slice(&data[i..i + 4]);
+            }
+        });
+    out
+}
+
+/// Small xorshift generator, noise only needs to look random and be repeatable
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Avoid the all zero state
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [0, 1)
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Standard normal with the Box-Muller transform
+    pub(crate) fn gaussian(&mut self) -> f32 {
+        let u = 1.0 - self.next_f32();
+        let v = self.next_f32();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f32::consts::PI * v).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Black left half, white right half
+    fn step() -> GrayImage {
+        GrayImage::from_fn(10, 10, |x, _| Luma([if x < 5 { 0 } else { 255 }]))
+    }
+
+    #[test]
+    fn test_edges() {
+        for operator in [
+            EdgeOperator::Sobel,
+            EdgeOperator::Prewitt,
+            EdgeOperator::Canny,
+        ] {
+            let edges = edges(&step(), operator, 50.0, 100.0);
+            assert_eq!(edges.dimensions(), (10, 10));
+            assert_eq!(edges.get_pixel(1, 5)[0], 0, "{:?}", operator);
+            assert_eq!(edges.get_pixel(8, 5)[0], 0, "{:?}", operator);
+            assert!(
+                edges.get_pixel(4, 5)[0] == 255 || edges.get_pixel(5, 5)[0] == 255,
+                "{:?}",
+                operator
+            );
+        }
+    }
+
+    #[test]
+    fn test_edges_low_threshold() {
+        // A faint step disappears above its gradient magnitude
+        let faint = GrayImage::from_fn(10, 10, |x, _| Luma([if x < 5 { 100 } else { 110 }]));
+        let kept = edges(&faint, EdgeOperator::Sobel, 10.0, 0.0);
+        assert!(kept.pixels().any(|p| p[0] > 0));
+        let dropped = edges(&faint, EdgeOperator::Sobel, 50.0, 0.0);
+        assert!(dropped.pixels().all(|p| p[0] == 0));
+    }
+
+    #[test]
+    fn test_convolve() {
+        let mut img = RgbaImage::from_pixel(5, 5, image::Rgba([100, 100, 100, 200]));
+        img.put_pixel(2, 2, image::Rgba([190, 10, 100, 50]));
+
+        // Identity keeps the image, offset shifts it
+        let identity = "0,0,0,0,1,0,0,0,0".parse::<Kernel>().unwrap();
+        assert_eq!(convolve(&img, &identity, 1.0, 0.0), img);
+        assert_eq!(
+            convolve(&img, &identity, 1.0, 10.0).get_pixel(0, 0).0,
+            [110, 110, 110, 200]
+        );
+
+        // Box blur spreads the center pixel, edges repeat
+        let blur = "kernel(1,1,1,1,1,1,1,1,1)".parse::<Kernel>().unwrap();
+        let blurred = convolve(&img, &blur, blur.default_divisor(), 0.0);
+        assert_eq!(blurred.get_pixel(1, 1).0, [110, 90, 100, 200]);
+        assert_eq!(blurred.get_pixel(2, 2)[3], 50);
+        assert_eq!(blurred.get_pixel(0, 4).0, [100, 100, 100, 200]);
+
+        let wide = format!("kernel({})", ["1"; 25].join(","))
+            .parse::<Kernel>()
+            .unwrap();
+        let blurred = convolve(&img, &wide, wide.default_divisor(), 0.0);
+        assert_eq!(blurred.get_pixel(0, 0).0, [104, 96, 100, 200]);
+    }
+
+    #[test]
+    fn test_noise() {
+        let gray = image::Rgba([128, 128, 128, 200]);
+        let mut img = RgbaImage::from_pixel(32, 32, gray);
+        noise(&mut img, NoiseType::Gaussian, 0.1, 1);
+        assert!(img.pixels().all(|p| p[3] == 200));
+        let mean = img.pixels().map(|p| p[0] as f32).sum::<f32>() / 1024.0;
+        assert!((mean - 128.0).abs() < 3.0, "{}", mean);
+        assert!(img.pixels().filter(|p| p[0] != 128).count() > 900);
+
+        // The same seed repeats the noise
+        let mut again = RgbaImage::from_pixel(32, 32, gray);
+        noise(&mut again, NoiseType::Gaussian, 0.1, 1);
+        assert_eq!(img, again);
+
+        let mut img = RgbaImage::from_pixel(32, 32, gray);
+        noise(&mut img, NoiseType::SaltPepper, 0.2, 7);
+        let changed = img.pixels().filter(|p| p[0] != 128).count();
+        assert!((150..260).contains(&changed), "{}", changed);
+        assert!(
+            img.pixels()
+                .all(|p| [0, 128, 255].contains(&p[0]) && p[0] == p[2])
+        );
+    }
+
+    #[test]
+    fn test_denoise() {
+        let gray = image::Rgba([100, 100, 100, 255]);
+        let mut img = RgbaImage::from_pixel(5, 5, gray);
+        img.put_pixel(2, 2, image::Rgba([255, 255, 255, 255]));
+
+        // A single speckle is removed by the median
+        let median = denoise(&img, DenoiseMethod::Median, 1, 30.0);
+        assert!(median.pixels().all(|p| *p == gray));
+
+        // Bilateral keeps a strong edge
+        let step = RgbaImage::from_fn(6, 6, |x, _| match x < 3 {
+            true => image::Rgba([0, 0, 0, 255]),
+            false => image::Rgba([255, 255, 255, 255]),
+        });
+        let smoothed = denoise(&step, DenoiseMethod::Bilateral, 2, 20.0);
+        assert_eq!(smoothed, step);
+        let smoothed = denoise(&img, DenoiseMethod::Bilateral, 1, 200.0);
+        assert!(smoothed.get_pixel(2, 2)[0] < 255);
+    }
+
+    #[test]
+    fn test_emboss() {
+        let flat = GrayImage::from_pixel(4, 4, Luma([200]));
+        assert!(emboss(&flat, 1.0).pixels().all(|p| p[0] == 128));
+
+        let relief = emboss(&step(), 0.1);
+        assert!(relief.get_pixel(5, 5)[0] > 128);
+        assert_eq!(relief.get_pixel(1, 5)[0], 128);
+    }
+
+    #[test]
+    fn test_box_blur() {
+        assert_eq!(box_radii(5.0), [4, 4, 5]);
+        assert_eq!(box_radii(20.0), [19, 19, 20]);
+
+        let step = RgbaImage::from_fn(60, 9, |x, _| match x < 30 {
+            true => image::Rgba([0, 0, 0, 255]),
+            false => image::Rgba([255, 255, 255, 255]),
+        });
+        let boxed = box_blur(&step, 6.0);
+        let gaussian = image::imageops::blur(&step, 6.0);
+        for (a, b) in boxed.pixels().zip(gaussian.pixels()) {
+            assert!(a[0].abs_diff(b[0]) <= 8, "{:?} {:?}", a, b);
+            assert_eq!(a[3], 255);
+        }
+    }
+}
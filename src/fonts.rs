@@ -0,0 +1,497 @@
+use crate::shape::shape;
+use crate::{Fill, ImgtoolsError};
+use ab_glyph::{Font, FontArc, FontRef, FontVec, GlyphImageFormat, PxScale, ScaleFont};
+use image::{ImageFormat, Rgba, RgbaImage, imageops};
+use imageproc::drawing::{draw_text_mut, text_size};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The bundled FangSong font, the last resort for every character
+const FANGSONG: &[u8] = include_bytes!("../data/仿宋_GB2312.ttf");
+
+/// Font files by extension, collections hold several faces
+const FONT_EXTENSIONS: [&str; 4] = ["ttf", "otf", "ttc", "otc"];
+
+/// Color emoji fonts of Linux, macOS and Windows, the first installed one is used
+const EMOJI_FAMILIES: [&str; 5] = [
+    "Noto Color Emoji",
+    "Apple Color Emoji",
+    "Segoe UI Emoji",
+    "Twemoji Mozilla",
+    "JoyPixels",
+];
+
+/// Code point ranges drawn as emoji when the given fonts lack them
+const EMOJI_RANGES: [(char, char); 8] = [
+    ('\u{00A9}', '\u{00AE}'),
+    ('\u{203C}', '\u{2049}'),
+    ('\u{2122}', '\u{2139}'),
+    ('\u{2194}', '\u{21AA}'),
+    ('\u{231A}', '\u{23FF}'),
+    ('\u{24C2}', '\u{27BF}'),
+    ('\u{2B05}', '\u{2B55}'),
+    ('\u{1F000}', '\u{1FAFF}'),
+];
+
+/// Index of the installed emoji font among the fonts of a run
+const EMOJI: usize = usize::MAX;
+
+/// Fonts tried in order for each character, the bundled FangSong last
+///
+/// Characters the first font lacks, such as Chinese in a Latin font, are
+/// drawn with the next font that has them. Emoji the fonts lack are drawn
+/// in color with an installed emoji font when there is one.
+pub struct Fonts {
+    fonts: Vec<FontArc>,
+    /// Looked up the first time an emoji needs it, scanning the system fonts is slow
+    emoji: OnceLock<Option<FontArc>>,
+}
+
+impl Fonts {
+    /// Load fonts from a comma separated list of files and installed family names
+    ///
+    /// Without a list only FangSong is used. Names that aren't files are
+    /// looked up among the system fonts, e.g. "Noto Sans, Noto Sans CJK SC".
+    pub fn load(spec: Option<&Path>) -> Result<Self, ImgtoolsError> {
+        let mut fonts = Vec::new();
+        let spec = spec.map(|spec| spec.to_string_lossy().into_owned());
+        let entries = spec.iter().flat_map(|spec| spec.split(',')).map(str::trim);
+        let mut system = None;
+        for entry in entries.filter(|entry| !entry.is_empty()) {
+            let path = Path::new(entry);
+            let font = match path.is_file() {
+                true => {
+                    let data = fs::read(path).map_err(|source| ImgtoolsError::Read {
+                        path: path.to_path_buf(),
+                        source,
+                    })?;
+                    FontArc::try_from_vec(data).map_err(|e| ImgtoolsError::Font(e.to_string()))?
+                }
+                false => system
+                    .get_or_insert_with(system_fonts)
+                    .iter()
+                    .find_map(|font: &SystemFont| font.load(entry))
+                    .ok_or_else(|| {
+                        ImgtoolsError::InvalidArgument(format!(
+                            "No font file or installed font family named {}",
+                            entry
+                        ))
+                    })?,
+            };
+            fonts.push(font);
+        }
+        fonts.push(FontArc::new(builtin()));
+        Ok(Fonts {
+            fonts,
+            emoji: OnceLock::new(),
+        })
+    }
+
+    /// The installed color emoji font
+    fn emoji(&self) -> Option<&FontArc> {
+        self.emoji
+            .get_or_init(|| {
+                let system = system_fonts();
+                EMOJI_FAMILIES
+                    .iter()
+                    .find_map(|name| system.iter().find_map(|font| font.load(name)))
+            })
+            .as_ref()
+    }
+
+    /// The font at an index returned by `font_for`
+    fn font(&self, index: usize) -> &FontArc {
+        match index {
+            EMOJI => self.emoji().expect("the emoji font was found"),
+            index => &self.fonts[index],
+        }
+    }
+
+    /// The font that draws the character, FangSong if none has it
+    ///
+    /// Emoji missing from the given fonts come from the emoji font before FangSong.
+    fn font_for(&self, c: char) -> usize {
+        let has = |font: &FontArc| font.glyph_id(c).0 != 0;
+        let last = self.fonts.len() - 1;
+        match self.fonts[..last].iter().position(has) {
+            Some(index) => index,
+            None if is_emoji(c) && self.emoji().is_some_and(has) => EMOJI,
+            None => last,
+        }
+    }
+
+    /// Split text into runs of characters drawn with the same font
+    ///
+    /// The text is shaped first, so the runs are in the order they are drawn.
+    /// Spaces stay with the run before them if its font has them. Variation selectors and zero
+    /// width joiners are left out, without shaping an emoji sequence is drawn
+    /// as the emoji it joins.
+    fn runs(&self, text: &str) -> Vec<(&FontArc, String)> {
+        let mut runs: Vec<(usize, String)> = Vec::new();
+        for c in shape(text).chars().filter(|&c| !is_ignorable(c)) {
+            let font = match (c.is_whitespace(), runs.last()) {
+                (true, Some((font, _))) if self.font(*font).glyph_id(c).0 != 0 => *font,
+                _ => self.font_for(c),
+            };
+            match runs.last_mut() {
+                Some((last, run)) if *last == font => run.push(c),
+                _ => runs.push((font, c.to_string())),
+            }
+        }
+        runs.into_iter()
+            .map(|(font, run)| (self.font(font), run))
+            .collect()
+    }
+
+    /// Width and height of a line of text, as imageproc's `text_size`
+    pub fn text_size(&self, scale: PxScale, text: &str) -> (u32, u32) {
+        self.runs(text)
+            .into_iter()
+            .map(|(font, run)| run_size(font, scale, &run))
+            .fold((0, 0), |(w, h), (run_w, run_h)| (w + run_w, h.max(run_h)))
+    }
+
+    /// Height of a line in the first font, from the top of the ascent to the bottom of the descent
+    pub fn height(&self, scale: PxScale) -> f32 {
+        self.fonts[0].as_scaled(scale).height()
+    }
+
+    /// Draw a line of text with its top left corner at `(x, y)`
+    ///
+    /// Runs of other fonts are shifted to sit on the baseline of the first.
+    /// Color glyphs keep their own colors.
+    pub fn draw_text_mut(
+        &self,
+        canvas: &mut RgbaImage,
+        color: Rgba<u8>,
+        (x, y): (i32, i32),
+        scale: PxScale,
+        text: &str,
+    ) {
+        let ascent = self.fonts[0].as_scaled(scale).ascent();
+        let mut x = x;
+        for (font, run) in self.runs(text) {
+            if has_color_glyphs(font, &run) {
+                draw_color_run(canvas, (x, y as f32 + ascent), scale, font, &run);
+            } else {
+                let shift = (ascent - font.as_scaled(scale).ascent()).round() as i32;
+                draw_text_mut(canvas, color, x, y + shift, scale, font, &run);
+            }
+            x += run_size(font, scale, &run).0 as i32;
+        }
+    }
+
+    /// Draw a line of text in a color, or in a gradient spanning the line
+    pub fn fill_text_mut(
+        &self,
+        canvas: &mut RgbaImage,
+        fill: &Fill,
+        (x, y): (i32, i32),
+        scale: PxScale,
+        text: &str,
+    ) {
+        match fill {
+            Fill::Solid(color) => self.draw_text_mut(canvas, (*color).into(), (x, y), scale, text),
+            Fill::Gradient(gradient) => {
+                let mut layer = RgbaImage::new(canvas.width(), canvas.height());
+                self.draw_text_mut(&mut layer, Rgba([255; 4]), (x, y), scale, text);
+                let (w, h) = self.text_size(scale, text);
+                gradient.paint(&mut layer, (x, y, w, h));
+                imageops::overlay(canvas, &layer, 0, 0);
+            }
+        }
+    }
+}
+
+/// Whether the character is in one of the emoji ranges
+fn is_emoji(c: char) -> bool {
+    EMOJI_RANGES
+        .iter()
+        .any(|&(first, last)| (first..=last).contains(&c))
+}
+
+/// Variation selectors and the zero width joiner, which only shaping can use
+fn is_ignorable(c: char) -> bool {
+    matches!(c, '\u{FE0E}' | '\u{FE0F}' | '\u{200D}')
+}
+
+/// Size of a run in one font, bitmap glyphs have no outlines to measure
+fn run_size(font: &FontArc, scale: PxScale, run: &str) -> (u32, u32) {
+    match has_color_glyphs(font, run) {
+        true => {
+            let scaled = font.as_scaled(scale);
+            let width: f32 = run
+                .chars()
+                .map(|c| scaled.h_advance(font.glyph_id(c)))
+                .sum();
+            (width.ceil() as u32, scaled.height().ceil() as u32)
+        }
+        false => text_size(scale, font, run),
+    }
+}
+
+/// Whether the run's glyphs are color bitmaps, as in emoji fonts
+fn has_color_glyphs(font: &FontArc, run: &str) -> bool {
+    run.chars()
+        .find(|c| !c.is_whitespace())
+        .and_then(|c| font.glyph_raster_image2(font.glyph_id(c), u16::MAX))
+        .is_some_and(|image| {
+            matches!(
+                image.format,
+                GlyphImageFormat::Png | GlyphImageFormat::BitmapPremulBgra32
+            )
+        })
+}
+
+/// Draw a run of bitmap glyphs in their own colors, the baseline starting at `origin`
+fn draw_color_run(
+    canvas: &mut RgbaImage,
+    (x, baseline): (i32, f32),
+    scale: PxScale,
+    font: &FontArc,
+    run: &str,
+) {
+    let scaled = font.as_scaled(scale);
+    let pixels_per_em = scaled.v_scale_factor() * font.units_per_em().unwrap_or(1.0);
+    let mut x = x as f32;
+    for c in run.chars() {
+        let id = font.glyph_id(c);
+        // The strike closest to the text size, scaled to it
+        let size = pixels_per_em.ceil().min(u16::MAX as f32) as u16;
+        if let Some(image) = font.glyph_raster_image2(id, size) {
+            let factor = pixels_per_em / image.pixels_per_em.max(1) as f32;
+            if let Some(glyph) = decode_glyph(image.data, image.format, image.width, image.height) {
+                let (w, h) = (
+                    (glyph.width() as f32 * factor).round().max(1.0) as u32,
+                    (glyph.height() as f32 * factor).round().max(1.0) as u32,
+                );
+                let glyph = imageops::resize(&glyph, w, h, imageops::FilterType::Triangle);
+                // The image's offsets put its bottom left corner relative to the baseline
+                let left = x + image.origin.x * factor;
+                let top = baseline - image.origin.y * factor - h as f32;
+                imageops::overlay(canvas, &glyph, left.round() as i64, top.round() as i64);
+            }
+        }
+        x += scaled.h_advance(id);
+    }
+}
+
+/// Decode the image of a bitmap glyph, PNG as in CBDT and sbix or premultiplied BGRA
+fn decode_glyph(
+    data: &[u8],
+    format: GlyphImageFormat,
+    width: u16,
+    height: u16,
+) -> Option<RgbaImage> {
+    match format {
+        GlyphImageFormat::Png => image::load_from_memory_with_format(data, ImageFormat::Png)
+            .ok()
+            .map(|image| image.to_rgba8()),
+        GlyphImageFormat::BitmapPremulBgra32 => {
+            let pixels = data
+                .chunks_exact(4)
+                .flat_map(|p| {
+                    let unpremultiply = |v: u8| match p[3] {
+                        0 => 0,
+                        a => (v as u32 * 255 / a as u32).min(255) as u8,
+                    };
+                    [
+                        unpremultiply(p[2]),
+                        unpremultiply(p[1]),
+                        unpremultiply(p[0]),
+                        p[3],
+                    ]
+                })
+                .collect();
+            RgbaImage::from_raw(width.into(), height.into(), pixels)
+        }
+        _ => None,
+    }
+}
+
+/// The bundled FangSong font
+fn builtin() -> FontRef<'static> {
+    FontRef::try_from_slice(FANGSONG).expect("the bundled font is valid")
+}
+
+/// A face of an installed font file and the names it goes by
+struct SystemFont {
+    path: PathBuf,
+    index: u32,
+    names: Vec<String>,
+    regular: bool,
+    /// Distance from the normal weight, so a family's regular face comes before its light one
+    weight: u16,
+}
+
+impl SystemFont {
+    /// Load the face if it is named `name`
+    fn load(&self, name: &str) -> Option<FontArc> {
+        if !self.names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            return None;
+        }
+        let data = fs::read(&self.path).ok()?;
+        FontVec::try_from_vec_and_index(data, self.index)
+            .ok()
+            .map(FontArc::new)
+    }
+}
+
+/// Directories that hold installed fonts on Linux, macOS and Windows
+fn font_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = [
+        "/usr/share/fonts",
+        "/usr/local/share/fonts",
+        "/System/Library/Fonts",
+        "/Library/Fonts",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .collect();
+    if let Some(home) = std::env::var_os("HOME").map(PathBuf::from) {
+        dirs.push(home.join(".local/share/fonts"));
+        dirs.push(home.join(".fonts"));
+        dirs.push(home.join("Library/Fonts"));
+    }
+    if let Some(windows) = std::env::var_os("WINDIR").map(PathBuf::from) {
+        dirs.push(windows.join("Fonts"));
+    }
+    if let Some(local) = std::env::var_os("LOCALAPPDATA").map(PathBuf::from) {
+        dirs.push(local.join("Microsoft/Windows/Fonts"));
+    }
+    dirs
+}
+
+/// Every face of the installed fonts, regular faces of normal weight first
+fn system_fonts() -> Vec<SystemFont> {
+    let mut files = Vec::new();
+    let mut pending = font_dirs();
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.is_dir() {
+                pending.push(path);
+            } else if path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| FONT_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            {
+                files.push(path);
+            }
+        }
+    }
+    // Same order on every run, whatever order the directories are listed in
+    files.sort();
+
+    let mut fonts: Vec<SystemFont> = files
+        .into_iter()
+        .flat_map(|path| {
+            let Ok(data) = fs::read(&path) else {
+                return Vec::new();
+            };
+            let count = ttf_parser::fonts_in_collection(&data).unwrap_or(1);
+            (0..count)
+                .filter_map(|index| {
+                    let face = ttf_parser::Face::parse(&data, index).ok()?;
+                    let names = face
+                        .names()
+                        .into_iter()
+                        .filter(|name| {
+                            matches!(
+                                name.name_id,
+                                ttf_parser::name_id::FAMILY
+                                    | ttf_parser::name_id::TYPOGRAPHIC_FAMILY
+                                    | ttf_parser::name_id::FULL_NAME
+                            )
+                        })
+                        .filter_map(|name| name.to_string())
+                        .collect();
+                    Some(SystemFont {
+                        path: path.clone(),
+                        index,
+                        names,
+                        regular: !face.is_bold() && !face.is_italic(),
+                        weight: face.weight().to_number().abs_diff(400),
+                    })
+                })
+                .collect()
+        })
+        .collect();
+    fonts.sort_by_key(|font| (!font.regular, font.weight));
+    fonts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback() {
+        let fonts = Fonts::load(None).unwrap();
+        assert_eq!(fonts.fonts.len(), 1);
+        assert_eq!(fonts.runs("图像 tools").len(), 1);
+
+        // Installed fonts are found by name, with a file path as the fallback
+        let Some(installed) = system_fonts().into_iter().find(|font| font.regular) else {
+            return;
+        };
+        let name = installed.names.first().cloned().unwrap_or_default();
+        let spec = format!("{}, {}", name, installed.path.display());
+        let fonts = Fonts::load(Some(Path::new(&spec))).unwrap();
+        assert_eq!(fonts.fonts.len(), 3);
+
+        // A font without Chinese glyphs hands them on to FangSong
+        let fonts = Fonts::load(Some(Path::new(&name))).unwrap();
+        if fonts.fonts[0].glyph_id('图').0 != 0 {
+            return;
+        }
+        let runs: Vec<String> = fonts.runs("ab 图像 cd").into_iter().map(|r| r.1).collect();
+        assert_eq!(runs, ["ab ", "图像 ", "cd"]);
+        let scale = PxScale::from(20.0);
+        assert_eq!(
+            fonts.text_size(scale, "ab 图像 cd").0,
+            ["ab ", "图像 ", "cd"]
+                .iter()
+                .zip([0, 1, 0])
+                .map(|(run, font)| text_size(scale, &fonts.fonts[font], run).0)
+                .sum::<u32>()
+        );
+    }
+
+    #[test]
+    fn test_emoji() {
+        assert!(is_emoji('🎉') && is_emoji('☀') && !is_emoji('图') && !is_emoji('a'));
+
+        // Variation selectors and joiners are dropped, the emoji they join are kept
+        let fonts = Fonts::load(None).unwrap();
+        let runs: Vec<String> = fonts
+            .runs("Sale 🎉\u{FE0F}👩\u{200D}💻")
+            .into_iter()
+            .map(|r| r.1)
+            .collect();
+        assert_eq!(runs.concat(), "Sale 🎉👩💻");
+
+        // Premultiplied BGRA glyphs come out as straight RGBA
+        let glyph = decode_glyph(
+            &[0, 0, 128, 128, 0, 0, 0, 0],
+            GlyphImageFormat::BitmapPremulBgra32,
+            2,
+            1,
+        )
+        .unwrap();
+        assert_eq!(glyph.get_pixel(0, 0).0, [255, 0, 0, 128]);
+        assert_eq!(glyph.get_pixel(1, 0).0, [0, 0, 0, 0]);
+        assert!(decode_glyph(&[0; 4], GlyphImageFormat::BitmapMono, 1, 1).is_none());
+    }
+
+    #[test]
+    fn test_missing_family() {
+        assert!(matches!(
+            Fonts::load(Some(Path::new("No Such Font Family"))),
+            Err(ImgtoolsError::InvalidArgument(_))
+        ));
+    }
+}
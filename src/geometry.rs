@@ -0,0 +1,490 @@
+use crate::process::rgba8;
+use crate::{Filter, ImgtoolsError, Region, Scale, ThumbnailMode};
+use image::imageops::overlay;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+/// Compute resize target dimensions from the source size
+///
+/// A scale factor applies to both sides, a single side derives the other one
+/// from the aspect ratio. Results are at least one pixel, a side given as 0
+/// is refused.
+pub fn resize_dimensions(
+    (src_w, src_h): (u32, u32),
+    width: Option<u32>,
+    height: Option<u32>,
+    scale: Option<Scale>,
+) -> Result<(u32, u32), ImgtoolsError> {
+    if width == Some(0) || height == Some(0) {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Resize width and height must be greater than 0".into(),
+        ));
+    }
+    let scaled = |size: u32, factor: f64| ((size as f64 * factor).round() as u32).max(1);
+
+    match (width, height, scale) {
+        (None, None, Some(Scale(factor))) => {
+            Ok((scaled(src_w, factor as f64), scaled(src_h, factor as f64)))
+        }
+        (Some(w), Some(h), None) => Ok((w, h)),
+        (Some(w), None, None) => Ok((w, scaled(src_h, w as f64 / src_w as f64))),
+        (None, Some(h), None) => Ok((scaled(src_w, h as f64 / src_h as f64), h)),
+        (None, None, None) => Err(ImgtoolsError::InvalidArgument(
+            "Resize needs a width, a height or a scale".into(),
+        )),
+        _ => Err(ImgtoolsError::InvalidArgument(
+            "A scale can't be combined with a width or height".into(),
+        )),
+    }
+}
+
+/// Largest size with the aspect ratio of `(src_w, src_h)` that fits in the box
+///
+/// Matches `DynamicImage::resize`, which may enlarge the image to fill the box.
+pub fn fit_dimensions((src_w, src_h): (u32, u32), (width, height): (u32, u32)) -> (u32, u32) {
+    let ratio = (width as f64 / src_w as f64).min(height as f64 / src_h as f64);
+    let fit = |side: u32| ((side as f64 * ratio).round() as u32).max(1);
+    (fit(src_w), fit(src_h))
+}
+
+/// Create a thumbnail of at most `width` x `height`
+///
+/// Fit and pad never enlarge the image, fill always produces exactly the
+/// requested size.
+pub fn thumbnail(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    mode: ThumbnailMode,
+    filter: Filter,
+    background: Rgba<u8>,
+) -> DynamicImage {
+    match mode {
+        ThumbnailMode::Fit => shrink_to_fit(img, width, height, filter),
+        ThumbnailMode::Fill => img.resize_to_fill(width, height, filter.into()),
+        ThumbnailMode::Pad => {
+            let fitted = shrink_to_fit(img, width, height, filter);
+            let x = (width - fitted.width()) / 2;
+            let y = (height - fitted.height()) / 2;
+            pad(&fitted, width, height, (x as i64, y as i64), background)
+        }
+    }
+}
+
+/// Place the image at `offset` on a `width` x `height` canvas filled with the background
+///
+/// Parts outside the canvas are cut off. The result is RGB when neither the
+/// image nor the background is transparent.
+pub fn pad(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    (x, y): (i64, i64),
+    background: Rgba<u8>,
+) -> DynamicImage {
+    let mut canvas = RgbaImage::from_pixel(width, height, background);
+    overlay(&mut canvas, &*rgba8(img), x, y);
+
+    // Keep images without transparency opaque, e.g. for JPEG output
+    match background[3] == 255 && !img.color().has_alpha() {
+        true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+        false => DynamicImage::ImageRgba8(canvas),
+    }
+}
+
+/// Shear the image by `x_degrees` horizontally and `y_degrees` vertically
+///
+/// The canvas grows to hold the whole sheared image and exposed areas are
+/// filled with the background. The result is RGB when neither the image nor
+/// the background is transparent.
+pub fn shear(
+    img: &DynamicImage,
+    x_degrees: f32,
+    y_degrees: f32,
+    background: Rgba<u8>,
+) -> Result<DynamicImage, ImgtoolsError> {
+    let (tx, ty) = (x_degrees.to_radians().tan(), y_degrees.to_radians().tan());
+    let (w, h) = (img.width() as f32, img.height() as f32);
+
+    // Bounding box of the sheared corners
+    let corners = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].map(|(x, y)| (x + tx * y, ty * x + y));
+    let (min_x, max_x) = corners.iter().fold((f32::MAX, f32::MIN), |(lo, hi), c| {
+        (lo.min(c.0), hi.max(c.0))
+    });
+    let (min_y, max_y) = corners.iter().fold((f32::MAX, f32::MIN), |(lo, hi), c| {
+        (lo.min(c.1), hi.max(c.1))
+    });
+
+    let det = 1.0 - tx * ty;
+    if det.abs() < 1e-3 {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Shearing by {} and {} degrees collapses the image",
+            x_degrees, y_degrees
+        )));
+    }
+
+    // Map each output pixel center back into the source
+    let rgba = rgba8(img);
+    let (width, height) = (
+        ((max_x - min_x).round() as u32).max(1),
+        ((max_y - min_y).round() as u32).max(1),
+    );
+    let canvas = RgbaImage::from_fn(width, height, |x, y| {
+        let (px, py) = (x as f32 + 0.5 + min_x, y as f32 + 0.5 + min_y);
+        let sx = (px - tx * py) / det;
+        let sy = (py - ty * px) / det;
+        sample_bilinear(&rgba, sx - 0.5, sy - 0.5, background)
+    });
+
+    Ok(match background[3] == 255 && !img.color().has_alpha() {
+        true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+        false => DynamicImage::ImageRgba8(canvas),
+    })
+}
+
+/// Interpolate between the four pixels around `(x, y)`, outside pixels are the background
+pub(crate) fn sample_bilinear(img: &RgbaImage, x: f32, y: f32, background: Rgba<u8>) -> Rgba<u8> {
+    let (left, top) = (x.floor(), y.floor());
+    let (fx, fy) = (x - left, y - top);
+    let pixel = |px: f32, py: f32| match px >= 0.0
+        && py >= 0.0
+        && px < img.width() as f32
+        && py < img.height() as f32
+    {
+        true => *img.get_pixel(px as u32, py as u32),
+        false => background,
+    };
+
+    let samples = [
+        (pixel(left, top), (1.0 - fx) * (1.0 - fy)),
+        (pixel(left + 1.0, top), fx * (1.0 - fy)),
+        (pixel(left, top + 1.0), (1.0 - fx) * fy),
+        (pixel(left + 1.0, top + 1.0), fx * fy),
+    ];
+    // Weight colors by alpha so transparent pixels don't darken the edges
+    let alpha: f32 = samples.iter().map(|(p, w)| p[3] as f32 * w).sum();
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let mixed = [0, 1, 2].map(|c| {
+        let value: f32 = samples
+            .iter()
+            .map(|(p, w)| p[c] as f32 * p[3] as f32 * w)
+            .sum();
+        (value / alpha).round().clamp(0.0, 255.0) as u8
+    });
+    Rgba([mixed[0], mixed[1], mixed[2], alpha.round().min(255.0) as u8])
+}
+
+/// Scale the image to a square icon of `size` pixels
+///
+/// Images that aren't square are centered on a transparent square first.
+pub fn icon(img: &DynamicImage, size: u32) -> RgbaImage {
+    let (w, h) = img.dimensions();
+    let side = w.max(h);
+    let square = pad(
+        img,
+        side,
+        side,
+        (((side - w) / 2) as i64, ((side - h) / 2) as i64),
+        Rgba([0; 4]),
+    );
+    image::imageops::resize(&square.to_rgba8(), size, size, Filter::Lanczos3.into())
+}
+
+/// How to cut an image into tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tiles {
+    /// Tiles of this size, the last column and row may be smaller
+    Size(u32, u32),
+    /// This many columns and rows of about equal size
+    Grid(u32, u32),
+}
+
+/// Cut the image into tiles, returned row by row with their column and row
+pub fn slice(
+    img: &DynamicImage,
+    tiles: Tiles,
+) -> Result<Vec<(u32, u32, DynamicImage)>, ImgtoolsError> {
+    let (w, h) = img.dimensions();
+    let (xs, ys) = match tiles {
+        Tiles::Size(tile_w, tile_h) => (spans_of_size(w, tile_w), spans_of_size(h, tile_h)),
+        Tiles::Grid(columns, rows) => {
+            if columns > w || rows > h {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "A {}x{} image can't be cut into {} columns and {} rows",
+                    w, h, columns, rows
+                )));
+            }
+            (spans_of_count(w, columns), spans_of_count(h, rows))
+        }
+    };
+
+    let mut result = Vec::with_capacity(xs.len() * ys.len());
+    for (row, &(y, tile_h)) in ys.iter().enumerate() {
+        for (col, &(x, tile_w)) in xs.iter().enumerate() {
+            result.push((col as u32, row as u32, img.crop_imm(x, y, tile_w, tile_h)));
+        }
+    }
+    Ok(result)
+}
+
+/// Area left after cutting off the border, `None` when the whole image is border
+///
+/// Pixels within `fuzz` of the border color, as a distance in RGBA from 0.0
+/// to 1.0, belong to the border. A fully transparent border color takes
+/// every pixel at most `fuzz` opaque, whatever color it hides.
+pub fn trim_bounds(img: &RgbaImage, border: Rgba<u8>, fuzz: f32) -> Option<Region> {
+    let max = 2.0 * 255.0;
+    let is_border = |x: u32, y: u32| {
+        let pixel = img.get_pixel(x, y);
+        match border[3] {
+            0 => pixel[3] as f32 <= fuzz * 255.0,
+            _ => {
+                let distance = (0..4)
+                    .map(|c| (pixel[c] as f32 - border[c] as f32).powi(2))
+                    .sum::<f32>()
+                    .sqrt();
+                distance <= fuzz * max
+            }
+        }
+    };
+    let (w, h) = img.dimensions();
+    let top = (0..h).find(|&y| !(0..w).all(|x| is_border(x, y)))?;
+    let bottom = (top..h).rev().find(|&y| !(0..w).all(|x| is_border(x, y)))?;
+    let content = |x: u32| !(top..=bottom).all(|y| is_border(x, y));
+    let left = (0..w).find(|&x| content(x))?;
+    let right = (left..w).rev().find(|&x| content(x))?;
+    Some(Region {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    })
+}
+
+/// Start and length of consecutive spans of `size`, the last one may be shorter
+fn spans_of_size(len: u32, size: u32) -> Vec<(u32, u32)> {
+    (0..len)
+        .step_by(size.max(1) as usize)
+        .map(|start| (start, size.min(len - start)))
+        .collect()
+}
+
+/// Start and length of `count` spans that differ by at most one pixel
+fn spans_of_count(len: u32, count: u32) -> Vec<(u32, u32)> {
+    let edge = |i: u32| (i as u64 * len as u64 / count as u64) as u32;
+    (0..count)
+        .map(|i| (edge(i), edge(i + 1) - edge(i)))
+        .collect()
+}
+
+fn shrink_to_fit(img: &DynamicImage, width: u32, height: u32, filter: Filter) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    match w <= width && h <= height {
+        true => img.clone(),
+        false => img.resize(width, height, filter.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn test_resize_dimensions() {
+        let src = (400, 300);
+        assert_eq!(
+            resize_dimensions(src, None, None, Some(Scale(0.5))).unwrap(),
+            (200, 150)
+        );
+        assert_eq!(
+            resize_dimensions(src, Some(100), None, None).unwrap(),
+            (100, 75)
+        );
+        assert_eq!(
+            resize_dimensions(src, None, Some(150), None).unwrap(),
+            (200, 150)
+        );
+        assert_eq!(
+            resize_dimensions(src, Some(10), Some(20), None).unwrap(),
+            (10, 20)
+        );
+        assert_eq!(
+            resize_dimensions(src, None, None, Some(Scale(0.001))).unwrap(),
+            (1, 1)
+        );
+
+        assert!(resize_dimensions(src, None, None, None).is_err());
+        assert!(resize_dimensions(src, Some(10), None, Some(Scale(2.0))).is_err());
+        for (width, height) in [(Some(0), None), (None, Some(0)), (Some(10), Some(0))] {
+            assert!(matches!(
+                resize_dimensions(src, width, height, None),
+                Err(ImgtoolsError::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_pad() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, Rgb([9, 9, 9])));
+        let red = Rgba([255, 0, 0, 255]);
+
+        let padded = pad(&img, 4, 3, (1, 0), red);
+        assert_eq!(padded.dimensions(), (4, 3));
+        let rgb = padded.as_rgb8().unwrap();
+        assert_eq!(rgb.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(rgb.get_pixel(1, 0), &Rgb([9, 9, 9]));
+        assert_eq!(rgb.get_pixel(2, 1), &Rgb([9, 9, 9]));
+        assert_eq!(rgb.get_pixel(2, 2), &Rgb([255, 0, 0]));
+
+        // Transparent backgrounds keep the alpha channel
+        let padded = pad(&img, 4, 4, (0, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(
+            padded.as_rgba8().unwrap().get_pixel(3, 3),
+            &Rgba([0, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn test_thumbnail_modes() {
+        let img = DynamicImage::new_rgb8(400, 200);
+        let white = Rgba([255, 255, 255, 255]);
+
+        let fit = thumbnail(&img, 100, 100, ThumbnailMode::Fit, Filter::Triangle, white);
+        assert_eq!(fit.dimensions(), (100, 50));
+
+        let fill = thumbnail(&img, 100, 100, ThumbnailMode::Fill, Filter::Triangle, white);
+        assert_eq!(fill.dimensions(), (100, 100));
+
+        let pad = thumbnail(&img, 100, 100, ThumbnailMode::Pad, Filter::Triangle, white);
+        assert_eq!(pad.dimensions(), (100, 100));
+        let pad = pad.as_rgb8().unwrap();
+        assert_eq!(*pad.get_pixel(50, 10), Rgb([255, 255, 255]));
+        assert_eq!(*pad.get_pixel(50, 50), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_thumbnail_does_not_enlarge() {
+        let img = DynamicImage::new_rgb8(40, 20);
+        let clear = Rgba([0, 0, 0, 0]);
+
+        let fit = thumbnail(&img, 100, 100, ThumbnailMode::Fit, Filter::Nearest, clear);
+        assert_eq!(fit.dimensions(), (40, 20));
+
+        let pad = thumbnail(&img, 100, 100, ThumbnailMode::Pad, Filter::Nearest, clear);
+        assert_eq!(pad.dimensions(), (100, 100));
+        assert!(pad.color().has_alpha());
+    }
+
+    #[test]
+    fn test_icon() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(20, 10, Rgb([9, 9, 9])));
+
+        // Wide images get transparent bars above and below
+        let small = icon(&img, 8);
+        assert_eq!(small.dimensions(), (8, 8));
+        assert_eq!(small.get_pixel(4, 0)[3], 0);
+        assert_eq!(small.get_pixel(4, 4), &Rgba([9, 9, 9, 255]));
+
+        // Small sources are enlarged
+        assert_eq!(icon(&img, 64).dimensions(), (64, 64));
+    }
+
+    #[test]
+    fn test_slice() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(10, 5, |x, y| {
+            Rgb([x as u8, y as u8, 0])
+        }));
+
+        let tiles = slice(&img, Tiles::Size(4, 4)).unwrap();
+        let sizes: Vec<_> = tiles
+            .iter()
+            .map(|(x, y, tile)| (*x, *y, tile.dimensions()))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (0, 0, (4, 4)),
+                (1, 0, (4, 4)),
+                (2, 0, (2, 4)),
+                (0, 1, (4, 1)),
+                (1, 1, (4, 1)),
+                (2, 1, (2, 1)),
+            ]
+        );
+        assert_eq!(tiles[4].2.to_rgb8().get_pixel(0, 0), &Rgb([4, 4, 0]));
+
+        // Grid tiles cover the image with sizes differing by at most one pixel
+        let tiles = slice(&img, Tiles::Grid(3, 2)).unwrap();
+        let widths: Vec<_> = tiles[..3].iter().map(|(_, _, t)| t.width()).collect();
+        assert_eq!(widths, [3, 3, 4]);
+        assert_eq!(tiles[3].2.dimensions(), (3, 3));
+        assert_eq!(tiles[5].2.to_rgb8().get_pixel(0, 0), &Rgb([6, 2, 0]));
+
+        assert!(slice(&img, Tiles::Grid(11, 1)).is_err());
+    }
+
+    #[test]
+    fn test_trim_bounds() {
+        let mut img = RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]));
+        img.put_pixel(4, 2, Rgba([0, 0, 0, 255]));
+        img.put_pixel(15, 7, Rgba([250, 250, 250, 255]));
+        let white = Rgba([255, 255, 255, 255]);
+        let area = |x, y, width, height| Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(trim_bounds(&img, white, 0.0), Some(area(4, 2, 12, 6)));
+        // The light gray pixel is close enough to white
+        assert_eq!(trim_bounds(&img, white, 0.05), Some(area(4, 2, 1, 1)));
+        assert_eq!(
+            trim_bounds(&img, Rgba([0, 0, 0, 255]), 0.0),
+            Some(area(0, 0, 20, 10))
+        );
+        assert_eq!(
+            trim_bounds(&RgbaImage::from_pixel(3, 3, white), white, 0.0),
+            None
+        );
+
+        // Transparent borders of any color
+        let mut img = RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 0]));
+        img.put_pixel(1, 8, Rgba([0, 255, 0, 10]));
+        img.put_pixel(6, 3, Rgba([0, 0, 255, 255]));
+        let clear = Rgba([0, 0, 0, 0]);
+        assert_eq!(trim_bounds(&img, clear, 0.0), Some(area(1, 3, 6, 6)));
+        assert_eq!(trim_bounds(&img, clear, 0.1), Some(area(6, 3, 1, 1)));
+    }
+
+    #[test]
+    fn test_shear() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(10, 4, Rgb([9, 9, 9])));
+
+        // 45 degrees horizontally widens the canvas by the height
+        let sheared = shear(&img, 45.0, 0.0, Rgba([0, 0, 0, 0])).unwrap();
+        assert_eq!(sheared.dimensions(), (14, 4));
+        let rgba = sheared.to_rgba8();
+        // Lower rows move right
+        assert_eq!(rgba.get_pixel(1, 0), &Rgba([9, 9, 9, 255]));
+        // Edges are anti-aliased without darkening
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([9, 9, 9, 128]));
+        assert_eq!(rgba.get_pixel(0, 3)[3], 0);
+        assert_eq!(rgba.get_pixel(6, 2), &Rgba([9, 9, 9, 255]));
+        assert_eq!(rgba.get_pixel(13, 0)[3], 0);
+        assert_eq!(rgba.get_pixel(12, 3), &Rgba([9, 9, 9, 255]));
+
+        // Negative angles lean the other way, an opaque background keeps RGB
+        let sheared = shear(&img, 0.0, -45.0, Rgba([255, 255, 255, 255])).unwrap();
+        assert_eq!(sheared.dimensions(), (10, 14));
+        assert_eq!(sheared.color(), image::ColorType::Rgb8);
+        assert_eq!(sheared.to_rgb8().get_pixel(0, 0), &Rgb([255, 255, 255]));
+
+        assert_eq!(
+            shear(&img, 0.0, 0.0, Rgba([0; 4])).unwrap().dimensions(),
+            (10, 4)
+        );
+        assert!(shear(&img, 45.0, 45.0, Rgba([0; 4])).is_err());
+    }
+}
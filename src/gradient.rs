@@ -0,0 +1,165 @@
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+
+/// How the colors of a gradient spread over an area
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradientShape {
+    /// Along a line at an angle in degrees, 0 runs upward and 90 to the right as in CSS
+    Linear(f32),
+    /// Outward from the center to the farthest corner
+    Radial,
+}
+
+/// Colors spread evenly from the start to the end of a gradient
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    pub shape: GradientShape,
+    pub colors: Vec<Rgba<u8>>,
+}
+
+impl Gradient {
+    /// Color at `(x, y)` of a gradient spanning a `width` x `height` area
+    ///
+    /// Points outside the area take the nearest end color.
+    pub fn color_at(&self, (x, y): (f32, f32), (width, height): (u32, u32)) -> Rgba<u8> {
+        let (w, h) = (width.max(1) as f32, height.max(1) as f32);
+        let (dx, dy) = (x - w / 2.0, y - h / 2.0);
+        let t = match self.shape {
+            GradientShape::Linear(angle) => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                // The gradient line is just long enough for the corners to get the end colors
+                let length = (w * sin).abs() + (h * cos).abs();
+                (dx * sin - dy * cos) / length.max(f32::EPSILON) + 0.5
+            }
+            GradientShape::Radial => (dx.hypot(dy) / (w / 2.0).hypot(h / 2.0)).min(1.0),
+        };
+        self.color(t.clamp(0.0, 1.0))
+    }
+
+    /// Color at `t` from 0.0 to 1.0, mixed with premultiplied alpha so transparent ends don't gray
+    fn color(&self, t: f32) -> Rgba<u8> {
+        let last = self.colors.len().saturating_sub(1);
+        let at = t * last as f32;
+        let i = (at.floor() as usize).min(last.saturating_sub(1));
+        let (from, to) = (self.colors[i], self.colors[(i + 1).min(last)]);
+        let frac = at - i as f32;
+
+        let alpha = from[3] as f32 * (1.0 - frac) + to[3] as f32 * frac;
+        let channel = |c: usize| match alpha {
+            0.0 => 0,
+            _ => {
+                let mixed = from[c] as f32 * from[3] as f32 * (1.0 - frac)
+                    + to[c] as f32 * to[3] as f32 * frac;
+                (mixed / alpha).round().clamp(0.0, 255.0) as u8
+            }
+        };
+        Rgba([channel(0), channel(1), channel(2), alpha.round() as u8])
+    }
+
+    /// Whether every color of the gradient is opaque
+    pub fn is_opaque(&self) -> bool {
+        self.colors.iter().all(|color| color[3] == 255)
+    }
+
+    /// Render the gradient over a whole `width` x `height` image
+    pub fn render(&self, width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            self.color_at((x as f32 + 0.5, y as f32 + 0.5), (width, height))
+        })
+    }
+
+    /// Recolor a layer with the gradient, keeping its alpha as coverage
+    ///
+    /// Text is filled by drawing it on a transparent layer first. The gradient
+    /// spans the `(x, y, width, height)` area of the layer, usually the text's box.
+    pub fn paint(&self, layer: &mut RgbaImage, (x, y, width, height): (i32, i32, u32, u32)) {
+        for (px, py, pixel) in layer.enumerate_pixels_mut() {
+            if pixel[3] == 0 {
+                continue;
+            }
+            let point = ((px as i32 - x) as f32 + 0.5, (py as i32 - y) as f32 + 0.5);
+            let color = self.color_at(point, (width, height));
+            let alpha = (color[3] as u32 * pixel[3] as u32 / 255) as u8;
+            *pixel = Rgba([color[0], color[1], color[2], alpha]);
+        }
+    }
+
+    /// Draw the image over the gradient, as the background of a padded image or a sheet
+    ///
+    /// The result is RGB when the gradient is opaque and `keep_alpha` is false.
+    pub fn underlay(&self, img: &DynamicImage, keep_alpha: bool) -> DynamicImage {
+        let mut canvas = self.render(img.width(), img.height());
+        imageops::overlay(&mut canvas, &img.to_rgba8(), 0, 0);
+        match self.is_opaque() && !keep_alpha {
+            true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+            false => DynamicImage::ImageRgba8(canvas),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    #[test]
+    fn test_linear() {
+        // 90 degrees runs from left to right
+        let gradient = Gradient {
+            shape: GradientShape::Linear(90.0),
+            colors: vec![RED, BLUE],
+        };
+        let image = gradient.render(100, 10);
+        assert!(image.get_pixel(0, 5)[0] > 250 && image.get_pixel(0, 5)[2] < 5);
+        assert!(image.get_pixel(99, 5)[2] > 250 && image.get_pixel(99, 5)[0] < 5);
+        assert_eq!(image.get_pixel(10, 0), image.get_pixel(10, 9));
+
+        // 0 degrees runs upward, a third color sits in the middle
+        let gradient = Gradient {
+            shape: GradientShape::Linear(0.0),
+            colors: vec![RED, Rgba([0, 255, 0, 255]), BLUE],
+        };
+        assert_eq!(gradient.color_at((5.0, 10.0), (10, 10)), RED);
+        assert_eq!(
+            gradient.color_at((5.0, 5.0), (10, 10)),
+            Rgba([0, 255, 0, 255])
+        );
+        assert_eq!(gradient.color_at((5.0, -3.0), (10, 10)), BLUE);
+    }
+
+    #[test]
+    fn test_radial() {
+        let gradient = Gradient {
+            shape: GradientShape::Radial,
+            colors: vec![RED, Rgba([0, 0, 255, 0])],
+        };
+        assert_eq!(gradient.color_at((5.0, 5.0), (10, 10)), RED);
+        assert_eq!(gradient.color_at((0.0, 0.0), (10, 10))[3], 0);
+        // Fading to transparent keeps the color instead of going dark
+        let halfway = gradient.color_at((5.0, 5.0 - 50f32.sqrt() / 2.0), (10, 10));
+        assert_eq!(halfway, Rgba([255, 0, 0, 128]));
+        assert!(!gradient.is_opaque());
+    }
+
+    #[test]
+    fn test_paint_and_underlay() {
+        let gradient = Gradient {
+            shape: GradientShape::Linear(90.0),
+            colors: vec![RED, BLUE],
+        };
+        let mut layer = RgbaImage::new(4, 1);
+        layer.put_pixel(0, 0, Rgba([255, 255, 255, 128]));
+        layer.put_pixel(3, 0, Rgba([255, 255, 255, 255]));
+        gradient.paint(&mut layer, (0, 0, 4, 1));
+        assert_eq!(layer.get_pixel(0, 0)[3], 128);
+        assert!(layer.get_pixel(0, 0)[0] > layer.get_pixel(0, 0)[2]);
+        assert!(layer.get_pixel(3, 0)[2] > layer.get_pixel(3, 0)[0]);
+        assert_eq!(layer.get_pixel(1, 0)[3], 0);
+
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(4, 2));
+        let under = gradient.underlay(&img, false);
+        assert!(!under.color().has_alpha());
+        assert!(gradient.underlay(&img, true).color().has_alpha());
+    }
+}
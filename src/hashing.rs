@@ -0,0 +1,163 @@
+use crate::{HashAlgorithm, ReportFormat};
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+use serde_json::json;
+use std::f64::consts::PI;
+use std::fmt;
+
+/// 64-bit perceptual hash, similar images differ in few bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Hash the grayscale image downscaled to a few pixels
+    ///
+    /// aHash compares 8x8 pixels with their mean, dHash compares each of 9x8
+    /// pixels with its right neighbour and pHash compares the lowest 8x8
+    /// frequencies of a 32x32 DCT with their median.
+    pub fn new(img: &DynamicImage, algorithm: HashAlgorithm) -> Self {
+        let small = |w, h| img.resize_exact(w, h, FilterType::Triangle).into_luma8();
+        match algorithm {
+            HashAlgorithm::Average => {
+                let pixels = small(8, 8).into_raw();
+                let mean = pixels.iter().map(|&p| p as u32).sum::<u32>() / 64;
+                ImageHash::from_bits(pixels.iter().map(|&p| p as u32 > mean))
+            }
+            HashAlgorithm::Difference => {
+                let pixels = small(9, 8);
+                ImageHash::from_bits(
+                    (0..8)
+                        .flat_map(|y| (0..8).map(move |x| (x, y)))
+                        .map(|(x, y)| pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0]),
+                )
+            }
+            HashAlgorithm::Perceptual => {
+                let coefficients = dct_8x8(&small(32, 32));
+                // The DC term only holds the mean brightness
+                let mut sorted = coefficients[1..].to_vec();
+                sorted.sort_by(f64::total_cmp);
+                let median = sorted[sorted.len() / 2];
+                ImageHash::from_bits(coefficients.iter().map(|&c| c > median))
+            }
+        }
+    }
+
+    /// Number of differing bits
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+
+    /// Build a hash from 64 bits, the first one is the most significant
+    fn from_bits(bits: impl Iterator<Item = bool>) -> Self {
+        ImageHash(bits.fold(0, |hash, bit| hash << 1 | bit as u64))
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Format a hash, or its distance to another hash, as text or JSON
+///
+/// Text prints the hex hash, or only the distance when comparing.
+pub fn hash_report(
+    algorithm: HashAlgorithm,
+    hash: ImageHash,
+    other: Option<ImageHash>,
+    format: ReportFormat,
+) -> String {
+    match (format, other) {
+        (ReportFormat::Text, None) => hash.to_string(),
+        (ReportFormat::Text, Some(other)) => hash.distance(&other).to_string(),
+        (ReportFormat::Json, None) => {
+            json!({ "algorithm": algorithm.to_string(), "hash": hash.to_string() }).to_string()
+        }
+        (ReportFormat::Json, Some(other)) => json!({
+            "algorithm": algorithm.to_string(),
+            "hash": hash.to_string(),
+            "other": other.to_string(),
+            "distance": hash.distance(&other),
+        })
+        .to_string(),
+    }
+}
+
+/// Lowest 8x8 coefficients of the 2D DCT-II of a 32x32 image, row by row
+fn dct_8x8(img: &GrayImage) -> Vec<f64> {
+    let cos: Vec<[f64; 32]> = (0..8)
+        .map(|u| std::array::from_fn(|x| ((2 * x + 1) as f64 * u as f64 * PI / 64.0).cos()))
+        .collect();
+
+    let mut coefficients = Vec::with_capacity(64);
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for (y, cos_y) in cos[v].iter().enumerate() {
+                for (x, cos_x) in cos[u].iter().enumerate() {
+                    sum += img.get_pixel(x as u32, y as u32)[0] as f64 * cos_x * cos_y;
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+    coefficients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb, RgbImage};
+
+    const ALGORITHMS: [HashAlgorithm; 3] = [
+        HashAlgorithm::Average,
+        HashAlgorithm::Difference,
+        HashAlgorithm::Perceptual,
+    ];
+
+    fn pattern(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(w, h, |x, y| {
+            let (fx, fy) = (x as f32 / w as f32, y as f32 / h as f32);
+            Luma([((fx * 6.0).sin() * (fy * 4.0).cos() * 100.0 + 128.0) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_hash_similar_images() {
+        for algorithm in ALGORITHMS {
+            let original = ImageHash::new(&pattern(128, 96), algorithm);
+            // Scaling and small brightness changes keep the hash close
+            let scaled = ImageHash::new(&pattern(64, 48), algorithm);
+            let brighter = ImageHash::new(&pattern(128, 96).brighten(10), algorithm);
+            assert!(original.distance(&scaled) <= 6, "{:?}", algorithm);
+            assert!(original.distance(&brighter) <= 6, "{:?}", algorithm);
+
+            let flipped = ImageHash::new(&pattern(128, 96).fliph(), algorithm);
+            assert!(original.distance(&flipped) > 10, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_hash_bits() {
+        // Left half dark: the first 4 pixels of every aHash row are below the mean
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, _| match x < 8 {
+            true => Rgb([0, 0, 0]),
+            false => Rgb([255, 255, 255]),
+        }));
+        let hash = ImageHash::new(&img, HashAlgorithm::Average);
+        assert_eq!(hash, ImageHash(0x0f0f_0f0f_0f0f_0f0f));
+        assert_eq!(hash.to_string(), "0f0f0f0f0f0f0f0f");
+        assert_eq!(hash.distance(&ImageHash(0)), 32);
+
+        let report = hash_report(
+            HashAlgorithm::Average,
+            hash,
+            Some(ImageHash(0)),
+            ReportFormat::Json,
+        );
+        let json: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(json["algorithm"], "ahash");
+        assert_eq!(json["distance"], 32);
+    }
+}
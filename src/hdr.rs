@@ -0,0 +1,414 @@
+use crate::process::same_size;
+use crate::{ImgtoolsError, ToneMap};
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Average scene brightness Reinhard maps to, the middle gray of a photo
+const KEY: f32 = 0.18;
+/// Drago's bias, lower values give more contrast
+const DRAGO_BIAS: f32 = 0.85;
+/// Spread of the brightness around 0.5 that exposure fusion counts as well exposed
+const WELL_EXPOSED: f32 = 0.2;
+/// Side of the smallest level of the fusion pyramids
+const SMALLEST_LEVEL: u32 = 8;
+
+/// Compress the brightness of a high dynamic range image into an 8-bit one
+///
+/// Floating point images such as Radiance HDR and OpenEXR hold linear light,
+/// other images are taken as sRGB. The exposure in stops is applied first.
+/// Reinhard maps the average brightness to middle gray and rolls off towards
+/// the brightest pixel, Drago compresses logarithmically, keeping more
+/// detail in the highlights.
+pub fn tone_map(img: &DynamicImage, operator: ToneMap, exposure: f32) -> RgbImage {
+    let linear = matches!(
+        img,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    );
+    let gain = 2f32.powf(exposure);
+    let mut rgb = img.to_rgb32f();
+    for pixel in rgb.pixels_mut() {
+        for value in pixel.0.iter_mut() {
+            let light = match linear {
+                true => value.max(0.0),
+                false => srgb_to_linear(*value),
+            };
+            *value = light * gain;
+        }
+    }
+
+    let luminance = |p: &Rgb<f32>| 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2];
+    let count = rgb.pixels().len().max(1) as f32;
+    // The geometric mean, the small offset keeps black pixels from taking it to zero
+    let average = (rgb
+        .pixels()
+        .map(|p| (1e-4 + luminance(p)).ln())
+        .sum::<f32>()
+        / count)
+        .exp();
+    let brightest = rgb.pixels().map(luminance).fold(0.0f32, f32::max);
+
+    let map: Box<dyn Fn(f32) -> f32> = match operator {
+        ToneMap::Reinhard => {
+            let white = (KEY / average * brightest).max(1e-6);
+            Box::new(move |l: f32| {
+                let scaled = KEY / average * l;
+                scaled * (1.0 + scaled / (white * white)) / (1.0 + scaled)
+            })
+        }
+        ToneMap::Drago => {
+            let brightest = (brightest / average).max(1e-6);
+            let exponent = DRAGO_BIAS.ln() / 0.5f32.ln();
+            Box::new(move |l: f32| {
+                let world = l / average;
+                let denominator = (2.0 + 8.0 * (world / brightest).powf(exponent)).ln();
+                (world + 1.0).ln() / denominator / (brightest + 1.0).log10()
+            })
+        }
+    };
+
+    RgbImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let pixel = rgb.get_pixel(x, y);
+        let l = luminance(pixel);
+        let scale = match l > 0.0 {
+            true => map(l) / l,
+            false => 0.0,
+        };
+        Rgb(pixel.0.map(|value| to_u8(linear_to_srgb(value * scale))))
+    })
+}
+
+/// Merge aligned exposures of the same scene with Mertens exposure fusion
+///
+/// Each pixel is weighted by how well exposed, saturated and detailed it is
+/// in each exposure. The exposures are blended level by level of Laplacian
+/// pyramids, so the weights change smoothly and no seams show.
+pub fn fuse(images: &[(String, DynamicImage)]) -> Result<RgbImage, ImgtoolsError> {
+    if images.is_empty() {
+        return Err(ImgtoolsError::InvalidArgument(
+            "No exposures to fuse".into(),
+        ));
+    }
+    let (w, h) = same_size(images, "Exposures")?;
+
+    let exposures: Vec<[Plane; 3]> = images
+        .iter()
+        .map(|(_, img)| {
+            let rgb = img.to_rgb32f();
+            std::array::from_fn(|c| Plane {
+                width: w,
+                height: h,
+                data: rgb.pixels().map(|p| p[c]).collect(),
+            })
+        })
+        .collect();
+    let mut weights: Vec<Plane> = exposures.iter().map(weight).collect();
+    // Weights add up to one at every pixel
+    for i in 0..(w * h) as usize {
+        let total: f32 = weights.iter().map(|plane| plane.data[i]).sum();
+        for plane in weights.iter_mut() {
+            plane.data[i] /= total;
+        }
+    }
+
+    let levels = levels(w, h);
+    let mut fused: [Vec<Plane>; 3] = Default::default();
+    for (exposure, weight) in exposures.iter().zip(&weights) {
+        let weight = gaussian_pyramid(weight, levels);
+        for (c, channel) in exposure.iter().enumerate() {
+            let laplacian = laplacian_pyramid(channel, levels);
+            if fused[c].is_empty() {
+                fused[c] = laplacian.iter().map(|level| level.scaled(0.0)).collect();
+            }
+            for ((out, level), weight) in fused[c].iter_mut().zip(&laplacian).zip(&weight) {
+                for ((out, value), weight) in out.data.iter_mut().zip(&level.data).zip(&weight.data)
+                {
+                    *out += value * weight;
+                }
+            }
+        }
+    }
+    let [r, g, b] = fused.map(|pyramid| collapse(&pyramid));
+    Ok(RgbImage::from_fn(w, h, |x, y| {
+        let i = (y * w + x) as usize;
+        Rgb([r.data[i], g.data[i], b.data[i]].map(to_u8))
+    }))
+}
+
+/// Contrast times saturation times well-exposedness of every pixel
+fn weight(rgb: &[Plane; 3]) -> Plane {
+    let (w, h) = (rgb[0].width, rgb[0].height);
+    let gray: Vec<f32> = (0..(w * h) as usize)
+        .map(|i| (rgb[0].data[i] + rgb[1].data[i] + rgb[2].data[i]) / 3.0)
+        .collect();
+    let at = |x: i64, y: i64| {
+        let (x, y) = (x.clamp(0, w as i64 - 1), y.clamp(0, h as i64 - 1));
+        gray[(y * w as i64 + x) as usize]
+    };
+    let mut data = Vec::with_capacity(gray.len());
+    for y in 0..h as i64 {
+        for x in 0..w as i64 {
+            let i = (y * w as i64 + x) as usize;
+            let contrast =
+                (at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y)).abs();
+            let channels = [rgb[0].data[i], rgb[1].data[i], rgb[2].data[i]];
+            let mean = gray[i];
+            let saturation =
+                (channels.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / 3.0).sqrt();
+            let exposedness: f32 = channels
+                .iter()
+                .map(|v| (-(v - 0.5).powi(2) / (2.0 * WELL_EXPOSED * WELL_EXPOSED)).exp())
+                .product();
+            // The small floor keeps flat, gray areas from having no weight at all
+            data.push(contrast * saturation * exposedness + 1e-12);
+        }
+    }
+    Plane {
+        width: w,
+        height: h,
+        data,
+    }
+}
+
+/// Number of pyramid levels, halving until a side reaches `SMALLEST_LEVEL`
+pub(crate) fn levels(width: u32, height: u32) -> usize {
+    let mut side = width.min(height);
+    let mut levels = 1;
+    while side / 2 >= SMALLEST_LEVEL {
+        side = side.div_ceil(2);
+        levels += 1;
+    }
+    levels
+}
+
+/// One channel of an image
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Plane {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) data: Vec<f32>,
+}
+
+/// Binomial approximation of a Gaussian used to shrink and grow levels
+const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+impl Plane {
+    fn get(&self, x: i64, y: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1);
+        let y = y.clamp(0, self.height as i64 - 1);
+        self.data[(y * self.width as i64 + x) as usize]
+    }
+
+    pub(crate) fn scaled(&self, factor: f32) -> Plane {
+        Plane {
+            data: self.data.iter().map(|v| v * factor).collect(),
+            ..*self
+        }
+    }
+
+    /// Blur and keep every other pixel
+    pub(crate) fn reduce(&self) -> Plane {
+        let (w, h) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut data = Vec::with_capacity((w * h) as usize);
+        for y in 0..h as i64 {
+            for x in 0..w as i64 {
+                let mut sum = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        sum += kx * ky * self.get(2 * x + i as i64 - 2, 2 * y + j as i64 - 2);
+                    }
+                }
+                data.push(sum);
+            }
+        }
+        Plane {
+            width: w,
+            height: h,
+            data,
+        }
+    }
+
+    /// Grow to `width` x `height`, the inverse of reduce for smooth planes
+    pub(crate) fn expand(&self, width: u32, height: u32) -> Plane {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                // Only the taps landing on a pixel of the smaller plane count, twice
+                let mut sum = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    let sy = y + j as i64 - 2;
+                    if sy % 2 != 0 {
+                        continue;
+                    }
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let sx = x + i as i64 - 2;
+                        if sx % 2 == 0 {
+                            sum += 4.0 * kx * ky * self.get(sx / 2, sy / 2);
+                        }
+                    }
+                }
+                data.push(sum);
+            }
+        }
+        Plane {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+pub(crate) fn gaussian_pyramid(plane: &Plane, levels: usize) -> Vec<Plane> {
+    let mut pyramid = vec![plane.clone()];
+    while pyramid.len() < levels {
+        let next = pyramid[pyramid.len() - 1].reduce();
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+/// Detail lost from each level to the next, and the smallest level itself
+pub(crate) fn laplacian_pyramid(plane: &Plane, levels: usize) -> Vec<Plane> {
+    let gaussian = gaussian_pyramid(plane, levels);
+    let mut pyramid: Vec<Plane> = gaussian
+        .windows(2)
+        .map(|pair| {
+            let grown = pair[1].expand(pair[0].width, pair[0].height);
+            Plane {
+                data: pair[0]
+                    .data
+                    .iter()
+                    .zip(&grown.data)
+                    .map(|(a, b)| a - b)
+                    .collect(),
+                ..pair[0]
+            }
+        })
+        .collect();
+    pyramid.push(gaussian[gaussian.len() - 1].clone());
+    pyramid
+}
+
+/// Add the levels of a Laplacian pyramid back up into an image
+pub(crate) fn collapse(pyramid: &[Plane]) -> Plane {
+    let mut image = pyramid[pyramid.len() - 1].clone();
+    for level in pyramid.iter().rev().skip(1) {
+        let grown = image.expand(level.width, level.height);
+        image = Plane {
+            data: level
+                .data
+                .iter()
+                .zip(&grown.data)
+                .map(|(a, b)| a + b)
+                .collect(),
+            ..*level
+        };
+    }
+    image
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    match value <= 0.04045 {
+        true => value / 12.92,
+        false => ((value + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    match value <= 0.0031308 {
+        true => value * 12.92,
+        false => 1.055 * value.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb32FImage, Rgba};
+
+    #[test]
+    fn test_tone_map() {
+        // Linear light from deep shadow to ten times paper white
+        // The second row is orange
+        let hdr = Rgb32FImage::from_fn(100, 2, |x, y| {
+            let l = 0.001 * 10_000f32.powf(x as f32 / 99.0);
+            match y {
+                0 => Rgb([l; 3]),
+                _ => Rgb([l, l * 0.6, l * 0.2]),
+            }
+        });
+        let hdr = DynamicImage::ImageRgb32F(hdr);
+        for operator in [ToneMap::Reinhard, ToneMap::Drago] {
+            let img = tone_map(&hdr, operator, 0.0);
+            let reds: Vec<u8> = (0..100).map(|x| img.get_pixel(x, 0)[0]).collect();
+            assert!(
+                reds.windows(2).all(|pair| pair[0] <= pair[1]),
+                "{:?}",
+                operator
+            );
+            assert!(reds[0] < 20 && reds[99] > 200, "{:?}", operator);
+            // Highlights still have detail, nothing is clipped before the end
+            assert!(reds[90] < reds[99], "{:?}", operator);
+            // The hue is kept
+            let p = img.get_pixel(50, 1);
+            assert!(p[0] > p[1] && p[1] > p[2], "{:?}", operator);
+        }
+        // More exposure is brighter
+        let darker = tone_map(&hdr, ToneMap::Drago, -2.0);
+        let brighter = tone_map(&hdr, ToneMap::Drago, 0.0);
+        assert!(darker.get_pixel(50, 0)[0] < brighter.get_pixel(50, 0)[0]);
+    }
+
+    #[test]
+    fn test_pyramid_round_trip() {
+        let plane = Plane {
+            width: 37,
+            height: 20,
+            data: (0..740)
+                .map(|i| ((i * 7919) % 101) as f32 / 100.0)
+                .collect(),
+        };
+        let levels = levels(37, 20);
+        assert_eq!(levels, 2);
+        let restored = collapse(&laplacian_pyramid(&plane, levels));
+        for (a, b) in plane.data.iter().zip(&restored.data) {
+            assert!((a - b).abs() < 1e-4);
+        }
+        assert_eq!(super::levels(1024, 768), 7);
+    }
+
+    #[test]
+    fn test_fuse() {
+        // The left half is well exposed in the first image, the right half in the second
+        let texture = |x: u32, y: u32| ((x * 3 + y * 5) % 7) as u8 * 8;
+        let exposure = |bright_left: bool| {
+            let img = image::RgbaImage::from_fn(64, 32, |x, y| {
+                let level = match (x < 32) == bright_left {
+                    true => 100 + texture(x, y),
+                    false => match bright_left {
+                        true => 255,
+                        false => 0,
+                    },
+                };
+                Rgba([level, level, level / 2 + 20, 255])
+            });
+            DynamicImage::ImageRgba8(img)
+        };
+        let images = vec![
+            ("a".to_string(), exposure(true)),
+            ("b".to_string(), exposure(false)),
+        ];
+        let fused = fuse(&images).unwrap();
+        assert_eq!(fused.dimensions(), (64, 32));
+        for x in [4, 60] {
+            let level = fused.get_pixel(x, 16)[0];
+            assert!((80..=180).contains(&level), "{} at {}", level, x);
+        }
+
+        let small = DynamicImage::new_rgb8(10, 10);
+        let error = fuse(&[images[0].clone(), ("c".into(), small)]).unwrap_err();
+        assert!(error.to_string().contains("c is 10x10"));
+    }
+}
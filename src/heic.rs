@@ -0,0 +1,396 @@
+use crate::{DecodeOptions, ImgtoolsError};
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageBuffer, ImageError};
+use std::ffi::{CStr, c_char, c_int, c_void};
+use std::ptr;
+use std::sync::OnceLock;
+
+/// Names libheif is looked up by, the versioned one first since the plain
+/// name only comes with its development files
+const LIBRARY_NAMES: [&CStr; 4] = [
+    c"libheif.so.1",
+    c"libheif.so",
+    c"libheif.1.dylib",
+    c"libheif.dylib",
+];
+
+// Values of libheif's enums
+const COLORSPACE_RGB: c_int = 1;
+const CHROMA_INTERLEAVED_RGB: c_int = 10;
+const CHROMA_INTERLEAVED_RGBA: c_int = 11;
+const CHROMA_INTERLEAVED_RRGGBB_LE: c_int = 14;
+const CHROMA_INTERLEAVED_RRGGBBAA_LE: c_int = 15;
+const CHANNEL_INTERLEAVED: c_int = 10;
+
+/// Result of most libheif calls, a code of 0 is success
+#[repr(C)]
+struct HeifError {
+    code: c_int,
+    subcode: c_int,
+    message: *const c_char,
+}
+
+impl HeifError {
+    fn check(self) -> Result<(), ImgtoolsError> {
+        if self.code == 0 {
+            return Ok(());
+        }
+        let message = match self.message.is_null() {
+            true => format!("libheif error {}.{}", self.code, self.subcode),
+            // SAFETY: libheif messages are static NUL-terminated strings
+            false => unsafe { CStr::from_ptr(self.message) }
+                .to_string_lossy()
+                .into_owned(),
+        };
+        Err(invalid(message))
+    }
+}
+
+type Context = c_void;
+type Handle = c_void;
+type Image = c_void;
+
+/// The functions of libheif used for decoding
+struct Library {
+    context_alloc: unsafe extern "C" fn() -> *mut Context,
+    context_free: unsafe extern "C" fn(*mut Context),
+    read_from_memory:
+        unsafe extern "C" fn(*mut Context, *const c_void, usize, *const c_void) -> HeifError,
+    primary_image_handle: unsafe extern "C" fn(*mut Context, *mut *mut Handle) -> HeifError,
+    handle_release: unsafe extern "C" fn(*mut Handle),
+    handle_width: unsafe extern "C" fn(*const Handle) -> c_int,
+    handle_height: unsafe extern "C" fn(*const Handle) -> c_int,
+    has_alpha: unsafe extern "C" fn(*const Handle) -> c_int,
+    luma_bits: unsafe extern "C" fn(*const Handle) -> c_int,
+    profile_size: unsafe extern "C" fn(*const Handle) -> usize,
+    profile: unsafe extern "C" fn(*const Handle, *mut c_void) -> HeifError,
+    decode_image: unsafe extern "C" fn(
+        *const Handle,
+        *mut *mut Image,
+        c_int,
+        c_int,
+        *const c_void,
+    ) -> HeifError,
+    image_release: unsafe extern "C" fn(*mut Image),
+    image_width: unsafe extern "C" fn(*const Image, c_int) -> c_int,
+    image_height: unsafe extern "C" fn(*const Image, c_int) -> c_int,
+    plane: unsafe extern "C" fn(*const Image, c_int, *mut c_int) -> *const u8,
+}
+
+// SAFETY: the library handle and function pointers are never freed, libheif
+// contexts are not shared between threads
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    /// Load libheif on first use, it is not linked so imgtools runs without it
+    fn get() -> Result<&'static Library, ImgtoolsError> {
+        static LIBRARY: OnceLock<Result<Library, String>> = OnceLock::new();
+        LIBRARY
+            .get_or_init(Library::open)
+            .as_ref()
+            .map_err(|message| ImgtoolsError::InvalidArgument(message.clone()))
+    }
+
+    fn open() -> Result<Library, String> {
+        let handle = LIBRARY_NAMES
+            .iter()
+            // SAFETY: loads a shared library by name, checked for failure
+            .map(|name| unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) })
+            .find(|handle| !handle.is_null())
+            .ok_or("Decoding HEIC/HEIF images needs libheif, which was not found")?;
+        // SAFETY: every symbol is cast to the signature libheif declares for it
+        unsafe {
+            Ok(Library {
+                context_alloc: symbol(handle, c"heif_context_alloc")?,
+                context_free: symbol(handle, c"heif_context_free")?,
+                read_from_memory: symbol(handle, c"heif_context_read_from_memory_without_copy")?,
+                primary_image_handle: symbol(handle, c"heif_context_get_primary_image_handle")?,
+                handle_release: symbol(handle, c"heif_image_handle_release")?,
+                handle_width: symbol(handle, c"heif_image_handle_get_width")?,
+                handle_height: symbol(handle, c"heif_image_handle_get_height")?,
+                has_alpha: symbol(handle, c"heif_image_handle_has_alpha_channel")?,
+                luma_bits: symbol(handle, c"heif_image_handle_get_luma_bits_per_pixel")?,
+                profile_size: symbol(handle, c"heif_image_handle_get_raw_color_profile_size")?,
+                profile: symbol(handle, c"heif_image_handle_get_raw_color_profile")?,
+                decode_image: symbol(handle, c"heif_decode_image")?,
+                image_release: symbol(handle, c"heif_image_release")?,
+                image_width: symbol(handle, c"heif_image_get_width")?,
+                image_height: symbol(handle, c"heif_image_get_height")?,
+                plane: symbol(handle, c"heif_image_get_plane_readonly")?,
+            })
+        }
+    }
+
+    /// Read HEIF data and run `f` on the handle of its primary image
+    fn with_primary<T>(
+        &self,
+        data: &[u8],
+        f: impl FnOnce(*const Handle) -> Result<T, ImgtoolsError>,
+    ) -> Result<T, ImgtoolsError> {
+        // SAFETY: the context is freed once, after the handle, and `data`
+        // outlives both since it is borrowed for the whole call
+        unsafe {
+            let context = (self.context_alloc)();
+            if context.is_null() {
+                return Err(invalid("libheif failed to allocate a context".into()));
+            }
+            let _context = Release(context, self.context_free);
+            (self.read_from_memory)(context, data.as_ptr().cast(), data.len(), ptr::null())
+                .check()?;
+            let mut handle = ptr::null_mut();
+            (self.primary_image_handle)(context, &mut handle).check()?;
+            let _handle = Release(handle, self.handle_release);
+            f(handle)
+        }
+    }
+}
+
+/// Look up a function of a loaded library
+///
+/// # Safety
+///
+/// `F` must be a function pointer type matching the symbol's signature.
+unsafe fn symbol<F>(library: *mut c_void, name: &CStr) -> Result<F, String> {
+    // SAFETY: looks up a symbol of an open library, checked for failure
+    let address = unsafe { libc::dlsym(library, name.as_ptr()) };
+    match address.is_null() {
+        true => Err(format!("libheif has no {}", name.to_string_lossy())),
+        // SAFETY: the caller guarantees `F` is the function's pointer type
+        false => Ok(unsafe { std::mem::transmute_copy(&address) }),
+    }
+}
+
+/// Releases a libheif object when dropped
+struct Release<T>(*mut T, unsafe extern "C" fn(*mut T));
+
+impl<T> Drop for Release<T> {
+    fn drop(&mut self) {
+        // SAFETY: the object was returned by libheif and is released once
+        unsafe { (self.1)(self.0) }
+    }
+}
+
+/// Decode the primary image of a HEIC/HEIF file, with its rotation and
+/// mirroring applied
+///
+/// Images with more than 8 bits per sample are decoded to 16 bits.
+pub fn decode(data: &[u8], options: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    let library = Library::get()?;
+    library.with_primary(data, |handle| {
+        // SAFETY: `handle` is a live image handle, the decoded image is
+        // released once and its plane read within its stride and size
+        unsafe {
+            let (width, height) = (
+                (library.handle_width)(handle),
+                (library.handle_height)(handle),
+            );
+            let alpha = (library.has_alpha)(handle) != 0;
+            let deep = (library.luma_bits)(handle) > 8;
+            let channels = if alpha { 4 } else { 3 };
+            let sample = if deep { 2 } else { 1 };
+            let pixels = width.max(0) as u64 * height.max(0) as u64;
+            options.check_alloc(pixels * channels * sample)?;
+
+            let chroma = match (alpha, deep) {
+                (false, false) => CHROMA_INTERLEAVED_RGB,
+                (true, false) => CHROMA_INTERLEAVED_RGBA,
+                (false, true) => CHROMA_INTERLEAVED_RRGGBB_LE,
+                (true, true) => CHROMA_INTERLEAVED_RRGGBBAA_LE,
+            };
+            let mut image = ptr::null_mut();
+            (library.decode_image)(handle, &mut image, COLORSPACE_RGB, chroma, ptr::null())
+                .check()?;
+            let _image = Release(image, library.image_release);
+            let width = (library.image_width)(image, CHANNEL_INTERLEAVED).max(0) as usize;
+            let height = (library.image_height)(image, CHANNEL_INTERLEAVED).max(0) as usize;
+            let mut stride = 0;
+            let plane = (library.plane)(image, CHANNEL_INTERLEAVED, &mut stride);
+            if plane.is_null() || (stride as usize) < width * (channels * sample) as usize {
+                return Err(invalid("libheif returned no pixels".into()));
+            }
+            let row_bytes = width * (channels * sample) as usize;
+            let rows = (0..height)
+                .map(|y| std::slice::from_raw_parts(plane.add(y * stride as usize), row_bytes));
+
+            let (width, height) = (width as u32, height as u32);
+            let img = match deep {
+                false => {
+                    let pixels = rows.flatten().copied().collect();
+                    match alpha {
+                        false => ImageBuffer::from_raw(width, height, pixels)
+                            .map(DynamicImage::ImageRgb8),
+                        true => ImageBuffer::from_raw(width, height, pixels)
+                            .map(DynamicImage::ImageRgba8),
+                    }
+                }
+                true => {
+                    // Samples keep their bit depth, scale them to 16 bits
+                    let max = ((1u32 << (library.luma_bits)(handle).clamp(9, 16)) - 1) as u64;
+                    let pixels = rows
+                        .flat_map(|row| row.chunks_exact(2))
+                        .map(|s| {
+                            let value = u16::from_le_bytes([s[0], s[1]]) as u64;
+                            ((value.min(max) * 65535 + max / 2) / max) as u16
+                        })
+                        .collect();
+                    match alpha {
+                        false => ImageBuffer::from_raw(width, height, pixels)
+                            .map(DynamicImage::ImageRgb16),
+                        true => ImageBuffer::from_raw(width, height, pixels)
+                            .map(DynamicImage::ImageRgba16),
+                    }
+                }
+            };
+            img.ok_or_else(|| invalid("the image has no pixels".into()))
+        }
+    })
+}
+
+/// The ICC profile of the primary image, the decoded pixels are in its color space
+pub fn icc(data: &[u8]) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    let library = Library::get()?;
+    library.with_primary(data, |handle| {
+        // SAFETY: the profile is copied into a buffer of the size libheif gives
+        unsafe {
+            let size = (library.profile_size)(handle);
+            if size == 0 {
+                return Ok(None);
+            }
+            let mut profile = vec![0; size];
+            (library.profile)(handle, profile.as_mut_ptr().cast()).check()?;
+            Ok(Some(profile))
+        }
+    })
+}
+
+/// Error for HEIF data that can't be read
+fn invalid(message: String) -> ImgtoolsError {
+    ImgtoolsError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("HEIF".into()),
+        message,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage};
+
+    /// Encode an image as HEIC with libheif's HEVC encoder
+    fn encode(img: &RgbImage) -> Vec<u8> {
+        type Encoder = c_void;
+        let path = std::env::temp_dir().join(format!("imgtools-heic-{}.heic", img.width()));
+        let name = std::ffi::CString::new(path.to_string_lossy().as_bytes()).unwrap();
+        unsafe {
+            let library = LIBRARY_NAMES
+                .iter()
+                .map(|name| libc::dlopen(name.as_ptr(), libc::RTLD_NOW))
+                .find(|handle| !handle.is_null())
+                .unwrap();
+            let get_encoder: unsafe extern "C" fn(
+                *mut Context,
+                c_int,
+                *mut *mut Encoder,
+            ) -> HeifError = symbol(library, c"heif_context_get_encoder_for_format").unwrap();
+            let set_quality: unsafe extern "C" fn(*mut Encoder, c_int) -> HeifError =
+                symbol(library, c"heif_encoder_set_lossy_quality").unwrap();
+            let encoder_release: unsafe extern "C" fn(*mut Encoder) =
+                symbol(library, c"heif_encoder_release").unwrap();
+            let image_create: unsafe extern "C" fn(
+                c_int,
+                c_int,
+                c_int,
+                c_int,
+                *mut *mut Image,
+            ) -> HeifError = symbol(library, c"heif_image_create").unwrap();
+            let add_plane: unsafe extern "C" fn(
+                *mut Image,
+                c_int,
+                c_int,
+                c_int,
+                c_int,
+            ) -> HeifError = symbol(library, c"heif_image_add_plane").unwrap();
+            let plane: unsafe extern "C" fn(*mut Image, c_int, *mut c_int) -> *mut u8 =
+                symbol(library, c"heif_image_get_plane").unwrap();
+            let encode_image: unsafe extern "C" fn(
+                *mut Context,
+                *const Image,
+                *mut Encoder,
+                *const c_void,
+                *mut *mut Handle,
+            ) -> HeifError = symbol(library, c"heif_context_encode_image").unwrap();
+            let write: unsafe extern "C" fn(*mut Context, *const c_char) -> HeifError =
+                symbol(library, c"heif_context_write_to_file").unwrap();
+
+            let heif = Library::get().unwrap();
+            let context = (heif.context_alloc)();
+            let _context = Release(context, heif.context_free);
+            let mut encoder = ptr::null_mut();
+            get_encoder(context, 1, &mut encoder).check().unwrap();
+            let _encoder = Release(encoder, encoder_release);
+            set_quality(encoder, 95).check().unwrap();
+
+            let (width, height) = (img.width() as c_int, img.height() as c_int);
+            let mut image = ptr::null_mut();
+            image_create(
+                width,
+                height,
+                COLORSPACE_RGB,
+                CHROMA_INTERLEAVED_RGB,
+                &mut image,
+            )
+            .check()
+            .unwrap();
+            let _image = Release(image, heif.image_release);
+            add_plane(image, CHANNEL_INTERLEAVED, width, height, 8)
+                .check()
+                .unwrap();
+            let mut stride = 0;
+            let pixels = plane(image, CHANNEL_INTERLEAVED, &mut stride);
+            for (y, row) in img.as_raw().chunks_exact(width as usize * 3).enumerate() {
+                ptr::copy_nonoverlapping(row.as_ptr(), pixels.add(y * stride as usize), row.len());
+            }
+            encode_image(context, image, encoder, ptr::null(), ptr::null_mut())
+                .check()
+                .unwrap();
+            write(context, name.as_ptr()).check().unwrap();
+        }
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_decode() {
+        let img = RgbImage::from_fn(96, 64, |x, y| image::Rgb([x as u8 * 2, y as u8 * 3, 128]));
+        let data = encode(&img);
+        assert!(crate::process::is_heif(&data));
+
+        let decoded = decode(&data, &DecodeOptions::default()).unwrap();
+        assert_eq!(decoded.dimensions(), (96, 64));
+        let decoded = decoded.to_rgb8();
+        let error: u32 = img
+            .iter()
+            .zip(decoded.iter())
+            .map(|(a, b)| a.abs_diff(*b) as u32)
+            .sum();
+        assert!(
+            error / (96 * 64 * 3) <= 3,
+            "mean error {}",
+            error / (96 * 64 * 3)
+        );
+        assert_eq!(icc(&data).unwrap(), None);
+
+        // Too large for the limit before anything is decoded
+        let options = DecodeOptions {
+            max_alloc: Some(1000),
+            ..DecodeOptions::default()
+        };
+        assert!(matches!(
+            decode(&data, &options),
+            Err(ImgtoolsError::Decode(ImageError::Limits(_)))
+        ));
+        assert!(decode(&data[..data.len() / 2], &DecodeOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use crate::{ImgtoolsError, Metadata};
+use image::error::{
+    EncodingError, ImageFormatHint, LimitError, LimitErrorKind, UnsupportedError,
+    UnsupportedErrorKind,
+};
+use image::{DynamicImage, ExtendedColorType, ImageError, ImageFormat};
+use std::f32::consts::PI;
+use std::io::Write;
+
+/// Quality without a given one, the same as the image crate's encoder
+pub(crate) const DEFAULT_QUALITY: u8 = 75;
+
+/// Luma quantization table of the JPEG standard, table K.1, in natural order
+#[rustfmt::skip]
+const LUMA_QUANTIZATION: [u8; 64] = [
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103,  99,
+];
+
+/// Chroma quantization table, table K.2
+#[rustfmt::skip]
+const CHROMA_QUANTIZATION: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Position in the block of every coefficient in zigzag order
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Huffman tables of the JPEG standard as code counts per length and symbols
+const LUMA_DC: ([u8; 16], &[u8]) = (
+    [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+);
+const CHROMA_DC: ([u8; 16], &[u8]) = (
+    [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+);
+const LUMA_AC: ([u8; 16], &[u8]) = (
+    [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D],
+    &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+        0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52,
+        0xD1, 0xF0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25,
+        0x26, 0x27, 0x28, 0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45,
+        0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64,
+        0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+        0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6,
+        0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3,
+        0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8,
+        0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+);
+const CHROMA_AC: ([u8; 16], &[u8]) = (
+    [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+        0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33,
+        0x52, 0xF0, 0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18,
+        0x19, 0x1A, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44,
+        0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63,
+        0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A,
+        0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+        0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4,
+        0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA,
+        0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7,
+        0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+);
+
+/// Largest ICC profile part in one APP2 segment
+const ICC_CHUNK: usize = 65_519;
+
+/// Encode the image as a progressive JPEG
+///
+/// A first scan holds the DC coefficients of every component, so a blurred
+/// preview appears early. Luma then gets its low frequencies, the chroma
+/// components follow and the remaining luma frequencies come last. Colors
+/// are not subsampled, as with the image crate's baseline encoder.
+pub(crate) fn encode_progressive<W: Write>(
+    img: &DynamicImage,
+    quality: u8,
+    metadata: &Metadata,
+    mut output: W,
+) -> Result<(), ImgtoolsError> {
+    let (width, height) = (img.width(), img.height());
+    if width > u16::MAX as u32 || height > u16::MAX as u32 || width == 0 || height == 0 {
+        return Err(ImgtoolsError::Encode(ImageError::Limits(
+            LimitError::from_kind(LimitErrorKind::DimensionError),
+        )));
+    }
+    let planes = match img {
+        DynamicImage::ImageLuma8(gray) => vec![gray.as_raw().iter().map(|&v| v as f32).collect()],
+        DynamicImage::ImageRgb8(rgb) => ycbcr(rgb.as_raw()),
+        img => {
+            return Err(ImgtoolsError::Encode(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    ImageFormat::Jpeg.into(),
+                    UnsupportedErrorKind::Color(ExtendedColorType::from(img.color())),
+                ),
+            )));
+        }
+    };
+    let tables = [
+        scaled_table(&LUMA_QUANTIZATION, quality),
+        scaled_table(&CHROMA_QUANTIZATION, quality),
+    ];
+    let components: Vec<_> = planes
+        .iter()
+        .enumerate()
+        .map(|(i, plane)| blocks(plane, width as usize, height as usize, &tables[i.min(1)]))
+        .collect();
+
+    let mut data = vec![0xFF, 0xD8];
+    segment(&mut data, 0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    if let Some(exif) = &metadata.exif {
+        segment(&mut data, 0xE1, &[b"Exif\0\0".as_slice(), exif].concat());
+    }
+    if let Some(icc) = &metadata.icc {
+        let count = icc.len().div_ceil(ICC_CHUNK);
+        if count > 255 {
+            return Err(encode_error("ICC profile too large"));
+        }
+        for (i, chunk) in icc.chunks(ICC_CHUNK).enumerate() {
+            let header = [b"ICC_PROFILE\0".as_slice(), &[i as u8 + 1, count as u8]].concat();
+            segment(&mut data, 0xE2, &[header.as_slice(), chunk].concat());
+        }
+    }
+    for (id, table) in tables.iter().enumerate().take(components.len()) {
+        let mut dqt = vec![id as u8];
+        dqt.extend(ZIGZAG.iter().map(|&i| table[i] as u8));
+        segment(&mut data, 0xDB, &dqt);
+    }
+
+    // Start of frame, progressive with Huffman coding
+    let mut sof = vec![8];
+    sof.extend((height as u16).to_be_bytes());
+    sof.extend((width as u16).to_be_bytes());
+    sof.push(components.len() as u8);
+    for i in 0..components.len() {
+        sof.extend([i as u8 + 1, 0x11, i.min(1) as u8]);
+    }
+    segment(&mut data, 0xC2, &sof);
+    for (class, id, (counts, symbols)) in [
+        (0, 0, LUMA_DC),
+        (1, 0, LUMA_AC),
+        (0, 1, CHROMA_DC),
+        (1, 1, CHROMA_AC),
+    ]
+    .into_iter()
+    .take(if components.len() == 1 { 2 } else { 4 })
+    {
+        segment(
+            &mut data,
+            0xC4,
+            &[&[class << 4 | id], counts.as_slice(), symbols].concat(),
+        );
+    }
+
+    let dc = [huffman(&LUMA_DC), huffman(&CHROMA_DC)];
+    let ac = [huffman(&LUMA_AC), huffman(&CHROMA_AC)];
+    let scans: &[(&[usize], usize, usize)] = match components.len() {
+        1 => &[(&[0], 0, 0), (&[0], 1, 5), (&[0], 6, 63)],
+        _ => &[
+            (&[0, 1, 2], 0, 0),
+            (&[0], 1, 5),
+            (&[1], 1, 63),
+            (&[2], 1, 63),
+            (&[0], 6, 63),
+        ],
+    };
+    for &(scan, start, end) in scans {
+        let mut sos = vec![scan.len() as u8];
+        for &i in scan {
+            sos.extend([i as u8 + 1, (i.min(1) as u8) << 4 | i.min(1) as u8]);
+        }
+        sos.extend([start as u8, end as u8, 0]);
+        segment(&mut data, 0xDA, &sos);
+
+        let mut bits = BitWriter::new(&mut data);
+        if start == 0 {
+            // Blocks of all components take turns, the DC is coded as a difference
+            let mut predictions = vec![0; scan.len()];
+            for (block, _) in components[0].iter().enumerate() {
+                for (&i, prediction) in scan.iter().zip(&mut predictions) {
+                    let value = components[i][block][0];
+                    let (size, extra) = magnitude(value - *prediction);
+                    bits.code(dc[i.min(1)][size as usize]);
+                    bits.write(extra, size);
+                    *prediction = value;
+                }
+            }
+        } else {
+            let i = scan[0];
+            let table = &ac[i.min(1)];
+            for block in &components[i] {
+                let mut run = 0;
+                for &value in &block[start..=end] {
+                    if value == 0 {
+                        run += 1;
+                        continue;
+                    }
+                    while run > 15 {
+                        bits.code(table[0xF0]);
+                        run -= 16;
+                    }
+                    let (size, extra) = magnitude(value);
+                    bits.code(table[(run << 4 | size) as usize]);
+                    bits.write(extra, size);
+                    run = 0;
+                }
+                // An end of band run of one block
+                if run > 0 {
+                    bits.code(table[0x00]);
+                }
+            }
+        }
+        bits.flush();
+    }
+    data.extend([0xFF, 0xD9]);
+    output
+        .write_all(&data)
+        .map_err(|e| ImgtoolsError::Encode(e.into()))
+}
+
+fn encode_error(message: &str) -> ImgtoolsError {
+    ImgtoolsError::Encode(ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Jpeg),
+        message,
+    )))
+}
+
+/// Append a marker segment with its length
+fn segment(data: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    data.extend([0xFF, marker]);
+    data.extend((payload.len() as u16 + 2).to_be_bytes());
+    data.extend(payload);
+}
+
+/// Split RGB samples into Y, Cb and Cr planes as in JFIF
+fn ycbcr(rgb: &[u8]) -> Vec<Vec<f32>> {
+    let mut planes: Vec<_> = (0..3).map(|_| Vec::with_capacity(rgb.len() / 3)).collect();
+    for pixel in rgb.chunks_exact(3) {
+        let [r, g, b] = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+        planes[0].push(0.299 * r + 0.587 * g + 0.114 * b);
+        planes[1].push(-0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0);
+        planes[2].push(0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0);
+    }
+    planes
+}
+
+/// Quantization table scaled to the quality as libjpeg does
+fn scaled_table(table: &[u8; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    table.map(|value| ((value as u32 * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// Quantized coefficients of every 8x8 block in zigzag order, row by row
+///
+/// Blocks past the right and bottom edge repeat the last column and row.
+fn blocks(plane: &[f32], width: usize, height: usize, table: &[u16; 64]) -> Vec<[i32; 64]> {
+    let cosines: [[f32; 8]; 8] = std::array::from_fn(|u| {
+        let scale = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+        std::array::from_fn(|x| scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos())
+    });
+    let mut blocks = Vec::new();
+    for block_y in (0..height).step_by(8) {
+        for block_x in (0..width).step_by(8) {
+            let samples: [[f32; 8]; 8] = std::array::from_fn(|y| {
+                std::array::from_fn(|x| {
+                    let (x, y) = ((block_x + x).min(width - 1), (block_y + y).min(height - 1));
+                    plane[y * width + x] - 128.0
+                })
+            });
+            // Separable DCT, rows first and then columns
+            let rows: [[f32; 8]; 8] = std::array::from_fn(|y| {
+                std::array::from_fn(|u| (0..8).map(|x| cosines[u][x] * samples[y][x]).sum())
+            });
+            let mut block = [0; 64];
+            for (k, &i) in ZIGZAG.iter().enumerate() {
+                let (v, u) = (i / 8, i % 8);
+                let coefficient: f32 = (0..8).map(|y| cosines[v][y] * rows[y][u]).sum();
+                block[k] = (coefficient / table[i] as f32).round() as i32;
+            }
+            blocks.push(block);
+        }
+    }
+    blocks
+}
+
+/// Number of bits of a coefficient and the bits that follow its code
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let extra = match value < 0 {
+        true => (value - 1) as u32 & ((1 << size) - 1),
+        false => value as u32,
+    };
+    (size, extra)
+}
+
+/// Codes and their lengths by symbol, built from the code counts per length
+fn huffman((counts, symbols): &([u8; 16], &[u8])) -> [(u16, u8); 256] {
+    let mut table = [(0, 0); 256];
+    let (mut code, mut symbols) = (0u16, symbols.iter());
+    for (length, &count) in counts.iter().enumerate() {
+        for symbol in symbols.by_ref().take(count as usize) {
+            table[*symbol as usize] = (code, length as u8 + 1);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    table
+}
+
+/// Entropy-coded data, with a zero byte stuffed after every 0xFF
+struct BitWriter<'a> {
+    data: &'a mut Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(data: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            data,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn code(&mut self, (code, length): (u16, u8)) {
+        self.write(code as u32, length);
+    }
+
+    fn write(&mut self, value: u32, length: u8) {
+        for bit in (0..length).rev() {
+            self.buffer = self.buffer << 1 | (value >> bit & 1);
+            self.count += 1;
+            if self.count == 8 {
+                self.byte();
+            }
+        }
+    }
+
+    fn byte(&mut self) {
+        let byte = self.buffer as u8;
+        self.data.push(byte);
+        if byte == 0xFF {
+            self.data.push(0);
+        }
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    /// Pad the last byte with one bits
+    fn flush(&mut self) {
+        if self.count > 0 {
+            let padding = 8 - self.count;
+            self.write((1 << padding) - 1, padding);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, ImageBuffer, Luma, Rgb};
+
+    #[test]
+    fn test_magnitude() {
+        assert_eq!(magnitude(0), (0, 0));
+        assert_eq!(magnitude(5), (3, 0b101));
+        assert_eq!(magnitude(-5), (3, 0b010));
+        assert_eq!(magnitude(-1), (1, 0));
+    }
+
+    #[test]
+    fn test_progressive() {
+        let rgb = DynamicImage::ImageRgb8(ImageBuffer::from_fn(37, 21, |x, y| {
+            Rgb([(x * 6) as u8, (y * 12) as u8, 128])
+        }));
+        let gray = DynamicImage::ImageLuma8(ImageBuffer::from_fn(19, 9, |x, y| {
+            Luma([((x + y) * 9) as u8])
+        }));
+        for img in [rgb, gray] {
+            let mut data = Vec::new();
+            encode_progressive(&img, 90, &Metadata::default(), &mut data).unwrap();
+            assert!(data.windows(2).any(|marker| marker == [0xFF, 0xC2]));
+            let decoded = image::load_from_memory_with_format(&data, ImageFormat::Jpeg).unwrap();
+            assert_eq!(decoded.dimensions(), img.dimensions());
+            assert_eq!(decoded.color(), img.color());
+            // Lossy, but close to the original everywhere
+            let difference = decoded
+                .as_bytes()
+                .iter()
+                .zip(img.as_bytes())
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap();
+            assert!(difference < 16, "{}", difference);
+        }
+    }
+
+    #[test]
+    fn test_progressive_metadata() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::new(8, 8));
+        let metadata = Metadata {
+            icc: Some(vec![7; 70_000]),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        encode_progressive(&img, 75, &metadata, &mut data).unwrap();
+        let icc = crate::read_icc(&data, Some(ImageFormat::Jpeg)).unwrap();
+        assert_eq!(icc, metadata.icc);
+    }
+}
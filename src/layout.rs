@@ -0,0 +1,244 @@
+use crate::fonts::Fonts;
+use crate::{Alignment, Direction};
+use ab_glyph::PxScale;
+use image::imageops::{FilterType, overlay};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Grid of equally sized cells for a montage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    /// Number of columns, 0 picks about as many columns as rows
+    pub columns: u32,
+    /// Cell size, images are shrunk to fit and centered
+    pub cell: (u32, u32),
+    /// Space around and between the cells
+    pub padding: u32,
+    pub background: Rgba<u8>,
+}
+
+/// Text drawn below each image of a montage
+pub struct Captions<'a> {
+    pub fonts: &'a Fonts,
+    pub scale: PxScale,
+    pub color: Rgba<u8>,
+}
+
+/// Lay out the images in a grid, optionally with a caption below each one
+///
+/// Captions too wide for their cell are shortened with an ellipsis.
+pub fn montage(
+    images: &[(String, DynamicImage)],
+    grid: &Grid,
+    captions: Option<&Captions>,
+) -> RgbaImage {
+    let count = images.len().max(1) as u32;
+    let columns = match grid.columns {
+        0 => (count as f64).sqrt().ceil() as u32,
+        columns => columns.min(count),
+    };
+    let rows = count.div_ceil(columns);
+    let (cell_w, cell_h) = grid.cell;
+    let caption_h = captions.map_or(0, |c| c.scale.y.ceil() as u32 + grid.padding / 2);
+    let (step_x, step_y) = (cell_w + grid.padding, cell_h + caption_h + grid.padding);
+
+    let mut canvas = RgbaImage::from_pixel(
+        columns * step_x + grid.padding,
+        rows * step_y + grid.padding,
+        grid.background,
+    );
+    for (i, (name, img)) in images.iter().enumerate() {
+        let (col, row) = (i as u32 % columns, i as u32 / columns);
+        let (x, y) = (grid.padding + col * step_x, grid.padding + row * step_y);
+
+        // Shrink to the cell, never enlarge
+        let fitted = match img.width() <= cell_w && img.height() <= cell_h {
+            true => img.to_rgba8(),
+            false => img
+                .resize(cell_w, cell_h, FilterType::Lanczos3)
+                .into_rgba8(),
+        };
+        let offset_x = x + (cell_w - fitted.width()) / 2;
+        let offset_y = y + (cell_h - fitted.height()) / 2;
+        overlay(&mut canvas, &fitted, offset_x as i64, offset_y as i64);
+
+        if let Some(captions) = captions {
+            let text = fit_text(name, captions, cell_w);
+            let (text_w, _) = captions.fonts.text_size(captions.scale, &text);
+            let text_x = x + cell_w.saturating_sub(text_w) / 2;
+            let text_y = y + cell_h + grid.padding / 2;
+            captions.fonts.draw_text_mut(
+                &mut canvas,
+                captions.color,
+                (text_x as i32, text_y as i32),
+                captions.scale,
+                &text,
+            );
+        }
+    }
+    canvas
+}
+
+/// Join the images in a row or column with `gap` pixels between them
+///
+/// Smaller images are aligned across the direction and the uncovered area is
+/// filled with the background. The result is RGB when neither the images nor
+/// the background are transparent.
+pub fn append(
+    images: &[DynamicImage],
+    direction: Direction,
+    align: Alignment,
+    gap: u32,
+    background: Rgba<u8>,
+) -> DynamicImage {
+    // Length along the direction and breadth across it
+    let extent = |img: &DynamicImage| match direction {
+        Direction::Horizontal => (img.width(), img.height()),
+        Direction::Vertical => (img.height(), img.width()),
+    };
+    let length = images.iter().map(|img| extent(img).0).sum::<u32>()
+        + gap * images.len().saturating_sub(1) as u32;
+    let breadth = images.iter().map(|img| extent(img).1).max().unwrap_or(0);
+
+    let (width, height) = match direction {
+        Direction::Horizontal => (length, breadth),
+        Direction::Vertical => (breadth, length),
+    };
+    let mut canvas = RgbaImage::from_pixel(width.max(1), height.max(1), background);
+    let mut along = 0;
+    for img in images {
+        let (len, size) = extent(img);
+        let across = match align {
+            Alignment::Start => 0,
+            Alignment::Center => (breadth - size) / 2,
+            Alignment::End => breadth - size,
+        };
+        let (x, y) = match direction {
+            Direction::Horizontal => (along, across),
+            Direction::Vertical => (across, along),
+        };
+        overlay(&mut canvas, &img.to_rgba8(), x as i64, y as i64);
+        along += len + gap;
+    }
+
+    match background[3] == 255 && !images.iter().any(|img| img.color().has_alpha()) {
+        true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+        false => DynamicImage::ImageRgba8(canvas),
+    }
+}
+
+/// Shorten the text with an ellipsis until it fits the width
+fn fit_text(text: &str, captions: &Captions, width: u32) -> String {
+    let fits = |s: &str| captions.fonts.text_size(captions.scale, s).0 <= width;
+    if fits(text) {
+        return text.to_string();
+    }
+    let mut chars: Vec<char> = text.chars().collect();
+    while !chars.is_empty() {
+        chars.pop();
+        let shortened = format!("{}…", chars.iter().collect::<String>());
+        if fits(&shortened) {
+            return shortened;
+        }
+    }
+    String::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    fn solid(w: u32, h: u32, color: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(w, h, Rgba(color)))
+    }
+
+    #[test]
+    fn test_montage_grid() {
+        let images: Vec<_> = (0..5)
+            .map(|i| (format!("{}.png", i), solid(20, 10, [i * 50, 0, 0, 255])))
+            .collect();
+        let grid = Grid {
+            columns: 0,
+            cell: (10, 10),
+            padding: 2,
+            background: Rgba([255; 4]),
+        };
+
+        // Five images give three columns and two rows
+        let sheet = montage(&images, &grid, None);
+        assert_eq!(sheet.dimensions(), (3 * 12 + 2, 2 * 12 + 2));
+        assert_eq!(sheet.get_pixel(0, 0), &Rgba([255; 4]));
+        // 20x10 shrinks to 10x5, centered vertically in the cell
+        assert_eq!(sheet.get_pixel(5, 2), &Rgba([255; 4]));
+        assert_eq!(sheet.get_pixel(5, 6), &Rgba([0, 0, 0, 255]));
+        assert_eq!(
+            sheet.get_pixel(2 + 12 + 5, 2 + 12 + 5),
+            &Rgba([200, 0, 0, 255])
+        );
+        // The last cell of the second row stays empty
+        assert_eq!(sheet.get_pixel(2 + 24 + 5, 2 + 12 + 5), &Rgba([255; 4]));
+
+        let columns = Grid { columns: 5, ..grid };
+        assert_eq!(montage(&images, &columns, None).width(), 5 * 12 + 2);
+    }
+
+    #[test]
+    fn test_append() {
+        let images = [
+            DynamicImage::new_rgb8(4, 2),
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(3, 6, image::Rgb([9, 9, 9]))),
+        ];
+        let white = Rgba([255; 4]);
+
+        let row = append(&images, Direction::Horizontal, Alignment::End, 1, white);
+        assert_eq!(row.dimensions(), (4 + 1 + 3, 6));
+        assert_eq!(row.color(), image::ColorType::Rgb8);
+        let row = row.to_rgba8();
+        // The short image sits at the bottom, the gap shows the background
+        assert_eq!(row.get_pixel(0, 0), &white);
+        assert_eq!(row.get_pixel(0, 5), &Rgba([0, 0, 0, 255]));
+        assert_eq!(row.get_pixel(4, 5), &white);
+        assert_eq!(row.get_pixel(5, 0), &Rgba([9, 9, 9, 255]));
+
+        let clear = Rgba([0; 4]);
+        let column = append(&images, Direction::Vertical, Alignment::Center, 0, clear);
+        assert_eq!(column.dimensions(), (4, 8));
+        assert!(column.color().has_alpha());
+        let column = column.to_rgba8();
+        // The narrow image is centered with a transparent column to its right
+        assert_eq!(column.get_pixel(0, 2), &Rgba([9, 9, 9, 255]));
+        assert_eq!(column.get_pixel(3, 2), &clear);
+    }
+
+    #[test]
+    fn test_montage_captions() {
+        let fonts = Fonts::load(None).unwrap();
+        let captions = Captions {
+            fonts: &fonts,
+            scale: PxScale::from(12.0),
+            color: Rgba([0, 0, 0, 255]),
+        };
+        let images = vec![(
+            "a-very-long-file-name.png".to_string(),
+            solid(8, 8, [0, 0, 255, 255]),
+        )];
+        let grid = Grid {
+            columns: 1,
+            cell: (40, 8),
+            padding: 4,
+            background: Rgba([255; 4]),
+        };
+
+        let sheet = montage(&images, &grid, Some(&captions));
+        assert_eq!(sheet.height(), 8 + 12 + 2 + 2 * 4);
+        let caption = (4 + 8..sheet.height()).flat_map(|y| (0..sheet.width()).map(move |x| (x, y)));
+        assert!(
+            caption
+                .into_iter()
+                .any(|(x, y)| sheet.get_pixel(x, y)[0] < 128)
+        );
+
+        let text = fit_text("a-very-long-file-name.png", &captions, 40);
+        assert!(text.ends_with('…') && fonts.text_size(captions.scale, &text).0 <= 40);
+    }
+}
@@ -1,24 +1,264 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use image::imageops::FilterType;
-use std::path::PathBuf;
+use image::{DynamicImage, ImageFormat, Rgba};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::OnceLock;
+
+mod adjust;
+mod analysis;
+mod animation;
+mod annotate;
+mod barcode;
+mod bench;
+mod caption;
+mod carve;
+mod channels;
+mod colormap;
+mod colors;
+mod composite;
+mod config;
+mod create;
+mod deskew;
+mod draw;
+mod effects;
+mod encoding;
+mod error;
+#[cfg(feature = "faces")]
+mod faces;
+#[cfg(feature = "fetch")]
+mod fetch;
+mod filters;
+mod fonts;
+mod geometry;
+mod gradient;
+mod hashing;
+mod hdr;
+#[cfg(feature = "heic")]
+mod heic;
+mod jpeg;
+mod layout;
+mod logging;
+mod mapped;
+mod metadata;
+#[cfg(feature = "onnx")]
+mod onnx;
+mod optimize;
+mod placeholder;
+mod process;
+mod profile;
+mod pyramid;
+mod quantize;
+#[cfg(feature = "raw")]
+mod raw;
+mod recipe;
+mod resample;
+#[cfg(feature = "serve")]
+mod serve;
+mod shape;
+mod srcset;
+mod stack;
+#[cfg(feature = "stitch")]
+mod stitch;
+mod stream;
+mod strip;
+#[cfg(feature = "svg")]
+mod svg;
+mod tags;
+mod upscale;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod watch;
+#[cfg(any(feature = "svg", feature = "faces"))]
+mod xml;
+
+pub use adjust::{
+    Adjustments, adjust, autolevel, balance_gains, equalize, gray_point_gains, shadows_highlights,
+    temperature_gains,
+};
+pub use analysis::{Assessment, ChannelStats, Comparison, Histogram, Statistics, diff_heatmap};
+pub use animation::Animation;
+pub use annotate::annotate;
+pub use barcode::{CodeStyle, EcLevel, Modules, Symbology, encode_code, overlay_logo, render_code};
+pub use bench::Benchmark;
+pub use caption::{Caption, render_caption};
+pub use carve::liquid_resize;
+pub use channels::{
+    apply_mask, channel_names, chromakey, extract_alpha, flatten, merge, premultiply, split,
+};
+pub use colormap::tint;
+pub use composite::{Tiling, blend, composite, placements};
+pub use config::{Config, Preset};
+pub use create::create;
+pub use deskew::{horizon_angle, level, skew_angle, straighten};
+pub use draw::{border, draw_rounded_rect_mut, round};
+pub use effects::{posterize, redact, solarize, threshold, vignette};
+pub use encoding::{EncodeOptions, Interlace};
+pub use error::{ImgtoolsError, error_report};
+#[cfg(feature = "faces")]
+pub use faces::detect_faces;
+pub use filters::{box_blur, convolve, denoise, edges, emboss, noise};
+pub use fonts::Fonts;
+pub use geometry::{
+    Tiles, fit_dimensions, icon, pad, resize_dimensions, shear, slice, thumbnail, trim_bounds,
+};
+pub use gradient::{Gradient, GradientShape};
+pub use hashing::{ImageHash, hash_report};
+pub use hdr::{fuse, tone_map};
+pub use layout::{Captions, Grid, append, montage};
+pub use logging::{Level, LogFormat, init_logging, log, log_file};
+pub use metadata::{
+    ExifField, ImageInfo, Metadata, exif_report, insert_xmp, read_exif, read_icc, read_orientation,
+};
+#[cfg(feature = "onnx")]
+pub use onnx::{Inference, infer, remove_background, upscale_with_model};
+pub use optimize::optimize;
+pub use placeholder::{Lqip, blurhash, decode_blurhash, decode_thumbhash, lqip, thumbhash};
+pub use process::{
+    DEFAULT_DENSITY, DecodeOptions, INPUTLESS_COMMANDS, Plan, ProcessOptions, Processed, STDIO,
+    WhiteBalance, Written, apply_command, combine_files, combine_images, create_file, encode,
+    encode_with_metadata, encode_with_options, is_stdio, is_url, open_image, output_format, plan,
+    process_bytes, process_file, report_file,
+};
+pub use profile::{convert_profile, profile_data};
+pub use pyramid::{dzi_descriptor, pyramid_levels, pyramid_tiles, xyz_descriptor};
+pub use quantize::{Palette, dominant_colors, palette_report, quantize, render_swatches};
+pub use recipe::{load_recipe, parse_recipe};
+pub use resample::fast_resize;
+#[cfg(feature = "serve")]
+pub use serve::serve;
+pub use srcset::{Variant, manifest_html, manifest_json, srcset_height, srcset_widths};
+pub use stack::focus_stack;
+#[cfg(feature = "stitch")]
+pub use stitch::stitch;
+pub use strip::{Keep, TimeFuzz, redact_metadata, strip_metadata};
+pub use tags::{ExifDateTime, ExifTag, set_metadata};
+pub use upscale::upscale;
+pub use watch::Watcher;
 
 /// Image Processing
 #[derive(Parser, Debug)]
 pub struct Cli {
     /// Input image file path
+    ///
+    /// A directory or a file name pattern such as photos/*.jpg processes
+    /// every matching image, the output must then be a directory. Repeat to
+    /// give several inputs, e.g. for montage.
+    /// Use - to read from standard input, and an http:// or https:// URL to
+    /// download the image when built with the fetch feature. Only create,
+    /// qrcode, placeholder --decode and recipes or presets of only one of them
+    /// work without one
     #[arg(long, short = 'i')]
-    pub input: PathBuf,
+    pub input: Vec<PathBuf>,
     /// Output image file path (optional)
+    ///
+    /// Use - to write to standard output, the default when reading from standard input
     #[arg(long, short = 'o')]
     pub output: Option<PathBuf>,
+    /// Input image format, for input whose format can't be guessed
+    #[arg(long)]
+    pub input_format: Option<Format>,
+    /// Resolution SVG input is rasterized at in dots per inch, 96 draws one
+    /// CSS pixel as one pixel
+    #[arg(long, default_value_t = DEFAULT_DENSITY)]
+    pub density: f32,
+    /// Rasterize SVG input to fit in this size instead, e.g. 1024x1024
+    #[arg(long, conflicts_with = "density")]
+    pub render_size: Option<Size>,
+    /// White balance camera RAW input is developed with
+    #[arg(long, value_enum, default_value_t = WhiteBalance::Camera)]
+    pub raw_white_balance: WhiteBalance,
+    /// Stops added to the exposure of camera RAW input, e.g. 1 or -0.5
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub raw_exposure: f32,
+    /// Copy EXIF, XMP and ICC metadata from the input to the output
+    ///
+    /// EXIF and ICC are kept for JPEG, PNG, WebP and TIFF, XMP for JPEG and PNG
+    #[arg(long)]
+    pub keep_metadata: bool,
+    /// Rotate and flip images according to their EXIF orientation before processing
+    #[arg(long)]
+    pub auto_orient: bool,
+    /// Color profile of images without an embedded ICC profile, sRGB by default
+    ///
+    /// srgb, display-p3, adobe-rgb or the path of an .icc file
+    #[arg(long)]
+    pub assume_profile: Option<Profile>,
+    /// Convert colors to this profile and embed it in the output
+    ///
+    /// srgb, display-p3, adobe-rgb or the path of an .icc file
+    #[arg(long)]
+    pub convert_to: Option<Profile>,
+    /// Remove EXIF, GPS, XMP, thumbnails and other ancillary data from the output
+    #[arg(long, conflicts_with = "keep_metadata")]
+    pub strip: bool,
+    /// Metadata --strip leaves in place, e.g. icc,orientation
+    #[arg(long, value_enum, value_delimiter = ',', requires = "strip")]
+    pub keep: Vec<Keep>,
+    /// Write JPEG output as progressive scans that sharpen while loading
+    #[arg(long)]
+    pub progressive: bool,
+    /// Interlace PNG output, so a coarse preview shows before it is fully loaded
+    #[arg(long, value_enum)]
+    pub interlace: Option<Interlace>,
+    /// Show the input, resolved operations, output path and format without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Log how long every stage of each image takes
+    #[arg(long, short = 'v')]
+    pub verbose: bool,
+    /// Write log events as plain text or as JSON lines for other tools
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// Name outputs after a template, written to the output directory
+    ///
+    /// Placeholders: {stem}, {ext}, {width}, {height}, {format}, {counter} and {date},
+    /// e.g. "{stem}_{width}x{height}.{format}"
+    #[arg(long)]
+    pub output_template: Option<String>,
+    /// Replace existing output files, including the input itself
+    #[arg(long, overrides_with = "no_clobber")]
+    pub force: bool,
+    /// Never replace existing output files, the default
+    #[arg(long, overrides_with = "force")]
+    pub no_clobber: bool,
+    /// Edit the input itself when there is no output
+    #[arg(long, conflicts_with = "output")]
+    pub in_place: bool,
+    /// Copy the input to a file with this suffix before editing it in place, e.g. .bak
+    #[arg(long, requires = "in_place")]
+    pub backup: Option<String>,
+    /// Number of images processed in parallel in batch mode, 0 uses one per CPU core
+    #[arg(long, short = 'j', default_value_t = 1)]
+    pub jobs: usize,
+    /// Threads decoding, processing and encoding a single image, 0 uses one per
+    /// CPU core; images of a batch use the --jobs workers
+    #[arg(long, default_value_t = 0)]
+    pub threads: usize,
+    /// Decode images larger than this in strips, e.g. 512MB; only crop, resize,
+    /// convert and watermark of PNG and TIFF work on strips. serve refuses
+    /// images larger than this, 512MB by default
+    #[arg(long)]
+    pub max_memory: Option<ByteSize>,
+    /// Run a named preset from the config file, before the command if one is given
+    #[arg(long)]
+    pub preset: Option<String>,
+    /// Config file with the presets, ~/.config/imgtools/config.toml by default
+    #[arg(long, requires = "preset")]
+    pub config: Option<PathBuf>,
+    /// Write the failed images of a batch with their errors to this JSON file
+    #[arg(long)]
+    pub error_report: Option<PathBuf>,
     /// Subcommand to execute
     #[command(subcommand)]
-    pub command: Command,
+    pub command: Option<Command>,
 }
 
 /// Available image processing commands
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone, PartialEq)]
 pub enum Command {
     /// Convert image format
     Convert {
@@ -26,7 +266,32 @@ pub enum Command {
         #[arg(long, short = 'f')]
         format: Format,
     },
+    /// Recompress PNG, JPEG or WebP to a smaller file
+    ///
+    /// PNG is compressed losslessly with the smallest color type, palette and
+    /// filters unless a quality is given, which reduces it to fewer palette
+    /// colors. JPEG is always recompressed lossily, WebP only losslessly and
+    /// without a quality or target size. The input is kept when nothing smaller
+    /// comes out.
+    Optimize {
+        /// Lossy quality, range (1 ~ 100), 85 for JPEG if not given
+        #[arg(long, short = 'q', value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: Option<u8>,
+        /// Largest output size, e.g. 200KB, the highest quality that fits is searched
+        #[arg(long, short = 's')]
+        target_size: Option<ByteSize>,
+    },
+    /// Remove EXIF, GPS, XMP, thumbnails and comments without re-encoding
+    ///
+    /// JPEG, PNG and WebP keep their encoded pixels, other formats are
+    /// decoded and written again without metadata.
+    Strip {
+        /// Metadata to leave in place, e.g. icc,orientation
+        #[arg(long, short = 'k', value_enum, value_delimiter = ',')]
+        keep: Vec<Keep>,
+    },
     /// Flip image
+    #[command(disable_help_flag = true, arg = help_arg())]
     Flip {
         /// Whether to flip horizontally
         #[arg(long, short = 'h')]
@@ -42,19 +307,128 @@ pub enum Command {
         rotate: Rotate,
     },
     /// Resize image
+    #[command(disable_help_flag = true, arg = help_arg())]
     Resize {
         /// Target width
-        #[arg(long, short = 'w')]
-        width: u32,
+        ///
+        /// If only the width is given, the height follows the aspect ratio
+        #[arg(long, short = 'w', required_unless_present_any = ["height", "scale"])]
+        width: Option<u32>,
         /// Target height
+        ///
+        /// If only the height is given, the width follows the aspect ratio
         #[arg(long, short = 'h')]
-        height: u32,
+        height: Option<u32>,
         /// Whether to force exact size
         #[arg(long, short = 'e')]
         exact: bool,
         /// Scaling filter type
         #[arg(long, short = 'f')]
         filter: Filter,
+        /// Scale factor instead of a target size, as a percentage (50%) or a ratio (0.5)
+        #[arg(long, short = 's', conflicts_with_all = ["width", "height"])]
+        scale: Option<Scale>,
+        /// Resample 8-bit images with SIMD on all cores, several times faster
+        /// on large photos and at most a level or two off
+        #[arg(long)]
+        fast: bool,
+    },
+    /// Shrink the image by removing its least noticeable columns and rows (seam carving)
+    ///
+    /// Winding paths of pixels through flat areas such as sky or walls are
+    /// removed one at a time, so subjects keep their shape where a resize
+    /// would squash them. Only shrinking is supported.
+    #[command(disable_help_flag = true, arg = help_arg())]
+    LiquidResize {
+        /// Target width, at most the image width
+        #[arg(long, short = 'w', required_unless_present = "height")]
+        width: Option<u32>,
+        /// Target height, at most the image height
+        #[arg(long, short = 'h')]
+        height: Option<u32>,
+        /// Mask image whose white areas are kept whole, stretched to the image size
+        #[arg(long, short = 'p')]
+        protect: Option<PathBuf>,
+    },
+    /// Enlarge the image 2, 4 or 8 times, keeping edges sharp
+    ///
+    /// New pixels are filled in along the edges around them rather than across,
+    /// so diagonal lines and curves stay smooth where resizing makes them
+    /// blurry or stepped, e.g. for printing small images.
+    Upscale {
+        /// How many times larger: 2, 4 or 8
+        #[arg(long, short = 'f', default_value_t = 2)]
+        factor: u32,
+        /// ONNX super-resolution model to enlarge with instead, e.g. Real-ESRGAN
+        ///
+        /// Needs imgtools built with the onnx feature. The model takes RGB values
+        /// of 0 to 1 in NCHW layout, results at another scale than --factor are
+        /// resized to it. Transparency is enlarged without the model.
+        #[arg(long, short = 'm')]
+        model: Option<PathBuf>,
+    },
+    /// Run an image-to-image ONNX model, e.g. for style transfer, segmentation or
+    /// background removal
+    ///
+    /// The whole image goes through the model at once. Its red, green and blue
+    /// values are scaled to 0 to --range, less --mean and divided by --std,
+    /// and the model's result is read as values of 0 to --range for images
+    /// and 0 to 1 for masks. Results at another size are resized to the image.
+    /// Only the operators common in such models are supported, run on the CPU.
+    #[cfg(feature = "onnx")]
+    Infer {
+        /// ONNX model file
+        #[arg(long, short = 'm')]
+        model: PathBuf,
+        /// Size the model takes, if it only works at one, e.g. 320x320
+        #[arg(long, short = 's')]
+        size: Option<Size>,
+        /// Largest input value, 1 or 255 for most models
+        #[arg(long, default_value_t = 1.0)]
+        range: f32,
+        /// Subtracted from the red, green and blue values, or one value for all
+        #[arg(long, value_delimiter = ',', default_value = "0")]
+        mean: Vec<f32>,
+        /// What the red, green and blue values are divided by after the mean,
+        /// or one value for all
+        #[arg(long, value_delimiter = ',', default_value = "1")]
+        std: Vec<f32>,
+        /// Pass the channels in blue, green, red order, as models trained with
+        /// OpenCV expect
+        #[arg(long)]
+        bgr: bool,
+        /// Order of the tensor axes: nchw or nhwc
+        #[arg(long, default_value = "nchw")]
+        layout: TensorLayout,
+        /// What the result is: image, mask (a grayscale image) or alpha (a mask
+        /// that cuts out the input)
+        #[arg(long, short = 'r', default_value = "image")]
+        result: ModelOutput,
+        /// Channel of the result masks are read from, e.g. a class of a
+        /// segmentation model
+        #[arg(long, default_value_t = 0)]
+        channel: usize,
+        /// Stretch the result from its lowest to its highest value, for models
+        /// whose results don't cover the whole range
+        #[arg(long)]
+        stretch: bool,
+    },
+    /// Remove the background of a photo, e.g. of a product, keeping the subject
+    ///
+    /// A U2Net-style salient object segmentation model in ONNX format finds the
+    /// subject. It is given with -m, or read from the file IMGTOOLS_BG_MODEL
+    /// names, or from u2net.onnx next to the config file.
+    #[cfg(feature = "onnx")]
+    RemoveBg {
+        /// ONNX model file
+        #[arg(long, short = 'm')]
+        model: Option<PathBuf>,
+        /// Size the model takes, 320x320 for U2Net
+        #[arg(long, short = 's', default_value = "320x320")]
+        size: Size,
+        /// Color put behind the subject, transparent leaves the background out
+        #[arg(long, short = 'b', default_value = "transparent")]
+        background: Color,
     },
     /// Convert to grayscale
     Grayscale,
@@ -90,6 +464,33 @@ pub enum Command {
         /// Crop parameters
         #[arg(long, short = 'c')]
         crop: Crop,
+        /// Move the crop to keep something in view: faces centers the faces
+        /// found in the image, keeping the crop's size
+        ///
+        /// Needs imgtools built with the faces feature. Without faces the crop
+        /// stays where its parameters put it.
+        #[arg(long)]
+        focus: Option<Focus>,
+        /// Shrink a crop that reaches past the image to the part on it,
+        /// instead of failing
+        #[arg(long, conflicts_with = "pad")]
+        clamp: bool,
+        /// Fill the part of a crop past the image with this color, white if
+        /// none is given, instead of failing
+        #[arg(long, num_args = 0..=1, default_missing_value = "white")]
+        pad: Option<Color>,
+    },
+    /// Cut off borders of one color or full transparency, e.g. around scans and screenshots
+    ///
+    /// The border color is the color of the top-left corner unless given.
+    /// The area that is kept is logged as x,y,width,height.
+    Trim {
+        /// Color distance from the border color that still counts as border, range (0.0 ~ 1.0)
+        #[arg(long, short = 'f', default_value_t = 0.0)]
+        fuzz: f32,
+        /// Border color instead of the top-left corner's
+        #[arg(long, short = 'c')]
+        color: Option<Color>,
     },
     /// Invert image colors
     Invert,
@@ -117,7 +518,7 @@ pub enum Command {
         /// - bottom-center: Bottom center
         /// - bottom-right: Bottom right corner
         /// - custom(x,y): Custom coordinate position
-        /// - flat-lay(spacing): Tiled mode (spacing between watermarks)
+        /// - flat-lay(gap) or flat-lay(x,y): Tiled mode (gap between watermarks)
         #[arg(long, short = 'p', default_value = "center")]
         position: Position,
         /// Watermark rotation angle
@@ -130,10 +531,1080 @@ pub enum Command {
         /// Pixel distance from the watermark to the edge, default is 20 pixels
         #[arg(long, short = 'm', default_value_t = 20)]
         margin: u32,
+        /// Watermark width relative to the image width
+        ///
+        /// Resizes the text or image watermark, e.g. 20% or 0.2, up to 100%
+        #[arg(long)]
+        relative_scale: Option<Scale>,
+        /// Shift of every other tiled row, e.g. 50% for a brick pattern
+        ///
+        /// Relative to the distance between tiles, only used with flat-lay
+        #[arg(long)]
+        row_offset: Option<Scale>,
+        /// Extra rotation of every other tiled row in degrees, only used with flat-lay
+        #[arg(long, default_value_t = 0.0)]
+        stagger_angle: f32,
+        /// Share of the image covered by tiles, e.g. 30%
+        ///
+        /// Spreads the tiles evenly and overrides the flat-lay gap
+        #[arg(long)]
+        coverage: Option<Scale>,
         /// Watermark mode
         #[command(subcommand)]
         command: Watermark,
     },
+    /// Draw a text caption, wrapped and aligned over several lines, in an optional box
+    Caption {
+        /// Caption text, \n starts a new line
+        #[arg(long, short = 't')]
+        text: String,
+        /// Font files or installed family names, comma separated, tried in order for each character
+        ///
+        /// The built-in FangSong font is the last resort, e.g. "Noto Sans, Noto Sans CJK SC"
+        #[arg(long, short = 'f')]
+        font: Option<PathBuf>,
+        /// Font size in pixels
+        #[arg(long, short = 's', default_value_t = 32.0)]
+        size: f32,
+        /// Text color or gradient, e.g. gradient(linear,90,gold,orange)
+        #[arg(long, short = 'c', default_value = "white")]
+        color: Fill,
+        /// Alignment of the lines: left, center or right
+        #[arg(long, short = 'a', default_value = "center")]
+        align: Alignment,
+        /// Distance between lines as a multiple of the font's line height
+        #[arg(long, default_value_t = 1.2)]
+        line_spacing: f32,
+        /// Wrap lines at this width in pixels
+        #[arg(long, short = 'w')]
+        max_width: Option<u32>,
+        /// Color of the box behind the text, no box if not given
+        #[arg(long, short = 'b')]
+        background: Option<Color>,
+        /// Space between the text and the edge of the box in pixels
+        #[arg(long, default_value_t = 10)]
+        padding: u32,
+        /// Corner radius of the box in pixels
+        #[arg(long, short = 'r', default_value_t = 0)]
+        radius: u32,
+        /// Caption position, the same options as for watermark
+        #[arg(long, short = 'p', default_value = "bottom-center")]
+        position: Position,
+        /// Distance from the caption to the edge of the image in pixels
+        #[arg(long, short = 'm', default_value_t = 20)]
+        margin: u32,
+    },
+    /// Pixelate or blur areas of the image, e.g. to hide emails or faces in a screenshot
+    Redact {
+        /// Area to obscure as x,y,width,height in pixels, repeatable
+        #[arg(long = "region", required_unless_present = "auto_faces")]
+        regions: Vec<Region>,
+        /// Also obscure every face found in the image
+        ///
+        /// Needs imgtools built with the faces feature. Check the result,
+        /// faces turned away or partly hidden may be missed.
+        #[arg(long)]
+        auto_faces: bool,
+        /// How to obscure the areas: pixelate or blur
+        #[arg(long, short = 'm', default_value = "pixelate")]
+        method: RedactMethod,
+        /// Size of the pixelation blocks, or the blur's standard deviation, in pixels
+        #[arg(long, short = 's', default_value_t = 16.0)]
+        strength: f32,
+        /// Corner radius of the areas in pixels
+        #[arg(long, short = 'r', default_value_t = 0)]
+        radius: u32,
+    },
+    /// Draw lines, arrows, rectangles, circles and text, e.g. to mark up a screenshot
+    Annotate {
+        /// Shape to draw, repeatable, drawn in order:
+        /// line(x1,y1,x2,y2), arrow(x1,y1,x2,y2), rect(x,y,width,height),
+        /// circle(x,y,radius) and text(x,y,"text")
+        ///
+        /// A color and a line width may follow, red and 3 by default. A width of 0
+        /// fills a rectangle or circle. Text takes a color and a font size instead,
+        /// 24 by default, e.g. text(20,20,"Bug here",yellow,32).
+        #[arg(long = "shape", short = 's', required = true)]
+        shapes: Vec<Shape>,
+        /// Font files or installed family names for text, comma separated,
+        /// the same as for caption
+        #[arg(long, short = 'f')]
+        font: Option<PathBuf>,
+    },
+    /// Apply several commands in sequence
+    ///
+    /// Steps are separated by '|' and the image is only decoded and encoded once.
+    /// Each step is either written like a function call, where values fill the
+    /// command's options in order and flags are given by name, or with the same
+    /// arguments as on the command line:
+    /// - resize(800,600,lanczos3) | grayscale
+    /// - blur(2.0,fast) | brighten(value=-20)
+    /// - watermark(bottom-right,0,20,text("Copyright",scale=30))
+    /// - watermark -p center text -t "Copyright"
+    ///
+    /// A convert(format) step selects the output format of the result.
+    Pipeline {
+        /// Pipeline steps
+        steps: Pipeline,
+    },
+    /// Process new and modified images of the input directories until interrupted
+    ///
+    /// Applies a recipe, or the --preset, to every new image. Images already
+    /// there when watching starts are left alone.
+    Watch {
+        /// Recipe file to apply, runs after the preset when both are given
+        recipe: Option<PathBuf>,
+        /// Milliseconds between checks for new files
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+    },
+    /// Answer HTTP requests with transformed images of the input directories
+    ///
+    /// The path names the command and the query its parameters, with src the
+    /// image relative to an input directory, e.g. /resize?w=800&f=lanczos3&src=cat.jpg
+    #[cfg(feature = "serve")]
+    Serve {
+        /// Address and port to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        address: String,
+    },
+    /// Run the steps of a JSON recipe file
+    ///
+    /// The recipe lists steps such as "grayscale" or
+    /// {"resize": {"width": 800, "height": 600, "filter": "lanczos3"}},
+    /// with the long option names of the commands as parameters.
+    Run {
+        /// Recipe file
+        recipe: PathBuf,
+    },
+    /// Create a thumbnail that fits, fills or is padded to the given bounds
+    #[command(disable_help_flag = true, arg = help_arg())]
+    Thumbnail {
+        /// Maximum width
+        #[arg(long, short = 'w')]
+        width: u32,
+        /// Maximum height
+        #[arg(long, short = 'h')]
+        height: u32,
+        /// Fit mode
+        ///
+        /// - fit: Shrink to fit inside the bounds (default)
+        /// - fill: Scale to cover the bounds and crop the overflow
+        /// - pad: Fit inside the bounds and fill the rest with the background color
+        #[arg(long, short = 'm', default_value = "fit")]
+        mode: ThumbnailMode,
+        /// Scaling filter type
+        #[arg(long, short = 'f', default_value = "lanczos3")]
+        filter: Filter,
+        /// Background color of the padded area
+        #[arg(long, short = 'b', default_value = "white")]
+        background: Color,
+    },
+    /// Rotate and flip the image according to its EXIF orientation
+    ///
+    /// The orientation is applied right after decoding, before any other step
+    Autoorient,
+    /// Overlay another image with an opacity and a blend mode
+    Composite {
+        /// Image to overlay
+        #[arg(long)]
+        overlay: PathBuf,
+        /// Overlay position, same options as the watermark position
+        #[arg(long, short = 'p', default_value = "center")]
+        position: Position,
+        /// Pixel distance from the overlay to the edge
+        #[arg(long, short = 'm', default_value_t = 0)]
+        margin: u32,
+        /// Overlay opacity, range (0.0 ~ 1.0)
+        #[arg(long, default_value_t = 1.0)]
+        opacity: f32,
+        /// Blend mode
+        ///
+        /// - normal: Overlay covers the image (default)
+        /// - multiply: Darkens, white leaves the image unchanged
+        /// - screen: Lightens, black leaves the image unchanged
+        /// - overlay: Multiplies dark areas and screens light areas
+        #[arg(long, short = 'b', default_value = "normal")]
+        blend: BlendMode,
+    },
+    /// Adjust exposure, gamma, saturation, color channels and tones
+    Adjust {
+        /// Gamma correction, values above 1.0 brighten the midtones
+        #[arg(long, default_value_t = 1.0)]
+        gamma: f32,
+        /// Exposure in stops, each stop doubles the brightness
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        exposure: f32,
+        /// Saturation multiplier, 0.0 is grayscale
+        #[arg(long, default_value_t = 1.0)]
+        saturation: f32,
+        /// Red channel multiplier
+        #[arg(long, default_value_t = 1.0)]
+        red: f32,
+        /// Green channel multiplier
+        #[arg(long, default_value_t = 1.0)]
+        green: f32,
+        /// Blue channel multiplier
+        #[arg(long, default_value_t = 1.0)]
+        blue: f32,
+        /// Tone curve through input:output points, e.g. curve(0:0,128:150,255:255)
+        #[arg(long)]
+        curve: Option<Curve>,
+    },
+    /// Extend the canvas by margins or to a target size
+    Pad {
+        /// Pixels added above the image, or percent of its height, e.g. 5%
+        #[arg(long, default_value = "0", conflicts_with = "to")]
+        top: Length,
+        /// Pixels added right of the image, or percent of its width
+        #[arg(long, default_value = "0", conflicts_with = "to")]
+        right: Length,
+        /// Pixels added below the image, or percent of its height
+        #[arg(long, default_value = "0", conflicts_with = "to")]
+        bottom: Length,
+        /// Pixels added left of the image, or percent of its width
+        #[arg(long, default_value = "0", conflicts_with = "to")]
+        left: Length,
+        /// Canvas size, e.g. 1920x1080, must not be smaller than the image
+        #[arg(long)]
+        to: Option<Size>,
+        /// Image position on a canvas given by --to, same options as the watermark position
+        #[arg(long, short = 'p', default_value = "center")]
+        position: Position,
+        /// Color or gradient of the added area, rgba(0,0,0,0) or transparent keeps it see-through
+        ///
+        /// A gradient spans the whole canvas, e.g. gradient(radial,white,gray)
+        #[arg(long, short = 'b', default_value = "white")]
+        background: Fill,
+    },
+    /// Shear the image and grow the canvas to fit
+    Shear {
+        /// Horizontal shear angle in degrees, range (-80.0 ~ 80.0)
+        #[arg(long, allow_negative_numbers = true, default_value_t = 0.0)]
+        x_degrees: f32,
+        /// Vertical shear angle in degrees, range (-80.0 ~ 80.0)
+        #[arg(long, allow_negative_numbers = true, default_value_t = 0.0)]
+        y_degrees: f32,
+        /// Color of the exposed areas
+        #[arg(long, short = 'b', default_value = "transparent")]
+        background: Color,
+    },
+    /// Straighten a scanned or photographed page whose lines are tilted
+    ///
+    /// The tilt is found from how sharply the dark pixels line up in rows, so
+    /// lines of text, rulings and table edges work best. The image keeps its
+    /// size and the tilt found is logged.
+    Deskew {
+        /// Largest tilt to look for in degrees, range (0.0 ~ 45.0)
+        #[arg(long, short = 'm', default_value_t = 10.0)]
+        max_angle: f32,
+        /// Color of the corners turned into view
+        #[arg(long, short = 'b', default_value = "white")]
+        background: Color,
+    },
+    /// Level a tilted photo by its horizon or the longest nearly level edge
+    ///
+    /// The photo is turned by the tilt found, which is logged, and cropped to
+    /// the largest rectangle of the same aspect ratio without empty corners.
+    /// Photos without a clear line are left as they are.
+    Straighten {
+        /// Largest tilt to look for in degrees, range (0.0 ~ 45.0)
+        #[arg(long, short = 'm', default_value_t = 5.0)]
+        max_angle: f32,
+    },
+    /// Draw a solid border, optionally with rounded corners
+    Border {
+        /// Border width in pixels
+        #[arg(long, short = 'w', default_value_t = 10)]
+        width: u32,
+        /// Border color
+        #[arg(long, short = 'c', default_value = "black")]
+        color: Color,
+        /// Corner radius in pixels, the corners outside it become transparent
+        #[arg(long, short = 'r', default_value_t = 0)]
+        radius: u32,
+        /// Draw over the edge of the image instead of growing the canvas
+        #[arg(long)]
+        inset: bool,
+    },
+    /// Round the corners or cut the image to an ellipse, leaving transparent edges
+    Round {
+        /// Corner radius in pixels or relative to the shorter side, e.g. 16 or 10%
+        #[arg(long, short = 'r', default_value = "10%", conflicts_with = "circle")]
+        radius: Length,
+        /// Keep only the ellipse inscribed in the image, a circle for square images
+        #[arg(long)]
+        circle: bool,
+    },
+    /// Darken or lighten the image towards the corners
+    Vignette {
+        /// Effect strength in the corners, range (0.0 ~ 1.0)
+        #[arg(long, short = 's', default_value_t = 0.5)]
+        strength: f32,
+        /// Distance from the center where the falloff starts, 0.0 center to 1.0 corners
+        #[arg(long, short = 'r', default_value_t = 0.5)]
+        radius: f32,
+        /// Vignette color, white lightens the corners
+        #[arg(long, short = 'c', default_value = "black")]
+        color: Color,
+    },
+    /// Recolor the image with a sepia, warm, cool or duotone tint
+    Tint {
+        /// Tint mode
+        ///
+        /// - sepia: Brownish old photo look (default)
+        /// - warm: Shift towards red and yellow
+        /// - cool: Shift towards blue
+        /// - duotone(dark,light): Map brightness to a gradient between two colors
+        #[arg(long, short = 'm', default_value = "sepia")]
+        mode: TintMode,
+        /// How much of the tint is applied, range (0.0 ~ 1.0)
+        #[arg(long, short = 's', default_value_t = 1.0)]
+        strength: f32,
+    },
+    /// Reduce the image to a palette of at most N colors
+    ///
+    /// GIF output stores the palette as is, PNG output compresses better.
+    Quantize {
+        /// Number of palette colors, range (1 ~ 256)
+        #[arg(long, short = 'c', default_value_t = 256)]
+        colors: u16,
+        /// Dithering: floyd-steinberg(default), ordered or none
+        #[arg(long, short = 'd', default_value = "floyd-steinberg")]
+        dither: Dither,
+    },
+    /// Reduce each color channel to a few levels
+    Posterize {
+        /// Number of levels per channel, range (2 ~ 255)
+        #[arg(long, short = 'l', default_value_t = 4)]
+        levels: u8,
+    },
+    /// Invert the color channels above a threshold
+    Solarize {
+        /// Channel values at or above the threshold are inverted
+        #[arg(long, short = 't', default_value_t = 128)]
+        threshold: u8,
+    },
+    /// Convert to black and white, e.g. to prepare scans for OCR
+    Threshold {
+        /// Gray values at or above this value become white
+        #[arg(long, short = 'v', default_value_t = 128)]
+        value: u8,
+    },
+    /// Detect edges, the result is a grayscale image
+    Edges {
+        /// Operator: sobel(default), prewitt or canny
+        #[arg(long, short = 'o', default_value = "sobel")]
+        operator: EdgeOperator,
+        /// Gradient magnitudes below this are dropped, the lower hysteresis threshold for canny
+        #[arg(long, default_value_t = 50.0)]
+        low: f32,
+        /// Upper hysteresis threshold, canny only
+        #[arg(long, default_value_t = 100.0)]
+        high: f32,
+    },
+    /// Grayscale relief effect
+    Emboss {
+        /// Height of the relief, range (0.0 ~ 10.0)
+        #[arg(long, short = 's', default_value_t = 1.0)]
+        strength: f32,
+    },
+    /// Apply a custom 3x3 or 5x5 convolution kernel
+    Convolve {
+        /// Kernel weights row by row, e.g. kernel(0,-1,0,-1,5,-1,0,-1,0)
+        #[arg(long, short = 'k')]
+        kernel: Kernel,
+        /// Weighted sums are divided by this, defaults to the sum of the weights or 1 if it is 0
+        #[arg(long, short = 'd')]
+        divisor: Option<f32>,
+        /// Added to each channel after dividing
+        #[arg(long, allow_negative_numbers = true, default_value_t = 0.0)]
+        offset: f32,
+    },
+    /// Add random noise, e.g. to generate test data
+    Noise {
+        /// Noise type: gaussian(default) or salt-pepper
+        #[arg(long = "type", short = 't', default_value = "gaussian")]
+        noise_type: NoiseType,
+        /// Standard deviation relative to the full range for gaussian, share of pixels for salt-pepper, range (0.0 ~ 1.0)
+        #[arg(long, short = 'a', default_value_t = 0.1)]
+        amount: f32,
+        /// Seed of the random generator, the same seed gives the same noise
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Remove noise with a median or bilateral filter
+    Denoise {
+        /// Method: median(default) or bilateral
+        #[arg(long, short = 'm', default_value = "median")]
+        method: DenoiseMethod,
+        /// Filter radius in pixels, range (1 ~ 20)
+        #[arg(long, short = 'r', default_value_t = 1)]
+        radius: u32,
+        /// Color difference at which neighbours stop counting, bilateral only
+        #[arg(long, short = 's', default_value_t = 30.0)]
+        sigma: f32,
+    },
+    /// Remove a color cast, estimated from the image, from a known gray spot or by hand
+    ///
+    /// The estimated or picked correction comes first, temperature and tint
+    /// are applied on top of it.
+    Whitebalance {
+        /// Estimate the cast: gray-world(default) makes the average color gray,
+        /// white-patch makes the brightest colors white
+        #[arg(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "gray-world",
+            conflicts_with = "from_pixel",
+            required_unless_present_any = ["from_pixel", "temperature", "tint"]
+        )]
+        auto: Option<AutoBalance>,
+        /// Make the color around this x,y position gray, e.g. a white wall or a gray card
+        #[arg(long)]
+        from_pixel: Option<Point>,
+        /// Warmer with positive values, cooler with negative ones, range (-100.0 ~ 100.0)
+        #[arg(
+            long,
+            short = 't',
+            default_value_t = 0.0,
+            allow_negative_numbers = true
+        )]
+        temperature: f32,
+        /// Towards magenta with positive values, towards green with negative ones,
+        /// range (-100.0 ~ 100.0)
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        tint: f32,
+    },
+    /// Brighten dark areas and darken bright ones, e.g. to rescue backlit photos
+    ///
+    /// The change follows the brightness of each area, so a dark foreground is
+    /// lifted as a whole and keeps its detail.
+    ShadowsHighlights {
+        /// How much to brighten the shadows, range (0.0 ~ 100.0)
+        #[arg(
+            long,
+            short = 's',
+            default_value_t = 0.0,
+            required_unless_present = "highlights"
+        )]
+        shadows: f32,
+        /// How much to darken the highlights, range (0.0 ~ 100.0)
+        #[arg(long, short = 'l', default_value_t = 0.0)]
+        highlights: f32,
+        /// Size of the areas in pixels, the blur radius of the brightness mask
+        #[arg(long, short = 'r', default_value_t = 30.0)]
+        radius: f32,
+    },
+    /// Spread out the brightness levels, e.g. to punch up flat images or before
+    /// computer vision
+    ///
+    /// Global equalization uses one histogram for the whole image. clahe
+    /// equalizes each tile of a grid on its own and blends between them,
+    /// bringing out local detail, while the clip limit keeps it from blowing up
+    /// noise in flat areas. Colors keep their hue, only brightness changes.
+    Equalize {
+        /// Method: global(default) or clahe
+        #[arg(long, short = 'm', default_value = "global")]
+        method: Equalization,
+        /// Columns and rows of the clahe tile grid, e.g. 8x8
+        #[arg(long, short = 't', default_value = "8x8")]
+        tiles: Size,
+        /// Most pixels a clahe tile may have of one level, as a multiple of the
+        /// average, lower values raise contrast less, range (>= 1.0)
+        #[arg(long, short = 'c', default_value_t = 2.0)]
+        clip_limit: f32,
+    },
+    /// Stretch each color channel to the full range to fix flat, low-contrast images
+    Autolevel {
+        /// Percentile of each channel that becomes black, range (0.0 ~ 100.0)
+        #[arg(long, default_value_t = 1.0)]
+        low: f32,
+        /// Percentile of each channel that becomes white, range (0.0 ~ 100.0)
+        #[arg(long, default_value_t = 99.0)]
+        high: f32,
+    },
+    /// Split an image into grayscale channel planes or merge planes into an image
+    Channels {
+        #[command(subcommand)]
+        command: Channels,
+    },
+    /// Flatten, extract, replace or premultiply the alpha channel
+    Alpha {
+        #[command(subcommand)]
+        command: Alpha,
+    },
+    /// Make a background color transparent, e.g. a green screen
+    Chromakey {
+        /// Key color to remove
+        #[arg(long, short = 'c', default_value = "green")]
+        color: Color,
+        /// Color distance that becomes fully transparent, range (0.0 ~ 1.0)
+        #[arg(long, short = 't', default_value_t = 0.15)]
+        tolerance: f32,
+        /// Distance beyond the tolerance over which pixels fade in, range (0.0 ~ 1.0)
+        #[arg(long, short = 'f', default_value_t = 0.05)]
+        feather: f32,
+    },
+    /// Print or draw the histogram of each color channel
+    ///
+    /// The png format replaces the image with a chart of the histogram, write
+    /// it to a new file with -o.
+    #[command(disable_help_flag = true, arg = help_arg())]
+    Histogram {
+        /// Output format: text(default), json or png
+        #[arg(long, short = 'f', default_value = "text")]
+        format: HistogramFormat,
+        /// Chart width, png only
+        #[arg(long, short = 'w', default_value_t = 512)]
+        width: u32,
+        /// Chart height, png only
+        #[arg(long, short = 'h', default_value_t = 200)]
+        height: u32,
+    },
+    /// Compare with another image of the same size and print MAE, PSNR and SSIM
+    Diff {
+        /// Image to compare with
+        other: PathBuf,
+        /// Output format: json(default) or text
+        #[arg(long, short = 'f', default_value = "json")]
+        format: ReportFormat,
+        /// Also write a heatmap of the differences to this file
+        #[arg(long)]
+        heatmap: Option<PathBuf>,
+    },
+    /// Print a perceptual hash as hex, or the distance to another image
+    Hash {
+        /// Algorithm: phash(default), ahash or dhash
+        #[arg(long, short = 'a', default_value = "phash")]
+        algorithm: HashAlgorithm,
+        /// Print the Hamming distance to this image instead, 0 means identical hashes
+        #[arg(long, short = 'c')]
+        compare: Option<PathBuf>,
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Print the min, max, mean and standard deviation of each channel, e.g. for
+    /// checks in scripts, and the values of single pixels
+    Stats {
+        /// Only measure this area, given as x,y,width,height
+        #[arg(long, short = 'r')]
+        region: Option<Region>,
+        /// Also print the values of the pixel at x,y, can be repeated
+        #[arg(long, short = 'p')]
+        pixel: Vec<Point>,
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Measure the sharpness, exposure and noise of an image, without a reference
+    ///
+    /// blur is the variance of the Laplacian, low values such as under 100 mean
+    /// a blurry image. overexposed and underexposed are the percentages of
+    /// nearly white and nearly black pixels, and noise estimates the standard
+    /// deviation of the noise.
+    Assess {
+        /// Fail when a metric meets the condition, e.g. blur<100 or overexposed>5,
+        /// can be repeated
+        #[arg(long)]
+        fail_if: Vec<QualityCheck>,
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Time steps on the image to compare filters and encoder settings
+    ///
+    /// The image is decoded once, then the steps run --warmup times untimed
+    /// and --runs times timed, each on a fresh copy. Reports the wall times,
+    /// the throughput in megapixels of the input per second at the median
+    /// time, and the peak memory of the whole process.
+    Bench {
+        /// Steps in pipeline syntax, e.g. "resize(800,filter=lanczos3)"
+        steps: Pipeline,
+        /// Timed runs
+        #[arg(long, short = 'n', default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+        /// Untimed runs first, to warm up caches and the thread pool
+        #[arg(long, short = 'w', default_value_t = 2)]
+        warmup: u32,
+        /// Also encode the result in this format in every run
+        #[arg(long, short = 'e')]
+        encode: Option<Format>,
+        /// Lossy quality of the encoding, range (1 ~ 100)
+        #[arg(long, short = 'q', requires = "encode", value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: Option<u8>,
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Print a BlurHash or ThumbHash string to show while the image loads, or
+    /// draw one with --decode
+    ///
+    /// With --decode no input is read, the placeholder is written to -o.
+    Placeholder {
+        /// Algorithm: blurhash(default) or thumbhash
+        #[arg(long, short = 'a', default_value = "blurhash")]
+        algorithm: PlaceholderAlgorithm,
+        /// BlurHash components across and down, 1 to 9 each, more keep more detail
+        #[arg(long, short = 'c', default_value = "4x3")]
+        components: Size,
+        /// Draw this placeholder string instead of hashing an image
+        #[arg(long, short = 'd')]
+        decode: Option<String>,
+        /// Size of the decoded image, 32x32 for a BlurHash and 32 pixels on the
+        /// longer side in its aspect ratio for a ThumbHash by default
+        #[arg(long, short = 's')]
+        size: Option<Size>,
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Print a tiny JPEG preview as a base64 data URI and the dominant color,
+    /// to inline in a page while the image lazy loads
+    ///
+    /// With --sidecar the JSON is written next to the input as
+    /// `<stem>.lqip.json` instead of printed.
+    Lqip {
+        /// Width of the preview, the height follows the aspect ratio
+        #[arg(long, short = 'w', default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..=256))]
+        width: u32,
+        /// JPEG quality, range (1 ~ 100), low keeps the data URI short
+        #[arg(long, short = 'q', default_value_t = 30, value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: u8,
+        /// Write the JSON to a sidecar file next to the input
+        #[arg(long)]
+        sidecar: bool,
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+    },
+    /// Print the format, size, color type, frame count, file size and an EXIF summary
+    Info {
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text", conflicts_with = "json")]
+        format: ReportFormat,
+        /// Shorthand for --format json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the dominant colors as hex codes or draw them as swatches
+    ///
+    /// The png format replaces the image with a strip of swatches, write it
+    /// to a new file with -o.
+    #[command(disable_help_flag = true, arg = help_arg())]
+    Palette {
+        /// Number of colors, range (1 ~ 256)
+        #[arg(long, short = 'c', default_value_t = 5)]
+        colors: u16,
+        /// Method: kmeans(default) or median-cut
+        #[arg(long, short = 'm', default_value = "kmeans")]
+        method: PaletteMethod,
+        /// Output format: text(default), json or png
+        #[arg(long, short = 'f', default_value = "text")]
+        format: HistogramFormat,
+        /// Swatch strip width, png only
+        #[arg(long, short = 'w', default_value_t = 500)]
+        width: u32,
+        /// Swatch strip height, png only
+        #[arg(long, short = 'h', default_value_t = 100)]
+        height: u32,
+    },
+    /// Cut the image into tiles, each written to its own file
+    Slice {
+        /// Tile size, the last column and row may be smaller
+        #[arg(
+            long,
+            short = 's',
+            required_unless_present = "grid",
+            conflicts_with = "grid"
+        )]
+        size: Option<Size>,
+        /// Number of columns and rows, e.g. 4x3
+        #[arg(long, short = 'g')]
+        grid: Option<Size>,
+        /// Tile file name, {x} and {y} are the column and row, {i} the tile
+        /// number and {name} the input file name without extension
+        #[arg(long, short = 'n', default_value = "tile_{x}_{y}.png")]
+        name: String,
+    },
+    /// Write each frame of an animated GIF, WebP or PNG to its own file
+    Frames {
+        /// Keep every Nth frame
+        #[arg(long, short = 'e', default_value_t = 1)]
+        every: usize,
+        /// Frame numbers to keep as start..end, the end is excluded and
+        /// either side may be left out
+        #[arg(long, short = 'r')]
+        range: Option<FrameRange>,
+        /// Frame file name, {i} is the frame number and {name} the input
+        /// file name without extension
+        #[arg(long, short = 'n', default_value = "frame_{i}.png")]
+        name: String,
+    },
+    /// Combine all input images into an animated GIF, WebP or PNG
+    Animate {
+        /// Time each frame is shown in milliseconds
+        #[arg(long, short = 'd', default_value_t = 100)]
+        delay: u32,
+        /// Number of times the animation plays, 0 loops forever
+        #[arg(long = "loop", short = 'l', default_value_t = 0)]
+        loops: u16,
+        /// Output format: gif, webp or png, the output extension decides if not given
+        #[arg(long, short = 'f')]
+        format: Option<Format>,
+    },
+    /// Generate a multi-resolution favicon.ico, optionally with PNG icons
+    Favicon {
+        /// Icon sizes stored in the .ico, at most 256
+        #[arg(
+            long,
+            short = 's',
+            value_delimiter = ',',
+            default_value = "16,32,48,64"
+        )]
+        sizes: Vec<u32>,
+        /// Also write apple-touch-icon.png (180x180) and icon-192.png and
+        /// icon-512.png for web app manifests next to the .ico
+        #[arg(long)]
+        png: bool,
+    },
+    /// Cut the image into a tile pyramid for deep zoom viewers and maps
+    ///
+    /// Without an output the tiles and the descriptor are written next to
+    /// the input. Every level is half the size of the next, up to the full image.
+    Tiles {
+        /// Layout: dzi(default) for OpenSeadragon or xyz for Leaflet and other map viewers
+        #[arg(long, short = 'l', default_value = "dzi")]
+        layout: PyramidLayout,
+        /// Tile width and height in pixels
+        #[arg(long, short = 's', default_value_t = 256)]
+        tile_size: u32,
+        /// Pixels each Deep Zoom tile reaches into its neighbors, XYZ tiles don't overlap
+        #[arg(long, default_value_t = 0)]
+        overlap: u32,
+        /// Tile format: jpeg(default), png or webp
+        #[arg(long, short = 'f', default_value = "jpeg")]
+        format: Format,
+        /// Fill of the XYZ tiles along the right and bottom edges, which are
+        /// always full size
+        #[arg(long, short = 'b', default_value = "transparent")]
+        background: Color,
+    },
+    /// Write the image at several widths in several formats for responsive
+    /// `srcset` markup, optionally with an HTML or JSON manifest
+    ///
+    /// Without an output the variants are written next to the input. Widths
+    /// beyond the image's own are written at its own width instead.
+    Srcset {
+        /// Widths of the variants in pixels, the heights keep the aspect ratio
+        #[arg(
+            long,
+            short = 'w',
+            value_delimiter = ',',
+            default_value = "320,640,1024,1920"
+        )]
+        widths: Vec<u32>,
+        /// Formats of the variants, best first: the manifest offers them in
+        /// this order and the last is the fallback for older browsers
+        #[arg(long, short = 'f', value_delimiter = ',', default_value = "webp,jpeg")]
+        formats: Vec<Format>,
+        /// Lossy quality for JPEG and AVIF, range (1 ~ 100)
+        #[arg(long, short = 'q', value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: Option<u8>,
+        /// Variant file name, {name} is the input file name without extension,
+        /// {width} and {height} the variant's size and {format} its extension
+        #[arg(long, short = 'n', default_value = "{name}-{width}.{format}")]
+        name: String,
+        /// Also write a manifest of the variants with this name, HTML with a
+        /// `<picture>` element for .html and JSON otherwise; {name} is the
+        /// input file name without extension
+        #[arg(long, short = 'm')]
+        manifest: Option<String>,
+        /// Sizes attribute of the HTML manifest, the width the image is shown at
+        #[arg(long, default_value = "100vw")]
+        sizes: String,
+    },
+    /// Lay out all input images in a grid, e.g. as a contact sheet
+    Montage {
+        /// Number of columns, 0 picks about as many columns as rows
+        #[arg(long, short = 'c', default_value_t = 0)]
+        columns: u32,
+        /// Cell size, larger images are shrunk to fit
+        #[arg(long, default_value = "200x200")]
+        cell: Size,
+        /// Space around and between the cells in pixels
+        #[arg(long, short = 'p', default_value_t = 10)]
+        padding: u32,
+        /// Background color or gradient
+        #[arg(long, short = 'b', default_value = "white")]
+        background: Fill,
+        /// Write the file name below each image
+        #[arg(long)]
+        captions: bool,
+        /// Caption font files or installed family names, comma separated, FangSong last
+        #[arg(long)]
+        font: Option<PathBuf>,
+        /// Caption font size in pixels
+        #[arg(long, default_value_t = 16.0)]
+        caption_size: f32,
+        /// Caption color
+        #[arg(long, default_value = "black")]
+        caption_color: Color,
+    },
+    /// Join all input images side by side or one below the other
+    Append {
+        /// Direction to join the images in
+        #[arg(long, short = 'd', default_value = "horizontal")]
+        direction: Direction,
+        /// Alignment across the direction: top, center or bottom for
+        /// horizontal, left, center or right for vertical
+        #[arg(long, short = 'a', default_value = "center")]
+        align: Alignment,
+        /// Space between the images in pixels
+        #[arg(long, short = 'g', default_value_t = 0)]
+        gap: u32,
+        /// Background color or gradient for gaps and around smaller images
+        #[arg(long, short = 'b', default_value = "transparent")]
+        background: Fill,
+    },
+    /// Merge bracketed exposures into one image, or tone-map a high dynamic range image
+    ///
+    /// Several inputs are merged with exposure fusion: each part of the result
+    /// comes mostly from the exposures where it is well exposed, saturated and
+    /// detailed. The exposures must be aligned and of the same size, e.g. shot
+    /// from a tripod. A single input, e.g. a Radiance .hdr or OpenEXR file, is
+    /// tone-mapped to 8 bits instead.
+    Hdr {
+        /// Tone mapping of a single input: reinhard(default) or drago, which keeps
+        /// more highlight detail
+        #[arg(long, short = 't', default_value = "reinhard")]
+        tone_map: ToneMap,
+        /// Exposure change in stops before tone mapping a single input
+        #[arg(
+            long,
+            short = 'e',
+            default_value_t = 0.0,
+            allow_negative_numbers = true
+        )]
+        exposure: f32,
+    },
+    /// Merge photos focused at different depths into one that is sharp throughout
+    ///
+    /// Each part of the result comes from the input that is sharpest there, e.g.
+    /// for macro shots where no single photo has the whole subject in focus.
+    /// The photos must be aligned and of the same size.
+    FocusStack {
+        /// Size in pixels of the area sharpness is compared over, larger areas
+        /// switch between photos less often
+        #[arg(long, short = 'r', default_value_t = 3.0)]
+        radius: f32,
+    },
+    /// Stitch a series of overlapping photos, in order from left to right, into a panorama
+    ///
+    /// Corners are matched between neighbouring photos to find how they overlap,
+    /// every photo is projected onto the plane of the middle one and the seams
+    /// are blended. Corners of the canvas no photo covers get the background.
+    #[cfg(feature = "stitch")]
+    Stitch {
+        /// Pyramid levels the seams are blended over, more hide differences in
+        /// brightness between the photos better, 1 leaves hard seams
+        #[arg(long, default_value_t = 8)]
+        bands: u32,
+        /// Background color for the parts no photo covers
+        #[arg(long, short = 'b', default_value = "transparent")]
+        background: Color,
+    },
+    /// Create an image from scratch, e.g. a placeholder or a test fixture, no input is read
+    Create {
+        /// Image size, e.g. 1920x1080
+        #[arg(long, short = 's')]
+        size: Size,
+        /// What fills the image: a color or gradient, checkerboard(size,color1,color2)
+        /// or noise(scale,color1,color2)
+        ///
+        /// The checkerboard squares are `size` pixels wide, white and light gray by
+        /// default. Noise is Perlin noise with features about `scale` pixels wide,
+        /// from black to white by default.
+        #[arg(long, short = 'f', default_value = "white")]
+        fill: Pattern,
+        /// Seed of the noise, the same seed gives the same image
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Draw a QR code or a barcode of some text, no input is read
+    Qrcode {
+        /// Text or URL to encode
+        #[arg(long, short = 't')]
+        text: String,
+        /// Kind of code: qr, code128 for printable ASCII, or ean13 for 12 or 13 digits
+        #[arg(long = "type", default_value = "qr")]
+        symbology: Symbology,
+        /// Error correction of a QR code: l, m, q or h, use q or h with a logo
+        #[arg(long, short = 'e', default_value = "m")]
+        ec: EcLevel,
+        /// Width of a module, the smallest square or bar, in pixels
+        #[arg(long, short = 'm', default_value_t = 10)]
+        module: u32,
+        /// Blank modules around the code, 4 for a QR code and 10 for a barcode by default
+        #[arg(long)]
+        quiet_zone: Option<u32>,
+        /// Height of the bars of a barcode in pixels
+        #[arg(long, default_value_t = 100)]
+        height: u32,
+        /// Color of the code
+        #[arg(long, short = 'c', default_value = "black")]
+        color: Color,
+        /// Background color
+        #[arg(long, short = 'b', default_value = "white")]
+        background: Color,
+        /// Image put in the middle of a QR code
+        #[arg(long)]
+        logo: Option<PathBuf>,
+    },
+    /// Print EXIF metadata, or redact it with a subcommand
+    Exif {
+        /// Output format: text(default) or json
+        #[arg(long, short = 'f', default_value = "text")]
+        format: ReportFormat,
+        #[command(subcommand)]
+        action: Option<ExifAction>,
+    },
+}
+
+impl Command {
+    /// Check whether the command prints a report instead of writing an image
+    pub fn is_report(&self) -> bool {
+        match self {
+            Command::Exif { action: None, .. }
+            | Command::Info { .. }
+            | Command::Diff { .. }
+            | Command::Hash { .. }
+            | Command::Stats { .. }
+            | Command::Assess { .. }
+            | Command::Bench { .. }
+            | Command::Lqip { .. } => true,
+            Command::Placeholder { decode, .. } => decode.is_none(),
+            Command::Histogram { format, .. } | Command::Palette { format, .. } => {
+                *format != HistogramFormat::Png
+            }
+            _ => false,
+        }
+    }
+
+    /// Check whether the command combines every input into one image
+    pub fn combines_inputs(&self) -> bool {
+        #[cfg(feature = "stitch")]
+        if let Command::Stitch { .. } = self {
+            return true;
+        }
+        matches!(
+            self,
+            Command::Montage { .. }
+                | Command::Append { .. }
+                | Command::Animate { .. }
+                | Command::Hdr { .. }
+                | Command::FocusStack { .. }
+        )
+    }
+
+    /// Check whether the command makes a new image instead of reading one
+    pub fn creates_image(&self) -> bool {
+        matches!(
+            self,
+            Command::Create { .. }
+                | Command::Qrcode { .. }
+                | Command::Placeholder {
+                    decode: Some(_),
+                    ..
+                }
+        )
+    }
+
+    /// The command itself, or every step of a pipeline in order
+    pub fn steps(&self) -> Vec<&Command> {
+        match self {
+            Command::Pipeline { steps } => steps.0.iter().flat_map(Command::steps).collect(),
+            command => vec![command],
+        }
+    }
+
+    /// Subcommand name as typed on the command line
+    pub fn name(&self) -> String {
+        let debug = format!("{:?}", self);
+        debug
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_lowercase()
+    }
+
+    /// Check whether the command, or any step of a pipeline, matches the predicate
+    pub fn any(&self, predicate: &impl Fn(&Command) -> bool) -> bool {
+        match self {
+            Command::Pipeline { steps } => steps.0.iter().any(|step| step.any(predicate)),
+            command => predicate(command),
+        }
+    }
+
+    /// Files the command reads besides its input, those of every step of a
+    /// pipeline included. Font lists are in `fonts_mut`
+    pub fn files_mut(&mut self) -> Vec<&mut PathBuf> {
+        match self {
+            Command::Pipeline { steps } | Command::Bench { steps, .. } => {
+                steps.0.iter_mut().flat_map(Command::files_mut).collect()
+            }
+            Command::LiquidResize { protect, .. } => protect.iter_mut().collect(),
+            Command::Upscale { model, .. } => model.iter_mut().collect(),
+            #[cfg(feature = "onnx")]
+            Command::Infer { model, .. } => vec![model],
+            #[cfg(feature = "onnx")]
+            Command::RemoveBg { model, .. } => model.iter_mut().collect(),
+            Command::Watch { recipe, .. } => recipe.iter_mut().collect(),
+            Command::Run { recipe } => vec![recipe],
+            Command::Composite { overlay, .. } => vec![overlay],
+            Command::Diff { other, .. } => vec![other],
+            Command::Hash { compare, .. } => compare.iter_mut().collect(),
+            Command::Qrcode { logo, .. } => logo.iter_mut().collect(),
+            Command::Watermark {
+                command: Watermark::Image { image },
+                ..
+            } => vec![image],
+            Command::Channels {
+                command: Channels::Merge { planes, .. },
+            } => planes.iter_mut().collect(),
+            Command::Alpha {
+                command: Alpha::Apply { mask },
+            } => vec![mask],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Font lists of the command and every step of a pipeline, comma
+    /// separated font files and installed family names
+    pub fn fonts_mut(&mut self) -> Vec<&mut PathBuf> {
+        match self {
+            Command::Pipeline { steps } | Command::Bench { steps, .. } => {
+                steps.0.iter_mut().flat_map(Command::fonts_mut).collect()
+            }
+            Command::Caption { font, .. }
+            | Command::Annotate { font, .. }
+            | Command::Montage { font, .. }
+            | Command::Watermark {
+                command: Watermark::Text { font, .. },
+                ..
+            } => font.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Long-only help flag for commands that use `-h` for their own options
+fn help_arg() -> clap::Arg {
+    clap::Arg::new("help")
+        .long("help")
+        .action(clap::ArgAction::Help)
+        .help("Print help")
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -145,6 +1616,8 @@ pub enum Format {
     Bmp,
     Avif,
     Tiff,
+    Gif,
+    Ico,
 }
 
 impl FromStr for Format {
@@ -157,100 +1630,370 @@ impl FromStr for Format {
             "bmp" => Ok(Format::Bmp),
             "avif" => Ok(Format::Avif),
             "tiff" => Ok(Format::Tiff),
+            "gif" => Ok(Format::Gif),
+            "ico" => Ok(Format::Ico),
+            _ => Err("Unsupported image formats"),
+        }
+    }
+}
+
+impl Format {
+    /// Media type of the format, e.g. for HTTP responses and HTML
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Jpeg => "image/jpeg",
+            Format::WebP => "image/webp",
+            Format::Bmp => "image/bmp",
+            Format::Avif => "image/avif",
+            Format::Tiff => "image/tiff",
+            Format::Gif => "image/gif",
+            Format::Ico => "image/x-icon",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpeg",
+            Format::WebP => "webp",
+            Format::Bmp => "bmp",
+            Format::Avif => "avif",
+            Format::Tiff => "tiff",
+            Format::Gif => "gif",
+            Format::Ico => "ico",
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<Format> for ImageFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Png => ImageFormat::Png,
+            Format::Jpeg => ImageFormat::Jpeg,
+            Format::WebP => ImageFormat::WebP,
+            Format::Bmp => ImageFormat::Bmp,
+            Format::Avif => ImageFormat::Avif,
+            Format::Tiff => ImageFormat::Tiff,
+            Format::Gif => ImageFormat::Gif,
+            Format::Ico => ImageFormat::Ico,
+        }
+    }
+}
+
+impl TryFrom<ImageFormat> for Format {
+    type Error = &'static str;
+    fn try_from(format: ImageFormat) -> Result<Self, Self::Error> {
+        match format {
+            ImageFormat::Png => Ok(Format::Png),
+            ImageFormat::Jpeg => Ok(Format::Jpeg),
+            ImageFormat::WebP => Ok(Format::WebP),
+            ImageFormat::Bmp => Ok(Format::Bmp),
+            ImageFormat::Avif => Ok(Format::Avif),
+            ImageFormat::Tiff => Ok(Format::Tiff),
+            ImageFormat::Gif => Ok(Format::Gif),
+            ImageFormat::Ico => Ok(Format::Ico),
             _ => Err("Unsupported image formats"),
         }
     }
 }
 
-impl ToString for Format {
-    fn to_string(&self) -> String {
+/// No-reference quality measure of the assess command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityMetric {
+    /// Variance of the Laplacian, low values mean a blurry image
+    Blur,
+    /// Percentage of nearly white pixels
+    Overexposed,
+    /// Percentage of nearly black pixels
+    Underexposed,
+    /// Estimated standard deviation of the noise, in levels of 0 ~ 255
+    Noise,
+}
+
+impl QualityMetric {
+    pub fn name(self) -> &'static str {
         match self {
-            Format::Png => "png".to_string(),
-            Format::Jpeg => "jpeg".to_string(),
-            Format::WebP => "webp".to_string(),
-            Format::Bmp => "bmp".to_string(),
-            Format::Avif => "avif".to_string(),
-            Format::Tiff => "tiff".to_string(),
+            QualityMetric::Blur => "blur",
+            QualityMetric::Overexposed => "overexposed",
+            QualityMetric::Underexposed => "underexposed",
+            QualityMetric::Noise => "noise",
         }
     }
 }
 
+impl FromStr for QualityMetric {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "blur" => Ok(QualityMetric::Blur),
+            "overexposed" => Ok(QualityMetric::Overexposed),
+            "underexposed" => Ok(QualityMetric::Underexposed),
+            "noise" => Ok(QualityMetric::Noise),
+            _ => Err("Unsupported quality metric, only blur/overexposed/underexposed/noise"),
+        }
+    }
+}
+
+/// Condition on a quality metric written as e.g. `blur<100` or `noise>=5`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityCheck {
+    pub metric: QualityMetric,
+    /// One of `<`, `<=`, `>` and `>=`
+    pub operator: &'static str,
+    pub value: f64,
+}
+
+impl QualityCheck {
+    /// Check whether a measured value meets the condition
+    pub fn matches(&self, measured: f64) -> bool {
+        match self.operator {
+            "<" => measured < self.value,
+            "<=" => measured <= self.value,
+            ">" => measured > self.value,
+            _ => measured >= self.value,
+        }
+    }
+}
+
+impl FromStr for QualityCheck {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid check: {}. Expected e.g. blur<100 or noise>=5", s);
+        // Two character operators first, so `<=` isn't read as `<`
+        let (operator, (metric, value)) = ["<=", ">=", "<", ">"]
+            .into_iter()
+            .find_map(|op| s.split_once(op).map(|parts| (op, parts)))
+            .ok_or_else(invalid)?;
+        Ok(QualityCheck {
+            metric: metric.parse()?,
+            operator,
+            value: value.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Kind of placeholder string for images that haven't loaded yet
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-pub enum Rotate {
+pub enum PlaceholderAlgorithm {
     #[default]
-    Rotate90,
-    Rotate180,
-    Rotate270,
+    BlurHash,
+    /// Keeps the aspect ratio and transparency
+    ThumbHash,
 }
 
-impl FromStr for Rotate {
+impl FromStr for PlaceholderAlgorithm {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
-            "90" | "rotate-90" => Ok(Rotate::Rotate90),
-            "180" | "rotate-180" => Ok(Rotate::Rotate180),
-            "270" | "rotate-270" => Ok(Rotate::Rotate270),
-            _ => Err("Unsupported rotation angle, only supports 90/180/270 degree rotation"),
+            "blurhash" => Ok(PlaceholderAlgorithm::BlurHash),
+            "thumbhash" => Ok(PlaceholderAlgorithm::ThumbHash),
+            _ => Err("Unsupported placeholder algorithm, only blurhash/thumbhash"),
         }
     }
 }
 
+/// Directory structure of a tile pyramid
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-pub enum Filter {
+pub enum PyramidLayout {
+    /// Deep Zoom, `<name>.dzi` and `<name>_files/<level>/<column>_<row>`, for OpenSeadragon
     #[default]
-    Nearest,
-    Triangle,
-    CatmullRom,
-    Gaussian,
-    Lanczos3,
+    Dzi,
+    /// `<name>/<zoom>/<x>/<y>` with a `<name>.json` descriptor, for Leaflet and other map viewers
+    Xyz,
 }
 
-impl Into<FilterType> for Filter {
-    fn into(self) -> FilterType {
-        match self {
-            Filter::Nearest => FilterType::Nearest,
-            Filter::Triangle => FilterType::Triangle,
-            Filter::CatmullRom => FilterType::CatmullRom,
-            Filter::Gaussian => FilterType::Gaussian,
-            Filter::Lanczos3 => FilterType::Lanczos3,
+impl FromStr for PyramidLayout {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dzi" | "deepzoom" => Ok(PyramidLayout::Dzi),
+            "xyz" => Ok(PyramidLayout::Xyz),
+            _ => Err("Unsupported tile layout, only dzi/xyz"),
         }
     }
 }
 
-impl FromStr for Filter {
+/// Output format of commands that print information about an image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for ReportFormat {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
-            "nearest" => Ok(Filter::Nearest),
-            "triangle" => Ok(Filter::Triangle),
-            "catmullrom" | "catmull-rom" => Ok(Filter::CatmullRom),
-            "gaussian" => Ok(Filter::Gaussian),
-            "lanczos3" => Ok(Filter::Lanczos3),
-            _ => {
-                Err("Unsupported filter types, only nearest/triangle/catmullrom/gaussian/lanczos3")
-            }
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err("Unsupported report format, only text/json"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Crop {
-    Center(u32, u32),
-    TopLeft(u32, u32),
-    TopCenter(u32, u32),
-    TopRight(u32, u32),
-    MiddleLeft(u32, u32),
-    MiddleRight(u32, u32),
-    BottomLeft(u32, u32),
-    BottomCenter(u32, u32),
-    BottomRight(u32, u32),
-    Custom(u32, u32, u32, u32),
+/// Perceptual hash algorithm
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Pixels compared with their mean
+    Average,
+    /// Pixels compared with their right neighbour
+    Difference,
+    /// Low DCT frequencies compared with their median
+    #[default]
+    Perceptual,
 }
 
-impl FromStr for Crop {
+impl FromStr for HashAlgorithm {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s.to_lowercase();
+        match s.to_lowercase().as_str() {
+            "ahash" | "average" => Ok(HashAlgorithm::Average),
+            "dhash" | "difference" => Ok(HashAlgorithm::Difference),
+            "phash" | "perceptual" => Ok(HashAlgorithm::Perceptual),
+            _ => Err("Unsupported hash algorithm, only ahash/dhash/phash"),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Average => "ahash",
+            HashAlgorithm::Difference => "dhash",
+            HashAlgorithm::Perceptual => "phash",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How the palette command picks colors
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteMethod {
+    /// Median cut refined by k-means clustering
+    #[default]
+    KMeans,
+    MedianCut,
+}
+
+impl FromStr for PaletteMethod {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kmeans" | "k-means" => Ok(PaletteMethod::KMeans),
+            "median-cut" | "mediancut" => Ok(PaletteMethod::MedianCut),
+            _ => Err("Unsupported palette method, only kmeans/median-cut"),
+        }
+    }
+}
+
+/// Output format of the histogram and palette commands
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HistogramFormat {
+    /// ASCII chart
+    #[default]
+    Text,
+    Json,
+    /// Chart image
+    Png,
+}
+
+impl FromStr for HistogramFormat {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(HistogramFormat::Text),
+            "json" => Ok(HistogramFormat::Json),
+            "png" => Ok(HistogramFormat::Png),
+            _ => Err("Unsupported histogram format, only text/json/png"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rotate {
+    #[default]
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl FromStr for Rotate {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "90" | "rotate-90" => Ok(Rotate::Rotate90),
+            "180" | "rotate-180" => Ok(Rotate::Rotate180),
+            "270" | "rotate-270" => Ok(Rotate::Rotate270),
+            _ => Err("Unsupported rotation angle, only supports 90/180/270 degree rotation"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(Filter::Nearest),
+            "triangle" => Ok(Filter::Triangle),
+            "catmullrom" | "catmull-rom" => Ok(Filter::CatmullRom),
+            "gaussian" => Ok(Filter::Gaussian),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            _ => {
+                Err("Unsupported filter types, only nearest/triangle/catmullrom/gaussian/lanczos3")
+            }
+        }
+    }
+}
+
+/// Where a crop goes and its size, each in pixels or percent of the image
+///
+/// Widths and left offsets are taken of the image width, heights and top
+/// offsets of its height, e.g. center(80%,80%) or custom(10%,10%,50%,50%).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Crop {
+    Center(Length, Length),
+    TopLeft(Length, Length),
+    TopCenter(Length, Length),
+    TopRight(Length, Length),
+    MiddleLeft(Length, Length),
+    MiddleRight(Length, Length),
+    BottomLeft(Length, Length),
+    BottomCenter(Length, Length),
+    BottomRight(Length, Length),
+    Custom(Length, Length, Length, Length),
+}
+
+impl FromStr for Crop {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
 
         if let Some((name, nums)) = s.split_once('(') {
             if !nums.ends_with(')') {
@@ -258,11 +2001,11 @@ impl FromStr for Crop {
             }
 
             let nums = nums.trim_end_matches(')');
-            let nums: Vec<u32> = nums
+            let nums: Vec<Length> = nums
                 .split(',')
-                .map(|n| n.trim().parse::<u32>())
+                .map(|n| n.trim().parse::<Length>())
                 .collect::<Result<Vec<_>, _>>()
-                .map_err(|_| "The parameter must be a non-negative integer")?;
+                .map_err(|_| "The parameter must be a non-negative integer or percentage")?;
 
             match (name.trim(), nums.as_slice()) {
                 ("center", &[w, h]) => Ok(Crop::Center(w, h)),
@@ -283,7 +2026,7 @@ impl FromStr for Crop {
     }
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone, PartialEq)]
 pub enum Watermark {
     /// Add watermark
     Text {
@@ -293,9 +2036,11 @@ pub enum Watermark {
         #[arg(long, short = 't')]
         text: String,
 
-        /// Font file path
+        /// Font files or installed family names
         ///
-        /// Supports ttf/otf font files. If not specified, will use built-in FangSong font
+        /// Supports ttf/otf/ttc font files and names such as "Noto Sans CJK SC".
+        /// Several fonts separated by commas are tried in order for each
+        /// character, the built-in FangSong font is the last resort
         #[arg(long, short = 'f')]
         font: Option<PathBuf>,
 
@@ -308,10 +2053,27 @@ pub enum Watermark {
         /// Text color
         ///
         /// Supports following color options:
-        /// - Preset colors: white(default), black, red, green, blue
-        /// - Custom color: rgba(r,g,b,a) - r,g,b range 0-255, a range 0-255 for transparency
+        /// - Names: white(default), black, red, green, blue, transparent and the CSS colors
+        /// - Hex: #RRGGBB or #RRGGBBAA, #RGB and #RGBA for short
+        /// - Custom color: rgb(r,g,b), rgba(r,g,b,a) - r,g,b range 0-255, a range 0-255 or 0.0-1.0
+        /// - HSL: hsl(h,s%,l%), hsla(h,s%,l%,a) - h in degrees
+        /// - Gradient: gradient(linear,angle,color1,color2,...) or gradient(radial,color1,color2,...)
         #[arg(long, short = 'c', default_value = "white")]
-        color: Color,
+        color: Fill,
+
+        /// Text opacity
+        ///
+        /// Applied over the alpha of the text and stroke colors, range (0.0 ~ 1.0)
+        #[arg(long, default_value_t = 1.0)]
+        opacity: f32,
+
+        /// Outline color, same options as the text color
+        #[arg(long, default_value = "black")]
+        stroke_color: Color,
+
+        /// Outline width in pixels, 0 draws no outline
+        #[arg(long, default_value_t = 0)]
+        stroke_width: u32,
     },
     Image {
         /// Watermark image file path
@@ -319,6 +2081,99 @@ pub enum Watermark {
     },
 }
 
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Channels {
+    /// Write each channel to its own grayscale file
+    ///
+    /// Files are named after the output with the channel appended, e.g.
+    /// out_r.png, out_g.png, out_b.png and out_a.png, or out_l.png for gray
+    /// images. Only a single command, not a pipeline step.
+    Split,
+    /// Combine grayscale planes into one image, or reorder the channels of the input
+    ///
+    /// With planes the input is the first plane. One to four planes are named
+    /// l, la, rgb or rgba, without planes the channels of the input are used.
+    Merge {
+        /// Further plane files in channel order
+        #[arg(long, short = 'p', num_args = 1..)]
+        planes: Vec<PathBuf>,
+        /// Channel names for the output in order, e.g. bgr, defaults to all channels
+        #[arg(long, short = 'm')]
+        map: Option<ChannelMap>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ExifAction {
+    /// Remove location, serial numbers and owner names, keeping exposure data
+    ///
+    /// GPS fields, camera and lens serial numbers, owner and artist names,
+    /// the unique image ID and maker notes are removed. XMP is removed as
+    /// well since it often repeats them. JPEG, PNG, WebP and TIFF keep their
+    /// encoded pixels.
+    Redact {
+        /// Round capture times down to the hour, day or month
+        #[arg(long)]
+        fuzz_time: Option<TimeFuzz>,
+    },
+    /// Set EXIF text fields, e.g. to stamp ownership onto exported images
+    ///
+    /// JPEG, PNG, WebP and TIFF keep their encoded pixels and all other
+    /// metadata. Fields that exist are replaced, the rest are added.
+    Set {
+        /// Name of the photographer, the Artist field
+        #[arg(long)]
+        artist: Option<String>,
+        /// Copyright notice
+        #[arg(long)]
+        copyright: Option<String>,
+        /// Date and time for DateTime and DateTimeOriginal, e.g. "2024-05-17 14:23:09"
+        #[arg(long)]
+        datetime: Option<ExifDateTime>,
+        /// Further fields as Name=value, e.g. LensModel="50mm F1.8", repeatable
+        #[arg(long = "tag", short = 't')]
+        tags: Vec<ExifTag>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Alpha {
+    /// Flatten onto a background color, e.g. before JPEG output
+    Remove {
+        /// Background color
+        #[arg(long, short = 'b', default_value = "white")]
+        background: Color,
+    },
+    /// Replace the image with its alpha channel as grayscale
+    Extract,
+    /// Use a grayscale image as the alpha channel, white is opaque
+    Apply {
+        /// Mask file path, stretched to the image size
+        #[arg(long, short = 'm')]
+        mask: PathBuf,
+    },
+    /// Multiply the color channels by alpha
+    Premultiply,
+}
+
+/// Source channel for each output channel, by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMap(pub Vec<char>);
+
+impl FromStr for ChannelMap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let map: Vec<char> = s.trim().to_lowercase().chars().collect();
+        if map.is_empty() || map.len() > 4 {
+            return Err(format!("Channel map {} needs 1 to 4 channels", s));
+        }
+        match map.iter().find(|c| !"rgbal".contains(**c)) {
+            Some(c) => Err(format!("Invalid channel '{}' in {}, only r/g/b/a/l", c, s)),
+            None => Ok(ChannelMap(map)),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Position {
     #[default]
@@ -332,7 +2187,7 @@ pub enum Position {
     BottomCenter,
     BottomRight,
     Custom(u32, u32),
-    FlatLay(usize),
+    FlatLay(u32, u32),
 }
 
 impl FromStr for Position {
@@ -374,13 +2229,20 @@ impl FromStr for Position {
                         )
                     }
                 }
-                // Handle flat-lay(n) format
+                // Handle flat-lay(n) and flat-lay(x,y) formats
                 else if s.starts_with("flat-lay(") && s.ends_with(")") {
                     // Extract content within parentheses
-                    let n = s[9..s.len() - 1].trim();
-                    match n.parse::<usize>() {
-                        Ok(value) => Ok(Position::FlatLay(value)),
-                        Err(_) => Err("Invalid flat-lay format. Expected a number.".to_string()),
+                    let gaps = s[9..s.len() - 1]
+                        .split(',')
+                        .map(|n| n.trim().parse::<u32>())
+                        .collect::<Result<Vec<_>, _>>();
+                    match gaps.as_deref() {
+                        Ok(&[gap]) => Ok(Position::FlatLay(gap, gap)),
+                        Ok(&[x, y]) => Ok(Position::FlatLay(x, y)),
+                        _ => Err(
+                            "Invalid flat-lay format. Expected flat-lay(gap) or flat-lay(x,y)"
+                                .to_string(),
+                        ),
                     }
                 } else {
                     Err(format!("Unknown position: {}", s))
@@ -390,57 +2252,1418 @@ impl FromStr for Position {
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-pub enum Color {
-    #[default]
-    White,
-    Black,
-    Red,
-    Green,
-    Blue,
-    Rgba(u8, u8, u8, u8),
+/// Relative scale factor, e.g. 50% or 0.5
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale(pub f32);
+
+/// Number of bytes, e.g. 200KB, 1.5MB, 2GiB or 4096
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let unit = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1.0,
+            "k" | "kb" => 1e3,
+            "kib" => 1024.0,
+            "m" | "mb" => 1e6,
+            "mib" => 1024.0 * 1024.0,
+            "g" | "gb" => 1e9,
+            "gib" => 1024.0 * 1024.0 * 1024.0,
+            _ => return Err("Invalid size unit, expected B, KB, MB, GB, KiB, MiB or GiB"),
+        };
+        let number = number
+            .parse::<f64>()
+            .map_err(|_| "Invalid size, expected a number of bytes like 200KB")?;
+        match (number * unit).round() {
+            bytes if bytes >= 1.0 => Ok(ByteSize(bytes as u64)),
+            _ => Err("Size must be at least 1 byte"),
+        }
+    }
+}
+
+impl FromStr for Scale {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let factor = match s.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f32>().map(|p| p / 100.0),
+            None => s.parse::<f32>(),
+        }
+        .map_err(|_| "Invalid scale, expected a percentage like 50% or a ratio like 0.5")?;
+
+        match factor.is_finite() && factor > 0.0 {
+            true => Ok(Scale(factor)),
+            false => Err("Scale must be greater than 0"),
+        }
+    }
+}
+
+/// How a thumbnail fits into its bounds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailMode {
+    /// Shrink to fit inside the bounds, keeping the aspect ratio
+    #[default]
+    Fit,
+    /// Scale to cover the bounds and crop the overflow
+    Fill,
+    /// Fit inside the bounds and fill the remaining area with the background
+    Pad,
+}
+
+impl FromStr for ThumbnailMode {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fit" => Ok(ThumbnailMode::Fit),
+            "fill" => Ok(ThumbnailMode::Fill),
+            "pad" => Ok(ThumbnailMode::Pad),
+            _ => Err("Unsupported thumbnail mode, only fit/fill/pad"),
+        }
+    }
+}
+
+/// Frame numbers `start..end`, the end is excluded and open if not given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange(pub usize, pub Option<usize>);
+
+impl FrameRange {
+    /// Check whether the frame number is in the range
+    pub fn contains(&self, frame: usize) -> bool {
+        frame >= self.0 && self.1.is_none_or(|end| frame < end)
+    }
+}
+
+impl FromStr for FrameRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid frame range: {}. Expected start..end, e.g. 10..20",
+                s
+            )
+        };
+        let (start, end) = s.split_once("..").ok_or_else(invalid)?;
+        let start = match start.trim() {
+            "" => 0,
+            start => start.parse().map_err(|_| invalid())?,
+        };
+        let end = match end.trim() {
+            "" => None,
+            end => Some(end.parse().map_err(|_| invalid())?),
+        };
+
+        match end {
+            Some(end) if end <= start => Err(format!(
+                "Invalid frame range: {}. The end must be after the start",
+                s
+            )),
+            _ => Ok(FrameRange(start, end)),
+        }
+    }
+}
+
+/// Direction to join images in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Left to right
+    #[default]
+    Horizontal,
+    /// Top to bottom
+    Vertical,
+}
+
+impl FromStr for Direction {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "horizontal" | "h" => Ok(Direction::Horizontal),
+            "vertical" | "v" => Ok(Direction::Vertical),
+            _ => Err("Unsupported direction, only horizontal/vertical"),
+        }
+    }
+}
+
+/// ICC color profile, a well-known color space or a profile file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Profile {
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+    /// Path of an .icc or .icm file
+    File(PathBuf),
+}
+
+impl FromStr for Profile {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(Profile::Srgb),
+            "display-p3" | "p3" => Ok(Profile::DisplayP3),
+            "adobe-rgb" | "adobergb" => Ok(Profile::AdobeRgb),
+            name if name.ends_with(".icc") || name.ends_with(".icm") => {
+                Ok(Profile::File(PathBuf::from(s)))
+            }
+            _ => Err("Unsupported profile, only srgb/display-p3/adobe-rgb or an .icc file"),
+        }
+    }
+}
+
+/// Where smaller images sit across the joining direction, or how caption lines line up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// Top or left edge
+    Start,
+    #[default]
+    Center,
+    /// Bottom or right edge
+    End,
+}
+
+impl FromStr for Alignment {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "top" | "left" | "start" => Ok(Alignment::Start),
+            "center" | "middle" => Ok(Alignment::Center),
+            "bottom" | "right" | "end" => Ok(Alignment::End),
+            _ => Err("Unsupported alignment, only top/center/bottom or left/center/right"),
+        }
+    }
+}
+
+/// Tone curve through `input:output` points with ascending inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Curve(pub Vec<(u8, u8)>);
+
+impl FromStr for Curve {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let points = s
+            .strip_prefix("curve(")
+            .and_then(|s| s.strip_suffix(')'))
+            .unwrap_or(s);
+
+        let points = points
+            .split(',')
+            .map(|point| {
+                let (x, y) = point
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid curve point: {}", point))?;
+                match (x.trim().parse::<u8>(), y.trim().parse::<u8>()) {
+                    (Ok(x), Ok(y)) => Ok((x, y)),
+                    _ => Err(format!(
+                        "Invalid curve point: {}. Expected input:output in range 0-255",
+                        point
+                    )),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if points.len() < 2 {
+            return Err("A curve needs at least two points".to_string());
+        }
+        if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err("Curve inputs must be in ascending order".to_string());
+        }
+        Ok(Curve(points))
+    }
+}
+
+/// Square convolution kernel, weights row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kernel(pub Vec<f32>);
+
+impl Kernel {
+    /// Number of rows and columns
+    pub fn size(&self) -> usize {
+        (self.0.len() as f64).sqrt() as usize
+    }
+
+    /// Sum of the weights, or 1 when they cancel out
+    pub fn default_divisor(&self) -> f32 {
+        let sum = self.0.iter().sum::<f32>();
+        if sum == 0.0 { 1.0 } else { sum }
+    }
+}
+
+impl FromStr for Kernel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let weights = s
+            .strip_prefix("kernel(")
+            .and_then(|s| s.strip_suffix(')'))
+            .unwrap_or(s);
+
+        let weights = weights
+            .split(',')
+            .map(|w| {
+                w.trim()
+                    .parse::<f32>()
+                    .map_err(|_| format!("Invalid kernel weight: {}", w))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match weights.len() {
+            9 | 25 => Ok(Kernel(weights)),
+            n => Err(format!(
+                "A kernel needs 9 (3x3) or 25 (5x5) weights, got {}",
+                n
+            )),
+        }
+    }
+}
+
+/// How quantization errors are spread over neighbouring pixels
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Error diffusion to the right and lower neighbours
+    #[default]
+    FloydSteinberg,
+    /// 4x4 Bayer threshold pattern
+    Ordered,
+    None,
+}
+
+impl FromStr for Dither {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "floyd-steinberg" | "floydsteinberg" => Ok(Dither::FloydSteinberg),
+            "ordered" | "bayer" => Ok(Dither::Ordered),
+            "none" => Ok(Dither::None),
+            _ => Err("Unsupported dithering, only floyd-steinberg/ordered/none"),
+        }
+    }
+}
+
+/// Gradient operator of the edges command
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeOperator {
+    #[default]
+    Sobel,
+    Prewitt,
+    /// Sobel gradients with non-maximum suppression and hysteresis
+    Canny,
+}
+
+impl FromStr for EdgeOperator {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sobel" => Ok(EdgeOperator::Sobel),
+            "prewitt" => Ok(EdgeOperator::Prewitt),
+            "canny" => Ok(EdgeOperator::Canny),
+            _ => Err("Unsupported edge operator, only sobel/prewitt/canny"),
+        }
+    }
+}
+
+/// Distribution of the noise command
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseType {
+    /// Normally distributed offsets on every channel
+    #[default]
+    Gaussian,
+    /// Random black and white pixels
+    SaltPepper,
+}
+
+impl FromStr for NoiseType {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gaussian" => Ok(NoiseType::Gaussian),
+            "salt-pepper" | "salt-and-pepper" => Ok(NoiseType::SaltPepper),
+            _ => Err("Unsupported noise type, only gaussian/salt-pepper"),
+        }
+    }
+}
+
+/// Filter used by the denoise command
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DenoiseMethod {
+    /// Median of the neighbourhood, removes speckles
+    #[default]
+    Median,
+    /// Average of similar neighbours, keeps edges sharp
+    Bilateral,
+}
+
+impl FromStr for DenoiseMethod {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "median" => Ok(DenoiseMethod::Median),
+            "bilateral" => Ok(DenoiseMethod::Bilateral),
+            _ => Err("Unsupported denoise method, only median/bilateral"),
+        }
+    }
+}
+
+/// Color mapping applied by the tint command
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TintMode {
+    #[default]
+    Sepia,
+    Warm,
+    Cool,
+    /// Dark and light end of the gradient
+    Duotone(Color, Color),
+}
+
+impl FromStr for TintMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        match lower.as_str() {
+            "sepia" => Ok(TintMode::Sepia),
+            "warm" => Ok(TintMode::Warm),
+            "cool" => Ok(TintMode::Cool),
+            _ => {
+                let colors = lower
+                    .strip_prefix("duotone(")
+                    .and_then(|s| s.strip_suffix(')'))
+                    .ok_or_else(|| {
+                        format!(
+                            "Unknown tint: {}. Expected sepia, warm, cool or duotone(dark,light)",
+                            s
+                        )
+                    })?;
+                match split_top_level(colors, ',')?.as_slice() {
+                    [dark, light] => Ok(TintMode::Duotone(
+                        dark.trim().parse()?,
+                        light.trim().parse()?,
+                    )),
+                    _ => Err("Invalid duotone format. Expected duotone(dark,light)".to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// Length in pixels, or in percent of a reference length, e.g. 16 or 10%
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixels(u32),
+    Percent(f32),
+}
+
+impl Length {
+    /// Length in pixels, percentages are taken of `reference`
+    pub fn pixels(self, reference: u32) -> f32 {
+        match self {
+            Length::Pixels(pixels) => pixels as f32,
+            Length::Percent(percent) => reference as f32 * percent / 100.0,
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let length = match s.strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|p| p.is_finite() && *p >= 0.0)
+                .map(Length::Percent),
+            None => s.parse::<u32>().ok().map(Length::Pixels),
+        };
+        length.ok_or_else(|| {
+            format!(
+                "Invalid length: {}. Expected pixels like 16 or a percentage like 10%",
+                s
+            )
+        })
+    }
+}
+
+/// Image size written as `WIDTHxHEIGHT`, e.g. 1920x1080
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub u32, pub u32);
+
+impl FromStr for Size {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (w, h) = s
+            .to_lowercase()
+            .split_once('x')
+            .map(|(w, h)| (w.trim().parse::<u32>(), h.trim().parse::<u32>()))
+            .ok_or_else(|| format!("Invalid size: {}. Expected WIDTHxHEIGHT", s))?;
+        match (w, h) {
+            (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok(Size(w, h)),
+            _ => Err(format!(
+                "Invalid size: {}. Width and height must be positive numbers",
+                s
+            )),
+        }
+    }
+}
+
+/// Area of an image written as `x,y,width,height` in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Region {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nums = s
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>();
+        match nums.as_deref() {
+            Ok(&[x, y, width, height]) if width > 0 && height > 0 => Ok(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(format!(
+                "Invalid region: {}. Expected x,y,width,height with a positive width and height",
+                s
+            )),
+        }
+    }
+}
+
+/// What a crop is moved to keep in view
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Focus {
+    /// The faces found in the image
+    Faces,
+}
+
+impl FromStr for Focus {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "faces" => Ok(Focus::Faces),
+            _ => Err("Unsupported focus, only faces"),
+        }
+    }
+}
+
+/// How the redact command obscures an area
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RedactMethod {
+    /// Blocks of the area's average colors
+    #[default]
+    Pixelate,
+    /// A heavy Gaussian blur of the area alone
+    Blur,
+}
+
+impl FromStr for RedactMethod {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pixelate" => Ok(RedactMethod::Pixelate),
+            "blur" => Ok(RedactMethod::Blur),
+            _ => Err("Unsupported redact method, only pixelate/blur"),
+        }
+    }
+}
+
+/// How the white balance is estimated from the image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AutoBalance {
+    /// The average color becomes gray
+    #[default]
+    GrayWorld,
+    /// The brightest colors become white
+    WhitePatch,
+}
+
+impl FromStr for AutoBalance {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gray-world" | "grayworld" => Ok(AutoBalance::GrayWorld),
+            "white-patch" | "whitepatch" => Ok(AutoBalance::WhitePatch),
+            _ => Err("Unsupported white balance method, only gray-world/white-patch"),
+        }
+    }
+}
+
+/// How the equalize command spreads out the brightness levels
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Equalization {
+    /// One histogram for the whole image
+    #[default]
+    Global,
+    /// Contrast limited adaptive histogram equalization, one histogram per tile
+    Clahe,
+}
+
+impl FromStr for Equalization {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "global" => Ok(Equalization::Global),
+            "clahe" => Ok(Equalization::Clahe),
+            _ => Err("Unsupported equalization method, only global/clahe"),
+        }
+    }
+}
+
+/// How a high dynamic range image is fitted into 8 bits
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMap {
+    /// Middle gray at the average brightness, rolling off towards white
+    #[default]
+    Reinhard,
+    /// Logarithmic compression that keeps more highlight detail
+    Drago,
+}
+
+impl FromStr for ToneMap {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "drago" => Ok(ToneMap::Drago),
+            _ => Err("Unsupported tone mapping operator, only reinhard/drago"),
+        }
+    }
+}
+
+/// Order of the axes of the tensors a model takes and returns
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TensorLayout {
+    /// Batch, channels, height, width, as PyTorch exports
+    #[default]
+    Nchw,
+    /// Batch, height, width, channels, as TensorFlow exports
+    Nhwc,
+}
+
+impl FromStr for TensorLayout {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nchw" => Ok(TensorLayout::Nchw),
+            "nhwc" => Ok(TensorLayout::Nhwc),
+            _ => Err("Unsupported tensor layout, only nchw/nhwc"),
+        }
+    }
+}
+
+/// What the result of a model is made into
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ModelOutput {
+    /// A color or grayscale image replacing the input
+    #[default]
+    Image,
+    /// A grayscale mask of one channel, 0 to 1 becoming black to white
+    Mask,
+    /// A mask cutting out the input, e.g. from background removal
+    Alpha,
+}
+
+impl FromStr for ModelOutput {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "image" => Ok(ModelOutput::Image),
+            "mask" => Ok(ModelOutput::Mask),
+            "alpha" => Ok(ModelOutput::Alpha),
+            _ => Err("Unsupported model output, only image/mask/alpha"),
+        }
+    }
+}
+
+/// Position of a pixel written as `x,y`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Point {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl FromStr for Point {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nums = s
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>();
+        match nums.as_deref() {
+            Ok(&[x, y]) => Ok(Point { x, y }),
+            _ => Err(format!("Invalid point: {}. Expected x,y in pixels", s)),
+        }
+    }
+}
+
+/// How overlay colors combine with the image below
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+}
+
+impl FromStr for BlendMode {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "normal" => Ok(BlendMode::Normal),
+            "multiply" => Ok(BlendMode::Multiply),
+            "screen" => Ok(BlendMode::Screen),
+            "overlay" => Ok(BlendMode::Overlay),
+            _ => Err("Unsupported blend mode, only normal/multiply/screen/overlay"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    White,
+    Black,
+    Red,
+    Green,
+    Blue,
+    Rgba(u8, u8, u8, u8),
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    /// Parse a preset or CSS color name, `#RRGGBB` with an optional alpha,
+    /// `rgb(r,g,b)`, `rgba(r,g,b,a)`, `hsl(h,s%,l%)` or `hsla(h,s%,l%,a)`
+    ///
+    /// Alpha is 0-255, or 0.0-1.0 and a percentage as in CSS.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let function = lower
+            .split_once('(')
+            .and_then(|(name, rest)| Some((name.trim(), rest.strip_suffix(')')?)));
+        match (lower.as_str(), function) {
+            ("white", _) => Ok(Color::White),
+            ("black", _) => Ok(Color::Black),
+            ("red", _) => Ok(Color::Red),
+            ("green", _) => Ok(Color::Green),
+            ("blue", _) => Ok(Color::Blue),
+            ("transparent", _) => Ok(Color::Rgba(0, 0, 0, 0)),
+            (hex, _) if hex.starts_with('#') => {
+                let [r, g, b, a] = colors::hex(hex).ok_or_else(|| {
+                    format!(
+                        "Invalid hex color: {}. Expected #RGB, #RGBA, #RRGGBB or #RRGGBBAA",
+                        s
+                    )
+                })?;
+                Ok(Color::Rgba(r, g, b, a))
+            }
+            (_, Some((name @ ("rgb" | "rgba" | "hsl" | "hsla"), content))) => {
+                let parts: Vec<&str> = content.split(',').map(|s| s.trim()).collect();
+                let alpha = name.ends_with('a');
+                if parts.len() != 3 + alpha as usize {
+                    return Err(match alpha {
+                        true => format!("Invalid format: {}. Expected {}(_,_,_,a)", s, name),
+                        false => format!("Invalid format: {}. Expected {}(_,_,_)", s, name),
+                    });
+                }
+                let a = match parts.get(3) {
+                    Some(a) => parse_alpha(a)?,
+                    None => 255,
+                };
+                let [r, g, b] = match name.starts_with("rgb") {
+                    true => {
+                        let parse_component = |s: &str| -> Result<u8, String> {
+                            s.parse()
+                                .map_err(|_| format!("Invalid color component: {}", s))
+                        };
+                        [
+                            parse_component(parts[0])?,
+                            parse_component(parts[1])?,
+                            parse_component(parts[2])?,
+                        ]
+                    }
+                    false => {
+                        let hue = parts[0]
+                            .trim_end_matches("deg")
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|hue| hue.is_finite())
+                            .ok_or_else(|| format!("Invalid hue: {}", parts[0]))?;
+                        colors::hsl_to_rgb(hue, parse_percent(parts[1])?, parse_percent(parts[2])?)
+                    }
+                };
+                Ok(Color::Rgba(r, g, b, a))
+            }
+            (name, _) => match colors::named(name) {
+                Some([r, g, b]) => Ok(Color::Rgba(r, g, b, 255)),
+                None => Err(format!(
+                    "Invalid color: {}. Expected a CSS color name, #RRGGBB, #RRGGBBAA, rgb(r,g,b), rgba(r,g,b,a) or hsl(h,s%,l%)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Parse an alpha of 0-255, or a fraction such as 0.5 or a percentage such as 50%
+fn parse_alpha(s: &str) -> Result<u8, String> {
+    let invalid = || format!("Invalid alpha: {}", s);
+    if s.ends_with('%') || s.contains('.') {
+        let fraction = match s.strip_suffix('%') {
+            Some(percent) => percent.parse::<f32>().map(|p| p / 100.0),
+            None => s.parse::<f32>(),
+        }
+        .map_err(|_| invalid())?;
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(invalid());
+        }
+        return Ok((fraction * 255.0).round() as u8);
+    }
+    s.parse().map_err(|_| invalid())
+}
+
+/// Parse a saturation or lightness percentage such as 50% into 0.0 ~ 1.0
+fn parse_percent(s: &str) -> Result<f32, String> {
+    s.strip_suffix('%')
+        .and_then(|percent| percent.trim().parse::<f32>().ok())
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .map(|percent| percent / 100.0)
+        .ok_or_else(|| format!("Invalid percentage: {}. Expected 0% to 100%", s))
+}
+
+/// A solid color or a gradient filling text or a background
+///
+/// Gradients are written `gradient(linear,angle,color1,color2,...)` with the
+/// angle in degrees as in CSS, 90 runs left to right, or
+/// `gradient(radial,color1,color2,...)` from the center outward.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fill {
+    Solid(Color),
+    Gradient(Gradient),
+}
+
+impl Fill {
+    /// The color of a solid fill, transparent for a gradient that `underlay` draws later
+    pub fn color(&self) -> Rgba<u8> {
+        match self {
+            Fill::Solid(color) => (*color).into(),
+            Fill::Gradient(_) => Rgba([0, 0, 0, 0]),
+        }
+    }
+
+    /// Draw the image over a gradient fill, images on a solid fill are returned as they are
+    pub fn underlay(&self, img: DynamicImage, keep_alpha: bool) -> DynamicImage {
+        match self {
+            Fill::Solid(_) => img,
+            Fill::Gradient(gradient) => gradient.underlay(&img, keep_alpha),
+        }
+    }
+}
+
+impl Default for Fill {
+    fn default() -> Self {
+        Fill::Solid(Color::default())
+    }
+}
+
+/// A shape drawn by the annotate command, at pixel coordinates of the image
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    /// Straight line between two points
+    Line {
+        from: (i32, i32),
+        to: (i32, i32),
+        color: Color,
+        width: u32,
+    },
+    /// Line with an arrowhead at its end
+    Arrow {
+        from: (i32, i32),
+        to: (i32, i32),
+        color: Color,
+        width: u32,
+    },
+    /// Rectangle from its top left corner, the outline drawn inside it, filled with width 0
+    Rect {
+        at: (i32, i32),
+        size: (u32, u32),
+        color: Color,
+        width: u32,
+    },
+    /// Circle around its center, the outline drawn inside it, filled with width 0
+    Circle {
+        center: (i32, i32),
+        radius: u32,
+        color: Color,
+        width: u32,
+    },
+    /// One line of text with its top left corner at a point
+    Text {
+        at: (i32, i32),
+        text: String,
+        color: Color,
+        size: f32,
+    },
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid shape: {}. Expected line(x1,y1,x2,y2), arrow(x1,y1,x2,y2), \
+                 rect(x,y,width,height), circle(x,y,radius) or text(x,y,\"text\"), \
+                 optionally followed by a color and a width",
+                s
+            )
+        };
+        let (name, args) = s
+            .trim()
+            .split_once('(')
+            .and_then(|(name, rest)| Some((name.trim().to_lowercase(), rest.strip_suffix(')')?)))
+            .ok_or_else(invalid)?;
+        let parts: Vec<&str> = split_top_level(args, ',')?
+            .into_iter()
+            .map(str::trim)
+            .collect();
+        let count = match name.as_str() {
+            "line" | "arrow" | "rect" => 4,
+            "circle" | "text" => 3,
+            _ => return Err(invalid()),
+        };
+        if parts.len() < count || parts.len() > count + 2 {
+            return Err(invalid());
+        }
+        let number = |i: usize| -> Result<i32, String> {
+            parts[i]
+                .parse()
+                .map_err(|_| format!("Invalid coordinate in {}: {}", s, parts[i]))
+        };
+        let length = |i: usize| -> Result<u32, String> {
+            parts[i]
+                .parse()
+                .map_err(|_| format!("Invalid size in {}: {}", s, parts[i]))
+        };
+        let color = match parts.get(count) {
+            Some(color) => color.parse()?,
+            None => Color::Red,
+        };
+        // Text takes a font size in place of the width
+        let width = match parts.get(count + 1) {
+            Some(_) if name != "text" => length(count + 1)?,
+            _ => 3,
+        };
+        match name.as_str() {
+            "line" => Ok(Shape::Line {
+                from: (number(0)?, number(1)?),
+                to: (number(2)?, number(3)?),
+                color,
+                width,
+            }),
+            "arrow" => Ok(Shape::Arrow {
+                from: (number(0)?, number(1)?),
+                to: (number(2)?, number(3)?),
+                color,
+                width,
+            }),
+            "rect" => Ok(Shape::Rect {
+                at: (number(0)?, number(1)?),
+                size: (length(2)?, length(3)?),
+                color,
+                width,
+            }),
+            "circle" => Ok(Shape::Circle {
+                center: (number(0)?, number(1)?),
+                radius: length(2)?,
+                color,
+                width,
+            }),
+            _ => Ok(Shape::Text {
+                at: (number(0)?, number(1)?),
+                text: unquote(parts[2]),
+                color,
+                size: match parts.get(4) {
+                    Some(size) => size
+                        .parse()
+                        .ok()
+                        .filter(|&size: &f32| size.is_finite() && size > 0.0)
+                        .ok_or_else(|| format!("Invalid font size in {}: {}", s, size))?,
+                    None => 24.0,
+                },
+            }),
+        }
+    }
+}
+
+/// What fills an image made by the create command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// A color or gradient
+    Fill(Fill),
+    /// Squares of `size` pixels in two alternating colors
+    Checkerboard { size: u32, colors: [Color; 2] },
+    /// Perlin noise with features about `scale` pixels wide, from the first color to the second
+    Noise { scale: f32, colors: [Color; 2] },
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let Some((name, args)) = lower
+            .split_once('(')
+            .and_then(|(name, rest)| Some((name.trim(), rest.strip_suffix(')')?)))
+            .filter(|(name, _)| matches!(*name, "checkerboard" | "noise"))
+        else {
+            return s.parse().map(Pattern::Fill);
+        };
+        let parts = split_top_level(args, ',')?;
+        let (first, colors) = match parts.as_slice() {
+            [first] => (first.trim(), None),
+            [first, a, b] => (first.trim(), Some([a.trim().parse()?, b.trim().parse()?])),
+            _ => {
+                return Err(format!(
+                    "Invalid pattern: {}. Expected {}(size) or {}(size,color1,color2)",
+                    s, name, name
+                ));
+            }
+        };
+        match name {
+            "checkerboard" => Ok(Pattern::Checkerboard {
+                size: first
+                    .parse()
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| format!("Invalid checkerboard size: {}", first))?,
+                colors: colors.unwrap_or([Color::White, Color::Rgba(204, 204, 204, 255)]),
+            }),
+            _ => Ok(Pattern::Noise {
+                scale: first
+                    .parse()
+                    .ok()
+                    .filter(|&scale: &f32| scale.is_finite() && scale > 0.0)
+                    .ok_or_else(|| format!("Invalid noise scale: {}", first))?,
+                colors: colors.unwrap_or([Color::Black, Color::White]),
+            }),
+        }
+    }
+}
+
+impl FromStr for Fill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let Some(args) = lower
+            .strip_prefix("gradient(")
+            .and_then(|args| args.strip_suffix(')'))
+        else {
+            return s.parse().map(Fill::Solid);
+        };
+        let parts = split_top_level(args, ',')?;
+        let (shape, colors) = match parts.as_slice() {
+            [shape, angle, colors @ ..] if shape.trim() == "linear" => {
+                let angle = angle
+                    .trim()
+                    .trim_end_matches("deg")
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|angle| angle.is_finite())
+                    .ok_or_else(|| format!("Invalid gradient angle: {}", angle.trim()))?;
+                (GradientShape::Linear(angle), colors)
+            }
+            [shape, colors @ ..] if shape.trim() == "radial" => (GradientShape::Radial, colors),
+            _ => {
+                return Err(format!(
+                    "Invalid gradient: {}. Expected gradient(linear,angle,color1,color2,...) or gradient(radial,color1,color2,...)",
+                    s
+                ));
+            }
+        };
+        if colors.len() < 2 {
+            return Err(format!("A gradient needs at least two colors: {}", s));
+        }
+        let colors = colors
+            .iter()
+            .map(|color| color.trim().parse::<Color>().map(Rgba::from))
+            .collect::<Result<_, _>>()?;
+        Ok(Fill::Gradient(Gradient { shape, colors }))
+    }
+}
+
+impl From<Color> for Rgba<u8> {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::White => Rgba([255, 255, 255, 255]),
+            Color::Black => Rgba([0, 0, 0, 255]),
+            Color::Red => Rgba([255, 0, 0, 255]),
+            Color::Green => Rgba([0, 255, 0, 255]),
+            Color::Blue => Rgba([0, 0, 255, 255]),
+            Color::Rgba(r, g, b, a) => Rgba([r, g, b, a]),
+        }
+    }
+}
+/// Check whether the input refers to several images (a directory or a pattern)
+pub fn is_batch_input(input: &Path) -> bool {
+    input.is_dir()
+        || input
+            .file_name()
+            .is_some_and(|name| is_pattern(&name.to_string_lossy()))
+}
+
+/// Resolve the input into the list of image files to process
+///
+/// Directories yield every file with a known image extension, and SVG
+/// documents with the svg feature. Patterns may use `*` and `?` in the file
+/// name. Results are sorted by path.
+pub fn collect_inputs(input: &Path) -> io::Result<Vec<PathBuf>> {
+    let (dir, pattern) = if input.is_dir() {
+        (input, None)
+    } else {
+        match input.file_name().map(|name| name.to_string_lossy()) {
+            Some(name) if is_pattern(&name) => {
+                let dir = input.parent().filter(|p| !p.as_os_str().is_empty());
+                (dir.unwrap_or(Path::new(".")), Some(name.to_lowercase()))
+            }
+            _ => return Ok(vec![input.to_path_buf()]),
+        }
+    };
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let svg = cfg!(feature = "svg")
+            && path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+        let raw = cfg!(feature = "raw") && process::has_raw_extension(&path);
+        let heic = cfg!(feature = "heic")
+            && path.extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("heic") || ext.eq_ignore_ascii_case("heif")
+            });
+        if !path.is_file() || ImageFormat::from_path(&path).is_err() && !svg && !raw && !heic {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if pattern.as_deref().is_none_or(|p| wildcard_match(p, &name)) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Match a name against a pattern where `*` matches any run of characters and
+/// `?` matches a single character
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last '*' consume one more character
+                Some((bp, bn)) => {
+                    backtrack = Some((bp, bn + 1));
+                    p = bp + 1;
+                    n = bn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A sequence of commands applied to the same in-memory image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline(pub Vec<Command>);
+
+/// A single pipeline step, parsed with the same rules as the command line
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+pub(crate) struct Step {
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
+
+/// Stack of the thread building the step parser, the generated code for the
+/// whole command tree needs a few MiB of it in unoptimized builds
+#[cfg(not(target_arch = "wasm32"))]
+const STEP_STACK_SIZE: usize = 16 << 20;
+
+impl Step {
+    /// The parser of a single step, built once and shared, so parsing steps
+    /// inside another parse takes little stack
+    ///
+    /// It is built on a thread of its own where threads can be spawned,
+    /// wasm32 has none and builds it in place.
+    pub(crate) fn parser() -> &'static clap::Command {
+        static PARSER: OnceLock<clap::Command> = OnceLock::new();
+        #[cfg(target_arch = "wasm32")]
+        let build = Step::command;
+        #[cfg(not(target_arch = "wasm32"))]
+        let build = || {
+            std::thread::Builder::new()
+                .stack_size(STEP_STACK_SIZE)
+                .spawn(Step::command)
+                .ok()
+                .and_then(|handle| handle.join().ok())
+                // Without a thread, build it in place like wasm32 does
+                .unwrap_or_else(Step::command)
+        };
+        PARSER.get_or_init(build)
+    }
+
+    /// Parse one step from its command line arguments
+    pub(crate) fn parse(args: &[String]) -> Result<Command, clap::Error> {
+        let mut parser = Self::parser().clone();
+        let matches = parser.try_get_matches_from_mut(args)?;
+        Self::from_arg_matches(&matches)
+            .map(|step| step.command)
+            .map_err(|e| e.format(&mut parser))
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut commands = Vec::new();
+        for step in split_top_level(s, '|')? {
+            let step = step.trim();
+            if step.is_empty() {
+                return Err("Empty pipeline step".to_string());
+            }
+
+            // Translate the step into command line arguments
+            let args = if is_call(step) {
+                let mut args = Vec::new();
+                expand_call(Step::parser(), step, &mut args)?;
+                args
+            } else {
+                split_args(step)?
+            };
+
+            let command = Step::parse(&args)
+                .map_err(|e| format!("Invalid pipeline step '{}': {}", step, e.render()))?;
+            if let Command::Pipeline { .. } = command {
+                return Err("Pipelines cannot be nested".to_string());
+            }
+            if let Command::Run { .. } = command {
+                return Err("Recipes can't be pipeline steps".to_string());
+            }
+            if command.is_report() {
+                return Err(format!("'{}' can't be used in a pipeline", step));
+            }
+            commands.push(command);
+        }
+
+        if commands.is_empty() {
+            return Err("Pipeline must contain at least one step".to_string());
+        }
+        Ok(Pipeline(commands))
+    }
+}
+
+/// Check whether a step is written as `name(...)`
+fn is_call(s: &str) -> bool {
+    match s.split_once('(') {
+        Some((name, _)) => {
+            let name = name.trim();
+            !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && s.ends_with(')')
+        }
+        None => false,
+    }
 }
 
-impl FromStr for Color {
-    type Err = String;
+/// Expand `name(a, b, key=value, flag)` into command line arguments for the
+/// subcommand `name` of `parent`
+fn expand_call(parent: &clap::Command, call: &str, args: &mut Vec<String>) -> Result<(), String> {
+    let (name, inner) = call
+        .split_once('(')
+        .map(|(name, inner)| (name.trim(), &inner[..inner.len() - 1]))
+        .ok_or_else(|| format!("Invalid step: {}", call))?;
+    let command = parent
+        .find_subcommand(name)
+        .ok_or_else(|| format!("Unknown command: {}", name))?;
+    args.push(command.get_name().to_string());
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "white" => Ok(Color::White),
-            "black" => Ok(Color::Black),
-            "red" => Ok(Color::Red),
-            "green" => Ok(Color::Green),
-            "blue" => Ok(Color::Blue),
-            s if s.starts_with("rgba(") && s.ends_with(')') => {
-                let content = &s[5..s.len() - 1];
-                let parts: Vec<&str> = content.split(',').map(|s| s.trim()).collect();
+    let options: Vec<_> = command
+        .get_arguments()
+        .filter(|a| a.get_id() != "help" && a.get_id() != "version")
+        .collect();
+    let mut used = Vec::new();
 
-                if parts.len() != 4 {
-                    return Err(format!(
-                        "Invalid RGBA format: {}. Expected rgba(r,g,b,a)",
-                        s
-                    ));
-                }
+    for token in split_top_level(inner, ',')? {
+        let token = token.trim();
+        if token.is_empty() {
+            continue;
+        }
 
-                let parse_component = |s: &str| -> Result<u8, String> {
-                    s.parse()
-                        .map_err(|_| format!("Invalid color component: {}", s))
-                };
+        // Named value: key=value
+        if let Some((key, value)) = token.split_once('=') {
+            let key = key.trim();
+            if let Some(option) = options.iter().find(|a| a.get_long() == Some(key)) {
+                args.push(format!("--{}={}", key, unquote(value.trim())));
+                used.push(option.get_id().clone());
+                continue;
+            }
+        }
 
-                let r = parse_component(parts[0])?;
-                let g = parse_component(parts[1])?;
-                let b = parse_component(parts[2])?;
-                let a = parse_component(parts[3])?;
+        // Flag given by name
+        if let Some(option) = options
+            .iter()
+            .find(|a| !a.get_action().takes_values() && a.get_long() == Some(token))
+        {
+            args.push(format!("--{}", token));
+            used.push(option.get_id().clone());
+            continue;
+        }
 
-                Ok(Color::Rgba(r, g, b, a))
+        // Nested subcommand, e.g. text(...) inside watermark(...)
+        if is_call(token) {
+            let name = token.split_once('(').map(|(n, _)| n.trim()).unwrap_or("");
+            if command.find_subcommand(name).is_some() {
+                expand_call(command, token, args)?;
+                continue;
             }
-            _ => Err(format!(
-                "Invalid color: {}. Expected one of: white, black, red, green, blue, rgba(r,g,b,a)",
-                s
-            )),
+        }
+
+        // Positional value: fill the next unused option that takes a value
+        let option = options
+            .iter()
+            .find(|a| a.get_action().takes_values() && !used.contains(a.get_id()))
+            .ok_or_else(|| format!("Too many arguments for {}: {}", name, token))?;
+        match option.get_long() {
+            Some(long) => args.push(format!("--{}={}", long, unquote(token))),
+            None => args.push(unquote(token)),
+        }
+        used.push(option.get_id().clone());
+    }
+
+    Ok(())
+}
+
+/// Split on `sep`, ignoring separators inside parentheses or quotes
+fn split_top_level(s: &str, sep: char) -> Result<Vec<&str>, String> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| format!("Unbalanced ')' in: {}", s))?;
+            }
+            (None, c) if c == sep && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+
+    if quote.is_some() {
+        return Err(format!("Unterminated quote in: {}", s));
+    }
+    if depth != 0 {
+        return Err(format!("Missing ')' in: {}", s));
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+/// Split a step written with command line syntax into arguments
+fn split_args(s: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(format!("Unterminated quote in: {}", s));
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Remove one pair of surrounding quotes
+fn unquote(s: &str) -> String {
+    for q in ['"', '\''] {
+        if s.len() >= 2 && s.starts_with(q) && s.ends_with(q) {
+            return s[1..s.len() - 1].to_string();
         }
     }
+    s.to_string()
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -450,61 +3673,81 @@ mod tests {
         // Test center crop
         assert_eq!(
             "center(100,200)".parse::<Crop>().unwrap(),
-            Crop::Center(100, 200)
+            Crop::Center(Length::Pixels(100), Length::Pixels(200))
         );
 
         // Test corner crops
         assert_eq!(
             "topleft(100,200)".parse::<Crop>().unwrap(),
-            Crop::TopLeft(100, 200)
+            Crop::TopLeft(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "topcenter(100,200)".parse::<Crop>().unwrap(),
-            Crop::TopCenter(100, 200)
+            Crop::TopCenter(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "topright(100,200)".parse::<Crop>().unwrap(),
-            Crop::TopRight(100, 200)
+            Crop::TopRight(Length::Pixels(100), Length::Pixels(200))
         );
 
         // Test middle positions
         assert_eq!(
             "middleleft(100,200)".parse::<Crop>().unwrap(),
-            Crop::MiddleLeft(100, 200)
+            Crop::MiddleLeft(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "middleright(100,200)".parse::<Crop>().unwrap(),
-            Crop::MiddleRight(100, 200)
+            Crop::MiddleRight(Length::Pixels(100), Length::Pixels(200))
         );
 
         // Test bottom positions
         assert_eq!(
             "bottomleft(100,200)".parse::<Crop>().unwrap(),
-            Crop::BottomLeft(100, 200)
+            Crop::BottomLeft(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "bottomcenter(100,200)".parse::<Crop>().unwrap(),
-            Crop::BottomCenter(100, 200)
+            Crop::BottomCenter(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "bottomright(100,200)".parse::<Crop>().unwrap(),
-            Crop::BottomRight(100, 200)
+            Crop::BottomRight(Length::Pixels(100), Length::Pixels(200))
         );
 
         // Test custom position crop
         assert_eq!(
             "custom(10,20,100,200)".parse::<Crop>().unwrap(),
-            Crop::Custom(10, 20, 100, 200)
+            Crop::Custom(
+                Length::Pixels(10),
+                Length::Pixels(20),
+                Length::Pixels(100),
+                Length::Pixels(200)
+            )
+        );
+
+        // Percentages of the image
+        assert_eq!(
+            "center(80%,80%)".parse::<Crop>().unwrap(),
+            Crop::Center(Length::Percent(80.0), Length::Percent(80.0))
+        );
+        assert_eq!(
+            "custom(10%,20,50%,12.5%)".parse::<Crop>().unwrap(),
+            Crop::Custom(
+                Length::Percent(10.0),
+                Length::Pixels(20),
+                Length::Percent(50.0),
+                Length::Percent(12.5)
+            )
         );
 
         // Test case insensitivity
         assert_eq!(
             "CENTER(100,200)".parse::<Crop>().unwrap(),
-            Crop::Center(100, 200)
+            Crop::Center(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "TopLeft(100,200)".parse::<Crop>().unwrap(),
-            Crop::TopLeft(100, 200)
+            Crop::TopLeft(Length::Pixels(100), Length::Pixels(200))
         );
     }
 
@@ -524,6 +3767,7 @@ mod tests {
         // Test invalid numbers
         assert!("center(abc,200)".parse::<Crop>().is_err());
         assert!("center(-100,200)".parse::<Crop>().is_err());
+        assert!("center(-10%,20%)".parse::<Crop>().is_err());
 
         // Test unknown crop position
         assert!("unknown(100,200)".parse::<Crop>().is_err());
@@ -538,11 +3782,16 @@ mod tests {
         // Test extra whitespace
         assert_eq!(
             "center ( 100 , 200 )".parse::<Crop>().unwrap(),
-            Crop::Center(100, 200)
+            Crop::Center(Length::Pixels(100), Length::Pixels(200))
         );
         assert_eq!(
             "custom( 10 , 20 , 100 , 200 )".parse::<Crop>().unwrap(),
-            Crop::Custom(10, 20, 100, 200)
+            Crop::Custom(
+                Length::Pixels(10),
+                Length::Pixels(20),
+                Length::Pixels(100),
+                Length::Pixels(200)
+            )
         );
     }
 
@@ -559,8 +3808,48 @@ mod tests {
             "RGBA(10, 20, 30, 40)".parse::<Color>().unwrap(),
             Color::Rgba(10, 20, 30, 40)
         ));
+        assert!(matches!(
+            "transparent".parse::<Color>().unwrap(),
+            Color::Rgba(0, 0, 0, 0)
+        ));
+
+        assert_eq!(
+            "#ff8000".parse::<Color>().unwrap(),
+            Color::Rgba(255, 128, 0, 255)
+        );
+        assert_eq!(
+            "#FF800080".parse::<Color>().unwrap(),
+            Color::Rgba(255, 128, 0, 128)
+        );
+        assert_eq!(
+            "rgb(1, 2, 3)".parse::<Color>().unwrap(),
+            Color::Rgba(1, 2, 3, 255)
+        );
+        assert_eq!(
+            "rgba(0,0,0,0.5)".parse::<Color>().unwrap(),
+            Color::Rgba(0, 0, 0, 128)
+        );
+        assert_eq!(
+            "hsl(120, 100%, 25%)".parse::<Color>().unwrap(),
+            Color::Rgba(0, 128, 0, 255)
+        );
+        assert_eq!(
+            "hsla(0deg,100%,50%,50%)".parse::<Color>().unwrap(),
+            Color::Rgba(255, 0, 0, 128)
+        );
+        assert_eq!(
+            "CornflowerBlue".parse::<Color>().unwrap(),
+            Color::Rgba(100, 149, 237, 255)
+        );
+        // The presets keep their values, green is pure green rather than CSS green
+        assert_eq!("green".parse::<Color>().unwrap(), Color::Green);
 
         assert!("invalid".parse::<Color>().is_err());
+        assert!("#12345".parse::<Color>().is_err());
+        assert!("rgb(1,2,3,4)".parse::<Color>().is_err());
+        assert!("hsl(0,100,50%)".parse::<Color>().is_err());
+        assert!("hsl(0,120%,50%)".parse::<Color>().is_err());
+        assert!("rgba(0,0,0,1.5)".parse::<Color>().is_err());
         assert!("rgba(256,0,0,0)".parse::<Color>().is_err());
         assert!("rgba(1,2,3)".parse::<Color>().is_err());
     }
@@ -647,4 +3936,561 @@ mod tests {
         assert!(Position::from_str("custom(1,b)").is_err());
         assert!(Position::from_str("custom(1,2,3)").is_err());
     }
+
+    #[test]
+    fn test_position_from_str_flat_lay() {
+        assert_eq!(
+            Position::from_str("flat-lay(20)").unwrap(),
+            Position::FlatLay(20, 20)
+        );
+        assert_eq!(
+            Position::from_str("flat-lay(10, 30)").unwrap(),
+            Position::FlatLay(10, 30)
+        );
+        assert!(Position::from_str("flat-lay()").is_err());
+        assert!(Position::from_str("flat-lay(1,2,3)").is_err());
+        assert!(Position::from_str("flat-lay(-1)").is_err());
+    }
+
+    #[test]
+    fn test_pipeline_call_syntax() {
+        let pipeline = "resize(800,600,lanczos3) | grayscale | blur(2.5, fast)"
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(
+            pipeline.0,
+            vec![
+                Command::Resize {
+                    width: Some(800),
+                    height: Some(600),
+                    exact: false,
+                    filter: Filter::Lanczos3,
+                    scale: None,
+                    fast: false,
+                },
+                Command::Grayscale,
+                Command::Blur {
+                    sigma: 2.5,
+                    fast: true,
+                },
+            ]
+        );
+
+        // Named and negative values
+        assert_eq!(
+            "brighten(value=-20)".parse::<Pipeline>().unwrap().0,
+            vec![Command::Brighten { value: -20 }]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_nested_subcommand() {
+        let pipeline = "watermark(custom(10,20), 45, text(\"Hello, world\", color=rgba(1,2,3,4)))"
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(
+            pipeline.0,
+            vec![Command::Watermark {
+                position: Position::Custom(10, 20),
+                rotate: 45.0,
+                margin: 20,
+                relative_scale: None,
+                row_offset: None,
+                stagger_angle: 0.0,
+                coverage: None,
+                command: Watermark::Text {
+                    text: "Hello, world".to_string(),
+                    font: None,
+                    scale: 50.0,
+                    color: Fill::Solid(Color::Rgba(1, 2, 3, 4)),
+                    opacity: 1.0,
+                    stroke_color: Color::Black,
+                    stroke_width: 0,
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_argument_syntax() {
+        let pipeline = "crop -c center(10,10) | watermark -p top-left image 'my mark.png'"
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(
+            pipeline.0,
+            vec![
+                Command::Crop {
+                    crop: Crop::Center(Length::Pixels(10), Length::Pixels(10)),
+                    focus: None,
+                    clamp: false,
+                    pad: None,
+                },
+                Command::Watermark {
+                    position: Position::TopLeft,
+                    rotate: 0.0,
+                    margin: 20,
+                    relative_scale: None,
+                    row_offset: None,
+                    stagger_angle: 0.0,
+                    coverage: None,
+                    command: Watermark::Image {
+                        image: PathBuf::from("my mark.png"),
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_errors() {
+        assert!("".parse::<Pipeline>().is_err());
+        assert!("grayscale |".parse::<Pipeline>().is_err());
+        assert!("unknown(1)".parse::<Pipeline>().is_err());
+        assert!("resize(800,600".parse::<Pipeline>().is_err());
+        assert!("resize(800,600,lanczos3,1)".parse::<Pipeline>().is_err());
+        assert!("brighten(abc)".parse::<Pipeline>().is_err());
+        assert!("pipeline(grayscale)".parse::<Pipeline>().is_err());
+        assert!("grayscale | exif".parse::<Pipeline>().is_err());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.jpg", "photo.jpg"));
+        assert!(wildcard_match("*.jpg", ".jpg"));
+        assert!(wildcard_match("img_??.png", "img_01.png"));
+        assert!(wildcard_match("*a*b*", "xxaxxbxx"));
+        assert!(wildcard_match("*", "anything"));
+
+        assert!(!wildcard_match("*.jpg", "photo.png"));
+        assert!(!wildcard_match("img_??.png", "img_1.png"));
+        assert!(!wildcard_match("*a*b", "xxaxxbxx"));
+    }
+
+    #[test]
+    fn test_collect_inputs() {
+        let dir = std::env::temp_dir().join(format!("imgtools-inputs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a.jpg", "b.JPG", "c.png", "notes.txt"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        // Directory: every image file
+        let files = collect_inputs(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![dir.join("a.jpg"), dir.join("b.JPG"), dir.join("c.png")]
+        );
+        assert!(is_batch_input(&dir));
+
+        // Pattern: case-insensitive match on the file name
+        let pattern = dir.join("*.jpg");
+        assert!(is_batch_input(&pattern));
+        assert_eq!(
+            collect_inputs(&pattern).unwrap(),
+            vec![dir.join("a.jpg"), dir.join("b.JPG")]
+        );
+
+        // Plain file
+        assert!(!is_batch_input(&dir.join("a.jpg")));
+        assert_eq!(
+            collect_inputs(&dir.join("a.jpg")).unwrap(),
+            vec![dir.join("a.jpg")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_image_format_round_trip() {
+        for format in [
+            Format::Png,
+            Format::Jpeg,
+            Format::WebP,
+            Format::Bmp,
+            Format::Avif,
+            Format::Tiff,
+            Format::Gif,
+            Format::Ico,
+        ] {
+            assert_eq!(Format::try_from(ImageFormat::from(format)), Ok(format));
+        }
+        assert!(Format::try_from(ImageFormat::Tga).is_err());
+    }
+
+    #[test]
+    fn test_scale_parsing() {
+        assert_eq!("50%".parse::<Scale>().unwrap(), Scale(0.5));
+        assert_eq!("0.25".parse::<Scale>().unwrap(), Scale(0.25));
+        assert_eq!(" 200 % ".parse::<Scale>().unwrap(), Scale(2.0));
+
+        assert!("0%".parse::<Scale>().is_err());
+        assert!("-50%".parse::<Scale>().is_err());
+        assert!("half".parse::<Scale>().is_err());
+    }
+
+    #[test]
+    fn test_byte_size_parsing() {
+        assert_eq!("200KB".parse::<ByteSize>().unwrap(), ByteSize(200_000));
+        assert_eq!("1.5 mb".parse::<ByteSize>().unwrap(), ByteSize(1_500_000));
+        assert_eq!("4KiB".parse::<ByteSize>().unwrap(), ByteSize(4096));
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("2GiB".parse::<ByteSize>().unwrap(), ByteSize(1 << 31));
+
+        assert!("0KB".parse::<ByteSize>().is_err());
+        assert!("-5KB".parse::<ByteSize>().is_err());
+        assert!("200TB".parse::<ByteSize>().is_err());
+        assert!("KB".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_command_steps_and_names() {
+        let pipeline: Pipeline = "grayscale | flip --horizontal".parse().unwrap();
+        let command = Command::Pipeline { steps: pipeline };
+        let names: Vec<_> = command.steps().iter().map(|step| step.name()).collect();
+        assert_eq!(names, ["grayscale", "flip"]);
+        assert_eq!(Command::Autoorient.steps(), [&Command::Autoorient]);
+    }
+
+    #[test]
+    fn test_input_help_names_the_inputless_commands() {
+        // The whole command tree takes more stack than a test thread has
+        let help = std::thread::Builder::new()
+            .stack_size(STEP_STACK_SIZE)
+            .spawn(|| {
+                let cli = Cli::command();
+                let input = cli.get_arguments().find(|arg| arg.get_id() == "input");
+                input.unwrap().get_long_help().unwrap().to_string()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(help.contains(INPUTLESS_COMMANDS), "{}", help);
+    }
+
+    #[test]
+    fn test_profile_parsing() {
+        assert_eq!("sRGB".parse::<Profile>().unwrap(), Profile::Srgb);
+        assert_eq!("display-p3".parse::<Profile>().unwrap(), Profile::DisplayP3);
+        assert_eq!("adobe-rgb".parse::<Profile>().unwrap(), Profile::AdobeRgb);
+        assert_eq!(
+            "profiles/Printer.ICC".parse::<Profile>().unwrap(),
+            Profile::File(PathBuf::from("profiles/Printer.ICC"))
+        );
+        assert!("rec2020".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn test_frame_range_parsing() {
+        assert_eq!("2..10".parse::<FrameRange>(), Ok(FrameRange(2, Some(10))));
+        assert_eq!("5..".parse::<FrameRange>(), Ok(FrameRange(5, None)));
+        assert_eq!("..3".parse::<FrameRange>(), Ok(FrameRange(0, Some(3))));
+        assert!("4..4".parse::<FrameRange>().is_err());
+        assert!("4".parse::<FrameRange>().is_err());
+        assert!("a..b".parse::<FrameRange>().is_err());
+
+        let range = FrameRange(2, Some(4));
+        assert!(!range.contains(1) && range.contains(2) && range.contains(3));
+        assert!(!range.contains(4));
+    }
+
+    #[test]
+    fn test_kernel_parsing() {
+        let sharpen = "kernel(0,-1,0,-1,5,-1,0,-1,0)".parse::<Kernel>().unwrap();
+        assert_eq!(sharpen.size(), 3);
+        assert_eq!(sharpen.default_divisor(), 1.0);
+        let blur = "1, 1, 1, 1, 1, 1, 1, 1, 1".parse::<Kernel>().unwrap();
+        assert_eq!(blur.default_divisor(), 9.0);
+        assert_eq!(
+            format!("kernel({})", ["1"; 25].join(","))
+                .parse::<Kernel>()
+                .unwrap()
+                .size(),
+            5
+        );
+
+        assert!("kernel(1,2,3,4)".parse::<Kernel>().is_err());
+        assert!("kernel(1,2,3,4,x,6,7,8,9)".parse::<Kernel>().is_err());
+
+        assert_eq!(
+            "convolve(kernel(0,-1,0,-1,5,-1,0,-1,0), offset=-10)"
+                .parse::<Pipeline>()
+                .unwrap()
+                .0,
+            vec![Command::Convolve {
+                kernel: sharpen,
+                divisor: None,
+                offset: -10.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_curve_parsing() {
+        let curve = Curve(vec![(0, 0), (128, 150), (255, 255)]);
+        assert_eq!(
+            "curve(0:0,128:150,255:255)".parse::<Curve>().unwrap(),
+            curve
+        );
+        assert_eq!("0:0, 128:150, 255:255".parse::<Curve>().unwrap(), curve);
+
+        assert!("curve(0:0)".parse::<Curve>().is_err());
+        assert!("curve(128:0,0:255)".parse::<Curve>().is_err());
+        assert!("curve(0:0,256:255)".parse::<Curve>().is_err());
+        assert!("curve(0,255)".parse::<Curve>().is_err());
+
+        // Curves contain commas and parentheses in pipeline steps
+        assert_eq!(
+            "adjust(gamma=1.2, curve=curve(0:0,128:150,255:255))"
+                .parse::<Pipeline>()
+                .unwrap()
+                .0,
+            vec![Command::Adjust {
+                gamma: 1.2,
+                exposure: 0.0,
+                saturation: 1.0,
+                red: 1.0,
+                green: 1.0,
+                blue: 1.0,
+                curve: Some(curve),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_length_parsing() {
+        assert_eq!("16".parse::<Length>().unwrap(), Length::Pixels(16));
+        assert_eq!("12.5%".parse::<Length>().unwrap(), Length::Percent(12.5));
+        assert_eq!(Length::Percent(10.0).pixels(300), 30.0);
+        assert_eq!(Length::Pixels(7).pixels(300), 7.0);
+
+        assert!("-3".parse::<Length>().is_err());
+        assert!("1.5".parse::<Length>().is_err());
+        assert!("-10%".parse::<Length>().is_err());
+    }
+
+    #[test]
+    fn test_fill_parsing() {
+        assert_eq!("red".parse::<Fill>().unwrap(), Fill::Solid(Color::Red));
+        assert_eq!(
+            "gradient(linear, 45deg, red, rgba(0,0,255,128))"
+                .parse::<Fill>()
+                .unwrap(),
+            Fill::Gradient(Gradient {
+                shape: GradientShape::Linear(45.0),
+                colors: vec![Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 128])],
+            })
+        );
+        assert_eq!(
+            "Gradient(radial,white,#000,gray)".parse::<Fill>().unwrap(),
+            Fill::Gradient(Gradient {
+                shape: GradientShape::Radial,
+                colors: vec![
+                    Rgba([255, 255, 255, 255]),
+                    Rgba([0, 0, 0, 255]),
+                    Rgba([128, 128, 128, 255])
+                ],
+            })
+        );
+
+        assert!("gradient(linear,45,red)".parse::<Fill>().is_err());
+        assert!("gradient(linear,red,blue)".parse::<Fill>().is_err());
+        assert!("gradient(conic,red,blue)".parse::<Fill>().is_err());
+        assert!("gradient(radial,red,bleu)".parse::<Fill>().is_err());
+    }
+
+    #[test]
+    fn test_pattern_parsing() {
+        assert_eq!(
+            "#112233".parse::<Pattern>().unwrap(),
+            Pattern::Fill(Fill::Solid(Color::Rgba(17, 34, 51, 255)))
+        );
+        assert!(matches!(
+            "gradient(radial,white,black)".parse::<Pattern>().unwrap(),
+            Pattern::Fill(Fill::Gradient(_))
+        ));
+        assert_eq!(
+            "checkerboard(16)".parse::<Pattern>().unwrap(),
+            Pattern::Checkerboard {
+                size: 16,
+                colors: [Color::White, Color::Rgba(204, 204, 204, 255)]
+            }
+        );
+        assert_eq!(
+            "Noise(32.5, navy, rgba(255,255,255,0))"
+                .parse::<Pattern>()
+                .unwrap(),
+            Pattern::Noise {
+                scale: 32.5,
+                colors: [Color::Rgba(0, 0, 128, 255), Color::Rgba(255, 255, 255, 0)]
+            }
+        );
+
+        assert!("checkerboard(0)".parse::<Pattern>().is_err());
+        assert!("checkerboard(8,white)".parse::<Pattern>().is_err());
+        assert!("noise(-1)".parse::<Pattern>().is_err());
+        assert!("stripes(8)".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_region_parsing() {
+        assert_eq!(
+            "10, 20,300,40".parse::<Region>().unwrap(),
+            Region {
+                x: 10,
+                y: 20,
+                width: 300,
+                height: 40,
+            }
+        );
+        assert!("10,20,0,40".parse::<Region>().is_err());
+        assert!("10,20,30".parse::<Region>().is_err());
+        assert!("-1,0,5,5".parse::<Region>().is_err());
+        assert_eq!("Blur".parse::<RedactMethod>(), Ok(RedactMethod::Blur));
+        assert!("mosaic".parse::<RedactMethod>().is_err());
+    }
+
+    #[test]
+    fn test_quality_check_parsing() {
+        let check = "blur<100".parse::<QualityCheck>().unwrap();
+        assert_eq!(
+            (check.metric, check.operator, check.value),
+            (QualityMetric::Blur, "<", 100.0)
+        );
+        assert!(check.matches(99.5) && !check.matches(100.0));
+        let check = " Noise >= 2.5".parse::<QualityCheck>().unwrap();
+        assert_eq!((check.metric, check.operator), (QualityMetric::Noise, ">="));
+        assert!(check.matches(2.5));
+        assert!("blur=100".parse::<QualityCheck>().is_err());
+        assert!("contrast<10".parse::<QualityCheck>().is_err());
+        assert!("overexposed>".parse::<QualityCheck>().is_err());
+    }
+
+    #[test]
+    fn test_white_balance_parsing() {
+        assert_eq!("12, 7".parse::<Point>(), Ok(Point { x: 12, y: 7 }));
+        assert!("12".parse::<Point>().is_err());
+        assert!("1,2,3".parse::<Point>().is_err());
+        assert_eq!(
+            "White-Patch".parse::<AutoBalance>(),
+            Ok(AutoBalance::WhitePatch)
+        );
+        assert_eq!(
+            "grayworld".parse::<AutoBalance>(),
+            Ok(AutoBalance::GrayWorld)
+        );
+        assert!("retinex".parse::<AutoBalance>().is_err());
+        assert_eq!("CLAHE".parse::<Equalization>(), Ok(Equalization::Clahe));
+        assert!("local".parse::<Equalization>().is_err());
+        assert_eq!("Drago".parse::<ToneMap>(), Ok(ToneMap::Drago));
+        assert!("aces".parse::<ToneMap>().is_err());
+    }
+
+    #[test]
+    fn test_shape_parsing() {
+        assert_eq!(
+            "rect(10, 20, 300, 40)".parse::<Shape>().unwrap(),
+            Shape::Rect {
+                at: (10, 20),
+                size: (300, 40),
+                color: Color::Red,
+                width: 3,
+            }
+        );
+        assert_eq!(
+            "Arrow(0,0,-5,50,rgb(0,0,255),6)".parse::<Shape>().unwrap(),
+            Shape::Arrow {
+                from: (0, 0),
+                to: (-5, 50),
+                color: Color::Rgba(0, 0, 255, 255),
+                width: 6,
+            }
+        );
+        assert_eq!(
+            "circle(5,5,10,black,0)".parse::<Shape>().unwrap(),
+            Shape::Circle {
+                center: (5, 5),
+                radius: 10,
+                color: Color::Black,
+                width: 0,
+            }
+        );
+        assert_eq!(
+            "text(1,2,\"Bug, here\",white,18.5)"
+                .parse::<Shape>()
+                .unwrap(),
+            Shape::Text {
+                at: (1, 2),
+                text: "Bug, here".into(),
+                color: Color::White,
+                size: 18.5,
+            }
+        );
+
+        assert!("rect(1,2,3)".parse::<Shape>().is_err());
+        assert!("rect(1,2,-3,4)".parse::<Shape>().is_err());
+        assert!("line(0,0,1,1,red,2,3)".parse::<Shape>().is_err());
+        assert!("text(0,0,hi,red,0)".parse::<Shape>().is_err());
+        assert!("star(0,0,5)".parse::<Shape>().is_err());
+        assert!("circle 5".parse::<Shape>().is_err());
+    }
+
+    #[test]
+    fn test_tint_mode_parsing() {
+        assert_eq!("Sepia".parse::<TintMode>().unwrap(), TintMode::Sepia);
+        assert_eq!("cool".parse::<TintMode>().unwrap(), TintMode::Cool);
+        assert_eq!(
+            "duotone(black, rgba(255,200,0,255))"
+                .parse::<TintMode>()
+                .unwrap(),
+            TintMode::Duotone(Color::Black, Color::Rgba(255, 200, 0, 255))
+        );
+
+        assert!("duotone(black)".parse::<TintMode>().is_err());
+        assert!("duotone(black,purplish)".parse::<TintMode>().is_err());
+        assert!("vintage".parse::<TintMode>().is_err());
+    }
+
+    #[test]
+    fn test_size_parsing() {
+        assert_eq!("1920x1080".parse::<Size>().unwrap(), Size(1920, 1080));
+        assert_eq!("64 X 32".parse::<Size>().unwrap(), Size(64, 32));
+        assert!("1920".parse::<Size>().is_err());
+        assert!("0x10".parse::<Size>().is_err());
+        assert!("ax10".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn test_resize_arguments() {
+        let pipeline = "resize -w 100 -f nearest | resize(scale=50%,filter=nearest)"
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(
+            pipeline.0,
+            vec![
+                Command::Resize {
+                    width: Some(100),
+                    height: None,
+                    exact: false,
+                    filter: Filter::Nearest,
+                    scale: None,
+                    fast: false,
+                },
+                Command::Resize {
+                    width: None,
+                    height: None,
+                    exact: false,
+                    filter: Filter::Nearest,
+                    scale: Some(Scale(0.5)),
+                    fast: false,
+                },
+            ]
+        );
+
+        assert!("resize -f nearest".parse::<Pipeline>().is_err());
+        assert!(
+            "resize -w 10 -s 50% -f nearest"
+                .parse::<Pipeline>()
+                .is_err()
+        );
+    }
 }
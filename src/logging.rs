@@ -0,0 +1,140 @@
+use crate::process::date;
+use crate::{ImgtoolsError, Processed};
+use clap::ValueEnum;
+use serde_json::{Map, Value, json};
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How log events are written to standard error
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain lines
+    #[default]
+    Text,
+    /// One JSON object per line, with a timestamp, level and fields
+    Json,
+}
+
+/// Importance of a log event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Debug,
+    Info,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Error => "error",
+        })
+    }
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Select how events are written, only the first call has an effect
+pub fn init_logging(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+/// Log a message
+pub fn log(level: Level, message: impl fmt::Display) {
+    log_with(level, message, Map::new());
+}
+
+/// Log a message with fields, which only JSON lines show
+pub(crate) fn log_with(level: Level, message: impl fmt::Display, fields: Map<String, Value>) {
+    match FORMAT.get().copied().unwrap_or_default() {
+        LogFormat::Text => eprintln!("{}", message),
+        LogFormat::Json => eprintln!("{}", json_line(level, &message.to_string(), fields)),
+    }
+}
+
+/// Log the outcome of processing one input
+///
+/// The event carries the input, the outputs, how long it took, the sizes
+/// before and after and whether it succeeded.
+pub fn log_file(input: &Path, duration: Duration, outcome: Result<&Processed, &ImgtoolsError>) {
+    let mut fields = Map::new();
+    fields.insert("input".into(), json!(input.display().to_string()));
+    fields.insert("duration_ms".into(), json!(duration.as_secs_f64() * 1000.0));
+    match outcome {
+        Ok(processed) => {
+            let outputs: Vec<_> = processed
+                .outputs
+                .iter()
+                .map(|written| written.path.display().to_string())
+                .collect();
+            let bytes_after: u64 = processed.outputs.iter().map(|written| written.bytes).sum();
+            let message = format!(
+                "{} -> {}: {} -> {} bytes in {:.2?}",
+                input.display(),
+                outputs.join(", "),
+                processed.bytes,
+                bytes_after,
+                duration
+            );
+            fields.insert("outcome".into(), json!("ok"));
+            fields.insert("outputs".into(), json!(outputs));
+            fields.insert("bytes_before".into(), json!(processed.bytes));
+            fields.insert("bytes_after".into(), json!(bytes_after));
+            log_with(Level::Info, message, fields);
+        }
+        Err(e) => {
+            fields.insert("outcome".into(), json!("error"));
+            fields.insert("error".into(), json!(e.to_string()));
+            log_with(Level::Error, format!("{}: {}", input.display(), e), fields);
+        }
+    }
+}
+
+/// One event as a JSON object, the fields follow the message
+fn json_line(level: Level, message: &str, fields: Map<String, Value>) -> Value {
+    let mut line = Map::new();
+    line.insert("timestamp".into(), json!(timestamp(SystemTime::now())));
+    line.insert("level".into(), json!(level.to_string()));
+    line.insert("message".into(), json!(message));
+    line.extend(fields);
+    Value::Object(line)
+}
+
+/// Point in time as RFC 3339 in UTC with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        date(time),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_760_000_000_123);
+        assert_eq!(timestamp(time), "2025-10-09T08:53:20.123Z");
+    }
+
+    #[test]
+    fn test_json_line() {
+        let mut fields = Map::new();
+        fields.insert("input".into(), json!("a.png"));
+        let line = json_line(Level::Info, "done", fields);
+        assert_eq!(line["level"], "info");
+        assert_eq!(line["message"], "done");
+        assert_eq!(line["input"], "a.png");
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
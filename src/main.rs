@@ -1,415 +1,418 @@
-use ab_glyph::{FontRef, PxScale};
 use clap::Parser;
-use image::codecs::avif::AvifEncoder;
-use image::codecs::bmp::BmpEncoder;
-use image::codecs::jpeg::JpegEncoder;
-use image::codecs::png::PngEncoder;
-use image::codecs::tiff::TiffEncoder;
-use image::codecs::webp::WebPEncoder;
-use image::imageops::overlay;
-use image::{ExtendedColorType, ImageBuffer, ImageEncoder, ImageReader, Rgba};
-use imageproc::drawing::{draw_text_mut, text_size};
-use imageproc::geometric_transformations::{Interpolation, rotate_about_center};
-use imgtools::{Cli, Color, Command, Crop, Format, Position, Rotate, Watermark};
-use std::f32::consts::PI;
-use std::fs::File;
-use std::io::{BufWriter, Read};
-use std::path::PathBuf;
+use imgtools::{
+    ByteSize, Cli, Command, Config, DecodeOptions, EncodeOptions, INPUTLESS_COMMANDS,
+    ImgtoolsError, Level, ProcessOptions, Processed, Watcher, collect_inputs, combine_files,
+    create_file, error_report, init_logging, is_batch_input, is_stdio, is_url, load_recipe, log,
+    log_file, plan, process_file, report_file,
+};
+use rayon::ThreadPoolBuilder;
+use rayon::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn main() {
-    // Parse command line arguments
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            log(Level::Error, &e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), ImgtoolsError> {
     let Cli {
         input,
         output,
+        input_format,
+        density,
+        render_size,
+        raw_white_balance,
+        raw_exposure,
+        keep_metadata,
+        auto_orient,
+        assume_profile,
+        convert_to,
+        strip,
+        keep,
+        progressive,
+        interlace,
+        dry_run,
+        verbose,
+        log_format,
+        output_template,
+        force,
+        no_clobber: _,
+        in_place,
+        backup,
+        jobs,
+        threads,
+        max_memory,
+        preset,
+        config,
+        error_report: report,
         command,
-    } = Cli::parse();
-
-    // Open and decode the input image
-    let mut img = match ImageReader::open(input.clone()) {
-        Ok(reader) => match reader.with_guessed_format() {
-            Ok(reader) => match reader.decode() {
-                Ok(img) => img,
-                Err(e) => {
-                    eprintln!("Failed to decode image: {}", e);
-                    return;
-                }
-            },
-            Err(e) => {
-                eprintln!("Failed to read image: {}", e);
-                return;
-            }
+    } = cli;
+    init_logging(log_format);
+    // Work within one image runs on a pool of --threads, in batch mode on the
+    // pool of --jobs workers below
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|e| ImgtoolsError::InvalidArgument(e.to_string()))?;
+    let options = ProcessOptions {
+        input_format,
+        keep_metadata,
+        auto_orient,
+        assume_profile,
+        convert_to,
+        dry_run,
+        verbose,
+        output_template,
+        counter: 1,
+        force,
+        in_place,
+        backup,
+        encoding: EncodeOptions {
+            progressive,
+            interlace,
+        },
+        strip: strip.then_some(keep),
+        max_memory: max_memory.map(|ByteSize(bytes)| bytes),
+        decoding: DecodeOptions {
+            density,
+            render_size,
+            white_balance: raw_white_balance,
+            exposure: raw_exposure,
+            max_alloc: None,
         },
-        Err(e) => {
-            eprintln!("Failed to open image: {}", e);
-            return;
-        }
     };
 
-    // Get image dimensions and color type
-    let width = img.width();
-    let height = img.height();
-    let color_type: ExtendedColorType = img.color().into();
+    // Watch the inputs and apply the recipe or preset to new images
+    let (watch, command) = match command {
+        Some(Command::Watch { recipe, interval }) => (
+            Some(Duration::from_millis(interval)),
+            recipe.map(|recipe| Command::Run { recipe }),
+        ),
+        command => (None, command),
+    };
 
-    // Extract input file name and paths
-    let input_file_name = match input.file_name() {
-        Some(name) => name,
-        None => {
-            eprintln!("Failed to get input file name");
-            return;
+    // Answer requests for transformed images of the inputs
+    #[cfg(feature = "serve")]
+    if let Some(Command::Serve { address }) = &command {
+        if preset.is_some() {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Serving takes its commands from the requests, not from a preset".into(),
+            ));
         }
-    };
-    let input_file_name = PathBuf::from(input_file_name);
-    let input_path = match input.parent() {
-        Some(path) => path.to_path_buf(),
-        None => {
-            eprintln!("Failed to get parent path");
-            return;
+        if input.is_empty() {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Give the images to serve with --input".into(),
+            ));
+        }
+        return imgtools::serve(address, &input, &options);
+    }
+
+    // Merge the preset with the command line, which wins over it
+    let (command, options) = match preset {
+        Some(name) => {
+            let path = config.or_else(Config::default_path).ok_or_else(|| {
+                ImgtoolsError::InvalidArgument(
+                    "Unable to find the config file, use --config to give it".into(),
+                )
+            })?;
+            Config::load(&path)?
+                .preset(&name)?
+                .apply(command, options)?
         }
+        None => (
+            command.ok_or_else(|| {
+                ImgtoolsError::InvalidArgument("Give a command or a --preset to run".into())
+            })?,
+            options,
+        ),
     };
-    let output_path = output.unwrap_or(input_path);
 
-    // Process the command
-    match command {
-        // Convert image to different format
-        Command::Convert { format } => {
-            let output = match output_path.is_dir() || output_path.as_os_str().is_empty() {
-                true => {
-                    let output_file_name = input_file_name.with_extension(format.to_string());
-                    output_path.with_file_name(output_file_name)
-                }
-                false => output_path,
-            };
-            let output = match File::create(output) {
-                Ok(file) => file,
-                Err(e) => {
-                    eprintln!("Failed to create output file: {}", e);
-                    return;
-                }
-            };
-            let mut output = BufWriter::new(output);
+    // Load the steps of a recipe
+    let command = match command {
+        Command::Run { recipe } => load_recipe(&recipe)?,
+        command => command,
+    };
 
-            // Handle different output formats
-            match format {
-                Format::Jpeg => {
-                    let mut encoder = JpegEncoder::new(output);
-                    if let Err(e) = encoder.encode(img.as_bytes(), width, height, color_type) {
-                        eprintln!("Failed to encode image: {}", e);
-                        return;
-                    }
-                }
-                Format::Png => {
-                    let encoder = PngEncoder::new(output);
-                    if let Err(e) = encoder.write_image(img.as_bytes(), width, height, color_type) {
-                        eprintln!("Failed to encode image: {}", e);
-                        return;
-                    }
-                }
-                Format::WebP => {
-                    let encoder = WebPEncoder::new_lossless(output);
-                    if let Err(e) = encoder.encode(img.as_bytes(), width, height, color_type) {
-                        eprintln!("Failed to encode image: {}", e);
-                        return;
-                    }
-                }
-                Format::Bmp => {
-                    let mut encoder = BmpEncoder::new(&mut output);
-                    if let Err(e) = encoder.encode(img.as_bytes(), width, height, color_type) {
-                        eprintln!("Failed to encode image: {}", e);
-                        return;
-                    }
-                }
-                Format::Avif => {
-                    let encoder = AvifEncoder::new(output);
-                    if let Err(e) = encoder.write_image(img.as_bytes(), width, height, color_type) {
-                        eprintln!("Failed to encode image: {}", e);
-                        return;
-                    }
-                }
-                Format::Tiff => {
-                    let encoder = TiffEncoder::new(output);
-                    if let Err(e) = encoder.encode(img.as_bytes(), width, height, color_type) {
-                        eprintln!("Failed to encode image: {}", e);
-                        return;
-                    }
-                }
-            }
-            return;
-        }
-        // Flip image horizontally and/or vertically
-        Command::Flip {
-            horizontal,
-            vertical,
-        } => {
-            img = match (horizontal, vertical) {
-                (true, true) => img.fliph().flipv(),
-                (true, false) => img.fliph(),
-                (false, true) => img.flipv(),
-                (false, false) => img,
-            };
-        }
-        // Rotate image by fixed angles
-        Command::Rotate { rotate } => {
-            img = match rotate {
-                Rotate::Rotate90 => img.rotate90(),
-                Rotate::Rotate180 => img.rotate180(),
-                Rotate::Rotate270 => img.rotate270(),
-            };
-        }
-        // Resize image with optional exact dimensions
-        Command::Resize {
-            width,
-            height,
-            exact,
-            filter,
-        } => {
-            img = match exact {
-                true => img.resize_exact(width, height, filter.into()),
-                false => img.resize(width, height, filter.into()),
-            };
-        }
-        // Convert image to grayscale
-        Command::Grayscale => {
-            img = img.grayscale();
-        }
-        // Apply blur effect
-        Command::Blur { sigma, fast } => {
-            img = match fast {
-                true => img.fast_blur(sigma),
-                false => img.blur(sigma),
-            };
-        }
-        // Adjust image brightness
-        Command::Brighten { value } => {
-            img = img.brighten(value);
+    // Create an image without reading one
+    if command.creates_image() {
+        if dry_run {
+            println!("{}", plan(&[], output.as_deref(), &command, &options)?);
+            return Ok(());
         }
-        // Rotate image hue
-        Command::Huerotate { value } => {
-            img = img.huerotate(value);
-        }
-        // Adjust image contrast
-        Command::Contrast { value } => {
-            img = img.adjust_contrast(value);
-        }
-        // Crop image with various positioning options
-        Command::Crop { crop } => {
-            let (x, y, w, h) = match crop {
-                Crop::Center(w, h) => {
-                    let x = (width - w) / 2;
-                    let y = (height - h) / 2;
-                    (x, y, w, h)
-                }
-                Crop::TopLeft(w, h) => (0, 0, w, h),
-                Crop::TopCenter(w, h) => {
-                    let x = (width - w) / 2;
-                    (x, 0, w, h)
-                }
-                Crop::TopRight(w, h) => {
-                    let x = width - w;
-                    (x, 0, w, h)
-                }
-                Crop::MiddleLeft(w, h) => {
-                    let y = (height - h) / 2;
-                    (0, y, w, h)
-                }
-                Crop::MiddleRight(w, h) => {
-                    let x = width - w;
-                    let y = (height - h) / 2;
-                    (x, y, w, h)
-                }
-                Crop::BottomLeft(w, h) => {
-                    let y = height - h;
-                    (0, y, w, h)
-                }
-                Crop::BottomCenter(w, h) => {
-                    let x = (width - w) / 2;
-                    let y = height - h;
-                    (x, y, w, h)
-                }
-                Crop::BottomRight(w, h) => {
-                    let x = width - w;
-                    let y = height - h;
-                    (x, y, w, h)
-                }
-                Crop::Custom(x, y, w, h) => (x, y, w, h),
-            };
+        let written = create_file(output.as_deref(), &command, &options)?;
+        log(
+            Level::Info,
+            format_args!(
+                "Created {}: {} bytes",
+                written.path.display(),
+                written.bytes
+            ),
+        );
+        return Ok(());
+    }
+    if input.is_empty() {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Give an image with --input, only {} work without one",
+            INPUTLESS_COMMANDS
+        )));
+    }
 
-            img = img.crop_imm(x, y, w, h);
+    if let Some(interval) = watch {
+        return watch_inputs(&input, output.as_deref(), &command, &options, interval);
+    }
+
+    // Combine every input into one image
+    if command.combines_inputs() {
+        let inputs = gather_inputs(&input)?;
+        if dry_run {
+            println!("{}", plan(&inputs, output.as_deref(), &command, &options)?);
+            return Ok(());
         }
-        // Invert image colors
-        Command::Invert => {
-            img.invert();
+        let written = combine_files(&inputs, output.as_deref(), &command, &options)?;
+        log(
+            Level::Info,
+            format_args!(
+                "Combined {} images into {}: {} bytes",
+                inputs.len(),
+                written.path.display(),
+                written.bytes
+            ),
+        );
+        return Ok(());
+    }
+
+    // Process a single image
+    if let [single] = input.as_slice()
+        && (is_stdio(single) || is_url(single) || !is_batch_input(single))
+    {
+        let start = Instant::now();
+        let result = run_file(single, output.as_deref(), &command, &options);
+        if let Some(report) = report.filter(|_| !dry_run) {
+            let failures: Vec<_> = result
+                .as_ref()
+                .err()
+                .map(|e| (single.as_path(), e))
+                .into_iter()
+                .collect();
+            write_error_report(&report, 1, &failures)?;
         }
-        // Apply unsharp mask
-        Command::Unsharpen { sigma, threshold } => {
-            img = img.unsharpen(sigma, threshold);
+        match result? {
+            Done::Printed(report) => println!("{}", report),
+            Done::Processed(processed) => log_file(single, start.elapsed(), Ok(&processed)),
         }
-        // Add watermark (text or image)
-        Command::Watermark {
-            position,
-            rotate,
-            margin,
-            command,
-        } => {
-            // Validate rotation angle
-            if !(0.0..=360.0).contains(&rotate) {
-                eprintln!(
-                    "Rotation value {} is out of valid range (0.0 to 360.0)",
-                    rotate
-                );
-                return;
-            }
+        return Ok(());
+    }
 
-            let rotate = rotate / 180.0 * PI;
+    // Process every matching image, results keep their file names
+    let inputs = gather_inputs(&input)?;
 
-            // Create watermark from text or image
-            let watermark = match command {
-                Watermark::Text {
-                    text,
-                    font,
-                    scale,
-                    color,
-                } => {
-                    // Load font data
-                    let font_data = match font {
-                        Some(f) => {
-                            let font = match File::open(f) {
-                                Ok(f) => f,
-                                Err(e) => {
-                                    eprintln!("Unable to open font file: {}", e);
-                                    return;
-                                }
-                            };
-                            match font.bytes().collect::<Result<Vec<u8>, _>>() {
-                                Ok(fd) => fd,
-                                Err(e) => {
-                                    eprintln!("Unable to read font file: {}", e);
-                                    return;
-                                }
-                            }
-                        }
-                        None => {
-                            let font_data = include_bytes!("../data/仿宋_GB2312.ttf").as_slice();
-                            font_data.to_vec()
-                        }
-                    };
+    // The output directory is only created for a real run, the separator marks it as one
+    let output = match dry_run {
+        true => output.map(|output| output.join("")),
+        false => output,
+    };
+    if let Some(output) = output.as_ref().filter(|_| !command.is_report() && !dry_run) {
+        if is_stdio(output) || output.exists() && !output.is_dir() {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Output must be a directory when processing multiple images".to_string(),
+            ));
+        }
+        fs::create_dir_all(output).map_err(|source| ImgtoolsError::Write {
+            path: output.clone(),
+            source,
+        })?;
+    }
 
-                    // Parse font
-                    let font = match FontRef::try_from_slice(&font_data) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            eprintln!("Unable to parse font file: {}", e);
-                            return;
-                        }
-                    };
+    // Process the images on a pool of workers, 0 uses one per CPU core
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|e| ImgtoolsError::InvalidArgument(e.to_string()))?;
+    let results: Vec<_> = pool.install(|| {
+        inputs
+            .par_iter()
+            .enumerate()
+            .map(|(i, file)| {
+                let options = ProcessOptions {
+                    counter: i + 1,
+                    ..options.clone()
+                };
+                let start = Instant::now();
+                let result = run_file(file, output.as_deref(), &command, &options);
+                (result, start.elapsed())
+            })
+            .collect()
+    });
 
-                    // Set text properties
-                    let scale = PxScale::from(scale);
-                    let color = match color {
-                        Color::White => Rgba([255, 255, 255, 255]),
-                        Color::Black => Rgba([0, 0, 0, 255]),
-                        Color::Red => Rgba([255, 0, 0, 255]),
-                        Color::Green => Rgba([0, 255, 0, 255]),
-                        Color::Blue => Rgba([0, 0, 255, 255]),
-                        Color::Rgba(r, g, b, a) => Rgba([r, g, b, a]),
-                    };
+    // Report in input order once every image is done
+    let mut failures = Vec::new();
+    for (file, (result, duration)) in inputs.iter().zip(&results) {
+        match result {
+            Ok(Done::Printed(plan)) if dry_run => println!("{}", plan),
+            Ok(Done::Printed(report)) => println!("{}:\n{}", file.display(), report),
+            Ok(Done::Processed(processed)) => log_file(file, *duration, Ok(processed)),
+            Err(e) => {
+                log_file(file, *duration, Err(e));
+                failures.push((file.as_path(), e));
+            }
+        }
+    }
+    let failed = failures.len();
+    log(
+        Level::Info,
+        format_args!(
+            "{} {} of {} images",
+            if dry_run { "Planned" } else { "Processed" },
+            inputs.len() - failed,
+            inputs.len()
+        ),
+    );
 
-                    // Create text watermark
-                    let (text_w, text_h) = text_size(scale, &font, &text);
-                    let diagonal = ((text_w.pow(2) + text_h.pow(2)) as f32).sqrt().ceil() as u32;
-                    let mut watermark = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(diagonal, diagonal);
-                    let center_x = diagonal / 2 - text_w / 2;
-                    let center_y = diagonal / 2 - text_h / 2;
-                    draw_text_mut(
-                        &mut watermark,
-                        color,
-                        center_x as i32,
-                        center_y as i32,
-                        scale,
-                        &font,
-                        &text,
-                    );
-                    watermark
-                }
-                // Load image watermark
-                Watermark::Image { image } => match ImageReader::open(&image) {
-                    Ok(reader) => match reader.with_guessed_format() {
-                        Ok(reader) => match reader.decode() {
-                            Ok(img) => img.into_rgba8(),
-                            Err(e) => {
-                                eprintln!("Failed to decode watermark image: {}", e);
-                                return;
-                            }
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to read watermark image: {}", e);
-                            return;
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("Failed to open watermark image: {}", e);
-                        return;
-                    }
-                },
-            };
+    if let Some(report) = report.filter(|_| !dry_run) {
+        write_error_report(&report, inputs.len(), &failures)?;
+    }
 
-            // Rotate watermark
-            let rotated = rotate_about_center(
-                &watermark,
-                rotate,
-                Interpolation::Nearest,
-                Rgba([0, 0, 0, 0]),
-            );
+    match failed {
+        0 => Ok(()),
+        failed => Err(ImgtoolsError::Batch {
+            failed,
+            total: inputs.len(),
+        }),
+    }
+}
 
-            let (w, h) = (rotated.width(), rotated.height());
-            // Handle flat lay pattern
-            if let Position::FlatLay(spacing) = position {
-                for y in (0..height).step_by(spacing) {
-                    for x in (0..width).step_by(spacing) {
-                        overlay(&mut img, &rotated, x as i64, y as i64);
-                    }
-                }
+/// Expand every input into the image files it names, in the given order
+fn gather_inputs(input: &[PathBuf]) -> Result<Vec<PathBuf>, ImgtoolsError> {
+    let mut inputs = Vec::new();
+    for path in input {
+        if is_stdio(path) || is_url(path) || !is_batch_input(path) {
+            inputs.push(path.clone());
+            continue;
+        }
+        let found = collect_inputs(path).map_err(|source| ImgtoolsError::Read {
+            path: path.clone(),
+            source,
+        })?;
+        if found.is_empty() {
+            return Err(ImgtoolsError::InvalidArgument(format!(
+                "No images found for {}",
+                path.display()
+            )));
+        }
+        inputs.extend(found);
+    }
+    Ok(inputs)
+}
 
-                // Save the processed image
-                let output = match output_path.is_dir() || output_path.as_os_str().is_empty() {
-                    true => output_path.with_file_name(input_file_name),
-                    false => output_path,
-                };
+/// Process new and modified images of the inputs until interrupted
+///
+/// Images already there are left alone, results of a modified image replace
+/// the earlier ones.
+fn watch_inputs(
+    input: &[PathBuf],
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+    interval: Duration,
+) -> Result<(), ImgtoolsError> {
+    let output = output.filter(|output| !is_stdio(output)).ok_or_else(|| {
+        ImgtoolsError::InvalidArgument("Watching needs an output directory".into())
+    })?;
+    if command.combines_inputs() || input.iter().any(|path| is_stdio(path)) {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Only commands that process images one by one can watch files".into(),
+        ));
+    }
+    fs::create_dir_all(output).map_err(|source| ImgtoolsError::Write {
+        path: output.to_path_buf(),
+        source,
+    })?;
 
-                if let Err(e) = img.save(output) {
-                    eprintln!("Failed to save image: {}", e);
-                    return;
-                }
+    // Results written next to the inputs would be picked up again
+    let output_dir = fs::canonicalize(output).ok();
+    for path in input {
+        let dir = match path.is_dir() {
+            true => path.as_path(),
+            false => path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+        };
+        if fs::canonicalize(dir).ok() == output_dir {
+            return Err(ImgtoolsError::InvalidArgument(
+                "The output directory must differ from the watched ones".into(),
+            ));
+        }
+    }
 
-                return;
+    let options = ProcessOptions {
+        force: true,
+        ..options.clone()
+    };
+    let list = || -> Vec<PathBuf> {
+        input
+            .iter()
+            .flat_map(|path| collect_inputs(path).unwrap_or_default())
+            .collect()
+    };
+    let mut watcher = Watcher::new(&list());
+    log(Level::Info, "Watching for new images, press Ctrl-C to stop");
+    loop {
+        thread::sleep(interval);
+        for file in watcher.poll(&list()) {
+            let start = Instant::now();
+            match run_file(&file, Some(output), command, &options) {
+                Ok(Done::Printed(report)) => println!("{}:\n{}", file.display(), report),
+                Ok(Done::Processed(processed)) => log_file(&file, start.elapsed(), Ok(&processed)),
+                Err(e) => log_file(&file, start.elapsed(), Err(&e)),
             }
-
-            // Position watermark
-            let (x, y) = match position {
-                Position::Center => ((width - w) / 2, (height - h) / 2),
-                Position::TopLeft => (margin, margin),
-                Position::TopCenter => ((width - w) / 2, margin),
-                Position::TopRight => (width - w - margin, margin),
-                Position::MiddleLeft => (margin, (height - h) / 2),
-                Position::MiddleRight => (width - w - margin, (height - h) / 2),
-                Position::BottomLeft => (margin, height - h - margin),
-                Position::BottomCenter => ((width - w) / 2, height - h - margin),
-                Position::BottomRight => (width - w - margin, height - h - margin),
-                Position::Custom(x, y) => (x, y),
-                Position::FlatLay(_) => unreachable!(),
-            };
-
-            overlay(&mut img, &rotated, x as i64, y as i64);
         }
     }
+}
 
-    // Save the processed image
-    let output = match output_path.is_dir() || output_path.as_os_str().is_empty() {
-        true => output_path.with_file_name(input_file_name),
-        false => output_path,
-    };
+/// Write the failures of a run to the --error-report file
+fn write_error_report(
+    path: &Path,
+    total: usize,
+    failures: &[(&Path, &ImgtoolsError)],
+) -> Result<(), ImgtoolsError> {
+    let json = error_report(total, failures).to_string() + "\n";
+    fs::write(path, json).map_err(|source| ImgtoolsError::Write {
+        path: path.to_path_buf(),
+        source,
+    })
+}
 
-    if let Err(e) = img.save(output) {
-        eprintln!("Failed to save image: {}", e);
-        return;
+/// What was done with one image
+enum Done {
+    /// The report of a reporting command or the dry-run plan, to print
+    Printed(String),
+    /// The image was processed and written, to log
+    Processed(Processed),
+}
+
+/// Process one image, or return the report of a reporting command or the dry-run plan
+fn run_file(
+    input: &Path,
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Done, ImgtoolsError> {
+    if options.dry_run {
+        let plan = plan(&[input.to_path_buf()], output, command, options)?;
+        return Ok(Done::Printed(plan.to_string()));
+    }
+    match command.is_report() {
+        true => report_file(input, command, options).map(Done::Printed),
+        false => process_file(input, output, command, options).map(Done::Processed),
     }
 }
@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Deref;
+
+/// Files smaller than this are read, mapping them costs more than it saves
+#[cfg(unix)]
+const MAP_THRESHOLD: u64 = 1 << 20;
+
+/// Contents of an input, mapped into memory instead of copied when large
+///
+/// Decoders read a mapped file straight from the page cache, so a large
+/// input isn't held in a buffer of its own next to the decoded image.
+pub(crate) enum InputData {
+    Owned(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(Mapping),
+}
+
+impl From<Vec<u8>> for InputData {
+    fn from(data: Vec<u8>) -> Self {
+        InputData::Owned(data)
+    }
+}
+
+impl Deref for InputData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputData::Owned(data) => data,
+            #[cfg(unix)]
+            InputData::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+impl AsRef<[u8]> for InputData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Map a large regular file, read small ones and those that can't be mapped
+pub(crate) fn map_file(mut file: File) -> io::Result<InputData> {
+    let metadata = file.metadata()?;
+    #[cfg(unix)]
+    if metadata.is_file()
+        && metadata.len() >= MAP_THRESHOLD
+        && let Ok(len) = usize::try_from(metadata.len())
+        && let Some(mapping) = Mapping::new(&file, len)
+    {
+        return Ok(InputData::Mapped(mapping));
+    }
+    let mut data = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(InputData::Owned(data))
+}
+
+/// A read-only private mapping of a whole file
+///
+/// The mapping stays valid after the file is closed. A file that another
+/// program truncates while it's mapped makes reading past its new end fault,
+/// which is why watch mode only hands out files whose size has settled.
+#[cfg(unix)]
+pub(crate) struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mapping {
+    fn new(file: &File, len: usize) -> Option<Self> {
+        use std::os::fd::AsRawFd;
+        // SAFETY: maps `len` bytes of an open file read-only, the result is
+        // checked before use and unmapped exactly once on drop
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        (ptr != libc::MAP_FAILED).then_some(Mapping { ptr, len })
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` readable bytes until it is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` are exactly what mmap returned and was given
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_map_file() {
+        let dir = std::env::temp_dir().join("imgtools-mapped");
+        fs::create_dir_all(&dir).unwrap();
+        let (small, large) = (dir.join("small.bin"), dir.join("large.bin"));
+        let content: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+        fs::write(&small, &content[..100]).unwrap();
+        fs::write(&large, &content).unwrap();
+        let small_data = map_file(File::open(&small).unwrap()).unwrap();
+        let large_data = map_file(File::open(&large).unwrap()).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(matches!(small_data, InputData::Owned(_)));
+        assert_eq!(&small_data[..], &content[..100]);
+        #[cfg(unix)]
+        assert!(matches!(large_data, InputData::Mapped(_)));
+        assert_eq!(&large_data[..], &content[..]);
+    }
+}
@@ -0,0 +1,617 @@
+use crate::animation::Animation;
+use crate::process::{decode, is_heif, is_raw, is_svg, unsupported_input};
+use crate::strip::{kept_exif, redact_exif, write_exif};
+use crate::tags::{set_exif, set_fields};
+use crate::{ExifAction, Format, ImgtoolsError, Keep, ReportFormat, TimeFuzz};
+use exif::{In, Tag};
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageFormat, ImageReader};
+use serde_json::json;
+use std::io::Cursor;
+
+/// Identifier that starts an XMP segment in JPEG files
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Keyword of the PNG text chunk that holds XMP
+pub(crate) const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// Metadata chunks that can be carried over from the input to the output
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Raw EXIF data in TIFF layout
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// ICC color profile
+    pub icc: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Read the metadata chunks of an encoded image
+    pub fn read(data: &[u8], format: Option<ImageFormat>) -> Result<Self, ImgtoolsError> {
+        // Rasterized SVG has no metadata to carry over
+        if format.is_none() && is_svg(data) {
+            return Ok(Metadata::default());
+        }
+        // Developed RAW files are sRGB, only the EXIF fields of the photo carry over
+        if format.is_none_or(|f| f == ImageFormat::Tiff) && is_raw(data) {
+            return Ok(Metadata {
+                exif: raw_exif(data)?,
+                ..Metadata::default()
+            });
+        }
+        // HEIF rotation is applied when decoding, the EXIF orientation is stale
+        if format.is_none() && is_heif(data) {
+            let mut metadata = Metadata {
+                exif: read_from_container(data)?.map(|exif| exif.buf().to_vec()),
+                icc: heif_icc(data)?,
+                ..Metadata::default()
+            };
+            metadata.clear_orientation();
+            return Ok(metadata);
+        }
+        let mut decoder = decoder(data, format)?;
+        Ok(Metadata {
+            exif: decoder.exif_metadata().map_err(ImgtoolsError::Decode)?,
+            xmp: decoder.xmp_metadata().map_err(ImgtoolsError::Decode)?,
+            icc: decoder.icc_profile().map_err(ImgtoolsError::Decode)?,
+        })
+    }
+
+    /// Check whether there is no metadata at all
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.icc.is_none()
+    }
+
+    /// Drop everything but what `keep` names, EXIF only keeps the orientation
+    pub fn strip(&mut self, keep: &[Keep]) {
+        self.xmp = None;
+        self.exif = self.exif.as_deref().and_then(|exif| kept_exif(exif, keep));
+        if !keep.contains(&Keep::Icc) {
+            self.icc = None;
+        }
+    }
+
+    /// Drop location, serial numbers, owner names and XMP, see `redact_metadata`
+    pub fn redact(&mut self, fuzz: Option<TimeFuzz>) -> Result<(), ImgtoolsError> {
+        self.xmp = None;
+        if let Some(exif) = &self.exif {
+            self.exif = Some(redact_exif(exif, fuzz)?);
+        }
+        Ok(())
+    }
+
+    /// Apply `exif redact` or `exif set` to the EXIF data
+    pub fn edit(&mut self, action: &ExifAction) -> Result<(), ImgtoolsError> {
+        match action {
+            ExifAction::Redact { fuzz_time } => self.redact(*fuzz_time),
+            ExifAction::Set {
+                artist,
+                copyright,
+                datetime,
+                tags,
+            } => {
+                let fields = set_fields(
+                    artist.as_deref(),
+                    copyright.as_deref(),
+                    datetime.as_ref(),
+                    tags,
+                );
+                self.exif = Some(set_exif(self.exif.as_deref(), &fields)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Mark the EXIF data as upright, after the orientation has been applied to the pixels
+    pub fn clear_orientation(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            let _ = Orientation::remove_from_exif_chunk(exif);
+        }
+    }
+}
+
+/// Read the orientation of an encoded image, usually from its EXIF data
+pub fn read_orientation(
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> Result<Orientation, ImgtoolsError> {
+    if format.is_none() && (is_svg(data) || is_heif(data)) {
+        return Ok(Orientation::NoTransforms);
+    }
+    if format.is_none_or(|f| f == ImageFormat::Tiff) && is_raw(data) {
+        let exif = raw_exif(data)?;
+        let orientation = exif.and_then(|exif| Orientation::from_exif_chunk(&exif));
+        return Ok(orientation.unwrap_or(Orientation::NoTransforms));
+    }
+    decoder(data, format)?
+        .orientation()
+        .map_err(ImgtoolsError::Decode)
+}
+
+/// Read the embedded ICC color profile of an encoded image
+pub fn read_icc(
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    let raw = format.is_none_or(|f| f == ImageFormat::Tiff) && is_raw(data);
+    if raw || format.is_none() && is_svg(data) {
+        return Ok(None);
+    }
+    if format.is_none() && is_heif(data) {
+        return heif_icc(data);
+    }
+    decoder(data, format)?
+        .icc_profile()
+        .map_err(ImgtoolsError::Decode)
+}
+
+#[cfg(feature = "heic")]
+fn heif_icc(data: &[u8]) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    crate::heic::icc(data)
+}
+
+#[cfg(not(feature = "heic"))]
+fn heif_icc(_: &[u8]) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    Ok(None)
+}
+
+/// Fields of IFD0 that describe how an image is stored rather than the photo
+pub(crate) const LAYOUT_TAGS: [Tag; 12] = [
+    Tag::ImageWidth,
+    Tag::ImageLength,
+    Tag::BitsPerSample,
+    Tag::Compression,
+    Tag::PhotometricInterpretation,
+    Tag::StripOffsets,
+    Tag::SamplesPerPixel,
+    Tag::RowsPerStrip,
+    Tag::StripByteCounts,
+    Tag::PlanarConfiguration,
+    Tag::TileOffsets,
+    Tag::TileByteCounts,
+];
+
+/// EXIF of a camera RAW file, the standard fields of the photo without
+/// those of the sensor data or the preview
+fn raw_exif(data: &[u8]) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    let exif = match exif::Reader::new().read_raw(data.to_vec()) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(ImgtoolsError::Metadata(e)),
+    };
+    let fields: Vec<_> = exif
+        .fields()
+        .filter(|field| {
+            field.ifd_num == In::PRIMARY
+                && field.tag.description().is_some()
+                && !LAYOUT_TAGS.contains(&field.tag)
+        })
+        .collect();
+    match fields.is_empty() {
+        true => Ok(None),
+        false => write_exif(fields, None, exif.little_endian()).map(Some),
+    }
+}
+
+fn decoder(
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> Result<impl ImageDecoder + '_, ImgtoolsError> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    match format {
+        Some(format) => reader.set_format(format),
+        None => {
+            reader = reader
+                .with_guessed_format()
+                .map_err(|e| ImgtoolsError::Decode(e.into()))?
+        }
+    }
+    reader.into_decoder().map_err(ImgtoolsError::Decode)
+}
+
+/// Add an XMP packet to an encoded JPEG or PNG
+///
+/// The image encoders can't write XMP, so the packet is inserted as a JPEG
+/// APP1 segment or a PNG iTXt chunk. Other formats are returned unchanged.
+pub fn insert_xmp(data: Vec<u8>, format: Format, xmp: &[u8]) -> Vec<u8> {
+    match format {
+        Format::Jpeg => insert_jpeg_xmp(data, xmp),
+        Format::Png => insert_png_xmp(data, xmp),
+        _ => data,
+    }
+}
+
+fn insert_jpeg_xmp(data: Vec<u8>, xmp: &[u8]) -> Vec<u8> {
+    let length = 2 + JPEG_XMP_HEADER.len() + xmp.len();
+    if !data.starts_with(&[0xFF, 0xD8]) || length > u16::MAX as usize {
+        return data;
+    }
+
+    // Keep the JFIF segment first if there is one
+    let mut at = 2;
+    if data.get(2..4) == Some(&[0xFF, 0xE0])
+        && let Some(len) = data.get(4..6)
+    {
+        at = 4 + u16::from_be_bytes([len[0], len[1]]) as usize;
+    }
+    if at > data.len() {
+        return data;
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(length as u16).to_be_bytes());
+    segment.extend_from_slice(JPEG_XMP_HEADER);
+    segment.extend_from_slice(xmp);
+
+    let mut result = data;
+    result.splice(at..at, segment);
+    result
+}
+
+fn insert_png_xmp(data: Vec<u8>, xmp: &[u8]) -> Vec<u8> {
+    // Signature (8) and the IHDR chunk (25) come first
+    const AFTER_IHDR: usize = 8 + 25;
+    if data.len() < AFTER_IHDR || &data[12..16] != b"IHDR" {
+        return data;
+    }
+
+    // iTXt: keyword, no compression, empty language and translated keyword
+    let mut chunk_data = PNG_XMP_KEYWORD.to_vec();
+    chunk_data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    chunk_data.extend_from_slice(xmp);
+
+    let mut chunk = (chunk_data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(b"iTXt");
+    chunk.extend_from_slice(&chunk_data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+    let mut result = data;
+    result.splice(AFTER_IHDR..AFTER_IHDR, chunk);
+    result
+}
+
+/// A single EXIF field prepared for display
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExifField {
+    /// Image file directory the field belongs to, e.g. primary or thumbnail
+    pub ifd: String,
+    /// Tag name
+    pub tag: String,
+    /// Human readable value including its unit
+    pub value: String,
+}
+
+/// Read every EXIF field of an encoded image, images without EXIF yield no fields
+pub fn read_exif(data: &[u8]) -> Result<Vec<ExifField>, ImgtoolsError> {
+    let Some(exif) = read_from_container(data)? else {
+        return Ok(Vec::new());
+    };
+
+    Ok(exif
+        .fields()
+        .map(|field| ExifField {
+            ifd: field.ifd_num.to_string(),
+            tag: field.tag.to_string(),
+            value: field.display_value().with_unit(&exif).to_string(),
+        })
+        .collect())
+}
+
+/// The EXIF data of a JPEG, PNG, WebP, TIFF or HEIF file, if it has any
+fn read_from_container(data: &[u8]) -> Result<Option<exif::Exif>, ImgtoolsError> {
+    match exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(exif) => Ok(Some(exif)),
+        Err(exif::Error::NotFound(_)) => Ok(None),
+        Err(e) => Err(ImgtoolsError::Metadata(e)),
+    }
+}
+
+/// Format EXIF fields as text lines or as a JSON object
+pub fn exif_report(fields: &[ExifField], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Text => fields
+            .iter()
+            .map(|f| format!("[{}] {}: {}", f.ifd, f.tag, f.value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        ReportFormat::Json => {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|f| json!({ "ifd": f.ifd, "tag": f.tag, "value": f.value }))
+                .collect();
+            json!({ "exif": fields }).to_string()
+        }
+    }
+}
+
+/// EXIF tags shown in the image info summary
+const EXIF_SUMMARY_TAGS: [&str; 10] = [
+    "Make",
+    "Model",
+    "LensModel",
+    "DateTimeOriginal",
+    "Orientation",
+    "ExposureTime",
+    "FNumber",
+    "PhotographicSensitivity",
+    "FocalLength",
+    "Software",
+];
+
+/// Basic properties of an encoded image, read without decoding the pixels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Format name, e.g. png or jpeg
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    /// Color type as stored in the file, e.g. Rgb8 or L1
+    pub color_type: String,
+    /// Bits per channel
+    pub bit_depth: u16,
+    /// Number of frames, more than one for animated GIF, WebP and PNG
+    pub frames: usize,
+    /// Size of the encoded data in bytes
+    pub file_size: usize,
+    /// A few common EXIF fields of the primary image
+    pub exif: Vec<ExifField>,
+}
+
+/// The primary image fields of the EXIF summary
+fn summary(fields: Vec<ExifField>) -> Vec<ExifField> {
+    fields
+        .into_iter()
+        .filter(|f| f.ifd == "primary" && EXIF_SUMMARY_TAGS.contains(&f.tag.as_str()))
+        .collect()
+}
+
+impl ImageInfo {
+    /// Read the properties of an encoded image
+    pub fn read(data: &[u8], format: Option<ImageFormat>) -> Result<Self, ImgtoolsError> {
+        let format = match format {
+            None | Some(ImageFormat::Tiff) if is_raw(data) => {
+                let exif = summary(read_exif(data)?);
+                return ImageInfo::read_decoded(data, "raw", exif);
+            }
+            Some(format) => format,
+            None if let Some(error) = unsupported_input(data) => return Err(error),
+            None if is_svg(data) => return ImageInfo::read_decoded(data, "svg", Vec::new()),
+            None if is_heif(data) => {
+                let exif = summary(read_exif(data)?);
+                return ImageInfo::read_decoded(data, "heic", exif);
+            }
+            None => image::guess_format(data).map_err(ImgtoolsError::Decode)?,
+        };
+        let decoder = decoder(data, Some(format))?;
+        let (width, height) = decoder.dimensions();
+        let color = decoder.original_color_type();
+
+        let frames = Animation::decode_animated(data, format)?.map_or(1, |a| a.frames.len());
+        // Only these containers are understood by the EXIF reader
+        let exif = match format {
+            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Tiff => {
+                summary(read_exif(data)?)
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(ImageInfo {
+            format: match Format::try_from(format) {
+                Ok(format) => format.to_string(),
+                Err(_) => format!("{:?}", format).to_lowercase(),
+            },
+            width,
+            height,
+            color_type: format!("{:?}", color),
+            bit_depth: color.bits_per_pixel() / color.channel_count().max(1) as u16,
+            frames,
+            file_size: data.len(),
+            exif,
+        })
+    }
+
+    /// Properties of an SVG document, camera RAW or HEIC file, with the size
+    /// it is decoded at by default
+    fn read_decoded(
+        data: &[u8],
+        format: &str,
+        exif: Vec<ExifField>,
+    ) -> Result<Self, ImgtoolsError> {
+        let img = decode(data, None)?;
+        let color = img.color();
+        Ok(ImageInfo {
+            format: format.into(),
+            width: img.width(),
+            height: img.height(),
+            color_type: format!("{:?}", color),
+            bit_depth: color.bits_per_pixel() / color.channel_count() as u16,
+            frames: 1,
+            file_size: data.len(),
+            exif,
+        })
+    }
+
+    /// Format the properties as text lines or as a JSON object
+    pub fn report(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => {
+                let mut lines = vec![
+                    format!("Format: {}", self.format),
+                    format!("Dimensions: {}x{}", self.width, self.height),
+                    format!("Color type: {}", self.color_type),
+                    format!("Bit depth: {}", self.bit_depth),
+                    format!("Frames: {}", self.frames),
+                    format!("File size: {} bytes", self.file_size),
+                ];
+                if !self.exif.is_empty() {
+                    lines.push("EXIF:".to_string());
+                    lines.extend(
+                        self.exif
+                            .iter()
+                            .map(|f| format!("  {}: {}", f.tag, f.value)),
+                    );
+                }
+                lines.join("\n")
+            }
+            ReportFormat::Json => {
+                let exif: serde_json::Map<_, _> = self
+                    .exif
+                    .iter()
+                    .map(|f| (f.tag.clone(), json!(f.value)))
+                    .collect();
+                json!({
+                    "format": self.format,
+                    "width": self.width,
+                    "height": self.height,
+                    "color_type": self.color_type,
+                    "bit_depth": self.bit_depth,
+                    "frames": self.frames,
+                    "file_size": self.file_size,
+                    "exif": exif,
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_with_metadata;
+    use image::DynamicImage;
+
+    /// Minimal little-endian EXIF block with Orientation = 6
+    fn exif_orientation() -> Vec<u8> {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend_from_slice(&8u32.to_le_bytes());
+        exif.extend_from_slice(&1u16.to_le_bytes());
+        // Tag 0x0112, SHORT, count 1, value 6
+        exif.extend_from_slice(&0x0112u16.to_le_bytes());
+        exif.extend_from_slice(&3u16.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&[6, 0, 0, 0]);
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        exif
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let img = DynamicImage::new_rgb8(4, 4);
+        let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec();
+        let metadata = Metadata {
+            exif: Some(exif_orientation()),
+            xmp: Some(xmp.clone()),
+            icc: None,
+        };
+
+        for format in [Format::Jpeg, Format::Png] {
+            let mut data = Cursor::new(Vec::new());
+            encode_with_metadata(&img, format, &metadata, &mut data).unwrap();
+            let data = data.into_inner();
+
+            let read = Metadata::read(&data, None).unwrap();
+            assert_eq!(read.exif, metadata.exif, "{}", format);
+            assert_eq!(read.xmp.as_deref(), Some(xmp.as_slice()), "{}", format);
+
+            let fields = read_exif(&data).unwrap();
+            assert!(
+                fields
+                    .iter()
+                    .any(|f| f.tag == "Orientation" && f.value.contains("row 0 at right")),
+                "{:?}",
+                fields
+            );
+        }
+    }
+
+    #[test]
+    fn test_orientation() {
+        let metadata = Metadata {
+            exif: Some(exif_orientation()),
+            ..Metadata::default()
+        };
+        let mut data = Cursor::new(Vec::new());
+        encode_with_metadata(
+            &DynamicImage::new_rgb8(4, 2),
+            Format::Jpeg,
+            &metadata,
+            &mut data,
+        )
+        .unwrap();
+        let data = data.into_inner();
+        assert_eq!(
+            read_orientation(&data, None).unwrap(),
+            Orientation::Rotate90
+        );
+
+        let mut metadata = Metadata::read(&data, None).unwrap();
+        metadata.clear_orientation();
+        let exif = metadata.exif.unwrap();
+        assert_eq!(
+            Orientation::from_exif_chunk(&exif),
+            Some(Orientation::NoTransforms)
+        );
+    }
+
+    #[test]
+    fn test_read_exif_without_metadata() {
+        let mut data = Cursor::new(Vec::new());
+        crate::encode(&DynamicImage::new_rgb8(2, 2), Format::Png, &mut data).unwrap();
+        let data = data.into_inner();
+        assert!(read_exif(&data).unwrap().is_empty());
+        assert!(Metadata::read(&data, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_exif_report() {
+        let fields = vec![ExifField {
+            ifd: "primary".to_string(),
+            tag: "Make".to_string(),
+            value: "\"Camera\"".to_string(),
+        }];
+        assert_eq!(
+            exif_report(&fields, ReportFormat::Text),
+            "[primary] Make: \"Camera\""
+        );
+        assert_eq!(
+            exif_report(&fields, ReportFormat::Json),
+            r#"{"exif":[{"ifd":"primary","tag":"Make","value":"\"Camera\""}]}"#
+        );
+    }
+
+    #[test]
+    fn test_image_info() {
+        let img = DynamicImage::new_rgba8(6, 4);
+        let metadata = Metadata {
+            exif: Some(exif_orientation()),
+            ..Metadata::default()
+        };
+        let mut data = Cursor::new(Vec::new());
+        encode_with_metadata(&img, Format::Png, &metadata, &mut data).unwrap();
+        let data = data.into_inner();
+
+        let info = ImageInfo::read(&data, None).unwrap();
+        assert_eq!(info.format, "png");
+        assert_eq!((info.width, info.height), (6, 4));
+        assert_eq!(info.color_type, "Rgba8");
+        assert_eq!(info.bit_depth, 8);
+        assert_eq!(info.frames, 1);
+        assert_eq!(info.file_size, data.len());
+        assert_eq!(info.exif.len(), 1);
+
+        let text = info.report(ReportFormat::Text);
+        assert!(
+            text.starts_with("Format: png\nDimensions: 6x4\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("EXIF:\n  Orientation: "), "{}", text);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&info.report(ReportFormat::Json)).unwrap();
+        assert_eq!(json["width"], 6);
+        assert_eq!(json["bit_depth"], 8);
+        assert!(json["exif"]["Orientation"].is_string());
+    }
+}
@@ -0,0 +1,1856 @@
+use crate::config::Config;
+use crate::{ImgtoolsError, ModelOutput, Size, TensorLayout};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Side of the squares the image is run through the model in, to bound memory
+const TILE: u32 = 192;
+/// Pixels of context added around each square, so tile edges don't show
+const TILE_PAD: u32 = 16;
+
+/// Environment variable naming the background removal model
+const BACKGROUND_MODEL_VAR: &str = "IMGTOOLS_BG_MODEL";
+/// Background removal model looked for next to the config file
+const BACKGROUND_MODEL_NAME: &str = "u2net.onnx";
+/// Mean and standard deviation of the ImageNet photos U2Net was trained on
+const IMAGENET_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const IMAGENET_STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+/// Enlarge the image with an ONNX super-resolution model, e.g. Real-ESRGAN
+///
+/// The model takes RGB values of 0.0 ~ 1.0 in NCHW layout and returns them
+/// at a whole multiple of the size. The image is run in tiles with some
+/// overlap, so large images fit in memory. Only the operators such models are
+/// made of are supported, in 32-bit floats.
+pub fn upscale_with_model(img: &DynamicImage, path: &Path) -> Result<RgbImage, ImgtoolsError> {
+    let model = load(path)?;
+    let rgb = img.to_rgb32f();
+    let (width, height) = rgb.dimensions();
+    let mut scale = None;
+    let mut out = RgbImage::new(0, 0);
+    for top in (0..height).step_by(TILE as usize) {
+        for left in (0..width).step_by(TILE as usize) {
+            let (x0, y0) = (left.saturating_sub(TILE_PAD), top.saturating_sub(TILE_PAD));
+            let x1 = (left + TILE + TILE_PAD).min(width);
+            let y1 = (top + TILE + TILE_PAD).min(height);
+            let (w, h) = ((x1 - x0) as usize, (y1 - y0) as usize);
+            let mut data = vec![0.0; 3 * w * h];
+            for y in 0..h {
+                for x in 0..w {
+                    let pixel = rgb.get_pixel(x0 + x as u32, y0 + y as u32);
+                    for c in 0..3 {
+                        data[(c * h + y) * w + x] = pixel[c];
+                    }
+                }
+            }
+            let input = Tensor::new(vec![1, 3, h, w], data);
+            let result = model.run(input).map_err(|e| invalid(path, e))?;
+            let s = match (result.shape.as_slice(), scale) {
+                ([1, 3, oh, ow], _) if oh % h != 0 || ow % w != 0 || oh / h != ow / w => {
+                    return Err(invalid(
+                        path,
+                        format!("gives {}x{} for a {}x{} input", ow, oh, w, h),
+                    ));
+                }
+                ([1, 3, oh, _], None) => {
+                    let s = oh / h;
+                    out = RgbImage::new(width * s as u32, height * s as u32);
+                    scale = Some(s);
+                    s
+                }
+                ([1, 3, oh, _], Some(s)) if oh / h == s => s,
+                (shape, _) => {
+                    return Err(invalid(
+                        path,
+                        format!("gives a {:?} tensor, not an RGB image", shape),
+                    ));
+                }
+            };
+            // Keep only the tile itself, the padding was there for context
+            let (ow, oh) = (w * s, h * s);
+            let tile_w = (TILE.min(width - left)) as usize * s;
+            let tile_h = (TILE.min(height - top)) as usize * s;
+            let (skip_x, skip_y) = ((left - x0) as usize * s, (top - y0) as usize * s);
+            for y in 0..tile_h {
+                for x in 0..tile_w {
+                    let (sx, sy) = (skip_x + x, skip_y + y);
+                    let value = |c: usize| {
+                        (result.data[(c * oh + sy) * ow + sx] * 255.0)
+                            .round()
+                            .clamp(0.0, 255.0) as u8
+                    };
+                    out.put_pixel(
+                        (left as usize * s + x) as u32,
+                        (top as usize * s + y) as u32,
+                        Rgb([value(0), value(1), value(2)]),
+                    );
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// How an image is fed to a model and its result read back
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inference {
+    /// ONNX model file
+    pub model: PathBuf,
+    /// Size the image is resized to for models that only take one
+    pub size: Option<Size>,
+    /// Largest input value, the pixel values are scaled to 0 ~ range
+    pub range: f32,
+    /// Subtracted from the red, green and blue values
+    pub mean: [f32; 3],
+    /// What the red, green and blue values are divided by after the mean
+    pub std: [f32; 3],
+    /// Channels in blue, green, red order
+    pub bgr: bool,
+    pub layout: TensorLayout,
+    pub output: ModelOutput,
+    /// Channel of the result a mask is read from
+    pub channel: usize,
+    /// Stretch the result from its lowest to its highest value
+    pub stretch: bool,
+}
+
+/// Run an image-to-image ONNX model, e.g. style transfer or background removal
+///
+/// The model gets the whole image as a batch of one RGB image and may return
+/// it at any size, which is resized back to the image. Image results keep the
+/// transparency of the input, masks are grayscale and alpha results are the
+/// input cut out by the mask.
+pub fn infer(img: &DynamicImage, inference: &Inference) -> Result<DynamicImage, ImgtoolsError> {
+    let path = &inference.model;
+    let model = load(path)?;
+    let (width, height) = img.dimensions();
+    let rgb = match inference.size {
+        Some(Size(w, h)) if (w, h) != (width, height) => {
+            img.resize_exact(w, h, FilterType::Triangle).to_rgb32f()
+        }
+        _ => img.to_rgb32f(),
+    };
+    let (w, h) = (rgb.width() as usize, rgb.height() as usize);
+    let order = |c: usize| if inference.bgr { 2 - c } else { c };
+    let mut data = vec![0.0; 3 * w * h];
+    for (i, pixel) in rgb.pixels().enumerate() {
+        for c in 0..3 {
+            let source = order(c);
+            let value =
+                (pixel[source] * inference.range - inference.mean[source]) / inference.std[source];
+            match inference.layout {
+                TensorLayout::Nchw => data[c * w * h + i] = value,
+                TensorLayout::Nhwc => data[i * 3 + c] = value,
+            }
+        }
+    }
+    let shape = match inference.layout {
+        TensorLayout::Nchw => vec![1, 3, h, w],
+        TensorLayout::Nhwc => vec![1, h, w, 3],
+    };
+    let result = model
+        .run(Tensor::new(shape, data))
+        .map_err(|e| invalid(path, e))?;
+
+    // Channel planes of the result, as 0 ~ 1
+    let (ow, oh, mut planes) = match (result.shape.as_slice(), inference.layout) {
+        ([1, _, oh, ow], TensorLayout::Nchw) => {
+            let planes = result.data.chunks(oh * ow).map(<[f32]>::to_vec).collect();
+            (*ow, *oh, planes)
+        }
+        ([1, oh, ow, c], TensorLayout::Nhwc) => {
+            let planes = (0..*c)
+                .map(|k| result.data.iter().skip(k).step_by(*c).copied().collect())
+                .collect();
+            (*ow, *oh, planes)
+        }
+        ([1, oh, ow] | [oh, ow], _) => (*ow, *oh, vec![result.data.clone()]),
+        (shape, _) => {
+            return Err(invalid(
+                path,
+                format!("gives a {:?} tensor, not an image", shape),
+            ));
+        }
+    };
+    planes = match inference.output {
+        ModelOutput::Image => match planes.len() {
+            1 => planes,
+            3 => (0..3).map(|c| planes[order(c)].clone()).collect(),
+            n => {
+                return Err(invalid(
+                    path,
+                    format!("gives {} channels, not a color or grayscale image", n),
+                ));
+            }
+        },
+        _ => match planes.get(inference.channel) {
+            Some(plane) => vec![plane.clone()],
+            None => {
+                return Err(invalid(
+                    path,
+                    format!(
+                        "gives {} channels, there is no channel {}",
+                        planes.len(),
+                        inference.channel
+                    ),
+                ));
+            }
+        },
+    };
+    let scale = match inference.output {
+        ModelOutput::Image => 1.0 / inference.range,
+        _ => 1.0,
+    };
+    let values = planes.iter().flatten();
+    let (low, high) = values.fold((f32::MAX, f32::MIN), |(low, high), &v| {
+        (low.min(v), high.max(v))
+    });
+    let level = |v: f32| {
+        let v = match inference.stretch && high > low {
+            true => (v - low) / (high - low),
+            false => v * scale,
+        };
+        (v * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    let (ow, oh) = (ow as u32, oh as u32);
+    let at = |plane: &[f32], x: u32, y: u32| level(plane[(y * ow + x) as usize]);
+    let result = match planes.as_slice() {
+        [gray] => {
+            DynamicImage::ImageLuma8(GrayImage::from_fn(ow, oh, |x, y| Luma([at(gray, x, y)])))
+        }
+        [r, g, b] => DynamicImage::ImageRgb8(RgbImage::from_fn(ow, oh, |x, y| {
+            Rgb([at(r, x, y), at(g, x, y), at(b, x, y)])
+        })),
+        _ => unreachable!("results are made one or three channels above"),
+    };
+    let result = match (ow, oh) == (width, height) {
+        true => result,
+        false => result.resize_exact(width, height, FilterType::CatmullRom),
+    };
+    Ok(match inference.output {
+        ModelOutput::Image if img.color().has_alpha() => {
+            let mut rgba = result.into_rgba8();
+            for (pixel, source) in rgba.pixels_mut().zip(img.to_rgba8().pixels()) {
+                pixel[3] = source[3];
+            }
+            DynamicImage::ImageRgba8(rgba)
+        }
+        ModelOutput::Image | ModelOutput::Mask => result,
+        ModelOutput::Alpha => {
+            let mut rgba = img.to_rgba8();
+            for (pixel, mask) in rgba.pixels_mut().zip(result.to_luma8().pixels()) {
+                pixel[3] = (pixel[3] as u16 * mask[0] as u16 / 255) as u8;
+            }
+            DynamicImage::ImageRgba8(rgba)
+        }
+    })
+}
+
+/// Cut out the subject of a photo with a U2Net-style segmentation model
+///
+/// The model is `model`, or the file `IMGTOOLS_BG_MODEL` names, or u2net.onnx
+/// next to the config file. It gets the image at `size` normalized like
+/// ImageNet and returns how likely each pixel is part of the subject, which
+/// becomes the alpha channel of the result.
+pub fn remove_background(
+    img: &DynamicImage,
+    model: Option<&Path>,
+    size: Size,
+) -> Result<DynamicImage, ImgtoolsError> {
+    let model = match model {
+        Some(path) => path.to_path_buf(),
+        None => background_model().ok_or_else(|| {
+            ImgtoolsError::InvalidArgument(format!(
+                "No background removal model found, pass one with -m, set {} to a U2Net ONNX file or put {} next to the config file",
+                BACKGROUND_MODEL_VAR, BACKGROUND_MODEL_NAME
+            ))
+        })?,
+    };
+    let inference = Inference {
+        model,
+        size: Some(size),
+        range: 1.0,
+        mean: IMAGENET_MEAN,
+        std: IMAGENET_STD,
+        bgr: false,
+        layout: TensorLayout::Nchw,
+        output: ModelOutput::Alpha,
+        channel: 0,
+        // The likelihoods rarely reach 0 and 1
+        stretch: true,
+    };
+    infer(img, &inference)
+}
+
+fn background_model() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(BACKGROUND_MODEL_VAR) {
+        return Some(PathBuf::from(path));
+    }
+    let path = Config::default_path()?.with_file_name(BACKGROUND_MODEL_NAME);
+    path.is_file().then_some(path)
+}
+
+fn invalid(path: &Path, message: String) -> ImgtoolsError {
+    ImgtoolsError::InvalidArgument(format!("Model {}: {}", path.display(), message))
+}
+
+/// Load the model, reusing the last one loaded while batches run it on every image
+fn load(path: &Path) -> Result<Arc<Model>, ImgtoolsError> {
+    static LAST: Mutex<Option<(PathBuf, Arc<Model>)>> = Mutex::new(None);
+    let mut last = LAST.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((loaded, model)) = last.as_ref()
+        && loaded == path
+    {
+        return Ok(model.clone());
+    }
+    let data = fs::read(path).map_err(|source| ImgtoolsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let model = Arc::new(Model::parse(&data).map_err(|e| invalid(path, e))?);
+    *last = Some((path.to_path_buf(), model.clone()));
+    Ok(model)
+}
+
+/// An n-dimensional array, integer tensors such as shapes are kept as floats too
+#[derive(Debug, Clone, PartialEq)]
+struct Tensor {
+    shape: Vec<usize>,
+    data: Vec<f32>,
+}
+
+impl Tensor {
+    fn new(shape: Vec<usize>, data: Vec<f32>) -> Self {
+        Tensor { shape, data }
+    }
+
+    /// The values as whole numbers, e.g. a shape or axes
+    fn ints(&self) -> Vec<i64> {
+        self.data.iter().map(|&v| v as i64).collect()
+    }
+
+    /// Distance in the data between neighbours along each axis
+    fn strides(&self) -> Vec<usize> {
+        let mut strides = vec![1; self.shape.len()];
+        for axis in (0..self.shape.len().saturating_sub(1)).rev() {
+            strides[axis] = strides[axis + 1] * self.shape[axis + 1];
+        }
+        strides
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Attribute {
+    Float(f32),
+    Int(i64),
+    Floats(Vec<f32>),
+    Ints(Vec<i64>),
+    Text(String),
+    Tensor(Tensor),
+}
+
+#[derive(Debug)]
+struct Node {
+    op: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    attributes: HashMap<String, Attribute>,
+}
+
+impl Node {
+    fn int(&self, name: &str, default: i64) -> i64 {
+        match self.attributes.get(name) {
+            Some(Attribute::Int(value)) => *value,
+            _ => default,
+        }
+    }
+
+    fn float(&self, name: &str, default: f32) -> f32 {
+        match self.attributes.get(name) {
+            Some(Attribute::Float(value)) => *value,
+            _ => default,
+        }
+    }
+
+    fn ints(&self, name: &str) -> Option<Vec<i64>> {
+        match self.attributes.get(name) {
+            Some(Attribute::Ints(values)) => Some(values.clone()),
+            _ => None,
+        }
+    }
+
+    fn text(&self, name: &str, default: &str) -> String {
+        match self.attributes.get(name) {
+            Some(Attribute::Text(value)) => value.clone(),
+            _ => default.to_string(),
+        }
+    }
+}
+
+/// The graph of an ONNX model, its nodes in the order they run
+#[derive(Debug)]
+struct Model {
+    nodes: Vec<Node>,
+    initializers: HashMap<String, Arc<Tensor>>,
+    input: String,
+    output: String,
+}
+
+impl Model {
+    /// Read a model from the protobuf encoding of ModelProto
+    fn parse(data: &[u8]) -> Result<Model, String> {
+        let mut graph = None;
+        let mut reader = Reader::new(data);
+        while let Some((field, value)) = reader.field()? {
+            if field == 7 {
+                graph = Some(value.bytes()?);
+            }
+        }
+        let graph = graph.ok_or("no graph in the file, is it an ONNX model?")?;
+
+        let (mut nodes, mut initializers) = (Vec::new(), HashMap::new());
+        let (mut inputs, mut outputs) = (Vec::new(), Vec::new());
+        let mut reader = Reader::new(graph);
+        while let Some((field, value)) = reader.field()? {
+            match field {
+                1 => nodes.push(parse_node(value.bytes()?)?),
+                5 => {
+                    let (name, tensor) = parse_tensor(value.bytes()?)?;
+                    initializers.insert(name, Arc::new(tensor));
+                }
+                11 => inputs.push(value_name(value.bytes()?)?),
+                12 => outputs.push(value_name(value.bytes()?)?),
+                _ => {}
+            }
+        }
+        // Older exports list the weights among the inputs too
+        let input = inputs
+            .into_iter()
+            .find(|name| !initializers.contains_key(name))
+            .ok_or("the graph has no input")?;
+        let output = outputs
+            .into_iter()
+            .next()
+            .ok_or("the graph has no output")?;
+        Ok(Model {
+            nodes,
+            initializers,
+            input,
+            output,
+        })
+    }
+
+    fn run(&self, input: Tensor) -> Result<Tensor, String> {
+        // Drop values after their last use, feature maps are large
+        let mut last_use = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            for name in &node.inputs {
+                last_use.insert(name.as_str(), i);
+            }
+        }
+        let mut values: HashMap<&str, Arc<Tensor>> = HashMap::new();
+        values.insert(&self.input, Arc::new(input));
+        for (i, node) in self.nodes.iter().enumerate() {
+            let inputs = node
+                .inputs
+                .iter()
+                .map(|name| match name.as_str() {
+                    // Optional inputs left out
+                    "" => Ok(None),
+                    name => match (values.get(name), self.initializers.get(name)) {
+                        (Some(value), _) => Ok(Some(value.clone())),
+                        (None, Some(tensor)) => Ok(Some(tensor.clone())),
+                        (None, None) => Err(format!("{} has no value", name)),
+                    },
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let output = run_node(node, &inputs)
+                .map_err(|e| format!("{} operator failed, {}", node.op, e))?;
+            drop(inputs);
+            for name in &node.inputs {
+                if last_use.get(name.as_str()) == Some(&i) && name != &self.output {
+                    values.remove(name.as_str());
+                }
+            }
+            if let Some(name) = node.outputs.first() {
+                values.insert(name.as_str(), Arc::new(output));
+            }
+        }
+        let output = values
+            .remove(self.output.as_str())
+            .ok_or("the output was never computed")?;
+        Ok(Arc::try_unwrap(output).unwrap_or_else(|shared| (*shared).clone()))
+    }
+}
+
+/// Run one operator on its inputs
+fn run_node(node: &Node, inputs: &[Option<Arc<Tensor>>]) -> Result<Tensor, String> {
+    let input = |i: usize| -> Result<&Tensor, String> {
+        inputs
+            .get(i)
+            .and_then(|input| input.as_deref())
+            .ok_or_else(|| format!("input {} is missing", i + 1))
+    };
+    let map = |f: &dyn Fn(f32) -> f32| -> Result<Tensor, String> {
+        let x = input(0)?;
+        Ok(Tensor::new(
+            x.shape.clone(),
+            x.data.iter().map(|&v| f(v)).collect(),
+        ))
+    };
+    match node.op.as_str() {
+        "Conv" => conv(
+            node,
+            input(0)?,
+            input(1)?,
+            inputs.get(2).and_then(|b| b.as_deref()),
+        ),
+        "ConvTranspose" => conv_transpose(
+            node,
+            input(0)?,
+            input(1)?,
+            inputs.get(2).and_then(|b| b.as_deref()),
+        ),
+        "MaxPool" | "AveragePool" => pool(node, input(0)?),
+        "GlobalAveragePool" => {
+            let x = input(0)?;
+            let [n, c, ..] = x.shape[..] else {
+                return Err("the input must have channels".into());
+            };
+            let data = x
+                .data
+                .chunks(x.data.len() / (n * c).max(1))
+                .map(|plane| plane.iter().sum::<f32>() / plane.len() as f32)
+                .collect();
+            let mut shape = vec![1; x.shape.len()];
+            shape[..2].copy_from_slice(&[n, c]);
+            Ok(Tensor::new(shape, data))
+        }
+        "InstanceNormalization" => {
+            let x = input(0)?;
+            let epsilon = node.float("epsilon", 1e-5);
+            normalize(x, |plane, c| {
+                let mean = plane.iter().sum::<f32>() / plane.len() as f32;
+                let variance =
+                    plane.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / plane.len() as f32;
+                Ok((
+                    mean,
+                    variance + epsilon,
+                    input(1)?.data[c],
+                    input(2)?.data[c],
+                ))
+            })
+        }
+        "BatchNormalization" => {
+            let x = input(0)?;
+            let epsilon = node.float("epsilon", 1e-5);
+            normalize(x, |_, c| {
+                Ok((
+                    input(3)?.data[c],
+                    input(4)?.data[c] + epsilon,
+                    input(1)?.data[c],
+                    input(2)?.data[c],
+                ))
+            })
+        }
+        "Pad" => pad(node, inputs),
+        "Softmax" => softmax(input(0)?, node.int("axis", -1)),
+        "Relu" => map(&|v| v.max(0.0)),
+        "LeakyRelu" => {
+            let alpha = node.float("alpha", 0.01);
+            map(&|v| if v < 0.0 { v * alpha } else { v })
+        }
+        "Sigmoid" => map(&|v| 1.0 / (1.0 + (-v).exp())),
+        "Tanh" => map(&f32::tanh),
+        "Clip" => {
+            let bound = |i: usize, name: &str, default: f32| match inputs.get(i) {
+                Some(Some(t)) => t.data[0],
+                _ => node.float(name, default),
+            };
+            let (min, max) = (bound(1, "min", f32::MIN), bound(2, "max", f32::MAX));
+            map(&|v| v.clamp(min, max))
+        }
+        "Exp" => map(&f32::exp),
+        "Sqrt" => map(&f32::sqrt),
+        "Abs" => map(&f32::abs),
+        "Neg" => map(&|v| -v),
+        "Identity" | "Cast" => Ok(input(0)?.clone()),
+        "Add" => broadcast(input(0)?, input(1)?, |a, b| a + b),
+        "Sub" => broadcast(input(0)?, input(1)?, |a, b| a - b),
+        "Mul" => broadcast(input(0)?, input(1)?, |a, b| a * b),
+        "Div" => broadcast(input(0)?, input(1)?, |a, b| a / b),
+        "Pow" => broadcast(input(0)?, input(1)?, f32::powf),
+        "Max" | "Min" => {
+            let f = match node.op.as_str() {
+                "Max" => f32::max,
+                _ => f32::min,
+            };
+            let mut values = inputs.iter().flatten();
+            let first = values.next().ok_or("input 1 is missing")?;
+            values.try_fold((**first).clone(), |result, x| broadcast(&result, x, f))
+        }
+        "PRelu" => broadcast(input(0)?, input(1)?, |v, slope| match v < 0.0 {
+            true => v * slope,
+            false => v,
+        }),
+        "Concat" => {
+            let parts: Vec<&Tensor> = inputs.iter().flatten().map(|t| t.as_ref()).collect();
+            concat(&parts, node.int("axis", 0))
+        }
+        "Constant" => match node.attributes.get("value") {
+            Some(Attribute::Tensor(tensor)) => Ok(tensor.clone()),
+            _ => match node.attributes.get("value_float") {
+                Some(Attribute::Float(v)) => Ok(Tensor::new(vec![], vec![*v])),
+                _ => Err("only tensor and float constants are supported".into()),
+            },
+        },
+        "Shape" => {
+            let x = input(0)?;
+            Ok(Tensor::new(
+                vec![x.shape.len()],
+                x.shape.iter().map(|&d| d as f32).collect(),
+            ))
+        }
+        "Gather" => {
+            let (x, indices) = (input(0)?, input(1)?);
+            if x.shape.len() != 1 || node.int("axis", 0) != 0 {
+                return Err("only gathering from a list is supported".into());
+            }
+            let data = indices
+                .ints()
+                .iter()
+                .map(|&i| {
+                    let i = if i < 0 { i + x.data.len() as i64 } else { i };
+                    x.data.get(i as usize).copied().ok_or("index out of range")
+                })
+                .collect::<Result<Vec<f32>, _>>()?;
+            Ok(Tensor::new(indices.shape.clone(), data))
+        }
+        "Unsqueeze" | "Squeeze" => {
+            let x = input(0)?;
+            let axes = match inputs.get(1) {
+                Some(Some(axes)) => Some(axes.ints()),
+                _ => node.ints("axes"),
+            };
+            let mut shape = x.shape.clone();
+            match (node.op.as_str(), axes) {
+                ("Unsqueeze", Some(mut axes)) => {
+                    let rank = (shape.len() + axes.len()) as i64;
+                    axes.iter_mut().for_each(|a| *a = (*a + rank) % rank);
+                    axes.sort();
+                    for a in axes {
+                        shape.insert(a as usize, 1);
+                    }
+                }
+                ("Squeeze", Some(axes)) => {
+                    let rank = shape.len() as i64;
+                    let axes: Vec<usize> =
+                        axes.iter().map(|a| ((a + rank) % rank) as usize).collect();
+                    shape = (0..shape.len())
+                        .filter(|a| !axes.contains(a))
+                        .map(|a| shape[a])
+                        .collect();
+                }
+                ("Squeeze", None) => shape.retain(|&d| d != 1),
+                _ => return Err("axes are missing".into()),
+            }
+            Ok(Tensor::new(shape, x.data.clone()))
+        }
+        "Reshape" => {
+            let x = input(0)?;
+            let mut shape = Vec::new();
+            let mut unknown = None;
+            for (i, d) in input(1)?.ints().into_iter().enumerate() {
+                match d {
+                    0 => shape.push(*x.shape.get(i).ok_or("0 past the input's dimensions")?),
+                    -1 => {
+                        unknown = Some(i);
+                        shape.push(1);
+                    }
+                    d => shape.push(d as usize),
+                }
+            }
+            if let Some(i) = unknown {
+                shape[i] = x.data.len() / shape.iter().product::<usize>().max(1);
+            }
+            if shape.iter().product::<usize>() != x.data.len() {
+                return Err(format!("can't reshape {:?} to {:?}", x.shape, shape));
+            }
+            Ok(Tensor::new(shape, x.data.clone()))
+        }
+        "Transpose" => {
+            let x = input(0)?;
+            let perm = node
+                .ints("perm")
+                .unwrap_or_else(|| (0..x.shape.len() as i64).rev().collect());
+            transpose(x, &perm.iter().map(|&p| p as usize).collect::<Vec<_>>())
+        }
+        "DepthToSpace" => {
+            let x = input(0)?;
+            let [n, c, h, w] = x.shape[..] else {
+                return Err("the input must have 4 dimensions".into());
+            };
+            let b = node.int("blocksize", 1) as usize;
+            let c = c / (b * b);
+            // Split the channels into blocks, then move the blocks next to the pixels
+            let (split, perm) = match node.text("mode", "DCR").as_str() {
+                "CRD" => (vec![n, c, b, b, h, w], [0, 1, 4, 2, 5, 3]),
+                _ => (vec![n, b, b, c, h, w], [0, 3, 4, 1, 5, 2]),
+            };
+            let moved = transpose(&Tensor::new(split, x.data.clone()), &perm)?;
+            Ok(Tensor::new(vec![n, c, h * b, w * b], moved.data))
+        }
+        "Resize" | "Upsample" => resize(node, inputs),
+        op => Err(format!("the {} operator isn't supported", op)),
+    }
+}
+
+/// 2D convolution of NCHW input with OIHW weights
+fn conv(
+    node: &Node,
+    x: &Tensor,
+    weights: &Tensor,
+    bias: Option<&Tensor>,
+) -> Result<Tensor, String> {
+    let [n, channels, h, w] = x.shape[..] else {
+        return Err("only 2D convolutions are supported".into());
+    };
+    let [out_channels, group_channels, kh, kw] = weights.shape[..] else {
+        return Err("the weights must have 4 dimensions".into());
+    };
+    let groups = node.int("group", 1) as usize;
+    if group_channels * groups != channels {
+        return Err(format!(
+            "{} input channels for weights of {}",
+            channels, group_channels
+        ));
+    }
+    let strides = node.ints("strides").unwrap_or(vec![1, 1]);
+    let dilations = node.ints("dilations").unwrap_or(vec![1, 1]);
+    let (sy, sx) = (strides[0] as usize, strides[1] as usize);
+    let (dy, dx) = (dilations[0] as usize, dilations[1] as usize);
+    let (extent_y, extent_x) = ((kh - 1) * dy + 1, (kw - 1) * dx + 1);
+    let pads = padding(node, (h, w), (sy, sx), (extent_y, extent_x));
+    let out_h = (h + pads[0] + pads[2] - extent_y) / sy + 1;
+    let out_w = (w + pads[1] + pads[3] - extent_x) / sx + 1;
+    let per_group = out_channels / groups;
+
+    let mut data = vec![0.0f32; n * out_channels * out_h * out_w];
+    data.par_chunks_mut(out_h * out_w)
+        .enumerate()
+        .for_each(|(index, out)| {
+            let (batch, oc) = (index / out_channels, index % out_channels);
+            out.fill(bias.map_or(0.0, |b| b.data[oc]));
+            let group = oc / per_group;
+            for ic in 0..group_channels {
+                let channel = batch * channels + group * group_channels + ic;
+                let plane = &x.data[channel * h * w..(channel + 1) * h * w];
+                for ky in 0..kh {
+                    for kx in 0..kw {
+                        let weight = weights.data[((oc * group_channels + ic) * kh + ky) * kw + kx];
+                        // Output columns whose input column falls inside the image
+                        let offset_x = (kx * dx) as i64 - pads[1] as i64;
+                        let first = (0..out_w).find(|&ox| (ox * sx) as i64 + offset_x >= 0);
+                        let last = (0..out_w).rfind(|&ox| ((ox * sx) as i64 + offset_x) < w as i64);
+                        let (Some(first), Some(last)) = (first, last) else {
+                            continue;
+                        };
+                        for oy in 0..out_h {
+                            let iy = (oy * sy + ky * dy) as i64 - pads[0] as i64;
+                            if iy < 0 || iy >= h as i64 {
+                                continue;
+                            }
+                            let row = &plane[iy as usize * w..(iy as usize + 1) * w];
+                            let out_row = &mut out[oy * out_w..(oy + 1) * out_w];
+                            let start = ((first * sx) as i64 + offset_x) as usize;
+                            let inputs = row[start..].iter().step_by(sx);
+                            for (value, input) in out_row[first..=last].iter_mut().zip(inputs) {
+                                *value += weight * input;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    Ok(Tensor::new(vec![n, out_channels, out_h, out_w], data))
+}
+
+/// 2D transposed convolution of NCHW input with IOHW weights, e.g. to upsample
+fn conv_transpose(
+    node: &Node,
+    x: &Tensor,
+    weights: &Tensor,
+    bias: Option<&Tensor>,
+) -> Result<Tensor, String> {
+    let [n, channels, h, w] = x.shape[..] else {
+        return Err("only 2D convolutions are supported".into());
+    };
+    let [weight_channels, per_group, kh, kw] = weights.shape[..] else {
+        return Err("the weights must have 4 dimensions".into());
+    };
+    if weight_channels != channels {
+        return Err(format!(
+            "{} input channels for weights of {}",
+            channels, weight_channels
+        ));
+    }
+    if node.ints("output_shape").is_some() {
+        return Err("output_shape isn't supported, only pads".into());
+    }
+    let groups = node.int("group", 1) as usize;
+    let group_channels = channels / groups;
+    let out_channels = per_group * groups;
+    let strides = node.ints("strides").unwrap_or(vec![1, 1]);
+    let dilations = node.ints("dilations").unwrap_or(vec![1, 1]);
+    let extra = node.ints("output_padding").unwrap_or(vec![0, 0]);
+    let pads = node.ints("pads").unwrap_or(vec![0; 4]);
+    let (sy, sx) = (strides[0] as usize, strides[1] as usize);
+    let (dy, dx) = (dilations[0] as usize, dilations[1] as usize);
+    let out_h = ((h - 1) * sy + (kh - 1) * dy + 1 + extra[0] as usize) as i64 - pads[0] - pads[2];
+    let out_w = ((w - 1) * sx + (kw - 1) * dx + 1 + extra[1] as usize) as i64 - pads[1] - pads[3];
+    if out_h <= 0 || out_w <= 0 {
+        return Err("the padding leaves nothing".into());
+    }
+    let (out_h, out_w) = (out_h as usize, out_w as usize);
+
+    let mut data = vec![0.0f32; n * out_channels * out_h * out_w];
+    data.par_chunks_mut(out_h * out_w)
+        .enumerate()
+        .for_each(|(index, out)| {
+            let (batch, oc) = (index / out_channels, index % out_channels);
+            out.fill(bias.map_or(0.0, |b| b.data[oc]));
+            let (group, goc) = (oc / per_group, oc % per_group);
+            for ic in group * group_channels..(group + 1) * group_channels {
+                let plane = &x.data[(batch * channels + ic) * h * w..][..h * w];
+                for ky in 0..kh {
+                    for kx in 0..kw {
+                        let weight = weights.data[((ic * per_group + goc) * kh + ky) * kw + kx];
+                        // Each input pixel adds to the output pixel it lands on
+                        for (iy, row) in plane.chunks(w).enumerate() {
+                            let oy = (iy * sy + ky * dy) as i64 - pads[0];
+                            if oy < 0 || oy >= out_h as i64 {
+                                continue;
+                            }
+                            for (ix, input) in row.iter().enumerate() {
+                                let ox = (ix * sx + kx * dx) as i64 - pads[1];
+                                if ox >= 0 && ox < out_w as i64 {
+                                    out[oy as usize * out_w + ox as usize] += weight * input;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    Ok(Tensor::new(vec![n, out_channels, out_h, out_w], data))
+}
+
+/// Largest or average value of the windows of a 2D pooling
+fn pool(node: &Node, x: &Tensor) -> Result<Tensor, String> {
+    let [n, c, h, w] = x.shape[..] else {
+        return Err("only 2D pooling is supported".into());
+    };
+    let Some([kh, kw]) = node.ints("kernel_shape").as_deref().map(|k| match k {
+        [kh, kw] => [*kh as usize, *kw as usize],
+        _ => [0, 0],
+    }) else {
+        return Err("kernel_shape is missing".into());
+    };
+    if kh == 0 || kw == 0 {
+        return Err("only 2D kernels are supported".into());
+    }
+    let strides = node.ints("strides").unwrap_or(vec![1, 1]);
+    let (sy, sx) = (strides[0] as usize, strides[1] as usize);
+    let pads = padding(node, (h, w), (sy, sx), (kh, kw));
+    let size = |size: usize, before: usize, after: usize, kernel: usize, stride: usize| {
+        let room = (size + before + after).saturating_sub(kernel);
+        match node.int("ceil_mode", 0) {
+            0 => room / stride + 1,
+            // The last window must start inside the image or the padding before it
+            _ => match room.div_ceil(stride) {
+                steps if steps * stride >= size + before => steps,
+                steps => steps + 1,
+            },
+        }
+    };
+    let out_h = size(h, pads[0], pads[2], kh, sy);
+    let out_w = size(w, pads[1], pads[3], kw, sx);
+    let max = node.op == "MaxPool";
+    let include_pad = node.int("count_include_pad", 0) != 0;
+    let mut data = Vec::with_capacity(n * c * out_h * out_w);
+    for plane in x.data.chunks(h * w) {
+        for oy in 0..out_h {
+            let top = (oy * sy) as i64 - pads[0] as i64;
+            let rows = top.max(0) as usize..((top + kh as i64).max(0) as usize).min(h);
+            for ox in 0..out_w {
+                let left = (ox * sx) as i64 - pads[1] as i64;
+                let columns = left.max(0) as usize..((left + kw as i64).max(0) as usize).min(w);
+                let values = rows
+                    .clone()
+                    .flat_map(|y| plane[y * w..][columns.clone()].iter());
+                data.push(match max {
+                    true => values.fold(f32::MIN, |a, &b| a.max(b)),
+                    false => {
+                        let count = match include_pad {
+                            // Windows hanging past the padding still count only up to it
+                            true => {
+                                let bottom = (top + kh as i64).min((h + pads[2]) as i64);
+                                let right = (left + kw as i64).min((w + pads[3]) as i64);
+                                ((bottom - top) * (right - left)) as usize
+                            }
+                            false => rows.len() * columns.len(),
+                        };
+                        values.sum::<f32>() / count.max(1) as f32
+                    }
+                });
+            }
+        }
+    }
+    Ok(Tensor::new(vec![n, c, out_h, out_w], data))
+}
+
+/// Normalize each channel plane, from its mean, variance, scale and bias
+fn normalize(
+    x: &Tensor,
+    statistics: impl Fn(&[f32], usize) -> Result<(f32, f32, f32, f32), String>,
+) -> Result<Tensor, String> {
+    let [n, c, ..] = x.shape[..] else {
+        return Err("the input must have channels".into());
+    };
+    let size = x.data.len() / (n * c).max(1);
+    let mut data = Vec::with_capacity(x.data.len());
+    for (i, plane) in x.data.chunks(size).enumerate() {
+        let (mean, variance, scale, bias) =
+            statistics(plane, i % c).map_err(|_| "scale, bias or statistics are too short")?;
+        let factor = scale / variance.sqrt();
+        data.extend(plane.iter().map(|v| (v - mean) * factor + bias));
+    }
+    Ok(Tensor::new(x.shape.clone(), data))
+}
+
+/// Add constant, mirrored or repeated edge values around each axis
+fn pad(node: &Node, inputs: &[Option<Arc<Tensor>>]) -> Result<Tensor, String> {
+    let x = inputs
+        .first()
+        .and_then(|x| x.as_deref())
+        .ok_or("the input is missing")?;
+    // Newer versions take the pads and value as inputs, older as attributes
+    let pads = match inputs.get(1) {
+        Some(Some(pads)) => pads.ints(),
+        _ => node.ints("pads").ok_or("pads are missing")?,
+    };
+    let value = match inputs.get(2) {
+        Some(Some(value)) => value.data.first().copied().unwrap_or(0.0),
+        _ => node.float("value", 0.0),
+    };
+    let rank = x.shape.len();
+    if pads.len() != 2 * rank {
+        return Err(format!("{} pads for {} dimensions", pads.len(), rank));
+    }
+    let shape = (0..rank)
+        .map(|a| x.shape[a] as i64 + pads[a] + pads[a + rank])
+        .map(|d| usize::try_from(d).map_err(|_| "the padding leaves nothing"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mode = node.text("mode", "constant");
+    let (source, strides) = (
+        x.strides(),
+        Tensor::new(shape.clone(), Vec::new()).strides(),
+    );
+    let total: usize = shape.iter().product();
+    let data = (0..total)
+        .map(|i| {
+            let mut index = 0;
+            for axis in 0..rank {
+                let size = x.shape[axis] as i64;
+                let v = (i / strides[axis] % shape[axis]) as i64 - pads[axis];
+                let v = match mode.as_str() {
+                    _ if (0..size).contains(&v) => v,
+                    "reflect" if size > 1 => {
+                        let period = 2 * (size - 1);
+                        let v = v.rem_euclid(period);
+                        if v < size { v } else { period - v }
+                    }
+                    "reflect" | "edge" => v.clamp(0, size - 1),
+                    _ => return value,
+                };
+                index += v as usize * source[axis];
+            }
+            x.data[index]
+        })
+        .collect();
+    Ok(Tensor::new(shape, data))
+}
+
+/// Exponentials along `axis` divided by their sum
+fn softmax(x: &Tensor, axis: i64) -> Result<Tensor, String> {
+    let rank = x.shape.len() as i64;
+    if !(-rank..rank).contains(&axis) {
+        return Err(format!("no axis {} in {:?}", axis, x.shape));
+    }
+    let axis = axis.rem_euclid(rank) as usize;
+    let (size, inner) = (x.shape[axis], x.shape[axis + 1..].iter().product::<usize>());
+    let mut data = x.data.clone();
+    for block in data.chunks_mut(size * inner) {
+        for i in 0..inner {
+            let largest = (0..size).fold(f32::MIN, |m, k| m.max(block[k * inner + i]));
+            let mut sum = 0.0;
+            for k in 0..size {
+                let v = &mut block[k * inner + i];
+                *v = (*v - largest).exp();
+                sum += *v;
+            }
+            for k in 0..size {
+                block[k * inner + i] /= sum;
+            }
+        }
+    }
+    Ok(Tensor::new(x.shape.clone(), data))
+}
+
+/// Padding above, left, below and right of a window sliding over an h x w plane
+fn padding(
+    node: &Node,
+    (h, w): (usize, usize),
+    (sy, sx): (usize, usize),
+    (extent_y, extent_x): (usize, usize),
+) -> Vec<usize> {
+    match node.text("auto_pad", "NOTSET").as_str() {
+        "SAME_UPPER" | "SAME_LOWER" => {
+            let total = |size: usize, stride: usize, extent: usize| {
+                ((size.div_ceil(stride) - 1) * stride + extent).saturating_sub(size)
+            };
+            let (ty, tx) = (total(h, sy, extent_y), total(w, sx, extent_x));
+            match node.text("auto_pad", "").as_str() {
+                "SAME_UPPER" => vec![ty / 2, tx / 2, ty - ty / 2, tx - tx / 2],
+                _ => vec![ty - ty / 2, tx - tx / 2, ty / 2, tx / 2],
+            }
+        }
+        "VALID" => vec![0; 4],
+        _ => node
+            .ints("pads")
+            .unwrap_or(vec![0; 4])
+            .iter()
+            .map(|&p| p as usize)
+            .collect(),
+    }
+}
+
+/// Apply `f` to the values of both tensors, with numpy broadcasting
+fn broadcast(a: &Tensor, b: &Tensor, f: impl Fn(f32, f32) -> f32) -> Result<Tensor, String> {
+    let rank = a.shape.len().max(b.shape.len());
+    let padded = |t: &Tensor| {
+        let mut shape = vec![1; rank - t.shape.len()];
+        shape.extend(&t.shape);
+        shape
+    };
+    let (sa, sb) = (padded(a), padded(b));
+    let shape = sa
+        .iter()
+        .zip(&sb)
+        .map(|(&x, &y)| match (x, y) {
+            (x, y) if x == y || y == 1 => Ok(x),
+            (1, y) => Ok(y),
+            _ => Err(format!("can't broadcast {:?} with {:?}", a.shape, b.shape)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if sa == sb {
+        return Ok(Tensor::new(
+            shape,
+            a.data.iter().zip(&b.data).map(|(&x, &y)| f(x, y)).collect(),
+        ));
+    }
+    let out = Tensor::new(shape, Vec::new());
+    // Broadcast axes don't move through the smaller tensor
+    let steps = |padded: &[usize]| {
+        let strides = Tensor::new(padded.to_vec(), Vec::new()).strides();
+        padded
+            .iter()
+            .zip(strides)
+            .map(|(&d, s)| if d == 1 { 0 } else { s })
+            .collect::<Vec<_>>()
+    };
+    let (step_a, step_b, strides) = (steps(&sa), steps(&sb), out.strides());
+    let total: usize = out.shape.iter().product();
+    let data = (0..total)
+        .map(|i| {
+            let (mut ia, mut ib) = (0, 0);
+            for axis in 0..rank {
+                let coordinate = i / strides[axis] % out.shape[axis];
+                ia += coordinate * step_a[axis];
+                ib += coordinate * step_b[axis];
+            }
+            f(a.data[ia], b.data[ib])
+        })
+        .collect();
+    Ok(Tensor::new(out.shape, data))
+}
+
+fn concat(parts: &[&Tensor], axis: i64) -> Result<Tensor, String> {
+    let first = parts.first().ok_or("nothing to concatenate")?;
+    let rank = first.shape.len();
+    let axis = ((axis + rank as i64) % rank.max(1) as i64) as usize;
+    let outer: usize = first.shape[..axis].iter().product();
+    let mut shape = first.shape.clone();
+    shape[axis] = 0;
+    for part in parts {
+        if part.shape.len() != rank
+            || (0..rank).any(|a| a != axis && part.shape[a] != first.shape[a])
+        {
+            return Err(format!("can't join {:?} and {:?}", first.shape, part.shape));
+        }
+        shape[axis] += part.shape[axis];
+    }
+    let mut data = Vec::with_capacity(shape.iter().product());
+    for o in 0..outer {
+        for part in parts {
+            let chunk = part.data.len() / outer.max(1);
+            data.extend_from_slice(&part.data[o * chunk..(o + 1) * chunk]);
+        }
+    }
+    Ok(Tensor::new(shape, data))
+}
+
+fn transpose(x: &Tensor, perm: &[usize]) -> Result<Tensor, String> {
+    if perm.len() != x.shape.len() {
+        return Err(format!("{:?} doesn't permute {:?}", perm, x.shape));
+    }
+    let shape: Vec<usize> = perm.iter().map(|&p| x.shape[p]).collect();
+    let source = x.strides();
+    let strides = Tensor::new(shape.clone(), Vec::new()).strides();
+    let data = (0..x.data.len())
+        .map(|i| {
+            let index: usize = (0..shape.len())
+                .map(|axis| i / strides[axis] % shape[axis] * source[perm[axis]])
+                .sum();
+            x.data[index]
+        })
+        .collect();
+    Ok(Tensor::new(shape, data))
+}
+
+/// Nearest or linear resizing of the last two axes
+fn resize(node: &Node, inputs: &[Option<Arc<Tensor>>]) -> Result<Tensor, String> {
+    let x = inputs
+        .first()
+        .and_then(|x| x.as_deref())
+        .ok_or("the input is missing")?;
+    let [n, c, h, w] = x.shape[..] else {
+        return Err("only 4 dimensional inputs are supported".into());
+    };
+    let given = |i: usize| {
+        inputs
+            .get(i)
+            .and_then(|t| t.as_deref())
+            .filter(|t| !t.data.is_empty())
+    };
+    // Upsample and old Resize take scales second, newer Resize third or sizes fourth
+    let (out_h, out_w) = match (node.op.as_str(), given(1), given(2), given(3)) {
+        (_, _, _, Some(sizes)) => (sizes.data[2] as usize, sizes.data[3] as usize),
+        ("Resize", _, Some(scales), _) | ("Upsample", Some(scales), _, _) => (
+            (h as f32 * scales.data[2]).floor() as usize,
+            (w as f32 * scales.data[3]).floor() as usize,
+        ),
+        ("Resize", Some(scales), None, None) if scales.data.len() == 4 => (
+            (h as f32 * scales.data[2]).floor() as usize,
+            (w as f32 * scales.data[3]).floor() as usize,
+        ),
+        _ => match node.attributes.get("scales") {
+            Some(Attribute::Floats(scales)) if scales.len() == 4 => (
+                (h as f32 * scales[2]).floor() as usize,
+                (w as f32 * scales[3]).floor() as usize,
+            ),
+            _ => return Err("no scales or sizes".into()),
+        },
+    };
+    let linear = node.text("mode", "nearest").contains("linear");
+    let transform = node.text("coordinate_transformation_mode", "half_pixel");
+    // Where an output coordinate lies in the input
+    let source = |out: usize, out_size: usize, size: usize| -> f32 {
+        let scale = out_size as f32 / size as f32;
+        match transform.as_str() {
+            "align_corners" if out_size > 1 => {
+                out as f32 * (size - 1) as f32 / (out_size - 1) as f32
+            }
+            "asymmetric" => out as f32 / scale,
+            "pytorch_half_pixel" if out_size == 1 => 0.0,
+            _ => (out as f32 + 0.5) / scale - 0.5,
+        }
+    };
+    let nearest = node.text("nearest_mode", "round_prefer_floor");
+    let pick = |v: f32, size: usize| -> usize {
+        let i = match nearest.as_str() {
+            "floor" => v.floor(),
+            "ceil" => v.ceil(),
+            "round_prefer_ceil" => v.round(),
+            // Old Upsample floors
+            _ if node.op == "Upsample" => v.floor(),
+            _ => (v - 0.5).ceil(),
+        };
+        i.clamp(0.0, size as f32 - 1.0) as usize
+    };
+    let mut data = Vec::with_capacity(n * c * out_h * out_w);
+    for plane in x.data.chunks(h * w) {
+        for oy in 0..out_h {
+            let y = source(oy, out_h, h);
+            for ox in 0..out_w {
+                let x = source(ox, out_w, w);
+                data.push(match linear {
+                    false => plane[pick(y, h) * w + pick(x, w)],
+                    true => {
+                        let (y, x) = (y.clamp(0.0, h as f32 - 1.0), x.clamp(0.0, w as f32 - 1.0));
+                        let (y0, x0) = (y.floor() as usize, x.floor() as usize);
+                        let (y1, x1) = ((y0 + 1).min(h - 1), (x0 + 1).min(w - 1));
+                        let (fy, fx) = (y - y0 as f32, x - x0 as f32);
+                        let top = plane[y0 * w + x0] * (1.0 - fx) + plane[y0 * w + x1] * fx;
+                        let bottom = plane[y1 * w + x0] * (1.0 - fx) + plane[y1 * w + x1] * fx;
+                        top * (1.0 - fy) + bottom * fy
+                    }
+                });
+            }
+        }
+    }
+    Ok(Tensor::new(vec![n, c, out_h, out_w], data))
+}
+
+/// A protobuf field value
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    fn bytes(self) -> Result<&'a [u8], String> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err("expected a length-delimited field".into()),
+        }
+    }
+
+    fn text(self) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    fn int(&self) -> i64 {
+        match *self {
+            Value::Varint(v) | Value::Fixed64(v) => v as i64,
+            Value::Fixed32(v) => v as i64,
+            Value::Bytes(_) => 0,
+        }
+    }
+
+    /// Repeated integers, packed or one per field
+    fn ints(self) -> Result<Vec<i64>, String> {
+        match self {
+            Value::Bytes(bytes) => {
+                let mut reader = Reader::new(bytes);
+                let mut values = Vec::new();
+                while !reader.done() {
+                    values.push(reader.varint()? as i64);
+                }
+                Ok(values)
+            }
+            value => Ok(vec![value.int()]),
+        }
+    }
+
+    /// Repeated floats, packed or one per field
+    fn floats(self) -> Result<Vec<f32>, String> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()),
+            Value::Fixed32(bits) => Ok(vec![f32::from_bits(bits)]),
+            _ => Err("expected floats".into()),
+        }
+    }
+}
+
+/// Reads the fields of a protobuf message one after another
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, position: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.position).ok_or("truncated file")?;
+            self.position += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid varint".into())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.data.len());
+        let bytes = &self.data[self.position..end.ok_or("truncated file")?];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    /// The next field number and value, `None` at the end of the message
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>, String> {
+        if self.done() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                let b = self.take(8)?;
+                Value::Fixed64(u64::from_le_bytes(b.try_into().unwrap()))
+            }
+            2 => {
+                let length = self.varint()? as usize;
+                Value::Bytes(self.take(length)?)
+            }
+            5 => {
+                let b = self.take(4)?;
+                Value::Fixed32(u32::from_le_bytes(b.try_into().unwrap()))
+            }
+            wire => return Err(format!("unsupported protobuf wire type {}", wire)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// Name of a ValueInfoProto
+fn value_name(data: &[u8]) -> Result<String, String> {
+    let mut reader = Reader::new(data);
+    while let Some((field, value)) = reader.field()? {
+        if field == 1 {
+            return value.text();
+        }
+    }
+    Err("a graph input or output has no name".into())
+}
+
+fn parse_node(data: &[u8]) -> Result<Node, String> {
+    let mut node = Node {
+        op: String::new(),
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        attributes: HashMap::new(),
+    };
+    let mut reader = Reader::new(data);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => node.inputs.push(value.text()?),
+            2 => node.outputs.push(value.text()?),
+            4 => node.op = value.text()?,
+            5 => {
+                let (name, attribute) = parse_attribute(value.bytes()?)?;
+                if let Some(attribute) = attribute {
+                    node.attributes.insert(name, attribute);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(node)
+}
+
+/// An AttributeProto, `None` for kinds no supported operator uses
+fn parse_attribute(data: &[u8]) -> Result<(String, Option<Attribute>), String> {
+    let (mut name, mut kind) = (String::new(), 0);
+    let (mut float, mut int, mut text, mut tensor) = (None, None, None, None);
+    let (mut floats, mut ints) = (Vec::new(), Vec::new());
+    let mut reader = Reader::new(data);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => name = value.text()?,
+            2 => float = value.floats()?.first().copied(),
+            3 => int = Some(value.int()),
+            4 => text = Some(value.text()?),
+            5 => tensor = Some(parse_tensor(value.bytes()?)?.1),
+            7 => floats.extend(value.floats()?),
+            8 => ints.extend(value.ints()?),
+            20 => kind = value.int(),
+            _ => {}
+        }
+    }
+    // AttributeType: FLOAT 1, INT 2, STRING 3, TENSOR 4, FLOATS 6, INTS 7
+    let attribute = match kind {
+        1 => float.map(Attribute::Float),
+        2 => int.map(Attribute::Int),
+        3 => text.map(Attribute::Text),
+        4 => tensor.map(Attribute::Tensor),
+        6 => Some(Attribute::Floats(floats)),
+        7 => Some(Attribute::Ints(ints)),
+        _ => None,
+    };
+    Ok((name, attribute))
+}
+
+/// A TensorProto and its name
+fn parse_tensor(data: &[u8]) -> Result<(String, Tensor), String> {
+    let (mut name, mut data_type) = (String::new(), 1);
+    let (mut dims, mut values, mut raw) = (Vec::new(), Vec::new(), None);
+    let mut reader = Reader::new(data);
+    while let Some((field, value)) = reader.field()? {
+        match field {
+            1 => dims.extend(value.ints()?),
+            2 => data_type = value.int(),
+            4 => values.extend(value.floats()?),
+            5 | 7 => values.extend(value.ints()?.into_iter().map(|v| v as f32)),
+            8 => name = value.text()?,
+            9 => raw = Some(value.bytes()?),
+            13 => return Err("weights in external files aren't supported".into()),
+            _ => {}
+        }
+    }
+    // TensorProto.DataType: FLOAT 1, INT32 6, INT64 7, FLOAT16 10, DOUBLE 11
+    if let Some(raw) = raw {
+        values = match data_type {
+            1 => raw
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            6 => raw
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32)
+                .collect(),
+            7 => raw
+                .chunks_exact(8)
+                .map(|b| i64::from_le_bytes(b.try_into().unwrap()) as f32)
+                .collect(),
+            10 => raw
+                .chunks_exact(2)
+                .map(|b| half_to_f32(u16::from_le_bytes([b[0], b[1]])))
+                .collect(),
+            11 => raw
+                .chunks_exact(8)
+                .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+                .collect(),
+            other => {
+                return Err(format!(
+                    "tensor {} has unsupported data type {}",
+                    name, other
+                ));
+            }
+        };
+    }
+    let shape: Vec<usize> = dims.iter().map(|&d| d as usize).collect();
+    if shape.iter().product::<usize>() != values.len() {
+        return Err(format!(
+            "tensor {} doesn't have the values of its shape",
+            name
+        ));
+    }
+    Ok((name, Tensor::new(shape, values)))
+}
+
+/// Convert an IEEE 754 half precision float
+fn half_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let fraction = (bits & 0x3ff) as f32;
+    sign * match exponent {
+        0 => fraction * 2f32.powi(-24),
+        31 if fraction == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        e => (1.0 + fraction / 1024.0) * 2f32.powi(e - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Protobuf encoding, just enough to write test models
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn field(number: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(number << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn int_field(number: u64, v: u64, out: &mut Vec<u8>) {
+        varint(number << 3, out);
+        varint(v, out);
+    }
+
+    fn tensor(name: &str, dims: &[u64], values: &[f32]) -> Vec<u8> {
+        let mut t = Vec::new();
+        for &d in dims {
+            int_field(1, d, &mut t);
+        }
+        int_field(2, 1, &mut t);
+        field(8, name.as_bytes(), &mut t);
+        let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        field(9, &raw, &mut t);
+        t
+    }
+
+    fn node(op: &str, inputs: &[&str], output: &str, attributes: &[Vec<u8>]) -> Vec<u8> {
+        let mut n = Vec::new();
+        for input in inputs {
+            field(1, input.as_bytes(), &mut n);
+        }
+        field(2, output.as_bytes(), &mut n);
+        field(4, op.as_bytes(), &mut n);
+        for attribute in attributes {
+            field(5, attribute, &mut n);
+        }
+        n
+    }
+
+    fn int_attribute(name: &str, v: u64) -> Vec<u8> {
+        let mut a = Vec::new();
+        field(1, name.as_bytes(), &mut a);
+        int_field(3, v, &mut a);
+        int_field(20, 2, &mut a);
+        a
+    }
+
+    fn string_attribute(name: &str, v: &str) -> Vec<u8> {
+        let mut a = Vec::new();
+        field(1, name.as_bytes(), &mut a);
+        field(4, v.as_bytes(), &mut a);
+        int_field(20, 3, &mut a);
+        a
+    }
+
+    /// A 2x model: a 1x1 convolution copies each channel four times, and
+    /// DepthToSpace spreads the copies over 2x2 pixels, less a bias of 0.25
+    /// added back by Add, then clipped by LeakyRelu's negative side
+    fn model() -> Vec<u8> {
+        let mut weights = vec![0.0; 12 * 3];
+        for c in 0..3 {
+            for k in 0..4 {
+                weights[(c * 4 + k) * 3 + c] = 1.0;
+            }
+        }
+        let mut graph = Vec::new();
+        field(
+            1,
+            &node("Conv", &["input", "w", "b"], "copies", &[]),
+            &mut graph,
+        );
+        field(
+            1,
+            &node(
+                "DepthToSpace",
+                &["copies"],
+                "spread",
+                &[
+                    int_attribute("blocksize", 2),
+                    string_attribute("mode", "CRD"),
+                ],
+            ),
+            &mut graph,
+        );
+        field(
+            1,
+            &node("Add", &["spread", "quarter"], "sum", &[]),
+            &mut graph,
+        );
+        field(1, &node("LeakyRelu", &["sum"], "output", &[]), &mut graph);
+        field(5, &tensor("w", &[12, 3, 1, 1], &weights), &mut graph);
+        field(5, &tensor("b", &[12], &[-0.25; 12]), &mut graph);
+        field(5, &tensor("quarter", &[3, 1, 1], &[0.25; 3]), &mut graph);
+        let mut input = Vec::new();
+        field(1, b"input", &mut input);
+        field(11, &input, &mut graph);
+        let mut output = Vec::new();
+        field(1, b"output", &mut output);
+        field(12, &output, &mut graph);
+        let mut model = Vec::new();
+        int_field(1, 8, &mut model);
+        field(7, &graph, &mut model);
+        model
+    }
+
+    #[test]
+    fn test_model() {
+        let model = Model::parse(&model()).unwrap();
+        assert_eq!(model.nodes.len(), 4);
+        assert_eq!(
+            (model.input.as_str(), model.output.as_str()),
+            ("input", "output")
+        );
+        let input = Tensor::new(vec![1, 3, 1, 2], vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        let output = model.run(input).unwrap();
+        assert_eq!(output.shape, vec![1, 3, 2, 4]);
+        let red: Vec<f32> = output.data[..8]
+            .iter()
+            .map(|v| (v * 100.0).round())
+            .collect();
+        assert_eq!(red, [10.0, 10.0, 20.0, 20.0, 10.0, 10.0, 20.0, 20.0]);
+    }
+
+    #[test]
+    fn test_upscale_with_model() {
+        let path = std::env::temp_dir().join(format!("imgtools_test_{}.onnx", std::process::id()));
+        fs::write(&path, model()).unwrap();
+        // Larger than a tile, so tiles are joined
+        let img = RgbImage::from_fn(200, 30, |x, y| Rgb([x as u8, y as u8 * 8, 100]));
+        let big = upscale_with_model(&DynamicImage::ImageRgb8(img.clone()), &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(big.dimensions(), (400, 60));
+        for (x, y, p) in img.enumerate_pixels() {
+            assert_eq!(big.get_pixel(2 * x + 1, 2 * y + 1), p);
+        }
+    }
+
+    /// A model made of one node from the input to the output
+    fn single(node_bytes: Vec<u8>, initializers: &[Vec<u8>]) -> Vec<u8> {
+        let mut graph = Vec::new();
+        field(1, &node_bytes, &mut graph);
+        for tensor in initializers {
+            field(5, tensor, &mut graph);
+        }
+        let mut input = Vec::new();
+        field(1, b"input", &mut input);
+        field(11, &input, &mut graph);
+        let mut output = Vec::new();
+        field(1, b"output", &mut output);
+        field(12, &output, &mut graph);
+        let mut model = Vec::new();
+        field(7, &graph, &mut model);
+        model
+    }
+
+    #[test]
+    fn test_infer() {
+        let dir = std::env::temp_dir();
+        let identity = dir.join(format!("imgtools_identity_{}.onnx", std::process::id()));
+        fs::write(
+            &identity,
+            single(node("Identity", &["input"], "output", &[]), &[]),
+        )
+        .unwrap();
+        // The average of the channels, halved
+        let average = dir.join(format!("imgtools_average_{}.onnx", std::process::id()));
+        let w = tensor("w", &[1, 3, 1, 1], &[1.0 / 6.0; 3]);
+        fs::write(
+            &average,
+            single(node("Conv", &["input", "w"], "output", &[]), &[w]),
+        )
+        .unwrap();
+
+        let img = RgbImage::from_fn(12, 10, |x, y| Rgb([x as u8 * 20, y as u8 * 20, 60]));
+        let img = DynamicImage::ImageRgb8(img);
+        let mut inference = Inference {
+            model: identity.clone(),
+            size: None,
+            range: 255.0,
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            bgr: true,
+            layout: TensorLayout::Nhwc,
+            output: ModelOutput::Image,
+            channel: 0,
+            stretch: false,
+        };
+        // Swapped to BGR and back, the image comes back unchanged
+        assert_eq!(infer(&img, &inference).unwrap(), img);
+        // Run at a fixed size and resized back
+        inference.size = Some(Size(4, 4));
+        let small = infer(&img, &inference).unwrap();
+        assert_eq!(small.dimensions(), (12, 10));
+        inference.size = None;
+        // The input is normalized, the result is not
+        inference.mean = [60.0; 3];
+        inference.std = [0.5; 3];
+        let normalized = infer(&img, &inference).unwrap().to_rgb8();
+        assert_eq!(normalized.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(normalized.get_pixel(5, 0).0, [80, 0, 0]);
+
+        inference.mean = [0.0; 3];
+        inference.std = [1.0; 3];
+        inference.model = average.clone();
+        inference.layout = TensorLayout::Nchw;
+        inference.range = 1.0;
+        inference.output = ModelOutput::Mask;
+        let mask = infer(&img, &inference).unwrap();
+        assert_eq!(mask.color(), image::ColorType::L8);
+        assert_eq!(mask.to_luma8().get_pixel(3, 0)[0], 20);
+        inference.stretch = true;
+        let stretched = infer(&img, &inference).unwrap().to_luma8();
+        assert_eq!(stretched.get_pixel(0, 0)[0], 0);
+        assert_eq!(stretched.get_pixel(11, 9)[0], 255);
+        inference.output = ModelOutput::Alpha;
+        let cut = infer(&img, &inference).unwrap().to_rgba8();
+        assert_eq!(cut.get_pixel(11, 9).0, [220, 180, 60, 255]);
+        assert_eq!(cut.get_pixel(0, 0).0, [0, 0, 60, 0]);
+        // The average is a grayscale image too, but has no second channel
+        inference.output = ModelOutput::Image;
+        assert_eq!(
+            infer(&img, &inference).unwrap().color(),
+            image::ColorType::L8
+        );
+        inference.output = ModelOutput::Mask;
+        inference.channel = 1;
+        assert!(infer(&img, &inference).is_err());
+        fs::remove_file(&identity).unwrap();
+        fs::remove_file(&average).unwrap();
+    }
+
+    #[test]
+    fn test_remove_background() {
+        // Likely the subject where the image is brighter than the ImageNet mean
+        let path = std::env::temp_dir().join(format!("imgtools_u2net_{}.onnx", std::process::id()));
+        let w = tensor("w", &[1, 3, 1, 1], &[1.0; 3]);
+        let conv = node("Conv", &["input", "w"], "sum", &[]);
+        let mut graph = Vec::new();
+        field(1, &conv, &mut graph);
+        field(1, &node("Sigmoid", &["sum"], "output", &[]), &mut graph);
+        field(5, &w, &mut graph);
+        let mut input = Vec::new();
+        field(1, b"input", &mut input);
+        field(11, &input, &mut graph);
+        let mut output = Vec::new();
+        field(1, b"output", &mut output);
+        field(12, &output, &mut graph);
+        let mut model = Vec::new();
+        field(7, &graph, &mut model);
+        fs::write(&path, model).unwrap();
+
+        let img = RgbImage::from_fn(40, 30, |x, y| {
+            match (10..30).contains(&x) && (5..25).contains(&y) {
+                true => Rgb([250, 240, 230]),
+                false => Rgb([20, 30, 40]),
+            }
+        });
+        let cut = remove_background(&DynamicImage::ImageRgb8(img), Some(&path), Size(20, 20))
+            .unwrap()
+            .to_rgba8();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cut.dimensions(), (40, 30));
+        assert_eq!(cut.get_pixel(20, 15).0, [250, 240, 230, 255]);
+        assert_eq!(cut.get_pixel(2, 2).0, [20, 30, 40, 0]);
+    }
+
+    #[test]
+    fn test_layers() {
+        let node = |op: &str, attributes: Vec<(&str, Attribute)>| Node {
+            op: op.into(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            attributes: attributes
+                .into_iter()
+                .map(|(name, a)| (name.to_string(), a))
+                .collect(),
+        };
+        let x = Arc::new(Tensor::new(
+            vec![1, 1, 2, 3],
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        ));
+
+        let pooled = pool(
+            &node(
+                "MaxPool",
+                vec![("kernel_shape", Attribute::Ints(vec![2, 2]))],
+            ),
+            &x,
+        )
+        .unwrap();
+        assert_eq!(
+            (pooled.shape, pooled.data),
+            (vec![1, 1, 1, 2], vec![5.0, 6.0])
+        );
+        let average = node(
+            "AveragePool",
+            vec![
+                ("kernel_shape", Attribute::Ints(vec![2, 2])),
+                ("strides", Attribute::Ints(vec![2, 2])),
+                ("ceil_mode", Attribute::Int(1)),
+            ],
+        );
+        assert_eq!(pool(&average, &x).unwrap().data, [3.0, 4.5]);
+
+        let reflect = node(
+            "Pad",
+            vec![
+                ("pads", Attribute::Ints(vec![0, 0, 0, 2, 0, 0, 0, 1])),
+                ("mode", Attribute::Text("reflect".into())),
+            ],
+        );
+        let padded = pad(&reflect, &[Some(x.clone())]).unwrap();
+        assert_eq!(padded.shape, [1, 1, 2, 6]);
+        assert_eq!(&padded.data[..6], [3.0, 2.0, 1.0, 2.0, 3.0, 2.0]);
+
+        let normalized = normalize(&x, |_, _| Ok((3.5, 4.0, 2.0, 1.0))).unwrap();
+        assert_eq!(normalized.data[0], -1.5);
+        let probabilities = softmax(&x, 1).unwrap();
+        assert!(probabilities.data.iter().all(|&p| p == 1.0));
+        let probabilities = softmax(&x, -1).unwrap();
+        assert!((probabilities.data[..3].iter().sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // Stride 2 spreads each input pixel over its own 2x2 block
+        let upsample = node(
+            "ConvTranspose",
+            vec![("strides", Attribute::Ints(vec![2, 2]))],
+        );
+        let w = Tensor::new(vec![1, 1, 2, 2], vec![1.0; 4]);
+        let big = conv_transpose(&upsample, &x, &w, None).unwrap();
+        assert_eq!(big.shape, [1, 1, 4, 6]);
+        assert_eq!(&big.data[..6], [1.0, 1.0, 2.0, 2.0, 3.0, 3.0]);
+    }
+
+    #[test]
+    fn test_operators() {
+        let a = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let column = Tensor::new(vec![2, 1], vec![10.0, 20.0]);
+        assert_eq!(
+            broadcast(&a, &column, |x, y| x + y).unwrap().data,
+            [11.0, 12.0, 13.0, 24.0, 25.0, 26.0]
+        );
+        assert!(broadcast(&a, &Tensor::new(vec![2], vec![1.0, 2.0]), |x, y| x + y).is_err());
+        let t = transpose(&a, &[1, 0]).unwrap();
+        assert_eq!(
+            (t.shape, t.data),
+            (vec![3, 2], vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0])
+        );
+        let joined = concat(&[&a, &column], 1).unwrap();
+        assert_eq!(joined.shape, [2, 4]);
+        assert_eq!(joined.data, [1.0, 2.0, 3.0, 10.0, 4.0, 5.0, 6.0, 20.0]);
+        assert_eq!(half_to_f32(0x3c00), 1.0);
+        assert_eq!(half_to_f32(0xc000), -2.0);
+
+        // A 3x3 box blur with zero padding
+        let mut node = Node {
+            op: "Conv".into(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            attributes: HashMap::new(),
+        };
+        node.attributes
+            .insert("pads".into(), Attribute::Ints(vec![1; 4]));
+        let x = Tensor::new(vec![1, 1, 2, 2], vec![1.0; 4]);
+        let w = Tensor::new(vec![1, 1, 3, 3], vec![1.0; 9]);
+        assert_eq!(conv(&node, &x, &w, None).unwrap().data, [4.0; 4]);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::encoding::write_png;
+use crate::jpeg::encode_progressive;
+use crate::process::{encodable, rgba8, write_image};
+use crate::{
+    Dither, EncodeOptions, Format, ImgtoolsError, Metadata, Palette, encode_with_metadata,
+    insert_xmp, quantize,
+};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, RgbaImage};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// JPEG quality without --quality
+const JPEG_QUALITY: u8 = 85;
+
+/// Row filters tried for every PNG, the smallest result wins
+const PNG_FILTERS: [png::Filter; 6] = [
+    png::Filter::NoFilter,
+    png::Filter::Sub,
+    png::Filter::Up,
+    png::Filter::Avg,
+    png::Filter::Paeth,
+    png::Filter::Adaptive,
+];
+
+/// Encode an image as small as the format and quality allow
+///
+/// PNG is lossless without a quality, otherwise the quality decides how many
+/// palette colors are kept. JPEG is encoded at the quality or 85, WebP only
+/// losslessly, so it takes neither a quality nor a target size. With a target
+/// size the highest quality whose result fits is searched, after trying
+/// lossless PNG first.
+pub fn optimize(
+    img: &DynamicImage,
+    format: Format,
+    metadata: &Metadata,
+    encoding: &EncodeOptions,
+    quality: Option<u8>,
+    target_size: Option<u64>,
+) -> Result<Vec<u8>, ImgtoolsError> {
+    // There is no lossy WebP encoder, so no quality to search
+    if format == Format::WebP && target_size.is_some() {
+        return Err(ImgtoolsError::InvalidArgument(
+            "WebP can only be optimized losslessly, --target-size needs JPEG or PNG output".into(),
+        ));
+    }
+    let encode = |quality| encode_optimized(img, format, metadata, encoding, quality);
+    let Some(target) = target_size else {
+        return encode(quality);
+    };
+    if format == Format::Png && quality.is_none() {
+        let data = encode(None)?;
+        if data.len() as u64 <= target {
+            return Ok(data);
+        }
+    }
+
+    // Sizes grow with the quality, so halve the range until the best fit is left
+    let (mut low, mut high) = (1, quality.unwrap_or(100));
+    let mut best = None;
+    while low <= high {
+        let middle = low + (high - low) / 2;
+        let data = encode(Some(middle))?;
+        match data.len() as u64 <= target {
+            true => {
+                best = Some(data);
+                low = middle + 1;
+            }
+            false => high = middle - 1,
+        }
+    }
+    match best {
+        Some(data) => Ok(data),
+        None => fits(encode(Some(1))?, target),
+    }
+}
+
+/// Fail when the smallest result is still larger than the target
+fn fits(data: Vec<u8>, target: u64) -> Result<Vec<u8>, ImgtoolsError> {
+    match data.len() as u64 <= target {
+        true => Ok(data),
+        false => Err(ImgtoolsError::InvalidArgument(format!(
+            "Unable to fit the image into {} bytes, the smallest result has {}",
+            target,
+            data.len()
+        ))),
+    }
+}
+
+/// Encode at one quality, `None` for lossless
+fn encode_optimized(
+    img: &DynamicImage,
+    format: Format,
+    metadata: &Metadata,
+    encoding: &EncodeOptions,
+    quality: Option<u8>,
+) -> Result<Vec<u8>, ImgtoolsError> {
+    let interlaced = encoding.interlace.is_some();
+    let mut data = Vec::new();
+    match (format, quality) {
+        (Format::Png, None) => data = lossless_png(img, metadata, interlaced)?,
+        (Format::Png, Some(quality)) => {
+            // 2 colors at the lowest quality up to 256 at the highest
+            let colors = 2 + (254 * (quality as usize - 1)) / 99;
+            let mut rgba = img.to_rgba8();
+            let palette = Palette::median_cut(&rgba, colors);
+            quantize(&mut rgba, &palette, Dither::FloydSteinberg);
+            data = lossless_png(&DynamicImage::ImageRgba8(rgba), metadata, interlaced)?;
+        }
+        (Format::Jpeg, quality) if encoding.progressive => {
+            let img = encodable(img, Format::Jpeg);
+            encode_progressive(&img, quality.unwrap_or(JPEG_QUALITY), metadata, &mut data)?;
+        }
+        (Format::Jpeg, quality) => {
+            let img = encodable(img, Format::Jpeg);
+            let encoder = JpegEncoder::new_with_quality(&mut data, quality.unwrap_or(JPEG_QUALITY));
+            write_image(encoder, &img, metadata).map_err(ImgtoolsError::Encode)?;
+        }
+        (Format::WebP, None) => {
+            encode_with_metadata(img, format, metadata, std::io::Cursor::new(&mut data))?;
+            return Ok(data);
+        }
+        (Format::WebP, Some(_)) => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "WebP can only be optimized losslessly".into(),
+            ));
+        }
+        (format, _) => {
+            return Err(ImgtoolsError::InvalidArgument(format!(
+                "Optimizing supports PNG, JPEG and WebP, not {}",
+                format
+            )));
+        }
+    }
+    Ok(match &metadata.xmp {
+        Some(xmp) => insert_xmp(data, format, xmp),
+        None => data,
+    })
+}
+
+/// Smallest lossless PNG of the image
+///
+/// Unused alpha and color channels are dropped, images with at most 256
+/// colors are written with a palette of the lowest bit depth, and every row
+/// filter is tried with the strongest compression.
+fn lossless_png(
+    img: &DynamicImage,
+    metadata: &Metadata,
+    interlaced: bool,
+) -> Result<Vec<u8>, ImgtoolsError> {
+    // 16-bit samples are only reduced when no precision is lost
+    let wide = img.color().bytes_per_pixel() > img.color().channel_count();
+    let rgba = match wide {
+        true => {
+            let rgba16 = img.to_rgba16();
+            if rgba16.as_raw().iter().any(|&v| v % 257 != 0) {
+                return smallest_png(metadata, interlaced, &[Candidate::from_image(img)]);
+            }
+            Cow::Owned(img.to_rgba8())
+        }
+        false => rgba8(img),
+    };
+
+    let mut candidates = vec![Candidate::reduced(&rgba)];
+    if let Some(palette) = Candidate::palette(&rgba) {
+        candidates.push(palette);
+    }
+    smallest_png(metadata, interlaced, &candidates)
+}
+
+/// Pixel data ready for the PNG encoder
+struct Candidate {
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    data: Vec<u8>,
+    /// Palette colors and their alpha values
+    palette: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Candidate {
+    /// The image as it is, 16-bit samples in big-endian order
+    fn from_image(img: &DynamicImage) -> Self {
+        let rgba = img.to_rgba16();
+        let opaque = rgba.pixels().all(|p| p[3] == u16::MAX);
+        let (color, channels) = match opaque {
+            true => (png::ColorType::Rgb, 3),
+            false => (png::ColorType::Rgba, 4),
+        };
+        let data = rgba
+            .pixels()
+            .flat_map(|p| p.0[..channels].to_vec())
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        Candidate {
+            width: img.width(),
+            height: img.height(),
+            color,
+            depth: png::BitDepth::Sixteen,
+            data,
+            palette: None,
+        }
+    }
+
+    /// Gray or color, with alpha only if some pixel isn't opaque
+    fn reduced(rgba: &RgbaImage) -> Self {
+        let opaque = rgba.pixels().all(|p| p[3] == 255);
+        let gray = rgba.pixels().all(|p| p[0] == p[1] && p[1] == p[2]);
+        let (color, channels): (_, &[usize]) = match (gray, opaque) {
+            (true, true) => (png::ColorType::Grayscale, &[0]),
+            (true, false) => (png::ColorType::GrayscaleAlpha, &[0, 3]),
+            (false, true) => (png::ColorType::Rgb, &[0, 1, 2]),
+            (false, false) => (png::ColorType::Rgba, &[0, 1, 2, 3]),
+        };
+        let data = rgba
+            .pixels()
+            .flat_map(|p| channels.iter().map(|&c| p[c]))
+            .collect();
+        Candidate {
+            width: rgba.width(),
+            height: rgba.height(),
+            color,
+            depth: png::BitDepth::Eight,
+            data,
+            palette: None,
+        }
+    }
+
+    /// Palette indices packed to the lowest bit depth, if there are at most 256 colors
+    fn palette(rgba: &RgbaImage) -> Option<Self> {
+        let mut colors: Vec<[u8; 4]> = Vec::new();
+        let mut seen = HashSet::new();
+        for pixel in rgba.pixels() {
+            if seen.insert(pixel.0) {
+                colors.push(pixel.0);
+                if colors.len() > 256 {
+                    return None;
+                }
+            }
+        }
+        // Transparent colors first keep the tRNS chunk short
+        colors.sort_by_key(|color| color[3] == 255);
+        let index: HashMap<_, _> = colors
+            .iter()
+            .enumerate()
+            .map(|(i, color)| (*color, i as u8))
+            .collect();
+
+        let (depth, bits) = match colors.len() {
+            0..=2 => (png::BitDepth::One, 1),
+            3..=4 => (png::BitDepth::Two, 2),
+            5..=16 => (png::BitDepth::Four, 4),
+            _ => (png::BitDepth::Eight, 8),
+        };
+        let row_bytes = (rgba.width() as usize * bits).div_ceil(8);
+        let mut data = vec![0u8; row_bytes * rgba.height() as usize];
+        for (x, y, pixel) in rgba.enumerate_pixels() {
+            let bit = x as usize * bits;
+            let shift = 8 - bits - bit % 8;
+            data[y as usize * row_bytes + bit / 8] |= index[&pixel.0] << shift;
+        }
+
+        let rgb = colors
+            .iter()
+            .flat_map(|color| &color[..3])
+            .copied()
+            .collect();
+        let alpha = colors
+            .iter()
+            .map(|color| color[3])
+            .take_while(|&alpha| alpha < 255)
+            .collect();
+        Some(Candidate {
+            width: rgba.width(),
+            height: rgba.height(),
+            color: png::ColorType::Indexed,
+            depth,
+            data,
+            palette: Some((rgb, alpha)),
+        })
+    }
+
+    /// Encode with one row filter and the strongest compression
+    fn encode(
+        &self,
+        filter: png::Filter,
+        metadata: &Metadata,
+        interlaced: bool,
+    ) -> Result<Vec<u8>, ImgtoolsError> {
+        let mut info = png::Info::with_size(self.width, self.height);
+        info.color_type = self.color;
+        info.bit_depth = self.depth;
+        info.interlaced = interlaced;
+        info.icc_profile = metadata.icc.as_deref().map(Cow::Borrowed);
+        info.exif_metadata = metadata.exif.as_deref().map(Cow::Borrowed);
+        if let Some((rgb, alpha)) = &self.palette {
+            info.palette = Some(Cow::Borrowed(rgb));
+            info.trns = (!alpha.is_empty()).then_some(Cow::Borrowed(alpha));
+        }
+        let mut data = Vec::new();
+        write_png(
+            info,
+            &self.data,
+            filter,
+            png::DeflateCompression::Level(9),
+            &mut data,
+        )?;
+        Ok(data)
+    }
+}
+
+/// Encode every candidate with every filter and keep the smallest
+fn smallest_png(
+    metadata: &Metadata,
+    interlaced: bool,
+    candidates: &[Candidate],
+) -> Result<Vec<u8>, ImgtoolsError> {
+    let mut best: Option<Vec<u8>> = None;
+    for candidate in candidates {
+        for filter in PNG_FILTERS {
+            let data = candidate.encode(filter, metadata, interlaced)?;
+            if best.as_ref().is_none_or(|best| data.len() < best.len()) {
+                best = Some(data);
+            }
+        }
+    }
+    Ok(best.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Interlace, encode};
+    use image::{ImageBuffer, Rgb, Rgba};
+    use std::io::Cursor;
+
+    /// Pseudo-random values that don't compress well
+    fn noise(x: u32, y: u32) -> u32 {
+        (x * 7919 + y * 104_729).wrapping_mul(2_654_435_761) >> 8
+    }
+
+    fn optimized(
+        img: &DynamicImage,
+        format: Format,
+        quality: Option<u8>,
+        target_size: Option<u64>,
+    ) -> Result<Vec<u8>, ImgtoolsError> {
+        let options = EncodeOptions::default();
+        optimize(
+            img,
+            format,
+            &Metadata::default(),
+            &options,
+            quality,
+            target_size,
+        )
+    }
+
+    fn noisy() -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, y| {
+            let n = noise(x, y);
+            Rgb([n as u8, (n >> 8) as u8, (n >> 16) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_lossless_png() {
+        // Two colors with alpha fit a 1-bit palette
+        let img =
+            DynamicImage::ImageRgba8(ImageBuffer::from_fn(32, 32, |x, y| match noise(x, y) % 2 {
+                0 => Rgba([255, 0, 0, 255]),
+                _ => Rgba([0, 0, 255, 128]),
+            }));
+        let data = optimized(&img, Format::Png, None, None).unwrap();
+        let mut plain = Cursor::new(Vec::new());
+        encode(&img, Format::Png, &mut plain).unwrap();
+        assert!(data.len() < plain.get_ref().len());
+
+        let decoder = png::Decoder::new(Cursor::new(&data));
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+        assert_eq!(reader.info().bit_depth, png::BitDepth::One);
+        let decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+
+        // Gray stays gray, and 16-bit images keep their precision
+        let gray = DynamicImage::ImageRgb8(ImageBuffer::from_fn(300, 1, |x, _| {
+            Rgb([(x % 256) as u8; 3])
+        }));
+        let data = optimized(&gray, Format::Png, None, None).unwrap();
+        let reader = png::Decoder::new(Cursor::new(&data)).read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Grayscale);
+        let deep = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(4, 4, Rgb([1000, 2, 3])));
+        let data = optimized(&deep, Format::Png, None, None).unwrap();
+        assert_eq!(image::load_from_memory(&data).unwrap(), deep);
+    }
+
+    #[test]
+    fn test_target_size() {
+        let img = noisy();
+        let full = optimized(&img, Format::Jpeg, Some(100), None).unwrap();
+        let target = full.len() as u64 / 2;
+        let data = optimized(&img, Format::Jpeg, None, Some(target)).unwrap();
+        assert!(data.len() as u64 <= target);
+        let worse = optimized(&img, Format::Jpeg, Some(1), None).unwrap();
+        assert!(data.len() > worse.len());
+
+        // PNG falls back to fewer colors when lossless is too large
+        let lossless = optimized(&img, Format::Png, None, None).unwrap();
+        let target = lossless.len() as u64 / 2;
+        let data = optimized(&img, Format::Png, None, Some(target)).unwrap();
+        assert!(data.len() as u64 <= target);
+
+        assert!(matches!(
+            optimized(&img, Format::Jpeg, None, Some(10)),
+            Err(ImgtoolsError::InvalidArgument(message)) if message.starts_with("Unable to fit")
+        ));
+
+        // WebP has no quality to search, even a size lossless output fits is refused
+        assert!(matches!(
+            optimized(&img, Format::WebP, None, Some(u64::MAX)),
+            Err(ImgtoolsError::InvalidArgument(message)) if message.contains("--target-size")
+        ));
+    }
+
+    #[test]
+    fn test_encode_options() {
+        let options = EncodeOptions {
+            progressive: true,
+            interlace: Some(Interlace::Adam7),
+        };
+        let img = noisy();
+        let data = optimize(
+            &img,
+            Format::Jpeg,
+            &Metadata::default(),
+            &options,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(data.windows(2).any(|marker| marker == [0xFF, 0xC2]));
+        let data = optimize(
+            &img,
+            Format::Png,
+            &Metadata::default(),
+            &options,
+            None,
+            None,
+        )
+        .unwrap();
+        let reader = png::Decoder::new(Cursor::new(&data)).read_info().unwrap();
+        assert!(reader.info().interlaced);
+        assert_eq!(
+            image::load_from_memory(&data).unwrap().to_rgb8(),
+            img.to_rgb8()
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let img = noisy();
+        for (format, quality) in [(Format::WebP, Some(80)), (Format::Bmp, None)] {
+            assert!(matches!(
+                optimized(&img, format, quality, None),
+                Err(ImgtoolsError::InvalidArgument(_))
+            ));
+        }
+    }
+}
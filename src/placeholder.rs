@@ -0,0 +1,561 @@
+use crate::quantize::dominant_colors;
+use crate::{ImgtoolsError, PaletteMethod, Size, flatten};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+use std::f64::consts::PI;
+
+/// Digits of the base 83 numbers BlurHash strings are made of
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Digits of the base64 ThumbHash bytes are written in
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Longer side images are scaled down to before hashing, a placeholder
+/// keeps only the coarsest shapes and ThumbHash takes at most 100x100
+const MAX_SIDE: u32 = 100;
+
+/// Size the hash is computed at, the image shrunk to fit `MAX_SIDE`
+fn shrink(img: &DynamicImage) -> DynamicImage {
+    match img.width().max(img.height()) > MAX_SIDE {
+        true => img.resize(MAX_SIDE, MAX_SIDE, FilterType::Triangle),
+        false => img.clone(),
+    }
+}
+
+fn to_linear(value: u8) -> f64 {
+    let v = value as f64 / 255.0;
+    match v <= 0.04045 {
+        true => v / 12.92,
+        false => ((v + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn to_srgb(value: f64) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    let v = match v <= 0.0031308 {
+        true => v * 12.92,
+        false => 1.055 * v.powf(1.0 / 2.4) - 0.055,
+    };
+    (v * 255.0 + 0.5) as u32
+}
+
+/// `value` raised to `exponent`, keeping its sign
+fn sign_pow(value: f64, exponent: f64) -> f64 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+fn encode83(value: u32, length: u32, out: &mut String) {
+    for i in (0..length).rev() {
+        out.push(BASE83[(value / 83u32.pow(i) % 83) as usize] as char);
+    }
+}
+
+fn decode83(digits: &str) -> Result<u32, ImgtoolsError> {
+    digits.bytes().try_fold(0u32, |value, digit| {
+        match BASE83.iter().position(|&d| d == digit) {
+            Some(d) => Ok(value * 83 + d as u32),
+            None => Err(ImgtoolsError::InvalidArgument(format!(
+                "Invalid BlurHash character {}",
+                digit as char
+            ))),
+        }
+    })
+}
+
+/// Encode the image as a BlurHash of `x` by `y` components, 1 to 9 each
+///
+/// The string holds the average color and the strongest waves of the cosine
+/// transform of the linear RGB values, more components keep more detail.
+/// Transparency is ignored.
+pub fn blurhash(img: &DynamicImage, x: u32, y: u32) -> Result<String, ImgtoolsError> {
+    if !(1..=9).contains(&x) || !(1..=9).contains(&y) {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "BlurHash components {}x{} must be 1 to 9 each",
+            x, y
+        )));
+    }
+    let rgb = shrink(img).to_rgb8();
+    let (w, h) = rgb.dimensions();
+    let linear: Vec<[f64; 3]> = rgb.pixels().map(|p| p.0.map(to_linear)).collect();
+    let mut factors = Vec::with_capacity((x * y) as usize);
+    for j in 0..y {
+        for i in 0..x {
+            let mut sum = [0.0; 3];
+            for py in 0..h {
+                let fy = (PI * j as f64 * py as f64 / h as f64).cos();
+                for px in 0..w {
+                    let basis = fy * (PI * i as f64 * px as f64 / w as f64).cos();
+                    let pixel = linear[(py * w + px) as usize];
+                    for (s, v) in sum.iter_mut().zip(pixel) {
+                        *s += basis * v;
+                    }
+                }
+            }
+            // The average counts once, waves on both sides of it twice
+            let scale = if i == 0 && j == 0 { 1.0 } else { 2.0 } / (w * h) as f64;
+            factors.push(sum.map(|s| s * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    encode83((x - 1) + (y - 1) * 9, 1, &mut hash);
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let maximum = match ac.iter().flatten().fold(0.0f64, |m, v| m.max(v.abs())) {
+        _ if ac.is_empty() => {
+            encode83(0, 1, &mut hash);
+            1.0
+        }
+        largest => {
+            let quantized = (largest * 166.0 - 0.5).floor().clamp(0.0, 82.0);
+            encode83(quantized as u32, 1, &mut hash);
+            (quantized + 1.0) / 166.0
+        }
+    };
+    encode83(
+        (to_srgb(dc[0]) << 16) + (to_srgb(dc[1]) << 8) + to_srgb(dc[2]),
+        4,
+        &mut hash,
+    );
+    for factor in ac {
+        let [r, g, b] = factor.map(|v| {
+            (sign_pow(v / maximum, 0.5) * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        encode83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+    Ok(hash)
+}
+
+/// Draw a BlurHash at the given size
+pub fn decode_blurhash(hash: &str, Size(w, h): Size) -> Result<RgbImage, ImgtoolsError> {
+    let invalid =
+        |message: &str| ImgtoolsError::InvalidArgument(format!("Invalid BlurHash: {}", message));
+    if !hash.is_ascii() || hash.len() < 6 {
+        return Err(invalid("it must be at least 6 characters"));
+    }
+    let flag = decode83(&hash[..1])?;
+    let (x, y) = (flag % 9 + 1, flag / 9 + 1);
+    if hash.len() != 4 + 2 * (x * y) as usize {
+        return Err(invalid("its length doesn't match its size flag"));
+    }
+    let maximum = (decode83(&hash[1..2])? + 1) as f64 / 166.0;
+    let dc = decode83(&hash[2..6])?;
+    let mut colors = vec![[dc >> 16, (dc >> 8) & 255, dc & 255].map(|v| to_linear(v as u8))];
+    for i in 1..(x * y) as usize {
+        let value = decode83(&hash[4 + 2 * i..6 + 2 * i])?;
+        let quantized = [value / (19 * 19), value / 19 % 19, value % 19];
+        colors.push(quantized.map(|q| sign_pow((q as f64 - 9.0) / 9.0, 2.0) * maximum));
+    }
+    Ok(RgbImage::from_fn(w, h, |px, py| {
+        let mut sum = [0.0; 3];
+        for j in 0..y {
+            let fy = (PI * py as f64 * j as f64 / h as f64).cos();
+            for i in 0..x {
+                let basis = fy * (PI * px as f64 * i as f64 / w as f64).cos();
+                for (s, c) in sum.iter_mut().zip(colors[(j * x + i) as usize]) {
+                    *s += c * basis;
+                }
+            }
+        }
+        Rgb(sum.map(|s| to_srgb(s).min(255) as u8))
+    }))
+}
+
+/// Cosine transform of one ThumbHash channel: the average, the other factors
+/// scaled to 0 ~ 1 and their scale
+fn thumbhash_channel(
+    channel: &[f64],
+    w: usize,
+    h: usize,
+    nx: usize,
+    ny: usize,
+) -> (f64, Vec<f64>, f64) {
+    let (mut dc, mut ac, mut scale) = (0.0, Vec::new(), 0.0f64);
+    for cy in 0..ny {
+        // Fewer waves across further down, a triangle of the lowest frequencies
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            let fx: Vec<f64> = (0..w)
+                .map(|x| (PI / w as f64 * cx as f64 * (x as f64 + 0.5)).cos())
+                .collect();
+            let mut f = 0.0;
+            for y in 0..h {
+                let fy = (PI / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                for x in 0..w {
+                    f += channel[x + y * w] * fx[x] * fy;
+                }
+            }
+            f /= (w * h) as f64;
+            match (cx, cy) {
+                (0, 0) => dc = f,
+                _ => {
+                    ac.push(f);
+                    scale = scale.max(f.abs());
+                }
+            }
+            cx += 1;
+        }
+    }
+    if scale > 0.0 {
+        ac.iter_mut().for_each(|f| *f = 0.5 + 0.5 / scale * *f);
+    }
+    (dc, ac, scale)
+}
+
+/// Encode the image as a ThumbHash, written in base64
+///
+/// ThumbHash keeps the aspect ratio and transparency, and more detail than a
+/// BlurHash of about the same length.
+pub fn thumbhash(img: &DynamicImage) -> String {
+    let rgba = shrink(img).to_rgba8();
+    let (w, h) = (rgba.width() as usize, rgba.height() as usize);
+    let pixels: Vec<[f64; 4]> = rgba
+        .pixels()
+        .map(|p| p.0.map(|v| v as f64 / 255.0))
+        .collect();
+
+    // Average color, weighted by alpha
+    let (mut average, mut total_alpha) = ([0.0; 3], 0.0);
+    for p in &pixels {
+        for c in 0..3 {
+            average[c] += p[3] * p[c];
+        }
+        total_alpha += p[3];
+    }
+    if total_alpha > 0.0 {
+        average.iter_mut().for_each(|v| *v /= total_alpha);
+    }
+    let has_alpha = total_alpha < (w * h) as f64;
+    let limit = if has_alpha { 5.0 } else { 7.0 };
+    let longer = w.max(h) as f64;
+    let lx = ((limit * w as f64 / longer).round() as usize).max(1);
+    let ly = ((limit * h as f64 / longer).round() as usize).max(1);
+
+    // Luminance, yellow-blue, red-green and alpha, atop the average color
+    let (mut l, mut p, mut q, mut a) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for pixel in &pixels {
+        let alpha = pixel[3];
+        let [r, g, b] = std::array::from_fn(|c| average[c] * (1.0 - alpha) + alpha * pixel[c]);
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+    let (l_dc, l_ac, l_scale) = thumbhash_channel(&l, w, h, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = thumbhash_channel(&p, w, h, 3, 3);
+    let (q_dc, q_ac, q_scale) = thumbhash_channel(&q, w, h, 3, 3);
+
+    let landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if landscape { ly } else { lx }) as u32
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | (landscape as u32) << 15;
+    let mut bytes = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+    let mut factors = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = thumbhash_channel(&a, w, h, 5, 5);
+        bytes.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+        factors.push(a_ac);
+    }
+    // Two factors of 4 bits to a byte
+    for (i, f) in factors.iter().flatten().enumerate() {
+        if i % 2 == 0 {
+            bytes.push(0);
+        }
+        *bytes.last_mut().unwrap() |= ((15.0 * f).round() as u8) << ((i % 2) * 4);
+    }
+    encode_base64(&bytes)
+}
+
+/// Draw a ThumbHash, at `size` or 32 pixels on the longer side in its aspect ratio
+pub fn decode_thumbhash(hash: &str, size: Option<Size>) -> Result<RgbaImage, ImgtoolsError> {
+    let hash = decode_base64(hash)
+        .filter(|bytes| bytes.len() >= 5)
+        .ok_or_else(|| {
+            ImgtoolsError::InvalidArgument(
+                "Invalid ThumbHash, expected base64 of at least 5 bytes".into(),
+            )
+        })?;
+    let header24 = hash[0] as u32 | (hash[1] as u32) << 8 | (hash[2] as u32) << 16;
+    let header16 = hash[3] as u32 | (hash[4] as u32) << 8;
+    let l_dc = (header24 & 63) as f64 / 63.0;
+    let p_dc = ((header24 >> 6) & 63) as f64 / 31.5 - 1.0;
+    let q_dc = ((header24 >> 12) & 63) as f64 / 31.5 - 1.0;
+    let l_scale = ((header24 >> 18) & 31) as f64 / 31.0;
+    let has_alpha = header24 >> 23 != 0;
+    let p_scale = ((header16 >> 3) & 63) as f64 / 63.0;
+    let q_scale = ((header16 >> 9) & 63) as f64 / 63.0;
+    let landscape = header16 >> 15 != 0;
+    let limit = if has_alpha { 5 } else { 7 };
+    let (lx, ly) = match landscape {
+        true => (limit, (header16 & 7) as usize),
+        false => ((header16 & 7) as usize, limit),
+    };
+    let ratio = lx as f64 / ly.max(1) as f64;
+    let (lx, ly) = (lx.max(3), ly.max(3));
+    let a_dc = match has_alpha {
+        true => (*hash.get(5).unwrap_or(&0) & 15) as f64 / 15.0,
+        false => 1.0,
+    };
+    let a_scale = (*hash.get(5).unwrap_or(&0) >> 4) as f64 / 15.0;
+
+    // The factors in the order they were written, the colors boosted by 1.25
+    // to make up for the saturation lost to quantization
+    let mut index = 0;
+    let start = if has_alpha { 6 } else { 5 };
+    let mut channel = |nx: usize, ny: usize, scale: f64| -> Result<Vec<f64>, ImgtoolsError> {
+        let mut ac = Vec::new();
+        for cy in 0..ny {
+            let mut cx = if cy == 0 { 1 } else { 0 };
+            while cx * ny < nx * (ny - cy) {
+                let byte = hash.get(start + index / 2).ok_or_else(|| {
+                    ImgtoolsError::InvalidArgument("Invalid ThumbHash, it is too short".into())
+                })?;
+                let bits = (byte >> ((index % 2) * 4)) & 15;
+                ac.push((bits as f64 / 7.5 - 1.0) * scale);
+                index += 1;
+                cx += 1;
+            }
+        }
+        Ok(ac)
+    };
+    let l_ac = channel(lx, ly, l_scale)?;
+    let p_ac = channel(3, 3, p_scale * 1.25)?;
+    let q_ac = channel(3, 3, q_scale * 1.25)?;
+    let a_ac = match has_alpha {
+        true => channel(5, 5, a_scale)?,
+        false => Vec::new(),
+    };
+
+    let Size(w, h) = size.unwrap_or(match ratio > 1.0 {
+        true => Size(32, (32.0 / ratio).round().max(1.0) as u32),
+        false => Size((32.0 * ratio).round().max(1.0) as u32, 32),
+    });
+    // Sum of the factors of a triangle of frequencies at a pixel
+    let sum = |factors: &[f64], nx: usize, ny: usize, fx: &[f64], fy: &[f64]| {
+        let mut total = 0.0;
+        let mut j = 0;
+        for (cy, fy) in fy.iter().enumerate().take(ny) {
+            let mut cx = if cy == 0 { 1 } else { 0 };
+            while cx * ny < nx * (ny - cy) {
+                total += factors[j] * fx[cx] * fy * 2.0;
+                j += 1;
+                cx += 1;
+            }
+        }
+        total
+    };
+    Ok(RgbaImage::from_fn(w, h, |x, y| {
+        let n = lx.max(ly).max(5);
+        let fx: Vec<f64> = (0..n)
+            .map(|cx| (PI / w as f64 * (x as f64 + 0.5) * cx as f64).cos())
+            .collect();
+        let fy: Vec<f64> = (0..n)
+            .map(|cy| (PI / h as f64 * (y as f64 + 0.5) * cy as f64).cos())
+            .collect();
+        let l = l_dc + sum(&l_ac, lx, ly, &fx, &fy);
+        let p = p_dc + sum(&p_ac, 3, 3, &fx, &fy);
+        let q = q_dc + sum(&q_ac, 3, 3, &fx, &fy);
+        let a = match has_alpha {
+            true => a_dc + sum(&a_ac, 5, 5, &fx, &fy),
+            false => 1.0,
+        };
+        let b = l - 2.0 / 3.0 * p;
+        let r = (3.0 * l - b + q) / 2.0;
+        let g = r - q;
+        Rgba([r, g, b, a].map(|v| (255.0 * v.clamp(0.0, 1.0)) as u8))
+    }))
+}
+
+/// Low quality image placeholder, a tiny JPEG small enough to inline in a page
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lqip {
+    /// The preview as a `data:image/jpeg;base64,` URI
+    pub data_uri: String,
+    pub width: u32,
+    pub height: u32,
+    /// The most common color, a background to show before even the preview
+    pub color: [u8; 3],
+}
+
+/// Shrink the image to `width` pixels across and encode it as a JPEG of the
+/// given quality, along with its dominant color
+///
+/// JPEG has no transparency, transparent parts are filled with the dominant
+/// color so the preview blends into the background it is shown on.
+pub fn lqip(img: &DynamicImage, width: u32, quality: u8) -> Result<Lqip, ImgtoolsError> {
+    let width = width.clamp(1, img.width());
+    let height = (img.height() as u64 * width as u64 / img.width() as u64).max(1) as u32;
+    let color = dominant_colors(&shrink(img).to_rgba8(), 1, PaletteMethod::KMeans)
+        .first()
+        .map_or([0, 0, 0], |&(color, _)| color);
+    let [r, g, b] = color;
+    let preview = flatten(
+        &img.resize_exact(width, height, FilterType::Triangle),
+        Rgba([r, g, b, 255]),
+    );
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality.clamp(1, 100))
+        .encode_image(&preview.to_rgb8())
+        .map_err(ImgtoolsError::Encode)?;
+    Ok(Lqip {
+        data_uri: format!("data:image/jpeg;base64,{}", encode_base64(&jpeg)),
+        width,
+        height,
+        color,
+    })
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+/// Bytes of standard or URL-safe base64, with or without padding
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim().trim_end_matches('=').bytes() {
+        let digit = match c {
+            b'-' => 62,
+            b'_' => 63,
+            c => BASE64.iter().position(|&d| d == c)? as u32,
+        };
+        bits = (bits << 6 | digit) & 0xffff;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A red to blue gradient, the top half transparent when `alpha` is set
+    fn gradient(w: u32, h: u32, alpha: bool) -> DynamicImage {
+        let img = RgbaImage::from_fn(w, h, |x, y| {
+            let t = (x * 255 / (w - 1)) as u8;
+            let a = if alpha && y < h / 2 { 0 } else { 255 };
+            Rgba([255 - t, 40, t, a])
+        });
+        DynamicImage::ImageRgba8(img)
+    }
+
+    #[test]
+    fn test_blurhash() {
+        // A flat color is the average alone
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([255, 0, 0])));
+        assert_eq!(blurhash(&red, 1, 1).unwrap(), "00TI:j");
+        let hash = blurhash(&red, 4, 3).unwrap();
+        assert_eq!(hash.len(), 4 + 2 * 12);
+        // BlurHash samples pixel corners, so a flat image has faint waves too
+        assert_eq!(&hash[..6], "LfTI:j");
+        let flat = decode_blurhash(&hash, Size(5, 4)).unwrap();
+        assert!(flat.pixels().all(|p| p[0] > 200 && p[1] == 0 && p[2] == 0));
+
+        // A gradient comes back close to itself
+        let img = gradient(60, 30, false);
+        let hash = blurhash(&img, 4, 3).unwrap();
+        let decoded = decode_blurhash(&hash, Size(60, 30)).unwrap();
+        let original = img.to_rgb8();
+        for x in [5, 30, 55] {
+            let (a, b) = (original.get_pixel(x, 15), decoded.get_pixel(x, 15));
+            assert!((0..3).all(|c| a[c].abs_diff(b[c]) < 30), "{:?} {:?}", a, b);
+        }
+
+        assert!(blurhash(&img, 0, 3).is_err());
+        assert!(blurhash(&img, 4, 10).is_err());
+        assert!(decode_blurhash("LEHV6nWB2yk8", Size(4, 4)).is_err());
+        assert!(decode_blurhash("00TI:!", Size(4, 4)).is_err());
+    }
+
+    #[test]
+    fn test_thumbhash() {
+        let img = gradient(80, 40, false);
+        let hash = thumbhash(&img);
+        let decoded = decode_thumbhash(&hash, None).unwrap();
+        // The aspect ratio is kept roughly, 7:4 for 2:1
+        assert_eq!(decoded.dimensions(), (32, 18));
+        assert!(decoded.pixels().all(|p| p[3] == 255));
+        let (left, right) = (decoded.get_pixel(2, 9), decoded.get_pixel(29, 9));
+        assert!(left[0] > 180 && left[2] < 80, "{:?}", left);
+        assert!(right[2] > 180 && right[0] < 80, "{:?}", right);
+
+        // Transparency is kept too
+        let hash = thumbhash(&gradient(40, 40, true));
+        let decoded = decode_thumbhash(&hash, Some(Size(20, 20))).unwrap();
+        assert_eq!(decoded.dimensions(), (20, 20));
+        assert!(decoded.get_pixel(10, 1)[3] < 60);
+        assert!(decoded.get_pixel(10, 18)[3] > 200);
+
+        assert!(decode_thumbhash("AAA", None).is_err());
+        assert!(decode_thumbhash("not base64!", None).is_err());
+    }
+
+    #[test]
+    fn test_lqip() {
+        let preview = lqip(&gradient(80, 40, false), 16, 30).unwrap();
+        assert_eq!((preview.width, preview.height), (16, 8));
+        let data = preview
+            .data_uri
+            .strip_prefix("data:image/jpeg;base64,")
+            .unwrap();
+        let jpeg = image::load_from_memory(&decode_base64(data).unwrap()).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (16, 8));
+        // Most of a tiny preview is the JPEG tables, it stays about a kilobyte
+        assert!(data.len() < 1500, "{}", data.len());
+
+        // Transparent parts take the dominant color
+        let red = RgbaImage::from_fn(10, 10, |x, _| match x < 8 {
+            true => Rgba([250, 0, 0, 255]),
+            false => Rgba([0, 0, 0, 0]),
+        });
+        let preview = lqip(&DynamicImage::ImageRgba8(red), 32, 90).unwrap();
+        assert_eq!((preview.width, preview.height), (10, 10));
+        assert_eq!(preview.color, [250, 0, 0]);
+        let data = &preview.data_uri[23..];
+        let jpeg = image::load_from_memory(&decode_base64(data).unwrap()).unwrap();
+        assert!(jpeg.to_rgb8().pixels().all(|p| p[0] > 200 && p[1] < 50));
+    }
+
+    #[test]
+    fn test_base64() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", &[0xfb, 0xff, 0x00]] {
+            let text = encode_base64(bytes);
+            assert_eq!(decode_base64(&text).as_deref(), Some(bytes));
+        }
+        assert_eq!(encode_base64(b"foob"), "Zm9vYg==");
+        assert_eq!(decode_base64("-_8").unwrap(), [0xfb, 0xff]);
+    }
+}
@@ -0,0 +1,5313 @@
+use crate::adjust::{
+    Adjustments, adjust, autolevel, balance_gains, equalize, gray_point_gains, shadows_highlights,
+    temperature_gains,
+};
+use crate::analysis::{Assessment, Comparison, Histogram, Statistics, diff_heatmap};
+use crate::animation::Animation;
+use crate::annotate::annotate;
+use crate::barcode::{CodeStyle, Symbology, encode_code, overlay_logo, render_code};
+use crate::bench::Benchmark;
+use crate::caption::{Caption, render_caption};
+use crate::carve::liquid_resize;
+use crate::channels::{
+    apply_mask, channel_names, chromakey, extract_alpha, flatten, merge, premultiply, split,
+};
+use crate::colormap::tint;
+use crate::composite::{Tiling, composite, placements};
+use crate::create::create;
+use crate::deskew::{horizon_angle, level, skew_angle, straighten};
+use crate::draw::{border, round};
+use crate::effects::{posterize, redact, solarize, threshold, vignette};
+use crate::encoding::{EncodeOptions, PARALLEL_PNG_BYTES, encode_png};
+#[cfg(feature = "faces")]
+use crate::faces::detect_faces;
+use crate::filters::{BOX_BLUR_SIGMA, box_blur, convolve, denoise, edges, emboss, noise};
+use crate::fonts::Fonts;
+use crate::geometry::{
+    Tiles, fit_dimensions, icon, pad, resize_dimensions, shear, slice, thumbnail, trim_bounds,
+};
+use crate::hashing::{ImageHash, hash_report};
+use crate::hdr::{self, fuse};
+use crate::jpeg::{DEFAULT_QUALITY, encode_progressive};
+use crate::layout::{Captions, Grid, append, montage};
+use crate::logging::{Level, log_with};
+use crate::mapped::{InputData, map_file};
+use crate::metadata::{
+    ImageInfo, Metadata, exif_report, insert_xmp, read_exif, read_icc, read_orientation,
+};
+#[cfg(feature = "onnx")]
+use crate::onnx::{Inference, infer, remove_background, upscale_with_model};
+use crate::optimize::optimize;
+use crate::placeholder::{blurhash, decode_blurhash, decode_thumbhash, lqip, thumbhash};
+use crate::profile::{convert_profile, profile_data};
+use crate::pyramid::{dzi_descriptor, pyramid_levels, pyramid_tiles, xyz_descriptor};
+use crate::quantize::{Palette, dominant_colors, palette_report, quantize, render_swatches};
+use crate::resample::fast_resize;
+use crate::srcset::{Variant, manifest_html, manifest_json, srcset_height, srcset_widths};
+use crate::stack::focus_stack;
+#[cfg(feature = "stitch")]
+use crate::stitch::stitch;
+use crate::stream::{CropRows, DecodeAhead, ResizeRows, Rows, StampRows, open_rows, write_rows};
+use crate::strip::{Keep, redact_metadata, strip_metadata};
+use crate::tags::{set_fields, set_metadata, set_tiff_exif};
+use crate::upscale::upscale;
+use crate::{
+    Alpha, ChannelMap, Channels, Command, Crop, ExifAction, Focus, Format, FrameRange,
+    HistogramFormat, ImgtoolsError, Length, PaletteMethod, PlaceholderAlgorithm, Position, Profile,
+    PyramidLayout, Region, ReportFormat, Rotate, Scale, Size, ThumbnailMode, Watermark,
+};
+use ab_glyph::PxScale;
+use clap::ValueEnum;
+use gif::Repeat;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::gif::GifEncoder;
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::{FilterType, overlay};
+use image::metadata::Orientation;
+use image::{
+    ColorType, Delay, DynamicImage, ExtendedColorType, GenericImageView, ImageBuffer, ImageDecoder,
+    ImageEncoder, ImageFormat, ImageReader, Limits, Rgba, RgbaImage,
+};
+use imageproc::geometric_transformations::{Interpolation, rotate_about_center};
+use serde_json::{Map, json};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::iter::Peekable;
+use std::path::{self, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::vec;
+
+/// Path that stands for standard input or standard output
+pub const STDIO: &str = "-";
+
+/// Options that apply to every processed image
+#[derive(Debug, Default, Clone)]
+pub struct ProcessOptions {
+    /// Input format, used instead of guessing from the content
+    pub input_format: Option<Format>,
+    /// Copy EXIF, XMP and ICC metadata from the input to the output
+    pub keep_metadata: bool,
+    /// Apply the EXIF orientation before processing
+    pub auto_orient: bool,
+    /// Profile of inputs without an embedded ICC profile
+    pub assume_profile: Option<Profile>,
+    /// Profile the colors are converted to and tagged with
+    pub convert_to: Option<Profile>,
+    /// Only show what would be done, without writing anything
+    pub dry_run: bool,
+    /// Log how long every stage of an image takes
+    pub verbose: bool,
+    /// Output file name with placeholders such as `{stem}` and `{width}`
+    pub output_template: Option<String>,
+    /// Position of the input among all inputs starting at 1, for `{counter}`
+    pub counter: usize,
+    /// Replace existing files, including the input itself
+    pub force: bool,
+    /// Allow replacing the input itself
+    pub in_place: bool,
+    /// Suffix of the copy made of the input before it is replaced, e.g. `.bak`
+    pub backup: Option<String>,
+    /// Progressive JPEG and interlaced PNG
+    pub encoding: EncodeOptions,
+    /// Remove metadata from the output except the kinds listed
+    pub strip: Option<Vec<Keep>>,
+    /// Decoded size above which images are processed in strips
+    pub max_memory: Option<u64>,
+    /// How SVG and camera RAW input are turned into pixels
+    pub decoding: DecodeOptions,
+}
+
+/// Resolution SVG input is rasterized at by default, in dots per inch
+pub const DEFAULT_DENSITY: f32 = 96.0;
+
+/// The commands that make an image without reading one, as messages name them
+pub const INPUTLESS_COMMANDS: &str =
+    "create, qrcode, placeholder --decode and recipes or presets of only one of them";
+
+/// How inputs that aren't stored as finished pixels are turned into them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeOptions {
+    /// Dots per inch SVG is rasterized at, 96 draws one CSS pixel as one pixel
+    pub density: f32,
+    /// Fit SVG drawings in this size instead, keeping their aspect ratio
+    pub render_size: Option<Size>,
+    /// White balance of camera RAW files
+    pub white_balance: WhiteBalance,
+    /// Stops added to the exposure of camera RAW files
+    pub exposure: f32,
+    /// Largest decoded image in bytes, larger ones are refused before they
+    /// are decoded. Without it the image crate's default limit applies
+    pub max_alloc: Option<u64>,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            density: DEFAULT_DENSITY,
+            render_size: None,
+            white_balance: WhiteBalance::Camera,
+            exposure: 0.0,
+            max_alloc: None,
+        }
+    }
+}
+
+impl DecodeOptions {
+    /// Refuse to decode or make an image of `bytes` bytes if it is over the
+    /// limit, for the decoders and commands of this crate, the image crate
+    /// checks its own
+    pub(crate) fn check_alloc(&self, bytes: u64) -> Result<(), ImgtoolsError> {
+        match self.max_alloc.is_some_and(|max| bytes > max) {
+            true => Err(ImgtoolsError::Decode(image::ImageError::Limits(
+                image::error::LimitError::from_kind(
+                    image::error::LimitErrorKind::InsufficientMemory,
+                ),
+            ))),
+            false => Ok(()),
+        }
+    }
+}
+
+/// White balance applied to camera RAW files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WhiteBalance {
+    /// As shot, from the neutral the camera recorded
+    Camera,
+    /// Make the average of the image gray
+    Auto,
+    /// Daylight, from the color calibration of a DNG
+    Daylight,
+}
+
+/// Check whether the path refers to standard input or output
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+/// Check whether the input is a URL to download the image from
+pub fn is_url(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    ["http://", "https://"].iter().any(|scheme| {
+        path.get(..scheme.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(scheme))
+    })
+}
+
+/// File name of an input, `stdin` for standard input and the last path
+/// segment of a URL
+fn input_name(input: &Path) -> Option<PathBuf> {
+    if is_stdio(input) {
+        return Some(PathBuf::from("stdin"));
+    }
+    if is_url(input) {
+        let url = input.to_string_lossy();
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+        let name = path.rsplit_once("://").map_or(path, |(_, rest)| rest);
+        return match name
+            .split_once('/')
+            .map(|(_, path)| path.rsplit('/').next())
+        {
+            Some(Some(name)) if !name.is_empty() => Some(PathBuf::from(name)),
+            _ => Some(PathBuf::from("download")),
+        };
+    }
+    input.file_name().map(PathBuf::from)
+}
+
+/// Open and decode an image file, guessing the format from its content
+pub fn open_image(path: &Path) -> Result<DynamicImage, ImgtoolsError> {
+    let data = read_input(path)?;
+    decode(&data, None)
+}
+
+/// Extensions of camera RAW files, most of them look like plain TIFF inside
+const RAW_EXTENSIONS: [&str; 11] = [
+    "cr2", "cr3", "nef", "nrw", "arw", "srf", "sr2", "dng", "orf", "rw2", "raf",
+];
+
+pub(crate) fn has_raw_extension(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| RAW_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Read the whole input from a file, standard input or a URL
+///
+/// Large files are mapped into memory rather than copied into a buffer.
+fn read_input(path: &Path) -> Result<InputData, ImgtoolsError> {
+    if is_url(path) {
+        #[cfg(feature = "fetch")]
+        return crate::fetch::fetch(&path.to_string_lossy()).map(InputData::from);
+        #[cfg(not(feature = "fetch"))]
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Reading {} needs imgtools built with the fetch feature",
+            path.display()
+        )));
+    }
+    let result = match is_stdio(path) {
+        true => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data).map(|_| data.into())
+        }
+        false => fs::File::open(path).and_then(map_file),
+    };
+    let data = result.map_err(|source| ImgtoolsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    // Don't let the TIFF decoder pick up the embedded preview of a RAW file
+    if has_raw_extension(path) && !is_raw(&data) {
+        return Err(raw_unsupported());
+    }
+    Ok(data)
+}
+
+/// Decode image data, `format` overrides detection from the content
+pub(crate) fn decode(
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> Result<DynamicImage, ImgtoolsError> {
+    decode_with_options(data, format, &DecodeOptions::default())
+}
+
+/// Decode image data, rasterizing SVG and developing camera RAW files as
+/// `options` say
+///
+/// RAW files look like TIFF, the sensor data is decoded rather than the
+/// preview a TIFF decoder finds.
+pub(crate) fn decode_with_options(
+    data: &[u8],
+    format: Option<ImageFormat>,
+    options: &DecodeOptions,
+) -> Result<DynamicImage, ImgtoolsError> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    if let Some(max_alloc) = options.max_alloc {
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(max_alloc);
+        reader.limits(limits);
+    }
+    match format {
+        None | Some(ImageFormat::Tiff) if is_raw(data) => return develop_raw(data, options),
+        Some(format) => reader.set_format(format),
+        None if let Some(error) = unsupported_input(data) => return Err(error),
+        None if is_heif(data) => return decode_heif(data, options),
+        None if is_svg(data) => return rasterize_svg(data, options),
+        None => {
+            reader = reader
+                .with_guessed_format()
+                .map_err(|e| ImgtoolsError::Decode(e.into()))?
+        }
+    }
+    reader.decode().map_err(ImgtoolsError::Decode)
+}
+
+#[cfg(feature = "svg")]
+fn rasterize_svg(data: &[u8], options: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    crate::svg::rasterize(data, options).map(DynamicImage::from)
+}
+
+#[cfg(not(feature = "svg"))]
+fn rasterize_svg(_: &[u8], _: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    Err(ImgtoolsError::InvalidArgument(
+        "Reading SVG images needs imgtools built with the svg feature".into(),
+    ))
+}
+
+/// Check for a HEIC or HEIF file by the brands of its ftyp box
+///
+/// AVIF shares the container, its brands tell it apart.
+pub(crate) fn is_heif(data: &[u8]) -> bool {
+    const BRANDS: [&[u8]; 8] = [
+        b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"hevm", b"hevs",
+    ];
+    let Some(size) = data.get(..4).filter(|_| data.get(4..8) == Some(b"ftyp")) else {
+        return false;
+    };
+    let end = (u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize).min(data.len());
+    // The major brand, or a generic one with the codec among the compatible brands
+    let compatible = data.get(16..end).unwrap_or_default().chunks_exact(4);
+    data.get(8..12)
+        .into_iter()
+        .chain(compatible)
+        .any(|brand| BRANDS.contains(&brand))
+}
+
+#[cfg(feature = "heic")]
+fn decode_heif(data: &[u8], options: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    crate::heic::decode(data, options)
+}
+
+#[cfg(not(feature = "heic"))]
+fn decode_heif(_: &[u8], _: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    Err(ImgtoolsError::InvalidArgument(
+        "Reading HEIC/HEIF images needs imgtools built with the heic feature".into(),
+    ))
+}
+
+/// Check for camera RAW data the raw decoder reads, DNG and other TIFF-based files
+#[cfg(feature = "raw")]
+pub(crate) fn is_raw(data: &[u8]) -> bool {
+    crate::raw::is_raw(data)
+}
+
+#[cfg(not(feature = "raw"))]
+pub(crate) fn is_raw(_: &[u8]) -> bool {
+    false
+}
+
+#[cfg(feature = "raw")]
+fn develop_raw(data: &[u8], options: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    crate::raw::decode(data, options).map(DynamicImage::from)
+}
+
+#[cfg(not(feature = "raw"))]
+fn develop_raw(_: &[u8], _: &DecodeOptions) -> Result<DynamicImage, ImgtoolsError> {
+    Err(raw_unsupported())
+}
+
+/// Check for a Canon CR2 or CR3 file, the other RAW formats are told by their extension
+pub(crate) fn is_camera_raw(data: &[u8]) -> bool {
+    let cr2 = data.starts_with(b"II*\0") && data.get(8..10) == Some(b"CR");
+    let cr3 = data.get(4..12) == Some(b"ftypcrx ");
+    cr2 || cr3
+}
+
+/// Error for camera RAW input the raw decoder can't read
+fn raw_unsupported() -> ImgtoolsError {
+    let message = match cfg!(feature = "raw") {
+        true => {
+            "Only DNG, CR2 and TIFF-based camera RAW files such as NEF can be decoded, convert this one to DNG first"
+        }
+        false => "Decoding camera RAW files needs imgtools built with the raw feature",
+    };
+    ImgtoolsError::InvalidArgument(message.into())
+}
+
+/// Check for an SVG document, whose root element is `<svg>`
+///
+/// An XML declaration, comments and a doctype may come before it, other
+/// XML and HTML with inline SVG are not SVG documents.
+pub(crate) fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(4096)]);
+    let mut rest = head.trim_start_matches('\u{feff}');
+    loop {
+        rest = rest.trim_start();
+        let skipped = if let Some(after) = rest.strip_prefix("<?") {
+            after.split_once("?>").map(|(_, after)| after)
+        } else if let Some(after) = rest.strip_prefix("<!--") {
+            after.split_once("-->").map(|(_, after)| after)
+        } else if rest.starts_with("<!") {
+            // A doctype may hold an internal subset of declarations in brackets
+            let end = match rest
+                .find('[')
+                .filter(|&i| rest.find('>').is_some_and(|j| i < j))
+            {
+                Some(_) => rest.find("]>").map(|i| i + 2),
+                None => rest.find('>').map(|i| i + 1),
+            };
+            end.map(|end| &rest[end..])
+        } else {
+            let name = rest.strip_prefix('<').unwrap_or_default();
+            let name = name.strip_prefix("svg:").unwrap_or(name);
+            return name.strip_prefix("svg").is_some_and(|after| {
+                after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            });
+        };
+        match skipped {
+            Some(after) => rest = after,
+            None => return false,
+        }
+    }
+}
+
+/// Error for recognized input formats that have no decoder yet
+pub(crate) fn unsupported_input(data: &[u8]) -> Option<ImgtoolsError> {
+    is_camera_raw(data).then(raw_unsupported)
+}
+
+/// Where the processed image is written
+enum Target {
+    /// Standard output in the given format
+    Stdout(Format),
+    /// A file, in the given format or the one implied by its extension
+    File(PathBuf, Option<Format>),
+}
+
+impl Target {
+    /// Resolve the output destination for an input
+    ///
+    /// Without an output the image is written next to the input, a directory
+    /// output keeps the input file name. Reading from standard input writes
+    /// to standard output unless an output is given.
+    fn resolve(
+        input: &Path,
+        output: Option<&Path>,
+        command: &Command,
+        input_format: Option<ImageFormat>,
+    ) -> Result<Self, ImgtoolsError> {
+        let format = output_format(command);
+
+        // Write to standard output, keeping the input format unless converted
+        if is_stdio(input) && output.is_none() || output.is_some_and(is_stdio) {
+            let format = format
+                .or_else(|| input_format.and_then(|f| Format::try_from(f).ok()))
+                .ok_or_else(|| {
+                    ImgtoolsError::InvalidArgument(
+                        "Unable to determine the output format, use convert to select one".into(),
+                    )
+                })?;
+            return Ok(Target::Stdout(format));
+        }
+
+        // Extract input file name and paths
+        let input_file_name = input_name(input).ok_or_else(|| {
+            ImgtoolsError::InvalidArgument("Failed to get input file name".into())
+        })?;
+        // Downloads are written to the current directory
+        let input_path = match is_url(input) {
+            true => Path::new(""),
+            false => input.parent().ok_or_else(|| {
+                ImgtoolsError::InvalidArgument("Failed to get parent path".into())
+            })?,
+        };
+        let output_path = output.unwrap_or(input_path);
+
+        // A trailing separator names a directory that doesn't exist yet
+        let to_dir = output_path.is_dir()
+            || output_path.as_os_str().is_empty()
+            || output_path.to_string_lossy().ends_with(path::is_separator);
+        let path = match (to_dir, format) {
+            (true, Some(format)) => {
+                output_path.join(input_file_name.with_extension(format.to_string()))
+            }
+            (true, None) => output_path.join(input_file_name),
+            (false, _) => output_path.to_path_buf(),
+        };
+        Ok(Target::File(path, format))
+    }
+
+    /// Output image format, if known
+    fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            Target::Stdout(format) | Target::File(_, Some(format)) => Some((*format).into()),
+            Target::File(path, None) => ImageFormat::from_path(path).ok(),
+        }
+    }
+
+    /// Make sure writing won't replace a file that wasn't meant to be replaced
+    fn check(&self, input: &Path, options: &ProcessOptions) -> Result<(), ImgtoolsError> {
+        match self {
+            Target::Stdout(_) => Ok(()),
+            Target::File(path, _) => check_overwrite(path, input, options),
+        }
+    }
+
+    /// Write encoded data produced by `write`
+    fn write<F>(&self, write: F) -> Result<Written, ImgtoolsError>
+    where
+        F: FnOnce(&mut Cursor<Vec<u8>>) -> Result<(), ImgtoolsError>,
+    {
+        let mut data = Cursor::new(Vec::new());
+        write(&mut data)?;
+
+        let (path, result) = match self {
+            Target::Stdout(_) => {
+                let mut stdout = io::stdout().lock();
+                let result = stdout
+                    .write_all(data.get_ref())
+                    .and_then(|_| stdout.flush());
+                (PathBuf::from(STDIO), result)
+            }
+            // Replace the file only once it is written, an input read from a
+            // memory map may still be in use when editing in place
+            Target::File(path, _) => {
+                let partial = partial_path(path);
+                let result = fs::write(&partial, data.get_ref())
+                    .and_then(|_| replace_with_partial(&partial, path));
+                if result.is_err() {
+                    let _ = fs::remove_file(&partial);
+                }
+                (path.clone(), result)
+            }
+        };
+        match result {
+            Ok(()) => Ok(Written {
+                path,
+                bytes: data.get_ref().len() as u64,
+            }),
+            Err(source) => Err(ImgtoolsError::Write { path, source }),
+        }
+    }
+}
+
+/// File an output is written to before it replaces `path`
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+/// Move a written partial file over `path`, keeping the permissions of the file it replaces
+fn replace_with_partial(partial: &Path, path: &Path) -> io::Result<()> {
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(partial, metadata.permissions())?;
+    }
+    fs::rename(partial, path)
+}
+
+/// Save an image in the format its extension implies
+fn save(img: &DynamicImage, path: &Path) -> Result<Written, ImgtoolsError> {
+    let format = ImageFormat::from_path(path).map_err(ImgtoolsError::Encode)?;
+    let partial = partial_path(path);
+    let saved = img
+        .save_with_format(&partial, format)
+        .map_err(ImgtoolsError::Encode)
+        .and_then(|_| {
+            replace_with_partial(&partial, path).map_err(|source| ImgtoolsError::Write {
+                path: path.to_path_buf(),
+                source,
+            })
+        });
+    if saved.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    saved?;
+    Ok(Written {
+        path: path.to_path_buf(),
+        bytes: fs::metadata(path).map_or(0, |m| m.len()),
+    })
+}
+
+/// A file written while processing, `-` for standard output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Written {
+    pub path: PathBuf,
+    /// Size of the encoded image
+    pub bytes: u64,
+}
+
+/// What processing one input read and wrote
+#[derive(Debug, Clone, PartialEq)]
+pub struct Processed {
+    /// Size of the input
+    pub bytes: u64,
+    pub outputs: Vec<Written>,
+}
+
+/// Input format given in the options, or guessed from the content
+fn input_format(data: &[u8], options: &ProcessOptions) -> Option<ImageFormat> {
+    match options.input_format {
+        Some(format) => Some(format.into()),
+        None => image::guess_format(data).ok(),
+    }
+}
+
+/// Where the output of an input goes
+///
+/// A template names it once the dimensions are known, creating the
+/// directories it leads to.
+fn output_target(
+    input: &Path,
+    output: Option<&Path>,
+    command: &Command,
+    input_format: Option<ImageFormat>,
+    dimensions: Option<(u32, u32)>,
+    options: &ProcessOptions,
+) -> Result<Target, ImgtoolsError> {
+    let Some(template) = &options.output_template else {
+        return Target::resolve(input, output, command, input_format);
+    };
+    let target = templated_target(
+        template,
+        input,
+        output,
+        command,
+        input_format,
+        dimensions,
+        options.counter,
+    )?;
+    if let (Target::File(path, _), Some(_)) = (&target, dimensions)
+        && let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir).map_err(|source| ImgtoolsError::Write {
+            path: dir.to_path_buf(),
+            source,
+        })?;
+    }
+    Ok(target)
+}
+
+/// Process an image too large for --max-memory a strip at a time
+///
+/// Returns `None` for images that fit, which are decoded whole as usual.
+/// Only PNG and TIFF files are read and written in strips, and only crop,
+/// resize, convert and watermark steps work on strips.
+fn stream_file(
+    input: &Path,
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+    limit: u64,
+) -> Result<Option<Processed>, ImgtoolsError> {
+    // The size of a RAW file is that of its preview until the sensor data is decoded
+    if is_stdio(input) || is_url(input) || has_raw_extension(input) {
+        return Ok(None);
+    }
+    let read_error = |source| ImgtoolsError::Read {
+        path: input.to_path_buf(),
+        source,
+    };
+    let mut reader = ImageReader::open(input).map_err(read_error)?;
+    match options.input_format {
+        Some(format) => reader.set_format(format.into()),
+        None => reader = reader.with_guessed_format().map_err(read_error)?,
+    }
+    let format = reader.format();
+    // Leave reporting unreadable images to the usual path
+    let Ok(decoder) = reader.into_decoder() else {
+        return Ok(None);
+    };
+    let ((width, height), needed) = (decoder.dimensions(), decoder.total_bytes());
+    drop(decoder);
+    if needed <= limit {
+        return Ok(None);
+    }
+    let too_large = |why: &str| {
+        ImgtoolsError::InvalidArgument(format!(
+            "Decoding the {}x{} image takes {} bytes, more than --max-memory {}; {}",
+            width, height, needed, limit, why
+        ))
+    };
+
+    let steps = command.steps();
+    let streamable = steps.iter().all(|step| {
+        matches!(
+            step,
+            Command::Crop { focus: None, .. }
+                | Command::Resize { .. }
+                | Command::Convert { .. }
+                | Command::Watermark { .. }
+        )
+    });
+    let Some(format) = format.filter(|f| matches!(f, ImageFormat::Png | ImageFormat::Tiff)) else {
+        return Err(too_large("only PNG and TIFF images are read in strips"));
+    };
+    if !streamable {
+        return Err(too_large(
+            "only crop, resize, convert and watermark work on strips",
+        ));
+    }
+    if options.keep_metadata
+        || options.auto_orient
+        || options.assume_profile.is_some()
+        || options.convert_to.is_some()
+        || options.encoding.interlace.is_some()
+    {
+        return Err(too_large(
+            "keeping metadata, orienting, color management and interlacing need the whole image",
+        ));
+    }
+
+    // Decode on another thread while the steps and the encoder work
+    let source = open_rows(input, format)?;
+    let mut rows: Box<dyn Rows> = match rayon::current_num_threads() {
+        1 => source,
+        _ => Box::new(DecodeAhead::new(source)),
+    };
+    for step in steps {
+        let size = rows.size();
+        rows = match *step {
+            Command::Crop {
+                crop, clamp, pad, ..
+            } => {
+                let rect = crop_rect(crop, size);
+                let inside = fit_crop(rect, size, clamp || pad.is_some())?;
+                if (inside.2, inside.3) != (rect.2, rect.3) && pad.is_some() {
+                    return Err(too_large("padding a crop needs the whole image"));
+                }
+                Box::new(CropRows::new(rows, inside))
+            }
+            Command::Resize {
+                width: w,
+                height: h,
+                exact,
+                filter,
+                scale,
+                ..
+            } => {
+                let fit = !exact && w.is_some() && h.is_some();
+                let (w, h) = resize_dimensions(size, w, h, scale)?;
+                let (w, h) = match fit {
+                    true => fit_dimensions(size, (w, h)),
+                    false => (w, h),
+                };
+                match (w, h) == size {
+                    true => rows,
+                    false => Box::new(ResizeRows::new(rows, (w, h), filter)),
+                }
+            }
+            Command::Watermark { .. } => {
+                let stamps = watermark_stamps(step, size)?;
+                Box::new(StampRows::new(rows, stamps.images, stamps.placements))
+            }
+            _ => rows,
+        };
+    }
+
+    let target = output_target(
+        input,
+        output,
+        command,
+        Some(format),
+        Some(rows.size()),
+        options,
+    )?;
+    let out_format = target.image_format();
+    let Target::File(path, _) = target else {
+        return Err(too_large(
+            "images written in strips can't go to standard output",
+        ));
+    };
+    let Some(out_format) = out_format
+        .filter(|f| matches!(f, ImageFormat::Png | ImageFormat::Tiff))
+        .and_then(|f| Format::try_from(f).ok())
+    else {
+        return Err(too_large("only PNG and TIFF images are written in strips"));
+    };
+    check_overwrite(&path, input, options)?;
+    let bytes = fs::metadata(input).map_or(0, |m| m.len());
+
+    // Write next to the output and replace it once done, which also keeps
+    // the input intact while it is still being read when editing in place
+    let partial = partial_path(&path);
+    let write_error = |source| ImgtoolsError::Write {
+        path: path.clone(),
+        source,
+    };
+    let mut file = io::BufWriter::new(fs::File::create(&partial).map_err(write_error)?);
+    let written = write_rows(rows.as_mut(), out_format, &mut file)
+        .and_then(|_| file.flush().map_err(write_error))
+        .and_then(|_| replace_with_partial(&partial, &path).map_err(write_error));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written?;
+    Ok(Some(Processed {
+        bytes,
+        outputs: vec![Written {
+            bytes: fs::metadata(&path).map_or(0, |m| m.len()),
+            path,
+        }],
+    }))
+}
+
+/// Decode one image, run the command on it and save the result
+///
+/// Without an output the image is written next to the input, a directory
+/// output keeps the input file name. `-` reads from standard input or writes
+/// to standard output. Animated GIFs saved as GIF keep all of their frames.
+pub fn process_file(
+    input: &Path,
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Processed, ImgtoolsError> {
+    // A strip step removes metadata from the whole output, as --strip does
+    let with_strip;
+    let options = match stripping(command) {
+        Some(keep) => {
+            with_strip = ProcessOptions {
+                strip: Some(keep),
+                ..options.clone()
+            };
+            &with_strip
+        }
+        None => options,
+    };
+    // Work through images too large to decode whole in strips
+    if let Some(limit) = options.max_memory
+        && let Some(processed) = stream_file(input, output, command, options, limit)?
+    {
+        return Ok(processed);
+    }
+    let mut stopwatch = Stopwatch::new(input, options.verbose);
+    let data = read_input(input)?;
+    stopwatch.lap("read");
+    let processed = |outputs| Processed {
+        bytes: data.len() as u64,
+        outputs,
+    };
+    let input_format = input_format(&data, options);
+    if let Command::Slice { size, grid, name } = command {
+        let tiles = match (size, grid) {
+            (Some(Size(w, h)), _) => Tiles::Size(*w, *h),
+            (None, Some(Size(columns, rows))) => Tiles::Grid(*columns, *rows),
+            (None, None) => {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Slicing needs a tile size or a grid".into(),
+                ));
+            }
+        };
+        return slice_file(input, &data, input_format, output, tiles, name, options).map(processed);
+    }
+    if let Command::Favicon { sizes, png } = command {
+        return favicon_file(input, &data, input_format, output, sizes, *png, options)
+            .map(processed);
+    }
+    if let Command::Tiles { .. } = command {
+        return pyramid_file(input, &data, input_format, output, command, options).map(processed);
+    }
+    if let Command::Srcset { .. } = command {
+        return srcset_file(input, &data, input_format, output, command, options).map(processed);
+    }
+    if let Command::Frames { every, range, name } = command {
+        return frames_file(input, &data, output, *every, *range, name, options).map(processed);
+    }
+    // Name the output after the template once the dimensions are known
+    let name_output =
+        |dimensions| output_target(input, output, command, input_format, dimensions, options);
+    let target = name_output(None)?;
+    let optimizing = optimizing(command);
+    let splitting = matches!(
+        command,
+        Command::Channels {
+            command: Channels::Split
+        }
+    );
+
+    // Strip JPEG, PNG and WebP without re-encoding when nothing else changes
+    if matches!(command, Command::Strip { .. })
+        && !options.auto_orient
+        && options.assume_profile.is_none()
+        && options.convert_to.is_none()
+        && options.encoding == EncodeOptions::default()
+        && target.image_format() == input_format
+        && let (Some(format), Some(keep)) = (input_format, &options.strip)
+        && let Some(stripped) = strip_metadata(&data, format, keep)
+    {
+        let dimensions = ImageReader::with_format(Cursor::new(&data), format)
+            .into_dimensions()
+            .map_err(ImgtoolsError::Decode)?;
+        let target = name_output(Some(dimensions))?;
+        target.check(input, options)?;
+        let written = target.write(|w| {
+            w.write_all(&stripped)
+                .map_err(|e| ImgtoolsError::Encode(e.into()))
+        })?;
+        stopwatch.lap("strip");
+        return Ok(processed(vec![written]));
+    }
+
+    // Redact or set EXIF fields the same way, other formats and conversions are written again
+    let exif_action = exif_action(command);
+    if let Some(action) = exif_action
+        && !options.auto_orient
+        && options.assume_profile.is_none()
+        && options.convert_to.is_none()
+        && options.encoding == EncodeOptions::default()
+        && options.strip.is_none()
+        && target.image_format() == input_format
+        && let Some(format) = input_format
+        && let Some(edited) = edit_exif(&data, format, action)?
+    {
+        let dimensions = ImageReader::with_format(Cursor::new(&data), format)
+            .into_dimensions()
+            .map_err(ImgtoolsError::Decode)?;
+        let target = name_output(Some(dimensions))?;
+        target.check(input, options)?;
+        let written = target.write(|w| {
+            w.write_all(&edited)
+                .map_err(|e| ImgtoolsError::Encode(e.into()))
+        })?;
+        stopwatch.lap("exif");
+        return Ok(processed(vec![written]));
+    }
+
+    // Process animations frame by frame
+    if input_format == Some(ImageFormat::Gif)
+        && target.image_format() == Some(ImageFormat::Gif)
+        && !splitting
+        && optimizing.is_none()
+    {
+        let animation = Animation::decode_gif(&data)?.apply(command)?;
+        stopwatch.lap("process frames");
+        let size = animation
+            .frames
+            .first()
+            .map(|frame| frame.buffer().dimensions());
+        let target = name_output(Some(size.unwrap_or_default()))?;
+        target.check(input, options)?;
+        let written = target.write(|w| animation.encode_gif(w))?;
+        stopwatch.lap("write");
+        return Ok(processed(vec![written]));
+    }
+
+    let mut metadata =
+        match options.keep_metadata || options.strip.is_some() || exif_action.is_some() {
+            true => Metadata::read(&data, input_format)?,
+            false => Metadata::default(),
+        };
+    let mut img = decode_with_options(&data, input_format, &options.decoding)?;
+
+    // Turn the image upright before any other step
+    if options.auto_orient || command.any(&|c| matches!(c, Command::Autoorient)) {
+        img.apply_orientation(read_orientation(&data, input_format)?);
+        metadata.clear_orientation();
+    }
+    let mut img = manage_colors(img, &data, input_format, options, &mut metadata)?;
+    if let Some(keep) = &options.strip {
+        metadata.strip(keep);
+    }
+    if let Some(action) = exif_action {
+        metadata.edit(action)?;
+    }
+    stopwatch.lap("decode");
+    // Write every channel next to the output, e.g. out_r.png
+    if splitting {
+        let target = name_output(Some(img.dimensions()))?;
+        let Target::File(path, format) = &target else {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Split channels can't be written to standard output".into(),
+            ));
+        };
+        let planes: Vec<_> = split(&img)
+            .into_iter()
+            .map(|(name, plane)| (plane_path(path, name), plane))
+            .collect();
+        for (path, _) in &planes {
+            check_overwrite(path, input, options)?;
+        }
+        let mut outputs = Vec::with_capacity(planes.len());
+        for (path, plane) in planes {
+            let plane = DynamicImage::ImageLuma8(plane);
+            outputs.push(match format {
+                Some(format) => Target::File(path, Some(*format))
+                    .write(|w| encode_output(&plane, *format, &metadata, options, w))?,
+                None => save(&plane, &path)?,
+            });
+        }
+        return Ok(processed(outputs));
+    }
+    for step in command.steps() {
+        img = apply_command(img, step)?;
+        stopwatch.lap(step.name());
+    }
+    let target = name_output(Some(img.dimensions()))?;
+    target.check(input, options)?;
+
+    // Recompress as far as the optimize step allows, the input wins if it is smaller
+    if let Some((quality, target_size)) = optimizing {
+        let format = target
+            .image_format()
+            .and_then(|f| Format::try_from(f).ok())
+            .ok_or_else(|| {
+                ImgtoolsError::InvalidArgument(
+                    "Unable to determine the output format, use convert to select one".into(),
+                )
+            })?;
+        let optimized = optimize(
+            &img,
+            format,
+            &metadata,
+            &options.encoding,
+            quality,
+            target_size,
+        )?;
+        let unchanged = command
+            .steps()
+            .iter()
+            .all(|step| matches!(step, Command::Optimize { .. } | Command::Strip { .. }))
+            && !options.auto_orient
+            && options.assume_profile.is_none()
+            && options.convert_to.is_none()
+            && options.encoding == EncodeOptions::default()
+            && input_format == Some(format.into());
+        // A stripped input has to lose its metadata as well
+        let original = match &options.strip {
+            Some(keep) => strip_metadata(&data, format.into(), keep).map(Cow::Owned),
+            None => Some(Cow::Borrowed(&data[..])),
+        };
+        let best = match original {
+            Some(original)
+                if unchanged
+                    && original.len() <= optimized.len()
+                    && target_size.is_none_or(|size| original.len() as u64 <= size) =>
+            {
+                original
+            }
+            _ => Cow::Owned(optimized),
+        };
+        let written = target.write(|w| {
+            w.write_all(&best)
+                .map_err(|e| ImgtoolsError::Encode(e.into()))
+        })?;
+        stopwatch.lap("optimize");
+        return Ok(processed(vec![written]));
+    }
+
+    // Save the processed image, the extension decides the format if not converted
+    let format = match &target {
+        Target::Stdout(format) | Target::File(_, Some(format)) => Some(*format),
+        Target::File(path, None)
+            if !metadata.is_empty()
+                || options.encoding != EncodeOptions::default()
+                || options.strip.is_some() =>
+        {
+            ImageFormat::from_path(path)
+                .ok()
+                .and_then(|f| Format::try_from(f).ok())
+        }
+        Target::File(_, None) => None,
+    };
+    if let Some(ExifAction::Set { .. }) = exif_action
+        && !matches!(
+            format,
+            Some(Format::Jpeg | Format::Png | Format::WebP | Format::Tiff | Format::Avif)
+        )
+    {
+        return Err(ImgtoolsError::InvalidArgument(
+            "EXIF fields can only be set in JPEG, PNG, WebP, TIFF and AVIF output".into(),
+        ));
+    }
+    let written = match (&target, format) {
+        (_, Some(format)) => target.write(|w| {
+            encode_output(&img, format, &metadata, options, w)?;
+            // The TIFF encoder leaves EXIF out, so the fields are set in the written file
+            if format == Format::Tiff
+                && let Some(action @ ExifAction::Set { .. }) = exif_action
+                && let Some(tagged) = edit_exif(w.get_ref(), ImageFormat::Tiff, action)?
+            {
+                *w = Cursor::new(tagged);
+            }
+            Ok(())
+        })?,
+        (Target::File(path, _), None) => save(&img, path)?,
+        (Target::Stdout(_), None) => unreachable!(),
+    };
+    stopwatch.lap("write");
+    Ok(processed(vec![written]))
+}
+
+/// Run the command on an encoded image held in memory
+///
+/// Returns the encoded result and its format, which is the converted format
+/// or else the input format, PNG if that can't be written. Steps that would
+/// make an image larger than the decoding limit are refused as well.
+pub fn process_bytes(
+    data: &[u8],
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<(Vec<u8>, Format), ImgtoolsError> {
+    let input_format = input_format(data, options);
+    let format = output_format(command)
+        .or_else(|| input_format.and_then(|f| Format::try_from(f).ok()))
+        .unwrap_or(Format::Png);
+    let exif_action = exif_action(command);
+    let mut metadata =
+        match options.keep_metadata || options.strip.is_some() || exif_action.is_some() {
+            true => Metadata::read(data, input_format)?,
+            false => Metadata::default(),
+        };
+    let mut img = decode_with_options(data, input_format, &options.decoding)?;
+    if options.auto_orient || command.any(&|c| matches!(c, Command::Autoorient)) {
+        img.apply_orientation(read_orientation(data, input_format)?);
+        metadata.clear_orientation();
+    }
+    let mut img = manage_colors(img, data, input_format, options, &mut metadata)?;
+    if let Some(keep) = &options.strip {
+        metadata.strip(keep);
+    }
+    if let Some(action) = exif_action {
+        metadata.edit(action)?;
+    }
+    for step in command.steps() {
+        // Refuse results over the decoding limit before making them
+        let (width, height) = output_size(step, img.dimensions());
+        let sample = img.color().bytes_per_pixel().max(4) as u64;
+        options
+            .decoding
+            .check_alloc(width.saturating_mul(height).saturating_mul(sample))?;
+        img = apply_command(img, step)?;
+    }
+    if let Some((quality, target_size)) = optimizing(command) {
+        return Ok((
+            optimize(
+                &img,
+                format,
+                &metadata,
+                &options.encoding,
+                quality,
+                target_size,
+            )?,
+            format,
+        ));
+    }
+    let mut encoded = Cursor::new(Vec::new());
+    encode_output(&img, format, &metadata, options, &mut encoded)?;
+    Ok((encoded.into_inner(), format))
+}
+
+/// What the strip step keeps, if the command has one
+fn stripping(command: &Command) -> Option<Vec<Keep>> {
+    command.steps().into_iter().find_map(|step| match step {
+        Command::Strip { keep } => Some(keep.clone()),
+        _ => None,
+    })
+}
+
+/// The EXIF subcommand that changes metadata, if it is the command
+fn exif_action(command: &Command) -> Option<&ExifAction> {
+    match command {
+        Command::Exif {
+            action: Some(action),
+            ..
+        } => Some(action),
+        _ => None,
+    }
+}
+
+/// Redact or set EXIF fields of an encoded image without decoding it
+fn edit_exif(
+    data: &[u8],
+    format: ImageFormat,
+    action: &ExifAction,
+) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    match action {
+        ExifAction::Redact { fuzz_time } => redact_metadata(data, format, *fuzz_time),
+        ExifAction::Set {
+            artist,
+            copyright,
+            datetime,
+            tags,
+        } => {
+            let fields = set_fields(
+                artist.as_deref(),
+                copyright.as_deref(),
+                datetime.as_ref(),
+                tags,
+            );
+            set_metadata(data, format, &fields)
+        }
+    }
+}
+
+/// Quality and target size of the optimize step, if the command has one
+fn optimizing(command: &Command) -> Option<(Option<u8>, Option<u64>)> {
+    command.steps().into_iter().find_map(|step| match step {
+        Command::Optimize {
+            quality,
+            target_size,
+        } => Some((*quality, target_size.map(|size| size.0))),
+        _ => None,
+    })
+}
+
+/// What processing would do, shown instead of writing anything with --dry-run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// Images that would be read, with their guessed formats
+    pub inputs: Vec<(PathBuf, Option<Format>)>,
+    /// Commands applied in order, with every parameter resolved
+    pub operations: Vec<Command>,
+    /// Written file, name template for several images or `-` for standard output
+    pub output: PathBuf,
+    /// Output format, if known before encoding
+    pub format: Option<Format>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (input, format) in &self.inputs {
+            write!(f, "input: {}", input.display())?;
+            match format {
+                Some(format) => writeln!(f, " ({})", format)?,
+                None => writeln!(f)?,
+            }
+        }
+        for (i, operation) in self.operations.iter().enumerate() {
+            writeln!(f, "step {}: {}", i + 1, describe(operation))?;
+        }
+        match is_stdio(&self.output) {
+            true => write!(f, "output: standard output")?,
+            false => write!(f, "output: {}", self.output.display())?,
+        }
+        match self.format {
+            Some(format) => write!(f, " ({})", format),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A step as its name and parameters, e.g. `resize width=800 filter=lanczos3`
+///
+/// Written out from the debug form, so every parameter shows without a list
+/// of them here. Unset options and switched off flags are left out.
+fn describe(command: &Command) -> String {
+    let debug = format!("{:?}", command);
+    let mut tokens = debug_tokens(&debug).into_iter().peekable();
+    match debug_value(&mut tokens) {
+        DebugValue::Struct(name, fields) => {
+            let mut words = vec![kebab(&name)];
+            words.extend(shown_fields(&fields));
+            words.join(" ")
+        }
+        value => render(&value),
+    }
+}
+
+/// Value of a debug form, parsed only as far as `describe` needs
+enum DebugValue {
+    Atom(String),
+    Tuple(String, Vec<DebugValue>),
+    Struct(String, Vec<(String, DebugValue)>),
+    List(Vec<DebugValue>),
+}
+
+/// Split a debug form into names and literals, and the punctuation between them
+fn debug_tokens(debug: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = debug.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | ',' | ':' => {}
+            '(' | ')' | '{' | '}' | '[' | ']' => tokens.push(c.to_string()),
+            '"' => {
+                let mut literal = String::from('"');
+                while let Some(c) = chars.next() {
+                    literal.push(c);
+                    match c {
+                        '\\' => literal.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+                tokens.push(literal);
+            }
+            c => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek()
+                    && !" ,:(){}[]\"".contains(c)
+                {
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    tokens
+}
+
+fn debug_value(tokens: &mut Peekable<vec::IntoIter<String>>) -> DebugValue {
+    let token = tokens.next().unwrap_or_default();
+    if token == "[" {
+        let mut items = Vec::new();
+        while tokens.peek().is_some_and(|t| t != "]") {
+            items.push(debug_value(tokens));
+        }
+        tokens.next();
+        return DebugValue::List(items);
+    }
+    match tokens.peek().map(String::as_str) {
+        Some("(") => {
+            tokens.next();
+            let mut items = Vec::new();
+            while tokens.peek().is_some_and(|t| t != ")") {
+                items.push(debug_value(tokens));
+            }
+            tokens.next();
+            DebugValue::Tuple(token, items)
+        }
+        Some("{") => {
+            tokens.next();
+            let mut fields = Vec::new();
+            while let Some(name) = tokens.next_if(|t| t != "}") {
+                fields.push((name, debug_value(tokens)));
+            }
+            tokens.next();
+            DebugValue::Struct(token, fields)
+        }
+        _ => DebugValue::Atom(token),
+    }
+}
+
+/// Fields as `name=value`, flags by their name alone
+fn shown_fields(fields: &[(String, DebugValue)]) -> Vec<String> {
+    fields
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.replace('_', "-");
+            match value {
+                DebugValue::Atom(atom) if atom == "None" || atom == "false" => None,
+                DebugValue::List(items) if items.is_empty() => None,
+                DebugValue::Atom(atom) if atom == "true" => Some(name),
+                value => Some(format!("{}={}", name, render(value))),
+            }
+        })
+        .collect()
+}
+
+/// A value written the way the command line takes it where that is known
+fn render(value: &DebugValue) -> String {
+    let number = |value: &DebugValue| match value {
+        DebugValue::Atom(atom) => atom.strip_suffix(".0").unwrap_or(atom).to_string(),
+        value => render(value),
+    };
+    match value {
+        DebugValue::Atom(atom) if atom.starts_with('"') => atom.clone(),
+        DebugValue::Atom(atom) if atom.starts_with(|c: char| c.is_ascii_uppercase()) => kebab(atom),
+        DebugValue::Atom(_) => number(value),
+        DebugValue::Tuple(name, items) => match (name.as_str(), &items[..]) {
+            ("Some" | "Solid" | "Pixels" | "ByteSize" | "Pipeline", [item]) => render(item),
+            ("Percent", [item]) => format!("{}%", number(item)),
+            ("Scale", [DebugValue::Atom(factor)]) => match factor.parse::<f32>() {
+                Ok(factor) => format!("{}%", factor * 100.0),
+                Err(_) => factor.clone(),
+            },
+            ("Size", [width, height]) => format!("{}x{}", number(width), number(height)),
+            (name, items) => format!(
+                "{}({})",
+                kebab(name),
+                items.iter().map(render).collect::<Vec<_>>().join(",")
+            ),
+        },
+        DebugValue::Struct(name, fields) => {
+            format!("{}({})", kebab(name), shown_fields(fields).join(" "))
+        }
+        DebugValue::List(items) => items.iter().map(render).collect::<Vec<_>>().join(","),
+    }
+}
+
+/// `TopLeft` as `top-left`, and `WebP` as `webp`
+fn kebab(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut kebab = String::with_capacity(name.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        let starts_word = chars.get(i + 1).is_some_and(char::is_ascii_lowercase);
+        if i > 0 && c.is_ascii_uppercase() && starts_word {
+            kebab.push('-');
+        }
+        kebab.push(c.to_ascii_lowercase());
+    }
+    kebab
+}
+
+/// Work out what processing the inputs would do, without writing anything
+///
+/// Several inputs are combined into one output like `combine_files` does,
+/// a single input is planned like `process_file`.
+pub fn plan(
+    inputs: &[PathBuf],
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Plan, ImgtoolsError> {
+    // Nothing is read to create an image
+    if command.creates_image() {
+        let output = output.ok_or_else(|| {
+            ImgtoolsError::InvalidArgument("Creating an image needs an output file".into())
+        })?;
+        let format = match is_stdio(output) {
+            true => Some(Format::Png),
+            false => ImageFormat::from_path(output)
+                .ok()
+                .and_then(|f| Format::try_from(f).ok()),
+        };
+        return Ok(Plan {
+            inputs: Vec::new(),
+            operations: vec![command.clone()],
+            output: output.to_path_buf(),
+            format,
+        });
+    }
+    let Some(input) = inputs.first() else {
+        return Err(ImgtoolsError::InvalidArgument(
+            "No images to process".into(),
+        ));
+    };
+    let mut planned = Vec::with_capacity(inputs.len());
+    let mut dimensions = None;
+    for input in inputs {
+        let data = read_input(input)?;
+        let format = input_format(&data, options);
+        // The size a template names comes from the header, worked through the steps
+        if dimensions.is_none()
+            && !command.combines_inputs()
+            && let Some(format) = format
+            && let Ok(size) = ImageReader::with_format(Cursor::new(&data), format).into_dimensions()
+        {
+            let orient = options.auto_orient || command.any(&|c| matches!(c, Command::Autoorient));
+            let turned = orient
+                && matches!(
+                    read_orientation(&data, Some(format)),
+                    Ok(Orientation::Rotate90
+                        | Orientation::Rotate270
+                        | Orientation::Rotate90FlipH
+                        | Orientation::Rotate270FlipH)
+                );
+            let (width, height) =
+                output_size(command, if turned { (size.1, size.0) } else { size });
+            let side = |side: u64| side.min(u32::MAX as u64) as u32;
+            dimensions = Some((side(width), side(height)));
+        }
+        planned.push((input.clone(), format));
+    }
+    let input_format = planned.iter().find_map(|(_, format)| *format);
+
+    let target = match command {
+        command if command.is_report() => Target::File(PathBuf::from(STDIO), None),
+        Command::Slice { name, .. }
+        | Command::Frames { name, .. }
+        | Command::Srcset { name, .. } => {
+            let format = ImageFormat::from_path(name)
+                .ok()
+                .and_then(|f| Format::try_from(f).ok());
+            Target::File(output_dir(input, output)?.join(name), format)
+        }
+        Command::Favicon { .. } => favicon_target(input, output)?,
+        Command::Tiles { layout, format, .. } => {
+            let descriptor = match layout {
+                PyramidLayout::Dzi => format!("{}.dzi", input_stem(input)),
+                PyramidLayout::Xyz => format!("{}.json", input_stem(input)),
+            };
+            Target::File(output_dir(input, output)?.join(descriptor), Some(*format))
+        }
+        command if command.combines_inputs() && output.is_none() => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Combining images needs an output file".into(),
+            ));
+        }
+        command if let Some(template) = &options.output_template => templated_target(
+            template,
+            input,
+            output,
+            command,
+            input_format,
+            dimensions,
+            options.counter,
+        )?,
+        command => Target::resolve(input, output, command, input_format)?,
+    };
+    let (output, format) = match target {
+        Target::Stdout(format) => (PathBuf::from(STDIO), Some(format)),
+        Target::File(path, format) => {
+            let format = format.or_else(|| {
+                ImageFormat::from_path(&path)
+                    .ok()
+                    .and_then(|f| Format::try_from(f).ok())
+            });
+            (path, format)
+        }
+    };
+    Ok(Plan {
+        inputs: planned
+            .into_iter()
+            .map(|(path, format)| (path, format.and_then(|f| Format::try_from(f).ok())))
+            .collect(),
+        operations: command.steps().into_iter().cloned().collect(),
+        output,
+        format,
+    })
+}
+
+/// Logs how long every stage of an image takes when verbose
+struct Stopwatch<'a> {
+    input: &'a Path,
+    verbose: bool,
+    start: Instant,
+}
+
+impl<'a> Stopwatch<'a> {
+    fn new(input: &'a Path, verbose: bool) -> Self {
+        Stopwatch {
+            input,
+            verbose,
+            start: Instant::now(),
+        }
+    }
+
+    /// Log the time since the previous stage and start timing the next one
+    fn lap(&mut self, stage: impl fmt::Display) {
+        if self.verbose {
+            let elapsed = self.start.elapsed();
+            let stage = stage.to_string();
+            let message = format!("{}: {} took {:.2?}", self.input.display(), stage, elapsed);
+            let mut fields = Map::new();
+            fields.insert("input".into(), json!(self.input.display().to_string()));
+            fields.insert("stage".into(), json!(stage));
+            fields.insert("duration_ms".into(), json!(elapsed.as_secs_f64() * 1000.0));
+            log_with(Level::Debug, message, fields);
+        }
+        self.start = Instant::now();
+    }
+}
+
+/// Convert the colors to the target profile and record the profile to embed
+///
+/// The embedded ICC profile describes the input, the assumed profile stands in
+/// for untagged images and sRGB otherwise. Without a target the source profile
+/// is only embedded and the pixels are left as they are.
+fn manage_colors(
+    img: DynamicImage,
+    data: &[u8],
+    input_format: Option<ImageFormat>,
+    options: &ProcessOptions,
+    metadata: &mut Metadata,
+) -> Result<DynamicImage, ImgtoolsError> {
+    if options.assume_profile.is_none() && options.convert_to.is_none() {
+        return Ok(img);
+    }
+    let embedded = match metadata.icc.take() {
+        Some(icc) => Some(icc),
+        None => read_icc(data, input_format)?,
+    };
+    let source = match (embedded, &options.assume_profile) {
+        (Some(icc), _) => icc,
+        (None, Some(assumed)) => profile_data(assumed)?,
+        (None, None) => profile_data(&Profile::Srgb)?,
+    };
+    let Some(target) = &options.convert_to else {
+        metadata.icc = Some(source);
+        return Ok(img);
+    };
+    let target = profile_data(target)?;
+    let img = match source == target {
+        true => img,
+        false => convert_profile(&img, &source, &target)?,
+    };
+    metadata.icc = Some(target);
+    Ok(img)
+}
+
+/// Where the favicon goes, favicon.ico in the output directory unless a file is given
+fn favicon_target(input: &Path, output: Option<&Path>) -> Result<Target, ImgtoolsError> {
+    Ok(match output {
+        Some(output) if is_stdio(output) => Target::Stdout(Format::Ico),
+        Some(output) if !output.is_dir() => Target::File(output.to_path_buf(), Some(Format::Ico)),
+        None if is_stdio(input) => Target::Stdout(Format::Ico),
+        _ => Target::File(
+            output_dir(input, output)?.join("favicon.ico"),
+            Some(Format::Ico),
+        ),
+    })
+}
+
+/// Cut a decoded input into tiles and write them to the output directory
+///
+/// Without an output the tiles are written next to the input. The extension
+/// of the name template decides the format.
+fn slice_file(
+    input: &Path,
+    data: &[u8],
+    input_format: Option<ImageFormat>,
+    output: Option<&Path>,
+    tiles: Tiles,
+    template: &str,
+    options: &ProcessOptions,
+) -> Result<Vec<Written>, ImgtoolsError> {
+    let dir = output_dir(input, output)?;
+    let stem = input_stem(input);
+    let (img, metadata) = decode_managed(data, input_format, options)?;
+
+    let tiles: Vec<_> = slice(&img, tiles)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, (x, y, tile))| (tile_name(template, &stem, x, y, i), tile))
+        .collect();
+    write_named(&dir, template, &tiles, &metadata, input, options)
+}
+
+/// Decode an input that is written as several images, turned upright and
+/// color managed, along with the metadata they keep
+fn decode_managed(
+    data: &[u8],
+    input_format: Option<ImageFormat>,
+    options: &ProcessOptions,
+) -> Result<(DynamicImage, Metadata), ImgtoolsError> {
+    let mut metadata = match options.keep_metadata || options.strip.is_some() {
+        true => Metadata::read(data, input_format)?,
+        false => Metadata::default(),
+    };
+    let mut img = decode_with_options(data, input_format, &options.decoding)?;
+    if options.auto_orient {
+        img.apply_orientation(read_orientation(data, input_format)?);
+        metadata.clear_orientation();
+    }
+    let img = manage_colors(img, data, input_format, options, &mut metadata)?;
+    if let Some(keep) = &options.strip {
+        metadata.strip(keep);
+    }
+    Ok((img, metadata))
+}
+
+/// Cut a decoded input into a tile pyramid and write its descriptor
+///
+/// Without an output the pyramid is written next to the input, named after
+/// the input file. Tiles keep no metadata.
+fn pyramid_file(
+    input: &Path,
+    data: &[u8],
+    input_format: Option<ImageFormat>,
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Vec<Written>, ImgtoolsError> {
+    let Command::Tiles {
+        layout,
+        tile_size,
+        overlap,
+        format,
+        background,
+    } = *command
+    else {
+        unreachable!()
+    };
+    if tile_size == 0 {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Tile size must be above 0".into(),
+        ));
+    }
+    if layout == PyramidLayout::Xyz && overlap > 0 {
+        return Err(ImgtoolsError::InvalidArgument(
+            "XYZ tiles can't overlap, only Deep Zoom ones".into(),
+        ));
+    }
+    let dir = output_dir(input, output)?;
+    let stem = input_stem(input);
+    let (img, _) = decode_managed(data, input_format, options)?;
+
+    let levels = pyramid_levels(&img, layout, tile_size);
+    let background = match layout {
+        PyramidLayout::Dzi => None,
+        PyramidLayout::Xyz => Some(background.into()),
+    };
+    let mut tiles = Vec::new();
+    for (zoom, level) in levels.iter().enumerate() {
+        for (column, row, tile) in pyramid_tiles(level, tile_size, overlap, background) {
+            let name = match layout {
+                PyramidLayout::Dzi => {
+                    format!("{}_files/{}/{}_{}.{}", stem, zoom, column, row, format)
+                }
+                PyramidLayout::Xyz => format!("{}/{}/{}/{}.{}", stem, zoom, column, row, format),
+            };
+            tiles.push((name, tile));
+        }
+    }
+    let (descriptor, text) = match layout {
+        PyramidLayout::Dzi => (
+            dir.join(format!("{}.dzi", stem)),
+            dzi_descriptor(img.dimensions(), tile_size, overlap, format),
+        ),
+        PyramidLayout::Xyz => (
+            dir.join(format!("{}.json", stem)),
+            xyz_descriptor(img.dimensions(), tile_size, levels.len() - 1, format),
+        ),
+    };
+    check_overwrite(&descriptor, input, options)?;
+
+    let mut outputs = write_named(&dir, &stem, &tiles, &Metadata::default(), input, options)?;
+    fs::write(&descriptor, &text).map_err(|source| ImgtoolsError::Write {
+        path: descriptor.clone(),
+        source,
+    })?;
+    outputs.push(Written {
+        path: descriptor,
+        bytes: text.len() as u64,
+    });
+    Ok(outputs)
+}
+
+/// Resize a decoded input to every width and write each in every format
+///
+/// Without an output the variants are written next to the input. The
+/// manifest goes into the same directory, its URLs are the variant names.
+fn srcset_file(
+    input: &Path,
+    data: &[u8],
+    input_format: Option<ImageFormat>,
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Vec<Written>, ImgtoolsError> {
+    let Command::Srcset {
+        widths,
+        formats,
+        quality,
+        name: template,
+        manifest,
+        sizes,
+    } = command
+    else {
+        unreachable!()
+    };
+    if widths.is_empty() || widths.contains(&0) {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Srcset widths must be above 0".into(),
+        ));
+    }
+    let mut unique = Vec::with_capacity(formats.len());
+    for &format in formats {
+        if !unique.contains(&format) {
+            unique.push(format);
+        }
+    }
+    let formats = unique;
+    let dir = output_dir(input, output)?;
+    let stem = input_stem(input);
+    let (img, metadata) = decode_managed(data, input_format, options)?;
+
+    let size = img.dimensions();
+    let resized: Vec<_> = srcset_widths(widths, img.width())
+        .into_iter()
+        .map(|width| match width == img.width() {
+            true => img.clone(),
+            false => img.resize_exact(width, srcset_height(size, width), FilterType::Lanczos3),
+        })
+        .collect();
+    let images: Vec<_> = formats
+        .iter()
+        .flat_map(|&format| resized.iter().map(move |img| (format, img)))
+        .map(|(format, img)| {
+            let name = template
+                .replace("{name}", &stem)
+                .replace("{width}", &img.width().to_string())
+                .replace("{height}", &img.height().to_string())
+                .replace("{format}", &format.to_string());
+            (name, format, img)
+        })
+        .collect();
+    let names: HashSet<_> = images.iter().map(|(name, ..)| name).collect();
+    if names.len() < images.len() {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Name {} gives several images the same file name, include {{width}} and {{format}}",
+            template
+        )));
+    }
+    let manifest = manifest
+        .as_ref()
+        .map(|name| dir.join(name.replace("{name}", &stem)));
+    for path in images
+        .iter()
+        .map(|(name, ..)| dir.join(name))
+        .chain(manifest.clone())
+    {
+        check_overwrite(&path, input, options)?;
+    }
+
+    let mut outputs = Vec::with_capacity(images.len() + 1);
+    let mut variants = Vec::with_capacity(images.len());
+    for (name, format, img) in images {
+        let path = dir.join(&name);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|source| ImgtoolsError::Write {
+                path: parent.to_path_buf(),
+                source,
+            })?;
+        }
+        let written = Target::File(path, Some(format))
+            .write(|w| encode_lossy(img, format, *quality, &metadata, options, w))?;
+        variants.push(Variant {
+            name,
+            format,
+            width: img.width(),
+            height: img.height(),
+            bytes: written.bytes,
+        });
+        outputs.push(written);
+    }
+    if let Some(path) = manifest {
+        let html = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"));
+        let text = match html {
+            true => manifest_html(&variants, &formats, sizes),
+            false => manifest_json(&variants, size),
+        };
+        fs::write(&path, &text).map_err(|source| ImgtoolsError::Write {
+            path: path.clone(),
+            source,
+        })?;
+        outputs.push(Written {
+            path,
+            bytes: text.len() as u64,
+        });
+    }
+    Ok(outputs)
+}
+
+/// Encode at the lossy quality when one is given and the format has one
+fn encode_lossy(
+    img: &DynamicImage,
+    format: Format,
+    quality: Option<u8>,
+    metadata: &Metadata,
+    options: &ProcessOptions,
+    output: &mut Cursor<Vec<u8>>,
+) -> Result<(), ImgtoolsError> {
+    match (format, quality) {
+        (Format::Jpeg, Some(_)) => {
+            *output = Cursor::new(optimize(
+                img,
+                format,
+                metadata,
+                &options.encoding,
+                quality,
+                None,
+            )?);
+            Ok(())
+        }
+        // Speed 4 is what AvifEncoder::new uses
+        (Format::Avif, Some(quality)) => write_image(
+            avif_encoder(AvifEncoder::new_with_speed_quality(output, 4, quality)),
+            &encodable(img, format),
+            metadata,
+        )
+        .map_err(ImgtoolsError::Encode),
+        _ => encode_output(img, format, metadata, options, output),
+    }
+}
+
+/// Decode every frame of an input and write the selected ones to the output directory
+///
+/// Without an output the frames are written next to the input. Frame numbers
+/// are zero padded so the files sort in order.
+fn frames_file(
+    input: &Path,
+    data: &[u8],
+    output: Option<&Path>,
+    every: usize,
+    range: Option<FrameRange>,
+    template: &str,
+    options: &ProcessOptions,
+) -> Result<Vec<Written>, ImgtoolsError> {
+    if every == 0 {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Every must be at least 1".into(),
+        ));
+    }
+    let dir = output_dir(input, output)?;
+    let stem = input_stem(input);
+
+    let animation = Animation::decode(data, input_format(data, options))?;
+    let count = animation.frames.len();
+    let range = range.unwrap_or(FrameRange(0, None));
+    if range.0 >= count {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Frame range starts at {} but the image has {} frames",
+            range.0, count
+        )));
+    }
+
+    let digits = (count - 1).to_string().len();
+    let frames: Vec<_> = animation
+        .frames
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| range.contains(*i) && (i - range.0).is_multiple_of(every))
+        .map(|(i, frame)| {
+            let name = template
+                .replace("{name}", &stem)
+                .replace("{i}", &format!("{:0digits$}", i));
+            // Decoded frames are RGBA, keep opaque ones RGB, e.g. for JPEG output
+            let frame = DynamicImage::ImageRgba8(frame.into_buffer());
+            match frame.as_bytes().chunks(4).all(|p| p[3] == 255) {
+                true => (name, DynamicImage::ImageRgb8(frame.to_rgb8())),
+                false => (name, frame),
+            }
+        })
+        .collect();
+    write_named(
+        &dir,
+        template,
+        &frames,
+        &Metadata::default(),
+        input,
+        options,
+    )
+}
+
+/// PNG icons written next to the favicon, with their sizes
+const FAVICON_PNGS: [(&str, u32); 3] = [
+    ("apple-touch-icon.png", 180),
+    ("icon-192.png", 192),
+    ("icon-512.png", 512),
+];
+
+/// Write a multi-resolution ICO and optionally the PNG icons next to it
+///
+/// Without an output, or with a directory, the icon is named favicon.ico.
+fn favicon_file(
+    input: &Path,
+    data: &[u8],
+    input_format: Option<ImageFormat>,
+    output: Option<&Path>,
+    sizes: &[u32],
+    png: bool,
+    options: &ProcessOptions,
+) -> Result<Vec<Written>, ImgtoolsError> {
+    if let Some(size) = sizes.iter().find(|size| !(1..=256).contains(*size)) {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Icon size {} must be between 1 and 256",
+            size
+        )));
+    }
+    let target = favicon_target(input, output)?;
+    let png_dir = match (&target, png) {
+        (_, false) => None,
+        (Target::File(path, _), true) => Some(path.parent().unwrap_or(Path::new(""))),
+        (Target::Stdout(_), true) => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "PNG icons can't be written to standard output, give an output file".into(),
+            ));
+        }
+    };
+
+    let mut img = decode_with_options(data, input_format, &options.decoding)?;
+    if options.auto_orient {
+        img.apply_orientation(read_orientation(data, input_format)?);
+    }
+    target.check(input, options)?;
+    let pngs: Vec<_> = match png_dir {
+        Some(dir) => FAVICON_PNGS
+            .iter()
+            .map(|&(name, size)| (dir.join(name), size))
+            .collect(),
+        None => Vec::new(),
+    };
+    for (path, _) in &pngs {
+        check_overwrite(path, input, options)?;
+    }
+
+    let icons: Vec<_> = sizes.iter().map(|&size| icon(&img, size)).collect();
+    let mut outputs = vec![target.write(|w| encode_ico(&icons, w))?];
+    for (path, size) in pngs {
+        let icon = DynamicImage::ImageRgba8(icon(&img, size));
+        outputs
+            .push(Target::File(path, Some(Format::Png)).write(|w| encode(&icon, Format::Png, w))?);
+    }
+    Ok(outputs)
+}
+
+/// Refuse to replace an existing file unless forced
+///
+/// The input itself may also be replaced in place, after copying it to a
+/// backup when a suffix is given.
+fn check_overwrite(
+    path: &Path,
+    input: &Path,
+    options: &ProcessOptions,
+) -> Result<(), ImgtoolsError> {
+    let is_input = match (fs::canonicalize(path), fs::canonicalize(input)) {
+        (Ok(path), Ok(input)) => path == input,
+        _ => false,
+    };
+    if is_input && (options.in_place || options.force) {
+        let Some(suffix) = &options.backup else {
+            return Ok(());
+        };
+        let mut backup = input.as_os_str().to_owned();
+        backup.push(suffix);
+        let backup = PathBuf::from(backup);
+        if backup.exists() && !options.force {
+            return Err(ImgtoolsError::OutputExists { path: backup });
+        }
+        return fs::copy(input, &backup)
+            .map(|_| ())
+            .map_err(|source| ImgtoolsError::Write {
+                path: backup,
+                source,
+            });
+    }
+    match (is_input, path.exists() && !options.force) {
+        (true, _) => Err(ImgtoolsError::InputOverwrite {
+            path: path.to_path_buf(),
+        }),
+        (false, true) => Err(ImgtoolsError::OutputExists {
+            path: path.to_path_buf(),
+        }),
+        (false, false) => Ok(()),
+    }
+}
+
+/// Directory for commands that write several files, the input's own without an output
+fn output_dir(input: &Path, output: Option<&Path>) -> Result<PathBuf, ImgtoolsError> {
+    match output {
+        Some(output) if !is_stdio(output) => Ok(output.to_path_buf()),
+        None if is_url(input) => Ok(PathBuf::new()),
+        None if !is_stdio(input) => Ok(input.parent().map(Path::to_path_buf).unwrap_or_default()),
+        _ => Err(ImgtoolsError::InvalidArgument(
+            "Several images can't be written to standard output, give an output directory".into(),
+        )),
+    }
+}
+
+/// Input file name without extension for name templates
+fn input_stem(input: &Path) -> String {
+    input_name(input)
+        .as_deref()
+        .and_then(Path::file_stem)
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write images under names made from `template`, the extensions decide the formats
+fn write_named(
+    dir: &Path,
+    template: &str,
+    images: &[(String, DynamicImage)],
+    metadata: &Metadata,
+    input: &Path,
+    options: &ProcessOptions,
+) -> Result<Vec<Written>, ImgtoolsError> {
+    if images
+        .iter()
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>()
+        .len()
+        < images.len()
+    {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Name {} gives several images the same file name",
+            template
+        )));
+    }
+    for (name, _) in images {
+        check_overwrite(&dir.join(name), input, options)?;
+    }
+
+    let mut outputs = Vec::with_capacity(images.len());
+    for (name, img) in images {
+        let path = dir.join(name);
+        // The template may name subdirectories, e.g. {name}/{x}_{y}.png
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|source| ImgtoolsError::Write {
+                path: parent.to_path_buf(),
+                source,
+            })?;
+        }
+        let format = ImageFormat::from_path(&path)
+            .ok()
+            .and_then(|f| Format::try_from(f).ok())
+            .ok_or_else(|| {
+                ImgtoolsError::InvalidArgument(format!(
+                    "Unsupported image format for {}",
+                    path.display()
+                ))
+            })?;
+        outputs.push(
+            Target::File(path, Some(format))
+                .write(|w| encode_output(img, format, metadata, options, w))?,
+        );
+    }
+    Ok(outputs)
+}
+
+/// Output file named by an output template, in the output directory
+///
+/// Without an output the file goes next to the input. `{width}` and
+/// `{height}` are left in place until the dimensions are known.
+fn templated_target(
+    template: &str,
+    input: &Path,
+    output: Option<&Path>,
+    command: &Command,
+    input_format: Option<ImageFormat>,
+    dimensions: Option<(u32, u32)>,
+    counter: usize,
+) -> Result<Target, ImgtoolsError> {
+    let dir = match output {
+        Some(output) if is_stdio(output) => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Output templates name files, they can't be used with standard output".into(),
+            ));
+        }
+        Some(output) => output.to_path_buf(),
+        None => input.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    let format = output_format(command);
+    let ext = input.extension().unwrap_or_default().to_string_lossy();
+
+    let mut name = template
+        .replace("{stem}", &input_stem(input))
+        .replace("{ext}", &ext)
+        .replace("{counter}", &counter.to_string())
+        .replace("{date}", &date(SystemTime::now()));
+    if let Some(shown) = format.or_else(|| input_format.and_then(|f| Format::try_from(f).ok())) {
+        name = name.replace("{format}", &shown.to_string());
+    }
+    if let Some((width, height)) = dimensions {
+        name = name
+            .replace("{width}", &width.to_string())
+            .replace("{height}", &height.to_string());
+    }
+    Ok(Target::File(dir.join(name), format))
+}
+
+/// Calendar date of a point in time as YYYY-MM-DD, in UTC
+pub(crate) fn date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Days since 0000-03-01, so leap days end a year
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Fill the placeholders of a tile name template
+fn tile_name(template: &str, stem: &str, x: u32, y: u32, i: usize) -> String {
+    template
+        .replace("{name}", stem)
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string())
+        .replace("{i}", &i.to_string())
+}
+
+/// Path of a channel plane, the channel name is appended to the file stem
+fn plane_path(path: &Path, name: char) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, name),
+    };
+    path.with_file_name(file_name)
+}
+
+/// Decode every input, combine them with the command and save the result
+///
+/// The output must be a file or `-`, the first input decides the format when
+/// writing to standard output.
+pub fn combine_files(
+    inputs: &[PathBuf],
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Written, ImgtoolsError> {
+    let output = output.ok_or_else(|| {
+        ImgtoolsError::InvalidArgument("Combining images needs an output file".into())
+    })?;
+    if inputs.is_empty() {
+        return Err(ImgtoolsError::InvalidArgument(
+            "No images to combine".into(),
+        ));
+    }
+
+    let mut stopwatch = Stopwatch::new(output, options.verbose);
+    let mut images = Vec::with_capacity(inputs.len());
+    let mut first_format = None;
+    for input in inputs {
+        let data = read_input(input)?;
+        let format = input_format(&data, options);
+        first_format = first_format.or(format);
+        let mut img = decode_with_options(&data, format, &options.decoding)?;
+        if options.auto_orient {
+            img.apply_orientation(read_orientation(&data, format)?);
+        }
+        let name = input_name(input)
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        stopwatch.lap(format_args!("read {}", name));
+        images.push((name, img));
+    }
+
+    let target = Target::resolve(&inputs[0], Some(output), command, first_format)?;
+    target.check(&inputs[0], options)?;
+
+    // Every image becomes a frame of the animation
+    if let Command::Animate { delay, loops, .. } = *command {
+        let format = target
+            .image_format()
+            .and_then(|f| Format::try_from(f).ok())
+            .ok_or_else(|| {
+                ImgtoolsError::InvalidArgument(
+                    "Unable to determine the animation format, use -f to select one".into(),
+                )
+            })?;
+        let repeat = match loops {
+            0 => Repeat::Infinite,
+            plays => Repeat::Finite(plays - 1),
+        };
+        let frames: Vec<_> = images.into_iter().map(|(_, img)| img).collect();
+        let delay = Delay::from_numer_denom_ms(delay, 1);
+        let animation = Animation::from_images(&frames, delay, repeat);
+        let written = target.write(|w| animation.encode(format, w))?;
+        stopwatch.lap("write");
+        return Ok(written);
+    }
+
+    let img = combine_images(&images, command)?;
+    stopwatch.lap(command.name());
+    let written = match &target {
+        Target::Stdout(format) | Target::File(_, Some(format)) => {
+            target.write(|w| encode(&img, *format, w))?
+        }
+        Target::File(path, None) => save(&img, path)?,
+    };
+    stopwatch.lap("write");
+    Ok(written)
+}
+
+/// Create an image from scratch with the create or qrcode command and write it
+///
+/// Standard output gets PNG, a file the format of its extension.
+pub fn create_file(
+    output: Option<&Path>,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<Written, ImgtoolsError> {
+    if !command.creates_image() {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Only {} make an image without an input",
+            INPUTLESS_COMMANDS
+        )));
+    }
+    let output = output.ok_or_else(|| {
+        ImgtoolsError::InvalidArgument("Creating an image needs an output file".into())
+    })?;
+    let target = match is_stdio(output) {
+        true => Target::Stdout(Format::Png),
+        false => Target::File(output.to_path_buf(), None),
+    };
+    // Nothing is read, so an existing file is only replaced with --force
+    target.check(Path::new(""), options)?;
+
+    let mut stopwatch = Stopwatch::new(output, options.verbose);
+    let img = generate(command)?;
+    stopwatch.lap(command.name());
+    let written = match target.image_format().and_then(|f| Format::try_from(f).ok()) {
+        Some(format) => {
+            target.write(|w| encode_output(&img, format, &Metadata::default(), options, w))?
+        }
+        None => match &target {
+            Target::File(path, _) => save(&img, path)?,
+            Target::Stdout(_) => unreachable!(),
+        },
+    };
+    stopwatch.lap("write");
+    Ok(written)
+}
+
+/// Make the image of a command that creates one
+fn generate(command: &Command) -> Result<DynamicImage, ImgtoolsError> {
+    match *command {
+        Command::Create {
+            size: Size(width, height),
+            ref fill,
+            seed,
+        } => Ok(create(width, height, fill, seed)),
+        Command::Qrcode {
+            ref text,
+            symbology,
+            ec,
+            module,
+            quiet_zone,
+            height,
+            color,
+            background,
+            ref logo,
+        } => {
+            let modules = encode_code(text, symbology, ec)?;
+            let style = CodeStyle {
+                module,
+                quiet_zone: quiet_zone.unwrap_or(match symbology {
+                    Symbology::Qr => 4,
+                    _ => 10,
+                }),
+                bar_height: height,
+                color: color.into(),
+                background: background.into(),
+            };
+            let mut code = render_code(&modules, &style);
+            if let Some(logo) = logo {
+                if symbology != Symbology::Qr {
+                    return Err(ImgtoolsError::InvalidArgument(
+                        "Only a QR code can have a logo".into(),
+                    ));
+                }
+                overlay_logo(&mut code, &open_image(logo)?, &style);
+            }
+            Ok(match code.pixels().all(|p| p[3] == 255) {
+                true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(code).to_rgb8()),
+                false => DynamicImage::ImageRgba8(code),
+            })
+        }
+        Command::Placeholder {
+            algorithm,
+            decode: Some(ref hash),
+            size,
+            ..
+        } => Ok(match algorithm {
+            PlaceholderAlgorithm::BlurHash => {
+                decode_blurhash(hash, size.unwrap_or(Size(32, 32)))?.into()
+            }
+            PlaceholderAlgorithm::ThumbHash => {
+                let rgba = decode_thumbhash(hash, size)?;
+                match rgba.pixels().all(|p| p[3] == 255) {
+                    true => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8()),
+                    false => DynamicImage::ImageRgba8(rgba),
+                }
+            }
+        }),
+        _ => Err(ImgtoolsError::InvalidArgument(format!(
+            "{} doesn't create an image",
+            command.name()
+        ))),
+    }
+}
+
+/// Combine named images into one with a command that combines inputs
+pub fn combine_images(
+    images: &[(String, DynamicImage)],
+    command: &Command,
+) -> Result<DynamicImage, ImgtoolsError> {
+    match *command {
+        Command::Montage {
+            columns,
+            cell: Size(cell_w, cell_h),
+            padding,
+            ref background,
+            captions,
+            ref font,
+            caption_size,
+            caption_color,
+        } => {
+            if captions && caption_size <= 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Caption size {} must be greater than 0",
+                    caption_size
+                )));
+            }
+            let grid = Grid {
+                columns,
+                cell: (cell_w, cell_h),
+                padding,
+                background: background.color(),
+            };
+            let fonts = match captions {
+                true => Some(Fonts::load(font.as_deref())?),
+                false => None,
+            };
+            let captions = fonts.as_ref().map(|fonts| Captions {
+                fonts,
+                scale: PxScale::from(caption_size),
+                color: caption_color.into(),
+            });
+            let sheet = DynamicImage::ImageRgba8(montage(images, &grid, captions.as_ref()));
+            // Images are drawn over the background, so an opaque one keeps the sheet opaque
+            let sheet = match grid.background[3] == 255 {
+                true => DynamicImage::ImageRgb8(sheet.to_rgb8()),
+                false => sheet,
+            };
+            Ok(background.underlay(sheet, false))
+        }
+        Command::Append {
+            direction,
+            align,
+            gap,
+            ref background,
+        } => {
+            let images: Vec<_> = images.iter().map(|(_, img)| img.clone()).collect();
+            let keep_alpha = images.iter().any(|img| img.color().has_alpha());
+            let joined = append(&images, direction, align, gap, background.color());
+            Ok(background.underlay(joined, keep_alpha))
+        }
+        Command::Hdr { tone_map, exposure } => match images {
+            [(_, img)] => Ok(hdr::tone_map(img, tone_map, exposure).into()),
+            images => Ok(fuse(images)?.into()),
+        },
+        Command::FocusStack { radius } => {
+            if radius < 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Radius {} must not be negative",
+                    radius
+                )));
+            }
+            focus_stack(images, radius)
+        }
+        #[cfg(feature = "stitch")]
+        Command::Stitch { bands, background } => {
+            let panorama = DynamicImage::ImageRgba8(stitch(images, bands)?);
+            let background: Rgba<u8> = background.into();
+            Ok(match background[3] {
+                0 => panorama,
+                _ => flatten(&panorama, background),
+            })
+        }
+        _ => Err(ImgtoolsError::InvalidArgument(
+            "The command does not combine images".into(),
+        )),
+    }
+}
+
+/// Run a command that reports on an image and return its output
+pub fn report_file(
+    input: &Path,
+    command: &Command,
+    options: &ProcessOptions,
+) -> Result<String, ImgtoolsError> {
+    let data = read_input(input)?;
+    match *command {
+        Command::Exif { format, .. } => Ok(exif_report(&read_exif(&data)?, format)),
+        Command::Info { format, json } => {
+            let format = if json { ReportFormat::Json } else { format };
+            Ok(ImageInfo::read(&data, None)?.report(format))
+        }
+        Command::Histogram { format, .. } if command.is_report() => {
+            let format = match format {
+                HistogramFormat::Json => ReportFormat::Json,
+                _ => ReportFormat::Text,
+            };
+            Ok(Histogram::new(&decode(&data, None)?).report(format))
+        }
+        Command::Palette {
+            colors,
+            method,
+            format,
+            ..
+        } if command.is_report() => {
+            let format = match format {
+                HistogramFormat::Json => ReportFormat::Json,
+                _ => ReportFormat::Text,
+            };
+            let colors = palette_colors(&decode(&data, None)?, colors, method)?;
+            Ok(palette_report(&colors, format))
+        }
+        Command::Diff {
+            ref other,
+            format,
+            ref heatmap,
+        } => {
+            let (img, other) = (decode(&data, None)?, open_image(other)?);
+            if let Some(path) = heatmap {
+                check_overwrite(path, input, options)?;
+                diff_heatmap(&img, &other)?
+                    .save(path)
+                    .map_err(ImgtoolsError::Encode)?;
+            }
+            Ok(Comparison::new(&img, &other)?.report(format))
+        }
+        Command::Hash {
+            algorithm,
+            ref compare,
+            format,
+        } => {
+            let hash = ImageHash::new(&decode(&data, None)?, algorithm);
+            let other = match compare {
+                Some(path) => Some(ImageHash::new(&open_image(path)?, algorithm)),
+                None => None,
+            };
+            Ok(hash_report(algorithm, hash, other, format))
+        }
+        Command::Stats {
+            region,
+            ref pixel,
+            format,
+        } => Ok(Statistics::new(&decode(&data, None)?, region, pixel)?.report(format)),
+        Command::Assess {
+            ref fail_if,
+            format,
+        } => {
+            let assessment = Assessment::new(&decode(&data, None)?);
+            let failed: Vec<String> = fail_if
+                .iter()
+                .filter(|check| check.matches(assessment.value(check.metric)))
+                .map(|check| {
+                    format!(
+                        "{} {:.2} {} {}",
+                        check.metric.name(),
+                        assessment.value(check.metric),
+                        check.operator,
+                        check.value
+                    )
+                })
+                .collect();
+            match failed.is_empty() {
+                true => Ok(assessment.report(format)),
+                false => Err(ImgtoolsError::QualityCheck(failed.join(", "))),
+            }
+        }
+        Command::Bench {
+            ref steps,
+            runs,
+            warmup,
+            encode,
+            quality,
+            format,
+        } => {
+            let img = decode(&data, None)?;
+            let steps = Command::Pipeline {
+                steps: steps.clone(),
+            };
+            let benchmark = Benchmark::measure(&img, runs as usize, warmup as usize, |img| {
+                let img = apply_command(img, &steps)?;
+                let Some(encode) = encode else {
+                    return Ok(None);
+                };
+                let mut output = Cursor::new(Vec::new());
+                let metadata = Metadata::default();
+                encode_lossy(&img, encode, quality, &metadata, options, &mut output)?;
+                Ok(Some(output.into_inner().len() as u64))
+            })?;
+            Ok(benchmark.report(format))
+        }
+        Command::Placeholder {
+            algorithm,
+            components: Size(x, y),
+            decode: None,
+            format,
+            ..
+        } => {
+            let img = decode(&data, None)?;
+            let hash = match algorithm {
+                PlaceholderAlgorithm::BlurHash => blurhash(&img, x, y)?,
+                PlaceholderAlgorithm::ThumbHash => thumbhash(&img),
+            };
+            Ok(match format {
+                ReportFormat::Text => hash,
+                ReportFormat::Json => json!({
+                    "algorithm": match algorithm {
+                        PlaceholderAlgorithm::BlurHash => "blurhash",
+                        PlaceholderAlgorithm::ThumbHash => "thumbhash",
+                    },
+                    "hash": hash,
+                    "width": img.width(),
+                    "height": img.height(),
+                })
+                .to_string(),
+            })
+        }
+        Command::Lqip {
+            width,
+            quality,
+            sidecar,
+            format,
+        } => {
+            let preview = lqip(&decode(&data, None)?, width, quality)?;
+            let [r, g, b] = preview.color;
+            let color = format!("#{:02x}{:02x}{:02x}", r, g, b);
+            let report = json!({
+                "data_uri": preview.data_uri,
+                "width": preview.width,
+                "height": preview.height,
+                "color": color,
+            })
+            .to_string();
+            if sidecar {
+                if is_stdio(input) {
+                    return Err(ImgtoolsError::InvalidArgument(
+                        "--sidecar is written next to the input, which standard input has none of"
+                            .into(),
+                    ));
+                }
+                let path =
+                    output_dir(input, None)?.join(format!("{}.lqip.json", input_stem(input)));
+                check_overwrite(&path, input, options)?;
+                fs::write(&path, &report).map_err(|source| ImgtoolsError::Write {
+                    path: path.clone(),
+                    source,
+                })?;
+                return Ok(format!("Wrote {}", path.display()));
+            }
+            Ok(match format {
+                ReportFormat::Text => format!("{}\n{}", preview.data_uri, color),
+                ReportFormat::Json => report,
+            })
+        }
+        _ => Err(ImgtoolsError::InvalidArgument(
+            "The command does not produce a report".into(),
+        )),
+    }
+}
+
+/// Dominant colors of the image for the palette command
+fn palette_colors(
+    img: &DynamicImage,
+    colors: u16,
+    method: PaletteMethod,
+) -> Result<Vec<([u8; 3], f64)>, ImgtoolsError> {
+    if !(1..=256).contains(&colors) {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Colors {} is out of valid range (1 to 256)",
+            colors
+        )));
+    }
+    Ok(dominant_colors(&rgba8(img), colors as usize, method))
+}
+
+/// Output format selected by the command, the last conversion in a pipeline wins
+pub fn output_format(command: &Command) -> Option<Format> {
+    match command {
+        Command::Convert { format } => Some(*format),
+        Command::Animate { format, .. } => *format,
+        Command::Pipeline { steps } => steps.0.iter().rev().find_map(output_format),
+        _ => None,
+    }
+}
+
+/// Encode the image to the given format
+pub fn encode<W: Write + Seek>(
+    img: &DynamicImage,
+    format: Format,
+    output: W,
+) -> Result<(), ImgtoolsError> {
+    encode_with_metadata(img, format, &Metadata::default(), output)
+}
+
+/// Encode the image to the given format, embedding the metadata the format supports
+pub fn encode_with_metadata<W: Write + Seek>(
+    img: &DynamicImage,
+    format: Format,
+    metadata: &Metadata,
+    output: W,
+) -> Result<(), ImgtoolsError> {
+    encode_with_options(img, format, metadata, &EncodeOptions::default(), output)
+}
+
+/// Encode the image with the encoding options, which only affect JPEG and PNG
+pub fn encode_with_options<W: Write + Seek>(
+    img: &DynamicImage,
+    format: Format,
+    metadata: &Metadata,
+    options: &EncodeOptions,
+    mut output: W,
+) -> Result<(), ImgtoolsError> {
+    // XMP is inserted into the encoded data
+    if let Some(xmp) = &metadata.xmp {
+        let metadata = Metadata {
+            xmp: None,
+            ..metadata.clone()
+        };
+        let mut data = Cursor::new(Vec::new());
+        encode_with_options(img, format, &metadata, options, &mut data)?;
+        let data = insert_xmp(data.into_inner(), format, xmp);
+        return output
+            .write_all(&data)
+            .map_err(|e| ImgtoolsError::Encode(e.into()));
+    }
+
+    // Handle different output formats
+    let img = &*encodable(img, format);
+    let result = match format {
+        Format::Jpeg if options.progressive => {
+            return encode_progressive(img, DEFAULT_QUALITY, metadata, output);
+        }
+        // Large images are compressed in stripes on every thread
+        Format::Png
+            if options.interlace.is_some()
+                || rayon::current_num_threads() > 1
+                    && img.as_bytes().len() >= PARALLEL_PNG_BYTES =>
+        {
+            return encode_png(img, metadata, options, output);
+        }
+        Format::Jpeg => write_image(JpegEncoder::new(output), img, metadata),
+        Format::Png => write_image(PngEncoder::new(output), img, metadata),
+        Format::WebP => write_image(WebPEncoder::new_lossless(output), img, metadata),
+        Format::Bmp => write_image(BmpEncoder::new(&mut output), img, metadata),
+        Format::Avif => write_image(avif_encoder(AvifEncoder::new(output)), img, metadata),
+        // The TIFF encoder can't store EXIF, it is added to what it wrote
+        Format::Tiff if let Some(exif) = &metadata.exif => {
+            let mut data = Cursor::new(Vec::new());
+            write_image(TiffEncoder::new(&mut data), img, metadata)
+                .map_err(ImgtoolsError::Encode)?;
+            let data = set_tiff_exif(data.get_ref(), exif)?;
+            return output
+                .write_all(&data)
+                .map_err(|e| ImgtoolsError::Encode(e.into()));
+        }
+        Format::Tiff => write_image(TiffEncoder::new(output), img, metadata),
+        Format::Ico => write_image(IcoEncoder::new(output), img, metadata),
+        Format::Gif => {
+            // GIF only supports 8-bit RGBA input
+            let mut encoder = GifEncoder::new(output);
+            let img = rgba8(img);
+            encoder.encode(
+                img.as_raw(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::Rgba8,
+            )
+        }
+    };
+
+    result.map_err(ImgtoolsError::Encode)
+}
+
+/// Encode an output with the encoding options, taking out what --strip removes
+///
+/// The metadata is already reduced to what is kept, stripping the encoded
+/// data as well makes sure nothing an encoder adds on its own slips through.
+fn encode_output(
+    img: &DynamicImage,
+    format: Format,
+    metadata: &Metadata,
+    options: &ProcessOptions,
+    output: &mut Cursor<Vec<u8>>,
+) -> Result<(), ImgtoolsError> {
+    encode_with_options(img, format, metadata, &options.encoding, &mut *output)?;
+    if let Some(keep) = &options.strip
+        && let Some(stripped) = strip_metadata(output.get_ref(), format.into(), keep)
+    {
+        *output = Cursor::new(stripped);
+    }
+    Ok(())
+}
+
+/// Convert the image to a color type the output format can store
+///
+/// 16-bit data is kept for PNG, TIFF and AVIF and float data for TIFF, other
+/// formats get 8 bits per channel. The channels stay the same except for
+/// gray with alpha in TIFF, which becomes RGBA.
+pub(crate) fn encodable(img: &DynamicImage, format: Format) -> Cow<'_, DynamicImage> {
+    let high_depth = matches!(format, Format::Png | Format::Tiff | Format::Avif);
+    let converted: DynamicImage = match img.color() {
+        ColorType::La8 if format == Format::Tiff => img.to_rgba8().into(),
+        ColorType::La16 if format == Format::Tiff => img.to_rgba16().into(),
+        ColorType::Rgb32F | ColorType::Rgba32F if format == Format::Tiff => {
+            return Cow::Borrowed(img);
+        }
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 if high_depth => {
+            return Cow::Borrowed(img);
+        }
+        ColorType::Rgb32F if high_depth => img.to_rgb16().into(),
+        ColorType::Rgba32F if high_depth => img.to_rgba16().into(),
+        ColorType::L16 => img.to_luma8().into(),
+        ColorType::La16 => img.to_luma_alpha8().into(),
+        ColorType::Rgb16 | ColorType::Rgb32F => img.to_rgb8().into(),
+        ColorType::Rgba16 | ColorType::Rgba32F => img.to_rgba8().into(),
+        _ => return Cow::Borrowed(img),
+    };
+    Cow::Owned(converted)
+}
+
+/// Encode square icons of different sizes into one ICO file
+pub fn encode_ico<W: Write>(icons: &[RgbaImage], output: W) -> Result<(), ImgtoolsError> {
+    let frames = icons
+        .iter()
+        .map(|icon| IcoFrame::as_png(icon, icon.width(), icon.height(), ExtendedColorType::Rgba8))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ImgtoolsError::Encode)?;
+    IcoEncoder::new(output)
+        .encode_images(&frames)
+        .map_err(ImgtoolsError::Encode)
+}
+
+/// An AVIF encoder splitting its tiles over the threads of the current pool
+fn avif_encoder<W: Write>(encoder: AvifEncoder<W>) -> AvifEncoder<W> {
+    encoder.with_num_threads(Some(rayon::current_num_threads()))
+}
+
+/// Write the image with an encoder, skipping metadata the encoder can't store
+pub(crate) fn write_image<E: ImageEncoder>(
+    mut encoder: E,
+    img: &DynamicImage,
+    metadata: &Metadata,
+) -> image::ImageResult<()> {
+    if let Some(icc) = &metadata.icc {
+        let _ = encoder.set_icc_profile(icc.clone());
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.set_exif_metadata(exif.clone());
+    }
+
+    let color_type: ExtendedColorType = img.color().into();
+    encoder.write_image(img.as_bytes(), img.width(), img.height(), color_type)
+}
+
+/// Apply a processing command to the image
+///
+/// Conversion only affects how the result is encoded and leaves the pixels
+/// unchanged, pipelines apply each of their steps in order.
+pub fn apply_command(
+    mut img: DynamicImage,
+    command: &Command,
+) -> Result<DynamicImage, ImgtoolsError> {
+    // Get image dimensions
+    let width = img.width();
+    let height = img.height();
+
+    match *command {
+        // Conversion, optimization, stripping and redaction are handled when encoding
+        Command::Convert { .. }
+        | Command::Optimize { .. }
+        | Command::Strip { .. }
+        | Command::Exif {
+            action: Some(_), ..
+        } => {}
+        // Create a thumbnail
+        Command::Thumbnail {
+            width,
+            height,
+            mode,
+            filter,
+            background,
+        } => {
+            if width == 0 || height == 0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Thumbnail size {}x{} must be at least 1x1",
+                    width, height
+                )));
+            }
+            img = thumbnail(&img, width, height, mode, filter, background.into());
+        }
+        // Overlay another image
+        Command::Composite {
+            ref overlay,
+            position,
+            margin,
+            opacity,
+            blend,
+        } => {
+            if !(0.0..=1.0).contains(&opacity) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Opacity {} is out of valid range (0.0 to 1.0)",
+                    opacity
+                )));
+            }
+            let top = open_image(overlay)?.into_rgba8();
+            img = composite(&img, &top, position, margin, opacity, blend);
+        }
+        // Orientation is applied when decoding, see process_file
+        Command::Autoorient => {}
+        // Adjust colors and tones
+        Command::Adjust {
+            gamma,
+            exposure,
+            saturation,
+            red,
+            green,
+            blue,
+            ref curve,
+        } => {
+            if gamma <= 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Gamma {} must be greater than 0",
+                    gamma
+                )));
+            }
+            if [saturation, red, green, blue].iter().any(|&v| v < 0.0) {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Saturation and channel multipliers must not be negative".into(),
+                ));
+            }
+
+            let adjustments = Adjustments {
+                channels: [red, green, blue],
+                exposure,
+                gamma,
+                saturation,
+                curve: curve.clone(),
+            };
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            adjust(&mut rgba, &adjustments);
+            img = with_color_type(rgba, color);
+        }
+        // Extend the canvas
+        Command::Pad {
+            top,
+            right,
+            bottom,
+            left,
+            to,
+            position,
+            ref background,
+        } => {
+            let (canvas, offset) = match to {
+                Some(Size(w, h)) => {
+                    if w < width || h < height {
+                        return Err(ImgtoolsError::InvalidArgument(format!(
+                            "Canvas {}x{} is smaller than the image {}x{}",
+                            w, h, width, height
+                        )));
+                    }
+                    if let Position::FlatLay(..) = position {
+                        return Err(ImgtoolsError::InvalidArgument(
+                            "Flat lay is not a valid position for padding".into(),
+                        ));
+                    }
+                    let offset = placements(position, (w, h), (width, height), 0)[0];
+                    ((w, h), offset)
+                }
+                None => {
+                    let across = |l: Length| l.pixels(width).round() as u32;
+                    let down = |l: Length| l.pixels(height).round() as u32;
+                    let (top, right, bottom, left) =
+                        (down(top), across(right), down(bottom), across(left));
+                    (
+                        (width + left + right, height + top + bottom),
+                        (left as i64, top as i64),
+                    )
+                }
+            };
+            let keep_alpha = img.color().has_alpha();
+            let padded = pad(&img, canvas.0, canvas.1, offset, background.color());
+            img = background.underlay(padded, keep_alpha);
+        }
+        // Shear with a grown canvas
+        Command::Shear {
+            x_degrees,
+            y_degrees,
+            background,
+        } => {
+            for degrees in [x_degrees, y_degrees] {
+                if !(-80.0..=80.0).contains(&degrees) {
+                    return Err(ImgtoolsError::InvalidArgument(format!(
+                        "Shear angle {} is out of valid range (-80.0 to 80.0)",
+                        degrees
+                    )));
+                }
+            }
+
+            img = shear(&img, x_degrees, y_degrees, background.into())?;
+        }
+        // Straighten tilted lines
+        Command::Deskew {
+            max_angle,
+            background,
+        } => {
+            if !(0.0..=45.0).contains(&max_angle) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Max angle {} is out of valid range (0.0 to 45.0)",
+                    max_angle
+                )));
+            }
+            let Some(angle) = skew_angle(&img, max_angle) else {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Nothing on the image to measure the tilt from".into(),
+                ));
+            };
+            let mut fields = Map::new();
+            fields.insert("angle".into(), json!(angle));
+            log_with(
+                Level::Info,
+                format!("Deskewed by {:.2} degrees", angle),
+                fields,
+            );
+            if angle != 0.0 {
+                img = straighten(&img, angle, background.into());
+            }
+        }
+        // Level the horizon, cropping off the corners
+        Command::Straighten { max_angle } => {
+            if !(0.0..=45.0).contains(&max_angle) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Max angle {} is out of valid range (0.0 to 45.0)",
+                    max_angle
+                )));
+            }
+            match horizon_angle(&img, max_angle) {
+                Some(angle) => {
+                    let mut fields = Map::new();
+                    fields.insert("angle".into(), json!(angle));
+                    log_with(
+                        Level::Info,
+                        format!("Straightened by {:.2} degrees", angle),
+                        fields,
+                    );
+                    if angle != 0.0 {
+                        img = level(&img, angle);
+                    }
+                }
+                None => log_with(
+                    Level::Info,
+                    "No horizon found, the image is left as it is",
+                    Map::new(),
+                ),
+            }
+        }
+        // Draw a border inside or around the image
+        Command::Border {
+            width,
+            color,
+            radius,
+            inset,
+        } => {
+            img = border(&img, width, radius, color.into(), inset);
+        }
+        // Round the corners or cut to an ellipse
+        Command::Round { radius, circle } => {
+            img = round(&img, radius.pixels(width.min(height)), circle);
+        }
+        // Blend towards a color with a radial falloff
+        Command::Vignette {
+            strength,
+            radius,
+            color,
+        } => {
+            if !(0.0..=1.0).contains(&strength) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Strength {} is out of valid range (0.0 to 1.0)",
+                    strength
+                )));
+            }
+            if !(0.0..1.0).contains(&radius) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Radius {} is out of valid range (0.0 to 1.0)",
+                    radius
+                )));
+            }
+
+            let original = img.color();
+            let mut rgba = img.into_rgba8();
+            vignette(&mut rgba, strength, radius, color.into());
+            img = with_color_type(rgba, original);
+        }
+        // Recolor with a tint
+        Command::Tint { mode, strength } => {
+            if !(0.0..=1.0).contains(&strength) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Strength {} is out of valid range (0.0 to 1.0)",
+                    strength
+                )));
+            }
+
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            tint(&mut rgba, mode, strength);
+            img = with_color_type(rgba, color);
+        }
+        // Reduce to a palette
+        Command::Quantize { colors, dither } => {
+            if !(1..=256).contains(&colors) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Colors {} is out of valid range (1 to 256)",
+                    colors
+                )));
+            }
+
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            let palette = Palette::median_cut(&rgba, colors as usize);
+            quantize(&mut rgba, &palette, dither);
+            img = with_color_type(rgba, color);
+        }
+        // Reduce channels to a few levels
+        Command::Posterize { levels } => {
+            if levels < 2 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Levels {} is out of valid range (2 to 255)",
+                    levels
+                )));
+            }
+
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            posterize(&mut rgba, levels);
+            img = with_color_type(rgba, color);
+        }
+        // Invert bright channels
+        Command::Solarize { threshold } => {
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            solarize(&mut rgba, threshold);
+            img = with_color_type(rgba, color);
+        }
+        // Convert to black and white
+        Command::Threshold { value } => {
+            let mut gray = img.into_luma8();
+            threshold(&mut gray, value);
+            img = gray.into();
+        }
+        // Gradient magnitude or canny edges
+        Command::Edges {
+            operator,
+            low,
+            high,
+        } => {
+            if !(0.0 <= low && low <= high) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Thresholds {} and {} are invalid, expected 0 <= low <= high",
+                    low, high
+                )));
+            }
+
+            img = edges(&img.into_luma8(), operator, low, high).into();
+        }
+        // Relief on mid gray
+        Command::Emboss { strength } => {
+            if !(0.0..=10.0).contains(&strength) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Strength {} is out of valid range (0.0 to 10.0)",
+                    strength
+                )));
+            }
+
+            img = emboss(&img.into_luma8(), strength).into();
+        }
+        // Custom kernel on the color channels
+        Command::Convolve {
+            ref kernel,
+            divisor,
+            offset,
+        } => {
+            let divisor = divisor.unwrap_or_else(|| kernel.default_divisor());
+            if divisor == 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Divisor must not be 0".to_string(),
+                ));
+            }
+
+            let color = img.color();
+            let rgba = convolve(&img.into_rgba8(), kernel, divisor, offset);
+            img = with_color_type(rgba, color);
+        }
+        // Random gaussian or salt and pepper noise
+        Command::Noise {
+            noise_type,
+            amount,
+            seed,
+        } => {
+            if !(0.0..=1.0).contains(&amount) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Amount {} is out of valid range (0.0 to 1.0)",
+                    amount
+                )));
+            }
+
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            noise(&mut rgba, noise_type, amount, seed);
+            img = with_color_type(rgba, color);
+        }
+        // Median or bilateral smoothing
+        Command::Denoise {
+            method,
+            radius,
+            sigma,
+        } => {
+            if !(1..=20).contains(&radius) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Radius {} is out of valid range (1 to 20)",
+                    radius
+                )));
+            }
+            if sigma <= 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Sigma {} must be positive",
+                    sigma
+                )));
+            }
+
+            let color = img.color();
+            let rgba = denoise(&img.into_rgba8(), method, radius, sigma);
+            img = with_color_type(rgba, color);
+        }
+        // Neutralize a color cast
+        Command::Whitebalance {
+            auto,
+            from_pixel,
+            temperature,
+            tint,
+        } => {
+            for (name, value) in [("Temperature", temperature), ("Tint", tint)] {
+                if !(-100.0..=100.0).contains(&value) {
+                    return Err(ImgtoolsError::InvalidArgument(format!(
+                        "{} {} is out of valid range (-100.0 to 100.0)",
+                        name, value
+                    )));
+                }
+            }
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            let mut gains = match (auto, from_pixel) {
+                (Some(method), _) => balance_gains(&rgba, method),
+                (None, Some(point)) => {
+                    if point.x >= width || point.y >= height {
+                        return Err(ImgtoolsError::InvalidArgument(format!(
+                            "Pixel {},{} is outside the {}x{} image",
+                            point.x, point.y, width, height
+                        )));
+                    }
+                    // Average a few pixels around it so noise doesn't tint the result
+                    let spot = rgba.view(
+                        point.x.saturating_sub(2),
+                        point.y.saturating_sub(2),
+                        5.min(width - point.x.saturating_sub(2)),
+                        5.min(height - point.y.saturating_sub(2)),
+                    );
+                    let mut sum = [0.0; 3];
+                    for (_, _, pixel) in spot.pixels() {
+                        for (channel, sum) in sum.iter_mut().enumerate() {
+                            *sum += pixel[channel] as f32;
+                        }
+                    }
+                    gray_point_gains(sum)
+                }
+                (None, None) => [1.0; 3],
+            };
+            for (gain, slider) in gains.iter_mut().zip(temperature_gains(temperature, tint)) {
+                *gain *= slider;
+            }
+            let adjustments = Adjustments {
+                channels: gains,
+                ..Adjustments::default()
+            };
+            adjust(&mut rgba, &adjustments);
+            img = with_color_type(rgba, color);
+        }
+        // Lift shadows and hold back highlights
+        Command::ShadowsHighlights {
+            shadows,
+            highlights,
+            radius,
+        } => {
+            for (name, value) in [("Shadows", shadows), ("Highlights", highlights)] {
+                if !(0.0..=100.0).contains(&value) {
+                    return Err(ImgtoolsError::InvalidArgument(format!(
+                        "{} {} is out of valid range (0.0 to 100.0)",
+                        name, value
+                    )));
+                }
+            }
+            if radius < 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Radius {} must not be negative",
+                    radius
+                )));
+            }
+
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            shadows_highlights(&mut rgba, shadows / 100.0, highlights / 100.0, radius);
+            img = with_color_type(rgba, color);
+        }
+        // Spread out the brightness levels
+        Command::Equalize {
+            method,
+            tiles: Size(columns, rows),
+            clip_limit,
+        } => {
+            if columns > width || rows > height {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "{}x{} tiles don't fit in the {}x{} image",
+                    columns, rows, width, height
+                )));
+            }
+            if clip_limit < 1.0 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Clip limit {} must be at least 1.0",
+                    clip_limit
+                )));
+            }
+
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            equalize(&mut rgba, method, (columns, rows), clip_limit);
+            img = with_color_type(rgba, color);
+        }
+        // Stretch channels between histogram percentiles
+        Command::Autolevel { low, high } => {
+            if !(0.0 <= low && low < high && high <= 100.0) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Percentiles {} and {} must satisfy 0 <= low < high <= 100",
+                    low, high
+                )));
+            }
+
+            let histogram = Histogram::new(&img);
+            let color = img.color();
+            let mut rgba = img.into_rgba8();
+            autolevel(&mut rgba, &histogram, low, high);
+            img = with_color_type(rgba, color);
+        }
+        // Replace the image with a chart of its histogram
+        Command::Histogram {
+            format: HistogramFormat::Png,
+            width,
+            height,
+        } => {
+            if width == 0 || height == 0 {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Histogram chart size must be greater than 0".into(),
+                ));
+            }
+            img = Histogram::new(&img).render(width, height).into();
+        }
+        // Combine planes or reorder the channels
+        Command::Channels {
+            command:
+                Channels::Merge {
+                    ref planes,
+                    ref map,
+                },
+        } => {
+            let sources = match planes.is_empty() {
+                true => split(&img).into_iter().map(|(_, plane)| plane).collect(),
+                false => std::iter::once(Ok(img.into_luma8()))
+                    .chain(planes.iter().map(|p| open_image(p).map(|i| i.into_luma8())))
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            if sources.len() > 4 {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Can't merge {} planes, at most 4",
+                    sources.len()
+                )));
+            }
+            let map = match map {
+                Some(map) => map.clone(),
+                None => ChannelMap(channel_names(sources.len()).to_vec()),
+            };
+            img = merge(&sources, &map)?;
+        }
+        // Alpha channel operations
+        Command::Alpha { ref command } => {
+            img = match command {
+                Alpha::Remove { background } => flatten(&img, (*background).into()),
+                Alpha::Extract => extract_alpha(&img).into(),
+                Alpha::Apply { mask } => apply_mask(&img, &open_image(mask)?.into_luma8()).into(),
+                Alpha::Premultiply => {
+                    let mut rgba = img.into_rgba8();
+                    premultiply(&mut rgba);
+                    rgba.into()
+                }
+            };
+        }
+        // Remove a background color
+        Command::Chromakey {
+            color,
+            tolerance,
+            feather,
+        } => {
+            for (name, value) in [("Tolerance", tolerance), ("Feather", feather)] {
+                if !(0.0..=1.0).contains(&value) {
+                    return Err(ImgtoolsError::InvalidArgument(format!(
+                        "{} {} is out of valid range (0.0 to 1.0)",
+                        name, value
+                    )));
+                }
+            }
+
+            let mut rgba = img.into_rgba8();
+            chromakey(&mut rgba, color.into(), tolerance, feather);
+            img = rgba.into();
+        }
+        // A single image to tone-map, several exposures are fused in combine_images
+        Command::Hdr { tone_map, exposure } => {
+            img = hdr::tone_map(&img, tone_map, exposure).into();
+        }
+        // Combining commands need every input, see combine_files
+        Command::Montage { .. }
+        | Command::Append { .. }
+        | Command::Animate { .. }
+        | Command::FocusStack { .. } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Commands that combine several images can't be pipeline steps".into(),
+            ));
+        }
+        // Images are created without an input, see create_file
+        Command::Create { .. }
+        | Command::Qrcode { .. }
+        | Command::Placeholder {
+            decode: Some(_), ..
+        } => {
+            return Err(ImgtoolsError::InvalidArgument(format!(
+                "{} makes a new image and can't be a pipeline step",
+                command.name()
+            )));
+        }
+        // Recipes are loaded before processing starts
+        Command::Run { .. } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Recipes can't be pipeline steps".into(),
+            ));
+        }
+        // Watching runs its command on every new file, see main
+        Command::Watch { .. } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Watching can't be a pipeline step".into(),
+            ));
+        }
+        #[cfg(feature = "stitch")]
+        Command::Stitch { .. } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Commands that combine several images can't be pipeline steps".into(),
+            ));
+        }
+        // Serving runs commands from requests, see serve
+        #[cfg(feature = "serve")]
+        Command::Serve { .. } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Serving can't be a pipeline step".into(),
+            ));
+        }
+        // Slicing, frame extraction, srcset and tiles write several files, see process_file
+        Command::Slice { .. }
+        | Command::Frames { .. }
+        | Command::Favicon { .. }
+        | Command::Srcset { .. }
+        | Command::Tiles { .. } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Commands that write several images can't be pipeline steps".into(),
+            ));
+        }
+        // Splitting writes several files, see process_file
+        Command::Channels {
+            command: Channels::Split,
+        } => {
+            return Err(ImgtoolsError::InvalidArgument(
+                "Channel splitting can't be a pipeline step".into(),
+            ));
+        }
+        // Replace the image with swatches of its dominant colors
+        Command::Palette {
+            colors,
+            method,
+            format: HistogramFormat::Png,
+            width,
+            height,
+        } => {
+            if width == 0 || height == 0 {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Swatch strip size must be greater than 0".into(),
+                ));
+            }
+            let colors = palette_colors(&img, colors, method)?;
+            img = render_swatches(&colors, width, height).into();
+        }
+        // Reports leave the image unchanged
+        Command::Exif { action: None, .. }
+        | Command::Palette { .. }
+        | Command::Histogram { .. }
+        | Command::Info { .. }
+        | Command::Diff { .. }
+        | Command::Hash { .. }
+        | Command::Stats { .. }
+        | Command::Assess { .. }
+        | Command::Bench { .. }
+        | Command::Lqip { .. }
+        | Command::Placeholder { decode: None, .. } => {}
+        // Apply every pipeline step in order
+        Command::Pipeline { ref steps } => {
+            for step in &steps.0 {
+                img = apply_command(img, step)?;
+            }
+        }
+        // Flip image horizontally and/or vertically
+        Command::Flip {
+            horizontal,
+            vertical,
+        } => {
+            img = match (horizontal, vertical) {
+                (true, true) => img.fliph().flipv(),
+                (true, false) => img.fliph(),
+                (false, true) => img.flipv(),
+                (false, false) => img,
+            };
+        }
+        // Rotate image by fixed angles
+        Command::Rotate { rotate } => {
+            img = match rotate {
+                Rotate::Rotate90 => img.rotate90(),
+                Rotate::Rotate180 => img.rotate180(),
+                Rotate::Rotate270 => img.rotate270(),
+            };
+        }
+        // Resize image with optional exact dimensions
+        Command::Resize {
+            width: w,
+            height: h,
+            exact,
+            filter,
+            scale,
+            fast,
+        } => {
+            // A box given by both sides is fitted into unless exact, other
+            // dimensions already keep the aspect ratio
+            let fit = !exact && w.is_some() && h.is_some();
+            let (w, h) = resize_dimensions((width, height), w, h, scale)?;
+            let (w, h) = match fit {
+                true => fit_dimensions((width, height), (w, h)),
+                false => (w, h),
+            };
+            img = match fast.then(|| fast_resize(&img, w, h, filter)).flatten() {
+                Some(resized) => resized,
+                None => img.resize_exact(w, h, filter.into()),
+            };
+        }
+        // Shrink by removing seams through the flattest areas
+        Command::LiquidResize {
+            width: w,
+            height: h,
+            ref protect,
+        } => {
+            let (w, h) = (w.unwrap_or(width), h.unwrap_or(height));
+            if w == 0 || h == 0 || w > width || h > height {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Liquid resize only shrinks, {}x{} doesn't fit in the {}x{} image",
+                    w, h, width, height
+                )));
+            }
+            let mask = match protect {
+                Some(path) => Some(open_image(path)?.into_luma8()),
+                None => None,
+            };
+            img = liquid_resize(&img, w, h, mask.as_ref());
+        }
+        Command::Upscale { factor, ref model } => {
+            if ![2, 4, 8].contains(&factor) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Upscale factor {} must be 2, 4 or 8",
+                    factor
+                )));
+            }
+            let (w, h) = width
+                .checked_mul(factor)
+                .zip(height.checked_mul(factor))
+                .ok_or_else(|| {
+                    ImgtoolsError::InvalidArgument(format!(
+                        "Upscaling {}x{} by {} exceeds the largest image size",
+                        width, height, factor
+                    ))
+                })?;
+            img = match model {
+                Some(path) => {
+                    let rgb = DynamicImage::ImageRgb8(upscale_with_model(&img, path)?);
+                    let rgb = match (rgb.width(), rgb.height()) == (w, h) {
+                        true => rgb,
+                        false => rgb.resize_exact(w, h, FilterType::Lanczos3),
+                    };
+                    let mut rgba = rgb.into_rgba8();
+                    if img.color().has_alpha() {
+                        let alpha = upscale(&img, factor).into_rgba8();
+                        for (pixel, a) in rgba.pixels_mut().zip(alpha.pixels()) {
+                            pixel[3] = a[3];
+                        }
+                    }
+                    with_color_type(rgba, img.color())
+                }
+                None => upscale(&img, factor),
+            };
+        }
+        #[cfg(feature = "onnx")]
+        Command::Infer {
+            ref model,
+            size,
+            range,
+            ref mean,
+            ref std,
+            bgr,
+            layout,
+            result,
+            channel,
+            stretch,
+        } => {
+            // One value for all channels or one for each
+            let per_channel = |values: &[f32], name: &str| match *values {
+                [v] => Ok([v; 3]),
+                [r, g, b] => Ok([r, g, b]),
+                _ => Err(ImgtoolsError::InvalidArgument(format!(
+                    "--{} takes one value or three for red, green and blue",
+                    name
+                ))),
+            };
+            let std = per_channel(std, "std")?;
+            if range <= 0.0 || std.contains(&0.0) {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "--range must be above 0 and --std must not be 0".into(),
+                ));
+            }
+            let inference = Inference {
+                model: model.clone(),
+                size,
+                range,
+                mean: per_channel(mean, "mean")?,
+                std,
+                bgr,
+                layout,
+                output: result,
+                channel,
+                stretch,
+            };
+            img = infer(&img, &inference)?;
+        }
+        #[cfg(feature = "onnx")]
+        Command::RemoveBg {
+            ref model,
+            size,
+            background,
+        } => {
+            let cut = remove_background(&img, model.as_deref(), size)?;
+            let background: Rgba<u8> = background.into();
+            img = match background[3] {
+                0 => cut,
+                _ => flatten(&cut, background),
+            };
+        }
+        // Convert image to grayscale
+        Command::Grayscale => {
+            img = img.grayscale();
+        }
+        // Apply blur effect
+        Command::Blur { sigma, fast } => {
+            let color = img.color();
+            let eight_bit = matches!(
+                color,
+                ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8
+            );
+            img = match (fast, sigma >= BOX_BLUR_SIGMA && eight_bit) {
+                (false, true) => with_color_type(box_blur(&rgba8(&img), sigma), color),
+                (true, _) => img.fast_blur(sigma),
+                (false, false) => img.blur(sigma),
+            };
+        }
+        // Adjust image brightness
+        Command::Brighten { value } => {
+            // The value is given for 8-bit channels, past 255 every one saturates
+            let value = value.clamp(-255, 255);
+            let value = match img.color().bytes_per_pixel() / img.color().channel_count() {
+                2 => value * 257,
+                _ => value,
+            };
+            img = img.brighten(value);
+        }
+        // Rotate image hue
+        Command::Huerotate { value } => {
+            img = img.huerotate(value);
+        }
+        // Adjust image contrast
+        Command::Contrast { value } => {
+            img = img.adjust_contrast(value);
+        }
+        // Crop image with various positioning options
+        Command::Crop {
+            crop,
+            focus,
+            clamp,
+            pad: padding,
+        } => {
+            let (mut x, mut y, w, h) = crop_rect(crop, (width, height));
+            if let Some(Focus::Faces) = focus {
+                let faces = detect_faces(&img)?;
+                if let Some((left, top, right, bottom)) = bounds(&faces) {
+                    // Only along sides the crop fits in, larger ones stay put
+                    let (cx, cy) = ((left + right) / 2, (top + bottom) / 2);
+                    if w <= width {
+                        x = cx.saturating_sub(w / 2).min(width - w) as i64;
+                    }
+                    if h <= height {
+                        y = cy.saturating_sub(h / 2).min(height - h) as i64;
+                    }
+                }
+            }
+
+            let inside = fit_crop((x, y, w, h), (width, height), clamp || padding.is_some())?;
+            img = match padding {
+                // The whole image keeps its place within the crop
+                Some(color) if (inside.2, inside.3) != (w, h) => {
+                    pad(&img, w, h, (-x, -y), Rgba::from(color))
+                }
+                _ => img.crop_imm(inside.0, inside.1, inside.2, inside.3),
+            };
+        }
+        // Cut off a uniform border
+        Command::Trim { fuzz, color } => {
+            if !(0.0..=1.0).contains(&fuzz) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Fuzz {} is out of valid range (0.0 to 1.0)",
+                    fuzz
+                )));
+            }
+            let rgba = rgba8(&img);
+            let border = color.map_or(*rgba.get_pixel(0, 0), Rgba::from);
+            let Some(area) = trim_bounds(&rgba, border, fuzz) else {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "The whole image is border, nothing is left after trimming".into(),
+                ));
+            };
+            let mut fields = Map::new();
+            for (name, value) in [
+                ("x", area.x),
+                ("y", area.y),
+                ("width", area.width),
+                ("height", area.height),
+            ] {
+                fields.insert(name.into(), json!(value));
+            }
+            log_with(
+                Level::Info,
+                format!(
+                    "Trimmed {}x{} to {},{},{},{}",
+                    width, height, area.x, area.y, area.width, area.height
+                ),
+                fields,
+            );
+            img = img.crop_imm(area.x, area.y, area.width, area.height);
+        }
+        // Invert image colors
+        Command::Invert => {
+            img.invert();
+        }
+        // Apply unsharp mask
+        Command::Unsharpen { sigma, threshold } => {
+            img = img.unsharpen(sigma, threshold);
+        }
+        // Add watermark (text or image)
+        Command::Watermark { .. } => {
+            let stamps = watermark_stamps(command, (width, height))?;
+            for (i, x, y) in stamps.placements {
+                overlay(&mut img, &stamps.images[i], x, y);
+            }
+        }
+        // Draw a caption box
+        Command::Caption {
+            ref text,
+            ref font,
+            size,
+            ref color,
+            align,
+            line_spacing,
+            max_width,
+            background,
+            padding,
+            radius,
+            position,
+            margin,
+        } => {
+            if size <= 0.0 || line_spacing < 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Font size must be greater than 0 and line spacing not negative".into(),
+                ));
+            }
+            let fonts = Fonts::load(font.as_deref())?;
+            let caption = Caption {
+                fonts: &fonts,
+                scale: PxScale::from(size),
+                color: color.clone(),
+                align,
+                line_spacing,
+                max_width,
+                background: background.map(Rgba::from),
+                padding,
+                radius,
+            };
+            let rendered = render_caption(text, &caption);
+            for (x, y) in placements(position, (width, height), rendered.dimensions(), margin) {
+                overlay(&mut img, &rendered, x, y);
+            }
+        }
+        // Obscure areas, e.g. emails or faces
+        Command::Redact {
+            ref regions,
+            auto_faces,
+            method,
+            strength,
+            radius,
+        } => {
+            if strength <= 0.0 {
+                return Err(ImgtoolsError::InvalidArgument(
+                    "Redact strength must be greater than 0".into(),
+                ));
+            }
+            // A region that misses the image would leave what it was meant to hide
+            if let Some(region) = regions.iter().find(|r| r.x >= width || r.y >= height) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Region {},{},{},{} is outside the {}x{} image",
+                    region.x, region.y, region.width, region.height, width, height
+                )));
+            }
+            let mut regions = regions.clone();
+            if auto_faces {
+                // The found square is tight around the eyes and mouth, grow it to the whole head
+                regions.extend(detect_faces(&img)?.into_iter().map(|face| {
+                    let (grow_x, grow_y) = (face.width / 5, face.height / 4);
+                    Region {
+                        x: face.x.saturating_sub(grow_x),
+                        y: face.y.saturating_sub(grow_y),
+                        width: face.width + 2 * grow_x,
+                        height: face.height + 2 * grow_y,
+                    }
+                }));
+            }
+            let color = img.color();
+            let mut canvas = img.into_rgba8();
+            for region in regions {
+                redact(&mut canvas, region, method, strength, radius);
+            }
+            img = with_color_type(canvas, color);
+        }
+        // Mark up the image with shapes and text
+        Command::Annotate {
+            ref shapes,
+            ref font,
+        } => {
+            let fonts = Fonts::load(font.as_deref())?;
+            img = annotate(&img, shapes, &fonts);
+        }
+    }
+
+    Ok(img)
+}
+
+#[cfg(not(feature = "onnx"))]
+fn upscale_with_model(_img: &DynamicImage, _path: &Path) -> Result<image::RgbImage, ImgtoolsError> {
+    Err(ImgtoolsError::InvalidArgument(
+        "Upscaling with a model needs imgtools built with the onnx feature".into(),
+    ))
+}
+
+#[cfg(not(feature = "faces"))]
+fn detect_faces(_img: &DynamicImage) -> Result<Vec<Region>, ImgtoolsError> {
+    Err(ImgtoolsError::InvalidArgument(
+        "Finding faces needs imgtools built with the faces feature".into(),
+    ))
+}
+
+/// Left, top, width and height of the area a crop keeps
+///
+/// The position is negative where a crop larger than the image reaches
+/// past its left or top edge.
+pub(crate) fn crop_rect(crop: Crop, (width, height): (u32, u32)) -> (i64, i64, u32, u32) {
+    // Percentages are taken of the image, rounded to whole pixels
+    let across = |l: Length| l.pixels(width).round() as u32;
+    let down = |l: Length| l.pixels(height).round() as u32;
+    let (w, h, x, y) = match crop {
+        Crop::Center(w, h)
+        | Crop::TopLeft(w, h)
+        | Crop::TopCenter(w, h)
+        | Crop::TopRight(w, h)
+        | Crop::MiddleLeft(w, h)
+        | Crop::MiddleRight(w, h)
+        | Crop::BottomLeft(w, h)
+        | Crop::BottomCenter(w, h)
+        | Crop::BottomRight(w, h) => (across(w), down(h), 0, 0),
+        Crop::Custom(x, y, w, h) => (across(w), down(h), across(x), down(y)),
+    };
+    let (right, bottom) = (width as i64 - w as i64, height as i64 - h as i64);
+    let (x, y) = match crop {
+        Crop::Center(..) => (right / 2, bottom / 2),
+        Crop::TopLeft(..) => (0, 0),
+        Crop::TopCenter(..) => (right / 2, 0),
+        Crop::TopRight(..) => (right, 0),
+        Crop::MiddleLeft(..) => (0, bottom / 2),
+        Crop::MiddleRight(..) => (right, bottom / 2),
+        Crop::BottomLeft(..) => (0, bottom),
+        Crop::BottomCenter(..) => (right / 2, bottom),
+        Crop::BottomRight(..) => (right, bottom),
+        Crop::Custom(..) => (x as i64, y as i64),
+    };
+    (x, y, w, h)
+}
+
+/// The part of a crop on the image, as left, top, width and height
+///
+/// A crop reaching past the edges is an error unless `clamp` allows
+/// shrinking it, one with nothing on the image always is.
+pub(crate) fn fit_crop(
+    (x, y, w, h): (i64, i64, u32, u32),
+    (width, height): (u32, u32),
+    clamp: bool,
+) -> Result<(u32, u32, u32, u32), ImgtoolsError> {
+    if w == 0 || h == 0 {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Crop width and height must be greater than 0".into(),
+        ));
+    }
+    let (left, top) = (x.max(0), y.max(0));
+    let right = (x + w as i64).min(width as i64);
+    let bottom = (y + h as i64).min(height as i64);
+    if right <= left || bottom <= top {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Crop {}x{} at {},{} lies outside the {}x{} image",
+            w, h, x, y, width, height
+        )));
+    }
+    let inside = (
+        left as u32,
+        top as u32,
+        (right - left) as u32,
+        (bottom - top) as u32,
+    );
+    if !clamp && (inside.2, inside.3) != (w, h) {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Crop {}x{} at {},{} reaches past the {}x{} image, use --clamp to shrink it or --pad to fill the rest",
+            w, h, x, y, width, height
+        )));
+    }
+    Ok(inside)
+}
+
+/// Size of the image a single step makes of one of `(width, height)`
+///
+/// Steps whose result depends on the pixels, such as trim or deskew, and
+/// steps with invalid parameters count as keeping the size, a pipeline ends
+/// at the size of its last step.
+pub(crate) fn output_size(command: &Command, (width, height): (u32, u32)) -> (u64, u64) {
+    let (w, h) = (width as u64, height as u64);
+    let side = |side: u64| side.min(u32::MAX as u64) as u32;
+    match *command {
+        Command::Pipeline { ref steps } => steps
+            .0
+            .iter()
+            .fold((w, h), |(w, h), step| output_size(step, (side(w), side(h)))),
+        Command::Rotate {
+            rotate: Rotate::Rotate90 | Rotate::Rotate270,
+        } => (h, w),
+        Command::Resize {
+            width: rw,
+            height: rh,
+            exact,
+            scale,
+            ..
+        } => match resize_dimensions((width, height), rw, rh, scale) {
+            Ok(size) if !exact && rw.is_some() && rh.is_some() => {
+                let (w, h) = fit_dimensions((width, height), size);
+                (w as u64, h as u64)
+            }
+            Ok((w, h)) => (w as u64, h as u64),
+            Err(_) => (w, h),
+        },
+        Command::LiquidResize {
+            width: rw,
+            height: rh,
+            ..
+        } => (rw.map_or(w, u64::from), rh.map_or(h, u64::from)),
+        Command::Upscale { factor, .. } => (w * factor as u64, h * factor as u64),
+        Command::Thumbnail {
+            width: tw,
+            height: th,
+            mode: ThumbnailMode::Fit,
+            ..
+        } if width > tw || height > th => {
+            let (w, h) = fit_dimensions((width, height), (tw, th));
+            (w as u64, h as u64)
+        }
+        Command::Thumbnail {
+            width: tw,
+            height: th,
+            mode: ThumbnailMode::Fill | ThumbnailMode::Pad,
+            ..
+        } => (tw as u64, th as u64),
+        Command::Pad {
+            to: Some(Size(w, h)),
+            ..
+        } => (w as u64, h as u64),
+        Command::Pad {
+            top,
+            right,
+            bottom,
+            left,
+            to: None,
+            ..
+        } => {
+            let across = |l: Length| l.pixels(width).round() as u64;
+            let down = |l: Length| l.pixels(height).round() as u64;
+            (
+                w + across(left) + across(right),
+                h + down(top) + down(bottom),
+            )
+        }
+        Command::Border {
+            width: border,
+            inset: false,
+            ..
+        } => (w + 2 * border as u64, h + 2 * border as u64),
+        Command::Shear {
+            x_degrees,
+            y_degrees,
+            ..
+        } => {
+            // Each side grows by the other one times the tangent, as in shear
+            let (tx, ty) = (x_degrees.to_radians().tan(), y_degrees.to_radians().tan());
+            let grown = |side: u64, other: u64, tan: f32| {
+                ((side as f64 + other as f64 * tan.abs() as f64).round() as u64).max(1)
+            };
+            (grown(w, h, tx), grown(h, w, ty))
+        }
+        Command::Crop {
+            crop, clamp, pad, ..
+        } => {
+            let rect = crop_rect(crop, (width, height));
+            match fit_crop(rect, (width, height), clamp || pad.is_some()) {
+                Ok(_) if pad.is_some() => (rect.2 as u64, rect.3 as u64),
+                Ok(inside) => (inside.2 as u64, inside.3 as u64),
+                Err(_) => (w, h),
+            }
+        }
+        _ => (w, h),
+    }
+}
+
+/// Watermark images and where copies of them go on an image
+pub(crate) struct Stamps {
+    pub images: Vec<RgbaImage>,
+    /// Index into `images` and the top left corner of every copy
+    pub placements: Vec<(usize, i64, i64)>,
+}
+
+/// Render the watermark of a watermark command for an image of the given size
+pub(crate) fn watermark_stamps(
+    command: &Command,
+    (width, height): (u32, u32),
+) -> Result<Stamps, ImgtoolsError> {
+    let Command::Watermark {
+        position,
+        rotate,
+        margin,
+        relative_scale,
+        row_offset,
+        stagger_angle,
+        coverage,
+        ref command,
+    } = *command
+    else {
+        unreachable!("Only watermarks have stamps")
+    };
+    // Validate rotation angle
+    if !(0.0..=360.0).contains(&rotate) {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Rotation value {} is out of valid range (0.0 to 360.0)",
+            rotate
+        )));
+    }
+
+    if let Some(Scale(coverage)) = coverage
+        && coverage > 1.0
+    {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Coverage {} is out of valid range (0% to 100%)",
+            coverage
+        )));
+    }
+
+    // A watermark wider than the image would only be cut off, and a huge one
+    // can't be allocated
+    if let Some(Scale(scale)) = relative_scale
+        && scale > 1.0
+    {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "Relative scale {} is out of valid range (0% to 100%)",
+            scale
+        )));
+    }
+
+    let stagger_angle = stagger_angle / 180.0 * PI;
+    let rotate = rotate / 180.0 * PI;
+
+    // Watermark width relative to the image width
+    let target_width =
+        relative_scale.map(|Scale(factor)| ((width as f32 * factor).round() as u32).max(1));
+
+    // Create watermark from text or image
+    let watermark = match command {
+        Watermark::Text {
+            text,
+            font,
+            scale,
+            color,
+            opacity,
+            stroke_color,
+            stroke_width,
+        } => {
+            // Validate opacity
+            if !(0.0..=1.0).contains(opacity) {
+                return Err(ImgtoolsError::InvalidArgument(format!(
+                    "Opacity {} is out of valid range (0.0 to 1.0)",
+                    opacity
+                )));
+            }
+
+            // Load the fonts, FangSong covers what the others lack
+            let fonts = Fonts::load(font.as_deref())?;
+
+            // Set text properties
+            let scale = PxScale::from(*scale);
+            let scale = match target_width {
+                Some(target) => {
+                    let (text_w, _) = fonts.text_size(scale, text);
+                    PxScale::from(scale.x * target as f32 / text_w.max(1) as f32)
+                }
+                None => scale,
+            };
+
+            // Create text watermark, leaving room for the outline
+            let stroke = *stroke_width as i32;
+            let (text_w, text_h) = fonts.text_size(scale, text);
+            let (text_w, text_h) = (text_w + 2 * stroke_width, text_h + 2 * stroke_width);
+            let diagonal = ((text_w.pow(2) + text_h.pow(2)) as f32).sqrt().ceil() as u32;
+            let mut watermark = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(diagonal, diagonal);
+            let center_x = (diagonal / 2 - text_w / 2) as i32 + stroke;
+            let center_y = (diagonal / 2 - text_h / 2) as i32 + stroke;
+
+            // Draw the outline as copies of the text within the stroke radius
+            let stroke_color = Rgba::from(*stroke_color);
+            for dy in -stroke..=stroke {
+                for dx in (-stroke..=stroke).filter(|dx| dx * dx + dy * dy <= stroke * stroke) {
+                    if (dx, dy) != (0, 0) {
+                        fonts.draw_text_mut(
+                            &mut watermark,
+                            stroke_color,
+                            (center_x + dx, center_y + dy),
+                            scale,
+                            text,
+                        );
+                    }
+                }
+            }
+            fonts.fill_text_mut(&mut watermark, color, (center_x, center_y), scale, text);
+
+            // Fade the whole text so the outline doesn't show through the fill
+            if *opacity < 1.0 {
+                for pixel in watermark.pixels_mut() {
+                    pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+                }
+            }
+            watermark
+        }
+        // Load image watermark
+        Watermark::Image { image } => {
+            let image = open_image(image)?;
+            match target_width {
+                Some(target) => {
+                    let (w, h) = resize_dimensions(image.dimensions(), Some(target), None, None)?;
+                    image.resize_exact(w, h, FilterType::Lanczos3).into_rgba8()
+                }
+                None => image.into_rgba8(),
+            }
+        }
+    };
+
+    // Rotate watermark
+    let rotated = rotate_about_center(
+        &watermark,
+        rotate,
+        Interpolation::Nearest,
+        Rgba([0, 0, 0, 0]),
+    );
+
+    // Position watermark
+    let size = (rotated.width(), rotated.height());
+    match position {
+        // Tile the whole image, every other row may be rotated further
+        Position::FlatLay(gap_x, gap_y) => {
+            let tiling = Tiling {
+                gap: (gap_x, gap_y),
+                row_offset: row_offset.map_or(0.0, |Scale(offset)| offset),
+                coverage: coverage.map(|Scale(coverage)| coverage),
+            };
+            let staggered = rotate_about_center(
+                &watermark,
+                rotate + stagger_angle,
+                Interpolation::Nearest,
+                Rgba([0, 0, 0, 0]),
+            );
+            let copies = tiling
+                .plan((width, height), size)
+                .iter()
+                .enumerate()
+                .flat_map(|(row, tiles)| tiles.iter().map(move |&(x, y)| (row % 2, x, y)))
+                .collect();
+            Ok(Stamps {
+                images: vec![rotated, staggered],
+                placements: copies,
+            })
+        }
+        _ => {
+            let copies = placements(position, (width, height), size, margin)
+                .into_iter()
+                .map(|(x, y)| (0, x, y))
+                .collect();
+            Ok(Stamps {
+                images: vec![rotated],
+                placements: copies,
+            })
+        }
+    }
+}
+
+/// Left, top, right and bottom edges around all the regions
+fn bounds(regions: &[Region]) -> Option<(u32, u32, u32, u32)> {
+    regions.iter().fold(None, |bounds, r| {
+        let (right, bottom) = (r.x + r.width, r.y + r.height);
+        Some(match bounds {
+            None => (r.x, r.y, right, bottom),
+            Some((l, t, rt, b)) => (l.min(r.x), t.min(r.y), rt.max(right), b.max(bottom)),
+        })
+    })
+}
+
+/// Size of the first image, or an error naming one that differs from it
+pub(crate) fn same_size(
+    images: &[(String, DynamicImage)],
+    what: &str,
+) -> Result<(u32, u32), ImgtoolsError> {
+    let Some((first_name, first)) = images.first() else {
+        return Ok((0, 0));
+    };
+    let (w, h) = first.dimensions();
+    match images.iter().find(|(_, img)| img.dimensions() != (w, h)) {
+        Some((name, img)) => Err(ImgtoolsError::InvalidArgument(format!(
+            "{} must be the same size, {} is {}x{} and {} is {}x{}",
+            what,
+            first_name,
+            w,
+            h,
+            name,
+            img.width(),
+            img.height()
+        ))),
+        None => Ok((w, h)),
+    }
+}
+
+/// The image as 8-bit RGBA, borrowed instead of copied when it already is
+pub(crate) fn rgba8(img: &DynamicImage) -> Cow<'_, RgbaImage> {
+    match img {
+        DynamicImage::ImageRgba8(rgba) => Cow::Borrowed(rgba),
+        _ => Cow::Owned(img.to_rgba8()),
+    }
+}
+
+/// Convert an RGBA buffer back to the channels of the color type it was made from
+///
+/// The depth stays 8 bits: 16-bit and float images that went through an 8-bit
+/// step only hold 8 bits of precision, widening them again would just hide that.
+pub(crate) fn with_color_type(rgba: RgbaImage, color: ColorType) -> DynamicImage {
+    let img = DynamicImage::ImageRgba8(rgba);
+    match color {
+        ColorType::L8 | ColorType::L16 => img.to_luma8().into(),
+        ColorType::La8 | ColorType::La16 => img.to_luma_alpha8().into(),
+        ColorType::Rgb8 | ColorType::Rgb16 | ColorType::Rgb32F => img.to_rgb8().into(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Color, Fill, Filter, Pipeline};
+    use image::RgbImage;
+
+    #[test]
+    fn test_apply_command_pipeline() {
+        let img = DynamicImage::new_rgb8(40, 20);
+        let pipeline = Command::Pipeline {
+            steps: Pipeline(vec![
+                Command::Resize {
+                    width: Some(20),
+                    height: Some(20),
+                    exact: true,
+                    filter: Filter::Nearest,
+                    scale: None,
+                    fast: false,
+                },
+                Command::Rotate {
+                    rotate: Rotate::Rotate90,
+                },
+                Command::Crop {
+                    crop: Crop::TopLeft(Length::Pixels(10), Length::Pixels(5)),
+                    focus: None,
+                    clamp: false,
+                    pad: None,
+                },
+            ]),
+        };
+
+        let img = apply_command(img, &pipeline).unwrap();
+        assert_eq!((img.width(), img.height()), (10, 5));
+    }
+
+    #[test]
+    fn test_apply_command_crop_past_the_image() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(30, 20, image::Rgb([9, 9, 9])));
+        let crop = |crop, clamp, pad| Command::Crop {
+            crop,
+            focus: None,
+            clamp,
+            pad,
+        };
+        let error = apply_command(
+            img.clone(),
+            &crop(
+                Crop::Center(Length::Pixels(40), Length::Pixels(10)),
+                false,
+                None,
+            ),
+        );
+        assert!(matches!(error, Err(ImgtoolsError::InvalidArgument(m)) if m.contains("--clamp")));
+        let error = apply_command(
+            img.clone(),
+            &crop(
+                Crop::Custom(
+                    Length::Pixels(30),
+                    Length::Pixels(0),
+                    Length::Pixels(5),
+                    Length::Pixels(5),
+                ),
+                true,
+                None,
+            ),
+        );
+        assert!(matches!(error, Err(ImgtoolsError::InvalidArgument(m)) if m.contains("outside")));
+        assert!(
+            apply_command(
+                img.clone(),
+                &crop(
+                    Crop::TopLeft(Length::Pixels(0), Length::Pixels(5)),
+                    true,
+                    None
+                )
+            )
+            .is_err()
+        );
+
+        let clamped = apply_command(
+            img.clone(),
+            &crop(
+                Crop::Custom(
+                    Length::Pixels(25),
+                    Length::Pixels(5),
+                    Length::Pixels(10),
+                    Length::Pixels(30),
+                ),
+                true,
+                None,
+            ),
+        );
+        let clamped = clamped.unwrap();
+        assert_eq!((clamped.width(), clamped.height()), (5, 15));
+
+        // The image stays centered on the larger canvas
+        let padded = crop(
+            Crop::Center(Length::Pixels(40), Length::Pixels(10)),
+            false,
+            Some(Color::Black),
+        );
+        let padded = apply_command(img.clone(), &padded).unwrap().to_rgb8();
+        assert_eq!(padded.dimensions(), (40, 10));
+        assert_eq!(padded.get_pixel(4, 5).0, [0, 0, 0]);
+        assert_eq!(padded.get_pixel(5, 5).0, [9, 9, 9]);
+        assert_eq!(padded.get_pixel(34, 5).0, [9, 9, 9]);
+        assert_eq!(padded.get_pixel(35, 5).0, [0, 0, 0]);
+        // A crop that fits isn't padded and keeps the color type
+        let fits = crop(
+            Crop::BottomRight(Length::Pixels(10), Length::Pixels(10)),
+            false,
+            Some(Color::Black),
+        );
+        assert_eq!(apply_command(img, &fits).unwrap().color(), ColorType::Rgb8);
+    }
+
+    #[test]
+    fn test_apply_command_percentages() {
+        let img = DynamicImage::new_rgb8(200, 100);
+        let crop = |crop: &str| Command::Crop {
+            crop: crop.parse().unwrap(),
+            focus: None,
+            clamp: false,
+            pad: None,
+        };
+        let size = |img: DynamicImage| (img.width(), img.height());
+        let cropped = apply_command(img.clone(), &crop("center(80%,50%)")).unwrap();
+        assert_eq!(size(cropped), (160, 50));
+        let rect = crop_rect("custom(10%,10%,50%,50%)".parse().unwrap(), (200, 100));
+        assert_eq!(rect, (20, 10, 100, 50));
+        assert_eq!(
+            crop_rect("bottomright(25%,10)".parse().unwrap(), (200, 100)),
+            (150, 90, 50, 10)
+        );
+        assert!(apply_command(img.clone(), &crop("custom(60%,0,50%,100%)")).is_err());
+
+        let pad = Command::Pad {
+            top: Length::Percent(10.0),
+            right: Length::Pixels(5),
+            bottom: Length::Pixels(0),
+            left: Length::Percent(10.0),
+            to: None,
+            position: Position::Center,
+            background: Fill::Solid(Color::Black),
+        };
+        assert_eq!(size(apply_command(img, &pad).unwrap()), (225, 110));
+    }
+
+    #[test]
+    fn test_thumbnail_zero_size() {
+        let img = DynamicImage::new_rgb8(40, 30);
+        for mode in [ThumbnailMode::Fit, ThumbnailMode::Fill, ThumbnailMode::Pad] {
+            for (width, height) in [(0, 20), (20, 0)] {
+                let thumbnail = Command::Thumbnail {
+                    width,
+                    height,
+                    mode,
+                    filter: Filter::Triangle,
+                    background: Color::White,
+                };
+                assert!(matches!(
+                    apply_command(img.clone(), &thumbnail),
+                    Err(ImgtoolsError::InvalidArgument(_))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_brighten_16_bit() {
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, image::Rgb([1000; 3])));
+        for (value, expected) in [(1, 1257), (i32::MAX, 65535), (i32::MIN, 0)] {
+            let brightened = apply_command(img.clone(), &Command::Brighten { value }).unwrap();
+            assert_eq!(brightened.into_rgb16().get_pixel(0, 0).0, [expected; 3]);
+        }
+    }
+
+    #[test]
+    fn test_output_size() {
+        // The size is known ahead for steps that change it by their parameters
+        let img = DynamicImage::new_rgb8(40, 30);
+        for steps in [
+            "resize -w 100 -h 100 -f nearest",
+            "resize -w 100 -h 100 -f nearest --exact",
+            "resize -s 50% -f nearest | rotate -r 90",
+            "thumbnail -w 20 -h 20 -f nearest -m fill",
+            "thumbnail -w 20 -h 20 -f nearest | pad --top 3 --left 10%",
+            "border -w 5",
+            "shear --x-degrees 30 --y-degrees -10",
+            "crop -c center(60,10) --pad white | upscale -f 2",
+            "crop -c topleft(60,10) --clamp",
+        ] {
+            let command = Command::Pipeline {
+                steps: steps.parse().unwrap(),
+            };
+            let made = apply_command(img.clone(), &command).unwrap();
+            let (width, height) = made.dimensions();
+            assert_eq!(
+                output_size(&command, (40, 30)),
+                (width as u64, height as u64),
+                "{}",
+                steps
+            );
+        }
+    }
+
+    #[test]
+    fn test_upscale_overflow() {
+        // An empty image wide enough that the upscaled width overflows
+        let img = DynamicImage::ImageRgb8(RgbImage::new(u32::MAX / 4 + 1, 0));
+        let upscale = Command::Upscale {
+            factor: 4,
+            model: None,
+        };
+        assert!(matches!(
+            apply_command(img, &upscale),
+            Err(ImgtoolsError::InvalidArgument(message)) if message.contains("largest image size")
+        ));
+    }
+
+    #[test]
+    fn test_apply_command_fast_resize_and_large_blur() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(60, 40, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 6) as u8, 128])
+        }));
+        let resize = |fast| Command::Resize {
+            width: Some(30),
+            height: Some(30),
+            exact: false,
+            filter: Filter::Lanczos3,
+            scale: None,
+            fast,
+        };
+        let fast = apply_command(img.clone(), &resize(true)).unwrap();
+        let exact = apply_command(img.clone(), &resize(false)).unwrap();
+        assert_eq!((fast.width(), fast.height()), (30, 20));
+        assert_eq!(fast.color(), ColorType::Rgb8);
+        let (fast, exact) = (fast.to_rgb8(), exact.to_rgb8());
+        assert!(
+            fast.iter()
+                .zip(exact.iter())
+                .all(|(a, b)| a.abs_diff(*b) <= 2)
+        );
+
+        // Large sigmas are blurred with boxes and keep the color type
+        let blur = Command::Blur {
+            sigma: 10.0,
+            fast: false,
+        };
+        let blurred = apply_command(img, &blur).unwrap();
+        assert_eq!(blurred.color(), ColorType::Rgb8);
+        assert_eq!((blurred.width(), blurred.height()), (60, 40));
+    }
+
+    #[test]
+    fn test_apply_command_invalid_watermark_rotation() {
+        let img = DynamicImage::new_rgb8(10, 10);
+        let command = Command::Watermark {
+            position: Position::Center,
+            rotate: 400.0,
+            margin: 0,
+            relative_scale: None,
+            row_offset: None,
+            stagger_angle: 0.0,
+            coverage: None,
+            command: Watermark::Text {
+                text: "x".to_string(),
+                font: None,
+                scale: 10.0,
+                color: Fill::Solid(Color::White),
+                opacity: 1.0,
+                stroke_color: Color::Black,
+                stroke_width: 0,
+            },
+        };
+        assert!(matches!(
+            apply_command(img, &command),
+            Err(ImgtoolsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_command_text_stroke() {
+        let img = DynamicImage::new_rgb8(120, 60);
+        let text = |opacity, stroke_width| Command::Watermark {
+            position: Position::Center,
+            rotate: 0.0,
+            margin: 0,
+            relative_scale: None,
+            row_offset: None,
+            stagger_angle: 0.0,
+            coverage: None,
+            command: Watermark::Text {
+                text: "X".to_string(),
+                font: None,
+                scale: 40.0,
+                color: Fill::Solid(Color::White),
+                opacity,
+                stroke_color: Color::Red,
+                stroke_width,
+            },
+        };
+
+        let colors = |img: DynamicImage| {
+            let pixels = img.into_rgb8().pixels().copied().collect::<Vec<_>>();
+            let red = pixels.iter().any(|p| p.0 == [255, 0, 0]);
+            let white = pixels.iter().any(|p| p.0 == [255, 255, 255]);
+            (red, white)
+        };
+        assert_eq!(
+            colors(apply_command(img.clone(), &text(1.0, 0)).unwrap()),
+            (false, true)
+        );
+        assert_eq!(
+            colors(apply_command(img.clone(), &text(1.0, 2)).unwrap()),
+            (true, true)
+        );
+        // Faded text never reaches full white
+        assert_eq!(
+            colors(apply_command(img.clone(), &text(0.5, 0)).unwrap()),
+            (false, false)
+        );
+        assert!(matches!(
+            apply_command(img, &text(1.5, 0)),
+            Err(ImgtoolsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_command_relative_watermark() {
+        let path = std::env::temp_dir().join("imgtools-relative-watermark.png");
+        DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(10, 5, Rgba([255; 4])))
+            .save(&path)
+            .unwrap();
+
+        let command = |scale| Command::Watermark {
+            position: Position::TopLeft,
+            rotate: 0.0,
+            margin: 0,
+            relative_scale: Some(Scale(scale)),
+            row_offset: None,
+            stagger_angle: 0.0,
+            coverage: None,
+            command: Watermark::Image {
+                image: path.clone(),
+            },
+        };
+        let img = apply_command(DynamicImage::new_rgb8(200, 100), &command(0.5)).unwrap();
+        // Wider than the image is refused instead of allocated
+        let huge = apply_command(DynamicImage::new_rgb8(200, 100), &command(1000.0));
+        fs::remove_file(&path).unwrap();
+
+        // Half the image width, keeping the aspect ratio
+        let white = img.into_rgb8().pixels().filter(|p| p.0 == [255; 3]).count();
+        assert_eq!(white, 100 * 50);
+        assert!(matches!(huge, Err(ImgtoolsError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_split_and_merge_channels() {
+        let dir = std::env::temp_dir().join("imgtools-channels");
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("in.png");
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(3, 2, image::Rgb([10, 20, 30])))
+            .save(&input)
+            .unwrap();
+
+        // Split writes one gray file per channel next to the output
+        let split = Command::Channels {
+            command: Channels::Split,
+        };
+        let output = dir.join("out.png");
+        process_file(&input, Some(&output), &split, &ProcessOptions::default()).unwrap();
+        let planes: Vec<_> = ["r", "g", "b"]
+            .iter()
+            .map(|name| dir.join(format!("out_{}.png", name)))
+            .collect();
+        assert!(!dir.join("out_a.png").exists());
+        let red = open_image(&planes[0]).unwrap();
+        assert_eq!(red.color(), ColorType::L8);
+        assert_eq!(red.to_luma8().get_pixel(0, 0)[0], 10);
+
+        // Merge the planes back with red and blue swapped
+        let merge = Command::Channels {
+            command: Channels::Merge {
+                planes: planes[1..].to_vec(),
+                map: Some("bgr".parse().unwrap()),
+            },
+        };
+        let img = apply_command(open_image(&planes[0]).unwrap(), &merge).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(img.to_rgb8().get_pixel(2, 1).0, [30, 20, 10]);
+
+        assert_eq!(
+            plane_path(Path::new("a/photo.jpg"), 'l'),
+            Path::new("a/photo_l.jpg")
+        );
+        assert!(apply_command(DynamicImage::new_rgb8(1, 1), &split).is_err());
+    }
+
+    #[test]
+    fn test_slice_writes_named_tiles() {
+        let dir = std::env::temp_dir().join("imgtools-slice");
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("sheet.png");
+        DynamicImage::new_rgb8(6, 4).save(&input).unwrap();
+
+        let command = Command::Slice {
+            size: None,
+            grid: Some(Size(3, 2)),
+            name: "{name}-{i}.jpg".into(),
+        };
+        let tiles = dir.join("tiles");
+        process_file(&input, Some(&tiles), &command, &ProcessOptions::default()).unwrap();
+        let last = open_image(&tiles.join("sheet-5.jpg"));
+        let duplicate = Command::Slice {
+            size: Some(Size(2, 2)),
+            grid: None,
+            name: "tile_{x}.png".into(),
+        };
+        let result = process_file(&input, Some(&tiles), &duplicate, &ProcessOptions::default());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(last.unwrap().dimensions(), (2, 2));
+        assert!(result.is_err());
+        assert_eq!(tile_name("{name}_{x}_{y}.png", "a", 1, 2, 5), "a_1_2.png");
+    }
+
+    #[test]
+    fn test_frames_writes_selected_frames() {
+        let dir = std::env::temp_dir().join("imgtools-frames");
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("anim.webp");
+        let images: Vec<_> = (0..12u8)
+            .map(|i| DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([i; 3]))))
+            .collect();
+        let animation =
+            Animation::from_images(&images, Delay::from_numer_denom_ms(50, 1), Repeat::Infinite);
+        let mut data = Vec::new();
+        animation.encode(Format::WebP, &mut data).unwrap();
+        fs::write(&input, data).unwrap();
+
+        let command = Command::Frames {
+            every: 3,
+            range: Some("2..9".parse().unwrap()),
+            name: "{name}_{i}.png".into(),
+        };
+        let frames = dir.join("frames");
+        process_file(&input, Some(&frames), &command, &ProcessOptions::default()).unwrap();
+        let mut written: Vec<_> = fs::read_dir(&frames)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        let fifth = open_image(&frames.join("anim_05.png")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(written, ["anim_02.png", "anim_05.png", "anim_08.png"]);
+        assert_eq!(fifth.to_rgba8().get_pixel(0, 0), &Rgba([5, 5, 5, 255]));
+    }
+
+    #[test]
+    fn test_svg_input_is_recognized() {
+        let svg =
+            b"<?xml version=\"1.0\"?>\n<!-- logo -->\n<!DOCTYPE svg [<!ENTITY red \"#f00\">]>\n\
+            <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\" height=\"2\">\
+            <rect width=\"2\" height=\"2\" fill=\"&red;\"/></svg>";
+        assert!(is_svg(svg));
+        assert!(is_svg(
+            b"<svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\"/>"
+        ));
+        assert!(!is_svg(
+            b"<!DOCTYPE html>\n<html><body><svg></svg></body></html>"
+        ));
+        assert!(!is_svg(b"<svgz/>"));
+        assert!(unsupported_input(svg).is_none());
+
+        let options = DecodeOptions {
+            density: 192.0,
+            ..DecodeOptions::default()
+        };
+        #[cfg(feature = "svg")]
+        {
+            let img = decode_with_options(svg, None, &options)
+                .unwrap()
+                .into_rgba8();
+            assert_eq!(img.dimensions(), (8, 4));
+            assert_eq!(img.get_pixel(1, 1).0, [255, 0, 0, 255]);
+            assert_eq!(img.get_pixel(6, 1).0[3], 0);
+            let info = ImageInfo::read(svg, None).unwrap();
+            assert_eq!(
+                (info.format.as_str(), info.width, info.height),
+                ("svg", 4, 2)
+            );
+        }
+        #[cfg(not(feature = "svg"))]
+        assert!(matches!(
+            decode_with_options(svg, None, &options),
+            Err(ImgtoolsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_inputs_are_reported() {
+        let mut cr2 = b"II*\0\x10\0\0\0CR\x02\0".to_vec();
+        cr2.resize(32, 0);
+        assert!(is_camera_raw(&cr2));
+        assert!(!is_camera_raw(b"II*\0\x08\0\0\0\0\0"));
+
+        // HEIC by its major brand or among the compatible ones, AVIF shares the container
+        let ftyp = |brands: &[u8]| {
+            let mut data = ((8 + brands.len()) as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(b"ftyp");
+            data.extend_from_slice(brands);
+            data
+        };
+        assert!(is_heif(&ftyp(b"heic\0\0\0\0mif1heic")));
+        assert!(is_heif(&ftyp(b"mif1\0\0\0\0mif1heix")));
+        assert!(!is_heif(&ftyp(b"avif\0\0\0\0mif1avif")));
+        assert!(!is_heif(&ftyp(b"mif1\0\0\0\0avif")));
+        #[cfg(not(feature = "heic"))]
+        assert!(matches!(
+            decode(&ftyp(b"heic\0\0\0\0mif1heic"), None),
+            Err(ImgtoolsError::InvalidArgument(_))
+        ));
+
+        // A NEF holding only a TIFF preview isn't passed off as the photo
+        let dir = std::env::temp_dir().join("imgtools_test_raw_extension");
+        fs::create_dir_all(&dir).unwrap();
+        let nef = dir.join("DSC_0001.NEF");
+        DynamicImage::new_rgb8(4, 4)
+            .save_with_format(&nef, ImageFormat::Tiff)
+            .unwrap();
+        assert!(matches!(
+            open_image(&nef),
+            Err(ImgtoolsError::InvalidArgument(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plan_writes_nothing() {
+        let dir = std::env::temp_dir().join("imgtools_test_plan");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.png");
+        DynamicImage::new_rgb8(8, 8).save(&input).unwrap();
+        let output = dir.join("converted").join("");
+        let command = Command::Pipeline {
+            steps: Pipeline(vec![
+                Command::Grayscale,
+                Command::Convert {
+                    format: Format::WebP,
+                },
+            ]),
+        };
+
+        let options = ProcessOptions {
+            dry_run: true,
+            ..ProcessOptions::default()
+        };
+        let plan = plan(
+            std::slice::from_ref(&input),
+            Some(&output),
+            &command,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(plan.inputs, vec![(input, Some(Format::Png))]);
+        assert_eq!(plan.operations.len(), 2);
+        assert_eq!(plan.output, dir.join("converted").join("photo.webp"));
+        assert_eq!(plan.format, Some(Format::WebP));
+        assert!(
+            plan.to_string()
+                .contains("step 1: grayscale\nstep 2: convert format=webp")
+        );
+        assert!(!dir.join("converted").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_template_names_the_result() {
+        let dir = std::env::temp_dir().join("imgtools_test_template");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.png");
+        DynamicImage::new_rgb8(8, 6).save(&input).unwrap();
+        let command = Command::Pipeline {
+            steps: Pipeline(vec![
+                Command::Resize {
+                    width: Some(4),
+                    height: Some(3),
+                    exact: true,
+                    filter: Filter::Nearest,
+                    scale: None,
+                    fast: false,
+                },
+                Command::Convert {
+                    format: Format::WebP,
+                },
+            ]),
+        };
+        let options = ProcessOptions {
+            output_template: Some("{counter}_{stem}_{width}x{height}.{format}".into()),
+            counter: 7,
+            ..ProcessOptions::default()
+        };
+
+        let output = dir.join("out");
+        // A dry run names the same file, with the size worked out from the header
+        let planned = plan(
+            std::slice::from_ref(&input),
+            Some(&output),
+            &command,
+            &options,
+        );
+        assert_eq!(planned.unwrap().output, output.join("7_photo_4x3.webp"));
+        process_file(&input, Some(&output), &command, &options).unwrap();
+        let written = image::open(output.join("7_photo_4x3.webp")).unwrap();
+        assert_eq!(written.dimensions(), (4, 3));
+
+        let result = process_file(&input, Some(Path::new(STDIO)), &command, &options);
+        assert!(matches!(result, Err(ImgtoolsError::InvalidArgument(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_files_are_kept_unless_forced() {
+        let dir = std::env::temp_dir().join("imgtools_test_clobber");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.png");
+        let output = dir.join("gray.png");
+        DynamicImage::new_rgb8(4, 4).save(&input).unwrap();
+        let original = fs::read(&input).unwrap();
+        let defaults = ProcessOptions::default();
+
+        process_file(&input, Some(&output), &Command::Invert, &defaults).unwrap();
+        let result = process_file(&input, Some(&output), &Command::Invert, &defaults);
+        assert!(matches!(result, Err(ImgtoolsError::OutputExists { .. })));
+        let forced = ProcessOptions {
+            force: true,
+            ..ProcessOptions::default()
+        };
+        process_file(&input, Some(&output), &Command::Invert, &forced).unwrap();
+
+        // Without an output the input itself would be replaced
+        let result = process_file(&input, None, &Command::Invert, &defaults);
+        assert!(matches!(result, Err(ImgtoolsError::InputOverwrite { .. })));
+        assert_eq!(fs::read(&input).unwrap(), original);
+
+        let in_place = ProcessOptions {
+            in_place: true,
+            backup: Some(".bak".into()),
+            ..ProcessOptions::default()
+        };
+        // The input is replaced rather than written over, so a link to it keeps the original
+        let link = dir.join("link.png");
+        fs::hard_link(&input, &link).unwrap();
+        process_file(&input, None, &Command::Invert, &in_place).unwrap();
+        assert_eq!(fs::read(dir.join("photo.png.bak")).unwrap(), original);
+        assert_ne!(fs::read(&input).unwrap(), original);
+        assert_eq!(fs::read(&link).unwrap(), original);
+        assert!(!dir.join("photo.png.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_date() {
+        let at = |secs| date(UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01");
+        assert_eq!(at(951_782_400), "2000-02-29");
+        assert_eq!(at(1_700_000_000), "2023-11-14");
+    }
+
+    #[test]
+    fn test_favicon_writes_ico_and_pngs() {
+        let dir = std::env::temp_dir().join("imgtools-favicon");
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("logo.png");
+        DynamicImage::new_rgb8(40, 30).save(&input).unwrap();
+
+        let command = Command::Favicon {
+            sizes: vec![16, 32],
+            png: true,
+        };
+        process_file(&input, Some(&dir), &command, &ProcessOptions::default()).unwrap();
+        let ico = fs::read(dir.join("favicon.ico")).unwrap();
+        let touch = open_image(&dir.join("apple-touch-icon.png"));
+        let large = dir.join("icon-512.png").exists();
+        let too_large = Command::Favicon {
+            sizes: vec![512],
+            png: false,
+        };
+        let result = process_file(&input, None, &too_large, &ProcessOptions::default());
+        fs::remove_dir_all(dir).unwrap();
+
+        // The header counts the stored sizes, the decoder picks the largest
+        assert_eq!(u16::from_le_bytes([ico[4], ico[5]]), 2);
+        let decoded = image::load_from_memory_with_format(&ico, ImageFormat::Ico).unwrap();
+        assert_eq!(decoded.dimensions(), (32, 32));
+        assert_eq!(touch.unwrap().dimensions(), (180, 180));
+        assert!(large);
+        assert!(matches!(result, Err(ImgtoolsError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_tiles_writes_pyramid() {
+        let dir = std::env::temp_dir().join("imgtools-tiles");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("scan.png");
+        DynamicImage::new_rgb8(40, 20).save(&input).unwrap();
+
+        let dzi = Command::Tiles {
+            layout: PyramidLayout::Dzi,
+            tile_size: 16,
+            overlap: 1,
+            format: Format::Png,
+            background: Color::default(),
+        };
+        let processed = process_file(&input, None, &dzi, &ProcessOptions::default()).unwrap();
+        let descriptor = fs::read_to_string(dir.join("scan.dzi")).unwrap();
+        let corner = open_image(&dir.join("scan_files/6/2_1.png"));
+        let xyz = Command::Tiles {
+            layout: PyramidLayout::Xyz,
+            tile_size: 16,
+            overlap: 0,
+            format: Format::Png,
+            background: Color::default(),
+        };
+        process_file(&input, None, &xyz, &ProcessOptions::default()).unwrap();
+        let edge = open_image(&dir.join("scan/2/2/1.png"));
+        let zooms = fs::read_dir(dir.join("scan")).unwrap().count();
+        fs::remove_dir_all(dir).unwrap();
+
+        // Levels 0 to 6 of 1x1 up to 40x20 pixels, the descriptor last
+        assert_eq!(processed.outputs.len(), 1 + 1 + 1 + 1 + 1 + 2 + 6 + 1);
+        assert!(descriptor.contains("<Size Width=\"40\" Height=\"20\"/>"));
+        assert_eq!(corner.unwrap().dimensions(), (9, 5));
+        assert_eq!(edge.unwrap().dimensions(), (16, 16));
+        assert_eq!(zooms, 3);
+    }
+
+    #[test]
+    fn test_srcset_writes_variants_and_manifest() {
+        let dir = std::env::temp_dir().join("imgtools-srcset");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("hero.png");
+        DynamicImage::new_rgb8(60, 40).save(&input).unwrap();
+
+        let command = Command::Srcset {
+            widths: vec![30, 15, 100],
+            formats: vec![Format::WebP, Format::Jpeg],
+            quality: Some(50),
+            name: "{name}-{width}.{format}".into(),
+            manifest: Some("{name}.html".into()),
+            sizes: "100vw".into(),
+        };
+        let processed =
+            process_file(&input, Some(&dir), &command, &ProcessOptions::default()).unwrap();
+        let small = open_image(&dir.join("hero-15.jpeg"));
+        let full = open_image(&dir.join("hero-60.webp"));
+        let html = fs::read_to_string(dir.join("hero.html")).unwrap();
+        let duplicate = Command::Srcset {
+            widths: vec![15, 30],
+            formats: vec![Format::Png],
+            quality: None,
+            name: "{name}.png".into(),
+            manifest: None,
+            sizes: "100vw".into(),
+        };
+        let result = process_file(&input, Some(&dir), &duplicate, &ProcessOptions::default());
+        fs::remove_dir_all(dir).unwrap();
+
+        // Three widths in two formats, 100 is beyond the image and becomes 60
+        assert_eq!(processed.outputs.len(), 7);
+        assert_eq!(small.unwrap().dimensions(), (15, 10));
+        assert_eq!(full.unwrap().dimensions(), (60, 40));
+        assert!(html.contains("srcset=\"hero-15.webp 15w, hero-30.webp 30w, hero-60.webp 60w\""));
+        assert!(html.contains("<img src=\"hero-60.jpeg\""));
+        assert!(matches!(result, Err(ImgtoolsError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_convert_to_embeds_the_target_profile() {
+        let dir = std::env::temp_dir().join("imgtools_test_profile");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("red.png");
+        let output = dir.join("p3.png");
+        RgbImage::from_pixel(4, 4, image::Rgb([255, 0, 0]))
+            .save(&input)
+            .unwrap();
+        let command = Command::Convert {
+            format: Format::Png,
+        };
+
+        let options = ProcessOptions {
+            convert_to: Some(Profile::DisplayP3),
+            ..ProcessOptions::default()
+        };
+        process_file(&input, Some(&output), &command, &options).unwrap();
+        let data = fs::read(&output).unwrap();
+        let p3 = profile_data(&Profile::DisplayP3).unwrap();
+        assert_eq!(read_icc(&data, None).unwrap(), Some(p3));
+        let converted = image::load_from_memory(&data).unwrap().to_rgb8();
+        assert!(converted.get_pixel(0, 0).0[0] < 250);
+
+        // An assumed profile only tags the untagged input
+        let options = ProcessOptions {
+            assume_profile: Some(Profile::AdobeRgb),
+            force: true,
+            ..ProcessOptions::default()
+        };
+        process_file(&input, Some(&output), &command, &options).unwrap();
+        let data = fs::read(&output).unwrap();
+        let adobe = profile_data(&Profile::AdobeRgb).unwrap();
+        assert_eq!(read_icc(&data, None).unwrap(), Some(adobe));
+        let tagged = image::load_from_memory(&data).unwrap().to_rgb8();
+        assert_eq!(tagged.get_pixel(0, 0).0, [255, 0, 0]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sixteen_bit_images_keep_their_depth() {
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_fn(8, 8, |x, _| {
+            image::Rgb([x as u16 * 1000 + 7, 30_000, 65_535])
+        }));
+        let pipeline = Command::Pipeline {
+            steps: Pipeline(vec![
+                Command::Resize {
+                    width: Some(4),
+                    height: Some(4),
+                    exact: true,
+                    filter: Filter::Nearest,
+                    scale: None,
+                    fast: false,
+                },
+                Command::Crop {
+                    crop: Crop::TopLeft(Length::Pixels(2), Length::Pixels(2)),
+                    focus: None,
+                    clamp: false,
+                    pad: None,
+                },
+                Command::Brighten { value: 1 },
+                Command::Contrast { value: 0.0 },
+            ]),
+        };
+        let img = apply_command(img, &pipeline).unwrap();
+        assert_eq!(img.color(), ColorType::Rgb16);
+        // Brightening by 1 adds one 8-bit step to 1007, the low bits survive
+        assert_eq!(
+            img.as_rgb16().unwrap().get_pixel(0, 0).0,
+            [1264, 30_257, 65_535]
+        );
+
+        // Formats without 16-bit support get 8 bits, PNG keeps all 16
+        for (format, color) in [
+            (Format::Png, ColorType::Rgb16),
+            (Format::Tiff, ColorType::Rgb16),
+            (Format::Jpeg, ColorType::Rgb8),
+            (Format::WebP, ColorType::Rgb8),
+            (Format::Bmp, ColorType::Rgb8),
+        ] {
+            let mut data = Cursor::new(Vec::new());
+            encode(&img, format, &mut data).unwrap();
+            let decoded = image::load_from_memory(data.get_ref()).unwrap();
+            assert_eq!(decoded.color(), color, "{}", format);
+        }
+
+        // 8-bit steps keep the 16-bit layout
+        let gray = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(2, 2, image::Luma([0])));
+        let inverted = apply_command(gray, &Command::Invert).unwrap();
+        assert_eq!(inverted.color(), ColorType::L16);
+        assert_eq!(encodable(&inverted, Format::Tiff).color(), ColorType::L16);
+        let gray_alpha = DynamicImage::new_luma_a16(1, 1);
+        assert_eq!(
+            encodable(&gray_alpha, Format::Tiff).color(),
+            ColorType::Rgba16
+        );
+
+        // Steps done in 8 bits keep the channels but not the depth they can't hold
+        for (input, color) in [
+            (DynamicImage::new_rgb16(2, 2), ColorType::Rgb8),
+            (DynamicImage::new_luma_a16(2, 2), ColorType::La8),
+            (DynamicImage::new_rgba32f(2, 2), ColorType::Rgba8),
+        ] {
+            let posterized = apply_command(input, &Command::Posterize { levels: 4 }).unwrap();
+            assert_eq!(posterized.color(), color);
+        }
+    }
+
+    #[test]
+    fn test_input_name() {
+        for (input, name) in [
+            ("photos/cat.jpg", "cat.jpg"),
+            ("-", "stdin"),
+            ("http://example.com/images/cat.jpg?w=800#top", "cat.jpg"),
+            ("HTTPS://example.com/cat.png", "cat.png"),
+            ("http://example.com", "download"),
+            ("http://example.com/images/", "download"),
+        ] {
+            assert_eq!(input_name(Path::new(input)).unwrap(), Path::new(name));
+        }
+        assert!(!is_url(Path::new("http.png")));
+
+        // Downloads are written to the current directory
+        let target = Target::resolve(
+            Path::new("http://example.com/cat.jpg"),
+            None,
+            &Command::Grayscale,
+            None,
+        )
+        .unwrap();
+        assert!(matches!(target, Target::File(path, _) if path == Path::new("cat.jpg")));
+    }
+
+    #[test]
+    fn test_process_bytes() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 2, image::Rgb([255, 0, 0])));
+        let mut png = Cursor::new(Vec::new());
+        encode(&img, Format::Png, &mut png).unwrap();
+        let png = png.into_inner();
+
+        // The input format is kept unless the command converts
+        let (data, format) =
+            process_bytes(&png, &Command::Invert, &ProcessOptions::default()).unwrap();
+        assert_eq!(format, Format::Png);
+        let inverted = image::load_from_memory(&data).unwrap().to_rgb8();
+        assert_eq!(inverted.get_pixel(0, 0).0, [0, 255, 255]);
+
+        let pipeline: Pipeline = "rotate -r 90 | convert -f bmp".parse().unwrap();
+        let command = Command::Pipeline { steps: pipeline };
+        let (data, format) = process_bytes(&png, &command, &ProcessOptions::default()).unwrap();
+        assert_eq!(format, Format::Bmp);
+        let rotated = image::load_from_memory_with_format(&data, ImageFormat::Bmp).unwrap();
+        assert_eq!(rotated.dimensions(), (2, 4));
+    }
+
+    #[test]
+    fn test_open_image_missing_file() {
+        assert!(matches!(
+            open_image(Path::new("does-not-exist.png")),
+            Err(ImgtoolsError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!(output_format(&Command::Grayscale), None);
+        assert_eq!(
+            output_format(&Command::Convert {
+                format: Format::Jpeg
+            }),
+            Some(Format::Jpeg)
+        );
+
+        let pipeline = "convert(png) | grayscale | convert(webp)"
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(
+            output_format(&Command::Pipeline { steps: pipeline }),
+            Some(Format::WebP)
+        );
+    }
+
+    #[test]
+    fn test_max_memory_processes_in_strips() {
+        let dir = std::env::temp_dir().join("imgtools-strips");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("scan.png");
+        RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+        })
+        .save(&input)
+        .unwrap();
+        let pipeline = Command::Pipeline {
+            steps: Pipeline(vec![
+                Command::Crop {
+                    crop: Crop::Center(Length::Pixels(40), Length::Pixels(40)),
+                    focus: None,
+                    clamp: false,
+                    pad: None,
+                },
+                Command::Resize {
+                    width: Some(20),
+                    height: Some(30),
+                    exact: false,
+                    filter: Filter::Triangle,
+                    scale: None,
+                    fast: false,
+                },
+            ]),
+        };
+        let streamed = dir.join("streamed.tiff");
+        let options = ProcessOptions {
+            max_memory: Some(1000),
+            ..Default::default()
+        };
+        process_file(&input, Some(&streamed), &pipeline, &options).unwrap();
+        let whole = dir.join("whole.tiff");
+        process_file(&input, Some(&whole), &pipeline, &ProcessOptions::default()).unwrap();
+        let blur = Command::Blur {
+            sigma: 1.0,
+            fast: false,
+        };
+        let error = process_file(&input, Some(&whole), &blur, &options).unwrap_err();
+        let (streamed, whole) = (open_image(&streamed), open_image(&whole));
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(streamed.unwrap().to_rgb8(), whole.unwrap().to_rgb8());
+        assert!(error.to_string().contains("more than --max-memory 1000"));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{ImgtoolsError, Profile};
+use image::{DynamicImage, ImageBuffer};
+use moxcms::{CmsError, ColorProfile, DataColorSpace, Layout, TransformOptions};
+use std::fs;
+
+/// ICC data of a profile, the well-known color spaces are encoded on the fly
+pub fn profile_data(profile: &Profile) -> Result<Vec<u8>, ImgtoolsError> {
+    let profile = match profile {
+        Profile::File(path) => {
+            return fs::read(path).map_err(|source| ImgtoolsError::Read {
+                path: path.clone(),
+                source,
+            });
+        }
+        Profile::Srgb => ColorProfile::new_srgb(),
+        Profile::DisplayP3 => ColorProfile::new_display_p3(),
+        Profile::AdobeRgb => ColorProfile::new_adobe_rgb(),
+    };
+    profile.encode().map_err(cms_error)
+}
+
+/// Convert the pixels of an image from one ICC profile to another
+///
+/// Images turn gray only for a gray target profile and RGB otherwise. 16-bit
+/// images keep their depth, everything else is converted at 8 bits.
+pub fn convert_profile(
+    img: &DynamicImage,
+    from: &[u8],
+    to: &[u8],
+) -> Result<DynamicImage, ImgtoolsError> {
+    let source = parse(from)?;
+    let target = parse(to)?;
+    let alpha = img.color().has_alpha();
+    let src_layout = layout(&source, alpha)?;
+    let dst_layout = layout(&target, alpha)?;
+    let (width, height) = (img.width(), img.height());
+    let options = TransformOptions::default();
+
+    let sixteen_bit = img.color().bytes_per_pixel() > img.color().channel_count();
+    if sixteen_bit {
+        let transform = source
+            .create_transform_16bit(src_layout, &target, dst_layout, options)
+            .map_err(cms_error)?;
+        let src = match src_layout {
+            Layout::Gray => img.to_luma16().into_raw(),
+            Layout::GrayAlpha => img.to_luma_alpha16().into_raw(),
+            Layout::Rgb => img.to_rgb16().into_raw(),
+            _ => img.to_rgba16().into_raw(),
+        };
+        let mut dst = vec![0; (width * height) as usize * dst_layout.channels()];
+        transform.transform(&src, &mut dst).map_err(cms_error)?;
+        Ok(match dst_layout {
+            Layout::Gray => {
+                ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageLuma16)
+            }
+            Layout::GrayAlpha => {
+                ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageLumaA16)
+            }
+            Layout::Rgb => ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageRgb16),
+            _ => ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageRgba16),
+        }
+        .expect("buffer matches the image size"))
+    } else {
+        let transform = source
+            .create_transform_8bit(src_layout, &target, dst_layout, options)
+            .map_err(cms_error)?;
+        let src = match src_layout {
+            Layout::Gray => img.to_luma8().into_raw(),
+            Layout::GrayAlpha => img.to_luma_alpha8().into_raw(),
+            Layout::Rgb => img.to_rgb8().into_raw(),
+            _ => img.to_rgba8().into_raw(),
+        };
+        let mut dst = vec![0; (width * height) as usize * dst_layout.channels()];
+        transform.transform(&src, &mut dst).map_err(cms_error)?;
+        Ok(match dst_layout {
+            Layout::Gray => ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageLuma8),
+            Layout::GrayAlpha => {
+                ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageLumaA8)
+            }
+            Layout::Rgb => ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageRgb8),
+            _ => ImageBuffer::from_raw(width, height, dst).map(DynamicImage::ImageRgba8),
+        }
+        .expect("buffer matches the image size"))
+    }
+}
+
+fn parse(data: &[u8]) -> Result<ColorProfile, ImgtoolsError> {
+    ColorProfile::new_from_slice(data).map_err(cms_error)
+}
+
+/// Pixel layout the profile describes, only RGB and gray profiles are handled
+fn layout(profile: &ColorProfile, alpha: bool) -> Result<Layout, ImgtoolsError> {
+    match (profile.color_space, alpha) {
+        (DataColorSpace::Rgb, false) => Ok(Layout::Rgb),
+        (DataColorSpace::Rgb, true) => Ok(Layout::Rgba),
+        (DataColorSpace::Gray, false) => Ok(Layout::Gray),
+        (DataColorSpace::Gray, true) => Ok(Layout::GrayAlpha),
+        (space, _) => Err(ImgtoolsError::Profile(format!(
+            "{:?} profiles are not supported, only RGB and gray",
+            space
+        ))),
+    }
+}
+
+fn cms_error(error: CmsError) -> ImgtoolsError {
+    ImgtoolsError::Profile(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    #[test]
+    fn test_convert_profile() {
+        let srgb = profile_data(&Profile::Srgb).unwrap();
+        let p3 = profile_data(&Profile::DisplayP3).unwrap();
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([255, 0, 0])));
+
+        // Pure sRGB red sits inside the wider P3 gamut
+        let converted = convert_profile(&red, &srgb, &p3).unwrap();
+        let [r, g, b, _] = converted.get_pixel(0, 0).0;
+        assert!(r < 250 && g > 20 && b > 10, "{:?}", (r, g, b));
+
+        let back = convert_profile(&converted, &p3, &srgb).unwrap();
+        let [r, g, b, _] = back.get_pixel(1, 1).0;
+        assert!(r >= 252 && g <= 3 && b <= 3, "{:?}", (r, g, b));
+
+        // 16-bit images keep their depth
+        let deep = DynamicImage::ImageRgb16(red.to_rgb16());
+        let converted = convert_profile(&deep, &srgb, &p3).unwrap();
+        assert_eq!(converted.color(), image::ColorType::Rgb16);
+
+        assert!(matches!(
+            convert_profile(&red, b"not a profile", &p3),
+            Err(ImgtoolsError::Profile(_))
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::process::with_color_type;
+use crate::{Format, PyramidLayout};
+use image::imageops::{self, FilterType};
+use image::{ColorType, DynamicImage, Rgba, RgbaImage};
+use serde_json::json;
+
+/// Every level of a tile pyramid, from the smallest to the full image
+///
+/// Each level is half the size of the next one, rounded up. Deep Zoom goes
+/// down to a single pixel, XYZ to the first level that fits in one tile.
+pub fn pyramid_levels(
+    img: &DynamicImage,
+    layout: PyramidLayout,
+    tile_size: u32,
+) -> Vec<DynamicImage> {
+    let smallest = match layout {
+        PyramidLayout::Dzi => 1,
+        PyramidLayout::Xyz => tile_size.max(1),
+    };
+    let mut levels = vec![img.clone()];
+    while let Some(level) = levels
+        .last()
+        .filter(|l| l.width().max(l.height()) > smallest)
+    {
+        let (w, h) = (level.width().div_ceil(2), level.height().div_ceil(2));
+        let half = level.resize_exact(w, h, FilterType::Triangle);
+        levels.push(half);
+    }
+    levels.reverse();
+    levels
+}
+
+/// Cut one level into tiles, returned row by row with their column and row
+///
+/// Tiles reach `overlap` pixels into their neighbors. With a background the
+/// tiles along the right and bottom edges are filled up to the full tile
+/// size, as map viewers stretch every tile to it.
+pub fn pyramid_tiles(
+    level: &DynamicImage,
+    tile_size: u32,
+    overlap: u32,
+    background: Option<Rgba<u8>>,
+) -> Vec<(u32, u32, DynamicImage)> {
+    let (w, h) = (level.width(), level.height());
+    let mut tiles = Vec::new();
+    for row in 0..h.div_ceil(tile_size) {
+        for column in 0..w.div_ceil(tile_size) {
+            let x = (column * tile_size).saturating_sub(overlap);
+            let y = (row * tile_size).saturating_sub(overlap);
+            let right = ((column + 1) * tile_size + overlap).min(w);
+            let bottom = ((row + 1) * tile_size + overlap).min(h);
+            let tile = level.crop_imm(x, y, right - x, bottom - y);
+            let tile = match background {
+                Some(color) if tile.width() < tile_size || tile.height() < tile_size => {
+                    let mut full = RgbaImage::from_pixel(tile_size, tile_size, color);
+                    imageops::replace(&mut full, &tile.to_rgba8(), 0, 0);
+                    match color[3] {
+                        255 => with_color_type(full, level.color()),
+                        _ => with_color_type(full, ColorType::Rgba8),
+                    }
+                }
+                _ => tile,
+            };
+            tiles.push((column, row, tile));
+        }
+    }
+    tiles
+}
+
+/// The .dzi descriptor OpenSeadragon opens, its tiles in `<name>_files`
+pub fn dzi_descriptor(
+    (width, height): (u32, u32),
+    tile_size: u32,
+    overlap: u32,
+    format: Format,
+) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n  \
+         <Size Width=\"{}\" Height=\"{}\"/>\n\
+         </Image>\n",
+        format, overlap, tile_size, width, height
+    )
+}
+
+/// A JSON descriptor of XYZ tiles with what a map viewer needs to show them
+pub fn xyz_descriptor(
+    (width, height): (u32, u32),
+    tile_size: u32,
+    max_zoom: usize,
+    format: Format,
+) -> String {
+    let descriptor = json!({
+        "width": width,
+        "height": height,
+        "tileSize": tile_size,
+        "minZoom": 0,
+        "maxZoom": max_zoom,
+        "format": format.to_string(),
+    });
+    format!("{}\n", descriptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_pyramid_levels() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(600, 300));
+        let sizes = |layout, tile_size| -> Vec<(u32, u32)> {
+            pyramid_levels(&img, layout, tile_size)
+                .iter()
+                .map(|l| (l.width(), l.height()))
+                .collect()
+        };
+        // Deep Zoom halves down to one pixel, ceil(log2(600)) + 1 levels
+        let dzi = sizes(PyramidLayout::Dzi, 256);
+        assert_eq!(dzi.len(), 11);
+        assert_eq!(dzi[..3], [(1, 1), (2, 1), (3, 2)]);
+        assert_eq!(dzi[9..], [(300, 150), (600, 300)]);
+        // XYZ stops once a level fits in a tile
+        let xyz = sizes(PyramidLayout::Xyz, 256);
+        assert_eq!(xyz, [(150, 75), (300, 150), (600, 300)]);
+    }
+
+    #[test]
+    fn test_pyramid_tiles() {
+        let level = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 6, Rgb([9, 9, 9])));
+        let tiles = pyramid_tiles(&level, 4, 1, None);
+        let sizes: Vec<_> = tiles
+            .iter()
+            .map(|(c, r, t)| (*c, *r, t.width(), t.height()))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (0, 0, 5, 5),
+                (1, 0, 6, 5),
+                (2, 0, 3, 5),
+                (0, 1, 5, 3),
+                (1, 1, 6, 3),
+                (2, 1, 3, 3)
+            ]
+        );
+
+        // Edge tiles are filled up to the full size
+        let tiles = pyramid_tiles(&level, 8, 0, Some(Rgba([255, 255, 255, 255])));
+        assert_eq!(tiles.len(), 2);
+        let (_, _, edge) = &tiles[1];
+        assert_eq!((edge.width(), edge.height()), (8, 8));
+        assert_eq!(edge.color(), ColorType::Rgb8);
+        assert_eq!(edge.to_rgb8().get_pixel(1, 1).0, [9, 9, 9]);
+        assert_eq!(edge.to_rgb8().get_pixel(3, 7).0, [255, 255, 255]);
+
+        let dzi = dzi_descriptor((10, 6), 254, 1, Format::Jpeg);
+        assert!(dzi.contains("Format=\"jpeg\" Overlap=\"1\" TileSize=\"254\""));
+        assert!(dzi.contains("<Size Width=\"10\" Height=\"6\"/>"));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::{Dither, PaletteMethod, ReportFormat};
+use image::{Rgba, RgbaImage};
+use serde_json::json;
+
+/// 4x4 Bayer matrix for ordered dithering
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Colors an image is reduced to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette(pub Vec<[u8; 3]>);
+
+impl Palette {
+    /// Build a palette of at most `colors` colors with median cut
+    ///
+    /// The color box with the widest channel range is split at its median
+    /// until there are enough boxes, each box contributes its mean color.
+    pub fn median_cut(img: &RgbaImage, colors: usize) -> Self {
+        let pixels: Vec<[u8; 3]> = img.pixels().map(|p| [p[0], p[1], p[2]]).collect();
+        Palette::median_cut_pixels(pixels, colors)
+    }
+
+    /// Refine a median cut palette with k-means clustering
+    ///
+    /// Each color moves to the mean of the pixels closest to it until the
+    /// palette stops changing, at most 20 rounds.
+    pub fn kmeans(img: &RgbaImage, colors: usize) -> Self {
+        let pixels: Vec<[u8; 3]> = img.pixels().map(|p| [p[0], p[1], p[2]]).collect();
+        Palette::kmeans_pixels(&pixels, colors)
+    }
+
+    fn kmeans_pixels(pixels: &[[u8; 3]], colors: usize) -> Self {
+        let mut palette = Palette::median_cut_pixels(pixels.to_vec(), colors);
+        for _ in 0..20 {
+            let mut sums = vec![[0u64; 4]; palette.0.len()];
+            for p in pixels {
+                let sum = &mut sums[palette.nearest_index([p[0] as i32, p[1] as i32, p[2] as i32])];
+                for c in 0..3 {
+                    sum[c] += p[c] as u64;
+                }
+                sum[3] += 1;
+            }
+
+            let next: Vec<[u8; 3]> = sums
+                .iter()
+                .zip(&palette.0)
+                .map(|(sum, &color)| match sum[3] {
+                    0 => color,
+                    n => [0, 1, 2].map(|c| ((sum[c] + n / 2) / n) as u8),
+                })
+                .collect();
+            if next == palette.0 {
+                break;
+            }
+            palette = Palette(next);
+        }
+        palette
+    }
+
+    fn median_cut_pixels(pixels: Vec<[u8; 3]>, colors: usize) -> Self {
+        if pixels.is_empty() || colors == 0 {
+            return Palette(Vec::new());
+        }
+
+        let mut boxes = vec![ColorBox::new(pixels)];
+        while boxes.len() < colors {
+            let widest = boxes
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, b)| b.range)
+                .map(|(i, b)| (i, b.range));
+
+            match widest {
+                Some((i, range)) if range > 0 => {
+                    let ColorBox {
+                        mut colors,
+                        channel,
+                        ..
+                    } = boxes.swap_remove(i);
+                    colors.sort_unstable_by_key(|p| p[channel]);
+                    let upper = colors.split_off(colors.len() / 2);
+                    boxes.push(ColorBox::new(colors));
+                    boxes.push(ColorBox::new(upper));
+                }
+                // Every box holds a single color
+                _ => break,
+            }
+        }
+
+        let palette = boxes
+            .iter()
+            .map(|b| {
+                let sum = b.colors.iter().fold([0u64; 3], |mut sum, p| {
+                    for c in 0..3 {
+                        sum[c] += p[c] as u64;
+                    }
+                    sum
+                });
+                let n = b.colors.len() as u64;
+                [0, 1, 2].map(|c| ((sum[c] + n / 2) / n) as u8)
+            })
+            .collect();
+        Palette(palette)
+    }
+
+    /// Closest palette color by squared RGB distance
+    pub fn nearest(&self, rgb: [i32; 3]) -> [u8; 3] {
+        self.0
+            .get(self.nearest_index(rgb))
+            .copied()
+            .unwrap_or([0; 3])
+    }
+
+    fn nearest_index(&self, rgb: [i32; 3]) -> usize {
+        (0..self.0.len())
+            .min_by_key(|&i| {
+                (0..3)
+                    .map(|c| (self.0[i][c] as i32 - rgb[c]).pow(2))
+                    .sum::<i32>()
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// The most common colors of an image and their share of the pixels, most common first
+///
+/// Transparent pixels are ignored, large images are sampled down to about
+/// 65536 pixels.
+pub fn dominant_colors(
+    img: &RgbaImage,
+    colors: usize,
+    method: PaletteMethod,
+) -> Vec<([u8; 3], f64)> {
+    let step = (img.pixels().len() / 65536).max(1);
+    let pixels: Vec<[u8; 3]> = img
+        .pixels()
+        .step_by(step)
+        .filter(|p| p[3] > 0)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+    let palette = match method {
+        PaletteMethod::KMeans => Palette::kmeans_pixels(&pixels, colors),
+        PaletteMethod::MedianCut => Palette::median_cut_pixels(pixels.clone(), colors),
+    };
+
+    let mut counts = vec![0usize; palette.0.len()];
+    for p in &pixels {
+        counts[palette.nearest_index([p[0] as i32, p[1] as i32, p[2] as i32])] += 1;
+    }
+    let total = pixels.len().max(1) as f64;
+    let mut dominant: Vec<_> = palette
+        .0
+        .into_iter()
+        .zip(counts)
+        .filter(|&(_, count)| count > 0)
+        .map(|(color, count)| (color, count as f64 / total))
+        .collect();
+    dominant.sort_by(|a, b| b.1.total_cmp(&a.1));
+    dominant
+}
+
+/// Format colors as hex codes with their share, as text lines or JSON
+pub fn palette_report(colors: &[([u8; 3], f64)], format: ReportFormat) -> String {
+    let hex = |[r, g, b]: [u8; 3]| format!("#{:02x}{:02x}{:02x}", r, g, b);
+    match format {
+        ReportFormat::Text => colors
+            .iter()
+            .map(|&(color, share)| format!("{} {:5.1}%", hex(color), share * 100.0))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        ReportFormat::Json => {
+            let colors: Vec<_> = colors
+                .iter()
+                .map(|&(color, share)| json!({ "color": hex(color), "share": share }))
+                .collect();
+            json!({ "palette": colors }).to_string()
+        }
+    }
+}
+
+/// Draw the colors as a strip of equally wide swatches
+pub fn render_swatches(colors: &[([u8; 3], f64)], width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, _| {
+        let i = x as usize * colors.len() / width.max(1) as usize;
+        match colors.get(i) {
+            Some(&([r, g, b], _)) => Rgba([r, g, b, 255]),
+            None => Rgba([0, 0, 0, 0]),
+        }
+    })
+}
+
+/// Colors of one median cut box and its widest channel
+struct ColorBox {
+    colors: Vec<[u8; 3]>,
+    channel: usize,
+    range: u8,
+}
+
+impl ColorBox {
+    fn new(colors: Vec<[u8; 3]>) -> Self {
+        let (channel, range) = (0..3)
+            .map(|c| {
+                let min = colors.iter().map(|p| p[c]).min().unwrap_or(0);
+                let max = colors.iter().map(|p| p[c]).max().unwrap_or(0);
+                (c, max - min)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap_or((0, 0));
+        ColorBox {
+            colors,
+            channel,
+            range,
+        }
+    }
+}
+
+/// Map every pixel to the palette, alpha is kept
+pub fn quantize(img: &mut RgbaImage, palette: &Palette, dither: Dither) {
+    let (width, height) = img.dimensions();
+    match dither {
+        Dither::None => {
+            for pixel in img.pixels_mut() {
+                let rgb = palette.nearest([pixel[0] as i32, pixel[1] as i32, pixel[2] as i32]);
+                pixel.0[..3].copy_from_slice(&rgb);
+            }
+        }
+        // Offset each pixel by a threshold pattern before mapping
+        Dither::Ordered => {
+            let spread = 255.0 / (palette.0.len() as f32).cbrt().max(1.0);
+            for (x, y, pixel) in img.enumerate_pixels_mut() {
+                let threshold = BAYER[y as usize % 4][x as usize % 4] as f32;
+                let offset = ((threshold + 0.5) / 16.0 - 0.5) * spread;
+                let rgb = [0, 1, 2].map(|c| (pixel[c] as f32 + offset).round() as i32);
+                pixel.0[..3].copy_from_slice(&palette.nearest(rgb));
+            }
+        }
+        // Spread the mapping error to the unvisited neighbours
+        Dither::FloydSteinberg => {
+            let (w, h) = (width as usize, height as usize);
+            let mut errors = vec![[0i32; 3]; w * h];
+            for y in 0..h {
+                for x in 0..w {
+                    let pixel = img.get_pixel_mut(x as u32, y as u32);
+                    let error = errors[y * w + x];
+                    let rgb = [0, 1, 2].map(|c| (pixel[c] as i32 + error[c] / 16).clamp(0, 255));
+                    let mapped = palette.nearest(rgb);
+                    pixel.0[..3].copy_from_slice(&mapped);
+
+                    let diff = [0, 1, 2].map(|c| rgb[c] - mapped[c] as i32);
+                    let neighbours = [(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)];
+                    for (dx, dy, weight) in neighbours {
+                        let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                        if nx < 0 || nx >= w as i64 || ny >= h as i64 {
+                            continue;
+                        }
+                        let error = &mut errors[ny as usize * w + nx as usize];
+                        for c in 0..3 {
+                            error[c] += diff[c] * weight;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::collections::HashSet;
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(64, 16, |x, y| Rgba([x as u8 * 4, y as u8 * 16, 128, 255]))
+    }
+
+    fn color_count(img: &RgbaImage) -> usize {
+        img.pixels().map(|p| p.0).collect::<HashSet<_>>().len()
+    }
+
+    #[test]
+    fn test_median_cut() {
+        let palette = Palette::median_cut(&gradient(), 8);
+        assert_eq!(palette.0.len(), 8);
+
+        // Never more colors than the image has
+        let flat = RgbaImage::from_pixel(4, 4, Rgba([1, 2, 3, 255]));
+        assert_eq!(Palette::median_cut(&flat, 16), Palette(vec![[1, 2, 3]]));
+    }
+
+    #[test]
+    fn test_kmeans() {
+        // Two clusters of slightly different colors
+        let img = RgbaImage::from_fn(10, 10, |x, y| match x < 7 {
+            true => Rgba([200 + (y % 3) as u8, 10, 10, 255]),
+            false => Rgba([10, 10, 200 + (y % 3) as u8, 255]),
+        });
+        let mut palette = Palette::kmeans(&img, 2).0;
+        palette.sort();
+        assert_eq!(palette, vec![[10, 10, 201], [201, 10, 10]]);
+    }
+
+    #[test]
+    fn test_dominant_colors() {
+        let img = RgbaImage::from_fn(10, 10, |x, _| match x {
+            0..6 => Rgba([255, 0, 0, 255]),
+            6..9 => Rgba([0, 0, 255, 255]),
+            _ => Rgba([0, 255, 0, 0]),
+        });
+        for method in [PaletteMethod::KMeans, PaletteMethod::MedianCut] {
+            let colors = dominant_colors(&img, 4, method);
+            assert_eq!(colors.len(), 2, "{:?}", method);
+            assert_eq!(colors[0].0, [255, 0, 0]);
+            assert!((colors[0].1 - 60.0 / 90.0).abs() < 1e-9);
+        }
+
+        let colors = dominant_colors(&img, 2, PaletteMethod::KMeans);
+        assert_eq!(
+            palette_report(&colors, ReportFormat::Text),
+            "#ff0000  66.7%\n#0000ff  33.3%"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&palette_report(&colors, ReportFormat::Json)).unwrap();
+        assert_eq!(json["palette"][1]["color"], "#0000ff");
+
+        let swatches = render_swatches(&colors, 10, 2);
+        assert_eq!(swatches.get_pixel(4, 1), &Rgba([255, 0, 0, 255]));
+        assert_eq!(swatches.get_pixel(5, 0), &Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn test_quantize_dithering() {
+        for dither in [Dither::None, Dither::Ordered, Dither::FloydSteinberg] {
+            let mut img = gradient();
+            let palette = Palette::median_cut(&img, 4);
+            quantize(&mut img, &palette, dither);
+            assert!(color_count(&img) <= 4, "{:?}", dither);
+            assert!(
+                img.pixels()
+                    .all(|p| palette.0.contains(&[p[0], p[1], p[2]]))
+            );
+        }
+    }
+
+    #[test]
+    fn test_floyd_steinberg_keeps_average() {
+        // Mid gray with a black and white palette becomes about half white
+        let mut img = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+        let palette = Palette(vec![[0; 3], [255; 3]]);
+        quantize(&mut img, &palette, Dither::FloydSteinberg);
+        let white = img.pixels().filter(|p| p[0] == 255).count();
+        assert!((120..=136).contains(&white), "{}", white);
+    }
+}
@@ -0,0 +1,2131 @@
+use crate::tags::{Entry, Order, read_ifd};
+use crate::{DecodeOptions, ImgtoolsError, WhiteBalance};
+use image::error::{DecodingError, ImageFormatHint};
+use image::{ImageBuffer, ImageError, Rgb};
+use rayon::prelude::*;
+
+/// Most samples of a RAW image decoded, larger sizes come from a damaged file
+const MAX_SAMPLES: usize = 1 << 28;
+/// Most directories followed, against loops of offsets
+const MAX_DIRECTORIES: usize = 64;
+/// Samples at or above this fraction of the white level count as clipped
+const CLIPPED: f32 = 0.98;
+
+/// XYZ of the D65 white point, of daylight
+const D65: [f32; 3] = [0.950_456, 1.0, 1.088_754];
+/// Linear sRGB to XYZ under D65
+const XYZ_RGB: [[f32; 3]; 3] = [
+    [0.412_453, 0.357_580, 0.180_423],
+    [0.212_671, 0.715_160, 0.072_169],
+    [0.019_334, 0.119_193, 0.950_227],
+];
+
+// TIFF and DNG tags
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const SUB_IFDS: u16 = 330;
+const CFA_REPEAT_PATTERN_DIM: u16 = 33421;
+const CFA_PATTERN: u16 = 33422;
+const DNG_VERSION: u16 = 50706;
+const CFA_PLANE_COLOR: u16 = 50710;
+const LINEARIZATION_TABLE: u16 = 50712;
+const BLACK_LEVEL_REPEAT_DIM: u16 = 50713;
+const BLACK_LEVEL: u16 = 50714;
+const BLACK_LEVEL_DELTA_H: u16 = 50715;
+const BLACK_LEVEL_DELTA_V: u16 = 50716;
+const WHITE_LEVEL: u16 = 50717;
+const DEFAULT_CROP_ORIGIN: u16 = 50719;
+const DEFAULT_CROP_SIZE: u16 = 50720;
+const COLOR_MATRIX_1: u16 = 50721;
+const COLOR_MATRIX_2: u16 = 50722;
+const AS_SHOT_NEUTRAL: u16 = 50728;
+const BASELINE_EXPOSURE: u16 = 50730;
+const CALIBRATION_ILLUMINANT_1: u16 = 50778;
+const CALIBRATION_ILLUMINANT_2: u16 = 50779;
+const ACTIVE_AREA: u16 = 50829;
+const EXIF_IFD: u16 = 34665;
+const MAKER_NOTE: u16 = 37500;
+/// Slices of the lossless JPEG data of a CR2
+const CR2_SLICES: u16 = 50752;
+
+// Maker note tags
+const CANON_SENSOR_INFO: u16 = 0x00e0;
+const NIKON_WB_LEVELS: u16 = 0x000c;
+const NIKON_BLACK_LEVEL: u16 = 0x003d;
+const NIKON_DECOMPRESSION: u16 = 0x0096;
+
+/// Compression of Canon's CR2 sensor data, lossless JPEG under the tag of old-style JPEG
+const CANON_JPEG: usize = 6;
+const LOSSLESS_JPEG: usize = 7;
+/// Compression of Nikon's NEF sensor data, Huffman coded differences
+const NIKON_HUFFMAN: usize = 34713;
+
+/// Huffman trees of compressed NEF data, the number of codes of every length
+/// then the values: 12-bit lossy, after the split, 12-bit lossless, then the
+/// same for 14 bits
+const NIKON_TREES: [[u8; 32]; 6] = [
+    [
+        0, 1, 5, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 0, 5, 4, 3, 6, 2, 7, 1, 0, 8, 9, 11, 10, 12,
+        0, 0, 0,
+    ],
+    [
+        0, 1, 5, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 0, 0x39, 0x5a, 0x38, 0x27, 0x16, 5, 4, 3, 2,
+        1, 0, 11, 12, 12, 0, 0,
+    ],
+    [
+        0, 1, 4, 2, 3, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 4, 6, 3, 7, 2, 8, 1, 9, 0, 10, 11, 12,
+        0, 0, 0,
+    ],
+    [
+        0, 1, 4, 3, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 0, 5, 6, 4, 7, 8, 3, 9, 2, 1, 0, 10, 11, 12,
+        13, 14, 0,
+    ],
+    [
+        0, 1, 5, 1, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 8, 0x5c, 0x4b, 0x3a, 0x29, 7, 6, 5, 4, 3,
+        2, 1, 0, 13, 14, 0,
+    ],
+    [
+        0, 1, 4, 2, 2, 3, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 7, 6, 8, 5, 9, 4, 10, 3, 11, 12, 2, 0, 1,
+        13, 14, 0,
+    ],
+];
+
+/// Photometric interpretation of color filter array data, one color per pixel
+const CFA: u32 = 32803;
+/// Photometric interpretation of demosaiced camera colors
+const LINEAR_RAW: u32 = 34892;
+/// Illuminants of calibration matrices that are daylight or close to it
+const DAYLIGHT_ILLUMINANTS: [u32; 6] = [1, 4, 20, 21, 22, 23];
+
+/// Check for a camera RAW file this decoder reads: a DNG, a CR2, or a
+/// TIFF-based RAW file like a NEF whose sensor data is stored as a color
+/// filter array
+pub fn is_raw(data: &[u8]) -> bool {
+    Tiff::new(data).is_some_and(|tiff| {
+        tiff.directories
+            .first()
+            .is_some_and(|ifd| tiff.value(ifd, DNG_VERSION).is_some())
+            || tiff.raw_directory().is_some()
+    })
+}
+
+/// Decode the sensor data of a camera RAW file into 16-bit sRGB
+///
+/// The data is linearized, has its black level subtracted and is white
+/// balanced before the missing colors of every pixel are interpolated.
+/// Camera colors go to sRGB with the color matrix of a DNG, and the exposure
+/// of `options` adds stops to the file's own baseline exposure.
+pub fn decode(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImgtoolsError> {
+    let DecodeOptions {
+        white_balance,
+        exposure,
+        ..
+    } = *options;
+    let tiff = Tiff::new(data).ok_or_else(|| invalid("not a TIFF-based RAW file".into()))?;
+    let ifd = tiff.raw_directory().ok_or_else(|| {
+        ImgtoolsError::InvalidArgument(
+            "The RAW file has no color filter array data, convert it to DNG first".into(),
+        )
+    })?;
+    let main = &tiff.directories[0];
+    let dimension = |tag| tiff.value(ifd, tag).unwrap_or(0.0) as u64;
+    options.check_alloc(dimension(IMAGE_WIDTH) * dimension(IMAGE_LENGTH) * 6)?;
+    let raw = tiff.samples(ifd).map_err(invalid)?;
+    // A CR2 only tells its size in its sensor data
+    options.check_alloc(raw.data.len() as u64 * 6)?;
+    let photometric = tiff.value(ifd, PHOTOMETRIC).unwrap_or(0.0) as u32;
+    let mut maker = MakerLevels::read(&tiff, &raw);
+    let mut sensor = Sensor::new(&tiff, ifd, raw, &maker).map_err(invalid)?;
+
+    let cfa = match photometric {
+        CFA => Some(Pattern::read(&tiff, ifd).map_err(invalid)?),
+        _ if maker.pattern.is_some() => maker.pattern.take(),
+        _ if sensor.channels == 3 => None,
+        _ => {
+            return Err(invalid(format!(
+                "linear RAW data with {} channels is not supported",
+                sensor.channels
+            )));
+        }
+    };
+    let cam_xyz = color_matrix(&tiff, main);
+    let gains = match white_balance {
+        WhiteBalance::Camera => camera_gains(&tiff, main).or(maker.gains),
+        WhiteBalance::Daylight => cam_xyz.map(|m| neutral_gains(multiply(m, D65))),
+        WhiteBalance::Auto => None,
+    }
+    .or_else(|| match white_balance {
+        WhiteBalance::Daylight => camera_gains(&tiff, main).or(maker.gains),
+        _ => None,
+    })
+    .unwrap_or_else(|| sensor.gray_world(cfa.as_ref()));
+    sensor.scale(&gains, cfa.as_ref());
+
+    let mut rgb = match &cfa {
+        Some(pattern) if pattern.is_bayer() => demosaic_bayer(&sensor, pattern),
+        Some(pattern) => demosaic_average(&sensor, pattern),
+        None => sensor.values.clone(),
+    };
+
+    // Camera colors to sRGB, with rows adding up to one so white stays white
+    let rgb_cam = cam_xyz.and_then(|cam_xyz| {
+        let mut cam_rgb = [[0.0; 3]; 3];
+        for (row, xyz) in cam_rgb.iter_mut().zip(cam_xyz) {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| xyz[k] * XYZ_RGB[k][j]).sum();
+            }
+            let sum: f32 = row.iter().sum();
+            if sum.abs() < 1e-6 {
+                return None;
+            }
+            row.iter_mut().for_each(|v| *v /= sum);
+        }
+        invert(cam_rgb)
+    });
+    let stops = tiff.value(main, BASELINE_EXPOSURE).unwrap_or(0.0) as f32 + exposure;
+    let scale = stops.exp2();
+    rgb.par_chunks_mut(3).for_each(|pixel| {
+        let cam = [pixel[0], pixel[1], pixel[2]];
+        let color = rgb_cam.map_or(cam, |m| multiply(m, cam));
+        for (value, c) in pixel.iter_mut().zip(color) {
+            *value = c * scale;
+        }
+    });
+
+    // The default crop leaves out the edges the camera doesn't want shown
+    let (width, height) = (sensor.width, sensor.height);
+    let crop_origin = tiff.values(ifd, DEFAULT_CROP_ORIGIN).unwrap_or_default();
+    let crop_size = tiff.values(ifd, DEFAULT_CROP_SIZE).unwrap_or_default();
+    let (x0, y0, w, h) = match (&crop_origin[..], &crop_size[..]) {
+        (&[x, y], &[w, h]) if x >= 0.0 && y >= 0.0 && w >= 1.0 && h >= 1.0 => {
+            let (x, y) = ((x as usize).min(width - 1), (y as usize).min(height - 1));
+            (
+                x,
+                y,
+                (w as usize).min(width - x),
+                (h as usize).min(height - y),
+            )
+        }
+        _ => (0, 0, width, height),
+    };
+    let mut pixels = vec![0u16; w * h * 3];
+    pixels
+        .par_chunks_mut(w * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let start = ((y0 + y) * width + x0) * 3;
+            for (out, &value) in row.iter_mut().zip(&rgb[start..start + w * 3]) {
+                *out = (encode_srgb(value.clamp(0.0, 1.0)) * 65535.0).round() as u16;
+            }
+        });
+    ImageBuffer::from_raw(w as u32, h as u32, pixels)
+        .ok_or_else(|| invalid("the image has no pixels".into()))
+}
+
+/// Error for RAW data that can't be read
+fn invalid(message: String) -> ImgtoolsError {
+    ImgtoolsError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("RAW".into()),
+        message,
+    )))
+}
+
+/// sRGB transfer curve of a linear value in 0.0 ~ 1.0
+fn encode_srgb(linear: f32) -> f32 {
+    match linear <= 0.003_130_8 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+fn multiply(m: [[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let [[a, b, c], [d, e, f], [g, h, i]] = m;
+    let det = a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g);
+    if det.abs() < 1e-9 || !det.is_finite() {
+        return None;
+    }
+    Some([
+        [
+            (e * i - f * h) / det,
+            (c * h - b * i) / det,
+            (b * f - c * e) / det,
+        ],
+        [
+            (f * g - d * i) / det,
+            (a * i - c * g) / det,
+            (c * d - a * f) / det,
+        ],
+        [
+            (d * h - e * g) / det,
+            (b * g - a * h) / det,
+            (a * e - b * d) / det,
+        ],
+    ])
+}
+
+/// Gains of the camera channels that make a neutral of the camera gray,
+/// the smallest one being 1 so no channel is darkened
+fn neutral_gains(neutral: [f32; 3]) -> [f32; 3] {
+    let gains = neutral.map(|n| if n > 1e-6 { 1.0 / n } else { 1.0 });
+    let min = gains.iter().copied().fold(f32::INFINITY, f32::min);
+    gains.map(|g| g / min)
+}
+
+/// White balance the camera picked, from the neutral it recorded
+fn camera_gains(tiff: &Tiff, main: &[Entry]) -> Option<[f32; 3]> {
+    match tiff.values(main, AS_SHOT_NEUTRAL)?[..] {
+        [r, g, b] => Some(neutral_gains([r as f32, g as f32, b as f32])),
+        _ => None,
+    }
+}
+
+/// Matrix from XYZ to camera colors, the one calibrated for daylight if there are two
+fn color_matrix(tiff: &Tiff, main: &[Entry]) -> Option<[[f32; 3]; 3]> {
+    let read = |tag| -> Option<[[f32; 3]; 3]> {
+        let values = tiff.values(main, tag)?;
+        (values.len() == 9).then(|| {
+            let v = |i: usize| values[i] as f32;
+            [[v(0), v(1), v(2)], [v(3), v(4), v(5)], [v(6), v(7), v(8)]]
+        })
+    };
+    let daylight = |tag| {
+        tiff.value(main, tag)
+            .is_some_and(|illuminant| DAYLIGHT_ILLUMINANTS.contains(&(illuminant as u32)))
+    };
+    match (read(COLOR_MATRIX_1), read(COLOR_MATRIX_2)) {
+        (Some(first), Some(_))
+            if daylight(CALIBRATION_ILLUMINANT_1) && !daylight(CALIBRATION_ILLUMINANT_2) =>
+        {
+            Some(first)
+        }
+        (_, Some(second)) => Some(second),
+        (first, None) => first,
+    }
+}
+
+/// A TIFF file with the offsets of its directories, sub directories included
+struct Tiff<'a> {
+    data: &'a [u8],
+    order: Order,
+    /// Entries of every directory, the first one first
+    directories: Vec<Vec<Entry>>,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let order = Order::of(data)?;
+        let mut tiff = Tiff {
+            data,
+            order,
+            directories: Vec::new(),
+        };
+        let mut pending = vec![order.u32(data.get(4..8)?) as usize];
+        let mut seen = Vec::new();
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || seen.contains(&offset) || seen.len() >= MAX_DIRECTORIES {
+                continue;
+            }
+            seen.push(offset);
+            let Some((entries, next)) = read_ifd(data, offset, order) else {
+                continue;
+            };
+            pending.push(next as usize);
+            let subs = tiff.values(&entries, SUB_IFDS).unwrap_or_default();
+            pending.extend(subs.iter().rev().map(|&sub| sub as usize));
+            tiff.directories.push(entries);
+        }
+        (!tiff.directories.is_empty()).then_some(tiff)
+    }
+
+    /// Check for a Canon CR2, whose header goes on with its own mark
+    fn is_cr2(&self) -> bool {
+        self.data.get(8..10) == Some(b"CR")
+    }
+
+    /// Directory of the full size sensor data
+    ///
+    /// That of a CR2 has no size or photometric interpretation, the lossless
+    /// JPEG data tells its size.
+    fn raw_directory(&self) -> Option<&[Entry]> {
+        self.directories
+            .iter()
+            .filter(|ifd| {
+                let photometric = self.value(ifd, PHOTOMETRIC).map(|p| p as u32);
+                let canon = self.is_cr2()
+                    && self.value(ifd, COMPRESSION) == Some(CANON_JPEG as f64)
+                    && (self.value(ifd, CR2_SLICES).is_some()
+                        || self.value(ifd, IMAGE_WIDTH).is_none());
+                matches!(photometric, Some(CFA | LINEAR_RAW)) || canon
+            })
+            .max_by_key(|ifd| {
+                // Full resolution images rather than previews
+                let main = self.value(ifd, NEW_SUBFILE_TYPE).unwrap_or(0.0) == 0.0;
+                let width = self.value(ifd, IMAGE_WIDTH).unwrap_or(0.0) as u64;
+                let height = self.value(ifd, IMAGE_LENGTH).unwrap_or(0.0) as u64;
+                (main, width * height)
+            })
+            .map(Vec::as_slice)
+    }
+
+    /// Type, count and bytes of an entry's value, wherever they are stored
+    fn bytes(&self, ifd: &[Entry], tag: u16) -> Option<(u16, usize, Vec<u8>)> {
+        let (_, raw) = ifd.iter().find(|(t, _)| *t == tag)?;
+        let kind = self.order.u16(&raw[2..4]);
+        let count = self.order.u32(&raw[4..8]) as usize;
+        let size: usize = match kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 => 8,
+            _ => return None,
+        };
+        let length = size.checked_mul(count)?;
+        let bytes = match length <= 4 {
+            true => &raw[8..8 + length],
+            false => {
+                let offset = self.order.u32(&raw[8..12]) as usize;
+                self.data.get(offset..offset.checked_add(length)?)?
+            }
+        };
+        Some((kind, count, bytes.to_vec()))
+    }
+
+    /// Numeric values of an entry, rationals as fractions
+    fn values(&self, ifd: &[Entry], tag: u16) -> Option<Vec<f64>> {
+        let (kind, count, bytes) = self.bytes(ifd, tag)?;
+        let order = self.order;
+        let rational = |numerator: f64, denominator: f64| match denominator {
+            0.0 => 0.0,
+            _ => numerator / denominator,
+        };
+        let values = (0..count).map(|i| match kind {
+            6 => bytes[i] as i8 as f64,
+            3 => order.u16(&bytes[i * 2..]) as f64,
+            8 => order.u16(&bytes[i * 2..]) as i16 as f64,
+            4 | 13 => order.u32(&bytes[i * 4..]) as f64,
+            9 => order.u32(&bytes[i * 4..]) as i32 as f64,
+            11 => f32::from_bits(order.u32(&bytes[i * 4..])) as f64,
+            5 => rational(
+                order.u32(&bytes[i * 8..]) as f64,
+                order.u32(&bytes[i * 8 + 4..]) as f64,
+            ),
+            10 => rational(
+                order.u32(&bytes[i * 8..]) as i32 as f64,
+                order.u32(&bytes[i * 8 + 4..]) as i32 as f64,
+            ),
+            _ => bytes[i] as f64,
+        });
+        Some(values.collect())
+    }
+
+    fn value(&self, ifd: &[Entry], tag: u16) -> Option<f64> {
+        self.values(ifd, tag)?.first().copied()
+    }
+
+    /// The maker note in the EXIF directory, for the camera makers whose notes
+    /// say how to read their sensor data
+    fn maker_note(&self) -> Option<MakerNote<'a>> {
+        let exif = self.value(&self.directories[0], EXIF_IFD)? as usize;
+        let (entries, _) = read_ifd(self.data, exif, self.order)?;
+        let (_, raw) = entries.iter().find(|(tag, _)| *tag == MAKER_NOTE)?;
+        let count = self.order.u32(&raw[4..8]) as usize;
+        let offset = self.order.u32(&raw[8..12]) as usize;
+        let note = self.data.get(offset..offset.checked_add(count)?)?;
+        match note {
+            // A TIFF of its own after the name and version, offsets count from its header
+            _ if note.starts_with(b"Nikon\0") => Tiff::new(note.get(10..)?).map(MakerNote::Nikon),
+            // A bare directory, offsets count from the start of the file
+            _ if self.is_cr2() => {
+                let (entries, _) = read_ifd(self.data, offset, self.order)?;
+                Some(MakerNote::Canon(Tiff {
+                    data: self.data,
+                    order: self.order,
+                    directories: vec![entries],
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Samples of the image in a directory, row by row with the samples of a pixel together
+    fn samples(&self, ifd: &[Entry]) -> Result<Samples, String> {
+        let number = |tag| self.value(ifd, tag).map(|v| v as usize);
+        if self.is_cr2() && number(COMPRESSION) == Some(CANON_JPEG) {
+            return self.sliced_samples(ifd);
+        }
+        let width = number(IMAGE_WIDTH).ok_or("the image has no width")?;
+        let height = number(IMAGE_LENGTH).ok_or("the image has no height")?;
+        let channels = number(SAMPLES_PER_PIXEL).unwrap_or(1);
+        let bits = number(BITS_PER_SAMPLE).unwrap_or(1);
+        let compression = number(COMPRESSION).unwrap_or(1);
+        let total = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(channels))
+            .filter(|&total| total > 0 && total <= MAX_SAMPLES)
+            .ok_or_else(|| format!("a {}x{} image is too large", width, height))?;
+        if !(1..=4).contains(&channels) {
+            return Err(format!("{} samples per pixel", channels));
+        }
+        if !(1..=16).contains(&bits) {
+            return Err(format!("{}-bit samples are not supported", bits));
+        }
+        if ![1, LOSSLESS_JPEG, NIKON_HUFFMAN].contains(&compression) {
+            return Err(format!(
+                "compression {} is not supported, only uncompressed, lossless JPEG and \
+                 Nikon compressed data; convert the file to DNG first",
+                compression
+            ));
+        }
+        let nikon = match compression {
+            NIKON_HUFFMAN => match self.maker_note() {
+                Some(MakerNote::Nikon(note)) => Some(Nikon::read(&note, bits)?),
+                _ => return Err("compressed NEF data without a Nikon maker note".into()),
+            },
+            _ => None,
+        };
+
+        // Strips are tiles as wide as the image
+        let (tile_width, tile_height, offsets, counts) = match number(TILE_WIDTH) {
+            Some(tile_width) => (
+                tile_width,
+                number(TILE_LENGTH).ok_or("tiles without a length")?,
+                self.values(ifd, TILE_OFFSETS),
+                self.values(ifd, TILE_BYTE_COUNTS),
+            ),
+            None => (
+                width,
+                number(ROWS_PER_STRIP).unwrap_or(height).clamp(1, height),
+                self.values(ifd, STRIP_OFFSETS),
+                self.values(ifd, STRIP_BYTE_COUNTS),
+            ),
+        };
+        let (offsets, counts) = offsets.zip(counts).ok_or("the image data is missing")?;
+        if tile_width == 0 || tile_height == 0 {
+            return Err("tiles of no size".into());
+        }
+        let across = width.div_ceil(tile_width);
+        let down = height.div_ceil(tile_height);
+        if offsets.len() < across * down || counts.len() < across * down {
+            return Err("the image data is missing".into());
+        }
+
+        let mut samples = vec![0u16; total];
+        let row_samples = tile_width * channels;
+        for (index, (&offset, &count)) in
+            offsets.iter().zip(&counts).take(across * down).enumerate()
+        {
+            let (offset, count) = (offset as usize, count as usize);
+            let chunk = self
+                .data
+                .get(offset..offset.saturating_add(count).min(self.data.len()))
+                .ok_or("the image data is outside the file")?;
+            let tile = match (compression, &nikon) {
+                (1, _) => unpack(chunk, bits, row_samples, tile_height, self.order),
+                (_, Some(nikon)) => nikon.decode(chunk, row_samples, tile_height)?,
+                _ => lossless_jpeg(chunk)?.samples,
+            };
+            let (x0, y0) = (
+                (index % across) * tile_width,
+                (index / across) * tile_height,
+            );
+            let columns = (width - x0).min(tile_width) * channels;
+            for (y, row) in tile.chunks(row_samples).take(tile_height).enumerate() {
+                if y0 + y >= height {
+                    break;
+                }
+                let start = ((y0 + y) * width + x0) * channels;
+                let length = columns.min(row.len());
+                samples[start..start + length].copy_from_slice(&row[..length]);
+            }
+        }
+        Ok(Samples {
+            width,
+            height,
+            channels,
+            bits,
+            data: samples,
+        })
+    }
+
+    /// Samples of a CR2, a lossless JPEG whose rows run down one vertical
+    /// slice of the sensor after the other
+    fn sliced_samples(&self, ifd: &[Entry]) -> Result<Samples, String> {
+        let number = |tag| self.value(ifd, tag).map(|v| v as usize);
+        let (offset, count) = number(STRIP_OFFSETS)
+            .zip(number(STRIP_BYTE_COUNTS))
+            .ok_or("the image data is missing")?;
+        let chunk = self
+            .data
+            .get(offset..offset.saturating_add(count).min(self.data.len()))
+            .ok_or("the image data is outside the file")?;
+        let jpeg = lossless_jpeg(chunk)?;
+        let (width, height) = (jpeg.width * jpeg.components, jpeg.height);
+        let data = match self.values(ifd, CR2_SLICES).as_deref() {
+            Some(&[slices, slice_width, last_width])
+                if slices >= 0.0
+                    && slice_width >= 1.0
+                    && last_width >= 1.0
+                    && slices * slice_width + last_width == width as f64 =>
+            {
+                let (slices, slice_width) = (slices as usize, slice_width as usize);
+                let mut samples = vec![0u16; width * height];
+                for (index, &sample) in jpeg.samples.iter().enumerate() {
+                    let slice = (index / (slice_width * height)).min(slices);
+                    let within = index - slice * slice_width * height;
+                    let across = match slice == slices {
+                        true => last_width as usize,
+                        false => slice_width,
+                    };
+                    let (y, x) = (within / across, within % across + slice * slice_width);
+                    samples[y * width + x] = sample;
+                }
+                samples
+            }
+            _ => jpeg.samples,
+        };
+        Ok(Samples {
+            width,
+            height,
+            channels: 1,
+            bits: jpeg.precision as usize,
+            data,
+        })
+    }
+}
+
+/// Maker note of a RAW file, as a TIFF whose first directory holds its entries
+enum MakerNote<'a> {
+    Canon(Tiff<'a>),
+    Nikon(Tiff<'a>),
+}
+
+/// What a CR2 or NEF keeps in its maker note rather than in DNG tags
+#[derive(Default)]
+struct MakerLevels {
+    /// Top, left, bottom and right edge of the exposed sensor area
+    active_area: Option<(usize, usize, usize, usize)>,
+    black: Option<f64>,
+    /// White balance gains the camera picked
+    gains: Option<[f32; 3]>,
+    /// Color filter pattern, for files without the tags of one
+    pattern: Option<Pattern>,
+}
+
+impl MakerLevels {
+    fn read(tiff: &Tiff, raw: &Samples) -> Self {
+        match tiff.maker_note() {
+            Some(MakerNote::Canon(note)) => {
+                let ifd = &note.directories[0];
+                // Sensor size then the inclusive borders of the exposed area
+                let active_area = match note.values(ifd, CANON_SENSOR_INFO).as_deref() {
+                    Some([_, _, _, _, _, left, top, right, bottom, ..])
+                        if left < right && top < bottom =>
+                    {
+                        let edge = |v: f64, size: usize| (v.max(0.0) as usize).min(size);
+                        Some((
+                            edge(*top, raw.height),
+                            edge(*left, raw.width),
+                            edge(bottom + 1.0, raw.height),
+                            edge(right + 1.0, raw.width),
+                        ))
+                    }
+                    _ => None,
+                };
+                // The masked columns left of the exposed area give the black level
+                let black = active_area
+                    .filter(|&(top, left, bottom, _)| left > 0 && bottom > top)
+                    .map(|(top, left, bottom, _)| {
+                        let rows = raw.data[top * raw.width..bottom * raw.width].chunks(raw.width);
+                        let sum: f64 = rows
+                            .map(|row| row[..left].iter().map(|&s| s as f64).sum::<f64>())
+                            .sum();
+                        sum / ((bottom - top) * left) as f64
+                    });
+                MakerLevels {
+                    active_area,
+                    black,
+                    gains: None,
+                    pattern: Some(Pattern::rggb()),
+                }
+            }
+            Some(MakerNote::Nikon(note)) => {
+                let ifd = &note.directories[0];
+                // Given for 14 bits, shifted for 12-bit data
+                let black = note
+                    .value(ifd, NIKON_BLACK_LEVEL)
+                    .map(|black| black / (1u32 << 14usize.saturating_sub(raw.bits)) as f64);
+                let gains = match note.values(ifd, NIKON_WB_LEVELS).as_deref() {
+                    Some(&[red, blue, ..]) if red > 0.0 && blue > 0.0 => {
+                        let gains = [red as f32, 1.0, blue as f32];
+                        let min = gains.iter().copied().fold(f32::INFINITY, f32::min);
+                        Some(gains.map(|g| g / min))
+                    }
+                    _ => None,
+                };
+                MakerLevels {
+                    black,
+                    gains,
+                    ..MakerLevels::default()
+                }
+            }
+            None => MakerLevels::default(),
+        }
+    }
+}
+
+/// Decoded samples of an image
+struct Samples {
+    width: usize,
+    height: usize,
+    channels: usize,
+    bits: usize,
+    data: Vec<u16>,
+}
+
+/// How the compressed sensor data of a NEF is read, from its maker note
+struct Nikon {
+    /// Index into `NIKON_TREES`
+    tree: usize,
+    /// Row where lossy data goes on with the next tree, 0 if it doesn't
+    split: usize,
+    /// Predictions of the first two samples of even and odd rows
+    predictions: [[u16; 2]; 2],
+    /// Sample values of the decoded numbers
+    curve: Vec<u16>,
+}
+
+impl Nikon {
+    fn read(note: &Tiff, bits: usize) -> Result<Self, String> {
+        let (_, _, meta) = note
+            .bytes(&note.directories[0], NIKON_DECOMPRESSION)
+            .ok_or("the Nikon maker note has no decompression table")?;
+        let [version, subversion, ..] = meta[..] else {
+            return Err("the Nikon decompression table is truncated".into());
+        };
+        let start = match version == 0x49 || subversion == 0x58 {
+            true => 2112,
+            false => 2,
+        };
+        let short = |index: usize| {
+            meta.get(start + index * 2..start + index * 2 + 2)
+                .map(|b| note.order.u16(b))
+                .ok_or("the Nikon decompression table is truncated")
+        };
+        let predictions = [[short(0)?, short(1)?], [short(2)?, short(3)?]];
+        let tree = match version {
+            0x46 => 2,
+            _ => 0,
+        } + match bits {
+            14 => 3,
+            _ => 0,
+        };
+
+        // The curve maps decoded numbers to samples, it is linear unless given
+        let mut curve: Vec<u16> = (0..=u16::MAX).collect();
+        let max = (1usize << bits) & 0x7fff;
+        let size = short(4)? as usize;
+        let mut split = 0;
+        if version == 0x44 && subversion == 0x20 && size > 1 && max / (size - 1) > 0 {
+            // Lossy data gives every step-th value, the rest is interpolated
+            let step = max / (size - 1);
+            for i in 0..size {
+                curve[i * step] = short(5 + i)?;
+            }
+            for i in 0..max {
+                let (base, rest) = (i - i % step, i % step);
+                let (low, high) = (curve[base] as usize, curve[base + step] as usize);
+                curve[i] = ((low * (step - rest) + high * rest) / step) as u16;
+            }
+            split = meta.get(562..564).map_or(0, |b| note.order.u16(b) as usize);
+        } else if version != 0x46 && size <= 0x4001 {
+            for (i, value) in curve.iter_mut().enumerate().take(size) {
+                *value = short(5 + i)?;
+            }
+        }
+        Ok(Nikon {
+            tree,
+            split,
+            predictions,
+            curve,
+        })
+    }
+
+    /// Decode `rows` rows of `width` samples, each predicted from the last
+    /// sample of its color on the row, or above for the first ones
+    fn decode(&self, data: &[u8], width: usize, rows: usize) -> Result<Vec<u16>, String> {
+        let table = |tree: usize| {
+            let mut counts = [0u8; 16];
+            counts.copy_from_slice(&NIKON_TREES[tree][..16]);
+            let total: usize = counts.iter().map(|&c| c as usize).sum();
+            Huffman::new(&counts, NIKON_TREES[tree][16..16 + total].to_vec())
+        };
+        let mut huffman = table(self.tree);
+        let mut reader = BitReader::new(data, false);
+        let mut predictions = self.predictions;
+        let mut row_predictions = [0u16; 2];
+        let mut samples = Vec::with_capacity(width * rows);
+        for y in 0..rows {
+            if self.split > 0 && y == self.split {
+                huffman = table(self.tree + 1);
+            }
+            for x in 0..width {
+                // Low bits give the length of the difference, high ones a shift of it
+                let code = huffman.decode(&mut reader)? as u32;
+                let (length, shift) = (code & 15, code >> 4);
+                if shift > length {
+                    return Err("invalid Huffman code".into());
+                }
+                let mut difference = 0i32;
+                if length > 0 {
+                    let bits = reader.bits((length - shift) as usize) as i32;
+                    difference = ((bits << 1) + 1) << shift >> 1;
+                    if difference & (1 << (length - 1)) == 0 {
+                        difference -= (1 << length) - (shift == 0) as i32;
+                    }
+                }
+                let prediction = match x < 2 {
+                    true => {
+                        let first = &mut predictions[y & 1][x];
+                        *first = first.wrapping_add(difference as u16);
+                        *first
+                    }
+                    false => row_predictions[x & 1].wrapping_add(difference as u16),
+                };
+                row_predictions[x & 1] = prediction;
+                samples.push(self.curve[(prediction as i16).clamp(0, 0x3fff) as usize]);
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Unpack uncompressed samples, 16-bit ones in the byte order of the file
+/// and others packed with the most significant bit first, every row
+/// starting on a new byte
+fn unpack(data: &[u8], bits: usize, row_samples: usize, rows: usize, order: Order) -> Vec<u16> {
+    let mut samples = Vec::with_capacity(row_samples * rows);
+    match bits {
+        16 => samples.extend(
+            data.chunks_exact(2)
+                .take(row_samples * rows)
+                .map(|b| order.u16(b)),
+        ),
+        8 => samples.extend(data.iter().take(row_samples * rows).map(|&b| b as u16)),
+        _ => {
+            let row_bytes = (row_samples * bits).div_ceil(8);
+            for row in data.chunks(row_bytes).take(rows) {
+                let mut reader = BitReader::new(row, false);
+                samples.extend((0..row_samples).map(|_| reader.bits(bits) as u16));
+            }
+        }
+    }
+    samples
+}
+
+/// Sensor values normalized to 0.0 ~ 1.0 between the black and white level,
+/// cut to the active area
+struct Sensor {
+    width: usize,
+    height: usize,
+    channels: usize,
+    values: Vec<f32>,
+}
+
+impl Sensor {
+    fn new(tiff: &Tiff, ifd: &[Entry], raw: Samples, maker: &MakerLevels) -> Result<Self, String> {
+        let channels = raw.channels;
+        let (top, left, bottom, right) = match tiff.values(ifd, ACTIVE_AREA).as_deref() {
+            Some(&[top, left, bottom, right]) => {
+                (top as usize, left as usize, bottom as usize, right as usize)
+            }
+            _ => maker.active_area.unwrap_or((0, 0, raw.height, raw.width)),
+        };
+        let (bottom, right) = (bottom.min(raw.height), right.min(raw.width));
+        if top + 2 >= bottom || left + 2 >= right {
+            return Err("the active area of the sensor is empty".into());
+        }
+        let (width, height) = (right - left, bottom - top);
+
+        let table: Vec<u16> = tiff
+            .values(ifd, LINEARIZATION_TABLE)
+            .unwrap_or_default()
+            .iter()
+            .map(|&v| v as u16)
+            .collect();
+        let (repeat_rows, repeat_columns) =
+            match tiff.values(ifd, BLACK_LEVEL_REPEAT_DIM).as_deref() {
+                Some(&[rows, columns]) if rows >= 1.0 && columns >= 1.0 => {
+                    (rows as usize, columns as usize)
+                }
+                _ => (1, 1),
+            };
+        let black = tiff
+            .values(ifd, BLACK_LEVEL)
+            .or_else(|| maker.black.map(|black| vec![black]))
+            .unwrap_or_default();
+        let black = match black.len() == repeat_rows * repeat_columns * channels {
+            true => black,
+            false => {
+                vec![black.first().copied().unwrap_or(0.0); repeat_rows * repeat_columns * channels]
+            }
+        };
+        let delta_h = tiff.values(ifd, BLACK_LEVEL_DELTA_H).unwrap_or_default();
+        let delta_v = tiff.values(ifd, BLACK_LEVEL_DELTA_V).unwrap_or_default();
+        let maximum = ((1u32 << raw.bits) - 1) as f64;
+        let white = tiff
+            .values(ifd, WHITE_LEVEL)
+            .unwrap_or_else(|| vec![table.iter().copied().max().map_or(maximum, f64::from)]);
+        let white: Vec<f64> = (0..channels)
+            .map(|c| white.get(c).or(white.first()).copied().unwrap_or(maximum))
+            .collect();
+
+        let mut values = vec![0f32; width * height * channels];
+        values
+            .par_chunks_mut(width * channels)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let source = &raw.data[((top + y) * raw.width + left) * channels..];
+                let dv = delta_v.get(y).copied().unwrap_or(0.0);
+                for (i, value) in row.iter_mut().enumerate() {
+                    let (x, c) = (i / channels, i % channels);
+                    let sample = source[i];
+                    let sample = table.get(sample as usize).copied().unwrap_or(sample) as f64;
+                    let pattern =
+                        ((y % repeat_rows) * repeat_columns + x % repeat_columns) * channels + c;
+                    let black = black[pattern] + dv + delta_h.get(x).copied().unwrap_or(0.0);
+                    let range = (white[c] - black).max(1.0);
+                    *value = ((sample - black) / range).max(0.0) as f32;
+                }
+            });
+        Ok(Sensor {
+            width,
+            height,
+            channels,
+            values,
+        })
+    }
+
+    /// Color of a sample, from the filter over it or its channel
+    fn color(&self, pattern: Option<&Pattern>, index: usize) -> usize {
+        match pattern {
+            Some(pattern) => pattern.at((index / self.width) % self.height, index % self.width),
+            None => index % self.channels,
+        }
+    }
+
+    /// Gains that make the average of the unclipped samples gray
+    fn gray_world(&self, pattern: Option<&Pattern>) -> [f32; 3] {
+        let mut sums = [0f64; 3];
+        let mut counts = [0u64; 3];
+        for (index, &value) in self.values.iter().enumerate() {
+            if value < CLIPPED {
+                let color = self.color(pattern, index);
+                sums[color] += value as f64;
+                counts[color] += 1;
+            }
+        }
+        let means = std::array::from_fn(|c| match counts[c] {
+            0 => 0.0,
+            n => (sums[c] / n as f64) as f32,
+        });
+        match means.iter().all(|&m| m > 0.0) {
+            true => neutral_gains(means),
+            false => [1.0; 3],
+        }
+    }
+
+    /// Apply white balance gains, clipping at the white level so clipped
+    /// highlights stay white rather than turning a color
+    fn scale(&mut self, gains: &[f32; 3], pattern: Option<&Pattern>) {
+        let width = self.width * self.channels;
+        let channels = self.channels;
+        self.values
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (i, value) in row.iter_mut().enumerate() {
+                    let color = match pattern {
+                        Some(pattern) => pattern.at(y, i),
+                        None => i % channels,
+                    };
+                    *value = (*value * gains[color]).min(1.0);
+                }
+            });
+    }
+}
+
+/// Colors of the filters over the sensor, repeating every few rows and columns
+struct Pattern {
+    rows: usize,
+    columns: usize,
+    /// 0 for red, 1 for green and 2 for blue, row by row
+    colors: Vec<usize>,
+}
+
+impl Pattern {
+    fn read(tiff: &Tiff, ifd: &[Entry]) -> Result<Self, String> {
+        let (rows, columns) = match tiff.values(ifd, CFA_REPEAT_PATTERN_DIM).as_deref() {
+            Some(&[rows, columns]) => (rows as usize, columns as usize),
+            _ => (2, 2),
+        };
+        let pattern = tiff
+            .values(ifd, CFA_PATTERN)
+            .ok_or("the color filter array has no pattern")?;
+        let planes = tiff
+            .values(ifd, CFA_PLANE_COLOR)
+            .unwrap_or_else(|| vec![0.0, 1.0, 2.0]);
+        if rows == 0 || columns == 0 || rows > 16 || columns > 16 || pattern.len() != rows * columns
+        {
+            return Err("the color filter array pattern is invalid".into());
+        }
+        let colors = pattern
+            .iter()
+            .map(
+                |&value| match planes.get(value as usize).map(|&p| p as usize) {
+                    Some(color @ 0..=2) => Ok(color),
+                    _ => Err("only red, green and blue color filters are supported".to_string()),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        if (0..3).any(|color| !colors.contains(&color)) {
+            return Err("the color filter array lacks a color".into());
+        }
+        Ok(Pattern {
+            rows,
+            columns,
+            colors,
+        })
+    }
+
+    /// Red, green, green and blue, the pattern of Canon's sensors
+    fn rggb() -> Self {
+        Pattern {
+            rows: 2,
+            columns: 2,
+            colors: vec![0, 1, 1, 2],
+        }
+    }
+
+    fn at(&self, y: usize, x: usize) -> usize {
+        self.colors[(y % self.rows) * self.columns + x % self.columns]
+    }
+
+    /// Check for a 2x2 Bayer pattern, with greens on one diagonal
+    fn is_bayer(&self) -> bool {
+        let mut sorted = self.colors.clone();
+        sorted.sort_unstable();
+        let diagonal =
+            self.colors.first() == self.colors.get(3) || self.colors.get(1) == self.colors.get(2);
+        self.rows == 2 && self.columns == 2 && sorted == [0, 1, 1, 2] && diagonal
+    }
+}
+
+/// Reflect a coordinate at the edges without repeating the edge itself, which
+/// keeps the color of the filter at the reflected position
+fn reflect(i: isize, size: usize) -> usize {
+    let last = size as isize - 1;
+    let i = if i < 0 { -i } else { i };
+    let i = if i > last { 2 * last - i } else { i };
+    i.clamp(0, last) as usize
+}
+
+/// Interpolate the missing colors of a Bayer pattern with the gradient-corrected
+/// linear filters of Malvar, He and Cutler
+fn demosaic_bayer(sensor: &Sensor, pattern: &Pattern) -> Vec<f32> {
+    let (width, height) = (sensor.width, sensor.height);
+    let values = &sensor.values;
+    let mut rgb = vec![0f32; width * height * 3];
+    rgb.par_chunks_mut(width * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let at = |dy: isize, dx: isize, x: usize| {
+                let (yy, xx) = (
+                    reflect(y as isize + dy, height),
+                    reflect(x as isize + dx, width),
+                );
+                values[yy * width + xx]
+            };
+            for x in 0..width {
+                let color = pattern.at(y, x);
+                let v = |dy, dx| at(dy, dx, x);
+                let center = v(0, 0);
+                let cross = v(-1, 0) + v(1, 0) + v(0, -1) + v(0, 1);
+                let diagonal = v(-1, -1) + v(-1, 1) + v(1, -1) + v(1, 1);
+                let far_h = v(0, -2) + v(0, 2);
+                let far_v = v(-2, 0) + v(2, 0);
+                let pixel = &mut row[x * 3..x * 3 + 3];
+                pixel[color] = center;
+                for target in (0..3).filter(|&t| t != color) {
+                    let estimate = if target == 1 {
+                        // Green at red or blue
+                        (4.0 * center + 2.0 * cross - far_h - far_v) / 8.0
+                    } else if color == 1 {
+                        let horizontal = pattern.at(y, x + 1) == target;
+                        let (near, far_along, far_across) = match horizontal {
+                            true => (v(0, -1) + v(0, 1), far_h, far_v),
+                            false => (v(-1, 0) + v(1, 0), far_v, far_h),
+                        };
+                        (5.0 * center + 4.0 * near - diagonal - far_along + 0.5 * far_across) / 8.0
+                    } else {
+                        // Red at blue or blue at red
+                        (6.0 * center + 2.0 * diagonal - 1.5 * (far_h + far_v)) / 8.0
+                    };
+                    pixel[target] = estimate.max(0.0);
+                }
+            }
+        });
+    rgb
+}
+
+/// Interpolate missing colors as the average of the nearby samples of that
+/// color, for patterns other than Bayer such as X-Trans
+fn demosaic_average(sensor: &Sensor, pattern: &Pattern) -> Vec<f32> {
+    let (width, height) = (sensor.width, sensor.height);
+    let values = &sensor.values;
+    let mut rgb = vec![0f32; width * height * 3];
+    rgb.par_chunks_mut(width * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for x in 0..width {
+                let color = pattern.at(y, x);
+                for target in 0..3 {
+                    let value = match target == color {
+                        true => values[y * width + x],
+                        false => (1..=2)
+                            .find_map(|radius: isize| {
+                                let (mut sum, mut count) = (0.0, 0);
+                                for dy in -radius..=radius {
+                                    for dx in -radius..=radius {
+                                        let yy = reflect(y as isize + dy, height);
+                                        let xx = reflect(x as isize + dx, width);
+                                        if pattern.at(yy, xx) == target {
+                                            sum += values[yy * width + xx];
+                                            count += 1;
+                                        }
+                                    }
+                                }
+                                (count > 0).then(|| sum / count as f32)
+                            })
+                            .unwrap_or(0.0),
+                    };
+                    row[x * 3 + target] = value;
+                }
+            }
+        });
+    rgb
+}
+
+/// Reads bits with the most significant first, skipping the zero byte
+/// JPEG stuffs after every 0xFF
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    count: u32,
+    /// Whether 0xFF 0x00 stands for 0xFF and other 0xFF bytes start markers
+    stuffed: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], stuffed: bool) -> Self {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+            stuffed,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.count <= 56 {
+            let mut byte = 0;
+            if let Some(&next) = self.data.get(self.position) {
+                if self.stuffed && next == 0xff {
+                    // At a marker zeros are read from here on
+                    if self.data.get(self.position + 1) == Some(&0) {
+                        byte = 0xff;
+                        self.position += 2;
+                    }
+                } else {
+                    byte = next;
+                    self.position += 1;
+                }
+            }
+            self.buffer |= (byte as u64) << (56 - self.count);
+            self.count += 8;
+        }
+    }
+
+    fn peek(&mut self, n: u32) -> u32 {
+        if self.count < n {
+            self.fill();
+        }
+        (self.buffer >> (64 - n)) as u32
+    }
+
+    fn skip(&mut self, n: u32) {
+        self.buffer <<= n;
+        self.count -= n;
+    }
+
+    fn bits(&mut self, n: usize) -> u32 {
+        match n {
+            0 => 0,
+            _ => {
+                let value = self.peek(n as u32);
+                self.skip(n as u32);
+                value
+            }
+        }
+    }
+
+    /// Skip to the data after the next restart marker, dropping the bits left
+    fn restart(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+        while let Some(window) = self.data.get(self.position..self.position + 2) {
+            self.position += 1;
+            if window[0] == 0xff && (0xd0..=0xd7).contains(&window[1]) {
+                self.position += 1;
+                return;
+            }
+        }
+    }
+}
+
+/// Bits of a lookup in the Huffman tables, longer codes are decoded bit by bit
+const LOOKUP_BITS: u32 = 9;
+
+/// Huffman table of a lossless JPEG, for the categories of differences
+#[derive(Clone, Default)]
+struct Huffman {
+    /// Length and value of the code starting with every `LOOKUP_BITS` bits, length 0 if longer
+    lookup: Vec<(u8, u8)>,
+    /// Largest code of every length, -1 if there is none
+    max_code: [i32; 17],
+    /// Index into `values` of the first code of every length, less that code
+    offset: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8; 16], values: Vec<u8>) -> Self {
+        let mut table = Huffman {
+            lookup: vec![(0, 0); 1 << LOOKUP_BITS],
+            max_code: [-1; 17],
+            offset: [0; 17],
+            values,
+        };
+        let (mut code, mut index) = (0i32, 0usize);
+        for length in 1..=16 {
+            let count = counts[length - 1] as usize;
+            table.offset[length] = index as i32 - code;
+            for _ in 0..count {
+                if length as u32 <= LOOKUP_BITS && index < table.values.len() {
+                    let shift = LOOKUP_BITS - length as u32;
+                    let start = (code as usize) << shift;
+                    // Codes past the end come from a table with too many codes of a length
+                    if let Some(entries) = table.lookup.get_mut(start..start + (1 << shift)) {
+                        entries.fill((length as u8, table.values[index]));
+                    }
+                }
+                code += 1;
+                index += 1;
+            }
+            if count > 0 {
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, String> {
+        let (length, value) = self.lookup[reader.peek(LOOKUP_BITS) as usize];
+        if length > 0 {
+            reader.skip(length as u32);
+            return Ok(value);
+        }
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = code << 1 | reader.bits(1) as i32;
+            if code <= self.max_code[length] {
+                let index = (code + self.offset[length]) as usize;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| "invalid Huffman code".into());
+            }
+        }
+        Err("invalid Huffman code".into())
+    }
+}
+
+/// Samples of a lossless JPEG, each row holding the components of a pixel together
+struct Lossless {
+    width: usize,
+    height: usize,
+    components: usize,
+    precision: u32,
+    samples: Vec<u16>,
+}
+
+/// Decode a lossless JPEG as DNG and many cameras store sensor data
+fn lossless_jpeg(data: &[u8]) -> Result<Lossless, String> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return Err("lossless JPEG data without a start marker".into());
+    }
+    let mut tables: [Option<Huffman>; 4] = Default::default();
+    let (mut precision, mut width, mut height) = (0, 0, 0);
+    let mut components: Vec<u8> = Vec::new();
+    let mut restart_interval = 0;
+    let mut position = 2;
+    loop {
+        while data.get(position) == Some(&0xff) && data.get(position + 1) == Some(&0xff) {
+            position += 1;
+        }
+        let marker = match data.get(position..position + 2) {
+            Some(&[0xff, marker]) => marker,
+            _ => return Err("lossless JPEG data ends without a scan".into()),
+        };
+        let length = data
+            .get(position + 2..position + 4)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+            .ok_or("truncated lossless JPEG marker")?;
+        let segment = data
+            .get(position + 4..position + 2 + length)
+            .ok_or("truncated lossless JPEG segment")?;
+        position += 2 + length;
+        match marker {
+            0xc3 => {
+                let [p, h0, h1, w0, w1, count, ..] = *segment else {
+                    return Err("truncated frame header".into());
+                };
+                precision = p as u32;
+                height = u16::from_be_bytes([h0, h1]) as usize;
+                width = u16::from_be_bytes([w0, w1]) as usize;
+                components = segment[6..]
+                    .chunks_exact(3)
+                    .take(count as usize)
+                    .map(|c| c[0])
+                    .collect();
+                if segment[6..]
+                    .chunks_exact(3)
+                    .take(count as usize)
+                    .any(|c| c[1] != 0x11)
+                {
+                    return Err("subsampled lossless JPEG is not supported".into());
+                }
+            }
+            0xc4 => {
+                let mut rest = segment;
+                while let [class, rest_after @ ..] = rest {
+                    let counts: [u8; 16] = rest_after
+                        .get(..16)
+                        .and_then(|c| c.try_into().ok())
+                        .ok_or("truncated Huffman table")?;
+                    let total: usize = counts.iter().map(|&c| c as usize).sum();
+                    let values = rest_after
+                        .get(16..16 + total)
+                        .ok_or("truncated Huffman table")?;
+                    let slot = tables
+                        .get_mut((class & 0x03) as usize)
+                        .ok_or("invalid Huffman table")?;
+                    *slot = Some(Huffman::new(&counts, values.to_vec()));
+                    rest = &rest_after[16 + total..];
+                }
+            }
+            0xdd => {
+                restart_interval = segment
+                    .get(..2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+                    .ok_or("truncated restart interval")?;
+            }
+            0xda => {
+                let count = *segment.first().ok_or("truncated scan header")? as usize;
+                let selectors = segment
+                    .get(1..1 + count * 2)
+                    .ok_or("truncated scan header")?;
+                let [predictor, _, transform] = *segment
+                    .get(1 + count * 2..4 + count * 2)
+                    .ok_or("truncated scan header")?
+                else {
+                    return Err("truncated scan header".into());
+                };
+                if count != components.len() || width == 0 || height == 0 {
+                    return Err("the scan doesn't match the frame".into());
+                }
+                let tables: Vec<&Huffman> = selectors
+                    .chunks_exact(2)
+                    .map(|s| tables.get((s[1] >> 4) as usize).and_then(Option::as_ref))
+                    .collect::<Option<_>>()
+                    .ok_or("the scan uses a missing Huffman table")?;
+                let scan = Scan {
+                    precision,
+                    predictor: predictor as u32,
+                    transform: (transform & 0x0f) as u32,
+                    width,
+                    height,
+                    restart_interval,
+                };
+                let samples = scan.decode(&data[position..], &tables)?;
+                return Ok(Lossless {
+                    width,
+                    height,
+                    components: count,
+                    precision,
+                    samples,
+                });
+            }
+            0xc0..=0xc2 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return Err("the JPEG data is not lossless".into());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parameters of the scan of a lossless JPEG
+struct Scan {
+    precision: u32,
+    predictor: u32,
+    /// Point transform, a right shift of every sample
+    transform: u32,
+    width: usize,
+    height: usize,
+    restart_interval: usize,
+}
+
+impl Scan {
+    fn decode(&self, data: &[u8], tables: &[&Huffman]) -> Result<Vec<u16>, String> {
+        let count = tables.len();
+        let row_samples = self.width * count;
+        let total = row_samples
+            .checked_mul(self.height)
+            .filter(|&total| total <= MAX_SAMPLES)
+            .ok_or("the lossless JPEG is too large")?;
+        let mut samples = vec![0u16; total];
+        let mut reader = BitReader::new(data, true);
+        let initial = 1i32 << (self.precision.saturating_sub(self.transform + 1)).min(15);
+        let mut until_restart = self.restart_interval;
+        // Row where prediction starts again as on the first row
+        let mut first_row = 0;
+        let mut restarted_at = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.restart_interval > 0 {
+                    if until_restart == 0 {
+                        reader.restart();
+                        until_restart = self.restart_interval;
+                        first_row = y;
+                        restarted_at = x;
+                    }
+                    until_restart -= 1;
+                }
+                for (c, table) in tables.iter().enumerate() {
+                    let category = table.decode(&mut reader)? as usize;
+                    let difference = match category {
+                        0 => 0,
+                        16 => 32768,
+                        1..=15 => {
+                            let bits = reader.bits(category) as i32;
+                            match bits < 1 << (category - 1) {
+                                true => bits - (1 << category) + 1,
+                                false => bits,
+                            }
+                        }
+                        _ => return Err("invalid difference category".into()),
+                    };
+                    let index = y * row_samples + x * count + c;
+                    let left = || samples[index - count] as i32;
+                    let above = || samples[index - row_samples] as i32;
+                    let prediction = if y == first_row && x == restarted_at {
+                        initial
+                    } else if y == first_row {
+                        left()
+                    } else if x == 0 {
+                        above()
+                    } else {
+                        let (a, b, c) =
+                            (left(), above(), samples[index - row_samples - count] as i32);
+                        match self.predictor {
+                            1 => a,
+                            2 => b,
+                            3 => c,
+                            4 => a + b - c,
+                            5 => a + ((b - c) >> 1),
+                            6 => b + ((a - c) >> 1),
+                            7 => (a + b) >> 1,
+                            _ => return Err(format!("invalid predictor {}", self.predictor)),
+                        }
+                    };
+                    samples[index] = (prediction + difference) as u16;
+                }
+            }
+        }
+        if self.transform > 0 {
+            samples.iter_mut().for_each(|s| *s <<= self.transform);
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::decode as decode_image;
+    use crate::{ImageInfo, Metadata, read_icc, read_orientation};
+    use image::metadata::Orientation;
+    use image::{DynamicImage, ImageFormat};
+    use std::io::Cursor;
+
+    const MAKE: u16 = 271;
+    const ORIENTATION: u16 = 274;
+
+    /// Values of a TIFF entry
+    enum Value {
+        Byte(Vec<u8>),
+        Ascii(&'static str),
+        Short(Vec<u16>),
+        Long(Vec<u32>),
+        Rational(Vec<(u32, u32)>),
+        SRational(Vec<(i32, i32)>),
+    }
+
+    /// A little-endian TIFF with one directory, the image data right after the header
+    fn tiff(entries: Vec<(u16, Value)>, image: &[u8]) -> Vec<u8> {
+        let mut data = b"II*\0\0\0\0\0".to_vec();
+        data.extend_from_slice(image);
+        if data.len() % 2 == 1 {
+            data.push(0);
+        }
+        let ifd = data.len();
+        data[4..8].copy_from_slice(&(ifd as u32).to_le_bytes());
+        data.extend_from_slice(&directory(entries, ifd));
+        data
+    }
+
+    /// A little-endian directory at `ifd`, followed by the values that don't fit in it
+    fn directory(mut entries: Vec<(u16, Value)>, ifd: usize) -> Vec<u8> {
+        entries.sort_by_key(|(tag, _)| *tag);
+        let mut overflow = ifd + 2 + entries.len() * 12 + 4;
+        let mut directory = (entries.len() as u16).to_le_bytes().to_vec();
+        let mut values = Vec::new();
+        for (tag, value) in entries {
+            let (kind, count, bytes): (u16, usize, Vec<u8>) = match value {
+                Value::Byte(v) => (1, v.len(), v),
+                Value::Ascii(v) => (2, v.len() + 1, [v.as_bytes(), &[0]].concat()),
+                Value::Short(v) => (3, v.len(), v.iter().flat_map(|x| x.to_le_bytes()).collect()),
+                Value::Long(v) => (4, v.len(), v.iter().flat_map(|x| x.to_le_bytes()).collect()),
+                Value::Rational(v) => (
+                    5,
+                    v.len(),
+                    v.iter()
+                        .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat())
+                        .collect(),
+                ),
+                Value::SRational(v) => (
+                    10,
+                    v.len(),
+                    v.iter()
+                        .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat())
+                        .collect(),
+                ),
+            };
+            directory.extend_from_slice(&tag.to_le_bytes());
+            directory.extend_from_slice(&kind.to_le_bytes());
+            directory.extend_from_slice(&(count as u32).to_le_bytes());
+            match bytes.len() <= 4 {
+                true => {
+                    let mut inline = bytes;
+                    inline.resize(4, 0);
+                    directory.extend_from_slice(&inline);
+                }
+                false => {
+                    directory.extend_from_slice(&(overflow as u32).to_le_bytes());
+                    overflow += bytes.len();
+                    values.extend_from_slice(&bytes);
+                }
+            }
+        }
+        directory.extend_from_slice(&[0; 4]);
+        directory.extend_from_slice(&values);
+        directory
+    }
+
+    /// A DNG of one strip of RGGB sensor data
+    fn dng(width: u32, height: u32, bits: u16, image: &[u8], extra: Vec<(u16, Value)>) -> Vec<u8> {
+        let mut entries = vec![
+            (DNG_VERSION, Value::Byte(vec![1, 4, 0, 0])),
+            (IMAGE_WIDTH, Value::Long(vec![width])),
+            (IMAGE_LENGTH, Value::Long(vec![height])),
+            (BITS_PER_SAMPLE, Value::Short(vec![bits])),
+            (COMPRESSION, Value::Short(vec![1])),
+            (PHOTOMETRIC, Value::Short(vec![CFA as u16])),
+            (STRIP_OFFSETS, Value::Long(vec![8])),
+            (SAMPLES_PER_PIXEL, Value::Short(vec![1])),
+            (ROWS_PER_STRIP, Value::Long(vec![height])),
+            (STRIP_BYTE_COUNTS, Value::Long(vec![image.len() as u32])),
+            (CFA_REPEAT_PATTERN_DIM, Value::Short(vec![2, 2])),
+            (CFA_PATTERN, Value::Byte(vec![0, 1, 1, 2])),
+        ];
+        for (tag, value) in extra {
+            entries.retain(|(t, _)| *t != tag);
+            entries.push((tag, value));
+        }
+        tiff(entries, image)
+    }
+
+    /// Samples of a scene of one color on an RGGB sensor
+    fn mosaic(width: usize, height: usize, rgb: [u16; 3]) -> Vec<u16> {
+        (0..width * height)
+            .map(|i| rgb[[0, 1, 1, 2][(i / width % 2) * 2 + i % width % 2]])
+            .collect()
+    }
+
+    fn little_endian(samples: &[u16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    fn develop(
+        data: &[u8],
+        white_balance: WhiteBalance,
+        exposure: f32,
+    ) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, ImgtoolsError> {
+        let options = DecodeOptions {
+            white_balance,
+            exposure,
+            ..DecodeOptions::default()
+        };
+        decode(data, &options)
+    }
+
+    fn srgb16(linear: f32) -> u16 {
+        (encode_srgb(linear) * 65535.0).round() as u16
+    }
+
+    /// Encode samples as a lossless JPEG with one Huffman table of 5-bit codes
+    fn lossless(
+        samples: &[u16],
+        width: usize,
+        height: usize,
+        components: usize,
+        predictor: u32,
+    ) -> Vec<u8> {
+        let mut out = vec![0xff, 0xd8, 0xff, 0xc3];
+        out.extend_from_slice(&(8 + 3 * components as u16).to_be_bytes());
+        out.push(16);
+        out.extend_from_slice(&(height as u16).to_be_bytes());
+        out.extend_from_slice(&(width as u16).to_be_bytes());
+        out.push(components as u8);
+        for c in 0..components {
+            out.extend_from_slice(&[c as u8 + 1, 0x11, 0]);
+        }
+        out.extend_from_slice(&[0xff, 0xc4, 0, 36, 0]);
+        out.extend_from_slice(&[0, 0, 0, 0, 17, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        out.extend(0..=16u8);
+        out.extend_from_slice(&[0xff, 0xda]);
+        out.extend_from_slice(&(6 + 2 * components as u16).to_be_bytes());
+        out.push(components as u8);
+        for c in 0..components {
+            out.extend_from_slice(&[c as u8 + 1, 0]);
+        }
+        out.extend_from_slice(&[predictor as u8, 0, 0]);
+
+        let (mut buffer, mut count) = (0u64, 0u32);
+        let mut bytes = Vec::new();
+        let mut put = |value: u32, bits: u32, bytes: &mut Vec<u8>| {
+            buffer = buffer << bits | value as u64;
+            count += bits;
+            while count >= 8 {
+                let byte = (buffer >> (count - 8)) as u8;
+                bytes.push(byte);
+                if byte == 0xff {
+                    bytes.push(0);
+                }
+                count -= 8;
+            }
+        };
+        let row = width * components;
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..components {
+                    let index = y * row + x * components + c;
+                    let sample = |i: usize| samples[i] as i32;
+                    let prediction = match (x, y) {
+                        (0, 0) => 1 << 15,
+                        (_, 0) => sample(index - components),
+                        (0, _) => sample(index - row),
+                        _ => {
+                            let (a, b, c) = (
+                                sample(index - components),
+                                sample(index - row),
+                                sample(index - row - components),
+                            );
+                            match predictor {
+                                1 => a,
+                                4 => a + b - c,
+                                6 => b + ((a - c) >> 1),
+                                _ => (a + b) >> 1,
+                            }
+                        }
+                    };
+                    let mut difference = (sample(index) - prediction).rem_euclid(65536);
+                    if difference > 32768 {
+                        difference -= 65536;
+                    }
+                    let category = match difference {
+                        32768 => 16,
+                        d => 32 - d.unsigned_abs().leading_zeros(),
+                    };
+                    put(category, 5, &mut bytes);
+                    if (1..16).contains(&category) {
+                        let extra = match difference < 0 {
+                            true => difference + (1 << category) - 1,
+                            false => difference,
+                        };
+                        put(extra as u32, category, &mut bytes);
+                    }
+                }
+            }
+        }
+        // Fill the last byte with ones
+        put(0x7f, 7, &mut bytes);
+        out.extend_from_slice(&bytes);
+        out.extend_from_slice(&[0xff, 0xd9]);
+        out
+    }
+
+    /// Samples that jump around, the difference of 32768 included
+    fn noise(count: usize) -> Vec<u16> {
+        let mut state = 12345u32;
+        let mut samples: Vec<u16> = (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u16
+            })
+            .collect();
+        samples[1] = samples[0].wrapping_add(32768);
+        samples
+    }
+
+    #[test]
+    fn test_camera_white_balance_makes_gray() {
+        let samples = mosaic(8, 8, [10000, 20000, 5000]);
+        let data = dng(
+            8,
+            8,
+            16,
+            &little_endian(&samples),
+            vec![(
+                AS_SHOT_NEUTRAL,
+                Value::Rational(vec![(1, 2), (1, 1), (1, 4)]),
+            )],
+        );
+        assert!(is_raw(&data));
+
+        let expected = srgb16(20000.0 / 65535.0);
+        for white_balance in [WhiteBalance::Camera, WhiteBalance::Auto] {
+            let img = develop(&data, white_balance, 0.0).unwrap();
+            assert_eq!(img.dimensions(), (8, 8));
+            for pixel in img.pixels() {
+                for value in pixel.0 {
+                    assert!(value.abs_diff(expected) <= 2, "{:?}", pixel);
+                }
+            }
+        }
+
+        // One stop more doubles the linear value
+        let brighter = develop(&data, WhiteBalance::Camera, 1.0).unwrap();
+        let expected = srgb16(40000.0 / 65535.0);
+        assert!(brighter.get_pixel(3, 3).0[1].abs_diff(expected) <= 2);
+    }
+
+    #[test]
+    fn test_packed_data_is_cut_to_the_crop() {
+        // A red scene on a 12-bit sensor with a masked border around it
+        let (width, height) = (12, 12);
+        let mut samples = vec![4095u16; width * height];
+        let scene = mosaic(8, 8, [256 + 1919, 256, 256]);
+        for y in 0..8 {
+            samples[(y + 2) * width + 2..(y + 2) * width + 10]
+                .copy_from_slice(&scene[y * 8..y * 8 + 8]);
+        }
+        let mut packed = Vec::new();
+        for pair in samples.chunks(2) {
+            let (a, b) = (pair[0], pair[1]);
+            packed.extend_from_slice(&[(a >> 4) as u8, ((a & 0xf) << 4 | b >> 8) as u8, b as u8]);
+        }
+        let data = dng(
+            width as u32,
+            height as u32,
+            12,
+            &packed,
+            vec![
+                (BLACK_LEVEL, Value::Short(vec![256])),
+                (WHITE_LEVEL, Value::Short(vec![4095])),
+                (ACTIVE_AREA, Value::Long(vec![2, 2, 10, 10])),
+                (DEFAULT_CROP_ORIGIN, Value::Long(vec![1, 1])),
+                (DEFAULT_CROP_SIZE, Value::Long(vec![6, 6])),
+                (
+                    AS_SHOT_NEUTRAL,
+                    Value::Rational(vec![(1, 1), (1, 1), (1, 1)]),
+                ),
+            ],
+        );
+
+        let img = develop(&data, WhiteBalance::Camera, 0.0).unwrap();
+        assert_eq!(img.dimensions(), (6, 6));
+        let red = srgb16(1919.0 / 3839.0);
+        for pixel in img.pixels() {
+            assert!(pixel.0[0].abs_diff(red) <= 2, "{:?}", pixel);
+            assert!(pixel.0[1] <= 1 && pixel.0[2] <= 1, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn test_color_matrix_keeps_white() {
+        // An identity camera matrix, camera colors are XYZ and white is D65
+        let samples = mosaic(8, 8, [23763, 25000, 27219]);
+        let matrix = [1, 0, 0, 0, 1, 0, 0, 0, 1].map(|v| (v, 1));
+        let data = dng(
+            8,
+            8,
+            16,
+            &little_endian(&samples),
+            vec![
+                (COLOR_MATRIX_1, Value::SRational(matrix.to_vec())),
+                (CALIBRATION_ILLUMINANT_1, Value::Short(vec![21])),
+                (BASELINE_EXPOSURE, Value::SRational(vec![(-1, 1)])),
+            ],
+        );
+        let img = develop(&data, WhiteBalance::Daylight, 0.0).unwrap();
+        let [r, g, b] = img.get_pixel(4, 4).0;
+        assert!(
+            r.abs_diff(g) <= 8 && g.abs_diff(b) <= 8,
+            "{} {} {}",
+            r,
+            g,
+            b
+        );
+        // Daylight gains leave the brightest channel alone, less the baseline stop
+        let expected = srgb16(0.5 * 27219.0 / 65535.0);
+        assert!(g.abs_diff(expected) <= 8, "{} {}", g, expected);
+    }
+
+    #[test]
+    fn test_lossless_jpeg_round_trips() {
+        let samples = noise(8 * 4 * 2);
+        for predictor in [1, 4, 6, 7] {
+            let jpeg = lossless(&samples, 8, 4, 2, predictor);
+            assert_eq!(lossless_jpeg(&jpeg).unwrap().samples, samples);
+        }
+        assert!(lossless_jpeg(&[0xff, 0xd8, 0xff, 0xc0, 0, 2]).is_err());
+
+        // Tiles of lossless JPEG decode as the same data uncompressed
+        let samples = noise(16 * 4);
+        let plain = dng(16, 4, 16, &little_endian(&samples), vec![]);
+        let jpeg = lossless(&samples, 8, 4, 2, 1);
+        let compressed = dng(
+            16,
+            4,
+            16,
+            &jpeg,
+            vec![
+                (COMPRESSION, Value::Short(vec![7])),
+                (TILE_WIDTH, Value::Long(vec![16])),
+                (TILE_LENGTH, Value::Long(vec![4])),
+                (TILE_OFFSETS, Value::Long(vec![8])),
+                (TILE_BYTE_COUNTS, Value::Long(vec![jpeg.len() as u32])),
+            ],
+        );
+        assert_eq!(
+            develop(&compressed, WhiteBalance::Auto, 0.0).unwrap(),
+            develop(&plain, WhiteBalance::Auto, 0.0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_raw_input_is_recognized() {
+        let mut plain = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut plain, ImageFormat::Tiff)
+            .unwrap();
+        assert!(!is_raw(plain.get_ref()));
+        assert!(!is_raw(b"II*\0"));
+
+        let samples = mosaic(8, 6, [1000, 2000, 3000]);
+        let data = dng(
+            8,
+            6,
+            16,
+            &little_endian(&samples),
+            vec![
+                (ORIENTATION, Value::Short(vec![6])),
+                (MAKE, Value::Ascii("Camera")),
+            ],
+        );
+        let img = decode_image(&data, Some(ImageFormat::Tiff)).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 6));
+        assert!(matches!(img, DynamicImage::ImageRgb16(_)));
+        let info = ImageInfo::read(&data, None).unwrap();
+        assert_eq!(
+            (info.format.as_str(), info.width, info.bit_depth),
+            ("raw", 8, 16)
+        );
+
+        // The photo's EXIF fields carry over, those of the sensor data don't
+        assert_eq!(
+            read_orientation(&data, None).unwrap(),
+            Orientation::Rotate90
+        );
+        let exif = Metadata::read(&data, None).unwrap().exif.unwrap();
+        let fields = exif::Reader::new().read_raw(exif).unwrap();
+        assert!(
+            fields
+                .get_field(exif::Tag::Make, exif::In::PRIMARY)
+                .is_some()
+        );
+        assert!(
+            fields
+                .get_field(exif::Tag::ImageWidth, exif::In::PRIMARY)
+                .is_none()
+        );
+        assert!(read_icc(&data, None).unwrap().is_none());
+
+        let limited = DecodeOptions {
+            max_alloc: Some(8 * 6 * 6 - 1),
+            ..DecodeOptions::default()
+        };
+        assert!(matches!(
+            decode(&data, &limited),
+            Err(ImgtoolsError::Decode(ImageError::Limits(_)))
+        ));
+
+        // Compressions this decoder doesn't know ask for a DNG
+        let packed = dng(
+            8,
+            6,
+            16,
+            &little_endian(&samples),
+            vec![(COMPRESSION, Value::Short(vec![32773]))],
+        );
+        let error = develop(&packed, WhiteBalance::Camera, 0.0).unwrap_err();
+        assert!(error.to_string().contains("32773"), "{}", error);
+    }
+
+    /// Encode samples with Nikon's lossless 12-bit Huffman codes, each
+    /// predicted as `Nikon::decode` does from `first` for the rows' first two
+    fn nikon(samples: &[u16], width: usize, first: u16) -> Vec<u8> {
+        let tree = &NIKON_TREES[2];
+        let mut codes = [(0u32, 0u32); 16];
+        let (mut code, mut index) = (0u32, 16);
+        for length in 1..=16u32 {
+            for _ in 0..tree[length as usize - 1] {
+                codes[tree[index] as usize] = (code, length);
+                code += 1;
+                index += 1;
+            }
+            code <<= 1;
+        }
+
+        let (mut buffer, mut count) = (0u64, 0u32);
+        let mut bytes = Vec::new();
+        let mut put = |value: u32, bits: u32| {
+            buffer = buffer << bits | value as u64;
+            count += bits;
+            while count >= 8 {
+                bytes.push((buffer >> (count - 8)) as u8);
+                count -= 8;
+            }
+        };
+        for (index, &sample) in samples.iter().enumerate() {
+            let (y, x) = (index / width, index % width);
+            let prediction = match x < 2 {
+                true if y < 2 => first,
+                true => samples[index - 2 * width],
+                false => samples[index - 2],
+            };
+            let difference = sample as i32 - prediction as i32;
+            let length = 32 - difference.unsigned_abs().leading_zeros();
+            let (code, bits) = codes[length as usize];
+            put(code, bits);
+            if length > 0 {
+                let value = match difference < 0 {
+                    true => difference + (1 << length) - 1,
+                    false => difference,
+                };
+                put(value as u32, length);
+            }
+        }
+        put(0, 7);
+        bytes
+    }
+
+    #[test]
+    fn test_nef() {
+        // Black level 100 of 12 bits given for 14, red and blue at a half and a quarter
+        let (width, height) = (8, 6);
+        let samples = mosaic(width, height, [600, 1100, 350]);
+        let image = nikon(&samples, width, 2048);
+
+        // Version 0x46 with lossless data, the first predictions and no curve
+        let mut meta = vec![0x46, 0x30];
+        meta.extend(
+            [2048u16, 2048, 2048, 2048, 0]
+                .iter()
+                .flat_map(|v| v.to_le_bytes()),
+        );
+        let note_entries = directory(
+            vec![
+                (
+                    NIKON_WB_LEVELS,
+                    Value::Rational(vec![(2, 1), (4, 1), (1, 1), (1, 1)]),
+                ),
+                (NIKON_BLACK_LEVEL, Value::Short(vec![400; 4])),
+                (NIKON_DECOMPRESSION, Value::Byte(meta)),
+            ],
+            8,
+        );
+        let note = [
+            b"Nikon\0\x02\x10\0\0II*\0\x08\0\0\0".as_slice(),
+            &note_entries,
+        ]
+        .concat();
+
+        // The EXIF directory and the maker note go before the sensor data
+        let exif = 8;
+        let note_offset = exif + 2 + 12 + 4;
+        let mut prefix = directory(vec![(MAKER_NOTE, Value::Byte(note.clone()))], exif);
+        prefix.truncate(note_offset - exif);
+        prefix.extend_from_slice(&note);
+        let data_offset = exif + prefix.len();
+        prefix.extend_from_slice(&image);
+        let data = tiff(
+            vec![
+                (IMAGE_WIDTH, Value::Long(vec![width as u32])),
+                (IMAGE_LENGTH, Value::Long(vec![height as u32])),
+                (BITS_PER_SAMPLE, Value::Short(vec![12])),
+                (COMPRESSION, Value::Short(vec![NIKON_HUFFMAN as u16])),
+                (PHOTOMETRIC, Value::Short(vec![CFA as u16])),
+                (STRIP_OFFSETS, Value::Long(vec![data_offset as u32])),
+                (SAMPLES_PER_PIXEL, Value::Short(vec![1])),
+                (ROWS_PER_STRIP, Value::Long(vec![height as u32])),
+                (STRIP_BYTE_COUNTS, Value::Long(vec![image.len() as u32])),
+                (CFA_REPEAT_PATTERN_DIM, Value::Short(vec![2, 2])),
+                (CFA_PATTERN, Value::Byte(vec![0, 1, 1, 2])),
+                (EXIF_IFD, Value::Long(vec![exif as u32])),
+            ],
+            &prefix,
+        );
+        assert!(is_raw(&data));
+
+        // Developed as a DNG of the same samples and levels
+        let same = dng(
+            width as u32,
+            height as u32,
+            16,
+            &little_endian(&samples),
+            vec![
+                (BLACK_LEVEL, Value::Short(vec![100])),
+                (WHITE_LEVEL, Value::Short(vec![4095])),
+                (
+                    AS_SHOT_NEUTRAL,
+                    Value::Rational(vec![(1, 2), (1, 1), (1, 4)]),
+                ),
+            ],
+        );
+        let img = develop(&data, WhiteBalance::Camera, 0.0).unwrap();
+        assert_eq!(img, develop(&same, WhiteBalance::Camera, 0.0).unwrap());
+        let pixel = img.get_pixel(3, 3);
+        assert!(pixel[0].abs_diff(pixel[2]) <= 1, "{:?}", pixel);
+    }
+
+    #[test]
+    fn test_cr2() {
+        // Two masked columns of black on the left, then the exposed RGGB area
+        let (width, height) = (10, 6);
+        let scene = mosaic(8, height, [3000, 5000, 4000]);
+        let sensor: Vec<u16> = (0..width * height)
+            .map(|i| match i % width {
+                0 | 1 => 512,
+                x => scene[i / width * 8 + x - 2] + 512,
+            })
+            .collect();
+
+        // Stored a slice of 6 columns and then one of 4, each top to bottom
+        let mut sliced = Vec::new();
+        for columns in [0..6, 6..10] {
+            for y in 0..height {
+                sliced.extend_from_slice(&sensor[y * width..][columns.clone()]);
+            }
+        }
+        let jpeg = lossless(&sliced, width / 2, height, 2, 1);
+
+        let info = [0, width, height, 0, 0, 2, 0, width - 1, height - 1].map(|v| v as u16);
+        let exif = 12;
+        let note = exif + 2 + 12 + 4;
+        let mut prefix = b"CR\x02\0".to_vec();
+        prefix.resize(exif - 8, 0);
+        let mut exif_ifd = directory(vec![(MAKER_NOTE, Value::Byte(vec![0; 8]))], exif);
+        exif_ifd.truncate(note - exif);
+        prefix.extend_from_slice(&exif_ifd);
+        let note_ifd = directory(vec![(CANON_SENSOR_INFO, Value::Short(info.to_vec()))], note);
+        prefix.extend_from_slice(&note_ifd);
+        // The maker note's entry points at its directory
+        let entry = exif - 8 + 2;
+        prefix[entry + 4..entry + 8].copy_from_slice(&(note_ifd.len() as u32).to_le_bytes());
+        prefix[entry + 8..entry + 12].copy_from_slice(&(note as u32).to_le_bytes());
+        let strip = 8 + prefix.len();
+        prefix.extend_from_slice(&jpeg);
+        let data = tiff(
+            vec![
+                (COMPRESSION, Value::Short(vec![CANON_JPEG as u16])),
+                (STRIP_OFFSETS, Value::Long(vec![strip as u32])),
+                (STRIP_BYTE_COUNTS, Value::Long(vec![jpeg.len() as u32])),
+                (CR2_SLICES, Value::Short(vec![1, 6, 4])),
+                (EXIF_IFD, Value::Long(vec![exif as u32])),
+            ],
+            &prefix,
+        );
+        assert!(is_raw(&data));
+
+        let same = dng(
+            width as u32,
+            height as u32,
+            16,
+            &little_endian(&sensor),
+            vec![
+                (
+                    ACTIVE_AREA,
+                    Value::Short(vec![0, 2, height as u16, width as u16]),
+                ),
+                (BLACK_LEVEL, Value::Short(vec![512])),
+            ],
+        );
+        let img = develop(&data, WhiteBalance::Auto, 0.0).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 6));
+        assert_eq!(img, develop(&same, WhiteBalance::Auto, 0.0).unwrap());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{Command, ImgtoolsError, Pipeline, Step};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::Path;
+
+/// Read a recipe file into the command it describes
+///
+/// A recipe is JSON, either a list of steps or an object with a `steps` list.
+/// A step is a command name, or an object naming the command with its
+/// parameters, e.g. `{"resize": {"width": 800, "height": 600, "filter": "lanczos3"}}`.
+/// Parameters use the long or short option names of the command line, and
+/// missing ones get the same defaults.
+pub fn load_recipe(path: &Path) -> Result<Command, ImgtoolsError> {
+    let text = fs::read_to_string(path).map_err(|source| ImgtoolsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let yaml = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml" | "yml")
+    );
+    parse_recipe(&text).map_err(|message| ImgtoolsError::Config {
+        path: path.to_path_buf(),
+        message: match yaml {
+            true => format!("{}, YAML recipes must be written in JSON syntax", message),
+            false => message,
+        },
+    })
+}
+
+/// Parse the text of a JSON recipe, see `load_recipe`
+pub fn parse_recipe(text: &str) -> Result<Command, String> {
+    let recipe: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let steps = match &recipe {
+        Value::Array(steps) => steps,
+        Value::Object(recipe) => match recipe.get("steps") {
+            Some(Value::Array(steps)) => steps,
+            _ => return Err("The recipe needs a list of steps".into()),
+        },
+        _ => return Err("A recipe is a list of steps or an object with steps".into()),
+    };
+
+    let mut commands = Vec::with_capacity(steps.len());
+    for (i, step) in steps.iter().enumerate() {
+        let (name, params) = match step {
+            Value::String(name) => (name.as_str(), &Value::Null),
+            Value::Object(step) if step.len() == 1 => step
+                .iter()
+                .next()
+                .map(|(name, params)| (name.as_str(), params))
+                .unwrap(),
+            _ => return Err(format!("Step {} must name one command", i + 1)),
+        };
+        let command = parse_step(name, params).map_err(|e| format!("Step {}: {}", i + 1, e))?;
+        if let Command::Pipeline { .. } | Command::Run { .. } = command {
+            return Err(format!("Step {}: {} can't be a recipe step", i + 1, name));
+        }
+        commands.push(command);
+    }
+
+    match commands.len() {
+        0 => Err("The recipe has no steps".into()),
+        // A single step stays a plain command, e.g. montage or info
+        1 => Ok(commands.remove(0)),
+        _ => match commands.iter().find(|command| command.is_report()) {
+            Some(report) => Err(format!(
+                "{} can't be combined with other steps",
+                report.name()
+            )),
+            None => Ok(Command::Pipeline {
+                steps: Pipeline(commands),
+            }),
+        },
+    }
+}
+
+/// Parse one command from its name and parameters, as a recipe step has them
+pub(crate) fn parse_step(name: &str, params: &Value) -> Result<Command, String> {
+    let mut args = Vec::new();
+    expand_step(Step::parser(), name, params, &mut args)?;
+    Step::parse(&args).map_err(|e| format!("Invalid {} parameters: {}", name, e.render()))
+}
+
+/// Turn a step and its parameters into command line arguments for the
+/// subcommand `name` of `parent`
+fn expand_step(
+    parent: &clap::Command,
+    name: &str,
+    params: &Value,
+    args: &mut Vec<String>,
+) -> Result<(), String> {
+    let command = parent
+        .find_subcommand(name)
+        .ok_or_else(|| format!("Unknown command {}", name))?;
+    args.push(command.get_name().to_string());
+
+    let empty = Map::new();
+    let params = match params {
+        Value::Null => &empty,
+        Value::Object(params) => params,
+        // A nested subcommand without parameters, e.g. {"channels": "split"}
+        Value::String(nested) => return expand_step(command, nested, &Value::Null, args),
+        _ => return Err(format!("Parameters of {} must be an object", name)),
+    };
+
+    // Options in declaration order, so positional values line up
+    let mut used = Vec::new();
+    for option in command.get_arguments() {
+        let id = option.get_id().as_str();
+        let long = option.get_long();
+        let short = option.get_short();
+        let Some((key, value)) = params.iter().find(|(key, _)| {
+            key.replace('-', "_") == id
+                || Some(key.as_str()) == long
+                || short.is_some_and(|short| key.chars().eq([short]))
+        }) else {
+            continue;
+        };
+        used.push(key.as_str());
+
+        // Flags are switched on by true
+        if !option.get_action().takes_values() {
+            match value {
+                Value::Bool(true) => args.push(format!("--{}", long.unwrap_or(id))),
+                Value::Bool(false) => {}
+                _ => return Err(format!("{} must be true or false", key)),
+            }
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.iter().map(scalar).collect::<Result<Vec<_>, _>>()?,
+            value => vec![scalar(value)?],
+        };
+        for value in values {
+            match long {
+                Some(long) => args.push(format!("--{}={}", long, value)),
+                None => args.push(value),
+            }
+        }
+    }
+
+    // Whatever is left names a nested subcommand, e.g. watermark text
+    for (key, value) in params {
+        if used.contains(&key.as_str()) {
+            continue;
+        }
+        if command.find_subcommand(key).is_none() {
+            return Err(format!("Unknown parameter {} for {}", key, name));
+        }
+        expand_step(command, key, value, args)?;
+    }
+    Ok(())
+}
+
+/// Command line text of a single parameter value
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(format!("Unsupported parameter value {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channels, Filter, Format};
+
+    #[test]
+    fn test_parse_recipe() {
+        let command = parse_recipe(
+            r#"{"steps": [
+                {"resize": {"width": 800, "height": 600, "filter": "lanczos3"}},
+                "grayscale",
+                {"flip": {"horizontal": true, "vertical": false}},
+                {"convert": {"format": "webp"}}
+            ]}"#,
+        )
+        .unwrap();
+        let steps = command.steps();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(
+            steps[0],
+            &Command::Resize {
+                width: Some(800),
+                height: Some(600),
+                exact: false,
+                filter: Filter::Lanczos3,
+                scale: None,
+                fast: false,
+            }
+        );
+        assert_eq!(
+            steps[2],
+            &Command::Flip {
+                horizontal: true,
+                vertical: false,
+            }
+        );
+        assert_eq!(
+            steps[3],
+            &Command::Convert {
+                format: Format::WebP
+            }
+        );
+
+        // Nested subcommands and single steps
+        assert_eq!(
+            parse_recipe(r#"[{"channels": "split"}]"#).unwrap(),
+            Command::Channels {
+                command: Channels::Split
+            }
+        );
+        assert!(matches!(
+            parse_recipe(r#"[{"diff": {"other": "b.png", "format": "text"}}]"#).unwrap(),
+            Command::Diff { .. }
+        ));
+        assert!(matches!(
+            parse_recipe(r#"[{"resize": {"w": 80, "f": "nearest"}}]"#).unwrap(),
+            Command::Resize {
+                width: Some(80),
+                filter: Filter::Nearest,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_invalid_recipes() {
+        for (recipe, error) in [
+            ("[]", "The recipe has no steps"),
+            (r#"["sparkle"]"#, "Step 1: Unknown command sparkle"),
+            (
+                r#"[{"blur": {"radius": 2}}]"#,
+                "Step 1: Unknown parameter radius",
+            ),
+            (
+                r#"["invert", {"resize": {"width": 10}}]"#,
+                "Step 2: Invalid resize parameters",
+            ),
+            (r#"["invert", {"info": {}}]"#, "info can't be combined"),
+            (r#"{"step": []}"#, "The recipe needs a list of steps"),
+        ] {
+            let message = parse_recipe(recipe).unwrap_err();
+            assert!(message.starts_with(error), "{}: {}", recipe, message);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use crate::logging::{Level, log, log_with};
+use crate::recipe::parse_step;
+use crate::{Command, DecodeOptions, ImgtoolsError, Pipeline, ProcessOptions, process_bytes};
+use image::ImageError;
+use serde_json::{Map, Value, json};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a client may take to send its request
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most header lines read before a request is refused
+const MAX_HEADERS: usize = 100;
+
+/// Connections waiting for a free worker, more are answered with 503
+const MAX_QUEUED: usize = 64;
+
+/// Stack of every worker thread, decoders and the SVG renderer recurse on
+/// nested input
+const STACK_SIZE: usize = 16 << 20;
+
+/// Largest decoded image served unless --max-memory gives another size
+const MAX_DECODED: u64 = 512 << 20;
+
+/// Status, content type and body of a response
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+/// Transform images of the root directories on request until interrupted
+///
+/// The request path names the command and the query holds its parameters by
+/// long or short option name, plus `src`, the image path relative to a root,
+/// e.g. `/resize?w=800&h=600&f=lanczos3&src=photos/cat.jpg`. Repeated parameters give
+/// several values and a parameter without a value switches a flag on.
+/// `/pipeline?steps=...` runs several commands written as on the command line.
+/// Files named by other parameters, such as overlays and masks, must be in
+/// the roots as well.
+///
+/// As many requests are answered at once as there are cores, images that
+/// decode to more than `--max-memory` bytes, 512 MiB by default, are refused,
+/// as are commands that would make a larger one, e.g. a resize to 100000x100000.
+pub fn serve(
+    address: &str,
+    roots: &[PathBuf],
+    options: &ProcessOptions,
+) -> Result<(), ImgtoolsError> {
+    let roots = roots
+        .iter()
+        .map(|root| match root.is_dir() {
+            true => root.canonicalize().map_err(|source| ImgtoolsError::Read {
+                path: root.clone(),
+                source,
+            }),
+            false => Err(ImgtoolsError::InvalidArgument(format!(
+                "Serving needs input directories, {} is not one",
+                root.display()
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let listener = TcpListener::bind(address).map_err(|source| ImgtoolsError::Listen {
+        address: address.to_string(),
+        source,
+    })?;
+    if let Ok(address) = listener.local_addr() {
+        log(
+            Level::Info,
+            format_args!("Serving on http://{}, press Ctrl-C to stop", address),
+        );
+    }
+    let options = ProcessOptions {
+        decoding: DecodeOptions {
+            max_alloc: Some(options.max_memory.unwrap_or(MAX_DECODED)),
+            ..options.decoding
+        },
+        ..options.clone()
+    };
+
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED);
+    let receiver = Mutex::new(receiver);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let spawned = thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, || work(&receiver, &roots, &options));
+            if let Err(source) = spawned {
+                // Without a sender the workers already running stop
+                drop(sender);
+                return Err(ImgtoolsError::Listen {
+                    address: address.to_string(),
+                    source,
+                });
+            }
+        }
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            if let Err(TrySendError::Full(stream)) = sender.try_send(stream) {
+                let busy = error(503, "Too many requests, try again later".into());
+                if let Err(e) = send(stream, "GET", &busy) {
+                    log(Level::Error, e);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Answer the connections the listener hands over until it stops
+fn work(connections: &Mutex<Receiver<TcpStream>>, roots: &[PathBuf], options: &ProcessOptions) {
+    loop {
+        // The lock is only held while waiting, not while answering
+        let next = match connections.lock() {
+            Ok(connections) => connections.recv(),
+            Err(_) => return,
+        };
+        let Ok(stream) = next else {
+            return;
+        };
+        if let Err(e) = handle(stream, roots, options) {
+            log(Level::Error, e);
+        }
+    }
+}
+
+/// Read one request from the connection and send the answer
+fn handle(stream: TcpStream, roots: &[PathBuf], options: &ProcessOptions) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+
+    // Skip the headers, nothing in them changes the answer
+    let mut headers = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Too many headers",
+            ));
+        }
+    }
+
+    let start = Instant::now();
+    let response = match (method, target) {
+        ("GET" | "HEAD", Some(target)) => answer(target, roots, options),
+        (_, Some(_)) => error(405, "Only GET and HEAD requests are supported".into()),
+        (_, None) => error(400, "Malformed request line".into()),
+    };
+    let mut fields = Map::new();
+    fields.insert("status".into(), json!(response.status));
+    fields.insert("method".into(), json!(method));
+    fields.insert("target".into(), json!(target.unwrap_or_default()));
+    fields.insert("bytes".into(), json!(response.body.len()));
+    fields.insert(
+        "duration_ms".into(),
+        json!(start.elapsed().as_secs_f64() * 1000.0),
+    );
+    log_with(
+        Level::Info,
+        format_args!(
+            "{} {} {}",
+            response.status,
+            method,
+            target.unwrap_or_default()
+        ),
+        fields,
+    );
+    send(stream, method, &response)
+}
+
+/// Write a response and close the connection, without the body for HEAD
+fn send(mut stream: TcpStream, method: &str, response: &Response) -> io::Result<()> {
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    if method != "HEAD" {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()
+}
+
+/// Run the command a request target asks for
+fn answer(target: &str, roots: &[PathBuf], options: &ProcessOptions) -> Response {
+    let (mut command, src) = match parse_target(target) {
+        Ok(request) => request,
+        Err(message) => return error(400, message),
+    };
+    if command.is_report() || command.combines_inputs() {
+        return error(400, format!("{} doesn't produce an image", command.name()));
+    }
+    let path = match source(&src, roots) {
+        Some(path) => path,
+        None => return error(404, format!("{} not found", src)),
+    };
+    // Every other file is looked up in the roots like the source image
+    for file in command.files_mut() {
+        let name = file.to_string_lossy().into_owned();
+        match source(&name, roots) {
+            Some(path) => *file = path,
+            None => return error(404, format!("{} not found", name)),
+        }
+    }
+    for spec in command.fonts_mut() {
+        match fonts(spec, roots) {
+            Ok(fonts) => *spec = fonts,
+            Err(message) => return error(400, message),
+        }
+    }
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => return error(500, format!("Failed to read {}: {}", src, e)),
+    };
+
+    match process_bytes(&data, &command, options) {
+        Ok((body, format)) => Response {
+            status: 200,
+            content_type: format.mime_type(),
+            body,
+        },
+        Err(e @ ImgtoolsError::InvalidArgument(_)) => error(400, e.to_string()),
+        Err(ImgtoolsError::Decode(ImageError::Limits(_))) => error(
+            413,
+            format!("{} or the image made of it is too large to hold", src),
+        ),
+        Err(e @ ImgtoolsError::Decode(_)) => error(415, e.to_string()),
+        Err(e) => error(500, e.to_string()),
+    }
+}
+
+/// Parse the command and the source image of a request target
+fn parse_target(target: &str) -> Result<(Command, String), String> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let name = decode(path.trim_start_matches('/'))?;
+    let mut src = None;
+    let mut params = Map::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, "true"));
+        let (key, value) = (decode(key)?, decode(value)?);
+        if key == "src" {
+            src = Some(value);
+            continue;
+        }
+        let value = match value.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(value),
+        };
+        // Repeated parameters collect their values, e.g. sizes=16&sizes=32
+        match params.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(first) => *first = Value::Array(vec![first.take(), value]),
+            None => {
+                params.insert(key, value);
+            }
+        }
+    }
+    let src = src.ok_or("The image to transform must be given with src")?;
+
+    let command = match name.as_str() {
+        "" => return Err("The path must name a command, e.g. /resize".into()),
+        "pipeline" => {
+            let Some(Value::String(steps)) = params.get("steps") else {
+                return Err("A pipeline needs its steps, e.g. steps=grayscale|invert".into());
+            };
+            let Pipeline(mut steps) = steps.parse()?;
+            match steps.len() {
+                1 => steps.remove(0),
+                _ => Command::Pipeline {
+                    steps: Pipeline(steps),
+                },
+            }
+        }
+        name => parse_step(name, &Value::Object(params))?,
+    };
+    Ok((command, src))
+}
+
+/// Find the source image inside one of the canonical roots
+///
+/// Only plain relative paths are accepted, and links may not lead out of the root.
+fn source(src: &str, roots: &[PathBuf]) -> Option<PathBuf> {
+    let relative = Path::new(src);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    roots.iter().find_map(|root| {
+        let path = root.join(relative).canonicalize().ok()?;
+        (path.starts_with(root) && path.is_file()).then_some(path)
+    })
+}
+
+/// Keep a font list to font files inside the roots and installed family names
+///
+/// A name that is no file in the roots is refused if it is a path or a file
+/// in the working directory, with the answer an unknown family gets.
+fn fonts(spec: &Path, roots: &[PathBuf]) -> Result<PathBuf, String> {
+    let spec = spec.to_string_lossy();
+    let entries = spec.split(',').map(str::trim).filter(|e| !e.is_empty());
+    let fonts = entries
+        .map(|entry| {
+            let name = Path::new(entry);
+            match source(entry, roots) {
+                Some(path) => Ok(path.to_string_lossy().into_owned()),
+                None if name.components().count() == 1 && !name.exists() => Ok(entry.to_string()),
+                None => Err(format!(
+                    "No font file or installed font family named {}",
+                    entry
+                )),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PathBuf::from(fonts.join(",")))
+}
+
+/// Decode `%XX` escapes and `+` for spaces
+fn decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid escape in {}", s))?;
+                rest = &rest[2..];
+                hex
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| format!("{} is not valid UTF-8", s))
+}
+
+fn error(status: u16, message: String) -> Response {
+    Response {
+        status,
+        content_type: "text/plain; charset=utf-8",
+        body: (message + "\n").into_bytes(),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Filter, Format, encode};
+    use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_target() {
+        let (command, src) =
+            parse_target("/resize?w=80&h=60&f=lanczos3&src=photos%2Fmy+cat.jpg").unwrap();
+        assert_eq!(src, "photos/my cat.jpg");
+        assert_eq!(
+            command,
+            Command::Resize {
+                width: Some(80),
+                height: Some(60),
+                exact: false,
+                filter: Filter::Lanczos3,
+                scale: None,
+                fast: false,
+            }
+        );
+
+        let (command, _) = parse_target("/flip?horizontal&src=a.png").unwrap();
+        assert!(matches!(
+            command,
+            Command::Flip {
+                horizontal: true,
+                vertical: false
+            }
+        ));
+        let (command, _) =
+            parse_target("/pipeline?steps=grayscale%20%7C%20invert&src=a.png").unwrap();
+        assert_eq!(command.steps(), [&Command::Grayscale, &Command::Invert]);
+
+        for (target, message) in [
+            ("/grayscale", "The image to transform"),
+            ("/?src=a.png", "The path must name"),
+            ("/sparkle?src=a.png", "Unknown command"),
+            ("/grayscale?src=%zz", "Invalid escape"),
+        ] {
+            let error = parse_target(target).unwrap_err();
+            assert!(error.starts_with(message), "{}: {}", target, error);
+        }
+    }
+
+    #[test]
+    fn test_answer() {
+        let dir = std::env::temp_dir().join("imgtools_test_serve");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 2, Rgb([10, 20, 30])));
+        let mut data = Cursor::new(Vec::new());
+        encode(&img, Format::Png, &mut data).unwrap();
+        fs::write(dir.join("a.png"), data.into_inner()).unwrap();
+        let roots = [dir.canonicalize().unwrap()];
+        let options = ProcessOptions::default();
+
+        let response = answer("/convert?format=bmp&src=a.png", &roots, &options);
+        assert_eq!((response.status, response.content_type), (200, "image/bmp"));
+        let converted = image::load_from_memory(&response.body).unwrap();
+        assert_eq!(converted.dimensions(), (4, 2));
+
+        // Paths may not leave the roots
+        for (target, status) in [
+            ("/invert?src=../a.png", 404),
+            ("/invert?src=%2Fetc%2Fpasswd", 404),
+            ("/invert?src=b.png", 404),
+            ("/info?src=a.png", 400),
+            ("/frames?src=a.png", 400),
+        ] {
+            assert_eq!(
+                answer(target, &roots, &options).status,
+                status,
+                "{}",
+                target
+            );
+        }
+
+        // So may the overlays, masks and fonts other parameters name, a file
+        // outside gets the answer of a missing one
+        let outside = std::env::temp_dir().join("imgtools_test_serve_outside.png");
+        fs::copy(dir.join("a.png"), &outside).unwrap();
+        let escaped = outside.to_string_lossy().replace('/', "%2F");
+        for (target, status) in [
+            ("/composite?overlay=a.png&src=a.png".to_string(), 200),
+            (
+                "/composite?overlay=../imgtools_test_serve_outside.png&src=a.png".into(),
+                404,
+            ),
+            (format!("/composite?overlay={}&src=a.png", escaped), 404),
+            ("/composite?overlay=%2Fetc%2Fmissing&src=a.png".into(), 404),
+            (
+                format!(
+                    "/pipeline?steps=invert%7Ccomposite+--overlay+{}&src=a.png",
+                    escaped
+                ),
+                404,
+            ),
+        ] {
+            assert_eq!(
+                answer(&target, &roots, &options).status,
+                status,
+                "{}",
+                target
+            );
+        }
+        let response = answer(
+            &format!("/caption?text=hi&font={}&src=a.png", escaped),
+            &roots,
+            &options,
+        );
+        assert!(response.body.starts_with(b"No font file"));
+        fs::remove_file(&outside).unwrap();
+
+        // Images that decode to more than the limit are refused
+        let limited = ProcessOptions {
+            decoding: DecodeOptions {
+                max_alloc: Some(4 * 2 * 3 - 1),
+                ..DecodeOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        assert_eq!(answer("/invert?src=a.png", &roots, &limited).status, 413);
+
+        // So are results and steps larger than it, checked before they are made
+        let limited = ProcessOptions {
+            decoding: DecodeOptions {
+                max_alloc: Some(4 * 2 * 4),
+                ..DecodeOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        for (target, status) in [
+            ("/invert?src=a.png", 200),
+            ("/resize?w=2&h=1&f=nearest&src=a.png", 200),
+            ("/resize?w=100000&h=100000&f=nearest&exact&src=a.png", 413),
+            ("/upscale?factor=2&src=a.png", 413),
+            ("/pad?top=1&src=a.png", 413),
+            ("/border?width=1&src=a.png", 413),
+            (
+                "/pipeline?steps=resize+-w+8+-f+nearest%7Cresize+-w+2+-f+nearest&src=a.png",
+                413,
+            ),
+        ] {
+            assert_eq!(
+                answer(target, &roots, &limited).status,
+                status,
+                "{}",
+                target
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
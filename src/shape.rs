@@ -0,0 +1,375 @@
+/// Arabic letters with how they join and their first presentation form
+///
+/// The forms follow each other as isolated, final, initial and medial.
+/// Letters that only join the letter before them have the first two.
+const ARABIC_FORMS: [(char, Joining, char); 43] = [
+    ('\u{0621}', Joining::None, '\u{FE80}'),
+    ('\u{0622}', Joining::Right, '\u{FE81}'),
+    ('\u{0623}', Joining::Right, '\u{FE83}'),
+    ('\u{0624}', Joining::Right, '\u{FE85}'),
+    ('\u{0625}', Joining::Right, '\u{FE87}'),
+    ('\u{0626}', Joining::Dual, '\u{FE89}'),
+    ('\u{0627}', Joining::Right, '\u{FE8D}'),
+    ('\u{0628}', Joining::Dual, '\u{FE8F}'),
+    ('\u{0629}', Joining::Right, '\u{FE93}'),
+    ('\u{062A}', Joining::Dual, '\u{FE95}'),
+    ('\u{062B}', Joining::Dual, '\u{FE99}'),
+    ('\u{062C}', Joining::Dual, '\u{FE9D}'),
+    ('\u{062D}', Joining::Dual, '\u{FEA1}'),
+    ('\u{062E}', Joining::Dual, '\u{FEA5}'),
+    ('\u{062F}', Joining::Right, '\u{FEA9}'),
+    ('\u{0630}', Joining::Right, '\u{FEAB}'),
+    ('\u{0631}', Joining::Right, '\u{FEAD}'),
+    ('\u{0632}', Joining::Right, '\u{FEAF}'),
+    ('\u{0633}', Joining::Dual, '\u{FEB1}'),
+    ('\u{0634}', Joining::Dual, '\u{FEB5}'),
+    ('\u{0635}', Joining::Dual, '\u{FEB9}'),
+    ('\u{0636}', Joining::Dual, '\u{FEBD}'),
+    ('\u{0637}', Joining::Dual, '\u{FEC1}'),
+    ('\u{0638}', Joining::Dual, '\u{FEC5}'),
+    ('\u{0639}', Joining::Dual, '\u{FEC9}'),
+    ('\u{063A}', Joining::Dual, '\u{FECD}'),
+    ('\u{0641}', Joining::Dual, '\u{FED1}'),
+    ('\u{0642}', Joining::Dual, '\u{FED5}'),
+    ('\u{0643}', Joining::Dual, '\u{FED9}'),
+    ('\u{0644}', Joining::Dual, '\u{FEDD}'),
+    ('\u{0645}', Joining::Dual, '\u{FEE1}'),
+    ('\u{0646}', Joining::Dual, '\u{FEE5}'),
+    ('\u{0647}', Joining::Dual, '\u{FEE9}'),
+    ('\u{0648}', Joining::Right, '\u{FEED}'),
+    ('\u{0649}', Joining::Right, '\u{FEEF}'),
+    ('\u{064A}', Joining::Dual, '\u{FEF1}'),
+    // Persian letters
+    ('\u{067E}', Joining::Dual, '\u{FB56}'),
+    ('\u{0686}', Joining::Dual, '\u{FB7A}'),
+    ('\u{0698}', Joining::Right, '\u{FB8A}'),
+    ('\u{06A9}', Joining::Dual, '\u{FB8E}'),
+    ('\u{06AF}', Joining::Dual, '\u{FB92}'),
+    ('\u{06CC}', Joining::Dual, '\u{FBFC}'),
+    // Tatweel joins on both sides and has no forms of its own
+    ('\u{0640}', Joining::Dual, '\u{0640}'),
+];
+
+/// Alef variants that join a lam before them into one ligature, and the ligature's isolated form
+const LAM_ALEF: [(char, char); 4] = [
+    ('\u{0622}', '\u{FEF5}'),
+    ('\u{0623}', '\u{FEF7}'),
+    ('\u{0625}', '\u{FEF9}'),
+    ('\u{0627}', '\u{FEFB}'),
+];
+
+/// Brackets swapped when drawn right to left
+const MIRRORED: [(char, char); 5] = [('(', ')'), ('[', ']'), ('{', '}'), ('<', '>'), ('«', '»')];
+
+/// How an Arabic letter connects to its neighbours
+#[derive(Clone, Copy, PartialEq)]
+enum Joining {
+    /// Joins neither neighbour, as hamza
+    None,
+    /// Joins only the letter before it, as alef
+    Right,
+    /// Joins the letters on both sides
+    Dual,
+}
+
+/// Direction of a character for ordering a line
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Class {
+    Left,
+    Right,
+    /// European digits
+    Number,
+    /// Arabic-Indic digits
+    ArabicNumber,
+    /// Spaces and punctuation, which take the direction around them
+    Neutral,
+    /// Combining marks, which go with the character before them
+    Mark,
+}
+
+/// Prepare a line of text for drawing glyph by glyph from left to right
+///
+/// Arabic letters are replaced by their joined forms, Devanagari vowel signs
+/// written after a consonant but drawn before it are moved in front, and
+/// right to left text such as Arabic and Hebrew is put in visual order.
+/// Conjuncts and other font specific shaping are not applied.
+pub(crate) fn shape(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let chars = reorder_devanagari(join_arabic(&chars));
+    visual_order(&chars).into_iter().collect()
+}
+
+/// Whether the character is an Arabic combining mark, skipped when letters join
+fn is_transparent(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0610}'..='\u{061A}' | '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{06D6}'..='\u{06ED}'
+    )
+}
+
+fn joining(c: char) -> Option<(Joining, char)> {
+    ARABIC_FORMS
+        .iter()
+        .find(|&&(letter, _, _)| letter == c)
+        .map(|&(_, joining, form)| (joining, form))
+}
+
+/// Replace Arabic letters by the presentation form for their position in a word
+fn join_arabic(chars: &[char]) -> Vec<char> {
+    // The neighbouring letters of each character, ignoring marks between them
+    let neighbour = |at: usize, step: isize| {
+        let mut i = at as isize + step;
+        while i >= 0 && (i as usize) < chars.len() && is_transparent(chars[i as usize]) {
+            i += step;
+        }
+        chars.get(i as usize).filter(|_| i >= 0).copied()
+    };
+    let joins_next = |c: Option<char>| matches!(c.and_then(joining), Some((Joining::Dual, _)));
+    let joins_previous = |c: Option<char>| {
+        matches!(
+            c.and_then(joining),
+            Some((Joining::Dual | Joining::Right, _))
+        )
+    };
+
+    let mut shaped = Vec::with_capacity(chars.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let Some((kind, form)) = joining(c) else {
+            shaped.push(c);
+            i += 1;
+            continue;
+        };
+        let before = kind != Joining::None && joins_next(neighbour(i, -1));
+
+        // Lam followed by alef is written as one ligature, which only joins the letter before
+        let ligature = LAM_ALEF
+            .iter()
+            .find(|&&(alef, _)| c == '\u{0644}' && chars.get(i + 1) == Some(&alef));
+        if let Some(&(_, isolated)) = ligature {
+            shaped.push(char_offset(isolated, before as u32));
+            i += 2;
+            continue;
+        }
+
+        let after = kind == Joining::Dual && joins_previous(neighbour(i, 1));
+        let offset = match (before, after) {
+            _ if c == '\u{0640}' => 0,
+            (false, false) => 0,
+            (true, false) => 1,
+            (false, true) => 2,
+            (true, true) => 3,
+        };
+        shaped.push(char_offset(form, offset));
+        i += 1;
+    }
+    shaped
+}
+
+fn char_offset(c: char, offset: u32) -> char {
+    char::from_u32(c as u32 + offset).unwrap_or(c)
+}
+
+fn is_devanagari_consonant(c: char) -> bool {
+    matches!(c, '\u{0915}'..='\u{0939}' | '\u{0958}'..='\u{095F}')
+}
+
+/// Move the short i vowel sign in front of the consonant cluster it follows
+fn reorder_devanagari(mut chars: Vec<char>) -> Vec<char> {
+    for i in 1..chars.len() {
+        if chars[i] != '\u{093F}' || !is_devanagari_consonant(chars[i - 1]) {
+            continue;
+        }
+        // Consonants joined by a virama form one cluster
+        let mut start = i - 1;
+        while start >= 2
+            && chars[start - 1] == '\u{094D}'
+            && is_devanagari_consonant(chars[start - 2])
+        {
+            start -= 2;
+        }
+        chars[start..=i].rotate_right(1);
+    }
+    chars
+}
+
+fn class(c: char) -> Class {
+    match c {
+        '0'..='9' => Class::Number,
+        '\u{0660}'..='\u{0669}' | '\u{06F0}'..='\u{06F9}' => Class::ArabicNumber,
+        _ if is_transparent(c) => Class::Mark,
+        '\u{0591}'..='\u{05BD}'
+        | '\u{05BF}'
+        | '\u{05C1}'
+        | '\u{05C2}'
+        | '\u{05C4}'
+        | '\u{05C5}'
+        | '\u{05C7}' => Class::Mark,
+        '\u{0590}'..='\u{08FF}' | '\u{FB1D}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFF}' => Class::Right,
+        '\u{0300}'..='\u{036F}' | '\u{0900}'..='\u{0903}' | '\u{093A}'..='\u{094F}' => Class::Mark,
+        _ if c.is_alphabetic() => Class::Left,
+        _ => Class::Neutral,
+    }
+}
+
+/// Reorder a line from reading order to the left to right order it is drawn in
+///
+/// A simplified Unicode bidirectional algorithm without explicit embeddings:
+/// the first strong character sets the line's direction, digits keep their
+/// order inside right to left text and neutrals between two runs of the same
+/// direction take it. Marks stay after the letter they belong to.
+fn visual_order(chars: &[char]) -> Vec<char> {
+    let mut classes: Vec<Class> = chars.iter().map(|&c| class(c)).collect();
+    let rtl = classes
+        .iter()
+        .find(|&&class| matches!(class, Class::Left | Class::Right))
+        .is_some_and(|&class| class == Class::Right);
+    if !rtl && !classes.contains(&Class::Right) {
+        return chars.to_vec();
+    }
+    let base = if rtl { Class::Right } else { Class::Left };
+
+    // Marks take the class of the character before them, digits after left to right text are left
+    let mut strong = base;
+    for i in 0..classes.len() {
+        if classes[i] == Class::Mark {
+            classes[i] = if i == 0 {
+                Class::Neutral
+            } else {
+                classes[i - 1]
+            };
+        }
+        match classes[i] {
+            Class::Left | Class::Right => strong = classes[i],
+            Class::Number if strong == Class::Left => classes[i] = Class::Left,
+            _ => {}
+        }
+    }
+
+    // Neutrals take the direction of their neighbours if they agree, otherwise the line's
+    let direction = |class: Class| match class {
+        Class::Left => Class::Left,
+        _ => Class::Right,
+    };
+    let mut i = 0;
+    while i < classes.len() {
+        if classes[i] != Class::Neutral {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < classes.len() && classes[i] == Class::Neutral {
+            i += 1;
+        }
+        let before = start.checked_sub(1).map_or(base, |j| direction(classes[j]));
+        let after = classes.get(i).map_or(base, |&class| direction(class));
+        let resolved = if before == after { before } else { base };
+        classes[start..i].fill(resolved);
+    }
+
+    let levels: Vec<u8> = classes
+        .iter()
+        .map(|&class| match (rtl, class) {
+            (false, Class::Left) => 0,
+            (false, Class::Right) => 1,
+            (false, _) => 2,
+            (true, Class::Right) => 1,
+            (true, _) => 2,
+        })
+        .collect();
+
+    // Reverse runs at or above each level, highest first, keeping marks after their letter
+    let mut clusters: Vec<(u8, Vec<char>)> = Vec::new();
+    for (&c, &level) in chars.iter().zip(&levels) {
+        match clusters.last_mut() {
+            Some((_, cluster)) if class(c) == Class::Mark => cluster.push(c),
+            _ => clusters.push((level, vec![c])),
+        }
+    }
+    let highest = levels.iter().copied().max().unwrap_or(0);
+    for level in (1..=highest).rev() {
+        let mut i = 0;
+        while i < clusters.len() {
+            if clusters[i].0 < level {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < clusters.len() && clusters[i].0 >= level {
+                i += 1;
+            }
+            clusters[start..i].reverse();
+        }
+    }
+
+    clusters
+        .into_iter()
+        .flat_map(|(level, cluster)| {
+            cluster.into_iter().map(move |c| match level % 2 {
+                1 => mirror(c),
+                _ => c,
+            })
+        })
+        .collect()
+}
+
+fn mirror(c: char) -> char {
+    MIRRORED
+        .iter()
+        .find_map(|&(open, close)| match c {
+            _ if c == open => Some(close),
+            _ if c == close => Some(open),
+            _ => None,
+        })
+        .unwrap_or(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_arabic() {
+        // مرحبا: meem initial, reh final, hah initial, beh medial, alef final
+        assert_eq!(
+            join_arabic(&"مرحبا".chars().collect::<Vec<_>>()),
+            ['\u{FEE3}', '\u{FEAE}', '\u{FEA3}', '\u{FE92}', '\u{FE8E}']
+        );
+        // Marks don't break the joining, lam alef becomes a ligature
+        assert_eq!(
+            join_arabic(&"بَلا".chars().collect::<Vec<_>>()),
+            ['\u{FE91}', '\u{064E}', '\u{FEFC}']
+        );
+        assert_eq!(join_arabic(&['\u{0628}']), ['\u{FE8F}']);
+    }
+
+    #[test]
+    fn test_visual_order() {
+        let order = |text: &str| {
+            visual_order(&text.chars().collect::<Vec<_>>())
+                .into_iter()
+                .collect::<String>()
+        };
+        assert_eq!(order("abc"), "abc");
+        assert_eq!(order("שלום"), "םולש");
+        // Digits keep their order, brackets are mirrored
+        assert_eq!(order("שלום 123 (עולם)"), "(םלוע) 123 םולש");
+        // Right to left words inside left to right text
+        assert_eq!(order("say שלום now"), "say םולש now");
+        // Hebrew points stay after their letter
+        assert_eq!(order("שָׁלוֹם"), "םוֹלשָׁ");
+    }
+
+    #[test]
+    fn test_shape() {
+        assert_eq!(shape("imgtools"), "imgtools");
+        assert_eq!(shape("مرحبا"), "\u{FE8E}\u{FE92}\u{FEA3}\u{FEAE}\u{FEE3}");
+        // कि: the vowel sign is drawn before the consonant, and before a whole cluster
+        assert_eq!(shape("कि"), "\u{093F}\u{0915}");
+        assert_eq!(shape("स्कि"), "\u{093F}\u{0938}\u{094D}\u{0915}");
+    }
+}
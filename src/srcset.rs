@@ -0,0 +1,159 @@
+use crate::Format;
+use serde_json::json;
+
+/// One resized and encoded image of a responsive image set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// File name in the output directory, also its URL in the manifest
+    pub name: String,
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    /// Size of the encoded image
+    pub bytes: u64,
+}
+
+/// Widths to write for an image `width` pixels wide, smallest first
+///
+/// Images are never enlarged, widths beyond the image's own become that
+/// width, so a small source still gets its full size once.
+pub fn srcset_widths(widths: &[u32], width: u32) -> Vec<u32> {
+    let mut widths: Vec<u32> = widths.iter().map(|&w| w.min(width)).collect();
+    widths.sort_unstable();
+    widths.dedup();
+    widths
+}
+
+/// Height of a variant `width` pixels wide, keeping the aspect ratio
+pub fn srcset_height((source_width, source_height): (u32, u32), width: u32) -> u32 {
+    let height = (source_height as f64 * width as f64 / source_width as f64).round();
+    (height as u32).max(1)
+}
+
+/// A file name as it goes into a srcset attribute, where spaces and commas
+/// separate the candidates
+fn url(name: &str) -> String {
+    name.replace('%', "%25")
+        .replace(' ', "%20")
+        .replace(',', "%2C")
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+}
+
+/// The srcset attribute value for the variants of one format
+fn srcset(variants: &[&Variant]) -> String {
+    variants
+        .iter()
+        .map(|v| format!("{} {}w", url(&v.name), v.width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A `<picture>` element offering the variants to browsers
+///
+/// Every format but the last gets a `<source>` in the given order, so list
+/// the best first. The last one is the `<img>` for browsers that support
+/// none of them, its largest variant the `src` and its size the layout size.
+pub fn manifest_html(variants: &[Variant], formats: &[Format], sizes: &str) -> String {
+    let of = |format: Format| -> Vec<&Variant> {
+        variants.iter().filter(|v| v.format == format).collect()
+    };
+    let sizes = sizes.replace('&', "&amp;").replace('"', "&quot;");
+    let mut html = String::from("<picture>\n");
+    let Some((&fallback, sources)) = formats.split_last() else {
+        return html + "</picture>\n";
+    };
+    for &format in sources {
+        html += &format!(
+            "  <source type=\"{}\" srcset=\"{}\" sizes=\"{}\">\n",
+            format.mime_type(),
+            srcset(&of(format)),
+            sizes
+        );
+    }
+    let fallback = of(fallback);
+    if let Some(largest) = fallback.last() {
+        html += &format!(
+            "  <img src=\"{}\" srcset=\"{}\" sizes=\"{}\" width=\"{}\" height=\"{}\" alt=\"\" loading=\"lazy\" decoding=\"async\">\n",
+            url(&largest.name),
+            srcset(&fallback),
+            sizes,
+            largest.width,
+            largest.height
+        );
+    }
+    html + "</picture>\n"
+}
+
+/// The variants as JSON, e.g. for a static site generator
+pub fn manifest_json(variants: &[Variant], (width, height): (u32, u32)) -> String {
+    let variants: Vec<_> = variants
+        .iter()
+        .map(|v| {
+            json!({
+                "name": v.name,
+                "format": v.format.to_string(),
+                "type": v.format.mime_type(),
+                "width": v.width,
+                "height": v.height,
+                "bytes": v.bytes,
+            })
+        })
+        .collect();
+    let manifest = json!({
+        "width": width,
+        "height": height,
+        "variants": variants,
+    });
+    format!("{}\n", manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(name: &str, format: Format, width: u32) -> Variant {
+        Variant {
+            name: name.into(),
+            format,
+            width,
+            height: width / 2,
+            bytes: 100,
+        }
+    }
+
+    #[test]
+    fn test_srcset_widths() {
+        assert_eq!(srcset_widths(&[640, 320, 1920], 1000), [320, 640, 1000]);
+        assert_eq!(srcset_widths(&[320, 320, 1024, 1920], 800), [320, 800]);
+        assert_eq!(srcset_widths(&[320, 640], 100), [100]);
+        assert_eq!(srcset_height((1000, 667), 320), 213);
+        assert_eq!(srcset_height((1000, 1), 320), 1);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let variants = [
+            variant("a-320.webp", Format::WebP, 320),
+            variant("a-640.webp", Format::WebP, 640),
+            variant("a-320.jpeg", Format::Jpeg, 320),
+            variant("my a-640.jpeg", Format::Jpeg, 640),
+        ];
+        let html = manifest_html(&variants, &[Format::WebP, Format::Jpeg], "50vw");
+        assert_eq!(
+            html,
+            "<picture>\n  \
+             <source type=\"image/webp\" srcset=\"a-320.webp 320w, a-640.webp 640w\" sizes=\"50vw\">\n  \
+             <img src=\"my%20a-640.jpeg\" srcset=\"a-320.jpeg 320w, my%20a-640.jpeg 640w\" \
+             sizes=\"50vw\" width=\"640\" height=\"320\" alt=\"\" loading=\"lazy\" decoding=\"async\">\n\
+             </picture>\n"
+        );
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&manifest_json(&variants, (800, 400))).unwrap();
+        assert_eq!(manifest["width"], 800);
+        assert_eq!(manifest["variants"].as_array().unwrap().len(), 4);
+        assert_eq!(manifest["variants"][3]["name"], "my a-640.jpeg");
+        assert_eq!(manifest["variants"][3]["type"], "image/jpeg");
+    }
+}
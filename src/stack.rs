@@ -0,0 +1,118 @@
+use crate::ImgtoolsError;
+use crate::process::{same_size, with_color_type};
+use image::imageops;
+use image::{DynamicImage, ImageBuffer, Luma, RgbaImage};
+
+/// Sharpness of every pixel of a grayscale image
+type Energy = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Merge images focused at different depths into one that is sharp throughout
+///
+/// Each pixel is taken from the image that is sharpest around it, measured by
+/// the squared Laplacian of the brightness averaged over a Gaussian of
+/// `radius` pixels. A larger radius picks from whole regions, so flat parts
+/// and noise don't flicker between images. The images must be aligned and of
+/// the same size, the color type of the first one is kept.
+pub fn focus_stack(
+    images: &[(String, DynamicImage)],
+    radius: f32,
+) -> Result<DynamicImage, ImgtoolsError> {
+    let Some((_, first)) = images.first() else {
+        return Err(ImgtoolsError::InvalidArgument("No images to stack".into()));
+    };
+    let (w, h) = same_size(images, "Focus stack images")?;
+    let energies: Vec<Energy> = images
+        .iter()
+        .map(|(_, img)| sharpness(img, radius))
+        .collect();
+    let sources: Vec<RgbaImage> = images.iter().map(|(_, img)| img.to_rgba8()).collect();
+    let stacked = RgbaImage::from_fn(w, h, |x, y| {
+        // The first of equally sharp images wins, e.g. in flat areas
+        let sharpest = (1..energies.len()).fold(0, |best, i| {
+            match energies[i].get_pixel(x, y)[0] > energies[best].get_pixel(x, y)[0] {
+                true => i,
+                false => best,
+            }
+        });
+        *sources[sharpest].get_pixel(x, y)
+    });
+    Ok(with_color_type(stacked, first.color()))
+}
+
+/// Squared Laplacian of the brightness, blurred over `radius`
+fn sharpness(img: &DynamicImage, radius: f32) -> Energy {
+    let luma = img.to_luma32f();
+    let (w, h) = luma.dimensions();
+    let at = |x: i64, y: i64| {
+        luma.get_pixel(
+            x.clamp(0, w as i64 - 1) as u32,
+            y.clamp(0, h as i64 - 1) as u32,
+        )[0]
+    };
+    let energy = Energy::from_fn(w, h, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let laplacian = 4.0 * at(x, y) - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1);
+        Luma([laplacian * laplacian])
+    });
+    match radius > 0.0 {
+        true => imageops::blur(&energy, radius),
+        false => energy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ColorType, Rgb, RgbImage};
+
+    /// Fine black and white stripes, two pixels wide
+    fn stripes() -> RgbImage {
+        RgbImage::from_fn(40, 20, |x, _| match x / 2 % 2 {
+            0 => Rgb([0, 0, 0]),
+            _ => Rgb([255, 255, 255]),
+        })
+    }
+
+    /// The stripes with the columns from `from` to `to` out of focus
+    fn defocused(from: u32, to: u32) -> DynamicImage {
+        let sharp = stripes();
+        let soft = imageops::blur(&sharp, 3.0);
+        let img = RgbImage::from_fn(40, 20, |x, y| match (from..to).contains(&x) {
+            true => *soft.get_pixel(x, y),
+            false => *sharp.get_pixel(x, y),
+        });
+        img.into()
+    }
+
+    #[test]
+    fn test_focus_stack() {
+        let images = [
+            ("near.png".to_string(), defocused(20, 40)),
+            ("far.png".to_string(), defocused(0, 20)),
+        ];
+        let stacked = focus_stack(&images, 1.0).unwrap();
+        assert_eq!(stacked.color(), ColorType::Rgb8);
+        // Sharp everywhere except where the halves meet
+        let stacked = stacked.to_rgb8();
+        let sharp = stripes();
+        for x in (0..16).chain(24..40) {
+            for y in 0..20 {
+                assert_eq!(
+                    stacked.get_pixel(x, y),
+                    sharp.get_pixel(x, y),
+                    "{}, {}",
+                    x,
+                    y
+                );
+            }
+        }
+
+        // A single image comes back as it is
+        let single = focus_stack(&images[..1], 2.0).unwrap();
+        assert_eq!(single.to_rgb8(), images[0].1.to_rgb8());
+
+        let small = ("small.png".to_string(), DynamicImage::new_rgb8(10, 10));
+        assert!(focus_stack(&[images[0].clone(), small], 2.0).is_err());
+        assert!(focus_stack(&[], 2.0).is_err());
+    }
+}
@@ -0,0 +1,610 @@
+use crate::ImgtoolsError;
+use crate::filters::Rng;
+use crate::geometry::sample_bilinear;
+use crate::hdr::{Plane, collapse, gaussian_pyramid, laplacian_pyramid, levels};
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, Rgba, RgbaImage};
+use imageproc::binary_descriptors::brief::{BriefDescriptor, TestPair, brief};
+use imageproc::binary_descriptors::match_binary_descriptors;
+use imageproc::corners::{Corner, oriented_fast};
+use imageproc::point::Point;
+use imageproc::suppress::local_maxima;
+use std::collections::HashMap;
+
+/// Longest side corners are found at, larger photos are scaled down first
+const MAX_SIDE: u32 = 1000;
+/// Corners described in each photo
+const FEATURES: usize = 1500;
+/// Pixels around a corner weaker corners are dropped in
+const CLUMP_RADIUS: u32 = 3;
+/// Pixels kept clear around corners, descriptors look at 31 pixels wide patches
+const EDGE: u32 = 20;
+/// Bits in a descriptor
+const DESCRIPTOR_BITS: usize = 256;
+/// Most bits two descriptors may differ in to match
+const MATCH_DISTANCE: u32 = 48;
+/// Matches that must agree on how two photos overlap
+const MIN_INLIERS: usize = 12;
+/// Pixels a match may be off the homography and still agree with it
+const INLIER_DISTANCE: f64 = 1.0;
+/// Homographies tried on random matches
+const RANSAC_ROUNDS: usize = 2000;
+/// Most times the best homography is fitted again to the matches agreeing with it
+const REFITS: usize = 5;
+/// Longest side of a panorama, photos that don't line up stretch it far out
+const MAX_PANORAMA: f64 = 30000.0;
+
+/// Projective transform of the plane, row by row
+type Homography = [f64; 9];
+
+/// Position of a corner in one photo and of the corner it matches in another
+type Match = ((f64, f64), (f64, f64));
+
+const IDENTITY: Homography = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+
+/// Join a horizontal series of overlapping photos into one panorama
+///
+/// Corners found in neighbouring photos are matched by their BRIEF
+/// descriptors, and the homography most of the matches agree on maps one
+/// photo onto the other. Every photo is projected onto the plane of the
+/// middle one, and the seams between them are blended over `bands` levels of
+/// Laplacian pyramids: fine detail switches sharply, brightness changes
+/// gradually. Parts of the canvas no photo covers are transparent.
+pub fn stitch(images: &[(String, DynamicImage)], bands: u32) -> Result<RgbaImage, ImgtoolsError> {
+    if images.is_empty() {
+        return Err(ImgtoolsError::InvalidArgument("No photos to stitch".into()));
+    }
+    let pairs = test_pairs();
+    let features = images
+        .iter()
+        .map(|(name, img)| features(name, img, &pairs))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Homographies onto the middle photo, chained through the neighbours
+    let middle = images.len() / 2;
+    let mut to_middle = vec![IDENTITY; images.len()];
+    for i in middle + 1..images.len() {
+        let step = overlap(&features[i - 1], &features[i])
+            .ok_or_else(|| no_overlap(&images[i - 1].0, &images[i].0))?;
+        to_middle[i] = multiply(&to_middle[i - 1], &step);
+    }
+    for i in (0..middle).rev() {
+        let step = overlap(&features[i + 1], &features[i])
+            .ok_or_else(|| no_overlap(&images[i].0, &images[i + 1].0))?;
+        to_middle[i] = multiply(&to_middle[i + 1], &step);
+    }
+
+    let mut corners = Vec::with_capacity(images.len() * 4);
+    for ((_, img), h) in images.iter().zip(&to_middle) {
+        let (w, ht) = (img.width() as f64, img.height() as f64);
+        for (x, y) in [(0.0, 0.0), (w, 0.0), (0.0, ht), (w, ht)] {
+            corners.push(project(h, x, y));
+        }
+    }
+    let (left, top, right, bottom) = corners.iter().fold(
+        (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+        |(l, t, r, b), corner| match corner {
+            Some((x, y)) => (l.min(*x), t.min(*y), r.max(*x), b.max(*y)),
+            None => (f64::NAN, f64::NAN, f64::NAN, f64::NAN),
+        },
+    );
+    let (width, height) = ((right - left).ceil(), (bottom - top).ceil());
+    // NaN from a corner behind the camera doesn't fit either
+    let fits = |side: f64| side <= MAX_PANORAMA;
+    if !fits(width) || !fits(height) {
+        return Err(ImgtoolsError::InvalidArgument(
+            "The photos don't line up into a panorama, the projection stretches too far".into(),
+        ));
+    }
+    let (width, height) = (width.max(1.0) as u32, height.max(1.0) as u32);
+
+    let warped: Vec<Warped> = images
+        .iter()
+        .zip(&to_middle)
+        .map(|((_, img), h)| warp(img, &invert(h), (left, top), width, height))
+        .collect();
+    Ok(blend(&warped, width, height, bands))
+}
+
+fn no_overlap(left: &str, right: &str) -> ImgtoolsError {
+    ImgtoolsError::InvalidArgument(format!(
+        "Found no overlap between {} and {}, the photos must be in order from one side to the other",
+        left, right
+    ))
+}
+
+/// Descriptors of the strongest corners of a photo, at full size positions
+struct Features {
+    descriptors: Vec<BriefDescriptor>,
+    /// Full size pixels per feature pixel
+    scale: f64,
+}
+
+fn features(name: &str, img: &DynamicImage, pairs: &[TestPair]) -> Result<Features, ImgtoolsError> {
+    let (gray, scale): (GrayImage, f64) = match img.width().max(img.height()) > MAX_SIDE {
+        true => {
+            let small = img.resize(MAX_SIDE, MAX_SIDE, FilterType::Triangle);
+            let scale = img.width() as f64 / small.width() as f64;
+            (small.into_luma8(), scale)
+        }
+        false => (img.to_luma8(), 1.0),
+    };
+    if gray.width().min(gray.height()) <= 2 * EDGE + 1 {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "{} is too small to stitch, photos need to be at least {} pixels on each side",
+            name,
+            2 * EDGE + 2
+        )));
+    }
+    // Corners come in clumps, only the strongest of each clump is kept
+    let candidates: Vec<Corner> = oriented_fast(&gray, None, FEATURES * 4, EDGE, Some(0))
+        .iter()
+        .map(|corner| corner.corner)
+        .collect();
+    let mut corners = local_maxima(&candidates, CLUMP_RADIUS);
+    corners.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let keypoints: Vec<Point<u32>> = corners
+        .iter()
+        .take(FEATURES)
+        .map(|corner| Point::new(corner.x, corner.y))
+        .collect();
+    let (descriptors, _) = brief(&gray, &keypoints, DESCRIPTOR_BITS, Some(&pairs.to_vec()))
+        .map_err(ImgtoolsError::InvalidArgument)?;
+    Ok(Features { descriptors, scale })
+}
+
+/// Pixel pairs the descriptors compare, the same for every photo
+///
+/// Points gather around the patch center like a Gaussian, as in BRIEF, but
+/// are drawn from a fixed seed so stitching the same photos twice gives the
+/// same panorama.
+fn test_pairs() -> Vec<TestPair> {
+    let mut rng = Rng::new(DESCRIPTOR_BITS as u64);
+    let mut point = || loop {
+        let (x, y) = (16.0 + 6.6 * rng.gaussian(), 16.0 + 6.6 * rng.gaussian());
+        if (0.0..31.0).contains(&x) && (0.0..31.0).contains(&y) {
+            break Point::new(x as u32, y as u32);
+        }
+    };
+    (0..DESCRIPTOR_BITS)
+        .map(|_| TestPair {
+            p0: point(),
+            p1: point(),
+        })
+        .collect()
+}
+
+/// Homography from the second photo onto the first, if enough corners agree on one
+fn overlap(first: &Features, second: &Features) -> Option<Homography> {
+    let corner = |d: &BriefDescriptor| (d.corner.x, d.corner.y);
+    // Only matches that are each other's best match in both directions
+    let backwards: HashMap<_, _> = match_binary_descriptors(
+        &first.descriptors,
+        &second.descriptors,
+        MATCH_DISTANCE,
+        Some(0),
+    )
+    .into_iter()
+    .map(|(to, from)| (corner(to), corner(from)))
+    .collect();
+    let matches: Vec<Match> = match_binary_descriptors(
+        &second.descriptors,
+        &first.descriptors,
+        MATCH_DISTANCE,
+        Some(0),
+    )
+    .into_iter()
+    .filter(|(from, to)| backwards.get(&corner(to)) == Some(&corner(from)))
+    .map(|(from, to)| {
+        let position = |d: &BriefDescriptor, scale: f64| {
+            (d.corner.x as f64 * scale, d.corner.y as f64 * scale)
+        };
+        (position(from, second.scale), position(to, first.scale))
+    })
+    .collect();
+    if matches.len() < MIN_INLIERS {
+        return None;
+    }
+
+    // The distance is measured in feature pixels, however large the photos
+    let tolerance = INLIER_DISTANCE * first.scale.max(second.scale);
+    let agreeing = |h: &Homography| -> Vec<Match> {
+        matches
+            .iter()
+            .filter(|(from, to)| {
+                project(h, from.0, from.1)
+                    .is_some_and(|(x, y)| (x - to.0).hypot(y - to.1) <= tolerance)
+            })
+            .copied()
+            .collect()
+    };
+    // RANSAC: the homography of four random matches that most others agree with
+    let mut rng = Rng::new(matches.len() as u64);
+    let mut best: Vec<Match> = Vec::new();
+    for _ in 0..RANSAC_ROUNDS {
+        let mut sample = Vec::with_capacity(4);
+        while sample.len() < 4 {
+            let i = (rng.next_u64() % matches.len() as u64) as usize;
+            if !sample.contains(&i) {
+                sample.push(i);
+            }
+        }
+        let sample: Vec<_> = sample.iter().map(|&i| matches[i]).collect();
+        if let Some(h) = fit(&sample) {
+            let inliers = agreeing(&h);
+            if inliers.len() > best.len() {
+                best = inliers;
+            }
+        }
+    }
+    if best.len() < MIN_INLIERS {
+        return None;
+    }
+    // Refit to every agreeing match, which averages out their errors, until
+    // no more matches agree
+    let mut h = fit(&best)?;
+    for _ in 0..REFITS {
+        let inliers = agreeing(&h);
+        if inliers.len() <= best.len() {
+            break;
+        }
+        best = inliers;
+        h = fit(&best)?;
+    }
+    (agreeing(&h).len() >= MIN_INLIERS).then_some(h)
+}
+
+/// Least squares homography through the point pairs, `None` if they don't pin one down
+///
+/// The points are moved to the origin and scaled to about one first, which
+/// keeps the normal equations well conditioned.
+fn fit(pairs: &[Match]) -> Option<Homography> {
+    let normalize = |points: Vec<(f64, f64)>| {
+        let n = points.len() as f64;
+        let (cx, cy) = points
+            .iter()
+            .fold((0.0, 0.0), |(x, y), p| (x + p.0 / n, y + p.1 / n));
+        let spread = points
+            .iter()
+            .map(|p| (p.0 - cx).hypot(p.1 - cy))
+            .sum::<f64>()
+            / n;
+        let s = match spread > 0.0 {
+            true => std::f64::consts::SQRT_2 / spread,
+            false => 1.0,
+        };
+        [s, 0.0, -s * cx, 0.0, s, -s * cy, 0.0, 0.0, 1.0]
+    };
+    let from = normalize(pairs.iter().map(|p| p.0).collect());
+    let to = normalize(pairs.iter().map(|p| p.1).collect());
+
+    // Normal equations of the linear system with the last entry fixed at one
+    let mut a = [[0.0f64; 9]; 8];
+    for (p, q) in pairs {
+        let (x, y) = (from[0] * p.0 + from[2], from[4] * p.1 + from[5]);
+        let (u, v) = (to[0] * q.0 + to[2], to[4] * q.1 + to[5]);
+        for row in [
+            [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u],
+            [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v],
+        ] {
+            for i in 0..8 {
+                for j in 0..9 {
+                    a[i][j] += row[i] * row[j];
+                }
+            }
+        }
+    }
+    // Gaussian elimination with partial pivoting
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-10 {
+            return None;
+        }
+        a.swap(col, pivot);
+        for row in 0..8 {
+            if row != col {
+                let pivot_row = a[col];
+                let factor = a[row][col] / pivot_row[col];
+                for (value, p) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                    *value -= factor * p;
+                }
+            }
+        }
+    }
+    let mut h = [1.0; 9];
+    for (i, value) in h.iter_mut().take(8).enumerate() {
+        *value = a[i][8] / a[i][i];
+    }
+    Some(multiply(&invert(&to), &multiply(&h, &from)))
+}
+
+/// Where the homography puts a point, `None` behind the camera
+fn project(h: &Homography, x: f64, y: f64) -> Option<(f64, f64)> {
+    let w = h[6] * x + h[7] * y + h[8];
+    (w > 1e-12).then(|| {
+        (
+            (h[0] * x + h[1] * y + h[2]) / w,
+            (h[3] * x + h[4] * y + h[5]) / w,
+        )
+    })
+}
+
+/// The homography of applying `b`, then `a`
+fn multiply(a: &Homography, b: &Homography) -> Homography {
+    std::array::from_fn(|i| {
+        let (row, col) = (i / 3, i % 3);
+        (0..3).map(|k| a[row * 3 + k] * b[k * 3 + col]).sum()
+    })
+}
+
+fn invert(h: &Homography) -> Homography {
+    let [a, b, c, d, e, f, g, k, l] = *h;
+    // The adjugate, scaling doesn't change a homography
+    let inverse = [
+        e * l - f * k,
+        c * k - b * l,
+        b * f - c * e,
+        f * g - d * l,
+        a * l - c * g,
+        c * d - a * f,
+        d * k - e * g,
+        b * g - a * k,
+        a * e - b * d,
+    ];
+    let det = a * inverse[0] + b * inverse[3] + c * inverse[6];
+    inverse.map(|value| value / det)
+}
+
+/// A photo projected onto the panorama canvas
+struct Warped {
+    /// Red, green and blue, with the uncovered parts filled in
+    colors: [Plane; 3],
+    /// 0.0 ~ 1.0 where the photo covers the canvas
+    coverage: Plane,
+    /// How far inside the photo each pixel is, the distances to the nearest
+    /// side and to the nearest top or bottom multiplied, so seams between
+    /// photos of the same height run straight
+    centrality: Plane,
+}
+
+fn warp(
+    img: &DynamicImage,
+    from_canvas: &Homography,
+    origin: (f64, f64),
+    width: u32,
+    height: u32,
+) -> Warped {
+    let rgba = img.to_rgba8();
+    let (w, h) = (img.width() as f64, img.height() as f64);
+    let plane = || Plane {
+        width,
+        height,
+        data: vec![0.0; (width * height) as usize],
+    };
+    let (mut colors, mut coverage, mut centrality) =
+        ([plane(), plane(), plane()], plane(), plane());
+    for y in 0..height {
+        for x in 0..width {
+            let (cx, cy) = (origin.0 + x as f64 + 0.5, origin.1 + y as f64 + 0.5);
+            let Some((sx, sy)) = project(from_canvas, cx, cy) else {
+                continue;
+            };
+            let pixel =
+                sample_bilinear(&rgba, sx as f32 - 0.5, sy as f32 - 0.5, Rgba([0, 0, 0, 0]));
+            let i = (y * width + x) as usize;
+            for (c, channel) in colors.iter_mut().enumerate() {
+                channel.data[i] = pixel[c] as f32;
+            }
+            coverage.data[i] = pixel[3] as f32 / 255.0;
+            let edge = sx.min(w - sx).max(0.0) * sy.min(h - sy).max(0.0);
+            centrality.data[i] = coverage.data[i] * edge as f32;
+        }
+    }
+    let colors = colors.map(|channel| fill(&channel, &coverage));
+    Warped {
+        colors,
+        coverage,
+        centrality,
+    }
+}
+
+/// Spread the covered parts of the plane over the uncovered ones
+///
+/// Pyramids shrink a photo together with the canvas around it, so the edge
+/// of the photo would bleed black into the blend. Filling the canvas with
+/// blurred neighbouring colors first, pull-push style, keeps them out.
+fn fill(plane: &Plane, coverage: &Plane) -> Plane {
+    let premultiplied = Plane {
+        data: plane
+            .data
+            .iter()
+            .zip(&coverage.data)
+            .map(|(v, a)| v * a)
+            .collect(),
+        ..*plane
+    };
+    let mut colors = vec![premultiplied];
+    let mut covered = vec![coverage.clone()];
+    while colors[colors.len() - 1].width > 1 || colors[colors.len() - 1].height > 1 {
+        let next = (
+            colors[colors.len() - 1].reduce(),
+            covered[covered.len() - 1].reduce(),
+        );
+        colors.push(next.0);
+        covered.push(next.1);
+    }
+    let (last, last_covered) = (&colors[colors.len() - 1], &covered[covered.len() - 1]);
+    let mut filled = Plane {
+        data: last
+            .data
+            .iter()
+            .zip(&last_covered.data)
+            .map(|(v, &a)| if a > 0.0 { v / a } else { 0.0 })
+            .collect(),
+        ..*last
+    };
+    for (level, cover) in colors.iter().zip(&covered).rev().skip(1) {
+        let grown = filled.expand(level.width, level.height);
+        filled = Plane {
+            data: level
+                .data
+                .iter()
+                .zip(&cover.data)
+                .zip(&grown.data)
+                .map(|((v, a), g)| v + (1.0 - a.min(1.0)) * g)
+                .collect(),
+            ..*level
+        };
+    }
+    filled
+}
+
+/// Blend the photos, each pixel coming mostly from the photo it is most central in
+fn blend(warped: &[Warped], width: u32, height: u32, bands: u32) -> RgbaImage {
+    let pixels = (width * height) as usize;
+    // Which photo each pixel comes from before blending
+    let masks: Vec<Plane> = (0..warped.len())
+        .map(|i| Plane {
+            width,
+            height,
+            data: (0..pixels)
+                .map(|p| {
+                    let owner = (0..warped.len())
+                        .filter(|&j| warped[j].coverage.data[p] > 0.0)
+                        .max_by(|&a, &b| {
+                            warped[a].centrality.data[p].total_cmp(&warped[b].centrality.data[p])
+                        });
+                    match owner == Some(i) {
+                        true => 1.0,
+                        false => 0.0,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    let levels = (bands.max(1) as usize).min(levels(width, height));
+    let mut blended: [Vec<Plane>; 3] = Default::default();
+    let mut total: Vec<Plane> = Vec::new();
+    for (photo, mask) in warped.iter().zip(&masks) {
+        let weight = gaussian_pyramid(mask, levels);
+        if total.is_empty() {
+            total = weight.iter().map(|level| level.scaled(0.0)).collect();
+        }
+        for (sum, level) in total.iter_mut().zip(&weight) {
+            for (s, w) in sum.data.iter_mut().zip(&level.data) {
+                *s += w;
+            }
+        }
+        for (c, channel) in photo.colors.iter().enumerate() {
+            let laplacian = laplacian_pyramid(channel, levels);
+            if blended[c].is_empty() {
+                blended[c] = laplacian.iter().map(|level| level.scaled(0.0)).collect();
+            }
+            for ((out, level), weight) in blended[c].iter_mut().zip(&laplacian).zip(&weight) {
+                for ((out, value), weight) in out.data.iter_mut().zip(&level.data).zip(&weight.data)
+                {
+                    *out += value * weight;
+                }
+            }
+        }
+    }
+    // Masks blurred at the edge of the panorama add up to less than one
+    let [r, g, b] = blended.map(|mut pyramid| {
+        for (level, sum) in pyramid.iter_mut().zip(&total) {
+            for (value, s) in level.data.iter_mut().zip(&sum.data) {
+                if *s > 1e-6 {
+                    *value /= s;
+                }
+            }
+        }
+        collapse(&pyramid)
+    });
+    RgbaImage::from_fn(width, height, |x, y| {
+        let i = (y * width + x) as usize;
+        let alpha = warped
+            .iter()
+            .map(|photo| photo.coverage.data[i])
+            .fold(0.0, f32::max);
+        let channel = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+        Rgba([
+            channel(r.data[i]),
+            channel(g.data[i]),
+            channel(b.data[i]),
+            channel(alpha * 255.0),
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    /// A wide scene of blocks in random shades, with corners to match
+    fn scene() -> RgbImage {
+        let mut rng = Rng::new(7);
+        let shades: Vec<[u8; 3]> = (0..60 * 20)
+            .map(|_| {
+                let v = rng.next_u64();
+                [v as u8, (v >> 8) as u8, (v >> 16) as u8]
+            })
+            .collect();
+        RgbImage::from_fn(480, 160, |x, y| Rgb(shades[(y / 8 * 60 + x / 8) as usize]))
+    }
+
+    #[test]
+    fn test_homography() {
+        let h = [1.1, 0.05, 20.0, -0.02, 0.95, -7.0, 0.0002, -0.0001, 1.0];
+        let points = [
+            (0.0, 0.0),
+            (300.0, 10.0),
+            (20.0, 250.0),
+            (310.0, 240.0),
+            (150.0, 120.0),
+        ];
+        let pairs: Vec<_> = points
+            .iter()
+            .map(|&(x, y)| ((x, y), project(&h, x, y).unwrap()))
+            .collect();
+        let fitted = fit(&pairs).unwrap();
+        for ((x, y), (u, v)) in pairs {
+            let (fu, fv) = project(&fitted, x, y).unwrap();
+            assert!((fu - u).abs() < 1e-6 && (fv - v).abs() < 1e-6);
+            let (bx, by) = project(&invert(&h), u, v).unwrap();
+            assert!((bx - x).abs() < 1e-6 && (by - y).abs() < 1e-6);
+        }
+        // Matches all on one point don't pin down a homography
+        assert!(fit(&[((0.0, 0.0), (1.0, 1.0)); 4]).is_none());
+    }
+
+    #[test]
+    fn test_stitch() {
+        let scene = scene();
+        let photos: Vec<(String, DynamicImage)> = [0, 115, 240]
+            .iter()
+            .map(|&x| {
+                let view = scene.view(x, 0, 240, 160).to_image();
+                (format!("{}.png", x), DynamicImage::ImageRgb8(view))
+            })
+            .collect();
+        let panorama = stitch(&photos, 5).unwrap();
+        let (w, h) = panorama.dimensions();
+        assert!(w.abs_diff(480) <= 1 && h.abs_diff(160) <= 1, "{}x{}", w, h);
+        // The middle photo stays where it is, the others line up with it
+        let mut off = 0;
+        for y in 4..156 {
+            for x in 4..476 {
+                let pixel = panorama.get_pixel(x, y);
+                let expected = scene.get_pixel(x, y);
+                if (0..3).any(|c| pixel[c].abs_diff(expected[c]) > 8) || pixel[3] < 250 {
+                    off += 1;
+                }
+            }
+        }
+        assert!(off < 152 * 472 / 100, "{} pixels off", off);
+
+        let flat = ("flat.png".to_string(), DynamicImage::new_rgb8(240, 160));
+        assert!(stitch(&[photos[0].clone(), flat], 5).is_err());
+    }
+}
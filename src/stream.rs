@@ -0,0 +1,704 @@
+use crate::encoding::png_error;
+use crate::resample::resize_weights;
+use crate::{Filter, Format, ImgtoolsError};
+use image::error::{DecodingError, EncodingError, ImageFormatHint};
+use image::{ImageError, ImageFormat, RgbaImage};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Seek, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use tiff::decoder::{ChunkType, Decoder as TiffDecoder, DecodingResult};
+use tiff::encoder::{TiffEncoder, TiffValue, colortype};
+use tiff::tags::Tag;
+
+/// Size of the strips TIFF output is written in
+const STRIP_BYTES: u64 = 64 * 1024;
+
+/// Bytes of rows decoded ahead of the stages at most
+const AHEAD_BYTES: usize = 8 << 20;
+
+/// Samples per pixel and whether they have 16 bits instead of 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub channels: usize,
+    pub sixteen: bool,
+}
+
+impl Layout {
+    /// Largest sample value
+    fn max(self) -> f32 {
+        match self.sixteen {
+            true => 65535.0,
+            false => 255.0,
+        }
+    }
+
+    /// Bytes one row of `width` pixels takes
+    fn row_bytes(self, width: u32) -> u64 {
+        width as u64 * self.channels as u64 * (1 + self.sixteen as u64)
+    }
+}
+
+/// An image read one row at a time, top to bottom
+///
+/// Samples are interleaved and 16-bit ones use the whole range, so only the
+/// rows a stage works on are ever in memory.
+pub(crate) trait Rows {
+    /// Width and height of the whole image
+    fn size(&self) -> (u32, u32);
+    fn layout(&self) -> Layout;
+    /// Samples of the next row, `None` after the last one
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError>;
+}
+
+/// Map an error of the png or tiff decoder
+fn decode_error(format: ImageFormat, e: impl Into<Box<dyn Error + Send + Sync>>) -> ImgtoolsError {
+    ImgtoolsError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Exact(format),
+        e,
+    )))
+}
+
+/// Map an error of the tiff encoder
+fn tiff_error(e: tiff::TiffError) -> ImgtoolsError {
+    ImgtoolsError::Encode(ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Tiff),
+        e,
+    )))
+}
+
+/// Error for an image that can't be processed in strips
+fn unstreamable(what: &str) -> ImgtoolsError {
+    ImgtoolsError::InvalidArgument(format!("{} can't be processed in strips", what))
+}
+
+/// Error for a source that ends before its last row
+fn truncated() -> ImgtoolsError {
+    ImgtoolsError::InvalidArgument("The image ended before its last row".into())
+}
+
+/// Open a PNG or TIFF file to read it row by row
+pub(crate) fn open_rows(
+    path: &Path,
+    format: ImageFormat,
+) -> Result<Box<dyn Rows + Send>, ImgtoolsError> {
+    let file = File::open(path).map_err(|source| ImgtoolsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let reader = BufReader::new(file);
+    match format {
+        ImageFormat::Png => Ok(Box::new(PngRows::new(reader)?)),
+        ImageFormat::Tiff => Ok(Box::new(TiffRows::new(reader)?)),
+        _ => Err(unstreamable(&format!("{:?}", format))),
+    }
+}
+
+/// Rows of a PNG, palettes and low bit depths expanded to 8 bits
+struct PngRows {
+    reader: png::Reader<BufReader<File>>,
+    size: (u32, u32),
+    layout: Layout,
+}
+
+impl PngRows {
+    fn new(reader: BufReader<File>) -> Result<Self, ImgtoolsError> {
+        let mut decoder = png::Decoder::new(reader);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let reader = decoder
+            .read_info()
+            .map_err(|e| decode_error(ImageFormat::Png, e))?;
+        // Interlaced rows arrive in seven passes over the whole image
+        if reader.info().interlaced {
+            return Err(unstreamable("An interlaced PNG"));
+        }
+        let (color, depth) = reader.output_color_type();
+        let channels = match color {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            png::ColorType::Indexed => return Err(unstreamable("An indexed PNG")),
+        };
+        let info = reader.info();
+        Ok(PngRows {
+            size: (info.width, info.height),
+            layout: Layout {
+                channels,
+                sixteen: depth == png::BitDepth::Sixteen,
+            },
+            reader,
+        })
+    }
+}
+
+impl Rows for PngRows {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+        let sixteen = self.layout.sixteen;
+        let Some(row) = self
+            .reader
+            .next_row()
+            .map_err(|e| decode_error(ImageFormat::Png, e))?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(match sixteen {
+            true => row
+                .data()
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect(),
+            false => row.data().iter().map(|&v| v as u16).collect(),
+        }))
+    }
+}
+
+/// Rows of a TIFF, read a strip or a row of tiles at a time
+struct TiffRows {
+    decoder: TiffDecoder<BufReader<File>>,
+    size: (u32, u32),
+    layout: Layout,
+    /// Rows of the strip read last that weren't returned yet
+    band: VecDeque<Vec<u16>>,
+    /// Strip or row of tiles to read next
+    next_band: u32,
+}
+
+impl TiffRows {
+    fn new(reader: BufReader<File>) -> Result<Self, ImgtoolsError> {
+        let error = |e| decode_error(ImageFormat::Tiff, e);
+        let mut decoder = TiffDecoder::new(reader).map_err(error)?;
+        let size = decoder.dimensions().map_err(error)?;
+        let (channels, bits) = match decoder.colortype().map_err(error)? {
+            tiff::ColorType::Gray(bits) => (1, bits),
+            tiff::ColorType::GrayA(bits) => (2, bits),
+            tiff::ColorType::RGB(bits) => (3, bits),
+            tiff::ColorType::RGBA(bits) => (4, bits),
+            other => return Err(unstreamable(&format!("A {:?} TIFF", other))),
+        };
+        if !matches!(bits, 8 | 16) {
+            return Err(unstreamable(&format!("A {}-bit TIFF", bits)));
+        }
+        // Planar images store every channel in strips of their own
+        if decoder
+            .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+            .map_err(error)?
+            == Some(2)
+        {
+            return Err(unstreamable("A planar TIFF"));
+        }
+        Ok(TiffRows {
+            decoder,
+            size,
+            layout: Layout {
+                channels,
+                sixteen: bits == 16,
+            },
+            band: VecDeque::new(),
+            next_band: 0,
+        })
+    }
+
+    /// Decode the next strip, or the next row of tiles side by side
+    fn read_band(&mut self) -> Result<(), ImgtoolsError> {
+        let error = |e| decode_error(ImageFormat::Tiff, e);
+        let (width, height) = self.size;
+        let channels = self.layout.channels;
+        let (chunk_width, chunk_height) = self.decoder.chunk_dimensions();
+        let across = match self.decoder.get_chunk_type() {
+            ChunkType::Strip => 1,
+            ChunkType::Tile => width.div_ceil(chunk_width),
+        };
+        let rows = chunk_height.min(height - self.next_band * chunk_height) as usize;
+        let mut band = vec![vec![0u16; width as usize * channels]; rows];
+        for column in 0..across {
+            let index = self.next_band * across + column;
+            let (data_width, _) = self.decoder.chunk_data_dimensions(index);
+            let samples: Vec<u16> = match self.decoder.read_chunk(index).map_err(error)? {
+                DecodingResult::U8(samples) => samples.into_iter().map(u16::from).collect(),
+                DecodingResult::U16(samples) => samples,
+                _ => return Err(unstreamable("A TIFF with floating point samples")),
+            };
+            let stride = data_width as usize * channels;
+            let x = (column * chunk_width) as usize * channels;
+            for (row, data) in band.iter_mut().zip(samples.chunks_exact(stride)) {
+                row[x..x + stride].copy_from_slice(data);
+            }
+        }
+        self.band = band.into();
+        self.next_band += 1;
+        Ok(())
+    }
+}
+
+impl Rows for TiffRows {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+        let (_, chunk_height) = self.decoder.chunk_dimensions();
+        if self.band.is_empty() && self.next_band * chunk_height < self.size.1 {
+            self.read_band()?;
+        }
+        Ok(self.band.pop_front())
+    }
+}
+
+/// Rows decoded on a thread of their own, ahead of the stages reading them
+///
+/// Decompressing PNG and TIFF data can't be split up, this runs it next to
+/// resizing and encoding instead of in turn with them.
+pub(crate) struct DecodeAhead {
+    size: (u32, u32),
+    layout: Layout,
+    rows: Receiver<Result<Option<Vec<u16>>, ImgtoolsError>>,
+}
+
+impl DecodeAhead {
+    pub(crate) fn new(mut source: Box<dyn Rows + Send>) -> Self {
+        let (size, layout) = (source.size(), source.layout());
+        let row_bytes = size.0 as usize * layout.channels * 2;
+        let (sender, rows) = mpsc::sync_channel((AHEAD_BYTES / row_bytes.max(1)).max(1));
+        // The thread stops at the end, on an error or once the stages are dropped
+        thread::spawn(move || {
+            loop {
+                let row = source.next_row();
+                let last = !matches!(row, Ok(Some(_)));
+                if sender.send(row).is_err() || last {
+                    break;
+                }
+            }
+        });
+        DecodeAhead { size, layout, rows }
+    }
+}
+
+impl Rows for DecodeAhead {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+        // Past the end the thread is gone
+        self.rows.recv().unwrap_or(Ok(None))
+    }
+}
+
+/// Part of the image, its left, top, width and height within it
+pub(crate) struct CropRows {
+    source: Box<dyn Rows>,
+    area: (u32, u32, u32, u32),
+    /// Rows taken from the source so far
+    read: u32,
+}
+
+impl CropRows {
+    /// Crop to the area, clamped to the image like `DynamicImage::crop_imm`
+    pub(crate) fn new(source: Box<dyn Rows>, (x, y, w, h): (u32, u32, u32, u32)) -> Self {
+        let (width, height) = source.size();
+        let (x, y) = (x.min(width), y.min(height));
+        let area = (x, y, w.min(width - x), h.min(height - y));
+        CropRows {
+            source,
+            area,
+            read: 0,
+        }
+    }
+}
+
+impl Rows for CropRows {
+    fn size(&self) -> (u32, u32) {
+        (self.area.2, self.area.3)
+    }
+
+    fn layout(&self) -> Layout {
+        self.source.layout()
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+        let (x, y, w, h) = self.area;
+        if self.read >= y + h {
+            return Ok(None);
+        }
+        while self.read < y {
+            self.source.next_row()?.ok_or_else(truncated)?;
+            self.read += 1;
+        }
+        let row = self.source.next_row()?.ok_or_else(truncated)?;
+        self.read += 1;
+        let channels = self.layout().channels;
+        let (start, end) = (x as usize * channels, (x + w) as usize * channels);
+        Ok(Some(row[start..end].to_vec()))
+    }
+}
+
+/// The image resized with the same filters as `DynamicImage::resize_exact`
+///
+/// Every source row is resized across once and added to the target rows it
+/// contributes to, which are returned as soon as no later row adds to them.
+pub(crate) struct ResizeRows {
+    source: Box<dyn Rows>,
+    size: (u32, u32),
+    columns: Vec<(usize, Vec<f32>)>,
+    rows: Vec<(usize, Vec<f32>)>,
+    /// Sums of the target rows from `next` on that have source rows in them
+    pending: VecDeque<Vec<f32>>,
+    /// Next target row to return and next source row to read
+    next: usize,
+    read: usize,
+}
+
+impl ResizeRows {
+    pub(crate) fn new(source: Box<dyn Rows>, (width, height): (u32, u32), filter: Filter) -> Self {
+        let (w, h) = source.size();
+        ResizeRows {
+            columns: resize_weights(w, width, filter),
+            rows: resize_weights(h, height, filter),
+            size: (width, height),
+            source,
+            pending: VecDeque::new(),
+            next: 0,
+            read: 0,
+        }
+    }
+
+    /// A source row resized to the target width
+    fn across(&self, row: &[u16]) -> Vec<f32> {
+        let channels = self.layout().channels;
+        let mut out = Vec::with_capacity(self.columns.len() * channels);
+        for (start, weights) in &self.columns {
+            for c in 0..channels {
+                let samples = row[start * channels + c..].iter().step_by(channels);
+                out.push(
+                    weights
+                        .iter()
+                        .zip(samples)
+                        .map(|(w, &v)| w * v as f32)
+                        .sum(),
+                );
+            }
+        }
+        out
+    }
+}
+
+impl Rows for ResizeRows {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn layout(&self) -> Layout {
+        self.source.layout()
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+        let Some((start, weights)) = self.rows.get(self.next) else {
+            return Ok(None);
+        };
+        let end = start + weights.len();
+        while self.read < end {
+            let row = self.source.next_row()?.ok_or_else(truncated)?;
+            let across = self.across(&row);
+            // Target rows that take this source row follow each other
+            for (i, (start, weights)) in self.rows.iter().enumerate().skip(self.next) {
+                if *start > self.read {
+                    break;
+                }
+                let Some(&w) = weights.get(self.read - start) else {
+                    continue;
+                };
+                let k = i - self.next;
+                if k == self.pending.len() {
+                    self.pending.push_back(vec![0.0; across.len()]);
+                }
+                for (sum, v) in self.pending[k].iter_mut().zip(&across) {
+                    *sum += w * v;
+                }
+            }
+            self.read += 1;
+        }
+        self.next += 1;
+        let max = self.layout().max();
+        let row = self.pending.pop_front().ok_or_else(truncated)?;
+        Ok(Some(
+            row.into_iter()
+                .map(|v| v.round().clamp(0.0, max) as u16)
+                .collect(),
+        ))
+    }
+}
+
+/// Watermark images drawn over the rows they cover
+pub(crate) struct StampRows {
+    source: Box<dyn Rows>,
+    images: Vec<RgbaImage>,
+    /// Index into `images` and the top left corner of every copy
+    placements: Vec<(usize, i64, i64)>,
+    y: i64,
+}
+
+impl StampRows {
+    pub(crate) fn new(
+        source: Box<dyn Rows>,
+        images: Vec<RgbaImage>,
+        placements: Vec<(usize, i64, i64)>,
+    ) -> Self {
+        StampRows {
+            source,
+            images,
+            placements,
+            y: 0,
+        }
+    }
+}
+
+impl Rows for StampRows {
+    fn size(&self) -> (u32, u32) {
+        self.source.size()
+    }
+
+    fn layout(&self) -> Layout {
+        self.source.layout()
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+        let Some(mut row) = self.source.next_row()? else {
+            return Ok(None);
+        };
+        let layout = self.layout();
+        let (channels, max) = (layout.channels, layout.max());
+        let width = row.len() / channels;
+        for &(i, left, top) in &self.placements {
+            let image = &self.images[i];
+            if self.y < top || self.y >= top + image.height() as i64 {
+                continue;
+            }
+            for x in left.max(0)..(left + image.width() as i64).min(width as i64) {
+                let stamp = image.get_pixel((x - left) as u32, (self.y - top) as u32);
+                let alpha = stamp[3] as f32 / 255.0;
+                if alpha == 0.0 {
+                    continue;
+                }
+                let [r, g, b] = [0, 1, 2].map(|c| stamp[c] as f32 / 255.0 * max);
+                let color = match channels {
+                    1 | 2 => vec![0.2126 * r + 0.7152 * g + 0.0722 * b],
+                    _ => vec![r, g, b],
+                };
+                let pixel = &mut row[x as usize * channels..(x as usize + 1) * channels];
+                // Paint over the pixel with the stamp's alpha, as overlay does
+                let below = match channels % 2 {
+                    0 => pixel[channels - 1] as f32 / max,
+                    _ => 1.0,
+                };
+                let out = alpha + below * (1.0 - alpha);
+                for (sample, paint) in pixel.iter_mut().zip(&color) {
+                    let blended = (paint * alpha + *sample as f32 * below * (1.0 - alpha)) / out;
+                    *sample = blended.round().clamp(0.0, max) as u16;
+                }
+                if channels % 2 == 0 {
+                    pixel[channels - 1] = (out * max).round() as u16;
+                }
+            }
+        }
+        self.y += 1;
+        Ok(Some(row))
+    }
+}
+
+/// Write the rows as PNG or TIFF, the formats that are encoded a row at a time
+pub(crate) fn write_rows<W: Write + Seek>(
+    rows: &mut dyn Rows,
+    format: Format,
+    output: W,
+) -> Result<(), ImgtoolsError> {
+    let layout = rows.layout();
+    match (format, layout.channels, layout.sixteen) {
+        (Format::Png, ..) => write_png(rows, output),
+        (Format::Tiff, 1, false) => write_tiff::<colortype::Gray8, _>(rows, output, |v| v as u8),
+        (Format::Tiff, 1, true) => write_tiff::<colortype::Gray16, _>(rows, output, |v| v),
+        (Format::Tiff, 3, false) => write_tiff::<colortype::RGB8, _>(rows, output, |v| v as u8),
+        (Format::Tiff, 3, true) => write_tiff::<colortype::RGB16, _>(rows, output, |v| v),
+        (Format::Tiff, _, false) => write_tiff::<colortype::RGBA8, _>(rows, output, |v| v as u8),
+        (Format::Tiff, _, true) => write_tiff::<colortype::RGBA16, _>(rows, output, |v| v),
+        _ => Err(unstreamable(&format!("{} output", format))),
+    }
+}
+
+fn write_png<W: Write>(rows: &mut dyn Rows, output: W) -> Result<(), ImgtoolsError> {
+    let ((width, height), layout) = (rows.size(), rows.layout());
+    let mut encoder = png::Encoder::new(output, width, height);
+    encoder.set_color(match layout.channels {
+        1 => png::ColorType::Grayscale,
+        2 => png::ColorType::GrayscaleAlpha,
+        3 => png::ColorType::Rgb,
+        _ => png::ColorType::Rgba,
+    });
+    encoder.set_depth(match layout.sixteen {
+        true => png::BitDepth::Sixteen,
+        false => png::BitDepth::Eight,
+    });
+    let mut header = encoder.write_header().map_err(png_error)?;
+    let mut writer = header.stream_writer().map_err(png_error)?;
+    while let Some(row) = rows.next_row()? {
+        let bytes: Vec<u8> = match layout.sixteen {
+            true => row.iter().flat_map(|v| v.to_be_bytes()).collect(),
+            false => row.iter().map(|&v| v as u8).collect(),
+        };
+        writer
+            .write_all(&bytes)
+            .map_err(|e| ImgtoolsError::Encode(e.into()))?;
+    }
+    writer.finish().map_err(png_error)?;
+    header.finish().map_err(png_error)
+}
+
+/// Write TIFF strips of about `STRIP_BYTES`, gray with alpha as RGBA
+fn write_tiff<C, W>(
+    rows: &mut dyn Rows,
+    output: W,
+    sample: fn(u16) -> C::Inner,
+) -> Result<(), ImgtoolsError>
+where
+    C: colortype::ColorType,
+    [C::Inner]: TiffValue,
+    W: Write + Seek,
+{
+    let ((width, height), layout) = (rows.size(), rows.layout());
+    let mut encoder = TiffEncoder::new(output).map_err(tiff_error)?;
+    let mut image = encoder.new_image::<C>(width, height).map_err(tiff_error)?;
+    let per_strip = (STRIP_BYTES / layout.row_bytes(width).max(1)).clamp(1, height as u64) as u32;
+    image.rows_per_strip(per_strip).map_err(tiff_error)?;
+    let (mut strip, mut count) = (Vec::new(), 0);
+    while let Some(row) = rows.next_row()? {
+        match layout.channels {
+            2 => strip.extend(
+                row.chunks_exact(2)
+                    .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                    .map(sample),
+            ),
+            _ => strip.extend(row.into_iter().map(sample)),
+        }
+        count += 1;
+        if count == per_strip {
+            image.write_strip(&strip).map_err(tiff_error)?;
+            (strip, count) = (Vec::new(), 0);
+        }
+    }
+    if count > 0 {
+        image.write_strip(&strip).map_err(tiff_error)?;
+    }
+    image.finish().map_err(tiff_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgb, RgbImage, Rgba};
+    use std::io::Cursor;
+
+    /// Rows of an image in memory
+    struct Memory(DynamicImage, u32);
+
+    impl Rows for Memory {
+        fn size(&self) -> (u32, u32) {
+            (self.0.width(), self.0.height())
+        }
+
+        fn layout(&self) -> Layout {
+            Layout {
+                channels: self.0.color().channel_count() as usize,
+                sixteen: false,
+            }
+        }
+
+        fn next_row(&mut self) -> Result<Option<Vec<u16>>, ImgtoolsError> {
+            if self.1 == self.0.height() {
+                return Ok(None);
+            }
+            let row = self.0.crop_imm(0, self.1, self.0.width(), 1);
+            self.1 += 1;
+            Ok(Some(row.as_bytes().iter().map(|&v| v as u16).collect()))
+        }
+    }
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(37, 23, |x, y| {
+            Rgb([(x * 7) as u8, (y * 11) as u8, ((x * y) % 256) as u8])
+        }))
+    }
+
+    fn collect(mut rows: impl Rows) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(row) = rows.next_row().unwrap() {
+            out.extend(row.into_iter().map(|v| v as u8));
+        }
+        out
+    }
+
+    #[test]
+    fn test_crop_and_resize_rows() {
+        let img = gradient();
+        let crop = CropRows::new(Box::new(Memory(img.clone(), 0)), (5, 3, 20, 100));
+        assert_eq!(crop.size(), (20, 20));
+        assert_eq!(collect(crop), img.crop_imm(5, 3, 20, 100).as_bytes());
+        // Rows decoded ahead arrive in order and end the same
+        let ahead = DecodeAhead::new(Box::new(Memory(img.clone(), 0)));
+        assert_eq!(ahead.size(), (37, 23));
+        assert_eq!(collect(ahead), img.as_bytes());
+
+        // Resizing matches the image crate up to rounding
+        for filter in [Filter::Triangle, Filter::CatmullRom, Filter::Lanczos3] {
+            for size in [(11, 7), (80, 50)] {
+                let rows = ResizeRows::new(Box::new(Memory(img.clone(), 0)), size, filter);
+                let expected = img.resize_exact(size.0, size.1, filter.into());
+                let got = collect(rows);
+                assert_eq!(got.len(), expected.as_bytes().len());
+                let mut pairs = got.iter().zip(expected.as_bytes());
+                assert!(pairs.all(|(a, b)| a.abs_diff(*b) <= 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_stamp_rows() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([0, 0, 0])));
+        let stamp = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 128]));
+        let rows = StampRows::new(Box::new(Memory(img, 0)), vec![stamp], vec![(0, 7, 3)]);
+        let out = collect(rows);
+        let at = |x: usize, y: usize| out[(y * 8 + x) * 3];
+        assert_eq!(at(7, 3), 128);
+        assert_eq!(at(7, 4), 128);
+        assert_eq!((at(6, 3), at(7, 5), at(7, 2)), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_write_rows() {
+        let img = gradient();
+        for format in [Format::Png, Format::Tiff] {
+            let mut data = Cursor::new(Vec::new());
+            write_rows(&mut Memory(img.clone(), 0), format, &mut data).unwrap();
+            let decoded = image::load_from_memory(data.get_ref()).unwrap();
+            assert_eq!(decoded.to_rgb8(), img.to_rgb8());
+        }
+    }
+}
@@ -0,0 +1,720 @@
+use crate::ImgtoolsError;
+use crate::metadata::PNG_XMP_KEYWORD;
+use crate::tags::set_tiff_fields;
+use clap::ValueEnum;
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Tag, Value};
+use image::ImageFormat;
+use image::metadata::Orientation;
+use std::io::Cursor;
+
+/// Metadata that stripping leaves in place
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Keep {
+    /// The embedded ICC color profile
+    Icc,
+    /// The EXIF orientation, as the only EXIF field left
+    Orientation,
+}
+
+/// Precision capture times are rounded down to when redacting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimeFuzz {
+    Hour,
+    Day,
+    Month,
+}
+
+/// PNG chunks besides the critical ones that affect how the image looks
+const PNG_RENDERING: [&[u8; 4]; 10] = [
+    b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"cICP", b"sBIT", b"bKGD", b"acTL", b"fcTL", b"fdAT",
+];
+
+/// VP8X flags of the chunks that stripping may remove
+const WEBP_ICC: u8 = 0x20;
+const WEBP_EXIF: u8 = 0x08;
+const WEBP_XMP: u8 = 0x04;
+
+/// EXIF fields that tell who took a photo or with which camera, besides GPS
+const SENSITIVE_TAGS: [Tag; 7] = [
+    Tag::BodySerialNumber,
+    Tag::LensSerialNumber,
+    Tag::CameraOwnerName,
+    Tag::Artist,
+    Tag::ImageUniqueID,
+    // Vendor data, which holds serial numbers and can't be moved intact
+    Tag::MakerNote,
+    // CameraSerialNumber of DNG files
+    Tag(Context::Tiff, 0xC62F),
+];
+
+/// TIFF tag of the XMP packet
+const TIFF_XMP: Tag = Tag(Context::Tiff, 700);
+
+/// Fractions of a second of the capture times, dropped when they are fuzzed
+const SUBSEC_TAGS: [Tag; 3] = [
+    Tag::SubSecTime,
+    Tag::SubSecTimeOriginal,
+    Tag::SubSecTimeDigitized,
+];
+
+/// Replacement of the EXIF data, `None` drops it
+pub(crate) type ExifRewrite<'a> = &'a dyn Fn(&[u8]) -> Result<Option<Vec<u8>>, ImgtoolsError>;
+
+/// What rewriting an image keeps of its metadata
+pub(crate) struct Rewrite<'a> {
+    pub(crate) icc: bool,
+    pub(crate) xmp: bool,
+    /// Comments, text chunks and application data besides EXIF, XMP and ICC
+    pub(crate) other: bool,
+    pub(crate) exif: ExifRewrite<'a>,
+    /// EXIF to add to images without any
+    pub(crate) insert: Option<Vec<u8>>,
+}
+
+/// Remove metadata from an encoded image without decoding it
+///
+/// EXIF with GPS and thumbnails, XMP, comments, text chunks and other
+/// ancillary data are dropped, except what `keep` names. Chunks that change
+/// how the image looks, such as transparency, gamma and animation control,
+/// stay. Returns `None` for formats other than JPEG, PNG and WebP, and for
+/// data that can't be parsed.
+pub fn strip_metadata(data: &[u8], format: ImageFormat, keep: &[Keep]) -> Option<Vec<u8>> {
+    let rewrite = Rewrite {
+        icc: keep.contains(&Keep::Icc),
+        xmp: false,
+        other: false,
+        exif: &|exif| Ok(kept_exif(exif, keep)),
+        insert: None,
+    };
+    // Keeping the orientation can't fail
+    rewrite_metadata(data, format, &rewrite).ok().flatten()
+}
+
+/// Remove location, serial numbers and owner names from an encoded image
+///
+/// Exposure and camera settings stay, as does everything outside EXIF but
+/// XMP, which often repeats the location. MakerNote data goes too since it
+/// holds serial numbers. With a fuzz the capture times are rounded down and
+/// their fractions of a second removed. TIFF files are redacted in place,
+/// see `set_tiff_fields`. Returns `None` for other formats and data that
+/// can't be parsed.
+pub fn redact_metadata(
+    data: &[u8],
+    format: ImageFormat,
+    fuzz: Option<TimeFuzz>,
+) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    if format == ImageFormat::Tiff {
+        return redact_tiff(data, fuzz);
+    }
+    let rewrite = Rewrite {
+        icc: true,
+        xmp: false,
+        other: true,
+        exif: &|exif| redact_exif(exif, fuzz).map(Some),
+        insert: None,
+    };
+    rewrite_metadata(data, format, &rewrite)
+}
+
+pub(crate) fn rewrite_metadata(
+    data: &[u8],
+    format: ImageFormat,
+    rewrite: &Rewrite,
+) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    match format {
+        ImageFormat::Jpeg => rewrite_jpeg(data, rewrite),
+        ImageFormat::Png => rewrite_png(data, rewrite),
+        ImageFormat::WebP => rewrite_webp(data, rewrite),
+        _ => Ok(None),
+    }
+}
+
+/// EXIF holding nothing but the orientation, if it is kept and not upright
+pub(crate) fn kept_exif(exif: &[u8], keep: &[Keep]) -> Option<Vec<u8>> {
+    if !keep.contains(&Keep::Orientation) {
+        return None;
+    }
+    match Orientation::from_exif_chunk(exif)? {
+        Orientation::NoTransforms => None,
+        orientation => Some(orientation_exif(orientation)),
+    }
+}
+
+/// Little-endian TIFF block with a single Orientation field
+fn orientation_exif(orientation: Orientation) -> Vec<u8> {
+    let mut exif = b"II*\0".to_vec();
+    exif.extend(8u32.to_le_bytes());
+    exif.extend(1u16.to_le_bytes());
+    // Tag 0x0112, SHORT, count 1, the value padded to four bytes
+    exif.extend(0x0112u16.to_le_bytes());
+    exif.extend(3u16.to_le_bytes());
+    exif.extend(1u32.to_le_bytes());
+    exif.extend([orientation.to_exif(), 0, 0, 0]);
+    exif.extend(0u32.to_le_bytes());
+    exif
+}
+
+/// EXIF without GPS and the sensitive fields, the thumbnail is kept
+pub(crate) fn redact_exif(exif: &[u8], fuzz: Option<TimeFuzz>) -> Result<Vec<u8>, ImgtoolsError> {
+    let exif = exif::Reader::new()
+        .read_raw(exif.to_vec())
+        .map_err(ImgtoolsError::Metadata)?;
+    let fuzzed: Vec<Field> = exif
+        .fields()
+        .filter(|field| {
+            field.tag.context() != Context::Gps
+                && !SENSITIVE_TAGS.contains(&field.tag)
+                && !matches!(field.value, Value::Unknown(..))
+        })
+        .filter_map(|field| match fuzz {
+            Some(_) if SUBSEC_TAGS.contains(&field.tag) => None,
+            Some(fuzz)
+                if matches!(
+                    field.tag,
+                    Tag::DateTime | Tag::DateTimeOriginal | Tag::DateTimeDigitized
+                ) =>
+            {
+                Some(Field {
+                    value: round_time(&field.value, fuzz),
+                    ..field.clone()
+                })
+            }
+            _ => Some(field.clone()),
+        })
+        .collect();
+    write_exif(&fuzzed, thumbnail(&exif), exif.little_endian())
+}
+
+/// Redact the fields of a TIFF file without touching its image data
+fn redact_tiff(data: &[u8], fuzz: Option<TimeFuzz>) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    let fuzzed: Vec<Field> = match fuzz {
+        Some(fuzz) => {
+            let exif = exif::Reader::new()
+                .read_raw(data.to_vec())
+                .map_err(ImgtoolsError::Metadata)?;
+            exif.fields()
+                .filter(|field| {
+                    field.ifd_num == In::PRIMARY
+                        && matches!(
+                            field.tag,
+                            Tag::DateTime | Tag::DateTimeOriginal | Tag::DateTimeDigitized
+                        )
+                })
+                .map(|field| Field {
+                    value: round_time(&field.value, fuzz),
+                    ..field.clone()
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    let removed = |tag: Tag| {
+        SENSITIVE_TAGS.contains(&tag)
+            || tag == Tag::GPSInfoIFDPointer
+            || tag == TIFF_XMP
+            || fuzz.is_some() && SUBSEC_TAGS.contains(&tag)
+    };
+    Ok(set_tiff_fields(data, &fuzzed, &removed))
+}
+
+/// The JPEG thumbnail of parsed EXIF data
+pub(crate) fn thumbnail(exif: &exif::Exif) -> Option<&[u8]> {
+    let offset = exif.get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?;
+    let length = exif.get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?;
+    let offset = offset.value.get_uint(0)? as usize;
+    let length = length.value.get_uint(0)? as usize;
+    exif.buf().get(offset..offset + length)
+}
+
+/// Lay out EXIF fields and a thumbnail as a TIFF block
+pub(crate) fn write_exif<'a>(
+    fields: impl IntoIterator<Item = &'a Field>,
+    thumbnail: Option<&'a [u8]>,
+    little_endian: bool,
+) -> Result<Vec<u8>, ImgtoolsError> {
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    if let Some(thumbnail) = thumbnail {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+    let mut data = Cursor::new(Vec::new());
+    writer
+        .write(&mut data, little_endian)
+        .map_err(ImgtoolsError::Metadata)?;
+    Ok(data.into_inner())
+}
+
+/// Round an EXIF date and time such as `2024:05:17 14:23:09` down
+fn round_time(value: &Value, fuzz: TimeFuzz) -> Value {
+    let Value::Ascii(lines) = value else {
+        return value.clone();
+    };
+    let keep = match fuzz {
+        TimeFuzz::Hour => 13,
+        TimeFuzz::Day => 10,
+        TimeFuzz::Month => 7,
+    };
+    const START: &[u8] = b"0000:00:01 00:00:00";
+    Value::Ascii(
+        lines
+            .iter()
+            .map(|line| match line.len() == START.len() {
+                true => [&line[..keep], &START[keep..]].concat(),
+                false => line.clone(),
+            })
+            .collect(),
+    )
+}
+
+fn rewrite_jpeg(data: &[u8], rewrite: &Rewrite) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Ok(None);
+    }
+    let mut result = vec![0xFF, 0xD8];
+    let mut at = 2;
+    let mut exif_seen = false;
+    loop {
+        // Fill bytes may precede a marker
+        while data.get(at + 1) == Some(&0xFF) {
+            at += 1;
+        }
+        let (Some(0xFF), Some(&marker)) = (data.get(at), data.get(at + 1)) else {
+            return Ok(None);
+        };
+        if marker == 0xD9 {
+            // Anything appended after the end of the image goes too
+            result.extend([0xFF, 0xD9]);
+            break;
+        }
+        let Some(segment) = data
+            .get(at + 2..at + 4)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .and_then(|length| data.get(at..at + 2 + length))
+        else {
+            return Ok(None);
+        };
+        let payload = &segment[4..];
+        match marker {
+            // JFIF without its thumbnail, JFXX only holds thumbnails
+            0xE0 if !rewrite.other && payload.starts_with(b"JFIF\0") && payload.len() >= 14 => {
+                let mut jfif = payload[..12].to_vec();
+                jfif.extend([0, 0]);
+                push_segment(&mut result, 0xE0, &jfif)?;
+            }
+            0xE1 if payload.starts_with(b"Exif\0\0") => {
+                exif_seen = true;
+                if let Some(exif) = (rewrite.exif)(&payload[6..])? {
+                    push_segment(&mut result, 0xE1, &[b"Exif\0\0".as_slice(), &exif].concat())?;
+                }
+            }
+            // XMP and extended XMP
+            0xE1 if payload.starts_with(b"http://ns.adobe.com/") => {
+                if rewrite.xmp {
+                    result.extend(segment);
+                }
+            }
+            0xE2 if payload.starts_with(b"ICC_PROFILE\0") => {
+                if rewrite.icc {
+                    result.extend(segment);
+                }
+            }
+            // Adobe's segment tells how the colors are transformed
+            0xEE if payload.starts_with(b"Adobe") => result.extend(segment),
+            // Other application segments and comments
+            0xE0..=0xEF | 0xFE if !rewrite.other => {}
+            _ => result.extend(segment),
+        }
+        at += segment.len();
+
+        // Entropy-coded data runs up to the next marker that isn't a restart
+        if marker == 0xDA {
+            let start = at;
+            while at + 1 < data.len()
+                && (data[at] != 0xFF || matches!(data[at + 1], 0x00 | 0xD0..=0xD7))
+            {
+                at += 1;
+            }
+            result.extend(&data[start..at]);
+        }
+    }
+
+    // New EXIF goes right after the JFIF segment, or else the start of the image
+    if let Some(exif) = rewrite.insert.as_deref().filter(|_| !exif_seen) {
+        let mut at = 2;
+        if result.get(2..4) == Some(&[0xFF, 0xE0]) {
+            at = 4 + u16::from_be_bytes([result[4], result[5]]) as usize;
+        }
+        let mut segment = Vec::new();
+        push_segment(&mut segment, 0xE1, &[b"Exif\0\0".as_slice(), exif].concat())?;
+        result.splice(at..at, segment);
+    }
+    Ok(Some(result))
+}
+
+fn push_segment(result: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<(), ImgtoolsError> {
+    let length = u16::try_from(payload.len() + 2).map_err(|_| {
+        ImgtoolsError::InvalidArgument("Metadata is too large for a JPEG segment".into())
+    })?;
+    result.extend([0xFF, marker]);
+    result.extend(length.to_be_bytes());
+    result.extend(payload);
+    Ok(())
+}
+
+fn rewrite_png(data: &[u8], rewrite: &Rewrite) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return Ok(None);
+    }
+    let mut result = SIGNATURE.to_vec();
+    let mut at = SIGNATURE.len();
+    let mut exif_seen = false;
+    while at < data.len() {
+        let Some(chunk) = data
+            .get(at..at + 4)
+            .map(|length| u32::from_be_bytes([length[0], length[1], length[2], length[3]]))
+            .and_then(|length| data.get(at..at + 12 + length as usize))
+        else {
+            return Ok(None);
+        };
+        let (kind, payload) = (&chunk[4..8], &chunk[8..chunk.len() - 4]);
+        let kept = match kind {
+            b"eXIf" => {
+                exif_seen = true;
+                if let Some(exif) = (rewrite.exif)(payload)? {
+                    result.extend(png_chunk(b"eXIf", &exif));
+                }
+                false
+            }
+            b"iCCP" => rewrite.icc,
+            b"iTXt" if payload.starts_with(PNG_XMP_KEYWORD) => rewrite.xmp,
+            // Critical chunks start with an upper case letter
+            kind => {
+                kind[0].is_ascii_uppercase()
+                    || PNG_RENDERING.iter().any(|&known| known == kind)
+                    || rewrite.other
+            }
+        };
+        if kept {
+            result.extend(chunk);
+        }
+        at += chunk.len();
+        if kind == b"IEND" {
+            break;
+        }
+    }
+
+    // New EXIF goes right after the header, it has to come before the image data
+    const AFTER_IHDR: usize = 8 + 25;
+    if let Some(exif) = rewrite.insert.as_deref().filter(|_| !exif_seen)
+        && result.get(12..16) == Some(b"IHDR")
+        && result.len() >= AFTER_IHDR
+    {
+        result.splice(AFTER_IHDR..AFTER_IHDR, png_chunk(b"eXIf", exif));
+    }
+    Ok(Some(result))
+}
+
+fn png_chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut chunk = (payload.len() as u32).to_be_bytes().to_vec();
+    chunk.extend(kind);
+    chunk.extend(payload);
+    chunk.extend(crc32fast::hash(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+fn rewrite_webp(data: &[u8], rewrite: &Rewrite) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Ok(None);
+    }
+    let mut chunks = Vec::new();
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let kind = &data[at..at + 4];
+        let length = u32::from_le_bytes([data[at + 4], data[at + 5], data[at + 6], data[at + 7]]);
+        let Some(payload) = data.get(at + 8..at + 8 + length as usize) else {
+            return Ok(None);
+        };
+        match kind {
+            b"ICCP" if !rewrite.icc => {}
+            b"EXIF" => {
+                if let Some(exif) = (rewrite.exif)(payload)? {
+                    chunks.push((kind, exif));
+                }
+            }
+            b"XMP " if !rewrite.xmp => {}
+            b"VP8X" | b"VP8 " | b"VP8L" | b"ALPH" | b"ANIM" | b"ANMF" | b"ICCP" | b"XMP " => {
+                chunks.push((kind, payload.to_vec()))
+            }
+            _ if rewrite.other => chunks.push((kind, payload.to_vec())),
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        at += 8 + length as usize + length as usize % 2;
+    }
+
+    // New EXIF goes last, simple files need an extended header to flag it
+    if let Some(exif) = &rewrite.insert
+        && !chunks.iter().any(|(kind, _)| *kind == b"EXIF")
+    {
+        if !chunks.iter().any(|(kind, _)| *kind == b"VP8X") {
+            let Some(header) = chunks
+                .first()
+                .and_then(|(kind, payload)| webp_header(kind, payload))
+            else {
+                return Ok(None);
+            };
+            chunks.insert(0, (b"VP8X", header));
+        }
+        chunks.push((b"EXIF", exif.clone()));
+    }
+
+    let has = |kind: &[u8]| chunks.iter().any(|(k, _)| *k == kind);
+    let flags = [
+        (b"ICCP", WEBP_ICC),
+        (b"EXIF", WEBP_EXIF),
+        (b"XMP ", WEBP_XMP),
+    ]
+    .iter()
+    .filter(|(kind, _)| has(kind.as_slice()))
+    .fold(0, |flags, (_, flag)| flags | flag);
+    if let Some((_, vp8x)) = chunks.iter_mut().find(|(kind, _)| *kind == b"VP8X")
+        && let Some(first) = vp8x.first_mut()
+    {
+        *first = *first & !(WEBP_ICC | WEBP_EXIF | WEBP_XMP) | flags;
+    }
+
+    let mut body = b"WEBP".to_vec();
+    for (kind, payload) in chunks {
+        body.extend(kind);
+        body.extend((payload.len() as u32).to_le_bytes());
+        body.extend(&payload);
+        if payload.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+    let mut result = b"RIFF".to_vec();
+    result.extend((body.len() as u32).to_le_bytes());
+    result.extend(body);
+    Ok(Some(result))
+}
+
+/// Extended header for a simple WebP file from the size in its image chunk
+fn webp_header(kind: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    const WEBP_ALPHA: u8 = 0x10;
+    let (width, height, flags) = match kind {
+        // Lossy frames start with a tag, a start code and two 14-bit sizes
+        b"VP8 " if payload.len() >= 10 && payload[3..6] == [0x9D, 0x01, 0x2A] => {
+            let size = |at: usize| u16::from_le_bytes([payload[at], payload[at + 1]]) & 0x3FFF;
+            (size(6) as u32, size(8) as u32, 0)
+        }
+        // Lossless images pack both sizes minus one and an alpha bit after a signature
+        b"VP8L" if payload.first() == Some(&0x2F) && payload.len() >= 5 => {
+            let bits = u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]);
+            let alpha = if bits >> 28 & 1 == 1 { WEBP_ALPHA } else { 0 };
+            ((bits & 0x3FFF) + 1, (bits >> 14 & 0x3FFF) + 1, alpha)
+        }
+        _ => return None,
+    };
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let mut header = vec![flags, 0, 0, 0];
+    header.extend(&(width - 1).to_le_bytes()[..3]);
+    header.extend(&(height - 1).to_le_bytes()[..3]);
+    Some(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, Metadata, encode_with_metadata, read_orientation};
+    use image::{DynamicImage, GenericImageView};
+    use std::io::Cursor;
+
+    fn tagged(format: Format) -> Vec<u8> {
+        let img = DynamicImage::new_rgb8(4, 2);
+        let metadata = Metadata {
+            exif: Some(orientation_exif(Orientation::Rotate90)),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+            icc: Some(crate::profile_data(&crate::Profile::DisplayP3).unwrap()),
+        };
+        let mut data = Cursor::new(Vec::new());
+        encode_with_metadata(&img, format, &metadata, &mut data).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_strip() {
+        for format in [Format::Jpeg, Format::Png, Format::WebP] {
+            let data = tagged(format);
+            let image_format = ImageFormat::from(format);
+            let before = Metadata::read(&data, Some(image_format)).unwrap();
+            assert!(before.exif.is_some() && before.icc.is_some(), "{}", format);
+
+            let stripped = strip_metadata(&data, image_format, &[]).unwrap();
+            assert!(stripped.len() < data.len(), "{}", format);
+            assert!(
+                Metadata::read(&stripped, Some(image_format))
+                    .unwrap()
+                    .is_empty()
+            );
+            let img = image::load_from_memory_with_format(&stripped, image_format).unwrap();
+            assert_eq!(img.dimensions(), (4, 2), "{}", format);
+
+            let kept =
+                strip_metadata(&data, image_format, &[Keep::Icc, Keep::Orientation]).unwrap();
+            let after = Metadata::read(&kept, Some(image_format)).unwrap();
+            assert_eq!(after.icc, before.icc, "{}", format);
+            assert_eq!(after.xmp, None, "{}", format);
+            assert_eq!(
+                read_orientation(&kept, Some(image_format)).unwrap(),
+                Orientation::Rotate90
+            );
+        }
+        assert_eq!(strip_metadata(b"BM", ImageFormat::Bmp, &[]), None);
+    }
+
+    #[test]
+    fn test_kept_exif() {
+        let exif = orientation_exif(Orientation::FlipHorizontal);
+        assert_eq!(kept_exif(&exif, &[]), None);
+        assert_eq!(kept_exif(&exif, &[Keep::Orientation]), Some(exif));
+        let upright = orientation_exif(Orientation::NoTransforms);
+        assert_eq!(kept_exif(&upright, &[Keep::Orientation]), None);
+    }
+
+    fn camera_exif() -> Vec<u8> {
+        let ascii = |tag, value: &str| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        };
+        let fields = [
+            ascii(Tag::Make, "Camera"),
+            ascii(Tag::Artist, "Someone"),
+            ascii(Tag::DateTimeOriginal, "2024:05:17 14:23:09"),
+            ascii(Tag::SubSecTimeOriginal, "25"),
+            ascii(Tag::BodySerialNumber, "12345"),
+            ascii(Tag::GPSLatitudeRef, "N"),
+            Field {
+                tag: Tag::ExposureTime,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(1, 250).into()]),
+            },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut data = Cursor::new(Vec::new());
+        writer.write(&mut data, false).unwrap();
+        data.into_inner()
+    }
+
+    fn fields(exif: &[u8]) -> Vec<(Tag, String)> {
+        let exif = exif::Reader::new().read_raw(exif.to_vec()).unwrap();
+        exif.fields()
+            .map(|field| (field.tag, field.display_value().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_redact_exif() {
+        let redacted = fields(&redact_exif(&camera_exif(), None).unwrap());
+        let tags: Vec<Tag> = redacted.iter().map(|(tag, _)| *tag).collect();
+        assert!(tags.contains(&Tag::Make));
+        assert!(tags.contains(&Tag::ExposureTime));
+        assert!(tags.contains(&Tag::SubSecTimeOriginal));
+        for tag in [Tag::Artist, Tag::BodySerialNumber, Tag::GPSLatitudeRef] {
+            assert!(!tags.contains(&tag), "{}", tag);
+        }
+
+        let fuzzed = fields(&redact_exif(&camera_exif(), Some(TimeFuzz::Day)).unwrap());
+        assert!(fuzzed.contains(&(Tag::DateTimeOriginal, "2024-05-17 00:00:00".into())));
+        assert!(
+            !fuzzed
+                .iter()
+                .any(|(tag, _)| *tag == Tag::SubSecTimeOriginal)
+        );
+        let month = fields(&redact_exif(&camera_exif(), Some(TimeFuzz::Month)).unwrap());
+        assert!(month.contains(&(Tag::DateTimeOriginal, "2024-05-01 00:00:00".into())));
+    }
+
+    #[test]
+    fn test_redact() {
+        for format in [Format::Jpeg, Format::Png, Format::WebP] {
+            let img = DynamicImage::new_rgb8(4, 2);
+            let icc = crate::profile_data(&crate::Profile::DisplayP3).unwrap();
+            let metadata = Metadata {
+                exif: Some(camera_exif()),
+                xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+                icc: Some(icc.clone()),
+            };
+            let mut data = Cursor::new(Vec::new());
+            encode_with_metadata(&img, format, &metadata, &mut data).unwrap();
+
+            let image_format = ImageFormat::from(format);
+            let redacted = redact_metadata(data.get_ref(), image_format, None)
+                .unwrap()
+                .unwrap();
+            let after = Metadata::read(&redacted, Some(image_format)).unwrap();
+            assert_eq!(after.icc, Some(icc), "{}", format);
+            assert_eq!(after.xmp, None, "{}", format);
+            let tags: Vec<Tag> = fields(&after.exif.unwrap())
+                .into_iter()
+                .map(|(tag, _)| tag)
+                .collect();
+            assert!(tags.contains(&Tag::ExposureTime), "{}", format);
+            assert!(!tags.contains(&Tag::GPSLatitudeRef), "{}", format);
+            let img = image::load_from_memory_with_format(&redacted, image_format).unwrap();
+            assert_eq!(img.dimensions(), (4, 2), "{}", format);
+        }
+        assert_eq!(
+            redact_metadata(b"BM", ImageFormat::Bmp, None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_redact_tiff() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(6, 4, |x, y| {
+            image::Rgb([x as u8 * 40, y as u8 * 60, 90])
+        }));
+        let metadata = Metadata {
+            exif: Some(camera_exif()),
+            ..Metadata::default()
+        };
+        let mut data = Cursor::new(Vec::new());
+        encode_with_metadata(&img, Format::Tiff, &metadata, &mut data).unwrap();
+        let data = data.into_inner();
+        let contains = |data: &[u8], text: &[u8]| data.windows(text.len()).any(|w| w == text);
+        assert!(contains(&data, b"12345"));
+
+        let redacted = redact_metadata(&data, ImageFormat::Tiff, Some(TimeFuzz::Day))
+            .unwrap()
+            .unwrap();
+        let after = fields(&redacted);
+        assert!(after.contains(&(Tag::Make, "\"Camera\"".into())));
+        assert!(after.contains(&(Tag::ExposureTime, "1/250".into())));
+        assert!(after.contains(&(Tag::DateTimeOriginal, "2024-05-17 00:00:00".into())));
+        let tags: Vec<Tag> = after.iter().map(|(tag, _)| *tag).collect();
+        for tag in [
+            Tag::Artist,
+            Tag::BodySerialNumber,
+            Tag::GPSLatitudeRef,
+            Tag::SubSecTimeOriginal,
+        ] {
+            assert!(!tags.contains(&tag), "{}", tag);
+        }
+        // Nothing of the removed values is left in the file
+        for text in [&b"12345"[..], b"Someone", b"14:23:09"] {
+            assert!(!contains(&redacted, text));
+        }
+        let decoded = image::load_from_memory_with_format(&redacted, ImageFormat::Tiff).unwrap();
+        assert_eq!(decoded, img);
+    }
+}
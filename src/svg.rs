@@ -0,0 +1,2206 @@
+use crate::xml::{self, Element};
+use crate::{Color, DEFAULT_DENSITY, DecodeOptions, ImgtoolsError, Size};
+use image::error::{DecodingError, ImageFormatHint};
+use image::{ImageError, Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+/// Sub-scanlines sampled in every row of pixels, for anti-aliased edges
+const SUBSAMPLES: usize = 16;
+/// Largest distance in pixels between a curve and the lines it is drawn with
+const TOLERANCE: f32 = 0.1;
+/// Most pixels rendered, larger sizes come from a mistake in the document or the density
+const MAX_PIXELS: u64 = 1 << 28;
+/// Deepest nesting of elements and `use` references followed
+const MAX_DEPTH: usize = 64;
+/// Size of documents without a width, height or viewBox
+const DEFAULT_SIZE: f32 = 100.0;
+/// Font size ems and exs are measured in, text itself is not drawn
+const FONT_SIZE: f32 = 16.0;
+
+/// Rasterize an SVG document at the density of `options`, or to fit in its render size
+///
+/// Paths, basic shapes, strokes with dashes, linear and radial gradients,
+/// clip paths, masks, `use` references and style sheets with simple
+/// selectors are drawn. Text, embedded images and filters are left out.
+pub fn rasterize(data: &[u8], options: &DecodeOptions) -> Result<RgbaImage, ImgtoolsError> {
+    let DecodeOptions {
+        density,
+        render_size: size,
+        ..
+    } = *options;
+    if !(density.is_finite() && density > 0.0) {
+        return Err(ImgtoolsError::InvalidArgument(
+            "Density must be a positive number of dots per inch".into(),
+        ));
+    }
+    let text = String::from_utf8_lossy(data);
+    let root = xml::parse(&text).map_err(invalid)?;
+    if root.name != "svg" {
+        return Err(invalid(format!(
+            "the root element is <{}>, not <svg>",
+            root.name
+        )));
+    }
+
+    // Size in CSS pixels from width and height, or the aspect ratio of the viewBox
+    let view_box = root.get("viewBox").and_then(parse_view_box);
+    let length = |name| {
+        root.get(name)
+            .filter(|v| !v.ends_with('%'))
+            .and_then(|v| parse_length(v, 0.0))
+    };
+    let (width, height) = match (length("width"), length("height"), view_box) {
+        (Some(w), Some(h), _) => (w, h),
+        (Some(w), None, Some(vb)) => (w, w * vb[3] / vb[2]),
+        (None, Some(h), Some(vb)) => (h * vb[2] / vb[3], h),
+        (None, None, Some(vb)) => (vb[2], vb[3]),
+        (w, h, None) => (w.unwrap_or(DEFAULT_SIZE), h.unwrap_or(DEFAULT_SIZE)),
+    };
+    if !(width > 0.0 && height > 0.0 && width.is_finite() && height.is_finite()) {
+        return Err(invalid("the document has no size".into()));
+    }
+    let (pixels_w, pixels_h) = match size {
+        Some(Size(w, h)) => {
+            let ratio = (w as f32 / width).min(h as f32 / height);
+            ((width * ratio).round(), (height * ratio).round())
+        }
+        None => {
+            let scale = density / DEFAULT_DENSITY;
+            ((width * scale).round(), (height * scale).round())
+        }
+    };
+    let (pixels_w, pixels_h) = (pixels_w.max(1.0), pixels_h.max(1.0));
+    if pixels_w as u64 * pixels_h as u64 > MAX_PIXELS || pixels_w > u32::MAX as f32 {
+        return Err(ImgtoolsError::InvalidArgument(format!(
+            "The SVG would be rasterized at {}x{} pixels, use a lower --density or --render-size",
+            pixels_w, pixels_h
+        )));
+    }
+    options.check_alloc(pixels_w as u64 * pixels_h as u64 * 4)?;
+
+    let mut ctm = Matrix::scale(pixels_w / width, pixels_h / height);
+    if let Some(vb) = view_box {
+        let align = root.get("preserveAspectRatio").unwrap_or_default();
+        ctm = ctm.multiply(view_box_transform(vb, (width, height), align));
+    }
+    let viewport = view_box.map_or((width, height), |vb| (vb[2], vb[3]));
+    let document = Document::new(&root, viewport);
+    let mut canvas = Canvas::new(pixels_w as usize, pixels_h as usize);
+    document.render(&root, &Style::default(), ctm, &mut canvas, 0);
+    Ok(canvas.into_image())
+}
+
+/// Error for a document that can't be read
+fn invalid(message: String) -> ImgtoolsError {
+    ImgtoolsError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("SVG".into()),
+        message,
+    )))
+}
+
+impl Element {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Id of the element a `use` or gradient refers to
+    fn href(&self) -> Option<&str> {
+        self.get("href")
+            .or_else(|| self.get("xlink:href"))
+            .and_then(|href| href.trim().strip_prefix('#'))
+    }
+
+    /// Length attribute, percentages of `reference`
+    fn length(&self, name: &str, reference: f32) -> Option<f32> {
+        self.get(name).and_then(|v| parse_length(v, reference))
+    }
+}
+
+/// Simple selector of a style sheet rule, such as `rect`, `.logo` or `path#mark.dark`
+#[derive(Debug, Default)]
+struct Selector {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+}
+
+impl Selector {
+    /// Parse a selector, combinators, attributes and pseudo classes are not understood
+    fn parse(text: &str) -> Option<Self> {
+        if text.is_empty() || text.contains(|c: char| c.is_whitespace() || ">+~:[".contains(c)) {
+            return None;
+        }
+        let mut selector = Selector::default();
+        let start = text.find(['.', '#']).unwrap_or(text.len());
+        match &text[..start] {
+            "" | "*" => {}
+            tag => selector.tag = Some(tag.to_string()),
+        }
+        let mut rest = &text[start..];
+        while let Some(kind) = rest.chars().next() {
+            let end = rest[1..].find(['.', '#']).map_or(rest.len(), |i| i + 1);
+            let name = rest[1..end].to_string();
+            match kind {
+                '#' => selector.id = Some(name),
+                _ => selector.classes.push(name),
+            }
+            rest = &rest[end..];
+        }
+        Some(selector)
+    }
+
+    fn specificity(&self) -> u32 {
+        self.id.is_some() as u32 * 100 + self.classes.len() as u32 * 10 + self.tag.is_some() as u32
+    }
+
+    fn matches(&self, element: &Element) -> bool {
+        let classes = element.get("class").unwrap_or_default();
+        self.tag.as_ref().is_none_or(|tag| *tag == element.name)
+            && self
+                .id
+                .as_deref()
+                .is_none_or(|id| element.get("id") == Some(id))
+            && self
+                .classes
+                .iter()
+                .all(|class| classes.split_whitespace().any(|c| c == class))
+    }
+}
+
+/// Rules of the style sheets in `<style>` elements, by increasing specificity
+fn parse_css(text: &str) -> Vec<(Selector, Vec<(String, String)>)> {
+    let mut text = text.to_string();
+    while let Some(start) = text.find("/*") {
+        let end = text[start..]
+            .find("*/")
+            .map_or(text.len(), |end| start + end + 2);
+        text.replace_range(start..end, "");
+    }
+    let mut rules = Vec::new();
+    for block in text.split('}') {
+        let Some((selectors, body)) = block.split_once('{') else {
+            continue;
+        };
+        let declarations = parse_declarations(body);
+        for selector in selectors
+            .split(',')
+            .filter_map(|s| Selector::parse(s.trim()))
+        {
+            rules.push((selector, declarations.clone()));
+        }
+    }
+    rules.sort_by_key(|(selector, _)| selector.specificity());
+    rules
+}
+
+/// Parse `name: value; ...` declarations of a rule or a style attribute
+fn parse_declarations(text: &str) -> Vec<(String, String)> {
+    text.split(';')
+        .filter_map(|declaration| declaration.split_once(':'))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value.strip_suffix("!important").unwrap_or(value).trim();
+            (name.trim().to_lowercase(), value.to_string())
+        })
+        .collect()
+}
+
+/// How the inside of a shape is told from the outside
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FillRule {
+    NonZero,
+    EvenOdd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineCap {
+    Butt,
+    Round,
+    Square,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineJoin {
+    Miter,
+    Round,
+    Bevel,
+}
+
+/// What the inside or the outline of a shape is painted with
+#[derive(Debug, Clone, PartialEq)]
+enum Paint {
+    None,
+    Color(Rgba<u8>),
+    /// The value of the color property
+    CurrentColor,
+    /// A gradient by id, and the paint to use if there is no such gradient
+    Url(String, Box<Paint>),
+}
+
+/// Parse a paint, `None` for values that aren't one
+fn parse_paint(value: &str) -> Option<Paint> {
+    let value = value.trim();
+    if let Some(rest) = value.strip_prefix("url(") {
+        let (id, fallback) = rest.split_once(')')?;
+        let id = id.trim().trim_matches(['"', '\'']).strip_prefix('#')?;
+        let fallback = match fallback.trim() {
+            "" => Paint::None,
+            fallback => parse_paint(fallback)?,
+        };
+        return Some(Paint::Url(id.to_string(), Box::new(fallback)));
+    }
+    match value {
+        "none" => Some(Paint::None),
+        "currentColor" | "currentcolor" => Some(Paint::CurrentColor),
+        _ => parse_color(value).map(Paint::Color),
+    }
+}
+
+/// Parse a CSS color, including `rgb()` with percentages
+fn parse_color(value: &str) -> Option<Rgba<u8>> {
+    // CSS names first, its green is darker than the green of the color options
+    if let Some([r, g, b]) = crate::colors::named(value) {
+        return Some(Rgba([r, g, b, 255]));
+    }
+    if let Some(content) = value
+        .strip_prefix("rgb(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .filter(|content| content.contains('%'))
+    {
+        let parts: Vec<u8> = content
+            .split(',')
+            .map(|part| {
+                let percent = part.trim().strip_suffix('%')?.parse::<f32>().ok()?;
+                Some((percent.clamp(0.0, 100.0) * 2.55).round() as u8)
+            })
+            .collect::<Option<_>>()?;
+        return match parts[..] {
+            [r, g, b] => Some(Rgba([r, g, b, 255])),
+            _ => None,
+        };
+    }
+    value.parse::<Color>().ok().map(Rgba::from)
+}
+
+/// Parse an opacity as a number or a percentage, clamped to 0.0 ~ 1.0
+fn parse_opacity(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let opacity = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f32>().ok()? / 100.0,
+        None => value.parse::<f32>().ok()?,
+    };
+    opacity.is_finite().then(|| opacity.clamp(0.0, 1.0))
+}
+
+/// Parse a length in user units, percentages of `reference`
+fn parse_length(value: &str, reference: f32) -> Option<f32> {
+    let mut scanner = Scanner::new(value);
+    let number = scanner.number()?;
+    let scale = match value[scanner.pos..].trim() {
+        "" | "px" => 1.0,
+        "%" => reference / 100.0,
+        "pt" => 4.0 / 3.0,
+        "pc" => 16.0,
+        "in" => 96.0,
+        "cm" => 96.0 / 2.54,
+        "mm" => 96.0 / 25.4,
+        "em" => FONT_SIZE,
+        "ex" => FONT_SIZE / 2.0,
+        _ => return None,
+    };
+    Some(number * scale).filter(|length| length.is_finite())
+}
+
+/// Parse the four numbers of a viewBox, which needs a positive width and height
+fn parse_view_box(value: &str) -> Option<[f32; 4]> {
+    let numbers = Scanner::new(value).numbers()?;
+    match numbers[..] {
+        [x, y, w, h] if w > 0.0 && h > 0.0 => Some([x, y, w, h]),
+        _ => None,
+    }
+}
+
+/// Transform from a viewBox to a viewport of `(width, height)`
+///
+/// `align` is a preserveAspectRatio value, the view box is centered and
+/// scaled to fit by default.
+fn view_box_transform([x, y, w, h]: [f32; 4], (width, height): (f32, f32), align: &str) -> Matrix {
+    let (sx, sy) = (width / w, height / h);
+    let mut words = align.split_whitespace();
+    let align = words.next().unwrap_or("xMidYMid");
+    if align == "none" {
+        return Matrix::scale(sx, sy).multiply(Matrix::translate(-x, -y));
+    }
+    let scale = match words.next() {
+        Some("slice") => sx.max(sy),
+        _ => sx.min(sy),
+    };
+    let position = |axis: &str| match axis {
+        "Min" => 0.0,
+        "Max" => 1.0,
+        _ => 0.5,
+    };
+    let (ax, ay) = match (align.get(1..4), align.get(5..8)) {
+        (Some(ax), Some(ay)) => (position(ax), position(ay)),
+        _ => (0.5, 0.5),
+    };
+    Matrix::translate((width - w * scale) * ax, (height - h * scale) * ay)
+        .multiply(Matrix::scale(scale, scale))
+        .multiply(Matrix::translate(-x, -y))
+}
+
+/// Reads numbers, flags and path commands separated by spaces, commas or signs
+struct Scanner<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(text: &'a str) -> Self {
+        Scanner {
+            text: text.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn skip_separators(&mut self) {
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|&c| c.is_ascii_whitespace() || c == b',')
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_separators();
+        self.pos >= self.text.len()
+    }
+
+    /// Next path command letter, if a letter comes next
+    fn command(&mut self) -> Option<u8> {
+        self.skip_separators();
+        let c = *self.text.get(self.pos)?;
+        (c.is_ascii_alphabetic() && c != b'e' && c != b'E').then(|| {
+            self.pos += 1;
+            c
+        })
+    }
+
+    fn number(&mut self) -> Option<f32> {
+        self.skip_separators();
+        let start = self.pos;
+        let digits = |scanner: &mut Self| {
+            let from = scanner.pos;
+            while scanner
+                .text
+                .get(scanner.pos)
+                .is_some_and(u8::is_ascii_digit)
+            {
+                scanner.pos += 1;
+            }
+            scanner.pos - from
+        };
+        if matches!(self.text.get(self.pos), Some(b'+' | b'-')) {
+            self.pos += 1;
+        }
+        let mut count = digits(self);
+        if self.text.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            count += digits(self);
+        }
+        if count == 0 {
+            self.pos = start;
+            return None;
+        }
+        // An exponent only if digits follow, so 2em stays a number and a unit
+        if matches!(self.text.get(self.pos), Some(b'e' | b'E')) {
+            let mark = self.pos;
+            self.pos += 1;
+            if matches!(self.text.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                self.pos = mark;
+            }
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Arc flag, a single 0 or 1 that needs no separator after it
+    fn flag(&mut self) -> Option<bool> {
+        self.skip_separators();
+        let flag = match self.text.get(self.pos)? {
+            b'0' => false,
+            b'1' => true,
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(flag)
+    }
+
+    /// All remaining numbers, `None` if anything else is in the way
+    fn numbers(&mut self) -> Option<Vec<f32>> {
+        let mut numbers = Vec::new();
+        while !self.at_end() {
+            numbers.push(self.number()?);
+        }
+        Some(numbers)
+    }
+}
+
+type Point = (f32, f32);
+
+/// Affine transform `[a, b, c, d, e, f]`, mapping `(x, y)` to `(ax + cy + e, bx + dy + f)`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Matrix([f32; 6]);
+
+impl Matrix {
+    const IDENTITY: Matrix = Matrix([1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+
+    fn translate(x: f32, y: f32) -> Self {
+        Matrix([1.0, 0.0, 0.0, 1.0, x, y])
+    }
+
+    fn scale(x: f32, y: f32) -> Self {
+        Matrix([x, 0.0, 0.0, y, 0.0, 0.0])
+    }
+
+    /// Transform applying `other` first, then this one
+    fn multiply(self, other: Matrix) -> Self {
+        let [a, b, c, d, e, f] = self.0;
+        let [g, h, i, j, k, l] = other.0;
+        Matrix([
+            a * g + c * h,
+            b * g + d * h,
+            a * i + c * j,
+            b * i + d * j,
+            a * k + c * l + e,
+            b * k + d * l + f,
+        ])
+    }
+
+    fn apply(self, (x, y): Point) -> Point {
+        let [a, b, c, d, e, f] = self.0;
+        (a * x + c * y + e, b * x + d * y + f)
+    }
+
+    fn invert(self) -> Option<Self> {
+        let [a, b, c, d, e, f] = self.0;
+        let det = a * d - b * c;
+        if det.abs() < 1e-12 || !det.is_finite() {
+            return None;
+        }
+        Some(Matrix([
+            d / det,
+            -b / det,
+            -c / det,
+            a / det,
+            (c * f - d * e) / det,
+            (b * e - a * f) / det,
+        ]))
+    }
+
+    /// Average factor lengths are scaled by
+    fn scale_factor(self) -> f32 {
+        let [a, b, c, d, ..] = self.0;
+        (a * d - b * c).abs().sqrt()
+    }
+}
+
+/// Parse a transform list such as `translate(10 20) rotate(45)`, `None` if invalid
+fn parse_transform(value: &str) -> Option<Matrix> {
+    let mut matrix = Matrix::IDENTITY;
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('(')?;
+        let (arguments, after) = after.split_once(')')?;
+        let arguments = Scanner::new(arguments).numbers()?;
+        let transform = match (name.trim(), &arguments[..]) {
+            ("matrix", &[a, b, c, d, e, f]) => Matrix([a, b, c, d, e, f]),
+            ("translate", &[x]) => Matrix::translate(x, 0.0),
+            ("translate", &[x, y]) => Matrix::translate(x, y),
+            ("scale", &[s]) => Matrix::scale(s, s),
+            ("scale", &[x, y]) => Matrix::scale(x, y),
+            ("rotate", &[angle]) => rotation(angle),
+            ("rotate", &[angle, x, y]) => Matrix::translate(x, y)
+                .multiply(rotation(angle))
+                .multiply(Matrix::translate(-x, -y)),
+            ("skewX", &[angle]) => Matrix([1.0, 0.0, angle.to_radians().tan(), 1.0, 0.0, 0.0]),
+            ("skewY", &[angle]) => Matrix([1.0, angle.to_radians().tan(), 0.0, 1.0, 0.0, 0.0]),
+            _ => return None,
+        };
+        matrix = matrix.multiply(transform);
+        rest = after.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    }
+    Some(matrix)
+}
+
+fn rotation(degrees: f32) -> Matrix {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    Matrix([cos, sin, -sin, cos, 0.0, 0.0])
+}
+
+/// Connected run of lines of a path
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Subpath {
+    points: Vec<Point>,
+    closed: bool,
+}
+
+/// Turns path segments into subpaths of short lines
+struct PathBuilder {
+    subpaths: Vec<Subpath>,
+    current: Subpath,
+    /// Largest distance between a curve and its lines, in user units
+    tolerance: f32,
+}
+
+impl PathBuilder {
+    fn new(tolerance: f32) -> Self {
+        PathBuilder {
+            subpaths: Vec::new(),
+            current: Subpath::default(),
+            tolerance,
+        }
+    }
+
+    fn last(&self) -> Point {
+        self.current.points.last().copied().unwrap_or_default()
+    }
+
+    /// Keep the current subpath if it has a segment, even one of zero length
+    fn finish(&mut self) {
+        let current = std::mem::take(&mut self.current);
+        if current.points.len() > 1 {
+            self.subpaths.push(current);
+        }
+    }
+
+    fn move_to(&mut self, p: Point) {
+        self.finish();
+        self.current.points.push(p);
+    }
+
+    fn line_to(&mut self, p: Point) {
+        if self.current.points.is_empty() {
+            self.current.points.push(p);
+        }
+        self.current.points.push(p);
+    }
+
+    fn quad_to(&mut self, c: Point, p: Point) {
+        let s = self.last();
+        let dd = (s.0 - 2.0 * c.0 + p.0).hypot(s.1 - 2.0 * c.1 + p.1);
+        let n = segments((dd / (4.0 * self.tolerance)).sqrt());
+        for i in 1..=n {
+            let t = i as f32 / n as f32;
+            let u = 1.0 - t;
+            self.line_to((
+                u * u * s.0 + 2.0 * u * t * c.0 + t * t * p.0,
+                u * u * s.1 + 2.0 * u * t * c.1 + t * t * p.1,
+            ));
+        }
+    }
+
+    fn cubic_to(&mut self, c1: Point, c2: Point, p: Point) {
+        let s = self.last();
+        let dd = (s.0 - 2.0 * c1.0 + c2.0)
+            .hypot(s.1 - 2.0 * c1.1 + c2.1)
+            .max((c1.0 - 2.0 * c2.0 + p.0).hypot(c1.1 - 2.0 * c2.1 + p.1));
+        let n = segments((3.0 * dd / (4.0 * self.tolerance)).sqrt());
+        for i in 1..=n {
+            let t = i as f32 / n as f32;
+            let u = 1.0 - t;
+            let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+            self.line_to((
+                a * s.0 + b * c1.0 + c * c2.0 + d * p.0,
+                a * s.1 + b * c1.1 + c * c2.1 + d * p.1,
+            ));
+        }
+    }
+
+    /// Elliptical arc to `p` as in SVG path data, with the radii scaled up if too small
+    fn arc_to(&mut self, (rx, ry): Point, angle: f32, large: bool, sweep: bool, p: Point) {
+        let s = self.last();
+        let (mut rx, mut ry) = (rx.abs(), ry.abs());
+        if s == p {
+            return;
+        }
+        if rx == 0.0 || ry == 0.0 {
+            self.line_to(p);
+            return;
+        }
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (dx, dy) = ((s.0 - p.0) / 2.0, (s.1 - p.1) / 2.0);
+        let (x1, y1) = (cos * dx + sin * dy, -sin * dx + cos * dy);
+        let lambda = (x1 / rx).powi(2) + (y1 / ry).powi(2);
+        if lambda > 1.0 {
+            rx *= lambda.sqrt();
+            ry *= lambda.sqrt();
+        }
+        let numerator = (rx * ry).powi(2) - (rx * y1).powi(2) - (ry * x1).powi(2);
+        let denominator = (rx * y1).powi(2) + (ry * x1).powi(2);
+        let mut coefficient = (numerator / denominator).max(0.0).sqrt();
+        if large == sweep {
+            coefficient = -coefficient;
+        }
+        let (cx1, cy1) = (coefficient * rx * y1 / ry, -coefficient * ry * x1 / rx);
+        let center = (
+            cos * cx1 - sin * cy1 + (s.0 + p.0) / 2.0,
+            sin * cx1 + cos * cy1 + (s.1 + p.1) / 2.0,
+        );
+        let start = ((y1 - cy1) / ry).atan2((x1 - cx1) / rx);
+        let end = ((-y1 - cy1) / ry).atan2((-x1 - cx1) / rx);
+        let mut sweep_angle = end - start;
+        if sweep && sweep_angle < 0.0 {
+            sweep_angle += 2.0 * PI;
+        } else if !sweep && sweep_angle > 0.0 {
+            sweep_angle -= 2.0 * PI;
+        }
+        let n = segments(sweep_angle.abs() / angle_step(rx.max(ry), self.tolerance));
+        for i in 1..n {
+            let t = start + sweep_angle * i as f32 / n as f32;
+            let (x, y) = (rx * t.cos(), ry * t.sin());
+            self.line_to((center.0 + cos * x - sin * y, center.1 + sin * x + cos * y));
+        }
+        self.line_to(p);
+    }
+
+    fn close(&mut self) {
+        if let Some(&start) = self.current.points.first() {
+            self.current.closed = true;
+            self.finish();
+            // Drawing after a close starts again where the subpath did
+            self.current.points.push(start);
+        }
+    }
+
+    fn build(mut self) -> Vec<Subpath> {
+        self.finish();
+        self.subpaths
+    }
+}
+
+/// Lines a curve is drawn with, from their fractional number
+fn segments(n: f32) -> usize {
+    (n.ceil() as usize).clamp(1, 1000)
+}
+
+/// Angle between points on a circle of `radius` so the lines stay within `tolerance`
+fn angle_step(radius: f32, tolerance: f32) -> f32 {
+    match radius > tolerance {
+        true => (2.0 * (1.0 - tolerance / radius).acos()).max(1e-3),
+        false => PI / 2.0,
+    }
+}
+
+/// Parse path data into subpaths, stopping at the first error as SVG renderers do
+fn parse_path(data: &str, tolerance: f32) -> Vec<Subpath> {
+    let mut path = PathBuilder::new(tolerance);
+    let mut scanner = Scanner::new(data);
+    let (mut current, mut start) = ((0.0, 0.0), (0.0, 0.0));
+    // Second control point of the last curve, reflected by S and T
+    let mut control = None;
+    let mut previous: Option<u8> = None;
+    while !scanner.at_end() {
+        let command = match scanner.command() {
+            Some(command) => command,
+            // Numbers after a moveto are linetos
+            None => match previous {
+                Some(b'M') => b'L',
+                Some(b'm') => b'l',
+                Some(c) if c != b'Z' && c != b'z' => c,
+                _ => break,
+            },
+        };
+        let origin = match command.is_ascii_lowercase() {
+            true => current,
+            false => (0.0, 0.0),
+        };
+        let mut point = || Some((scanner.number()? + origin.0, scanner.number()? + origin.1));
+        let result = match command.to_ascii_uppercase() {
+            b'M' => point().map(|p| {
+                path.move_to(p);
+                start = p;
+                (p, None)
+            }),
+            b'L' => point().map(|p| {
+                path.line_to(p);
+                (p, None)
+            }),
+            b'H' => scanner.number().map(|x| {
+                let p = (x + origin.0, current.1);
+                path.line_to(p);
+                (p, None)
+            }),
+            b'V' => scanner.number().map(|y| {
+                let p = (current.0, y + origin.1);
+                path.line_to(p);
+                (p, None)
+            }),
+            b'C' => (|| {
+                let (c1, c2, p) = (point()?, point()?, point()?);
+                path.cubic_to(c1, c2, p);
+                Some((p, Some((b'C', c2))))
+            })(),
+            b'S' => (|| {
+                let (c2, p) = (point()?, point()?);
+                let c1 = reflect(control, b'C', current);
+                path.cubic_to(c1, c2, p);
+                Some((p, Some((b'C', c2))))
+            })(),
+            b'Q' => (|| {
+                let (c, p) = (point()?, point()?);
+                path.quad_to(c, p);
+                Some((p, Some((b'Q', c))))
+            })(),
+            b'T' => point().map(|p| {
+                let c = reflect(control, b'Q', current);
+                path.quad_to(c, p);
+                (p, Some((b'Q', c)))
+            }),
+            b'A' => (|| {
+                let radii = (scanner.number()?, scanner.number()?);
+                let angle = scanner.number()?;
+                let (large, sweep) = (scanner.flag()?, scanner.flag()?);
+                let p = (scanner.number()? + origin.0, scanner.number()? + origin.1);
+                path.arc_to(radii, angle, large, sweep, p);
+                Some((p, None))
+            })(),
+            b'Z' => {
+                path.close();
+                Some((start, None))
+            }
+            _ => None,
+        };
+        let Some((p, c)) = result else {
+            break;
+        };
+        current = p;
+        control = c;
+        previous = Some(command);
+    }
+    path.build()
+}
+
+/// First control point of a smooth curve, the last one mirrored if it was of the same kind
+fn reflect(control: Option<(u8, Point)>, kind: u8, current: Point) -> Point {
+    match control {
+        Some((k, c)) if k == kind => (2.0 * current.0 - c.0, 2.0 * current.1 - c.1),
+        _ => current,
+    }
+}
+
+/// Parse the points of a polyline or polygon, dropping an odd coordinate at the end
+fn parse_points(value: &str) -> Vec<Point> {
+    let mut scanner = Scanner::new(value);
+    let mut points = Vec::new();
+    while let (Some(x), Some(y)) = (scanner.number(), scanner.number()) {
+        points.push((x, y));
+    }
+    points
+}
+
+/// Smallest rectangle around some points, `[x0, y0, x1, y1]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bounds([f32; 4]);
+
+impl Bounds {
+    fn of(points: impl IntoIterator<Item = Point>) -> Option<Self> {
+        points.into_iter().fold(None, |bounds, (x, y)| {
+            let [x0, y0, x1, y1] = bounds.map_or([x, y, x, y], |b: Bounds| b.0);
+            Some(Bounds([x0.min(x), y0.min(y), x1.max(x), y1.max(y)]))
+        })
+    }
+
+    fn union(self, other: Bounds) -> Self {
+        let ([a0, b0, a1, b1], [x0, y0, x1, y1]) = (self.0, other.0);
+        Bounds([a0.min(x0), b0.min(y0), a1.max(x1), b1.max(y1)])
+    }
+
+    fn transform(self, matrix: Matrix) -> Self {
+        let [x0, y0, x1, y1] = self.0;
+        let corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1)];
+        Bounds::of(corners.map(|p| matrix.apply(p))).unwrap_or(self)
+    }
+
+    /// Transform from the unit square to these bounds, `None` if they have no area
+    fn unit_matrix(self) -> Option<Matrix> {
+        let [x0, y0, x1, y1] = self.0;
+        (x1 > x0 && y1 > y0).then_some(Matrix([x1 - x0, 0.0, 0.0, y1 - y0, x0, y0]))
+    }
+}
+
+/// Properties inherited by the children of an element
+#[derive(Debug, Clone, PartialEq)]
+struct Style {
+    fill: Paint,
+    fill_opacity: f32,
+    fill_rule: FillRule,
+    stroke: Paint,
+    stroke_width: f32,
+    stroke_opacity: f32,
+    line_cap: LineCap,
+    line_join: LineJoin,
+    miter_limit: f32,
+    dashes: Vec<f32>,
+    dash_offset: f32,
+    clip_rule: FillRule,
+    color: Rgba<u8>,
+    visible: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            fill: Paint::Color(Rgba([0, 0, 0, 255])),
+            fill_opacity: 1.0,
+            fill_rule: FillRule::NonZero,
+            stroke: Paint::None,
+            stroke_width: 1.0,
+            stroke_opacity: 1.0,
+            line_cap: LineCap::Butt,
+            line_join: LineJoin::Miter,
+            miter_limit: 4.0,
+            dashes: Vec::new(),
+            dash_offset: 0.0,
+            clip_rule: FillRule::NonZero,
+            color: Rgba([0, 0, 0, 255]),
+            visible: true,
+        }
+    }
+}
+
+impl Style {
+    /// Set an inherited property, invalid values and other properties are ignored
+    fn set(&mut self, name: &str, value: &str, diagonal: f32) {
+        let rule = |value: &str| match value {
+            "evenodd" => Some(FillRule::EvenOdd),
+            "nonzero" => Some(FillRule::NonZero),
+            _ => None,
+        };
+        let value = value.trim();
+        match name {
+            "fill" => self.fill = parse_paint(value).unwrap_or(self.fill.clone()),
+            "stroke" => self.stroke = parse_paint(value).unwrap_or(self.stroke.clone()),
+            "fill-opacity" => self.fill_opacity = parse_opacity(value).unwrap_or(self.fill_opacity),
+            "stroke-opacity" => {
+                self.stroke_opacity = parse_opacity(value).unwrap_or(self.stroke_opacity)
+            }
+            "fill-rule" => self.fill_rule = rule(value).unwrap_or(self.fill_rule),
+            "clip-rule" => self.clip_rule = rule(value).unwrap_or(self.clip_rule),
+            "stroke-width" => {
+                if let Some(width) = parse_length(value, diagonal).filter(|w| *w >= 0.0) {
+                    self.stroke_width = width;
+                }
+            }
+            "stroke-linecap" => {
+                self.line_cap = match value {
+                    "butt" => LineCap::Butt,
+                    "round" => LineCap::Round,
+                    "square" => LineCap::Square,
+                    _ => self.line_cap,
+                }
+            }
+            "stroke-linejoin" => {
+                self.line_join = match value {
+                    "miter" | "miter-clip" | "arcs" => LineJoin::Miter,
+                    "round" => LineJoin::Round,
+                    "bevel" => LineJoin::Bevel,
+                    _ => self.line_join,
+                }
+            }
+            "stroke-miterlimit" => {
+                if let Some(limit) = value.parse::<f32>().ok().filter(|l| *l >= 1.0) {
+                    self.miter_limit = limit;
+                }
+            }
+            "stroke-dasharray" if value == "none" => self.dashes.clear(),
+            "stroke-dasharray" => {
+                let dashes: Option<Vec<f32>> = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|s| !s.is_empty())
+                    .map(|s| parse_length(s, diagonal).filter(|d| *d >= 0.0))
+                    .collect();
+                if let Some(dashes) = dashes {
+                    self.dashes = dashes;
+                }
+            }
+            "stroke-dashoffset" => {
+                self.dash_offset = parse_length(value, diagonal).unwrap_or(self.dash_offset)
+            }
+            "color" => self.color = parse_color(value).unwrap_or(self.color),
+            "visibility" => self.visible = value == "visible",
+            _ => {}
+        }
+    }
+}
+
+/// Paint of a pixel, premultiplied RGBA in 0.0 ~ 1.0
+type Pixel = [f32; 4];
+
+/// Premultiplied color of a paint with an opacity
+fn premultiply(Rgba([r, g, b, a]): Rgba<u8>, opacity: f32) -> Pixel {
+    let alpha = a as f32 / 255.0 * opacity;
+    [
+        r as f32 / 255.0 * alpha,
+        g as f32 / 255.0 * alpha,
+        b as f32 / 255.0 * alpha,
+        alpha,
+    ]
+}
+
+/// How colors continue past the ends of a gradient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Spread {
+    Pad,
+    Reflect,
+    Repeat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum GradientKind {
+    /// From the start to the end point
+    Linear(Point, Point),
+    /// Outward from the focal point to a circle of center and radius
+    Radial {
+        center: Point,
+        radius: f32,
+        focal: Point,
+    },
+}
+
+/// Gradient paint ready to sample at pixels
+#[derive(Debug, Clone, PartialEq)]
+struct Gradient {
+    kind: GradientKind,
+    /// Offsets and premultiplied colors of the stops, in order
+    stops: Vec<(f32, Pixel)>,
+    spread: Spread,
+    /// Transform from pixels to the space of the gradient
+    inverse: Matrix,
+}
+
+impl Gradient {
+    fn at(&self, p: Point) -> Pixel {
+        let (x, y) = self.inverse.apply(p);
+        let t = match self.kind {
+            GradientKind::Linear((x1, y1), (x2, y2)) => {
+                let (dx, dy) = (x2 - x1, y2 - y1);
+                ((x - x1) * dx + (y - y1) * dy) / (dx * dx + dy * dy)
+            }
+            GradientKind::Radial {
+                center,
+                radius,
+                focal,
+            } => {
+                // The ray from the focal point through p meets the circle at
+                // focal + s * (p - focal), p is at 1 / s of the way there
+                let (dx, dy) = (x - focal.0, y - focal.1);
+                let (fx, fy) = (focal.0 - center.0, focal.1 - center.1);
+                let a = dx * dx + dy * dy;
+                let b = 2.0 * (dx * fx + dy * fy);
+                let c = fx * fx + fy * fy - radius * radius;
+                let s = (-b + (b * b - 4.0 * a * c).max(0.0).sqrt()) / (2.0 * a);
+                match a > 0.0 && s > 0.0 {
+                    true => 1.0 / s,
+                    false => 0.0,
+                }
+            }
+        };
+        let t = match self.spread {
+            Spread::Pad => t.clamp(0.0, 1.0),
+            Spread::Repeat => t.rem_euclid(1.0),
+            Spread::Reflect => {
+                let t = t.rem_euclid(2.0);
+                if t > 1.0 { 2.0 - t } else { t }
+            }
+        };
+        self.color(t)
+    }
+
+    /// Color at an offset, mixed from the stops around it
+    fn color(&self, t: f32) -> Pixel {
+        let after = self.stops.iter().position(|&(offset, _)| offset > t);
+        match after {
+            Some(0) => self.stops[0].1,
+            None => self.stops[self.stops.len() - 1].1,
+            Some(i) => {
+                let ((o0, c0), (o1, c1)) = (self.stops[i - 1], self.stops[i]);
+                let f = (t - o0) / (o1 - o0).max(f32::EPSILON);
+                std::array::from_fn(|k| c0[k] + (c1[k] - c0[k]) * f)
+            }
+        }
+    }
+}
+
+/// What a shape is filled with, one color or a gradient
+#[derive(Debug, Clone, PartialEq)]
+enum Shader {
+    Solid(Pixel),
+    Gradient(Gradient),
+}
+
+impl Shader {
+    fn at(&self, p: Point) -> Pixel {
+        match self {
+            Shader::Solid(color) => *color,
+            Shader::Gradient(gradient) => gradient.at(p),
+        }
+    }
+}
+
+/// Premultiplied pixels drawn on
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<Pixel>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Canvas {
+            width,
+            height,
+            pixels: vec![[0.0; 4]; width * height],
+        }
+    }
+
+    /// Fill polygons in pixel coordinates
+    fn fill(&mut self, polygons: &[Vec<Point>], rule: FillRule, shader: &Shader) {
+        let width = self.width;
+        coverage(polygons, rule, (self.width, self.height), |x, y, cover| {
+            let src = shader.at((x as f32 + 0.5, y as f32 + 0.5));
+            let dst = &mut self.pixels[y * width + x];
+            let keep = 1.0 - src[3] * cover;
+            for (d, s) in dst.iter_mut().zip(src) {
+                *d = s * cover + *d * keep;
+            }
+        });
+    }
+
+    /// Draw another canvas over this one with an opacity
+    fn draw(&mut self, layer: &Canvas, opacity: f32) {
+        for (dst, src) in self.pixels.iter_mut().zip(&layer.pixels) {
+            let keep = 1.0 - src[3] * opacity;
+            for (d, s) in dst.iter_mut().zip(src) {
+                *d = s * opacity + *d * keep;
+            }
+        }
+    }
+
+    /// Scale every pixel by a factor taken from the pixel of another canvas
+    fn multiply(&mut self, other: &Canvas, factor: impl Fn(&Pixel) -> f32) {
+        for (pixel, other) in self.pixels.iter_mut().zip(&other.pixels) {
+            let f = factor(other);
+            pixel.iter_mut().for_each(|c| *c *= f);
+        }
+    }
+
+    fn into_image(self) -> RgbaImage {
+        let data = self
+            .pixels
+            .iter()
+            .flat_map(|&[r, g, b, a]| {
+                let unpremultiply = |c: f32| match a > 0.0 {
+                    true => (c / a * 255.0).round().clamp(0.0, 255.0) as u8,
+                    false => 0,
+                };
+                [
+                    unpremultiply(r),
+                    unpremultiply(g),
+                    unpremultiply(b),
+                    (a * 255.0).round().clamp(0.0, 255.0) as u8,
+                ]
+            })
+            .collect();
+        RgbaImage::from_raw(self.width as u32, self.height as u32, data)
+            .unwrap_or_else(|| RgbaImage::new(self.width as u32, self.height as u32))
+    }
+}
+
+/// Edge of a polygon from top to bottom, `winding` is -1 if it was drawn upward
+struct Edge {
+    top: Point,
+    bottom_y: f32,
+    slope: f32,
+    winding: i32,
+}
+
+/// Call `paint` with the anti-aliased coverage of every pixel the polygons touch
+///
+/// Every row is sampled at several heights, the spans inside the polygons
+/// at each height count with their exact horizontal extent.
+fn coverage(
+    polygons: &[Vec<Point>],
+    rule: FillRule,
+    (width, height): (usize, usize),
+    mut paint: impl FnMut(usize, usize, f32),
+) {
+    let mut edges: Vec<Edge> = polygons
+        .iter()
+        .flat_map(|polygon| {
+            let next = polygon.iter().cycle().skip(1);
+            polygon.iter().zip(next).filter_map(|(&a, &b)| {
+                let (top, bottom, winding) = match a.1 < b.1 {
+                    true => (a, b, 1),
+                    false => (b, a, -1),
+                };
+                (top.1 != bottom.1 && top.1.is_finite() && bottom.1.is_finite()).then(|| Edge {
+                    top,
+                    bottom_y: bottom.1,
+                    slope: (bottom.0 - top.0) / (bottom.1 - top.1),
+                    winding,
+                })
+            })
+        })
+        .collect();
+    if edges.is_empty() {
+        return;
+    }
+    edges.sort_by(|a, b| a.top.1.total_cmp(&b.top.1));
+    let first = edges[0].top.1.max(0.0) as usize;
+    let last = edges
+        .iter()
+        .map(|e| e.bottom_y)
+        .fold(0.0, f32::max)
+        .ceil()
+        .min(height as f32) as usize;
+
+    let mut cells = vec![0.0f32; width + 2];
+    let mut active: Vec<usize> = Vec::new();
+    let mut crossings: Vec<(f32, i32)> = Vec::new();
+    let mut next = 0;
+    let weight = 1.0 / SUBSAMPLES as f32;
+    for y in first..last {
+        let (top, bottom) = (y as f32, y as f32 + 1.0);
+        while next < edges.len() && edges[next].top.1 < bottom {
+            active.push(next);
+            next += 1;
+        }
+        active.retain(|&i| edges[i].bottom_y > top);
+        if active.is_empty() {
+            continue;
+        }
+        let (mut low, mut high) = (width + 1, 0);
+        for sample in 0..SUBSAMPLES {
+            let sy = top + (sample as f32 + 0.5) * weight;
+            crossings.clear();
+            crossings.extend(
+                active
+                    .iter()
+                    .map(|&i| &edges[i])
+                    .filter(|e| e.top.1 <= sy && sy < e.bottom_y)
+                    .map(|e| (e.top.0 + (sy - e.top.1) * e.slope, e.winding)),
+            );
+            crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut winding = 0;
+            for pair in crossings.windows(2) {
+                winding += pair[0].1;
+                let inside = match rule {
+                    FillRule::NonZero => winding != 0,
+                    FillRule::EvenOdd => winding % 2 != 0,
+                };
+                if inside {
+                    let (x0, x1) = (
+                        pair[0].0.clamp(0.0, width as f32),
+                        pair[1].0.clamp(0.0, width as f32),
+                    );
+                    if x1 > x0 {
+                        // Differences of coverage, summed up along the row below
+                        let (i0, i1) = (x0 as usize, x1 as usize);
+                        let (f0, f1) = (x0 - i0 as f32, x1 - i1 as f32);
+                        cells[i0] += weight * (1.0 - f0);
+                        cells[i0 + 1] += weight * f0;
+                        cells[i1] -= weight * (1.0 - f1);
+                        cells[i1 + 1] -= weight * f1;
+                        low = low.min(i0);
+                        high = high.max(i1 + 1);
+                    }
+                }
+            }
+        }
+        let mut cover = 0.0;
+        for (x, cell) in cells.iter_mut().enumerate().take(high + 1).skip(low) {
+            cover += std::mem::take(cell);
+            if x < width && cover > 1.0 / 1024.0 {
+                paint(x, y, cover.min(1.0));
+            }
+        }
+    }
+}
+
+/// Polygons covering the outline of subpaths drawn with a line of `width`
+///
+/// Every segment, join and cap is a polygon of its own, all turning the same
+/// way so filling them with the nonzero rule paints their union.
+fn stroke(subpaths: &[Subpath], style: &Style, width: f32, tolerance: f32) -> Vec<Vec<Point>> {
+    let total: f32 = style.dashes.iter().sum();
+    let dashed: Vec<Subpath>;
+    let subpaths = match total > 0.0 && total.is_finite() {
+        true => {
+            let pattern: Vec<f32> = match style.dashes.len() % 2 {
+                // An odd list is repeated to give an even number of dashes and gaps
+                1 => style.dashes.repeat(2),
+                _ => style.dashes.clone(),
+            };
+            dashed = subpaths
+                .iter()
+                .flat_map(|s| dash(s, &pattern, style.dash_offset))
+                .collect();
+            &dashed
+        }
+        false => subpaths,
+    };
+    let half = width / 2.0;
+    let mut polygons = Vec::new();
+    for subpath in subpaths {
+        let mut points = subpath.points.clone();
+        points.dedup_by(|a, b| (a.0 - b.0).hypot(a.1 - b.1) < 1e-6);
+        if subpath.closed && points.len() > 1 && points.first() == points.last() {
+            points.pop();
+        }
+        let closed = subpath.closed && points.len() > 2;
+        if points.len() == 1 {
+            // A subpath of zero length shows its caps
+            let p = points[0];
+            match style.line_cap {
+                LineCap::Round => polygons.push(circle(p, half, tolerance)),
+                LineCap::Square => polygons.push(vec![
+                    (p.0 - half, p.1 - half),
+                    (p.0 + half, p.1 - half),
+                    (p.0 + half, p.1 + half),
+                    (p.0 - half, p.1 + half),
+                ]),
+                LineCap::Butt => {}
+            }
+            continue;
+        }
+        let count = points.len();
+        let segment_count = if closed { count } else { count - 1 };
+        let direction = |i: usize| {
+            let (a, b) = (points[i % count], points[(i + 1) % count]);
+            let length = (b.0 - a.0).hypot(b.1 - a.1);
+            ((b.0 - a.0) / length, (b.1 - a.1) / length)
+        };
+        for i in 0..segment_count {
+            let (a, b) = (points[i], points[(i + 1) % count]);
+            let (dx, dy) = direction(i);
+            let n = (-dy * half, dx * half);
+            polygons.push(vec![
+                (a.0 + n.0, a.1 + n.1),
+                (b.0 + n.0, b.1 + n.1),
+                (b.0 - n.0, b.1 - n.1),
+                (a.0 - n.0, a.1 - n.1),
+            ]);
+        }
+        let joints = match closed {
+            true => 0..count,
+            false => 1..count - 1,
+        };
+        for i in joints {
+            let incoming = direction((i + count - 1) % count);
+            polygons.extend(join(
+                points[i],
+                incoming,
+                direction(i),
+                half,
+                style,
+                tolerance,
+            ));
+        }
+        if !closed {
+            let (dx, dy) = direction(0);
+            polygons.extend(cap(points[0], (-dx, -dy), half, style.line_cap, tolerance));
+            let end = direction(count - 2);
+            polygons.extend(cap(points[count - 1], end, half, style.line_cap, tolerance));
+        }
+    }
+    for polygon in &mut polygons {
+        if signed_area(polygon) < 0.0 {
+            polygon.reverse();
+        }
+    }
+    polygons
+}
+
+/// Split a subpath into open dashes, `pattern` alternating dash and gap lengths
+fn dash(subpath: &Subpath, pattern: &[f32], offset: f32) -> Vec<Subpath> {
+    let total: f32 = pattern.iter().sum();
+    let mut index = 0;
+    let mut position = offset.rem_euclid(total);
+    while position >= pattern[index] {
+        position -= pattern[index];
+        index = (index + 1) % pattern.len();
+    }
+    let mut left = pattern[index] - position;
+    let mut on = index % 2 == 0;
+    let mut dashes = Vec::new();
+    let points = &subpath.points;
+    let mut current = match (on, points.first()) {
+        (true, Some(&p)) => vec![p],
+        _ => Vec::new(),
+    };
+    let closing = subpath
+        .closed
+        .then(|| (points[points.len() - 1], points[0]));
+    for (a, b) in points.windows(2).map(|w| (w[0], w[1])).chain(closing) {
+        let mut length = (b.0 - a.0).hypot(b.1 - a.1);
+        let mut start = a;
+        while length > left {
+            let f = left / length;
+            let p = (start.0 + (b.0 - start.0) * f, start.1 + (b.1 - start.1) * f);
+            if on {
+                current.push(p);
+                dashes.push(Subpath {
+                    points: std::mem::take(&mut current),
+                    closed: false,
+                });
+            } else {
+                current = vec![p];
+            }
+            on = !on;
+            length -= left;
+            start = p;
+            index = (index + 1) % pattern.len();
+            left = pattern[index];
+        }
+        left -= length;
+        if on {
+            current.push(b);
+        }
+    }
+    if on && current.len() > 1 {
+        dashes.push(Subpath {
+            points: current,
+            closed: false,
+        });
+    }
+    dashes
+}
+
+/// Polygons filling the corner between two segments meeting at `p`
+fn join(
+    p: Point,
+    incoming: Point,
+    outgoing: Point,
+    half: f32,
+    style: &Style,
+    tolerance: f32,
+) -> Vec<Vec<Point>> {
+    let cross = incoming.0 * outgoing.1 - incoming.1 * outgoing.0;
+    let dot = incoming.0 * outgoing.0 + incoming.1 * outgoing.1;
+    if cross.abs() < 1e-6 && dot > 0.0 {
+        return Vec::new();
+    }
+    // The outer side of the corner is away from the turn
+    let side = if cross > 0.0 { -half } else { half };
+    let n0 = (-incoming.1 * side, incoming.0 * side);
+    let n1 = (-outgoing.1 * side, outgoing.0 * side);
+    let bevel = vec![p, (p.0 + n0.0, p.1 + n0.1), (p.0 + n1.0, p.1 + n1.1)];
+    match style.line_join {
+        LineJoin::Round => vec![circle(p, half, tolerance)],
+        LineJoin::Bevel => vec![bevel],
+        LineJoin::Miter => {
+            let sum = (n0.0 + n1.0, n0.1 + n1.1);
+            let length = sum.0.hypot(sum.1);
+            // The miter is as long as half the width over the cosine of half the angle
+            if length < 1e-6 || 2.0 * half / length > style.miter_limit {
+                return vec![bevel];
+            }
+            let scale = 2.0 * half * half / (length * length);
+            let tip = (p.0 + sum.0 * scale, p.1 + sum.1 * scale);
+            vec![vec![
+                p,
+                (p.0 + n0.0, p.1 + n0.1),
+                tip,
+                (p.0 + n1.0, p.1 + n1.1),
+            ]]
+        }
+    }
+}
+
+/// Polygon of the cap at the end of an open subpath, `direction` pointing away from it
+fn cap(p: Point, direction: Point, half: f32, cap: LineCap, tolerance: f32) -> Option<Vec<Point>> {
+    let n = (-direction.1 * half, direction.0 * half);
+    let d = (direction.0 * half, direction.1 * half);
+    match cap {
+        LineCap::Butt => None,
+        LineCap::Round => Some(circle(p, half, tolerance)),
+        LineCap::Square => Some(vec![
+            (p.0 + n.0, p.1 + n.1),
+            (p.0 + n.0 + d.0, p.1 + n.1 + d.1),
+            (p.0 - n.0 + d.0, p.1 - n.1 + d.1),
+            (p.0 - n.0, p.1 - n.1),
+        ]),
+    }
+}
+
+fn circle(center: Point, radius: f32, tolerance: f32) -> Vec<Point> {
+    let n = segments(2.0 * PI / angle_step(radius, tolerance)).max(8);
+    (0..n)
+        .map(|i| {
+            let (sin, cos) = (2.0 * PI * i as f32 / n as f32).sin_cos();
+            (center.0 + radius * cos, center.1 + radius * sin)
+        })
+        .collect()
+}
+
+/// Twice the area of a polygon, positive if it turns clockwise on screen
+fn signed_area(polygon: &[Point]) -> f32 {
+    let next = polygon.iter().cycle().skip(1);
+    polygon
+        .iter()
+        .zip(next)
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum()
+}
+
+/// Elements drawn as they are met, the others are only drawn by reference or not at all
+const RENDERED: [&str; 12] = [
+    "svg", "g", "a", "switch", "use", "path", "rect", "circle", "ellipse", "line", "polyline",
+    "polygon",
+];
+
+/// Document being drawn, with elements by id and the rules of its style sheets
+struct Document<'a> {
+    ids: HashMap<&'a str, &'a Element>,
+    rules: Vec<(Selector, Vec<(String, String)>)>,
+    /// Width and height of the root viewport in user units, for percentages
+    viewport: (f32, f32),
+}
+
+impl<'a> Document<'a> {
+    fn new(root: &'a Element, viewport: (f32, f32)) -> Self {
+        let mut document = Document {
+            ids: HashMap::new(),
+            rules: Vec::new(),
+            viewport,
+        };
+        let mut css = String::new();
+        let mut pending = vec![root];
+        while let Some(element) = pending.pop() {
+            if let Some(id) = element.get("id") {
+                document.ids.entry(id).or_insert(element);
+            }
+            if element.name == "style" {
+                css.push_str(&element.text);
+                css.push('\n');
+            }
+            pending.extend(element.children.iter().rev());
+        }
+        document.rules = parse_css(&css);
+        document
+    }
+
+    /// Length of the viewport diagonal over the square root of 2, for other percentages
+    fn diagonal(&self) -> f32 {
+        (self.viewport.0.hypot(self.viewport.1)) / 2f32.sqrt()
+    }
+
+    /// Style properties of an element by increasing priority: attributes,
+    /// style sheet rules, then its style attribute
+    fn declarations(&self, element: &Element) -> Vec<(String, String)> {
+        let mut declarations = element.attributes.clone();
+        for (selector, rule) in &self.rules {
+            if selector.matches(element) {
+                declarations.extend(rule.iter().cloned());
+            }
+        }
+        if let Some(style) = element.get("style") {
+            declarations.extend(parse_declarations(style));
+        }
+        declarations
+    }
+
+    /// Style of an element with the style of its parent
+    fn style(&self, declarations: &[(String, String)], parent: &Style) -> Style {
+        let mut style = parent.clone();
+        for (name, value) in declarations {
+            style.set(name, value, self.diagonal());
+        }
+        style
+    }
+
+    /// Transform an element adds for its content, beyond the one it is drawn with
+    fn local_transform(&self, element: &Element, root: bool) -> Matrix {
+        let mut matrix = element
+            .get("transform")
+            .filter(|_| !root)
+            .and_then(parse_transform)
+            .unwrap_or(Matrix::IDENTITY);
+        let (w, h) = self.viewport;
+        let offset = (
+            element.length("x", w).unwrap_or(0.0),
+            element.length("y", h).unwrap_or(0.0),
+        );
+        match element.name.as_str() {
+            "use" => matrix = matrix.multiply(Matrix::translate(offset.0, offset.1)),
+            // A nested document is a viewport of its own
+            "svg" if !root => {
+                let size = (
+                    element.length("width", w).unwrap_or(w),
+                    element.length("height", h).unwrap_or(h),
+                );
+                matrix = matrix.multiply(Matrix::translate(offset.0, offset.1));
+                if let Some(view_box) = element.get("viewBox").and_then(parse_view_box) {
+                    let align = element.get("preserveAspectRatio").unwrap_or_default();
+                    matrix = matrix.multiply(view_box_transform(view_box, size, align));
+                }
+            }
+            _ => {}
+        }
+        matrix
+    }
+
+    /// Draw an element with its group opacity, clip path and mask
+    fn render(
+        &self,
+        element: &Element,
+        parent: &Style,
+        ctm: Matrix,
+        canvas: &mut Canvas,
+        depth: usize,
+    ) {
+        if depth > MAX_DEPTH {
+            return;
+        }
+        let declarations = self.declarations(element);
+        let property = |name: &str| {
+            declarations
+                .iter()
+                .rev()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.trim())
+        };
+        if property("display") == Some("none") {
+            return;
+        }
+        let style = self.style(&declarations, parent);
+        let ctm = ctm.multiply(self.local_transform(element, depth == 0));
+        let opacity = property("opacity").and_then(parse_opacity).unwrap_or(1.0);
+        let reference = |name| {
+            property(name).and_then(|v| match parse_paint(v) {
+                Some(Paint::Url(id, _)) => Some(id),
+                _ => None,
+            })
+        };
+        let (clip, mask) = (reference("clip-path"), reference("mask"));
+        if opacity >= 1.0 && clip.is_none() && mask.is_none() {
+            self.draw(element, &style, ctm, canvas, depth);
+            return;
+        }
+        if opacity <= 0.0 {
+            return;
+        }
+        let mut layer = Canvas::new(canvas.width, canvas.height);
+        self.draw(element, &style, ctm, &mut layer, depth);
+        let bounds = self.bounds(element, depth);
+        if let Some(clip) = clip.and_then(|id| self.ids.get(id.as_str())) {
+            let mask = self.clip_mask(clip, bounds, ctm, (canvas.width, canvas.height));
+            layer.multiply(&mask, |p| p[3]);
+        }
+        if let Some(mask) = mask.and_then(|id| self.ids.get(id.as_str())) {
+            let mut content = Canvas::new(canvas.width, canvas.height);
+            let mut mask_ctm = ctm;
+            if mask.get("maskContentUnits") == Some("objectBoundingBox") {
+                match bounds.and_then(Bounds::unit_matrix) {
+                    Some(unit) => mask_ctm = ctm.multiply(unit),
+                    None => return,
+                }
+            }
+            let style = self.style(&self.declarations(mask), &Style::default());
+            self.draw_children(mask, &style, mask_ctm, &mut content, depth + 1);
+            // Luminance, premultiplied so it is already scaled by the alpha of the mask
+            layer.multiply(&content, |p| 0.2125 * p[0] + 0.7154 * p[1] + 0.0721 * p[2]);
+        }
+        canvas.draw(&layer, opacity);
+    }
+
+    fn draw_children(
+        &self,
+        element: &Element,
+        style: &Style,
+        ctm: Matrix,
+        canvas: &mut Canvas,
+        depth: usize,
+    ) {
+        for child in &element.children {
+            if RENDERED.contains(&child.name.as_str()) {
+                self.render(child, style, ctm, canvas, depth + 1);
+            }
+        }
+    }
+
+    /// Draw the content of an element, its transform already applied
+    fn draw(
+        &self,
+        element: &Element,
+        style: &Style,
+        ctm: Matrix,
+        canvas: &mut Canvas,
+        depth: usize,
+    ) {
+        match element.name.as_str() {
+            "svg" | "g" | "a" | "switch" | "symbol" => {
+                self.draw_children(element, style, ctm, canvas, depth)
+            }
+            "use" => {
+                if let Some(target) = element.href().and_then(|id| self.ids.get(id)) {
+                    self.render(target, style, ctm, canvas, depth + 1);
+                }
+            }
+            _ => {
+                let scale = ctm.scale_factor();
+                if !style.visible || scale <= 0.0 || !scale.is_finite() {
+                    return;
+                }
+                let Some(subpaths) = self.shape(element, TOLERANCE / scale) else {
+                    return;
+                };
+                let bounds = Bounds::of(subpaths.iter().flat_map(|s| s.points.iter().copied()));
+                let to_pixels = |polygons: Vec<Vec<Point>>| -> Vec<Vec<Point>> {
+                    polygons
+                        .into_iter()
+                        .map(|polygon| polygon.into_iter().map(|p| ctm.apply(p)).collect())
+                        .collect()
+                };
+                if let Some(shader) =
+                    self.shader(&style.fill, style.fill_opacity, style, bounds, ctm)
+                {
+                    let polygons = subpaths.iter().map(|s| s.points.clone()).collect();
+                    canvas.fill(&to_pixels(polygons), style.fill_rule, &shader);
+                }
+                if style.stroke_width > 0.0
+                    && let Some(shader) =
+                        self.shader(&style.stroke, style.stroke_opacity, style, bounds, ctm)
+                {
+                    let outline = stroke(&subpaths, style, style.stroke_width, TOLERANCE / scale);
+                    canvas.fill(&to_pixels(outline), FillRule::NonZero, &shader);
+                }
+            }
+        }
+    }
+
+    /// Outline of a path or basic shape in user units, `None` for other elements
+    fn shape(&self, element: &Element, tolerance: f32) -> Option<Vec<Subpath>> {
+        let (w, h) = self.viewport;
+        let diagonal = self.diagonal();
+        let length = |name, reference| element.length(name, reference).unwrap_or(0.0);
+        let mut path = PathBuilder::new(tolerance);
+        match element.name.as_str() {
+            "path" => return Some(parse_path(element.get("d")?, tolerance)),
+            "rect" => {
+                let (x, y) = (length("x", w), length("y", h));
+                let (width, height) = (length("width", w), length("height", h));
+                if width <= 0.0 || height <= 0.0 {
+                    return None;
+                }
+                let rx = element.length("rx", w).filter(|r| *r >= 0.0);
+                let ry = element.length("ry", h).filter(|r| *r >= 0.0);
+                // A missing radius takes the other one
+                let (rx, ry) = match (rx, ry) {
+                    (Some(rx), Some(ry)) => (rx, ry),
+                    (Some(r), None) | (None, Some(r)) => (r, r),
+                    (None, None) => (0.0, 0.0),
+                };
+                let (rx, ry) = (rx.min(width / 2.0), ry.min(height / 2.0));
+                let corner = |path: &mut PathBuilder, p| match rx > 0.0 && ry > 0.0 {
+                    true => path.arc_to((rx, ry), 0.0, false, true, p),
+                    false => path.line_to(p),
+                };
+                path.move_to((x + rx, y));
+                path.line_to((x + width - rx, y));
+                corner(&mut path, (x + width, y + ry));
+                path.line_to((x + width, y + height - ry));
+                corner(&mut path, (x + width - rx, y + height));
+                path.line_to((x + rx, y + height));
+                corner(&mut path, (x, y + height - ry));
+                path.line_to((x, y + ry));
+                corner(&mut path, (x + rx, y));
+                path.close();
+            }
+            name @ ("circle" | "ellipse") => {
+                let (cx, cy) = (length("cx", w), length("cy", h));
+                let (rx, ry) = match name {
+                    "circle" => (length("r", diagonal), length("r", diagonal)),
+                    _ => {
+                        let rx = element.length("rx", w);
+                        let ry = element.length("ry", h);
+                        (rx.or(ry).unwrap_or(0.0), ry.or(rx).unwrap_or(0.0))
+                    }
+                };
+                if rx <= 0.0 || ry <= 0.0 {
+                    return None;
+                }
+                path.move_to((cx + rx, cy));
+                path.arc_to((rx, ry), 0.0, false, true, (cx - rx, cy));
+                path.arc_to((rx, ry), 0.0, false, true, (cx + rx, cy));
+                path.close();
+            }
+            "line" => {
+                path.move_to((length("x1", w), length("y1", h)));
+                path.line_to((length("x2", w), length("y2", h)));
+            }
+            name @ ("polyline" | "polygon") => {
+                let points = parse_points(element.get("points")?);
+                let (first, rest) = points.split_first()?;
+                path.move_to(*first);
+                rest.iter().for_each(|&p| path.line_to(p));
+                if name == "polygon" {
+                    path.close();
+                }
+            }
+            _ => return None,
+        }
+        Some(path.build())
+    }
+
+    /// Bounding box of an element in its own user units, for gradients, clip paths and masks
+    fn bounds(&self, element: &Element, depth: usize) -> Option<Bounds> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let child_bounds = |child: &Element| {
+            let transform = self.local_transform(child, false);
+            self.bounds(child, depth + 1)
+                .map(|b| b.transform(transform))
+        };
+        match element.name.as_str() {
+            "svg" | "g" | "a" | "switch" | "symbol" => element
+                .children
+                .iter()
+                .filter(|child| RENDERED.contains(&child.name.as_str()))
+                .filter_map(child_bounds)
+                .reduce(Bounds::union),
+            "use" => element
+                .href()
+                .and_then(|id| self.ids.get(id))
+                .and_then(|target| child_bounds(target)),
+            _ => {
+                let subpaths = self.shape(element, TOLERANCE)?;
+                Bounds::of(subpaths.iter().flat_map(|s| s.points.iter().copied()))
+            }
+        }
+    }
+
+    /// Coverage of a clip path as the alpha of a canvas
+    fn clip_mask(
+        &self,
+        clip: &Element,
+        bounds: Option<Bounds>,
+        ctm: Matrix,
+        size: (usize, usize),
+    ) -> Canvas {
+        let mut mask = Canvas::new(size.0, size.1);
+        let mut ctm = ctm.multiply(self.local_transform(clip, false));
+        if clip.get("clipPathUnits") == Some("objectBoundingBox") {
+            match bounds.and_then(Bounds::unit_matrix) {
+                Some(unit) => ctm = ctm.multiply(unit),
+                None => return mask,
+            }
+        }
+        let base = self.style(&self.declarations(clip), &Style::default());
+        let white = Shader::Solid([1.0; 4]);
+        for child in &clip.children {
+            let declarations = self.declarations(child);
+            let style = self.style(&declarations, &base);
+            let hidden = declarations
+                .iter()
+                .any(|(n, v)| n == "display" && v.trim() == "none");
+            let child_ctm = ctm.multiply(self.local_transform(child, false));
+            let scale = child_ctm.scale_factor();
+            if hidden || !style.visible || scale <= 0.0 || !scale.is_finite() {
+                continue;
+            }
+            let Some(subpaths) = self.shape(child, TOLERANCE / scale) else {
+                continue;
+            };
+            let polygons: Vec<Vec<Point>> = subpaths
+                .iter()
+                .map(|s| s.points.iter().map(|&p| child_ctm.apply(p)).collect())
+                .collect();
+            mask.fill(&polygons, style.clip_rule, &white);
+        }
+        mask
+    }
+
+    /// Shader of a paint, `None` if nothing is painted
+    fn shader(
+        &self,
+        paint: &Paint,
+        opacity: f32,
+        style: &Style,
+        bounds: Option<Bounds>,
+        ctm: Matrix,
+    ) -> Option<Shader> {
+        match paint {
+            Paint::None => None,
+            Paint::Color(color) => Some(Shader::Solid(premultiply(*color, opacity))),
+            Paint::CurrentColor => Some(Shader::Solid(premultiply(style.color, opacity))),
+            Paint::Url(id, fallback) => match self.ids.get(id.as_str()) {
+                Some(element) if element.name.ends_with("Gradient") => {
+                    self.gradient(element, opacity, bounds, ctm)
+                }
+                _ => self.shader(fallback, opacity, style, bounds, ctm),
+            },
+        }
+    }
+
+    /// Shader of a linear or radial gradient, with attributes and stops it takes from the gradients it refers to
+    fn gradient(
+        &self,
+        element: &'a Element,
+        opacity: f32,
+        bounds: Option<Bounds>,
+        ctm: Matrix,
+    ) -> Option<Shader> {
+        let mut chain = vec![element];
+        while let Some(next) = chain
+            .last()
+            .and_then(|e| e.href())
+            .and_then(|id| self.ids.get(id))
+        {
+            if chain.len() > MAX_DEPTH || chain.iter().any(|e| std::ptr::eq(*e, *next)) {
+                break;
+            }
+            chain.push(next);
+        }
+        let attribute = |name: &str| chain.iter().find_map(|e| e.get(name));
+        let stops_element = chain
+            .iter()
+            .find(|e| e.children.iter().any(|c| c.name == "stop"))?;
+        let mut stops: Vec<(f32, Pixel)> = Vec::new();
+        for stop in stops_element.children.iter().filter(|c| c.name == "stop") {
+            let declarations = self.declarations(stop);
+            let property = |name: &str| {
+                declarations
+                    .iter()
+                    .rev()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, v)| v.trim())
+            };
+            let offset = property("offset").and_then(parse_opacity).unwrap_or(0.0);
+            let offset = offset.max(stops.last().map_or(0.0, |s| s.0));
+            let color = property("stop-color")
+                .and_then(parse_color)
+                .unwrap_or(Rgba([0, 0, 0, 255]));
+            let stop_opacity = property("stop-opacity")
+                .and_then(parse_opacity)
+                .unwrap_or(1.0);
+            stops.push((offset, premultiply(color, opacity * stop_opacity)));
+        }
+        match stops.len() {
+            0 => return None,
+            1 => return Some(Shader::Solid(stops[0].1)),
+            _ => {}
+        }
+
+        let user_space = attribute("gradientUnits") == Some("userSpaceOnUse");
+        let (w, h) = match user_space {
+            true => self.viewport,
+            false => (1.0, 1.0),
+        };
+        let diagonal = match user_space {
+            true => self.diagonal(),
+            false => 1.0,
+        };
+        // Fractions of the bounding box may be written as percentages or plain numbers
+        let coordinate = |name: &str, reference: f32, default: f32| {
+            attribute(name)
+                .and_then(|v| parse_length(v, reference))
+                .unwrap_or(default * reference)
+        };
+        let mut matrix = ctm;
+        if !user_space {
+            matrix = matrix.multiply(bounds.and_then(Bounds::unit_matrix)?);
+        }
+        if let Some(transform) = attribute("gradientTransform").and_then(parse_transform) {
+            matrix = matrix.multiply(transform);
+        }
+        let last = stops[stops.len() - 1].1;
+        let kind = match element.name.as_str() {
+            "linearGradient" => {
+                let start = (coordinate("x1", w, 0.0), coordinate("y1", h, 0.0));
+                let end = (coordinate("x2", w, 1.0), coordinate("y2", h, 0.0));
+                if start == end {
+                    return Some(Shader::Solid(last));
+                }
+                GradientKind::Linear(start, end)
+            }
+            _ => {
+                let center = (coordinate("cx", w, 0.5), coordinate("cy", h, 0.5));
+                let radius = coordinate("r", diagonal, 0.5);
+                let mut focal = (
+                    attribute("fx")
+                        .and_then(|v| parse_length(v, w))
+                        .unwrap_or(center.0),
+                    attribute("fy")
+                        .and_then(|v| parse_length(v, h))
+                        .unwrap_or(center.1),
+                );
+                if radius <= 0.0 {
+                    return Some(Shader::Solid(last));
+                }
+                // A focal point outside the circle is moved onto its edge
+                let (fx, fy) = (focal.0 - center.0, focal.1 - center.1);
+                let distance = fx.hypot(fy);
+                if distance > radius * 0.999 {
+                    let scale = radius * 0.999 / distance;
+                    focal = (center.0 + fx * scale, center.1 + fy * scale);
+                }
+                GradientKind::Radial {
+                    center,
+                    radius,
+                    focal,
+                }
+            }
+        };
+        let spread = match attribute("spreadMethod") {
+            Some("reflect") => Spread::Reflect,
+            Some("repeat") => Spread::Repeat,
+            _ => Spread::Pad,
+        };
+        Some(Shader::Gradient(Gradient {
+            kind,
+            stops,
+            spread,
+            inverse: matrix.invert()?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(body: &str) -> RgbaImage {
+        let svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"20\" height=\"20\">{}</svg>",
+            body
+        );
+        rasterize(svg.as_bytes(), &DecodeOptions::default()).unwrap()
+    }
+
+    fn alpha(img: &RgbaImage) -> u32 {
+        img.pixels().map(|p| p[3] as u32).sum::<u32>() / 255
+    }
+
+    #[test]
+    fn test_sizes() {
+        let svg =
+            b"<svg viewBox=\"0 0 40 20\" width=\"1in\"><rect width=\"40\" height=\"20\"/></svg>";
+        assert_eq!(
+            rasterize(svg, &DecodeOptions::default())
+                .unwrap()
+                .dimensions(),
+            (96, 48)
+        );
+        let dense = DecodeOptions {
+            density: 300.0,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(rasterize(svg, &dense).unwrap().dimensions(), (300, 150));
+        let fit = DecodeOptions {
+            render_size: Some(Size(64, 64)),
+            ..DecodeOptions::default()
+        };
+        let fitted = rasterize(svg, &fit).unwrap();
+        assert_eq!(fitted.dimensions(), (64, 32));
+        assert_eq!(fitted.get_pixel(63, 31).0, [0, 0, 0, 255]);
+        let default = rasterize(b"<svg/>", &DecodeOptions::default()).unwrap();
+        assert_eq!(default.dimensions(), (100, 100));
+        assert!(
+            rasterize(
+                b"<svg width=\"1e6\" height=\"1e6\"/>",
+                &DecodeOptions::default()
+            )
+            .is_err()
+        );
+        assert!(rasterize(b"<html><svg/></html>", &DecodeOptions::default()).is_err());
+        let limited = DecodeOptions {
+            max_alloc: Some(96 * 48 * 4 - 1),
+            ..DecodeOptions::default()
+        };
+        assert!(matches!(
+            rasterize(svg, &limited),
+            Err(ImgtoolsError::Decode(ImageError::Limits(_)))
+        ));
+
+        // The view box is centered in a wider viewport
+        let img = render(
+            "<svg viewBox=\"0 0 10 20\" width=\"20\" height=\"20\">\
+             <rect width=\"10\" height=\"20\" fill=\"blue\"/></svg>",
+        );
+        assert_eq!(img.get_pixel(10, 10).0, [0, 0, 255, 255]);
+        assert_eq!(img.get_pixel(2, 10).0[3], 0);
+        assert_eq!(img.get_pixel(17, 10).0[3], 0);
+    }
+
+    #[test]
+    fn test_shapes() {
+        // Edges between pixels are covered in proportion
+        let img = render("<rect x=\"2.5\" y=\"2\" width=\"10\" height=\"4\" fill=\"#00ff00\"/>");
+        assert_eq!(img.get_pixel(5, 3).0, [0, 255, 0, 255]);
+        assert_eq!(img.get_pixel(2, 3).0, [0, 255, 0, 128]);
+        assert_eq!(img.get_pixel(5, 6).0[3], 0);
+        assert_eq!(alpha(&img), 40);
+
+        let circle = alpha(&render("<circle cx=\"10\" cy=\"10\" r=\"8\"/>"));
+        assert!((circle as f32 - PI * 64.0).abs() < 5.0);
+
+        // A hole with the even-odd rule, filled with nonzero
+        let square = "M2 2h16v16h-16z M6 6h8v8h-8z";
+        let evenodd = render(&format!("<path d=\"{}\" fill-rule=\"evenodd\"/>", square));
+        assert_eq!(evenodd.get_pixel(10, 10).0[3], 0);
+        assert_eq!(alpha(&evenodd), 256 - 64);
+        let nonzero = render(&format!("<path d=\"{}\"/>", square));
+        assert_eq!(alpha(&nonzero), 256);
+
+        // Half a circle by arcs, relative commands and implicit lines
+        let arc = render("<path d=\"m2 10 a8 8 0 0 1 16 0z\"/>");
+        assert!((alpha(&arc) as f32 - PI * 32.0).abs() < 5.0);
+        assert_eq!(arc.get_pixel(10, 12).0[3], 0);
+        let polygon = render("<polygon points=\"0,0 20,0 20,20\"/>");
+        assert_eq!(alpha(&polygon), 200);
+    }
+
+    #[test]
+    fn test_strokes() {
+        let line = render(
+            "<line x1=\"2\" y1=\"10\" x2=\"18\" y2=\"10\" stroke=\"red\" stroke-width=\"4\"/>",
+        );
+        assert_eq!(line.get_pixel(10, 9).0, [255, 0, 0, 255]);
+        assert_eq!(alpha(&line), 64);
+        let capped = render(
+            "<line x1=\"2\" y1=\"10\" x2=\"18\" y2=\"10\" stroke=\"red\" stroke-width=\"4\" \
+             stroke-linecap=\"square\"/>",
+        );
+        assert_eq!(alpha(&capped), 80);
+        let dashed = render(
+            "<line x1=\"0\" y1=\"10\" x2=\"20\" y2=\"10\" stroke=\"red\" stroke-width=\"2\" \
+             stroke-dasharray=\"5\"/>",
+        );
+        assert_eq!(alpha(&dashed), 20);
+        assert_eq!(dashed.get_pixel(7, 10).0[3], 0);
+
+        // Outline of a square, the miter joins fill the corners
+        let outline = render(
+            "<rect x=\"4\" y=\"4\" width=\"12\" height=\"12\" fill=\"none\" stroke=\"black\" stroke-width=\"2\"/>",
+        );
+        assert_eq!(alpha(&outline), 14 * 14 - 10 * 10);
+        assert_eq!(outline.get_pixel(3, 3).0[3], 255);
+        let beveled = render(
+            "<rect x=\"4\" y=\"4\" width=\"12\" height=\"12\" fill=\"none\" stroke=\"black\" \
+             stroke-width=\"2\" stroke-linejoin=\"bevel\"/>",
+        );
+        assert_eq!(alpha(&beveled), 14 * 14 - 10 * 10 - 2);
+    }
+
+    #[test]
+    fn test_styles_and_references() {
+        let img = render(
+            "<style>rect { fill: blue } .warm { fill: red } #mark { fill: lime }</style>\
+             <defs><rect id=\"cell\" width=\"4\" height=\"4\"/></defs>\
+             <rect width=\"4\" height=\"4\" fill=\"black\"/>\
+             <rect class=\"warm\" x=\"5\" width=\"4\" height=\"4\"/>\
+             <rect id=\"mark\" class=\"warm\" x=\"10\" width=\"4\" height=\"4\"/>\
+             <rect x=\"15\" width=\"4\" height=\"4\" style=\"fill: #ff0\"/>\
+             <g fill=\"purple\" transform=\"translate(0 10) scale(2)\"><use href=\"#cell\"/></g>",
+        );
+        assert_eq!(img.get_pixel(1, 1).0, [0, 0, 255, 255]);
+        assert_eq!(img.get_pixel(6, 1).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(11, 1).0, [0, 255, 0, 255]);
+        assert_eq!(img.get_pixel(16, 1).0, [255, 255, 0, 255]);
+        // The referenced rect is styled by the sheet, not by the group
+        assert_eq!(img.get_pixel(7, 17).0, [0, 0, 255, 255]);
+        assert_eq!(img.get_pixel(9, 17).0[3], 0);
+
+        let hidden = render(
+            "<g display=\"none\"><rect width=\"20\" height=\"20\"/></g>\
+             <rect width=\"20\" height=\"20\" visibility=\"hidden\"/>",
+        );
+        assert_eq!(alpha(&hidden), 0);
+        let faded = render(
+            "<g opacity=\"0.5\"><rect width=\"20\" height=\"20\"/><rect width=\"20\" height=\"20\"/></g>",
+        );
+        assert_eq!(faded.get_pixel(5, 5).0, [0, 0, 0, 128]);
+    }
+
+    #[test]
+    fn test_paint_servers() {
+        let img = render(
+            "<linearGradient id=\"fade\"><stop offset=\"0\" stop-color=\"black\"/>\
+             <stop offset=\"1\" stop-color=\"white\"/></linearGradient>\
+             <rect width=\"20\" height=\"10\" fill=\"url(#fade)\"/>\
+             <radialGradient id=\"glow\" gradientUnits=\"userSpaceOnUse\" cx=\"10\" cy=\"15\" r=\"5\">\
+             <stop offset=\"0\" stop-color=\"red\"/><stop offset=\"1\" stop-color=\"red\" stop-opacity=\"0\"/>\
+             </radialGradient><rect y=\"10\" width=\"20\" height=\"10\" fill=\"url(#glow)\"/>",
+        );
+        let (left, right) = (img.get_pixel(0, 5).0[0], img.get_pixel(19, 5).0[0]);
+        assert!(left < 10 && right > 245, "{} {}", left, right);
+        assert!(img.get_pixel(10, 5).0[0].abs_diff(134) < 4);
+        assert!(img.get_pixel(10, 15).0[3] > 200);
+        assert_eq!(img.get_pixel(2, 15).0[3], 0);
+        let fallback = render("<rect width=\"20\" height=\"20\" fill=\"url(#missing) green\"/>");
+        assert_eq!(fallback.get_pixel(5, 5).0, [0, 128, 0, 255]);
+    }
+
+    #[test]
+    fn test_clips_and_masks() {
+        let clipped = render(
+            "<clipPath id=\"left\"><rect width=\"10\" height=\"20\"/></clipPath>\
+             <rect width=\"20\" height=\"20\" clip-path=\"url(#left)\"/>",
+        );
+        assert_eq!(alpha(&clipped), 200);
+        assert_eq!(clipped.get_pixel(15, 5).0[3], 0);
+
+        let bounded = render(
+            "<clipPath id=\"half\" clipPathUnits=\"objectBoundingBox\"><rect width=\"0.5\" height=\"1\"/></clipPath>\
+             <rect x=\"10\" width=\"10\" height=\"20\" clip-path=\"url(#half)\"/>",
+        );
+        assert_eq!(alpha(&bounded), 100);
+
+        let masked = render(
+            "<mask id=\"dim\"><rect width=\"20\" height=\"20\" fill=\"white\"/>\
+             <rect y=\"10\" width=\"20\" height=\"10\" fill=\"black\"/></mask>\
+             <rect width=\"20\" height=\"20\" fill=\"blue\" mask=\"url(#dim)\"/>",
+        );
+        assert_eq!(masked.get_pixel(5, 5).0, [0, 0, 255, 255]);
+        assert_eq!(masked.get_pixel(5, 15).0[3], 0);
+    }
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!(
+            parse_transform("translate(10,20) scale(2)")
+                .unwrap()
+                .apply((1.0, 1.0)),
+            (12.0, 22.0)
+        );
+        let (x, y) = parse_transform("rotate(90 10 10)")
+            .unwrap()
+            .apply((20.0, 10.0));
+        assert!((x - 10.0).abs() < 1e-4 && (y - 20.0).abs() < 1e-4);
+        assert!(parse_transform("spin(3)").is_none());
+        assert_eq!(parse_length("2em", 0.0), Some(32.0));
+        assert_eq!(parse_length("50%", 40.0), Some(20.0));
+        assert_eq!(parse_length("1e1", 0.0), Some(10.0));
+        assert_eq!(parse_length("3furlongs", 0.0), None);
+        assert_eq!(parse_points("1,2 3-4 5"), vec![(1.0, 2.0), (3.0, -4.0)]);
+
+        let path = parse_path("M0 0L10 0 10 10zm5 5h1 X 7 7", 0.1);
+        assert_eq!(path.len(), 2);
+        assert!(path[0].closed);
+        assert_eq!(path[1].points, vec![(5.0, 5.0), (6.0, 5.0)]);
+    }
+}
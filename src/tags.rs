@@ -0,0 +1,592 @@
+use crate::ImgtoolsError;
+use crate::metadata::LAYOUT_TAGS;
+use crate::strip::{Rewrite, rewrite_metadata, thumbnail, write_exif};
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Tag, Value};
+use image::ImageFormat;
+use std::io::Cursor;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Text fields that `--tag` can set, by their EXIF names
+const TEXT_TAGS: [Tag; 17] = [
+    Tag::ImageDescription,
+    Tag::Make,
+    Tag::Model,
+    Tag::Software,
+    Tag::Artist,
+    Tag::Copyright,
+    Tag::DateTime,
+    Tag::DateTimeOriginal,
+    Tag::DateTimeDigitized,
+    Tag::OffsetTime,
+    Tag::OffsetTimeOriginal,
+    Tag::CameraOwnerName,
+    Tag::BodySerialNumber,
+    Tag::LensMake,
+    Tag::LensModel,
+    Tag::LensSerialNumber,
+    Tag::ImageUniqueID,
+];
+
+/// TIFF tag of the pointer to the EXIF directory
+const EXIF_POINTER: u16 = 0x8769;
+/// TIFF tag of the pointer to the GPS directory
+const GPS_POINTER: u16 = 0x8825;
+
+/// An EXIF text field and its value, given as `Name=value`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExifTag {
+    pub tag: Tag,
+    pub value: String,
+}
+
+impl FromStr for ExifTag {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or("Format error: Expected Name=value")?;
+        let tag = TEXT_TAGS
+            .into_iter()
+            .find(|tag| tag.to_string().eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| {
+                let names: Vec<String> = TEXT_TAGS.iter().map(Tag::to_string).collect();
+                format!("Unknown tag {}, expected one of {}", name, names.join(", "))
+            })?;
+        Ok(ExifTag {
+            tag,
+            value: value.to_string(),
+        })
+    }
+}
+
+/// A date and time in EXIF layout, e.g. `2024:05:17 14:23:09`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExifDateTime(String);
+
+impl FromStr for ExifDateTime {
+    type Err = &'static str;
+    /// Accept `2024-05-17 14:23:09`, `2024-05-17T14:23:09` or the EXIF layout
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "Format error: Expected a date and time like 2024-05-17 14:23:09";
+        let (date, time) = s.trim().split_once([' ', 'T']).ok_or(ERROR)?;
+        let numbers = |part: &str, separators: &[char]| -> Option<Vec<u32>> {
+            part.split(separators)
+                .map(|n| n.parse::<u32>().ok().filter(|_| n.len() >= 2))
+                .collect()
+        };
+        match numbers(date, &['-', ':'])
+            .zip(numbers(time, &[':']))
+            .ok_or(ERROR)?
+        {
+            (date, time)
+                if matches!(
+                    (&date[..], &time[..]),
+                    ([_, 1..=12, 1..=31], [0..=23, 0..=59, 0..=59])
+                ) =>
+            {
+                Ok(ExifDateTime(format!(
+                    "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+                    date[0], date[1], date[2], time[0], time[1], time[2]
+                )))
+            }
+            _ => Err(ERROR),
+        }
+    }
+}
+
+/// Fields that `exif set` writes, later ones replace earlier ones of the same tag
+///
+/// The date and time goes into DateTime and DateTimeOriginal.
+pub(crate) fn set_fields(
+    artist: Option<&str>,
+    copyright: Option<&str>,
+    datetime: Option<&ExifDateTime>,
+    tags: &[ExifTag],
+) -> Vec<Field> {
+    let text = |tag, value: &str| Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+    };
+    let mut fields: Vec<Field> = Vec::new();
+    let assigned = artist
+        .map(|artist| (Tag::Artist, artist))
+        .into_iter()
+        .chain(copyright.map(|copyright| (Tag::Copyright, copyright)))
+        .chain(datetime.into_iter().flat_map(|datetime| {
+            [
+                (Tag::DateTime, datetime.0.as_str()),
+                (Tag::DateTimeOriginal, datetime.0.as_str()),
+            ]
+        }))
+        .chain(tags.iter().map(|tag| (tag.tag, tag.value.as_str())));
+    for (tag, value) in assigned {
+        fields.retain(|field| field.tag != tag);
+        fields.push(text(tag, value));
+    }
+    fields
+}
+
+/// EXIF data with the fields set, new EXIF if there is none yet
+pub(crate) fn set_exif(exif: Option<&[u8]>, fields: &[Field]) -> Result<Vec<u8>, ImgtoolsError> {
+    let Some(exif) = exif else {
+        return write_exif(fields, None, true);
+    };
+    let exif = exif::Reader::new()
+        .read_raw(exif.to_vec())
+        .map_err(ImgtoolsError::Metadata)?;
+    let kept = exif.fields().filter(|field| {
+        !matches!(field.value, Value::Unknown(..))
+            && !fields
+                .iter()
+                .any(|set| set.tag == field.tag && set.ifd_num == field.ifd_num)
+    });
+    write_exif(kept.chain(fields), thumbnail(&exif), exif.little_endian())
+}
+
+/// Set EXIF fields in an encoded image without decoding it
+///
+/// JPEG, PNG and WebP get their EXIF data replaced or added, everything else
+/// stays. TIFF files keep their image data and get new directories with the
+/// fields appended. Returns `None` for other formats and for data that can't
+/// be parsed.
+pub fn set_metadata(
+    data: &[u8],
+    format: ImageFormat,
+    fields: &[Field],
+) -> Result<Option<Vec<u8>>, ImgtoolsError> {
+    if format == ImageFormat::Tiff {
+        return Ok(set_tiff_fields(data, fields, &|_| false));
+    }
+    let rewrite = Rewrite {
+        icc: true,
+        xmp: true,
+        other: true,
+        exif: &|exif| set_exif(Some(exif), fields).map(Some),
+        insert: Some(set_exif(None, fields)?),
+    };
+    rewrite_metadata(data, format, &rewrite)
+}
+
+/// Add EXIF data to a TIFF file as the image encoder wrote it
+///
+/// The file is laid out again with the fields of its directory, those of the
+/// primary image of the EXIF data and its strips. EXIF fields replace the ones
+/// of the file, except those describing how the image is stored.
+pub(crate) fn set_tiff_exif(data: &[u8], exif: &[u8]) -> Result<Vec<u8>, ImgtoolsError> {
+    let image = exif::Reader::new()
+        .read_raw(data.to_vec())
+        .map_err(ImgtoolsError::Metadata)?;
+    let exif = exif::Reader::new()
+        .read_raw(exif.to_vec())
+        .map_err(ImgtoolsError::Metadata)?;
+    let added: Vec<&Field> = exif
+        .fields()
+        .filter(|field| {
+            field.ifd_num == In::PRIMARY
+                && !LAYOUT_TAGS.contains(&field.tag)
+                && !matches!(field.value, Value::Unknown(..))
+        })
+        .collect();
+    let own = image.fields().filter(|field| {
+        field.ifd_num == In::PRIMARY
+            && !added.iter().any(|exif| exif.tag == field.tag)
+            && !matches!(field.value, Value::Unknown(..))
+    });
+
+    let uints = |tag| {
+        image.get_field(tag, In::PRIMARY).map(|field| {
+            field
+                .value
+                .iter_uint()
+                .map(|v| v.into_iter().collect::<Vec<_>>())
+        })
+    };
+    let (Some(Some(offsets)), Some(Some(counts))) =
+        (uints(Tag::StripOffsets), uints(Tag::StripByteCounts))
+    else {
+        return Err(ImgtoolsError::Metadata(exif::Error::InvalidFormat(
+            "TIFF output without strips",
+        )));
+    };
+    let strips = offsets
+        .iter()
+        .zip(&counts)
+        .map(|(&offset, &count)| data.get(offset as usize..(offset + count) as usize))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(ImgtoolsError::Metadata(exif::Error::InvalidFormat(
+            "TIFF strip out of bounds",
+        )))?;
+
+    let mut writer = Writer::new();
+    for field in own.chain(added.iter().copied()) {
+        writer.push_field(field);
+    }
+    writer.set_strips(&strips, In::PRIMARY);
+    let mut result = Cursor::new(Vec::new());
+    writer
+        .write(&mut result, image.little_endian())
+        .map_err(ImgtoolsError::Metadata)?;
+    Ok(result.into_inner())
+}
+
+/// Tag of a TIFF directory entry and the raw entry
+pub(crate) type Entry = (u16, [u8; 12]);
+
+/// Byte order of a TIFF file
+#[derive(Clone, Copy)]
+pub(crate) struct Order {
+    little_endian: bool,
+}
+
+impl Order {
+    /// Byte order of a TIFF header, `None` for BigTIFF and anything else
+    pub(crate) fn of(data: &[u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Order { little_endian })
+    }
+
+    pub(crate) fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub(crate) fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn put_u16(self, value: u16) -> [u8; 2] {
+        match self.little_endian {
+            true => value.to_le_bytes(),
+            false => value.to_be_bytes(),
+        }
+    }
+
+    fn put_u32(self, value: u32) -> [u8; 4] {
+        match self.little_endian {
+            true => value.to_le_bytes(),
+            false => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Set text fields in a TIFF file and remove the fields `remove` picks, the
+/// image data stays where it is
+///
+/// Offsets in TIFF count from the start of the file, so the entries of the
+/// first directory and its EXIF directory are copied into new directories at
+/// the end that hold the fields as well. The old directories, the values of
+/// removed and replaced fields and a removed GPS directory are zeroed, so
+/// what was taken out can't be read from the file anymore.
+pub(crate) fn set_tiff_fields(
+    data: &[u8],
+    fields: &[Field],
+    remove: &dyn Fn(Tag) -> bool,
+) -> Option<Vec<u8>> {
+    let order = Order::of(data)?;
+    let (tiff_fields, exif_fields): (Vec<&Field>, Vec<&Field>) = fields
+        .iter()
+        .partition(|field| field.tag.context() == Context::Tiff);
+
+    let mut result = data.to_vec();
+    let mut cleared = Vec::new();
+    let ifd = order.u32(data.get(4..8)?) as usize;
+    let (mut entries, next) = read_ifd(data, ifd, order)?;
+    cleared.push(directory(ifd, entries.len()));
+    let exif_pointer = entries.iter().find(|entry| entry.0 == EXIF_POINTER);
+    if exif_pointer.is_some() || !exif_fields.is_empty() {
+        let mut exif_entries = match exif_pointer {
+            Some((_, raw)) => {
+                let offset = order.u32(&raw[8..12]) as usize;
+                let (exif_entries, _) = read_ifd(data, offset, order)?;
+                cleared.push(directory(offset, exif_entries.len()));
+                exif_entries
+            }
+            None => Vec::new(),
+        };
+        let dropped = |tag| {
+            remove(Tag(Context::Exif, tag)) || exif_fields.iter().any(|f| f.tag.number() == tag)
+        };
+        take_entries(&mut exif_entries, dropped, order, &mut cleared);
+        let exif_ifd = write_ifd(&mut result, exif_entries, &exif_fields, 0, order)?;
+        // LONG, count 1, the offset of the new directory
+        let mut raw = [0; 12];
+        raw[..2].copy_from_slice(&order.put_u16(EXIF_POINTER));
+        raw[2..4].copy_from_slice(&order.put_u16(4));
+        raw[4..8].copy_from_slice(&order.put_u32(1));
+        raw[8..12].copy_from_slice(&order.put_u32(exif_ifd));
+        entries.retain(|entry| entry.0 != EXIF_POINTER);
+        entries.push((EXIF_POINTER, raw));
+    }
+    if remove(Tag::GPSInfoIFDPointer)
+        && let Some((_, raw)) = entries.iter().find(|entry| entry.0 == GPS_POINTER)
+    {
+        let offset = order.u32(&raw[8..12]) as usize;
+        let (mut gps_entries, _) = read_ifd(data, offset, order)?;
+        cleared.push(directory(offset, gps_entries.len()));
+        take_entries(&mut gps_entries, |_| true, order, &mut cleared);
+        entries.retain(|entry| entry.0 != GPS_POINTER);
+    }
+    let dropped = |tag| {
+        tag != EXIF_POINTER
+            && (remove(Tag(Context::Tiff, tag))
+                || tiff_fields.iter().any(|f| f.tag.number() == tag))
+    };
+    take_entries(&mut entries, dropped, order, &mut cleared);
+    let ifd = write_ifd(&mut result, entries, &tiff_fields, next, order)?;
+    result[4..8].copy_from_slice(&order.put_u32(ifd));
+    for range in cleared {
+        if let Some(bytes) = result.get_mut(range.start..range.end.min(data.len())) {
+            bytes.fill(0);
+        }
+    }
+    Some(result)
+}
+
+/// Bytes of a directory with `count` entries, with the offset of the next one
+fn directory(offset: usize, count: usize) -> Range<usize> {
+    offset..offset + 2 + count * 12 + 4
+}
+
+/// Remove the entries whose tags `dropped` picks, noting where their values
+/// are stored when they don't fit in the entry
+fn take_entries(
+    entries: &mut Vec<Entry>,
+    dropped: impl Fn(u16) -> bool,
+    order: Order,
+    cleared: &mut Vec<Range<usize>>,
+) {
+    entries.retain(|(tag, raw)| {
+        if !dropped(*tag) {
+            return true;
+        }
+        let size = match order.u16(&raw[2..4]) {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            _ => 8,
+        };
+        let len = size * order.u32(&raw[4..8]) as usize;
+        if len > 4 {
+            let offset = order.u32(&raw[8..12]) as usize;
+            cleared.push(offset..offset.saturating_add(len));
+        }
+        false
+    });
+}
+
+/// Entries of a directory with their tags, and the offset of the next one
+pub(crate) fn read_ifd(data: &[u8], offset: usize, order: Order) -> Option<(Vec<Entry>, u32)> {
+    let count = order.u16(data.get(offset..offset + 2)?) as usize;
+    let end = offset + 2 + count * 12;
+    let entries = data
+        .get(offset + 2..end)?
+        .chunks_exact(12)
+        .map(|raw| (order.u16(raw), raw.try_into().unwrap_or([0; 12])))
+        .collect();
+    Some((entries, order.u32(data.get(end..end + 4)?)))
+}
+
+/// Append a directory with the entries and text fields, returning its offset
+fn write_ifd(
+    result: &mut Vec<u8>,
+    mut entries: Vec<Entry>,
+    fields: &[&Field],
+    next: u32,
+    order: Order,
+) -> Option<u32> {
+    for field in fields {
+        let Value::Ascii(lines) = &field.value else {
+            continue;
+        };
+        let text: Vec<u8> = lines
+            .iter()
+            .flat_map(|line| line.iter().copied().chain([0]))
+            .collect();
+        let tag = field.tag.number();
+        // ASCII, the text itself if it fits in four bytes or else its offset
+        let mut raw = [0; 12];
+        raw[..2].copy_from_slice(&order.put_u16(tag));
+        raw[2..4].copy_from_slice(&order.put_u16(2));
+        raw[4..8].copy_from_slice(&order.put_u32(u32::try_from(text.len()).ok()?));
+        if text.len() <= 4 {
+            raw[8..8 + text.len()].copy_from_slice(&text);
+        } else {
+            result.resize(result.len() + result.len() % 2, 0);
+            raw[8..12].copy_from_slice(&order.put_u32(u32::try_from(result.len()).ok()?));
+            result.extend(text);
+        }
+        entries.retain(|entry| entry.0 != tag);
+        entries.push((tag, raw));
+    }
+    // Directories start on a word boundary and list their entries by tag
+    entries.sort_by_key(|entry| entry.0);
+    result.resize(result.len() + result.len() % 2, 0);
+    let offset = u32::try_from(result.len()).ok()?;
+    result.extend(order.put_u16(entries.len() as u16));
+    for (_, raw) in entries {
+        result.extend(raw);
+    }
+    result.extend(order.put_u32(next));
+    Some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, Metadata, encode_with_metadata};
+    use image::{DynamicImage, GenericImageView};
+    use std::io::Cursor;
+
+    fn read_field(data: &[u8], tag: Tag) -> Option<String> {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(data))
+            .ok()?;
+        let field = exif.get_field(tag, In::PRIMARY)?;
+        match &field.value {
+            Value::Ascii(lines) => Some(String::from_utf8_lossy(&lines[0]).into_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let tag: ExifTag = "lensmodel=50mm F1.8".parse().unwrap();
+        assert_eq!(tag.tag, Tag::LensModel);
+        assert_eq!(tag.value, "50mm F1.8");
+        assert!("Rating=5".parse::<ExifTag>().is_err());
+        assert!("Artist".parse::<ExifTag>().is_err());
+
+        let expected = ExifDateTime("2024:05:17 14:23:09".into());
+        for s in [
+            "2024-05-17 14:23:09",
+            "2024-05-17T14:23:09",
+            "2024:05:17 14:23:09",
+        ] {
+            assert_eq!(s.parse::<ExifDateTime>(), Ok(expected.clone()));
+        }
+        assert!("2024-13-17 14:23:09".parse::<ExifDateTime>().is_err());
+        assert!("2024-05-17".parse::<ExifDateTime>().is_err());
+    }
+
+    #[test]
+    fn test_set_fields() {
+        let tags = [ExifTag {
+            tag: Tag::Artist,
+            value: "B".into(),
+        }];
+        let datetime = "2024-05-17 14:23:09".parse().unwrap();
+        let fields = set_fields(Some("A"), Some("(c) A"), Some(&datetime), &tags);
+        let tags: Vec<Tag> = fields.iter().map(|field| field.tag).collect();
+        assert_eq!(
+            tags,
+            [
+                Tag::Copyright,
+                Tag::DateTime,
+                Tag::DateTimeOriginal,
+                Tag::Artist
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_tiff_exif() {
+        let fields = [
+            Field {
+                tag: Tag::Model,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"Camera".to_vec()]),
+            },
+            Field {
+                tag: Tag::ExposureTime,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(1, 250).into()]),
+            },
+            // Stale layout of another file, the encoded one is kept
+            Field {
+                tag: Tag::ImageWidth,
+                ifd_num: In::PRIMARY,
+                value: Value::Long(vec![6000]),
+            },
+        ];
+        let exif = write_exif(&fields, None, false).unwrap();
+        let icc = crate::profile_data(&crate::Profile::DisplayP3).unwrap();
+        let metadata = Metadata {
+            exif: Some(exif),
+            xmp: None,
+            icc: Some(icc.clone()),
+        };
+        let img = DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(5, 3, |x, y| {
+            image::Rgb([x as u16 * 9000, y as u16 * 20000, 777])
+        }));
+        let mut data = Cursor::new(Vec::new());
+        encode_with_metadata(&img, Format::Tiff, &metadata, &mut data).unwrap();
+        let data = data.into_inner();
+
+        assert_eq!(read_field(&data, Tag::Model).as_deref(), Some("Camera"));
+        let exif = exif::Reader::new().read_raw(data.clone()).unwrap();
+        let exposure = exif.get_field(Tag::ExposureTime, In::PRIMARY).unwrap();
+        assert_eq!(exposure.display_value().to_string(), "1/250");
+        let profile = exif
+            .get_field(Tag(Context::Tiff, 34675), In::PRIMARY)
+            .unwrap();
+        assert!(matches!(&profile.value, Value::Byte(data) if *data == icc));
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Tiff).unwrap();
+        assert_eq!(decoded, img);
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let datetime = "2024-05-17 14:23:09".parse().unwrap();
+        let fields = set_fields(Some("Someone"), None, Some(&datetime), &[]);
+        let img = DynamicImage::new_rgb8(4, 2);
+        for format in [Format::Jpeg, Format::Png, Format::WebP, Format::Tiff] {
+            let image_format = ImageFormat::from(format);
+            let mut data = Cursor::new(Vec::new());
+            encode_with_metadata(&img, format, &Metadata::default(), &mut data).unwrap();
+            let data = data.into_inner();
+
+            // Added first, then changed with the other fields kept
+            let tagged = set_metadata(&data, image_format, &fields).unwrap().unwrap();
+            assert_eq!(
+                read_field(&tagged, Tag::Artist).as_deref(),
+                Some("Someone"),
+                "{}",
+                format
+            );
+            let artist = set_fields(Some("Another"), None, None, &[]);
+            let retagged = set_metadata(&tagged, image_format, &artist)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                read_field(&retagged, Tag::Artist).as_deref(),
+                Some("Another")
+            );
+            assert_eq!(
+                read_field(&retagged, Tag::DateTimeOriginal).as_deref(),
+                Some("2024:05:17 14:23:09"),
+                "{}",
+                format
+            );
+            let decoded = image::load_from_memory_with_format(&retagged, image_format).unwrap();
+            assert_eq!(decoded.dimensions(), (4, 2), "{}", format);
+        }
+        assert_eq!(
+            set_metadata(b"BM", ImageFormat::Bmp, &fields).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use crate::{ProcessOptions, parse_recipe};
+use wasm_bindgen::prelude::*;
+
+/// Run a JSON recipe on an encoded image and return the encoded result
+///
+/// The recipe is written as for the run command, e.g.
+/// `[{"resize": {"width": 800, "filter": "lanczos3"}}, {"convert": {"format": "webp"}}]`.
+/// The result keeps the input format unless a step converts it. Steps that
+/// read other files, such as image watermarks or font files, fail since there
+/// is no file system.
+#[wasm_bindgen(js_name = processBytes)]
+pub fn process_bytes(input: &[u8], recipe_json: &str) -> Result<Vec<u8>, JsError> {
+    let command = parse_recipe(recipe_json).map_err(|e| JsError::new(&e))?;
+    if command.is_report() || command.combines_inputs() {
+        return Err(JsError::new(&format!(
+            "{} doesn't produce an image",
+            command.name()
+        )));
+    }
+    crate::process_bytes(input, &command, &ProcessOptions::default())
+        .map(|(data, _)| data)
+        .map_err(|e| JsError::new(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, encode};
+    use image::{DynamicImage, GenericImageView, ImageBuffer, ImageFormat, Rgb};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_process_bytes() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 2, Rgb([255, 0, 0])));
+        let mut png = Cursor::new(Vec::new());
+        encode(&img, Format::Png, &mut png).unwrap();
+
+        let recipe = r#"[{"rotate": {"r": 90}}, {"convert": {"format": "bmp"}}]"#;
+        let data = process_bytes(png.get_ref(), recipe).unwrap();
+        let rotated = image::load_from_memory_with_format(&data, ImageFormat::Bmp).unwrap();
+        assert_eq!(rotated.dimensions(), (2, 4));
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Size and modification time, which change whenever a file is written
+type Stamp = (u64, Option<SystemTime>);
+
+/// Tracks the images of watched inputs to find new and modified ones
+///
+/// A file is ready once its size and modification time stayed the same for a
+/// whole poll, so files that are still being copied aren't read half written.
+#[derive(Debug, Default)]
+pub struct Watcher {
+    /// Last stamp of every file and whether that version was handed out
+    files: HashMap<PathBuf, (Stamp, bool)>,
+}
+
+impl Watcher {
+    /// Start watching, the files that are already there count as handled
+    pub fn new(existing: &[PathBuf]) -> Self {
+        let files = existing
+            .iter()
+            .filter_map(|path| Some((path.clone(), (stamp(path)?, true))))
+            .collect();
+        Watcher { files }
+    }
+
+    /// Record the current files and return the new or modified ones that are ready
+    pub fn poll(&mut self, files: &[PathBuf]) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+        for path in files {
+            let Some(stamp) = stamp(path) else {
+                continue;
+            };
+            match self.files.get_mut(path) {
+                Some((last, done)) if *last == stamp => {
+                    if !*done {
+                        *done = true;
+                        ready.push(path.clone());
+                    }
+                }
+                Some(entry) => *entry = (stamp, false),
+                None => {
+                    self.files.insert(path.clone(), (stamp, false));
+                }
+            }
+        }
+        // Forget deleted files, they are new again if they come back
+        self.files.retain(|path, _| files.contains(path));
+        ready
+    }
+}
+
+fn stamp(path: &PathBuf) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watcher_finds_settled_new_and_modified_files() {
+        let dir = std::env::temp_dir().join("imgtools_test_watch");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("old.png");
+        let new = dir.join("new.png");
+        fs::write(&old, b"old").unwrap();
+
+        let mut watcher = Watcher::new(std::slice::from_ref(&old));
+        assert!(watcher.poll(std::slice::from_ref(&old)).is_empty());
+
+        // A new file is ready once it stops changing
+        fs::write(&new, b"new").unwrap();
+        let files = [old.clone(), new.clone()];
+        assert!(watcher.poll(&files).is_empty());
+        assert_eq!(watcher.poll(&files), [new.as_path()]);
+        assert!(watcher.poll(&files).is_empty());
+
+        // A modified file is handed out again
+        fs::write(&old, b"modified").unwrap();
+        assert!(watcher.poll(&files).is_empty());
+        assert_eq!(watcher.poll(&files), [old.as_path()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+/// Element of a document with its attributes, children and text
+#[derive(Debug, Default)]
+pub(crate) struct Element {
+    /// Name without a namespace prefix
+    pub(crate) name: String,
+    pub(crate) attributes: Vec<(String, String)>,
+    pub(crate) children: Vec<Element>,
+    /// Text and CDATA content
+    pub(crate) text: String,
+}
+
+/// Parse the elements of an XML document, skipping declarations and comments
+pub(crate) fn parse(text: &str) -> Result<Element, String> {
+    let mut stack: Vec<Element> = Vec::new();
+    let mut entities = Entities::new();
+    let mut rest = text;
+    loop {
+        let start = rest.find('<').ok_or("the document has no root element")?;
+        if let Some(top) = stack.last_mut() {
+            top.text.push_str(&unescape(&rest[..start], &entities));
+        }
+        rest = &rest[start..];
+        let element = if let Some(after) = rest.strip_prefix("<!--") {
+            rest = skip_past(after, "-->")?;
+            continue;
+        } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after.find("]]>").ok_or("unterminated CDATA section")?;
+            if let Some(top) = stack.last_mut() {
+                top.text.push_str(&after[..end]);
+            }
+            rest = &after[end + 3..];
+            continue;
+        } else if let Some(after) = rest.strip_prefix("<?") {
+            rest = skip_past(after, "?>")?;
+            continue;
+        } else if rest.starts_with("<!") {
+            let after = skip_declaration(rest)?;
+            declare_entities(&rest[..rest.len() - after.len()], &mut entities);
+            rest = after;
+            continue;
+        } else if let Some(after) = rest.strip_prefix("</") {
+            let end = after.find('>').ok_or("unterminated end tag")?;
+            let element = stack.pop().ok_or("end tag without a start tag")?;
+            if local_name(after[..end].trim()) != element.name {
+                return Err(format!(
+                    "</{}> closes <{}>",
+                    after[..end].trim(),
+                    element.name
+                ));
+            }
+            rest = &after[end + 1..];
+            element
+        } else {
+            let (element, closed, after) = parse_tag(&rest[1..], &entities)?;
+            rest = after;
+            if !closed {
+                stack.push(element);
+                continue;
+            }
+            element
+        };
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(element),
+            None => return Ok(element),
+        }
+    }
+}
+
+/// Text after the end of a comment or processing instruction
+fn skip_past<'a>(text: &'a str, end: &str) -> Result<&'a str, String> {
+    text.find(end)
+        .map(|i| &text[i + end.len()..])
+        .ok_or_else(|| format!("missing {}", end))
+}
+
+/// Text after a doctype declaration, which may hold an internal subset in brackets
+fn skip_declaration(text: &str) -> Result<&str, String> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            '>' if depth <= 0 => return Ok(&text[i + 1..]),
+            _ => {}
+        }
+    }
+    Err("unterminated declaration".into())
+}
+
+/// Name without its namespace prefix, `svg:rect` is `rect`
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// Parse a start tag after its `<`, returning whether it closes itself and the text after it
+fn parse_tag<'a>(text: &'a str, entities: &Entities) -> Result<(Element, bool, &'a str), String> {
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .ok_or("unterminated tag")?;
+    let mut element = Element {
+        name: local_name(&text[..end]).to_string(),
+        ..Element::default()
+    };
+    let mut rest = &text[end..];
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix("/>") {
+            return Ok((element, true, after));
+        }
+        if let Some(after) = rest.strip_prefix('>') {
+            return Ok((element, false, after));
+        }
+        let (name, after) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("attribute without a value in <{}>", element.name))?;
+        let after = after.trim_start();
+        let quote = after
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+            .ok_or_else(|| format!("unquoted attribute in <{}>", element.name))?;
+        let (value, after) = after[1..]
+            .split_once(quote)
+            .ok_or_else(|| format!("unterminated attribute in <{}>", element.name))?;
+        element
+            .attributes
+            .push((name.trim().to_string(), unescape(value, entities)));
+        rest = after;
+    }
+}
+
+/// Entities declared in the doctype, by name
+type Entities = HashMap<String, String>;
+
+/// Add the general entities declared in a doctype, `<!ENTITY name "value">`
+///
+/// Editors such as Illustrator declare namespaces this way.
+fn declare_entities(declaration: &str, entities: &mut Entities) {
+    let mut rest = declaration;
+    while let Some(start) = rest.find("<!ENTITY") {
+        rest = rest[start + 8..].trim_start();
+        // Parameter entities only matter to the doctype itself
+        if rest.starts_with('%') {
+            continue;
+        }
+        let Some((name, after)) = rest.split_once(char::is_whitespace) else {
+            return;
+        };
+        let after = after.trim_start();
+        let Some(quote) = after.chars().next().filter(|&c| c == '"' || c == '\'') else {
+            continue;
+        };
+        let Some((value, after)) = after[1..].split_once(quote) else {
+            return;
+        };
+        let value = unescape(value, entities);
+        entities.entry(name.to_string()).or_insert(value);
+        rest = after;
+    }
+}
+
+/// Replace character and entity references
+fn unescape(text: &str, entities: &Entities) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';').filter(|&end| end <= 64) else {
+            result.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..end];
+        if let Some(value) = entities.get(entity) {
+            result.push_str(value);
+            rest = &rest[end + 1..];
+            continue;
+        }
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity
+                    .strip_prefix('#')
+                    .and_then(|n| n.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        };
+        match c {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut entities = Entities::new();
+        declare_entities(
+            "<!DOCTYPE svg [<!ENTITY % p \"x\"> <!ENTITY ns 'a&amp;b'>]>",
+            &mut entities,
+        );
+        assert_eq!(entities.len(), 1);
+        assert_eq!(
+            unescape("&lt;a &amp; &#x42;&#67;&bogus;&ns;", &entities),
+            "<a & BC&bogus;a&b"
+        );
+
+        let root = parse(
+            "<?xml version=\"1.0\"?><!DOCTYPE svg [<!ENTITY x \"y\">]><!-- c -->\
+             <svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\"><svg:style><![CDATA[a>b{}]]></svg:style></svg:svg>",
+        )
+        .unwrap();
+        assert_eq!(root.name, "svg");
+        assert_eq!(root.attributes[0].0, "xmlns:svg");
+        assert_eq!(root.children[0].text, "a>b{}");
+        assert!(parse("<svg><g></svg>").is_err());
+        assert!(parse("<svg>").is_err());
+    }
+}